@@ -0,0 +1,93 @@
+//! 木をたどる評価器、スタックマシン、レジスタマシン、JIT の速さを比べるベンチマーク
+//!
+//! `cargo bench --bench engines` で実行し、プログラムごとに各方式の実行時間を表示する。
+//! JIT は `--features jit` を付けたときだけ測る。各方式で何度か実行し、最も速かった回の結果を使う。
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use ruscal_b::ast::{Expr, ExprKind, Span};
+use ruscal_b::register_vm::RegisterVm;
+use ruscal_b::{compile, eval_statements, statements, stdlib, Bytecode, Environment, Value, Vm};
+
+/// 1つの方式を測る時間の目安
+const BUDGET: Duration = Duration::from_secs(1);
+
+/// 測るプログラムの名前とソースコード
+const PROGRAMS: &[(&str, &str)] = &[
+    (
+        "fib",
+        "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }; fib(24)",
+    ),
+    (
+        "loop",
+        "fn sum(n) { var t = 0; for i in 0..n { t = t + i * i / 3 }; t }; \
+         var t = 0; for k in 0..200 { t = t + sum(1000 + k) }; t",
+    ),
+    (
+        "float",
+        "fn area(n) { var t = 0.0; var x = 0.0; for i in 0..n { x = i * 1.0 / n; t = t + x * x / n }; t }; \
+         var t = 0.0; for k in 0..200 { t = t + area(1000 + k) }; t",
+    ),
+];
+
+/// 標準関数を登録した環境
+fn env() -> Environment {
+    let mut env = Environment::new();
+    stdlib::register(&mut env);
+    env
+}
+
+/// `run` を繰り返し実行し、最も速かった回の時間と結果を返す
+fn measure(mut run: impl FnMut() -> Value) -> (Duration, Value) {
+    let mut best = Duration::MAX;
+    let mut value = Value::Nil;
+    let started = Instant::now();
+    while started.elapsed() < BUDGET {
+        let start = Instant::now();
+        value = black_box(run());
+        best = best.min(start.elapsed());
+    }
+    (best, value)
+}
+
+fn report(name: &str, engine: &str, (time, value): (Duration, Value)) {
+    println!(
+        "{name:<8} {engine:<10} {:>10.3} ms  {value}",
+        time.as_secs_f64() * 1000.0
+    );
+}
+
+fn vm(code: &Bytecode) -> Value {
+    Vm::new().run(code, &mut env()).unwrap()
+}
+
+fn bench(name: &str, input: &str) {
+    let program = statements(input).unwrap();
+    report(
+        name,
+        "tree-walk",
+        measure(|| eval_statements(&program, &mut env()).unwrap().unwrap()),
+    );
+    let block = Expr::new(ExprKind::Block(program), Span::new(0, input.len()));
+    let code = compile(&block);
+    #[cfg(feature = "jit")]
+    ruscal_b::jit::set_jit_enabled(false);
+    report(name, "vm", measure(|| vm(&code)));
+    report(
+        name,
+        "register",
+        measure(|| RegisterVm::new().run(&code, &mut env()).unwrap()),
+    );
+    #[cfg(feature = "jit")]
+    {
+        ruscal_b::jit::set_jit_enabled(true);
+        report(name, "jit", measure(|| vm(&code)));
+    }
+}
+
+fn main() {
+    for (name, input) in PROGRAMS {
+        bench(name, input);
+    }
+}
@@ -0,0 +1,59 @@
+//! 字句解析の速さを測るベンチマーク
+//!
+//! `cargo bench --bench lexer` で実行し、入力ごとに1秒あたりのトークン数を表示する。
+//! 各入力を何度か字句解析し、最も速かった回の結果を使う。
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use ruscal_b::Lexer;
+
+/// 1つの入力を測る時間の目安
+const BUDGET: Duration = Duration::from_secs(1);
+
+/// 要素を並べただけの浅い入力
+fn flat(forms: usize) -> String {
+    let form = r#"(define total_count (+ 1_000 -2.5e3 0x1F "a \"quoted\" string" 'sym nil)) "#;
+    let mut input = form.repeat(forms);
+    input.push_str("/* block /* nested */ comment */ // line comment\n");
+    input
+}
+
+/// 括弧を深く入れ子にした入力
+fn nested(depth: usize, repeat: usize) -> String {
+    let form = format!("{}{}", "(変数 1 ".repeat(depth), ")".repeat(depth));
+    vec![form; repeat].join(" ")
+}
+
+/// 入力を字句解析して、トークンの数を返す
+fn lex(input: &str) -> usize {
+    let mut count = 0;
+    for token in Lexer::new(input) {
+        black_box(token.unwrap());
+        count += 1;
+    }
+    count
+}
+
+fn bench(name: &str, input: &str) {
+    let tokens = lex(input);
+    let mut best = Duration::MAX;
+    let started = Instant::now();
+    while started.elapsed() < BUDGET {
+        let start = Instant::now();
+        black_box(lex(black_box(input)));
+        best = best.min(start.elapsed());
+    }
+    let per_second = tokens as f64 / best.as_secs_f64();
+    println!(
+        "{name:<8} {:>8} bytes {tokens:>8} tokens {:>10.3} ms {:>8.2} Mtokens/s",
+        input.len(),
+        best.as_secs_f64() * 1000.0,
+        per_second / 1e6
+    );
+}
+
+fn main() {
+    bench("flat", &flat(10_000));
+    bench("nested", &nested(1_000, 100));
+}
@@ -0,0 +1,81 @@
+//! 中置記法の式を1行ずつ評価する電卓
+//!
+//! ```text
+//! $ cargo run --example calculator
+//! > var r = 2
+//! 2
+//! > 3.14 * r * r
+//! 12.56
+//! ```
+//!
+//! 引数を渡せば、標準入力を読まずにその式だけを評価する。
+//!
+//! ```text
+//! $ cargo run --example calculator -- "1 + 2 * 3"
+//! 7
+//! ```
+
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+
+use ruscal_b::{Diagnostic, Error, Interpreter, SourceMap};
+
+/// エラーを、式の下に範囲の下線を引いて表示する
+fn report(line: &str, e: &Error) {
+    let diagnostic = match e {
+        Error::Parse(e) => Diagnostic::from(e),
+        Error::Eval(e) => Diagnostic::from(e),
+        Error::Type(e) => Diagnostic::from(e),
+        e => return eprintln!("error: {e}"),
+    };
+    eprint!("{}", diagnostic.render(&SourceMap::new(line)));
+}
+
+/// 1行を評価し、値があれば表示する
+fn eval_line(interpreter: &mut Interpreter, line: &str) -> bool {
+    match interpreter.run_infix(line) {
+        Ok(Some(value)) => {
+            println!("{value}");
+            true
+        }
+        Ok(None) => true,
+        Err(e) => {
+            report(line, &e);
+            false
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut interpreter = Interpreter::new();
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        let ok = eval_line(&mut interpreter, &args.join(" "));
+        return if ok {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush().expect("failed to flush stdout");
+        match lines.next() {
+            Some(Ok(line)) if line.trim().is_empty() => {}
+            Some(Ok(line)) => {
+                eval_line(&mut interpreter, &line);
+            }
+            Some(Err(e)) => {
+                eprintln!("error: {e}");
+                return ExitCode::FAILURE;
+            }
+            None => {
+                println!();
+                return ExitCode::SUCCESS;
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Rust で書いた関数を組み込みの関数として追加する例
+//!
+//! ```text
+//! $ cargo run --example custom_builtins
+//! ```
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ruscal_b::stdlib::Arity;
+use ruscal_b::{EvalError, Interpreter, NativeFn, Span, Value};
+
+/// 整数の階乗。負の数と大きすぎる結果はエラーにする
+fn factorial(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let Value::I64(n) = args[0] else {
+        return Err(EvalError::NotAnInteger { span });
+    };
+    if n < 0 {
+        return Err(EvalError::TypeMismatch {
+            expected: "non-negative integer",
+            span,
+        });
+    }
+    (1..=n)
+        .try_fold(1i64, i64::checked_mul)
+        .map(Value::I64)
+        .ok_or(EvalError::IntegerOverflow { span })
+}
+
+fn main() {
+    // 呼び出しごとに Rust 側の状態を書き換える関数
+    let log = Rc::new(RefCell::new(vec![]));
+    let sink = Rc::clone(&log);
+
+    let mut interpreter = Interpreter::builder()
+        // 引数の数は評価器が呼び出す前に確かめる
+        .with_global(
+            "factorial",
+            Value::NativeFn(Rc::new(NativeFn::new(
+                "factorial",
+                Arity::Exact(1),
+                factorial,
+            ))),
+        )
+        // 引数の数を確かめない関数は、クロージャで手軽に書ける
+        .with_native_fn("log", move |args, _| {
+            let words: Vec<_> = args
+                .iter()
+                .map(|arg| match arg {
+                    Value::Str(s) => s.to_string(),
+                    arg => arg.to_string(),
+                })
+                .collect();
+            let line = words.join(" ");
+            sink.borrow_mut().push(line);
+            Ok(Value::Nil)
+        })
+        .build();
+
+    let program = "(log \"start\") (define x (factorial 10)) (log \"x =\" x) x";
+    let value = interpreter.run(program).expect("the program runs");
+    println!("{program}\n=> {}", value.unwrap_or(Value::Nil));
+    println!("log: {:?}", log.borrow());
+
+    // 組み込みの関数は中置記法からも呼べる
+    let value = interpreter
+        .run_infix("factorial(5) * 2")
+        .expect("factorial is callable");
+    println!("factorial(5) * 2 => {}", value.unwrap_or(Value::Nil));
+
+    for program in ["(factorial 1 2)", "(factorial -1)", "(factorial 30)"] {
+        let err = interpreter.run(program).unwrap_err();
+        println!("{program} => error: {err}");
+    }
+}
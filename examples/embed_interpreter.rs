@@ -0,0 +1,48 @@
+//! プログラムを埋め込んで評価し、定義された値を Rust から読み出す例
+//!
+//! ```text
+//! $ cargo run --example embed_interpreter
+//! ```
+
+use ruscal_b::{Interpreter, RunLimits, Value};
+
+/// `examples/programs/` の S式のプログラム
+const FIB: &str = include_str!("programs/fib.rscl");
+const CLOSURES: &str = include_str!("programs/closures.rscl");
+
+fn main() {
+    let mut interpreter = Interpreter::new();
+
+    // 最後の式の値が返り、定義は大域環境に残る
+    let value = interpreter.run(FIB).expect("fib.rscl runs");
+    println!("fib.rscl => {}", value.unwrap_or(Value::Nil));
+    let fib = interpreter.env().get("fib").expect("fib.rscl defines fib");
+    println!("fib is {fib}");
+
+    // 残った定義は、続けて評価するプログラムから中置記法でも呼べる
+    let value = interpreter
+        .run_infix("fib(12) + 1")
+        .expect("fib is callable");
+    println!("fib(12) + 1 => {}", value.unwrap_or(Value::Nil));
+
+    // Rust で作った値を大域変数として渡す
+    interpreter.env_mut().define("base", Value::I64(100));
+    let value = interpreter.run(CLOSURES).expect("closures.rscl runs");
+    println!("closures.rscl => {}", value.unwrap_or(Value::Nil));
+    let value = interpreter
+        .run("((adder base) 1)")
+        .expect("adder is callable");
+    println!("((adder base) 1) => {}", value.unwrap_or(Value::Nil));
+
+    // 利用者のコードには上限を付けて、終わらないプログラムを止める
+    let mut sandbox = Interpreter::builder()
+        .with_limits(RunLimits {
+            max_steps: Some(10_000),
+            ..RunLimits::UNLIMITED
+        })
+        .build();
+    let err = sandbox
+        .run("(define loop (fn (n) (loop (+ n 1)))) (loop 0)")
+        .unwrap_err();
+    println!("sandboxed loop => {err}");
+}
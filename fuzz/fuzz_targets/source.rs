@@ -0,0 +1,222 @@
+//! `source()` をはじめ、ソースコードを読む関数に任意の入力を与えるファジングの対象
+//!
+//! 引数にファイルを渡すとその内容を1つずつ試す。`corpus/` には文字の境界や数値の桁あふれなど、
+//! パニックしやすい入力の種を置いてある。引数が無ければ、S式に現れやすい文字を
+//! 偏って含む入力を乱数で作り続ける。次のいずれかを見つけると入力を `artifacts/` に保存して
+//! 異常終了する。
+//!
+//! * `source()`、中置記法の解析、具象構文木の解析、色付けのいずれかがパニックする
+//! * 1つの入力の解析が `TIMEOUT` を過ぎても終わらない
+//! * 構文木の範囲が入力の外にはみ出す、文字の途中を指す、または親の範囲に収まらない
+//!
+//! ```text
+//! cd fuzz
+//! cargo run --release --bin source                  # 乱数で作った入力を試し続ける
+//! cargo run --release --bin source -- corpus/*      # 保存した入力を試す
+//! RUSCAL_FUZZ_SEED=42 RUSCAL_FUZZ_RUNS=100000 cargo run --release --bin source
+//! ```
+
+use std::panic;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ruscal_b::cst::Cst;
+use ruscal_b::highlight::highlight;
+use ruscal_b::parser::source_recovering;
+use ruscal_b::{parse_expr, source, statements, Span, TokenTree};
+
+/// 1つの入力の解析にかけてよい時間
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 乱数で作る入力に混ぜる断片。数値や演算子の境界になりやすいものを多めに含める
+const PIECES: &[&str] = &[
+    "(",
+    ")",
+    " ",
+    "+",
+    "-",
+    ".",
+    "..",
+    "e",
+    "E",
+    "0",
+    "1",
+    "9",
+    "0x",
+    "0b",
+    "_",
+    "1e",
+    "1.",
+    ".5",
+    "-.",
+    "+.",
+    "inf",
+    "NaN",
+    "\"",
+    "\\",
+    "\\u{",
+    "}",
+    "/",
+    "*",
+    "//",
+    "/*",
+    "*/",
+    "'",
+    "`",
+    ",",
+    "a",
+    "?",
+    "nil",
+    "true",
+    "<=",
+    "&&",
+    "!",
+    "=",
+    ":",
+    ";",
+    "{",
+    "\n",
+    "\t",
+    "é",
+    "変数",
+    "\u{0}",
+    "9223372036854775808",
+];
+
+/// 1つの入力を解析して、結果の範囲を確かめる
+fn fuzz_one(data: &[u8]) {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(tree) = source(input) {
+        check_spans(input, &tree, Span::new(0, input.len()));
+    }
+    let (tree, _) = source_recovering(input);
+    check_spans(input, &tree, Span::new(0, input.len()));
+    let _ = parse_expr(input);
+    let _ = statements(input);
+    let _ = Cst::parse(input);
+    let _ = highlight(input);
+}
+
+/// ノードの範囲が入力と親の範囲に収まり、文字の境界を指していることを確かめる
+fn check_spans(input: &str, tree: &TokenTree, parent: Span) {
+    let span = tree.span();
+    assert!(
+        span.start <= span.end && span.end <= input.len(),
+        "span {span:?} is outside the input of {} bytes",
+        input.len()
+    );
+    assert!(
+        input.is_char_boundary(span.start) && input.is_char_boundary(span.end),
+        "span {span:?} splits a character"
+    );
+    assert!(
+        parent.start <= span.start && span.end <= parent.end,
+        "span {span:?} is outside its parent {parent:?}"
+    );
+    if let TokenTree::Tree(children, _) = tree {
+        for child in children {
+            check_spans(input, child, span);
+        }
+    }
+}
+
+/// xorshift64 による乱数
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn random_input(state: &mut u64) -> Vec<u8> {
+    let len = next(state) % 64;
+    let mut data = vec![];
+    for _ in 0..len {
+        let r = next(state);
+        if r.is_multiple_of(8) {
+            data.push((r >> 8) as u8);
+        } else {
+            data.extend_from_slice(PIECES[(r >> 8) as usize % PIECES.len()].as_bytes());
+        }
+    }
+    data
+}
+
+/// 失敗した入力を保存して異常終了する
+fn fail(data: &[u8], reason: &str) -> ! {
+    let _ = std::fs::create_dir_all("artifacts");
+    let path = format!("artifacts/crash-{:016x}", hash(data));
+    let _ = std::fs::write(&path, data);
+    eprintln!("{reason} on input {:?}", String::from_utf8_lossy(data));
+    eprintln!("saved to {path}");
+    std::process::exit(1);
+}
+
+/// FNV-1a による入力のハッシュ
+fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |h, &b| {
+        (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn main() {
+    // 解析中の入力を読み始めた時刻に1を足したミリ秒。解析していなければ0
+    let started = Arc::new(AtomicU64::new(0));
+    let current = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let epoch = Instant::now();
+    {
+        let started = Arc::clone(&started);
+        let current = Arc::clone(&current);
+        thread::spawn(move || loop {
+            thread::sleep(TIMEOUT / 4);
+            let since = started.load(Ordering::SeqCst);
+            let elapsed = (epoch.elapsed().as_millis() as u64).saturating_sub(since);
+            if since != 0 && elapsed > TIMEOUT.as_millis() as u64 {
+                let data = current.lock().map(|data| data.clone()).unwrap_or_default();
+                fail(&data, "timeout");
+            }
+        });
+    }
+    panic::set_hook(Box::new(|_| {}));
+    let run = |data: Vec<u8>| {
+        *current.lock().unwrap() = data.clone();
+        started.store(epoch.elapsed().as_millis() as u64 + 1, Ordering::SeqCst);
+        let result = panic::catch_unwind(|| fuzz_one(&data));
+        started.store(0, Ordering::SeqCst);
+        if let Err(payload) = result {
+            let message = payload
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            fail(&data, &format!("panic: {message}"));
+        }
+    };
+
+    let paths: Vec<_> = std::env::args_os().skip(1).collect();
+    if !paths.is_empty() {
+        for path in paths {
+            match std::fs::read(&path) {
+                Ok(data) => run(data),
+                Err(e) => eprintln!("{}: {e}", path.to_string_lossy()),
+            }
+        }
+        return;
+    }
+    let env = |name: &str| std::env::var(name).ok().and_then(|v| v.parse().ok());
+    let mut state: u64 = env("RUSCAL_FUZZ_SEED").unwrap_or(0x9e3779b97f4a7c15).max(1);
+    let runs: Option<u64> = env("RUSCAL_FUZZ_RUNS");
+    let mut count = 0u64;
+    while runs.is_none_or(|runs| count < runs) {
+        run(random_input(&mut state));
+        count += 1;
+        if count.is_multiple_of(1_000_000) {
+            eprintln!("{count} inputs");
+        }
+    }
+    eprintln!("{count} inputs, no failures");
+}
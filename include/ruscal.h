@@ -0,0 +1,55 @@
+/*
+ * ruscal の解析器と評価器を C や C++ から使うための宣言
+ *
+ * src/ffi.rs の定義と手で合わせている。ライブラリは次のように作る。
+ *
+ *     cargo rustc --release --features ffi --crate-type cdylib
+ *
+ * 所有権:
+ *   - 入力の文字列は呼び出しの間だけ借用する。NUL で終わっている必要はない
+ *   - 返された RuscalResult とその text はライブラリが所有しており、
+ *     ruscal_free_result() でちょうど1回解放する。free() で解放してはならない
+ */
+
+#ifndef RUSCAL_H
+#define RUSCAL_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* 呼び出しの結果の種類 */
+typedef enum RuscalStatus {
+    RUSCAL_STATUS_OK = 0,
+    RUSCAL_STATUS_PARSE_ERROR = 1,
+    RUSCAL_STATUS_EVAL_ERROR = 2,
+    RUSCAL_STATUS_INVALID_UTF8 = 3,
+    RUSCAL_STATUS_PANIC = 4,
+} RuscalStatus;
+
+/* 呼び出しの結果。フィールドの並びは変えない */
+typedef struct RuscalResult {
+    /* 結果の種類 */
+    RuscalStatus status;
+    /* NUL で終わる UTF-8 の文字列。成功すれば木の JSON か値の表示、失敗すればエラーの報告 */
+    char *text;
+    /* text の末尾の NUL を除いたバイト数 */
+    size_t len;
+} RuscalResult;
+
+/* input から len バイトを解析する。len が0なら input は NULL でもよい */
+RuscalResult *ruscal_parse(const char *input, size_t len);
+
+/* input から len バイトを標準ライブラリ入りの新しい環境で評価する */
+RuscalResult *ruscal_eval(const char *input, size_t len);
+
+/* ruscal_parse() や ruscal_eval() の結果を解放する。NULL なら何もしない */
+void ruscal_free_result(RuscalResult *result);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RUSCAL_H */
@@ -0,0 +1,285 @@
+//! パーサーコンビネーターで書いた、S式の字句解析器と構文解析器の別実装
+//!
+//! [`Lexer`] と [`parser::source`] と同じ規則を、[`combinator`](crate::combinator) の規則を
+//! 組み合わせて書き直したもの。同じ [`Token`] と [`TokenTree`] を返すので、
+//! 手書きの解析器と結果を突き合わせて、どちらかの誤りを見つけるのに使う。
+//! 読めない入力には `None` を返し、エラーの内容は返さない。
+//!
+//! ```
+//! use ruscal_b::alt_parser;
+//! use ruscal_b::parser::source;
+//!
+//! let input = "(define (f x) '(+ x 1.5)) // f";
+//! assert_eq!(alt_parser::source(input), source(input).ok());
+//! assert_eq!(alt_parser::source("(f"), None);
+//! ```
+
+use crate::ast::{Span, Token, TokenTree};
+use crate::combinator::{
+    and_then, many0, map, opt, or, recognize, satisfy, tag, take_while, take_while1, ParseFn,
+};
+#[cfg(doc)]
+use crate::lexer::Lexer;
+use crate::lexer::{is_ident_continue, is_ident_start, OPERATORS};
+#[cfg(doc)]
+use crate::parser;
+use crate::parser::{quote_form, MAX_DEPTH};
+
+/// 改行を含む空白文字とコメントを読み飛ばす
+fn trivia(input: &str) -> &str {
+    let whitespace = take_while1(|c: char| c.is_whitespace() && c != '\n');
+    let line_comment = and_then(tag("//"), take_while(|c| c != '\n'));
+    let piece = or(
+        or(map(whitespace, |_| ()), map(tag("\n"), |_| ())),
+        or(map(line_comment, |_| ()), block_comment),
+    );
+    many0(piece).parse(input).map_or(input, |(rest, _)| rest)
+}
+
+/// 入れ子にできるブロックコメント。閉じていなければ入力の終わりまでを読む
+fn block_comment(input: &str) -> Option<(&str, ())> {
+    let (mut rest, _) = tag("/*").parse(input)?;
+    let step = or(block_comment, map(satisfy(|_| true), |_| ()));
+    loop {
+        if let Some((after, _)) = tag("*/").parse(rest) {
+            return Some((after, ()));
+        }
+        match step.parse(rest) {
+            Some((after, ())) => rest = after,
+            None => return Some((rest, ())),
+        }
+    }
+}
+
+/// 識別子、または `true`、`false`、`nil` のリテラル
+fn ident(input: &str) -> Option<(&str, Token<'_>)> {
+    let name = recognize(and_then(
+        and_then(satisfy(is_ident_start), take_while(is_ident_continue)),
+        opt(tag("?")),
+    ));
+    map(name, |name| match name {
+        "true" => Token::Bool(true),
+        "false" => Token::Bool(false),
+        "nil" => Token::Nil,
+        name => Token::Ident(name),
+    })
+    .parse(input)
+}
+
+/// 数値リテラル
+fn number(input: &str) -> Option<(&str, Token<'_>)> {
+    let (rest, sign) = opt(or(tag("-"), tag("+"))).parse(input)?;
+    for (prefix, radix) in [("0x", 16), ("0X", 16), ("0b", 2), ("0B", 2)] {
+        if let Some((rest, _)) = tag(prefix).parse(rest) {
+            let (rest, digits) = take_while(|c: char| c.is_digit(radix) || c == '_').parse(rest)?;
+            let sign = if sign == Some("-") { "-" } else { "" };
+            let literal = format!("{sign}{}", digits.replace('_', ""));
+            let value = i64::from_str_radix(&literal, radix).ok()?;
+            return Some((rest, Token::Int(value)));
+        }
+    }
+    if rest.starts_with('_') {
+        return None;
+    }
+    let (mut after, mut body) = take_while(|c| matches!(c, '.' | '_' | '0'..='9')).parse(rest)?;
+    // `0..10` の `..` は範囲の記号なので数値に含めない
+    if let Some(pos) = body.find("..") {
+        (after, body) = (rest.get(pos..)?, body.get(..pos)?);
+    }
+    let exponent = recognize(and_then(
+        and_then(or(tag("e"), tag("E")), opt(or(tag("+"), tag("-")))),
+        and_then(
+            satisfy(|c| c.is_ascii_digit()),
+            take_while(|c| c.is_ascii_digit() || c == '_'),
+        ),
+    ));
+    let (after, exponent) = opt(exponent).parse(after)?;
+    let literal = input
+        .get(..input.len().checked_sub(after.len())?)?
+        .replace('_', "");
+    let token = if body.contains('.') || exponent.is_some() {
+        Token::Float(literal.parse().ok()?)
+    } else {
+        Token::Int(literal.parse().ok()?)
+    };
+    Some((after, token))
+}
+
+/// 演算子。入力の先頭に合う演算子のうち最も長いものを読む
+fn operator(input: &str) -> Option<(&str, Token<'_>)> {
+    let (rest, op) = OPERATORS
+        .iter()
+        .filter_map(|op| tag(op).parse(input))
+        .min_by_key(|(rest, _)| rest.len())?;
+    Some((rest, Token::Ident(op)))
+}
+
+/// 文字列リテラル。値は引用符の内側をそのまま持つ
+fn string(input: &str) -> Option<(&str, Token<'_>)> {
+    let plain = take_while1(|c| c != '"' && c != '\\');
+    let escape = recognize(and_then(
+        tag("\\"),
+        satisfy(|c| matches!(c, 'n' | 't' | '"' | '\\')),
+    ));
+    let body = recognize(many0(or(plain, escape)));
+    let (rest, (_, (body, _))) = and_then(tag("\""), and_then(body, tag("\""))).parse(input)?;
+    Some((rest, Token::StrLiteral(body)))
+}
+
+/// トークンを1つ読む
+///
+/// 最初の文字でどの規則を使うかを決め、その規則で読めなければ他の規則は試さない。
+/// `-.` のように数値として読み始めて失敗した入力を、別のトークンの並びとして読まないようにするため。
+fn token(input: &str) -> Option<(&str, Token<'_>)> {
+    let sign_starts_number = matches!(input.as_bytes().get(1), Some(b'.' | b'0'..=b'9'));
+    match input.chars().next()? {
+        c if is_ident_start(c) => ident(input),
+        '+' | '-' if !sign_starts_number => operator(input),
+        '.' if input.starts_with("..") => operator(input),
+        '-' | '+' | '.' | '0'..='9' => number(input),
+        '*' | '/' | '%' | '=' | '<' | '>' | '!' | '&' | '|' | ':' | ',' | '\'' | '`' => {
+            operator(input)
+        }
+        '"' => string(input),
+        '(' => Some((input.get(1..)?, Token::LParen)),
+        ')' => Some((input.get(1..)?, Token::RParen)),
+        // S式には文もブロックも無いので、中置記法の記号も読めない文字として扱う
+        _ => None,
+    }
+}
+
+/// 前にある空白とコメントを読み飛ばしてトークンを1つ読み、入力全体 `full` での範囲を付ける規則
+fn lexeme<'src>(full: &'src str) -> impl ParseFn<'src, (Span, Token<'src>)> {
+    move |input: &'src str| {
+        let input = trivia(input);
+        let start = full.len() - input.len();
+        let (rest, token) = token(input)?;
+        Some((rest, (Span::new(start, full.len() - rest.len()), token)))
+    }
+}
+
+/// 入力全体をトークンに分割する関数
+///
+/// # 戻り値
+/// * `Option<Vec<(Span, Token)>>` - [`Lexer`] が返すものと同じトークンの並び。読めない入力なら `None`
+pub fn tokens(input: &str) -> Option<Vec<(Span, Token<'_>)>> {
+    let (rest, tokens) = many0(lexeme(input)).parse(input)?;
+    trivia(rest).is_empty().then_some(tokens)
+}
+
+/// 要素を1つ読む。`depth` は外側にある括弧の数
+fn datum<'src>(
+    full: &'src str,
+    depth: usize,
+    input: &'src str,
+) -> Option<(&'src str, TokenTree<'src>)> {
+    let (rest, (span, token)) = lexeme(full).parse(input)?;
+    match token {
+        Token::LParen if depth >= MAX_DEPTH => None,
+        Token::LParen => {
+            let children = many0(|input| datum(full, depth + 1, input));
+            let close = |input| match lexeme(full).parse(input)? {
+                (rest, (close, Token::RParen)) => Some((rest, close)),
+                _ => None,
+            };
+            let (rest, (children, close)) = and_then(children, close).parse(rest)?;
+            Some((rest, TokenTree::Tree(children, span.merge(close))))
+        }
+        // `'x` は `(quote x)` と同じ木にする
+        Token::Ident(prefix) if quote_form(prefix).is_some() => {
+            let (rest, node) = datum(full, depth, rest)?;
+            let form = quote_form(prefix)?;
+            let merged = span.merge(node.span());
+            let prefix = TokenTree::Token(Token::Ident(form), span);
+            Some((rest, TokenTree::Tree(vec![prefix, node], merged)))
+        }
+        Token::RParen => None,
+        token => Some((rest, TokenTree::Token(token, span))),
+    }
+}
+
+/// ソースコードを解析する関数
+///
+/// # 戻り値
+/// * `Option<TokenTree>` - [`parser::source`] と同じ、入力全体を1つの `TokenTree::Tree` にまとめた解析結果。
+///   解析できない入力なら `None`
+pub fn source(input: &str) -> Option<TokenTree<'_>> {
+    let (rest, forms) = many0(|rest| datum(input, 0, rest)).parse(input)?;
+    trivia(rest)
+        .is_empty()
+        .then(|| TokenTree::Tree(forms, Span::new(0, input.len())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser;
+
+    const CORPUS: &[&str] = &[
+        "",
+        "  \n\t",
+        "(define (square x) (* x x))\n(square 3)",
+        "'(a `(b ,c)) ''x",
+        "(print \"a\\\"b\\\\c\\n\\t\" \"// no\" \"/* no */\")",
+        "1 -2 +3 1.5 .5 -.5 1e3 1E-3 2.5e+2 1_000 0x1F -0xff 0b1010 1..10 1e x1e2",
+        "(<= a b) (&& x y) (|| x) (-> a) (=> b) (% 7 3) (! x) (: a) (- 1) (+ a)",
+        "null? empty?? 変数1 _x true false nil truex",
+        "a // line\nb /* block /* nested */ */ c /* unterminated",
+        "(a (b (c (d))))",
+        "(a b",
+        "a)",
+        "'",
+        "(a ')",
+        "\"unterminated",
+        "\"bad \\q escape\"",
+        "1.2.3 -.. 1__ -_1 0x 99999999999999999999",
+        "a; b",
+        "{x} [y]",
+        "& #",
+    ];
+
+    /// 平易な乱数で、コーパスの入力を少し書き換えた入力を作る
+    fn mutations() -> Vec<String> {
+        let pieces = [
+            "", " ", "(", ")", "'", ",", "\"", "\\", "1", ".", "-", "e", "_", "x", "/", "*", "\n",
+        ];
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+        let mut inputs = vec![];
+        for _ in 0..3000 {
+            let input = CORPUS[next(CORPUS.len())];
+            let mut at = next(input.len() + 1);
+            while !input.is_char_boundary(at) {
+                at -= 1;
+            }
+            let piece = pieces[next(pieces.len())];
+            inputs.push(format!("{}{piece}{}", &input[..at], &input[at..]));
+        }
+        inputs
+    }
+
+    #[test]
+    fn test_tokens_agree() {
+        let inputs = CORPUS.iter().map(|s| s.to_string()).chain(mutations());
+        for input in inputs {
+            let expected = Lexer::new(&input).collect::<Result<Vec<_>, _>>().ok();
+            assert_eq!(tokens(&input), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn test_source_agrees() {
+        let inputs = CORPUS.iter().map(|s| s.to_string()).chain(mutations());
+        for input in inputs {
+            assert_eq!(source(&input), parser::source(&input).ok(), "{input:?}");
+        }
+        let deep = |n| format!("{}{}", "(".repeat(n), ")".repeat(n));
+        assert!(source(&deep(100)).is_some());
+    }
+}
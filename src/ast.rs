@@ -0,0 +1,730 @@
+//! 字句解析・構文解析の結果を表すデータ型
+
+use std::rc::Rc;
+
+use crate::intern::Symbol;
+
+/// ソースコード上の範囲を表すバイト位置の組
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    /// 範囲の先頭のバイト位置
+    pub start: usize,
+    /// 範囲の末尾の次のバイト位置
+    pub end: usize,
+}
+
+impl Span {
+    /// 範囲を作る
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// 範囲のバイト長
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// 範囲が空かどうか
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// 2つの範囲を両方含む最小の範囲
+    pub fn merge(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+/// 字句解析で得られるトークン
+#[derive(Debug, PartialEq)]
+pub enum Token<'src> {
+    /// 識別子
+    Ident(&'src str),
+    /// 整数リテラル。10進数、16進数 (`0x`)、2進数 (`0b`) のいずれか
+    Int(i64),
+    /// 小数点や指数を含む浮動小数点数リテラル
+    Float(f64),
+    /// 文字列リテラル。エスケープシーケンスは展開せず、引用符の内側をそのまま保持する
+    StrLiteral(&'src str),
+    /// 真偽値のキーワード `true` と `false`
+    Bool(bool),
+    /// 値が無いことを表すキーワード `nil`
+    Nil,
+    /// 左括弧 `(`
+    LParen,
+    /// 右括弧 `)`
+    RParen,
+    /// 中置記法のブロックを開く `{`
+    LBrace,
+    /// 中置記法のブロックを閉じる `}`
+    RBrace,
+    /// 中置記法の左角括弧 `[`
+    LBracket,
+    /// 中置記法の右角括弧 `]`
+    RBracket,
+    /// 文の区切り `;`。中置記法では被演算子の直後の改行もこれになる
+    Semicolon,
+    /// 中置記法の予約語。S式では予約語も識別子として読む
+    Keyword(Keyword),
+}
+
+/// 括弧の入れ子構造を表すトークンの木
+#[derive(Debug, PartialEq)]
+pub enum TokenTree<'src> {
+    /// 葉となる単一のトークンとその範囲
+    Token(Token<'src>, Span),
+    /// 括弧で囲まれた部分木とその範囲。範囲は括弧自体を含む
+    Tree(Vec<TokenTree<'src>>, Span),
+}
+
+impl TokenTree<'_> {
+    /// ノードが覆うソースコード上の範囲
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Token(_, span) | Self::Tree(_, span) => *span,
+        }
+    }
+
+    /// 入力の文字列を借用しない [`OwnedTokenTree`] に変換する
+    ///
+    /// 識別子は [`Symbol`] に、文字列リテラルは `String` に置き換えるので、
+    /// 解析した入力のバッファを解放した後も結果を使い続けられる。
+    pub fn to_owned(&self) -> OwnedTokenTree {
+        match self {
+            Self::Token(token, span) => OwnedTokenTree::Token(token.to_owned(), *span),
+            Self::Tree(children, span) => {
+                OwnedTokenTree::Tree(children.iter().map(Self::to_owned).collect(), *span)
+            }
+        }
+    }
+}
+
+impl Token<'_> {
+    /// 入力の文字列を借用しない [`OwnedToken`] に変換する
+    pub fn to_owned(&self) -> OwnedToken {
+        match *self {
+            Self::Ident(name) => OwnedToken::Ident(Symbol::intern(name)),
+            Self::Int(n) => OwnedToken::Int(n),
+            Self::Float(n) => OwnedToken::Float(n),
+            Self::StrLiteral(s) => OwnedToken::StrLiteral(s.to_string()),
+            Self::Bool(b) => OwnedToken::Bool(b),
+            Self::Nil => OwnedToken::Nil,
+            Self::LParen => OwnedToken::LParen,
+            Self::RParen => OwnedToken::RParen,
+            Self::LBrace => OwnedToken::LBrace,
+            Self::RBrace => OwnedToken::RBrace,
+            Self::LBracket => OwnedToken::LBracket,
+            Self::RBracket => OwnedToken::RBracket,
+            Self::Keyword(keyword) => OwnedToken::Keyword(keyword),
+            Self::Semicolon => OwnedToken::Semicolon,
+        }
+    }
+}
+
+/// 入力の文字列を借用しない [`Token`]
+///
+/// 各列挙子の意味は [`Token`] の同名の列挙子と同じ。
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedToken {
+    /// インターン済みの識別子
+    Ident(Symbol),
+    /// 整数リテラル
+    Int(i64),
+    /// 浮動小数点数リテラル
+    Float(f64),
+    /// 引用符の内側をそのまま保持した文字列リテラル
+    StrLiteral(String),
+    /// 真偽値のキーワード
+    Bool(bool),
+    /// キーワード `nil`
+    Nil,
+    /// 左括弧 `(`
+    LParen,
+    /// 右括弧 `)`
+    RParen,
+    /// 左波括弧 `{`
+    LBrace,
+    /// 右波括弧 `}`
+    RBrace,
+    /// 左角括弧 `[`
+    LBracket,
+    /// 右角括弧 `]`
+    RBracket,
+    /// 文の区切り `;`
+    Semicolon,
+    /// 中置記法の予約語
+    Keyword(Keyword),
+}
+
+/// 入力の文字列を借用しない [`TokenTree`]
+///
+/// [`TokenTree::to_owned`] で作る。
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedTokenTree {
+    /// 葉となる単一のトークンとその範囲
+    Token(OwnedToken, Span),
+    /// 括弧で囲まれた部分木とその範囲。範囲は括弧自体を含む
+    Tree(Vec<OwnedTokenTree>, Span),
+}
+
+impl OwnedTokenTree {
+    /// ノードが覆うソースコード上の範囲
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Token(_, span) | Self::Tree(_, span) => *span,
+        }
+    }
+}
+
+/// 評価の対象となる式の抽象構文木
+///
+/// 中置記法のパーサーとS式の木の両方から組み立てられる。
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    /// 式の種類
+    pub kind: ExprKind,
+    /// 式が覆うソースコード上の範囲
+    pub span: Span,
+}
+
+impl Expr {
+    /// 式を作る
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    /// 演算子の式なら、その演算子の優先順位
+    ///
+    /// 中置記法で書き出すとき、部分式の優先順位が親の演算子より低ければ括弧で囲む必要がある。
+    pub fn precedence(&self) -> Option<Precedence> {
+        match &self.kind {
+            ExprKind::BinaryOp { op, .. } => Some(op.precedence()),
+            ExprKind::UnaryOp { .. } => Some(Precedence::Prefix),
+            _ => None,
+        }
+    }
+}
+
+/// 式の種類
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    /// 整数リテラル
+    Int(i64),
+    /// 浮動小数点数リテラル
+    Float(f64),
+    /// エスケープシーケンスを展開済みの文字列リテラル
+    Str(String),
+    /// 真偽値リテラル
+    Bool(bool),
+    /// `nil` リテラル
+    Nil,
+    /// 変数や関数の名前
+    Ident(Symbol),
+    /// 二項演算
+    BinaryOp {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// 単項演算
+    UnaryOp { op: UnOp, operand: Box<Expr> },
+    /// 中置記法で優先順位を変えるために括弧で囲んだ式。値は中の式の値になる
+    ///
+    /// 関数呼び出しの括弧は [`ExprKind::Call`] に、`[...]` の並びは [`ExprKind::Array`] になる。
+    /// S式の括弧は常に特殊形式か関数呼び出しなので、この節にはならない。
+    Group(Box<Expr>),
+    /// 関数呼び出し
+    Call { func: Box<Expr>, args: Vec<Expr> },
+    /// 現在のスコープへの変数の定義。値は定義した値になる
+    Define { name: Symbol, value: Box<Expr> },
+    /// 仮引数と本体から関数を作る。本体は関数値の間で共有する
+    Fn {
+        params: Vec<Symbol>,
+        body: Rc<[Expr]>,
+        /// 型注釈。注釈の無い関数では `None`
+        signature: Option<Signature>,
+    },
+    /// 新しいスコープで変数を順に束縛してから本体を評価する
+    Let {
+        bindings: Vec<(Symbol, Expr)>,
+        body: Vec<Expr>,
+    },
+    /// 条件が真なら `then_branch`、偽なら `else_branch` を評価する
+    If {
+        cond: Box<Expr>,
+        then_branch: Box<Expr>,
+        else_branch: Option<Box<Expr>>,
+    },
+    /// 新しいスコープで文を順に評価する中置記法の `{ ... }`
+    Block(Vec<Statement>),
+    /// 条件が真の間、本体を繰り返す
+    While { cond: Box<Expr>, body: Box<Expr> },
+    /// `var` を `start` から `end` の手前まで1ずつ増やしながら本体を繰り返す
+    For {
+        var: Symbol,
+        start: Box<Expr>,
+        end: Box<Expr>,
+        body: Box<Expr>,
+    },
+    /// 一番内側のループを抜ける
+    Break,
+    /// 一番内側のループの次の繰り返しに進む
+    Continue,
+    /// 一番内側の関数から値を返す。値を省略すると `nil` を返す
+    Return(Option<Box<Expr>>),
+    /// 値に一致する最初の腕の本体を評価する
+    Match {
+        scrutinee: Box<Expr>,
+        arms: Vec<MatchArm>,
+    },
+    /// `[a, b, ...]` で要素を並べた配列
+    Array(Vec<Expr>),
+    /// `{ key: value, ... }` でキーと値の組を並べたマップ。`{:}` は空のマップ
+    Map(Vec<(Expr, Expr)>),
+    /// `import "path"` で読み込むモジュール
+    Import(String),
+    /// `target[index]` による配列の要素の参照
+    Index { target: Box<Expr>, index: Box<Expr> },
+    /// `(quote datum)` で引用したデータ。評価せずに値にする
+    Quote(OwnedTokenTree),
+    /// `(quasiquote template)` で引用したデータ。`(unquote expr)` の部分だけを評価して埋め込む
+    Quasiquote(Template),
+}
+
+/// 準引用 `(quasiquote ...)` の雛形
+#[derive(Debug, Clone, PartialEq)]
+pub enum Template {
+    /// `unquote` を含まず、そのまま値にするデータ
+    Datum(OwnedTokenTree),
+    /// `unquote` を含むので、要素ごとに組み立てるリストとその範囲
+    List(Vec<Template>, Span),
+    /// 評価した値を埋め込む `(unquote expr)` の式
+    Unquote(Box<Expr>),
+}
+
+/// 関数の仮引数と戻り値の型注釈
+///
+/// 評価には影響せず、[`typecheck`](crate::typecheck) が検査に使う。
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Signature {
+    /// 各仮引数の型。注釈を省略した仮引数は `None`
+    pub params: Vec<Option<TypeName>>,
+    /// 戻り値の型。注釈を省略した場合は `None`
+    pub ret: Option<TypeName>,
+}
+
+/// `match` の腕
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchArm {
+    /// 値と照合するパターン
+    pub pattern: Pattern,
+    /// パターンの範囲
+    pub pattern_span: Span,
+    /// `if` に続く条件。パターンに一致し、条件も真のときだけ腕を選ぶ
+    pub guard: Option<Expr>,
+    /// 腕を選んだときに評価する式
+    pub body: Expr,
+}
+
+impl MatchArm {
+    /// どの値にも必ず一致する腕かどうか
+    pub fn is_catch_all(&self) -> bool {
+        self.guard.is_none() && matches!(self.pattern, Pattern::Wildcard | Pattern::Binding(_))
+    }
+}
+
+/// `match` の腕のパターン
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// どの値にも一致する `_`
+    Wildcard,
+    /// どの値にも一致し、その値を本体と条件の中で名前に束縛する
+    Binding(Symbol),
+    /// `==` で等しい値に一致する整数
+    Int(i64),
+    /// `==` で等しい値に一致する浮動小数点数
+    Float(f64),
+    /// 等しい文字列に一致する、エスケープシーケンスを展開済みの文字列
+    Str(String),
+    /// 等しい真偽値に一致する
+    Bool(bool),
+    /// `nil` に一致する
+    Nil,
+}
+
+/// 中置記法で変数名に使えない予約語
+///
+/// `true`, `false`, `nil` はリテラルとして別のトークンになる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keyword {
+    If,
+    Else,
+    Fn,
+    Let,
+    Var,
+    While,
+    For,
+    In,
+    Break,
+    Continue,
+    Return,
+    Match,
+    Import,
+}
+
+impl Keyword {
+    /// 予約語の表から、名前に対応する予約語を得る
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "if" => Self::If,
+            "else" => Self::Else,
+            "fn" => Self::Fn,
+            "let" => Self::Let,
+            "var" => Self::Var,
+            "while" => Self::While,
+            "for" => Self::For,
+            "in" => Self::In,
+            "break" => Self::Break,
+            "continue" => Self::Continue,
+            "return" => Self::Return,
+            "match" => Self::Match,
+            "import" => Self::Import,
+            _ => return None,
+        })
+    }
+
+    /// ソースコードに書く名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::If => "if",
+            Self::Else => "else",
+            Self::Fn => "fn",
+            Self::Let => "let",
+            Self::Var => "var",
+            Self::While => "while",
+            Self::For => "for",
+            Self::In => "in",
+            Self::Break => "break",
+            Self::Continue => "continue",
+            Self::Return => "return",
+            Self::Match => "match",
+            Self::Import => "import",
+        }
+    }
+}
+
+/// 型注釈に書ける型の名前
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypeName {
+    /// `i64`
+    Int,
+    /// `f64`
+    Float,
+    /// `bool`
+    Bool,
+    /// `str`
+    Str,
+    /// `nil`
+    Nil,
+}
+
+impl TypeName {
+    /// 型注釈の名前から型を得る
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "i64" => Self::Int,
+            "f64" => Self::Float,
+            "bool" => Self::Bool,
+            "str" => Self::Str,
+            "nil" => Self::Nil,
+            _ => return None,
+        })
+    }
+
+    /// 型注釈に書く名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Int => "i64",
+            Self::Float => "f64",
+            Self::Bool => "bool",
+            Self::Str => "str",
+            Self::Nil => "nil",
+        }
+    }
+}
+
+/// 中置記法のプログラムを構成する文
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    /// `var name = value` による変数の定義
+    VarDef {
+        name: Symbol,
+        value: Expr,
+        span: Span,
+    },
+    /// `name = value` による定義済みの変数への代入
+    Assignment {
+        name: Symbol,
+        value: Expr,
+        span: Span,
+    },
+    /// `target[index] = value` による配列の要素への代入
+    IndexAssignment {
+        target: Expr,
+        index: Expr,
+        value: Expr,
+        span: Span,
+    },
+    /// 値を求めるだけの式文
+    Expr(Expr),
+}
+
+impl Statement {
+    /// 文が覆うソースコード上の範囲。区切りの `;` は含まない
+    pub fn span(&self) -> Span {
+        match self {
+            Self::VarDef { span, .. }
+            | Self::Assignment { span, .. }
+            | Self::IndexAssignment { span, .. } => *span,
+            Self::Expr(expr) => expr.span,
+        }
+    }
+}
+
+/// 二項演算子
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// 剰余 `%`。結果の符号は左辺と同じ
+    Rem,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// 短絡評価する論理積 `&&`
+    And,
+    /// 短絡評価する論理和 `||`
+    Or,
+}
+
+impl BinOp {
+    /// 演算子の記号から二項演算子を得る
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        Some(match symbol {
+            "+" => Self::Add,
+            "-" => Self::Sub,
+            "*" => Self::Mul,
+            "/" => Self::Div,
+            "%" => Self::Rem,
+            "<" => Self::Lt,
+            "<=" => Self::Le,
+            ">" => Self::Gt,
+            ">=" => Self::Ge,
+            "==" => Self::Eq,
+            "!=" => Self::Ne,
+            "&&" => Self::And,
+            "||" => Self::Or,
+            _ => return None,
+        })
+    }
+
+    /// 演算子の記号
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Rem => "%",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::And => "&&",
+            Self::Or => "||",
+        }
+    }
+
+    /// 演算子の優先順位
+    pub fn precedence(&self) -> Precedence {
+        match self {
+            Self::Or => Precedence::Or,
+            Self::And => Precedence::And,
+            Self::Eq | Self::Ne => Precedence::Equality,
+            Self::Lt | Self::Le | Self::Gt | Self::Ge => Precedence::Comparison,
+            Self::Add | Self::Sub => Precedence::Sum,
+            Self::Mul | Self::Div | Self::Rem => Precedence::Product,
+        }
+    }
+
+    /// 四則演算と剰余の演算子かどうか
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            Self::Add | Self::Sub | Self::Mul | Self::Div | Self::Rem
+        )
+    }
+
+    /// 真偽値を返す比較演算子かどうか
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            Self::Lt | Self::Le | Self::Gt | Self::Ge | Self::Eq | Self::Ne
+        )
+    }
+}
+
+/// 中置記法の演算子の優先順位
+///
+/// 後に並ぶ段階ほど強く結合し、順序の比較もその順になる。
+/// 二項演算子はどの段階でも左結合で、前置の単項演算子はどの二項演算子よりも強く結合する。
+///
+/// ```
+/// use ruscal_b::ast::{BinOp, Precedence};
+///
+/// assert!(BinOp::Mul.precedence() > BinOp::Add.precedence());
+/// assert_eq!(Precedence::Sum.operators(), [BinOp::Add, BinOp::Sub]);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Precedence {
+    /// `||`
+    Or,
+    /// `&&`
+    And,
+    /// `==` と `!=`
+    Equality,
+    /// `<`、`<=`、`>`、`>=`
+    Comparison,
+    /// `+` と `-`
+    Sum,
+    /// `*`、`/`、`%`
+    Product,
+    /// 前置の `-` と `!`
+    Prefix,
+}
+
+impl Precedence {
+    /// 弱く結合するものから順に並べたすべての段階
+    pub const ALL: [Self; 7] = [
+        Self::Or,
+        Self::And,
+        Self::Equality,
+        Self::Comparison,
+        Self::Sum,
+        Self::Product,
+        Self::Prefix,
+    ];
+
+    /// この段階の二項演算子。[`Precedence::Prefix`] なら空
+    pub fn operators(self) -> &'static [BinOp] {
+        match self {
+            Self::Or => &[BinOp::Or],
+            Self::And => &[BinOp::And],
+            Self::Equality => &[BinOp::Eq, BinOp::Ne],
+            Self::Comparison => &[BinOp::Lt, BinOp::Le, BinOp::Gt, BinOp::Ge],
+            Self::Sum => &[BinOp::Add, BinOp::Sub],
+            Self::Product => &[BinOp::Mul, BinOp::Div, BinOp::Rem],
+            Self::Prefix => &[],
+        }
+    }
+
+    /// 1つ強く結合する段階。最も強い段階ならそのまま返す
+    pub fn tighter(self) -> Self {
+        Self::ALL
+            .get(self as usize + 1)
+            .copied()
+            .unwrap_or(Self::Prefix)
+    }
+}
+
+/// 単項演算子
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    /// 符号反転
+    Neg,
+    /// 論理否定 `!`
+    Not,
+}
+
+impl UnOp {
+    /// 演算子の記号
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Neg => "-",
+            Self::Not => "!",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::source;
+
+    #[test]
+    fn test_to_owned() {
+        let owned = {
+            let input = String::from("(f \"s\" 1)");
+            source(&input).unwrap().to_owned()
+        };
+        let OwnedTokenTree::Tree(forms, _) = &owned else {
+            panic!("{owned:?}");
+        };
+        let OwnedTokenTree::Tree(items, span) = &forms[0] else {
+            panic!("{owned:?}");
+        };
+        assert_eq!(*span, Span::new(0, 9));
+        let tokens: Vec<_> = items
+            .iter()
+            .map(|item| match item {
+                OwnedTokenTree::Token(token, _) => token.clone(),
+                tree => panic!("{tree:?}"),
+            })
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                OwnedToken::Ident(Symbol::intern("f")),
+                OwnedToken::StrLiteral("s".to_string()),
+                OwnedToken::Int(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_precedence() {
+        // 各二項演算子は、自分の優先順位の段階にちょうど1回だけ現れる
+        for level in Precedence::ALL {
+            for op in level.operators() {
+                assert_eq!(op.precedence(), level);
+            }
+        }
+        let count: usize = Precedence::ALL.iter().map(|p| p.operators().len()).sum();
+        assert_eq!(count, 13);
+        assert!(Precedence::ALL
+            .windows(2)
+            .all(|w| w[0] < w[1] && w[0].tighter() == w[1]));
+        assert_eq!(Precedence::Prefix.tighter(), Precedence::Prefix);
+
+        let expr = crate::infix::parse_expr("-a * b").unwrap();
+        assert_eq!(expr.precedence(), Some(Precedence::Product));
+        let ExprKind::BinaryOp { lhs, .. } = &expr.kind else {
+            panic!("{expr:?}");
+        };
+        assert_eq!(lhs.precedence(), Some(Precedence::Prefix));
+        assert_eq!(crate::infix::parse_expr("f(1)").unwrap().precedence(), None);
+    }
+}
@@ -0,0 +1,14 @@
+//! 標準入出力で Language Server Protocol を話す `ruscal-lsp` バイナリ
+
+use std::io;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    match ruscal_b::lsp::serve(io::stdin().lock(), io::stdout().lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
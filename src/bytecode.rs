@@ -0,0 +1,789 @@
+//! `Expr` をスタックマシンの命令列にコンパイルするコンパイラ
+//!
+//! 命令列は [`Vm`](crate::vm::Vm) で実行する。木構造の評価器と同じ環境と値を使うので、
+//! 同じ式はどちらで評価しても同じ結果になる。
+
+use std::rc::Rc;
+
+use crate::ast::{BinOp, Expr, ExprKind, Pattern, Span, Statement, Template, UnOp};
+use crate::debug_info::LineTable;
+use crate::eval::{datum, pattern_value, Value};
+use crate::intern::Symbol;
+
+/// スタックマシンの命令
+///
+/// 命令はスタックの先頭の値を取り出して使い、結果をスタックに積む。
+/// ジャンプ先は同じ命令列の中の位置で表す。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    /// 定数表の値を積む
+    Constant(u32),
+    /// 先頭の値を捨てる
+    Pop,
+    /// 先頭の値を複製して積む
+    Dup,
+    /// 変数の値を積む
+    Load(Symbol),
+    /// 先頭の値で一番内側のスコープに変数を定義する。値はスタックに残す
+    Define(Symbol),
+    /// 先頭の値を定義済みの変数に代入する。値はスタックに残す
+    Assign(Symbol),
+    /// 先頭の値が数値であることを確かめる
+    ExpectNumber,
+    /// 先頭の値が整数であることを確かめる
+    ExpectInteger,
+    /// 演算子が変数で隠されていなければ、先頭の値が数値であることを確かめる
+    ExpectOperand(Symbol),
+    /// 2つの値を取り出して和を積む
+    Add,
+    /// 先頭の値を取り出し、定数表の数値との和を積む
+    ///
+    /// コンパイラは生成せず、[`peephole`](crate::peephole) が `Constant` と `Add` の並びをまとめて作る。
+    AddConstant(u32),
+    /// 2つの値を取り出して差を積む
+    Sub,
+    /// 2つの値を取り出して積を積む
+    Mul,
+    /// 2つの値を取り出して商を積む
+    Div,
+    /// 2つの値を取り出して剰余を積む
+    Rem,
+    /// 2つの値を取り出して `<` の結果を積む
+    Lt,
+    /// 2つの値を取り出して `<=` の結果を積む
+    Le,
+    /// 2つの値を取り出して `>` の結果を積む
+    Gt,
+    /// 2つの値を取り出して `>=` の結果を積む
+    Ge,
+    /// 2つの値を取り出して `==` の結果を積む
+    Eq,
+    /// 2つの値を取り出して `!=` の結果を積む
+    Ne,
+    /// 先頭の数値の符号を反転する
+    Neg,
+    /// 先頭の値の真偽を反転した真偽値にする
+    Not,
+    /// 先頭の値をその真偽を表す真偽値にする
+    Truthy,
+    /// 無条件にジャンプする
+    Jump(u32),
+    /// 先頭の値を取り出し、偽ならジャンプする
+    JumpIfFalse(u32),
+    /// `argc` 個の引数に組み込みの演算子を適用する。演算子が変数で隠されていればその値を呼び出す
+    Operator { name: Symbol, argc: u32 },
+    /// `argc` 個の引数の下にある値が関数であることを確かめる
+    ExpectFunction(u32),
+    /// `argc` 個の引数の下にある関数を呼び出す。`name` はエラーメッセージに使う
+    Call { name: Option<Symbol>, argc: u32 },
+    /// 関数表の関数から、現在の環境を捕捉した関数を作って積む
+    Closure(u32),
+    /// 新しい内側のスコープを開始する
+    PushScope,
+    /// 一番内側のスコープを終了する
+    PopScope,
+    /// `for` の繰り返しを1回進める
+    ///
+    /// スタックの先頭にある現在の値と終わりの値を見て、現在の値が終わりの値より小さければ
+    /// 現在の値を1増やしてから増やす前の値を積み、そうでなければジャンプする。
+    ForNext(u32),
+    /// ループの外で `break` や `continue` を使ったエラーにする
+    OutsideLoop(&'static str),
+    /// 関数の外で `return` を使ったエラーにする
+    OutsideFunction,
+    /// 先頭の値を取り出し、`match` のどの腕にも一致しなかったエラーにする
+    NoMatch,
+    /// 先頭から指定した数の値を取り出し、積んだ順に並べたリストを積む
+    List(u32),
+    /// 先頭から指定した数の値を取り出し、積んだ順に並べた配列を積む
+    Array(u32),
+    /// 先頭から指定した数のキーと値の組を取り出し、それらを持つマップを積む
+    Map(u32),
+    /// 先頭の値がマップのキーにできることを確かめる
+    ExpectKey,
+    /// 定数表の文字列のパスのファイルをモジュールとして読み込んで積む
+    Import(u32),
+    /// 位置と配列を取り出し、その位置の要素を積む
+    Index,
+    /// 値と位置と配列を取り出し、その位置の要素を値に書き換えてから値を積む
+    SetIndex,
+    /// 先頭の値を戻り値として命令列の実行を終える
+    Return,
+}
+
+impl Instruction {
+    /// 二項演算子に対応する命令
+    ///
+    /// 短絡評価する `&&` と `||` はジャンプで表すので対応する命令は無い。
+    pub fn binary(op: BinOp) -> Option<Self> {
+        Some(match op {
+            BinOp::Add => Self::Add,
+            BinOp::Sub => Self::Sub,
+            BinOp::Mul => Self::Mul,
+            BinOp::Div => Self::Div,
+            BinOp::Rem => Self::Rem,
+            BinOp::Lt => Self::Lt,
+            BinOp::Le => Self::Le,
+            BinOp::Gt => Self::Gt,
+            BinOp::Ge => Self::Ge,
+            BinOp::Eq => Self::Eq,
+            BinOp::Ne => Self::Ne,
+            BinOp::And | BinOp::Or => return None,
+        })
+    }
+
+    /// 二項演算の命令が表す演算子
+    pub fn binary_op(self) -> Option<BinOp> {
+        Some(match self {
+            Self::Add => BinOp::Add,
+            Self::Sub => BinOp::Sub,
+            Self::Mul => BinOp::Mul,
+            Self::Div => BinOp::Div,
+            Self::Rem => BinOp::Rem,
+            Self::Lt => BinOp::Lt,
+            Self::Le => BinOp::Le,
+            Self::Gt => BinOp::Gt,
+            Self::Ge => BinOp::Ge,
+            Self::Eq => BinOp::Eq,
+            Self::Ne => BinOp::Ne,
+            _ => return None,
+        })
+    }
+
+    /// 命令を実行したときのスタックの深さの変化
+    ///
+    /// ジャンプしない場合の変化を返す。
+    pub(crate) fn stack_effect(self) -> isize {
+        match self {
+            Self::Constant(_)
+            | Self::Dup
+            | Self::Load(_)
+            | Self::Closure(_)
+            | Self::ForNext(_)
+            | Self::OutsideLoop(_)
+            | Self::OutsideFunction
+            | Self::Import(_) => 1,
+            Self::Define(_)
+            | Self::Assign(_)
+            | Self::ExpectNumber
+            | Self::ExpectInteger
+            | Self::ExpectOperand(_)
+            | Self::Neg
+            | Self::Not
+            | Self::Truthy
+            | Self::Jump(_)
+            | Self::ExpectFunction(_)
+            | Self::PushScope
+            | Self::PopScope
+            | Self::NoMatch
+            | Self::ExpectKey
+            | Self::AddConstant(_) => 0,
+            Self::Operator { argc, .. } => 1 - argc as isize,
+            Self::Call { argc, .. } => -(argc as isize),
+            Self::List(len) | Self::Array(len) => 1 - len as isize,
+            Self::Map(len) => 1 - 2 * len as isize,
+            Self::SetIndex => -2,
+            _ => -1,
+        }
+    }
+}
+
+/// コンパイル済みの命令列
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bytecode {
+    /// 実行する命令
+    pub code: Vec<Instruction>,
+    /// 各命令を生成した式の範囲。実行時のエラーの位置に使う
+    pub spans: Vec<Span>,
+    /// [`Instruction::Constant`] が参照する定数表
+    pub constants: Vec<Value>,
+    /// [`Instruction::Closure`] が参照する関数表
+    pub functions: Vec<Rc<Prototype>>,
+    /// 命令の位置とソースコードの行と桁の対応表。[`Bytecode::attach_lines`] で付けるまでは空
+    pub lines: LineTable,
+}
+
+/// `fn` 式をコンパイルした、環境を捕捉する前の関数
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prototype {
+    /// 仮引数の名前
+    pub params: Vec<Symbol>,
+    /// 関数の本体の命令列
+    pub code: Rc<Bytecode>,
+    /// 関数を定義した `fn` 式の範囲
+    pub span: Span,
+}
+
+/// 式を命令列にコンパイルする関数
+///
+/// 命令列は式の値を戻り値として終わる。木構造の評価器と同じく、
+/// ループの外の `break` や `continue` は実行したときにエラーになる。
+///
+/// # 引数
+/// * `ast` - コンパイルする式
+///
+/// # 戻り値
+/// * `Bytecode` - 式を評価する命令列
+pub fn compile(ast: &Expr) -> Bytecode {
+    let mut compiler = Compiler::default();
+    compiler.expr(ast);
+    compiler.emit(Instruction::Return, ast.span);
+    compiler.bytecode
+}
+
+/// 最上位の式の並びを1つの命令列にコンパイルする関数
+///
+/// 式は実行に使う環境の中で順に評価される。
+///
+/// # 引数
+/// * `exprs` - コンパイルする式の並び
+///
+/// # 戻り値
+/// * `Bytecode` - 最後の式の値を返す命令列。式が無ければ `nil` を返す
+pub fn compile_program(exprs: &[Expr]) -> Bytecode {
+    let mut compiler = Compiler::default();
+    let span = match (exprs.first(), exprs.last()) {
+        (Some(first), Some(last)) => first.span.merge(last.span),
+        _ => Span::default(),
+    };
+    if exprs.is_empty() {
+        compiler.constant(Value::Nil, span);
+    } else {
+        compiler.body(exprs);
+    }
+    compiler.emit(Instruction::Return, span);
+    compiler.bytecode
+}
+
+/// 関数の本体をコンパイルする関数
+fn compile_body(body: &[Expr], span: Span) -> Bytecode {
+    let mut compiler = Compiler {
+        in_function: true,
+        ..Compiler::default()
+    };
+    compiler.body(body);
+    compiler.emit(Instruction::Return, span);
+    compiler.bytecode
+}
+
+/// コンパイル中のループ
+struct Loop {
+    /// ループに入ったときのスタックの深さ。`break` はここまで値を捨てる
+    depth: usize,
+    /// ループに入ったときのスコープの深さ
+    scopes: usize,
+    /// `continue` で戻るときのスタックとスコープの深さ
+    continue_depth: (usize, usize),
+    /// `continue` のジャンプ先。後から決まる場合は `None`
+    continue_target: Option<u32>,
+    /// ジャンプ先を後から埋める `continue` の位置
+    continues: Vec<usize>,
+    /// ジャンプ先を後から埋める `break` の位置
+    breaks: Vec<usize>,
+}
+
+/// 1つの命令列を組み立てるコンパイラ
+#[derive(Default)]
+struct Compiler {
+    bytecode: Bytecode,
+    /// 現在のスタックの深さ
+    depth: usize,
+    /// 現在のスコープの深さ
+    scopes: usize,
+    loops: Vec<Loop>,
+    /// 関数の本体をコンパイルしているか。`return` を使えるのは関数の本体の中だけ
+    in_function: bool,
+}
+
+impl Compiler {
+    /// 命令を追加し、その位置を返す
+    fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.depth = self
+            .depth
+            .checked_add_signed(instruction.stack_effect())
+            .expect("the compiler keeps the stack balanced");
+        match instruction {
+            Instruction::PushScope => self.scopes += 1,
+            Instruction::PopScope => self.scopes -= 1,
+            _ => {}
+        }
+        self.bytecode.code.push(instruction);
+        self.bytecode.spans.push(span);
+        self.bytecode.code.len() - 1
+    }
+
+    /// 次に追加する命令の位置
+    fn here(&self) -> u32 {
+        self.bytecode.code.len() as u32
+    }
+
+    /// 追加済みのジャンプ命令のジャンプ先を次の命令にする
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        match &mut self.bytecode.code[at] {
+            Instruction::Jump(to) | Instruction::JumpIfFalse(to) | Instruction::ForNext(to) => {
+                *to = target
+            }
+            instruction => unreachable!("{instruction:?} is not a jump"),
+        }
+    }
+
+    fn constant(&mut self, value: Value, span: Span) {
+        let index = self.bytecode.constants.len() as u32;
+        self.bytecode.constants.push(value);
+        self.emit(Instruction::Constant(index), span);
+    }
+
+    /// 式の値を1つ積む命令を追加する
+    fn expr(&mut self, expr: &Expr) {
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Int(n) => self.constant(Value::I64(*n), span),
+            ExprKind::Float(n) => self.constant(Value::F64(*n), span),
+            ExprKind::Bool(b) => self.constant(Value::Bool(*b), span),
+            ExprKind::Nil => self.constant(Value::Nil, span),
+            ExprKind::Str(s) => self.constant(Value::Str(s.as_str().into()), span),
+            ExprKind::Ident(name) => {
+                self.emit(Instruction::Load(*name), span);
+            }
+            ExprKind::Group(inner) => self.expr(inner),
+            ExprKind::BinaryOp {
+                op: op @ (BinOp::And | BinOp::Or),
+                lhs,
+                rhs,
+            } => {
+                // 左辺で結果が決まれば、その真偽値を残して右辺を飛ばす
+                self.expr(lhs);
+                self.emit(Instruction::Truthy, lhs.span);
+                self.emit(Instruction::Dup, span);
+                if *op == BinOp::Or {
+                    self.emit(Instruction::Not, span);
+                }
+                let skip = self.emit(Instruction::JumpIfFalse(0), span);
+                self.emit(Instruction::Pop, span);
+                self.expr(rhs);
+                self.emit(Instruction::Truthy, rhs.span);
+                self.patch(skip);
+            }
+            ExprKind::BinaryOp {
+                op: op @ (BinOp::Eq | BinOp::Ne),
+                lhs,
+                rhs,
+            } => {
+                self.expr(lhs);
+                self.expr(rhs);
+                self.emit(Instruction::binary(*op).unwrap(), span);
+            }
+            ExprKind::BinaryOp { op, lhs, rhs } => {
+                self.expr(lhs);
+                self.emit(Instruction::ExpectNumber, lhs.span);
+                self.expr(rhs);
+                self.emit(Instruction::ExpectNumber, rhs.span);
+                self.emit(Instruction::binary(*op).unwrap(), span);
+            }
+            ExprKind::UnaryOp {
+                op: UnOp::Neg,
+                operand,
+            } => {
+                self.expr(operand);
+                self.emit(Instruction::ExpectNumber, operand.span);
+                self.emit(Instruction::Neg, span);
+            }
+            ExprKind::UnaryOp {
+                op: UnOp::Not,
+                operand,
+            } => {
+                self.expr(operand);
+                self.emit(Instruction::Not, span);
+            }
+            ExprKind::Call { func, args } => match &func.kind {
+                ExprKind::Ident(name) if BinOp::from_symbol(name.as_str()).is_some() => {
+                    for arg in args {
+                        self.expr(arg);
+                        self.emit(Instruction::ExpectOperand(*name), arg.span);
+                    }
+                    let argc = args.len() as u32;
+                    self.emit(Instruction::Operator { name: *name, argc }, span);
+                }
+                _ => {
+                    self.expr(func);
+                    for arg in args {
+                        self.expr(arg);
+                    }
+                    let argc = args.len() as u32;
+                    let name = match &func.kind {
+                        ExprKind::Ident(name) => Some(*name),
+                        _ => None,
+                    };
+                    self.emit(Instruction::ExpectFunction(argc), func.span);
+                    self.emit(Instruction::Call { name, argc }, span);
+                }
+            },
+            ExprKind::Define { name, value } => {
+                self.expr(value);
+                self.emit(Instruction::Define(*name), span);
+            }
+            ExprKind::Let { bindings, body } => {
+                self.emit(Instruction::PushScope, span);
+                for (name, value) in bindings {
+                    self.expr(value);
+                    self.emit(Instruction::Define(*name), value.span);
+                    self.emit(Instruction::Pop, value.span);
+                }
+                self.body(body);
+                self.emit(Instruction::PopScope, span);
+            }
+            ExprKind::Fn { params, body, .. } => {
+                let index = self.bytecode.functions.len() as u32;
+                self.bytecode.functions.push(Rc::new(Prototype {
+                    params: params.clone(),
+                    code: Rc::new(compile_body(body, span)),
+                    span,
+                }));
+                self.emit(Instruction::Closure(index), span);
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(cond);
+                let to_else = self.emit(Instruction::JumpIfFalse(0), span);
+                self.expr(then_branch);
+                let to_end = self.emit(Instruction::Jump(0), span);
+                // 実行されるのはどちらか一方の枝なので、深さを分岐前に戻す
+                self.depth -= 1;
+                self.patch(to_else);
+                match else_branch {
+                    Some(else_branch) => self.expr(else_branch),
+                    None => self.constant(Value::Nil, span),
+                }
+                self.patch(to_end);
+            }
+            ExprKind::Block(statements) => {
+                self.emit(Instruction::PushScope, span);
+                self.statements(statements, span);
+                self.emit(Instruction::PopScope, span);
+            }
+            ExprKind::While { cond, body } => {
+                let head = self.here();
+                self.expr(cond);
+                let exit = self.emit(Instruction::JumpIfFalse(0), span);
+                self.loops.push(Loop {
+                    depth: self.depth,
+                    scopes: self.scopes,
+                    continue_depth: (self.depth, self.scopes),
+                    continue_target: Some(head),
+                    continues: vec![],
+                    breaks: vec![],
+                });
+                self.expr(body);
+                self.emit(Instruction::Pop, span);
+                self.emit(Instruction::Jump(head), span);
+                let lp = self.loops.pop().expect("the loop was pushed above");
+                self.patch(exit);
+                for at in lp.breaks {
+                    self.patch(at);
+                }
+                self.constant(Value::Nil, span);
+            }
+            ExprKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                self.expr(start);
+                self.emit(Instruction::ExpectInteger, start.span);
+                self.expr(end);
+                self.emit(Instruction::ExpectInteger, end.span);
+                let depth = self.depth;
+                let head = self.here();
+                let exit = self.emit(Instruction::ForNext(0), span);
+                // 繰り返しごとにスコープを作り、クロージャがその回の値を捕捉できるようにする
+                self.emit(Instruction::PushScope, span);
+                self.emit(Instruction::Define(*var), span);
+                self.emit(Instruction::Pop, span);
+                self.loops.push(Loop {
+                    depth,
+                    scopes: self.scopes - 1,
+                    continue_depth: (self.depth, self.scopes),
+                    continue_target: None,
+                    continues: vec![],
+                    breaks: vec![],
+                });
+                self.expr(body);
+                self.emit(Instruction::Pop, span);
+                let lp = self.loops.pop().expect("the loop was pushed above");
+                for at in lp.continues {
+                    self.patch(at);
+                }
+                self.emit(Instruction::PopScope, span);
+                self.emit(Instruction::Jump(head), span);
+                self.patch(exit);
+                for at in lp.breaks {
+                    self.patch(at);
+                }
+                self.depth = depth;
+                self.emit(Instruction::Pop, span);
+                self.emit(Instruction::Pop, span);
+                self.constant(Value::Nil, span);
+            }
+            ExprKind::Break => self.jump_out(true, span),
+            ExprKind::Continue => self.jump_out(false, span),
+            ExprKind::Return(value) => {
+                let before = (self.depth, self.scopes);
+                if !self.in_function {
+                    self.emit(Instruction::OutsideFunction, span);
+                    return;
+                }
+                match value {
+                    Some(value) => self.expr(value),
+                    None => self.constant(Value::Nil, span),
+                }
+                // 積んだ値と開始したスコープは関数から戻るときにまとめて捨てる
+                self.emit(Instruction::Return, span);
+                self.depth = before.0 + 1;
+                self.scopes = before.1;
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                // 照合する値は、ソースコードからは参照できない名前の変数に置いて各腕から読む
+                let value = Symbol::intern("match value");
+                self.emit(Instruction::PushScope, span);
+                self.expr(scrutinee);
+                self.emit(Instruction::Define(value), span);
+                self.emit(Instruction::Pop, span);
+                let mut ends = vec![];
+                for arm in arms {
+                    let at = arm.pattern_span;
+                    let mismatch = pattern_value(&arm.pattern).map(|literal| {
+                        self.emit(Instruction::Load(value), at);
+                        self.constant(literal, at);
+                        self.emit(Instruction::Eq, at);
+                        self.emit(Instruction::JumpIfFalse(0), at)
+                    });
+                    self.emit(Instruction::PushScope, at);
+                    if let Pattern::Binding(name) = arm.pattern {
+                        self.emit(Instruction::Load(value), at);
+                        self.emit(Instruction::Define(name), at);
+                        self.emit(Instruction::Pop, at);
+                    }
+                    let rejected = arm.guard.as_ref().map(|guard| {
+                        self.expr(guard);
+                        self.emit(Instruction::JumpIfFalse(0), guard.span)
+                    });
+                    self.expr(&arm.body);
+                    self.emit(Instruction::PopScope, span);
+                    ends.push(self.emit(Instruction::Jump(0), span));
+                    // 腕を選ばなかった経路では本体の値を積んでいない
+                    self.depth -= 1;
+                    if let Some(rejected) = rejected {
+                        // 条件が偽になった経路では、腕のスコープがまだ開いている
+                        self.scopes += 1;
+                        self.patch(rejected);
+                        self.emit(Instruction::PopScope, at);
+                    }
+                    if let Some(mismatch) = mismatch {
+                        self.patch(mismatch);
+                    }
+                }
+                self.emit(Instruction::Load(value), span);
+                self.emit(Instruction::NoMatch, span);
+                for at in ends {
+                    self.patch(at);
+                }
+                self.emit(Instruction::PopScope, span);
+            }
+            ExprKind::Array(items) => {
+                for item in items {
+                    self.expr(item);
+                }
+                self.emit(Instruction::Array(items.len() as u32), span);
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.emit(Instruction::ExpectKey, key.span);
+                    self.expr(value);
+                }
+                self.emit(Instruction::Map(entries.len() as u32), span);
+            }
+            ExprKind::Import(path) => {
+                let index = self.bytecode.constants.len() as u32;
+                self.bytecode
+                    .constants
+                    .push(Value::Str(path.as_str().into()));
+                self.emit(Instruction::Import(index), span);
+            }
+            ExprKind::Index { target, index } => {
+                self.expr(target);
+                self.expr(index);
+                self.emit(Instruction::Index, span);
+            }
+            ExprKind::Quote(tree) => self.constant(datum(tree), span),
+            ExprKind::Quasiquote(template) => self.template(template, span),
+        }
+    }
+
+    /// 準引用の雛形から値を組み立てる命令を追加する
+    fn template(&mut self, template: &Template, span: Span) {
+        match template {
+            Template::Datum(tree) => self.constant(datum(tree), span),
+            Template::List(items, span) => {
+                for item in items {
+                    self.template(item, *span);
+                }
+                self.emit(Instruction::List(items.len() as u32), *span);
+            }
+            Template::Unquote(expr) => self.expr(expr),
+        }
+    }
+
+    /// `break` か `continue` の命令を追加する
+    ///
+    /// ループの内側で積んだ値と開始したスコープを片付けてからジャンプする。
+    fn jump_out(&mut self, is_break: bool, span: Span) {
+        let keyword = if is_break { "break" } else { "continue" };
+        let Some(lp) = self.loops.last() else {
+            self.emit(Instruction::OutsideLoop(keyword), span);
+            return;
+        };
+        let (depth, scopes) = if is_break {
+            (lp.depth, lp.scopes)
+        } else {
+            lp.continue_depth
+        };
+        let target = if is_break { None } else { lp.continue_target };
+        let before = (self.depth, self.scopes);
+        for _ in depth..self.depth {
+            self.emit(Instruction::Pop, span);
+        }
+        for _ in scopes..self.scopes {
+            self.emit(Instruction::PopScope, span);
+        }
+        let at = self.emit(Instruction::Jump(target.unwrap_or(0)), span);
+        let lp = self.loops.last_mut().expect("checked above");
+        if is_break {
+            lp.breaks.push(at);
+        } else if target.is_none() {
+            lp.continues.push(at);
+        }
+        // ジャンプの後には進まないが、式として値を1つ積んだものとして続ける
+        self.depth = before.0 + 1;
+        self.scopes = before.1;
+    }
+
+    /// 空でない式の並びを評価し、最後の式の値だけを残す命令を追加する
+    fn body(&mut self, body: &[Expr]) {
+        for (i, expr) in body.iter().enumerate() {
+            if i > 0 {
+                self.emit(Instruction::Pop, expr.span);
+            }
+            self.expr(expr);
+        }
+    }
+
+    /// 文の並びを評価し、最後の文の値を残す命令を追加する。文が無ければ `nil` を残す
+    fn statements(&mut self, statements: &[Statement], span: Span) {
+        if statements.is_empty() {
+            self.constant(Value::Nil, span);
+        }
+        for (i, statement) in statements.iter().enumerate() {
+            if i > 0 {
+                self.emit(Instruction::Pop, statement.span());
+            }
+            match statement {
+                Statement::VarDef { name, value, span } => {
+                    self.expr(value);
+                    self.emit(Instruction::Define(*name), *span);
+                }
+                Statement::Assignment { name, value, span } => {
+                    self.expr(value);
+                    self.emit(Instruction::Assign(*name), *span);
+                }
+                Statement::IndexAssignment {
+                    target,
+                    index,
+                    value,
+                    span,
+                } => {
+                    self.expr(target);
+                    self.expr(index);
+                    self.expr(value);
+                    self.emit(Instruction::SetIndex, *span);
+                }
+                Statement::Expr(expr) => self.expr(expr),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::lower;
+    use crate::parser::source;
+    use crate::TokenTree;
+
+    fn compile_str(input: &str) -> Bytecode {
+        let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+            panic!("failed to parse {input:?}");
+        };
+        compile(&lower(&forms[0]).unwrap())
+    }
+
+    #[test]
+    fn test_compile() {
+        let bytecode = compile_str("(* 2 (+ 1 x))");
+        assert_eq!(
+            bytecode.code,
+            [
+                Instruction::Constant(0),
+                Instruction::ExpectNumber,
+                Instruction::Constant(1),
+                Instruction::ExpectNumber,
+                Instruction::Load(Symbol::intern("x")),
+                Instruction::ExpectNumber,
+                Instruction::Add,
+                Instruction::ExpectNumber,
+                Instruction::Mul,
+                Instruction::Return,
+            ]
+        );
+        assert_eq!(bytecode.constants, [Value::I64(2), Value::I64(1)]);
+        assert_eq!(bytecode.spans.len(), bytecode.code.len());
+
+        // 二項演算に変換されない演算子は、実行時に変数で隠されているかを確かめる
+        let add = Symbol::intern("+");
+        assert_eq!(
+            compile_str("(+ 1)").code,
+            [
+                Instruction::Constant(0),
+                Instruction::ExpectOperand(add),
+                Instruction::Operator { name: add, argc: 1 },
+                Instruction::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_function() {
+        let bytecode = compile_str("(fn (x) (define y x) y)");
+        assert_eq!(
+            bytecode.code,
+            [Instruction::Closure(0), Instruction::Return]
+        );
+        let function = &bytecode.functions[0];
+        assert_eq!(function.params, [Symbol::intern("x")]);
+        assert_eq!(
+            function.code.code,
+            [
+                Instruction::Load(Symbol::intern("x")),
+                Instruction::Define(Symbol::intern("y")),
+                Instruction::Pop,
+                Instruction::Load(Symbol::intern("y")),
+                Instruction::Return,
+            ]
+        );
+    }
+}
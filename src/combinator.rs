@@ -0,0 +1,212 @@
+//! 小さな規則を組み合わせて文法を書くためのパーサーコンビネーター
+//!
+//! 入力の先頭から何かを読み、読めれば `Some((残りの入力, 結果))` を、読めなければ `None` を返す関数を
+//! [`ParseFn`] として扱う。[`and_then`]、[`or`]、[`many0`]、[`delimited`] で組み合わせれば、
+//! 新しい規則ごとに残りの入力を受け渡す処理を書かずに済む。
+//!
+//! ```
+//! use ruscal_b::combinator::{delimited, many0, tag, take_while1, ParseFn};
+//!
+//! let spaces = || many0(tag(" "));
+//! let word = delimited(spaces(), take_while1(char::is_alphanumeric), spaces());
+//! let list = delimited(tag("("), many0(word), tag(")"));
+//! assert_eq!(list.parse("( a 1 ) b"), Some((" b", vec!["a", "1"])));
+//! assert_eq!(list.parse("(a"), None);
+//! ```
+
+use crate::ast::Token;
+use crate::lexer;
+
+/// 入力の先頭から値を1つ読む規則
+///
+/// `Fn(&str) -> Option<(&str, T)>` の関数とクロージャはすべてこのトレイトを実装する。
+pub trait ParseFn<'src, T> {
+    /// 入力の先頭を読む
+    ///
+    /// # 戻り値
+    /// * `Option<(&str, T)>` - (残りの入力, 読んだ値)のタプル。読めなければ `None`
+    fn parse(&self, input: &'src str) -> Option<(&'src str, T)>;
+}
+
+impl<'src, T, F> ParseFn<'src, T> for F
+where
+    F: Fn(&'src str) -> Option<(&'src str, T)>,
+{
+    fn parse(&self, input: &'src str) -> Option<(&'src str, T)> {
+        self(input)
+    }
+}
+
+/// 入力が `prefix` で始まっていれば、その部分を読む規則
+pub fn tag<'src>(prefix: &'static str) -> impl ParseFn<'src, &'src str> {
+    move |input: &'src str| {
+        let (matched, rest) = input.split_at_checked(prefix.len())?;
+        (matched == prefix).then_some((rest, matched))
+    }
+}
+
+/// 条件を満たす文字が続く部分を読む規則。1文字も無ければ空文字列を読む
+pub fn take_while<'src>(pred: impl Fn(char) -> bool) -> impl ParseFn<'src, &'src str> {
+    move |input: &'src str| {
+        let end = input.find(|c| !pred(c)).unwrap_or(input.len());
+        let (matched, rest) = input.split_at_checked(end)?;
+        Some((rest, matched))
+    }
+}
+
+/// 条件を満たす文字が1つ以上続く部分を読む規則
+pub fn take_while1<'src>(pred: impl Fn(char) -> bool) -> impl ParseFn<'src, &'src str> {
+    move |input: &'src str| {
+        let end = input.find(|c| !pred(c)).unwrap_or(input.len());
+        let (matched, rest) = input.split_at_checked(end)?;
+        (end > 0).then_some((rest, matched))
+    }
+}
+
+/// 条件を満たす1文字を読む規則
+pub fn satisfy<'src>(pred: impl Fn(char) -> bool) -> impl ParseFn<'src, char> {
+    move |input: &'src str| {
+        let c = input.chars().next().filter(|c| pred(*c))?;
+        Some((input.get(c.len_utf8()..)?, c))
+    }
+}
+
+/// 字句解析器でトークンを1つ読む規則。前にある空白とコメントは読み飛ばす
+pub fn token<'src>() -> impl ParseFn<'src, Token<'src>> {
+    |input: &'src str| {
+        let (rest, _, token) = lexer::token(input, 0).ok()?;
+        Some((rest, token))
+    }
+}
+
+/// 読んだ値を `f` で変換する規則
+pub fn map<'src, A, B>(p: impl ParseFn<'src, A>, f: impl Fn(A) -> B) -> impl ParseFn<'src, B> {
+    move |input: &'src str| {
+        let (rest, value) = p.parse(input)?;
+        Some((rest, f(value)))
+    }
+}
+
+/// `p` の後に続けて `q` を読み、両方の値を組にする規則
+pub fn and_then<'src, A, B>(
+    p: impl ParseFn<'src, A>,
+    q: impl ParseFn<'src, B>,
+) -> impl ParseFn<'src, (A, B)> {
+    move |input: &'src str| {
+        let (rest, a) = p.parse(input)?;
+        let (rest, b) = q.parse(rest)?;
+        Some((rest, (a, b)))
+    }
+}
+
+/// `p` を試し、読めなければ同じ位置から `q` を試す規則
+pub fn or<'src, T>(p: impl ParseFn<'src, T>, q: impl ParseFn<'src, T>) -> impl ParseFn<'src, T> {
+    move |input: &'src str| p.parse(input).or_else(|| q.parse(input))
+}
+
+/// `p` を試し、読めなければ何も読まずに `None` を値とする規則
+pub fn opt<'src, T>(p: impl ParseFn<'src, T>) -> impl ParseFn<'src, Option<T>> {
+    move |input: &'src str| match p.parse(input) {
+        Some((rest, value)) => Some((rest, Some(value))),
+        None => Some((input, None)),
+    }
+}
+
+/// `p` が読んだ部分の入力を値とする規則
+pub fn recognize<'src, T>(p: impl ParseFn<'src, T>) -> impl ParseFn<'src, &'src str> {
+    move |input: &'src str| {
+        let (rest, _) = p.parse(input)?;
+        Some((rest, input.get(..input.len().checked_sub(rest.len())?)?))
+    }
+}
+
+/// `p` を読めなくなるまで繰り返し読む規則。1回も読めなくても空の並びを返す
+///
+/// 入力を読み進めずに成功した場合も、無限に繰り返さないようにそこで止める。
+pub fn many0<'src, T>(p: impl ParseFn<'src, T>) -> impl ParseFn<'src, Vec<T>> {
+    move |mut input: &'src str| {
+        let mut items = vec![];
+        while let Some((rest, item)) = p.parse(input) {
+            if rest.len() == input.len() {
+                break;
+            }
+            items.push(item);
+            input = rest;
+        }
+        Some((input, items))
+    }
+}
+
+/// `open`、`p`、`close` の順に読み、`p` の値だけを返す規則
+pub fn delimited<'src, A, T, B>(
+    open: impl ParseFn<'src, A>,
+    p: impl ParseFn<'src, T>,
+    close: impl ParseFn<'src, B>,
+) -> impl ParseFn<'src, T> {
+    map(and_then(and_then(open, p), close), |((_, value), _)| value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_primitives() {
+        assert_eq!(tag("ab").parse("abc"), Some(("c", "ab")));
+        assert_eq!(tag("ab").parse("ac"), None);
+        let digits = take_while1(|c| c.is_ascii_digit());
+        assert_eq!(digits.parse("12a"), Some(("a", "12")));
+        assert_eq!(digits.parse("a"), None);
+        assert_eq!(take_while(|c| c == 'a').parse("b"), Some(("b", "")));
+        assert_eq!(
+            satisfy(char::is_alphabetic).parse("あい"),
+            Some(("い", 'あ'))
+        );
+        assert_eq!(satisfy(char::is_alphabetic).parse("1"), None);
+        assert_eq!(
+            token().parse("  \"s\" x"),
+            Some((" x", Token::StrLiteral("s")))
+        );
+        assert_eq!(token().parse("\"s"), None);
+    }
+
+    #[test]
+    fn test_combinators() {
+        let digits = || take_while1(|c| c.is_ascii_digit());
+        let number = map(digits(), |s: &str| s.parse::<i64>().unwrap());
+        let pair = and_then(number, and_then(tag(","), digits()));
+        assert_eq!(pair.parse("1,23!"), Some(("!", (1, (",", "23")))));
+        assert_eq!(pair.parse("1;23"), None);
+
+        let sign = or(tag("+"), tag("-"));
+        assert_eq!(sign.parse("-1"), Some(("1", "-")));
+        assert_eq!(sign.parse("*1"), None);
+
+        let bracketed = delimited(tag("["), many0(or(digits(), tag(" "))), tag("]"));
+        assert_eq!(bracketed.parse("[1 2]."), Some((".", vec!["1", " ", "2"])));
+        assert_eq!(bracketed.parse("[1 2"), None);
+
+        let signed = recognize(and_then(opt(sign), digits()));
+        assert_eq!(signed.parse("-12;"), Some((";", "-12")));
+        assert_eq!(signed.parse("12;"), Some((";", "12")));
+    }
+
+    #[test]
+    fn test_many0() {
+        assert_eq!(many0(tag("a")).parse("aab"), Some(("b", vec!["a", "a"])));
+        assert_eq!(many0(tag("a")).parse("b"), Some(("b", vec![])));
+        // 何も読まずに成功する規則でも止まる
+        assert_eq!(many0(tag("")).parse("b"), Some(("b", vec![])));
+    }
+
+    #[test]
+    fn test_char_boundaries() {
+        assert_eq!(tag("é").parse("éa"), Some(("a", "é")));
+        // `"é"` の1バイト目だけを読むような規則は作れない
+        assert_eq!(tag("e").parse("é"), None);
+        assert_eq!(take_while(|c| c != 'い').parse("あい"), Some(("い", "あ")));
+        // 入力より長い残りを返す誤った規則を渡しても、パニックせずに読めなかったことにする
+        let longer = |_: &str| Some(("longer than input", ()));
+        assert_eq!(recognize(longer).parse("a"), None);
+    }
+}
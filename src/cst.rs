@@ -0,0 +1,326 @@
+//! 空白とコメントも失わずに持つ具象構文木
+//!
+//! [`TokenTree`](crate::TokenTree) は意味に関わらない空白やコメントを捨てるが、
+//! [`Cst`] は各トークンの前にある空白とコメントを [`Trivia`] としてトークンに付けて持つ。
+//! 入力の終わりの直前にある空白とコメントは [`Cst::trailing`] に入る。
+//! [`Cst`] を `Display` で書き出すと、元の入力とバイト単位で同じ文字列になる。
+//!
+//! ```
+//! use ruscal_b::cst::Cst;
+//!
+//! let input = "(define x /* one */ 1) // x\n";
+//! let cst = Cst::parse(input).unwrap();
+//! assert_eq!(cst.to_string(), input);
+//! ```
+
+use std::fmt;
+
+use crate::ast::{Span, Token};
+use crate::lexer::{split_trivia, Lexer, TriviaKind};
+use crate::parser::{quote_form, source_with_options, ParseError, ParserOptions, MAX_DEPTH};
+
+/// トークンの前にある空白やコメントの1区切り
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trivia<'src> {
+    /// 区切りの種類
+    pub kind: TriviaKind,
+    /// 区切りの文字列
+    pub text: &'src str,
+    /// 区切りの範囲
+    pub span: Span,
+}
+
+/// 前にある空白やコメントを付けたトークン
+#[derive(Debug, PartialEq)]
+pub struct CstToken<'src> {
+    /// 直前のトークンとの間にある空白とコメント
+    pub leading: Vec<Trivia<'src>>,
+    /// トークン
+    pub token: Token<'src>,
+    /// ソースコードに書かれたトークンの文字列
+    pub text: &'src str,
+    /// トークンの範囲
+    pub span: Span,
+}
+
+/// 具象構文木の節
+#[derive(Debug, PartialEq)]
+pub enum CstNode<'src> {
+    /// 括弧ではないトークン
+    Atom(CstToken<'src>),
+    /// 括弧で囲まれた要素の並び
+    List {
+        open: CstToken<'src>,
+        children: Vec<CstNode<'src>>,
+        close: CstToken<'src>,
+    },
+    /// `'x` のように前置記号を付けた要素
+    Prefixed {
+        mark: CstToken<'src>,
+        node: Box<CstNode<'src>>,
+    },
+}
+
+impl<'src> CstNode<'src> {
+    /// 前にある空白とコメントを含まない、節の範囲
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Atom(token) => token.span,
+            Self::List { open, close, .. } => open.span.merge(close.span),
+            Self::Prefixed { mark, node } => mark.span.merge(node.span()),
+        }
+    }
+
+    /// 節の最初のトークン。節の前にある空白とコメントはこのトークンが持つ
+    pub fn first_token(&self) -> &CstToken<'src> {
+        match self {
+            Self::Atom(token)
+            | Self::List { open: token, .. }
+            | Self::Prefixed { mark: token, .. } => token,
+        }
+    }
+}
+
+/// 入力全体の具象構文木
+#[derive(Debug, PartialEq)]
+pub struct Cst<'src> {
+    /// 最上位の節
+    pub nodes: Vec<CstNode<'src>>,
+    /// 最後のトークンの後にある空白とコメント
+    pub trailing: Vec<Trivia<'src>>,
+}
+
+/// 閉じていない括弧ごとの、読み終えた要素
+struct Frame<'src> {
+    open: Option<CstToken<'src>>,
+    nodes: Vec<CstNode<'src>>,
+    prefixes: Vec<CstToken<'src>>,
+}
+
+impl<'src> Frame<'src> {
+    fn new(open: Option<CstToken<'src>>) -> Self {
+        Self {
+            open,
+            nodes: vec![],
+            prefixes: vec![],
+        }
+    }
+
+    /// 読み終えた節に前置記号を付けて加える
+    fn push(&mut self, mut node: CstNode<'src>) {
+        while let Some(mark) = self.prefixes.pop() {
+            node = CstNode::Prefixed {
+                mark,
+                node: Box::new(node),
+            };
+        }
+        self.nodes.push(node);
+    }
+}
+
+/// 範囲 `start..end` の空白とコメントを区切りに分ける
+fn trivia(input: &str, start: usize, end: usize) -> Vec<Trivia<'_>> {
+    split_trivia(input.get(start..end).unwrap_or_default())
+        .into_iter()
+        .map(|(kind, span)| {
+            let span = Span::new(start + span.start, start + span.end);
+            Trivia {
+                kind,
+                text: input.get(span.start..span.end).unwrap_or_default(),
+                span,
+            }
+        })
+        .collect()
+}
+
+impl<'src> Cst<'src> {
+    /// ソースコードを具象構文木にする関数
+    ///
+    /// [`source`](crate::parser::source) と同じ入力を受け付け、同じエラーを返す。
+    ///
+    /// # 引数
+    /// * `input` - 解析対象の文字列
+    ///
+    /// # 戻り値
+    /// * `Result<Cst, ParseError>` - 具象構文木、または最初に見つかったエラー
+    pub fn parse(input: &'src str) -> Result<Self, ParseError> {
+        Self::parse_with_limit(input, MAX_DEPTH)
+    }
+
+    /// 括弧を入れ子にできる最大の深さを指定して具象構文木にする
+    pub(crate) fn parse_with_limit(input: &'src str, max_depth: usize) -> Result<Self, ParseError> {
+        // 括弧の対応や深さの検査は `source` と同じ解析に任せ、ここでは正しい入力だけを組み立てる
+        source_with_options(input, ParserOptions::new().max_depth(max_depth))?;
+        let mut frames = vec![Frame::new(None)];
+        let mut end = 0;
+        for item in Lexer::new(input) {
+            let (span, token) = item?;
+            let cst_token = CstToken {
+                leading: trivia(input, end, span.start),
+                text: input.get(span.start..span.end).unwrap_or_default(),
+                token,
+                span,
+            };
+            end = span.end;
+            let frame = frames.last_mut().expect("the root frame is never popped");
+            match cst_token.token {
+                Token::LParen => frames.push(Frame::new(Some(cst_token))),
+                Token::RParen => {
+                    let inner = frames.pop().expect("source() checked the parentheses");
+                    let node = CstNode::List {
+                        open: inner.open.expect("source() checked the parentheses"),
+                        children: inner.nodes,
+                        close: cst_token,
+                    };
+                    frames
+                        .last_mut()
+                        .expect("source() checked the parentheses")
+                        .push(node);
+                }
+                Token::Ident(prefix) if quote_form(prefix).is_some() => {
+                    frame.prefixes.push(cst_token);
+                }
+                _ => frame.push(CstNode::Atom(cst_token)),
+            }
+        }
+        let root = frames.pop().expect("the root frame is never popped");
+        Ok(Self {
+            nodes: root.nodes,
+            trailing: trivia(input, end, input.len()),
+        })
+    }
+
+    /// 木に含まれるコメントを先頭から順に返す
+    pub fn comments(&self) -> Vec<&Trivia<'src>> {
+        fn collect<'a, 'src>(node: &'a CstNode<'src>, out: &mut Vec<&'a Trivia<'src>>) {
+            match node {
+                CstNode::Atom(token) => out.extend(comments_of(&token.leading)),
+                CstNode::List {
+                    open,
+                    children,
+                    close,
+                } => {
+                    out.extend(comments_of(&open.leading));
+                    children.iter().for_each(|child| collect(child, out));
+                    out.extend(comments_of(&close.leading));
+                }
+                CstNode::Prefixed { mark, node } => {
+                    out.extend(comments_of(&mark.leading));
+                    collect(node, out);
+                }
+            }
+        }
+        let mut out = vec![];
+        self.nodes.iter().for_each(|node| collect(node, &mut out));
+        out.extend(comments_of(&self.trailing));
+        out
+    }
+}
+
+/// 空白を除いたコメントの区切り
+fn comments_of<'a, 'src>(trivia: &'a [Trivia<'src>]) -> impl Iterator<Item = &'a Trivia<'src>> {
+    trivia
+        .iter()
+        .filter(|t| matches!(t.kind, TriviaKind::LineComment | TriviaKind::BlockComment))
+}
+
+impl fmt::Display for CstToken<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for trivia in &self.leading {
+            f.write_str(trivia.text)?;
+        }
+        f.write_str(self.text)
+    }
+}
+
+impl fmt::Display for CstNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Atom(token) => write!(f, "{token}"),
+            Self::List {
+                open,
+                children,
+                close,
+            } => {
+                write!(f, "{open}")?;
+                for child in children {
+                    write!(f, "{child}")?;
+                }
+                write!(f, "{close}")
+            }
+            Self::Prefixed { mark, node } => write!(f, "{mark}{node}"),
+        }
+    }
+}
+
+impl fmt::Display for Cst<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for node in &self.nodes {
+            write!(f, "{node}")?;
+        }
+        for trivia in &self.trailing {
+            f.write_str(trivia.text)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::source;
+
+    #[test]
+    fn test_round_trip() {
+        let inputs = [
+            "",
+            "  \n",
+            "(define (square x)\r\n\t(* x x)) // square\n\n(square\t3)\n",
+            "' ( a , `b ) /* c /* nested */ */",
+            "(print \"// not a comment\" 1.5e3 -2 nil)",
+            "x // unterminated line comment",
+            "a /* unterminated",
+        ];
+        for input in inputs {
+            let cst = Cst::parse(input).unwrap_or_else(|e| panic!("{input:?}: {e}"));
+            assert_eq!(cst.to_string(), input);
+        }
+    }
+
+    #[test]
+    fn test_structure() {
+        let input = "// head\n'(a  b) ; x";
+        assert!(Cst::parse(input).is_err());
+
+        let input = "// head\n'(a  b)\n";
+        let cst = Cst::parse(input).unwrap();
+        assert_eq!(cst.nodes.len(), 1);
+        let CstNode::Prefixed { mark, node } = &cst.nodes[0] else {
+            panic!("expected a prefixed node: {cst:?}");
+        };
+        assert_eq!(mark.text, "'");
+        let kinds: Vec<_> = mark.leading.iter().map(|t| t.kind).collect();
+        assert_eq!(kinds, [TriviaKind::LineComment, TriviaKind::Newline]);
+        let CstNode::List { children, .. } = node.as_ref() else {
+            panic!("expected a list: {node:?}");
+        };
+        assert_eq!(children[1].first_token().leading[0].text, "  ");
+        assert_eq!(cst.nodes[0].span(), Span::new(8, 15));
+        assert_eq!(cst.trailing[0].kind, TriviaKind::Newline);
+
+        let comments: Vec<_> = Cst::parse("(a /* x */ b) // y")
+            .unwrap()
+            .comments()
+            .iter()
+            .map(|t| t.text)
+            .collect();
+        assert_eq!(comments, ["/* x */", "// y"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Cst::parse("(a").unwrap_err(), source("(a").unwrap_err());
+        assert_eq!(Cst::parse("a)").unwrap_err(), source("a)").unwrap_err());
+        assert_eq!(Cst::parse("'").unwrap_err(), source("'").unwrap_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! 命令列の位置とソースコードの行と桁の対応表
+//!
+//! 命令列はソースコードのバイト位置を [`Bytecode::spans`] に持つが、`.rsclc` 形式のファイルから
+//! 読み込んだ命令列にはソースコードが無いので、バイト位置を行と桁に直せない。コンパイルしたときに
+//! [`Bytecode::attach_lines`] で対応表を作っておくと、ファイルに一緒に書き出され、読み込んだ後も
+//! 実行時のエラーの位置を行と桁で示せる。行ごとの最初の命令の位置も引けるので、行を指定して
+//! 止める位置を決めるのにも使える。
+//!
+//! ```
+//! use ruscal_b::{compile_program, parse_expr};
+//! use ruscal_b::source_map::LineCol;
+//!
+//! let source = "{\n  var x = 1\n  x + y\n}";
+//! let mut bytecode = compile_program(&[parse_expr(source).unwrap()]);
+//! bytecode.attach_lines(source);
+//! let at = bytecode.lines.line_starts(3)[0];
+//! assert_eq!(bytecode.lines.location(at), Some(LineCol { line: 3, column: 3 }));
+//! ```
+
+use std::rc::Rc;
+
+use crate::ast::Span;
+use crate::bytecode::Bytecode;
+use crate::source_map::{LineCol, SourceMap};
+
+/// 対応表の1行。`pc` の命令から次の行の `pc` の手前の命令までが同じ位置から始まる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRow {
+    /// 命令列の位置
+    pub pc: u32,
+    /// 命令を生成した式の始まりの行と桁
+    pub start: LineCol,
+}
+
+/// 命令列の位置と、命令を生成した式の始まりの行と桁の対応表
+///
+/// 行は `pc` の昇順に並び、隣り合う行の位置は異なる。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineTable {
+    pub rows: Vec<LineRow>,
+}
+
+impl LineTable {
+    /// 各命令の範囲から対応表を作る
+    ///
+    /// # 引数
+    /// * `spans` - 命令ごとの、命令を生成した式の範囲
+    /// * `map` - 範囲を含むソースコードの行の表
+    pub fn new(spans: &[Span], map: &SourceMap) -> Self {
+        let mut table = Self::default();
+        for (pc, span) in spans.iter().enumerate() {
+            table.push(pc as u32, map.line_col(span.start));
+        }
+        table
+    }
+
+    /// 対応表が空かどうか
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// `pc` の命令を生成した式の始まりの行と桁
+    pub fn location(&self, pc: usize) -> Option<LineCol> {
+        let index = self.rows.partition_point(|row| row.pc as usize <= pc);
+        Some(self.rows.get(index.checked_sub(1)?)?.start)
+    }
+
+    /// 行 `line` の命令の並びが始まる位置
+    ///
+    /// 別の行の命令を挟んで同じ行に戻る場合は、並びごとに位置を返す。
+    pub fn line_starts(&self, line: usize) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut previous = None;
+        for row in &self.rows {
+            if row.start.line == line && previous != Some(line) {
+                starts.push(row.pc as usize);
+            }
+            previous = Some(row.start.line);
+        }
+        starts
+    }
+
+    /// 命令を取り除いた後に、位置を付け直す
+    ///
+    /// `map` は古い位置から、その位置かその後に残る最初の命令の新しい位置を返す。
+    pub(crate) fn remap(&mut self, map: impl Fn(usize) -> u32) {
+        let rows = std::mem::take(&mut self.rows);
+        for row in rows {
+            let pc = map(row.pc as usize);
+            // 取り除いた命令の行は、後に残る命令自身の行で置き換える
+            if self.rows.last().is_some_and(|last| last.pc == pc) {
+                self.rows.pop();
+            }
+            self.push(pc, row.start);
+        }
+    }
+
+    fn push(&mut self, pc: u32, start: LineCol) {
+        if self.rows.last().is_none_or(|last| last.start != start) {
+            self.rows.push(LineRow { pc, start });
+        }
+    }
+}
+
+impl Bytecode {
+    /// 命令列とその中の関数の命令列に、ソースコードの行と桁の対応表を付ける
+    ///
+    /// # 引数
+    /// * `source` - 命令列にコンパイルしたソースコード
+    pub fn attach_lines(&mut self, source: &str) {
+        attach(self, &SourceMap::new(source));
+    }
+
+    /// 命令列かその中の関数の命令列で、`span` の範囲から生成した命令の行と桁
+    ///
+    /// 実行時のエラーの範囲は、エラーになった命令か関数の範囲なので、対応表があれば行と桁が分かる。
+    pub fn locate(&self, span: Span) -> Option<LineCol> {
+        let found = self
+            .spans
+            .iter()
+            .position(|s| *s == span)
+            .and_then(|pc| self.lines.location(pc));
+        found.or_else(|| {
+            self.functions
+                .iter()
+                .find_map(|prototype| prototype.code.locate(span))
+        })
+    }
+}
+
+fn attach(bytecode: &mut Bytecode, map: &SourceMap) {
+    bytecode.lines = LineTable::new(&bytecode.spans, map);
+    for prototype in &mut bytecode.functions {
+        attach(Rc::make_mut(&mut Rc::make_mut(prototype).code), map);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{Expr, ExprKind};
+    use crate::bytecode::compile;
+    use crate::infix::statements;
+    use crate::peephole::optimize;
+
+    fn compile_str(input: &str) -> Bytecode {
+        let mut bytecode = compile(&Expr::new(
+            ExprKind::Block(statements(input).unwrap()),
+            Span::new(0, input.len()),
+        ));
+        bytecode.attach_lines(input);
+        bytecode
+    }
+
+    #[test]
+    fn test_lines() {
+        let input = "var x = 1\nfn f(n) {\n  n * 2\n}\nf(x)";
+        let bytecode = compile_str(input);
+        let starts = bytecode.lines.line_starts(5);
+        assert_eq!(starts.len(), 1);
+        assert_eq!(bytecode.lines.location(starts[0]).unwrap().line, 5);
+        assert!(bytecode.lines.line_starts(3).is_empty());
+        let body = &bytecode.functions[0].code;
+        // 本体の最初の命令はブロックのスコープを始める
+        assert_eq!(body.lines.location(0), Some(LineCol { line: 2, column: 9 }));
+        assert_eq!(body.lines.location(1), Some(LineCol { line: 3, column: 3 }));
+        for pair in bytecode.lines.rows.windows(2) {
+            assert!(pair[0].pc < pair[1].pc && pair[0].start != pair[1].start);
+        }
+    }
+
+    #[test]
+    fn test_locate() {
+        let input = "var x = 1\nfn f(n) {\n  n * true\n}\nf(x)";
+        let bytecode = compile_str(input);
+        let span = bytecode.functions[0].code.spans[1];
+        assert_eq!(bytecode.locate(span), Some(LineCol { line: 3, column: 3 }));
+        assert_eq!(bytecode.locate(Span::new(1000, 1001)), None);
+    }
+
+    #[test]
+    fn test_optimized_lines() {
+        // 最適化で命令を取り除いても、残る命令の行は変わらない
+        let input = "var x = 1\nx = x + 2\nwhile false {\n  x = 0\n}\nx";
+        let original = compile_str(input);
+        let mut optimized = original.clone();
+        optimize(&mut optimized);
+        let lines = |bytecode: &Bytecode| -> Vec<_> {
+            (0..bytecode.code.len())
+                .map(|pc| (bytecode.spans[pc], bytecode.lines.location(pc)))
+                .collect()
+        };
+        let before = lines(&original);
+        for entry in lines(&optimized) {
+            assert!(before.contains(&entry), "{entry:?}");
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! スタックマシンの実行を行ごとに止めて調べる対話的なデバッガ
+//!
+//! [`Debugger`] は [`Hook`] として [`Vm`](crate::vm::Vm) に設定し、命令列の行と桁の対応表
+//! ([`LineTable`](crate::debug_info::LineTable)) を使って、ソースコードの行の最初の命令で止まる。
+//! 止まるたびに入力から1行ずつコマンドを読み、実行を再開するコマンドを読むまで繰り返す。
+//! 使えるコマンドは `help` で表示する。入力が終わると実行を打ち切る。
+//!
+//! ```
+//! use ruscal_b::debugger::Debugger;
+//! use ruscal_b::{compile_program, parse_expr, Environment, Value, Vm};
+//!
+//! let source = "{\n  var x = 20\n  x + 22\n}";
+//! let mut bytecode = compile_program(&[parse_expr(source).unwrap()]);
+//! bytecode.attach_lines(source);
+//! let debugger = Debugger::new(source, "continue\n".as_bytes(), std::io::sink());
+//! let value = Vm::new().with_hook(debugger).run(&bytecode, &mut Environment::new());
+//! assert_eq!(value, Ok(Value::I64(42)));
+//! ```
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use crate::bytecode::{Bytecode, Instruction};
+use crate::source_map::SourceMap;
+use crate::vm::{Frame, Hook};
+
+/// 止まったときのプロンプト
+const PROMPT: &str = "(rdb) ";
+
+/// `help` で表示するコマンドの一覧
+const HELP: &str = "\
+step (s)          run to the next line, entering calls
+next (n)          run to the next line in this or a calling function
+continue (c)      run to the next breakpoint
+break <line> (b)  stop at the start of <line>
+delete <line> (d) remove the breakpoint at <line>
+backtrace (bt)    list the active calls, innermost first
+locals [<n>]      print the local variables of call <n> (default: 0, the innermost)
+print <name> (p)  print the value of a variable in the innermost call
+list (l)          show the source lines around the current line
+quit (q)          stop the program";
+
+/// 次に止まる条件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// 次の行で止まる
+    Step,
+    /// 呼び出しの深さがこの値以下になった次の行で止まる
+    Next(usize),
+    /// ブレークポイントの行まで止まらない
+    Continue,
+}
+
+/// 入力 `R` からコマンドを読み、出力 `W` に結果を書くデバッガ
+///
+/// 最初の行で止まる。ブレークポイントの行では、どの実行の仕方でも止まる。
+pub struct Debugger<R, W> {
+    source: String,
+    input: R,
+    output: W,
+    breakpoints: BTreeSet<usize>,
+    mode: Mode,
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    /// デバッガを作る
+    ///
+    /// # 引数
+    /// * `source` - 実行する命令列にコンパイルしたソースコード。行の内容の表示に使う
+    /// * `input` - コマンドを読み込む入力
+    /// * `output` - 止まった位置やコマンドの結果を書き出す出力
+    pub fn new(source: impl Into<String>, input: R, output: W) -> Self {
+        Self {
+            source: source.into(),
+            input,
+            output,
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Step,
+        }
+    }
+
+    /// 止まった位置を表示し、実行を再開するまでコマンドを読む。実行を続けるなら `true` を返す
+    fn pause(&mut self, frames: &[Frame], line: usize) -> io::Result<bool> {
+        let name = frames.last().map_or("", |frame| frame.name.as_str());
+        writeln!(self.output, "stopped in {name} at line {line}")?;
+        self.show_lines(line, line)?;
+        loop {
+            write!(self.output, "{PROMPT}")?;
+            self.output.flush()?;
+            let mut command = String::new();
+            if self.input.read_line(&mut command)? == 0 {
+                return Ok(false);
+            }
+            let mut words = command.split_whitespace();
+            let Some(word) = words.next() else {
+                continue;
+            };
+            let arg = words.next();
+            match word {
+                "s" | "step" => self.mode = Mode::Step,
+                "n" | "next" => self.mode = Mode::Next(frames.len()),
+                "c" | "continue" => self.mode = Mode::Continue,
+                "q" | "quit" => return Ok(false),
+                "b" | "break" | "d" | "delete" => match arg.map(str::parse) {
+                    Some(Ok(line)) => self.breakpoint(frames, word, line)?,
+                    _ => writeln!(self.output, "{word} expects a line number")?,
+                },
+                "bt" | "backtrace" => {
+                    for (i, frame) in frames.iter().rev().enumerate() {
+                        let at = frame.code.lines.location(frame.pc);
+                        let line = at.map_or("?".to_string(), |at| at.line.to_string());
+                        writeln!(self.output, "#{i} {} at line {line}", frame.name)?;
+                    }
+                }
+                "locals" => {
+                    let n = arg.map_or(Ok(0), str::parse::<usize>);
+                    match n.ok().and_then(|n| frames.iter().rev().nth(n)) {
+                        Some(frame) => {
+                            let locals = frame.env.locals();
+                            if locals.is_empty() {
+                                writeln!(self.output, "no local variables")?;
+                            }
+                            for (name, value) in locals {
+                                writeln!(self.output, "{name} = {value}")?;
+                            }
+                        }
+                        None => writeln!(self.output, "no such call")?,
+                    }
+                }
+                "p" | "print" => {
+                    let value = arg
+                        .zip(frames.last())
+                        .and_then(|(name, frame)| frame.env.get(name));
+                    match (arg, value) {
+                        (Some(name), Some(value)) => writeln!(self.output, "{name} = {value}")?,
+                        (Some(name), None) => writeln!(self.output, "unknown variable `{name}`")?,
+                        (None, _) => writeln!(self.output, "print expects a variable name")?,
+                    }
+                }
+                "l" | "list" => self.show_lines(line.saturating_sub(2).max(1), line + 2)?,
+                "h" | "help" => writeln!(self.output, "{HELP}")?,
+                _ => writeln!(self.output, "unknown command: {word} (try `help`)")?,
+            }
+            if matches!(word, "s" | "step" | "n" | "next" | "c" | "continue") {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// `break` か `delete` のコマンドでブレークポイントを変える
+    fn breakpoint(&mut self, frames: &[Frame], command: &str, line: usize) -> io::Result<()> {
+        if matches!(command, "d" | "delete") {
+            if self.breakpoints.remove(&line) {
+                writeln!(self.output, "deleted the breakpoint at line {line}")
+            } else {
+                writeln!(self.output, "no breakpoint at line {line}")
+            }
+        } else if frames
+            .first()
+            .is_some_and(|frame| has_line(&frame.code, line))
+        {
+            self.breakpoints.insert(line);
+            writeln!(self.output, "breakpoint at line {line}")
+        } else {
+            writeln!(self.output, "no code at line {line}")
+        }
+    }
+
+    /// `first` 行目から `last` 行目までのソースコードを、行番号を付けて表示する
+    fn show_lines(&mut self, first: usize, last: usize) -> io::Result<()> {
+        let map = SourceMap::new(&self.source);
+        for line in first..=last.min(map.line_count()) {
+            if let Some(text) = map.line(line) {
+                writeln!(self.output, "{line:>4} | {text}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead, W: Write> Hook for Debugger<R, W> {
+    fn before(&mut self, frames: &[Frame]) -> bool {
+        let Some(frame) = frames.last() else {
+            return true;
+        };
+        let Some(line) = line_start(&frame.code, frame.pc) else {
+            return true;
+        };
+        let stop = match self.mode {
+            Mode::Step => true,
+            Mode::Next(depth) => frames.len() <= depth,
+            Mode::Continue => false,
+        };
+        if !stop && !self.breakpoints.contains(&line) {
+            return true;
+        }
+        // 出力に書けなくなったら、続けても結果を見られないので実行を打ち切る
+        self.pause(frames, line).unwrap_or(false)
+    }
+}
+
+/// `pc` の命令が行の最初の命令なら、その行
+///
+/// 命令列の最後の [`Instruction::Return`] は命令列全体の範囲を持ち、その最初の行を指すので止まらない。
+fn line_start(code: &Bytecode, pc: usize) -> Option<usize> {
+    if code.code.get(pc) == Some(&Instruction::Return) {
+        return None;
+    }
+    let rows = &code.lines.rows;
+    let index = rows.binary_search_by_key(&pc, |row| row.pc as usize).ok()?;
+    let line = rows[index].start.line;
+    let previous = index.checked_sub(1).map(|i| rows[i].start.line);
+    (previous != Some(line)).then_some(line)
+}
+
+/// 命令列かその中の関数の命令列に、`line` 行目から始まる命令があるかどうか
+fn has_line(code: &Bytecode, line: usize) -> bool {
+    !code.lines.line_starts(line).is_empty()
+        || code
+            .functions
+            .iter()
+            .any(|prototype| has_line(&prototype.code, line))
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::ast::{Expr, ExprKind, Span};
+    use crate::bytecode::compile;
+    use crate::env::Environment;
+    use crate::eval::{EvalError, Value};
+    use crate::infix::statements;
+    use crate::vm::Vm;
+
+    /// テストの後で中身を読める出力
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const PROGRAM: &str = "\
+fn double(n) {
+  var twice = n * 2
+  twice
+}
+var a = 1
+var b = double(a + 1)
+b + a";
+
+    /// プログラムをデバッガで実行し、結果と出力を返す
+    fn debug(commands: &'static str) -> (Result<Value, EvalError>, String) {
+        let mut bytecode = compile(&Expr::new(
+            ExprKind::Block(statements(PROGRAM).unwrap()),
+            Span::new(0, PROGRAM.len()),
+        ));
+        bytecode.attach_lines(PROGRAM);
+        let output = Output::default();
+        let debugger = Debugger::new(PROGRAM, commands.as_bytes(), output.clone());
+        let value = Vm::new()
+            .with_hook(debugger)
+            .run(&bytecode, &mut Environment::new());
+        let text = String::from_utf8(output.0.take()).unwrap();
+        (value, text)
+    }
+
+    #[test]
+    fn test_step_and_next() {
+        let (value, output) = debug("n\nn\ns\ns\nn\nc\n");
+        assert_eq!(value, Ok(Value::I64(5)));
+        let stops: Vec<_> = output
+            .lines()
+            .filter(|line| line.contains("stopped"))
+            .map(|line| line.trim_start_matches(PROMPT))
+            .collect();
+        assert_eq!(
+            stops,
+            [
+                "stopped in <top level> at line 1",
+                "stopped in <top level> at line 5",
+                "stopped in <top level> at line 6",
+                "stopped in double at line 1",
+                "stopped in double at line 2",
+                "stopped in double at line 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_breakpoints_and_inspection() {
+        let (value, output) = debug("b 3\nb 40\nc\nbt\nlocals\nlocals 1\np a\np zz\nd 3\nd 3\nc\n");
+        assert_eq!(value, Ok(Value::I64(5)));
+        for expected in [
+            "breakpoint at line 3",
+            "no code at line 40",
+            "stopped in double at line 3",
+            "   3 |   twice",
+            "#0 double at line 3",
+            "#1 <top level> at line 6",
+            "n = 2\n",
+            "twice = 4\n",
+            "double = <fn (n)>\n",
+            "a = 1\n",
+            "unknown variable `zz`",
+            "deleted the breakpoint at line 3",
+            "no breakpoint at line 3",
+        ] {
+            assert!(output.contains(expected), "{expected:?} in {output}");
+        }
+    }
+
+    #[test]
+    fn test_quit() {
+        let (value, output) = debug("list\nhelp\nfoo\nq\n");
+        assert!(matches!(value, Err(EvalError::Interrupted { .. })));
+        assert!(output.contains("   2 |   var twice = n * 2"));
+        assert!(output.contains("unknown command: foo"));
+        // 入力が終わっても打ち切る
+        let (value, _) = debug("");
+        assert!(matches!(value, Err(EvalError::Interrupted { .. })));
+    }
+}
@@ -0,0 +1,562 @@
+//! 解析を止めずに集めるエラーの報告と、その表示
+//!
+//! [`Diagnostic::render`] は rustc に似た形で、問題のある行と範囲の下線を表示する。
+//!
+//! ```text
+//! error[E0002]: expected token, found '@'
+//!  --> 1:4
+//!   |
+//! 1 | (a @ b)
+//!   |    ^
+//! ```
+//!
+//! 報告には種類ごとに変わらない番号を付け、ツールが説明の文言に頼らずに報告を見分けられるようにする。
+//! 番号は一度決めたら変えず、種類を増やすときは末尾に足す。
+//!
+//! * `E00xx` - 構文解析のエラー ([`ParseError::code`])
+//! * `E01xx` - 評価のエラー ([`EvalError::code`])
+//! * `E02xx` - 型検査のエラー ([`TypeError::code`])
+//! * `E03xx` - Rust や WebAssembly への変換のエラー ([`TranspileError::code`])
+//! * `W00xx` - 型検査の警告
+//! * `W01xx` - 組み込みのリンターの規則の警告 ([`Rule::code`](crate::lint::Rule::code))
+//!
+//! 端末に表示するときは [`Diagnostic::render_colored`] で、エラーを赤、警告を黄色で強調できる。
+//! 色を付けるかどうかは [`ColorChoice`] で決める。
+
+use std::fmt;
+
+use crate::ast::Span;
+use crate::eval::EvalError;
+use crate::parser::ParseError;
+use crate::source_map::{Located, SourceMap};
+use crate::transpile::TranspileError;
+use crate::typecheck::TypeError;
+
+/// 報告の重大さ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// 実行できない誤り
+    Error,
+    /// 実行はできるが、誤りの疑いがある書き方
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// 表示に色を付けるかどうかの設定
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// 出力先が端末で、環境変数 `NO_COLOR` が空でなく設定されていなければ色を付ける
+    #[default]
+    Auto,
+    /// 常に色を付ける
+    Always,
+    /// 色を付けない
+    Never,
+}
+
+impl ColorChoice {
+    /// `--color` に渡す `auto`、`always`、`never` の名前から設定を得る
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Self::Auto),
+            "always" => Some(Self::Always),
+            "never" => Some(Self::Never),
+            _ => None,
+        }
+    }
+
+    /// 色を付けるなら `true`
+    ///
+    /// # 引数
+    /// * `terminal` - 出力先が端末なら `true`
+    pub fn enabled(self, terminal: bool) -> bool {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+        match self {
+            Self::Auto => terminal && !no_color,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// 表示の各部分に付ける ANSI のエスケープシーケンス。色を付けなければどれも空文字列
+#[derive(Clone, Copy)]
+struct Palette {
+    /// 重大さと下線の色
+    severity: &'static str,
+    /// 行番号、区切り、関係する範囲の下線の色
+    accent: &'static str,
+    /// 説明の太字
+    bold: &'static str,
+    /// 色を戻す
+    reset: &'static str,
+}
+
+impl Palette {
+    const PLAIN: Self = Self {
+        severity: "",
+        accent: "",
+        bold: "",
+        reset: "",
+    };
+
+    fn colored(severity: Severity) -> Self {
+        Self {
+            severity: match severity {
+                Severity::Error => "\x1b[1;31m",
+                Severity::Warning => "\x1b[1;33m",
+            },
+            accent: "\x1b[1;34m",
+            bold: "\x1b[1m",
+            reset: "\x1b[0m",
+        }
+    }
+}
+
+/// 報告の本題とは別の、関係するソースコード上の範囲
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    /// 関係する範囲
+    pub span: Span,
+    /// 範囲の下線に添える説明
+    pub message: String,
+}
+
+/// ソースコード上の範囲に結び付いたエラーの報告
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// 報告の重大さ
+    pub severity: Severity,
+    /// 報告の種類を表す `E0001` のような番号
+    pub code: Option<&'static str>,
+    /// 問題のある範囲
+    pub span: Span,
+    /// 位置を含まないエラーの説明
+    pub message: String,
+    /// 直し方の手がかりなど、説明に添える補足
+    pub note: Option<String>,
+    /// 関数の定義の位置など、問題に関係する他の範囲
+    pub labels: Vec<Label>,
+}
+
+impl Diagnostic {
+    /// エラーの報告を作る
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: None,
+            span,
+            message: message.into(),
+            note: None,
+            labels: vec![],
+        }
+    }
+
+    /// 警告の報告を作る
+    pub fn warning(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::new(span, message)
+        }
+    }
+
+    /// 種類を表す番号を付ける
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// 補足を添える
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+
+    /// 関係する範囲を加える
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    /// 問題のある行と範囲の下線を含む、複数行の表示を作る関数
+    ///
+    /// 範囲が複数の行にまたがる場合は、最初の行の終わりまでに下線を引く。
+    /// 空の範囲には1文字分の下線を引く。関係する範囲は `:::` に続けて同じ形で表示する。
+    ///
+    /// # 引数
+    /// * `map` - 報告の元になったソースコードの表
+    ///
+    /// # 戻り値
+    /// * `String` - 改行で終わる表示用の文字列
+    pub fn render(&self, map: &SourceMap) -> String {
+        self.render_with(map, Palette::PLAIN)
+    }
+
+    /// [`Self::render`] と同じ表示に、端末で色が付く ANSI のエスケープシーケンスを加える関数
+    ///
+    /// エラーは赤、警告は黄色で重大さと下線を表示し、行番号と関係する範囲は青で表示する。
+    pub fn render_colored(&self, map: &SourceMap) -> String {
+        self.render_with(map, Palette::colored(self.severity))
+    }
+
+    fn render_with(&self, map: &SourceMap, palette: Palette) -> String {
+        let Palette {
+            severity,
+            accent,
+            bold,
+            reset,
+        } = palette;
+        let width = std::iter::once(self.span)
+            .chain(self.labels.iter().map(|label| label.span))
+            .map(|span| map.line_col(span.start).line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = " ".repeat(width);
+
+        let mut out = self.header(palette);
+        let at = map.line_col(self.span.start);
+        out += &format!("{accent}{gutter}-->{reset} {at}\n");
+        out += &snippet(map, self.span, &gutter, "", (severity, palette));
+        for label in &self.labels {
+            let at = map.line_col(label.span.start);
+            out += &format!("{accent}{gutter}:::{reset} {at}\n");
+            out += &snippet(map, label.span, &gutter, &label.message, (accent, palette));
+        }
+        if let Some(note) = &self.note {
+            out += &format!("{accent}{gutter} ={reset} {bold}note{reset}: {note}\n");
+        }
+        out
+    }
+
+    /// ソースコードが無いときに、行の代わりに位置だけを示す複数行の表示を作る関数
+    ///
+    /// コンパイル済みのファイルを実行したときのように、行の内容は分からないが位置は分かる場合に使う。
+    /// 見出しと注記は [`Self::render`] と同じで、関係する範囲は位置に続けて説明を表示する。
+    ///
+    /// # 引数
+    /// * `locate` - 範囲の位置を `file.rsclc:2:19` のような文字列にする関数
+    ///
+    /// # 戻り値
+    /// * `String` - 改行で終わる表示用の文字列
+    pub fn render_located(&self, locate: impl Fn(Span) -> String) -> String {
+        self.render_located_with(locate, Palette::PLAIN)
+    }
+
+    /// [`Self::render_located`] と同じ表示に、端末で色が付く ANSI のエスケープシーケンスを加える関数
+    pub fn render_located_colored(&self, locate: impl Fn(Span) -> String) -> String {
+        self.render_located_with(locate, Palette::colored(self.severity))
+    }
+
+    fn render_located_with(&self, locate: impl Fn(Span) -> String, palette: Palette) -> String {
+        let Palette {
+            accent,
+            bold,
+            reset,
+            ..
+        } = palette;
+        let mut out = self.header(palette);
+        out += &format!(" {accent}-->{reset} {}\n", locate(self.span));
+        for label in &self.labels {
+            let at = locate(label.span);
+            out += &format!(" {accent}:::{reset} {at}: {}\n", label.message);
+        }
+        if let Some(note) = &self.note {
+            out += &format!("  {accent}={reset} {bold}note{reset}: {note}\n");
+        }
+        out
+    }
+
+    /// `error[E0107]: 説明` の形の1行目
+    fn header(&self, palette: Palette) -> String {
+        let Palette {
+            severity,
+            bold,
+            reset,
+            ..
+        } = palette;
+        let header = match self.code {
+            Some(code) => format!("{}[{code}]", self.severity),
+            None => self.severity.to_string(),
+        };
+        format!("{severity}{header}{reset}{bold}: {}{reset}\n", self.message)
+    }
+
+    /// 位置を行と桁で表した説明を得る
+    pub fn locate(&self, map: &SourceMap) -> Located {
+        Located {
+            message: self.message.clone(),
+            at: map.line_col(self.span.start),
+        }
+    }
+}
+
+/// 範囲のある行と、範囲の下線を表示する
+///
+/// 最後の引数は (下線の色, 表示全体の色) の組。
+fn snippet(
+    map: &SourceMap,
+    span: Span,
+    gutter: &str,
+    message: &str,
+    (color, palette): (&str, Palette),
+) -> String {
+    let Palette { accent, reset, .. } = palette;
+    let at = map.line_col(span.start);
+    let line = map.line(at.line).unwrap_or("");
+    let line_end = map.line_span(at.line).map_or(span.end, |line| line.end);
+    let underlined = map
+        .source()
+        .get(span.start.min(line_end)..span.end.min(line_end))
+        .unwrap_or_default();
+    let carets = "^".repeat(underlined.chars().count().max(1));
+    let indent = " ".repeat(at.column - 1);
+    let bar = format!("{accent}{gutter} |{reset}");
+    let mut out = format!("{bar}\n");
+    let number = format!("{:>width$}", at.line, width = gutter.len());
+    out += &format!("{accent}{number} |{reset} {line}\n");
+    if message.is_empty() {
+        out += &format!("{bar} {indent}{color}{carets}{reset}\n");
+    } else {
+        out += &format!("{bar} {indent}{color}{carets} {message}{reset}\n");
+    }
+    out
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(e: &ParseError) -> Self {
+        let diagnostic = match e {
+            ParseError::Unexpected {
+                offset,
+                expected,
+                found: Some(c),
+            } => Self::new(
+                Span::new(*offset, offset + c.len_utf8()),
+                format!("expected {expected}, found {c:?}"),
+            ),
+            ParseError::Unexpected {
+                offset,
+                expected,
+                found: None,
+            } => Self::new(
+                Span::new(*offset, *offset),
+                format!("expected {expected}, found end of input"),
+            ),
+            ParseError::UnbalancedParen { span } => Self::new(*span, "unbalanced parenthesis"),
+            ParseError::TooDeep { span, max_depth } => {
+                Self::new(*span, format!("parentheses nested deeper than {max_depth}"))
+            }
+            ParseError::InvalidNumber { span, literal } => {
+                Self::new(*span, format!("invalid number {literal:?}"))
+            }
+        };
+        diagnostic.with_code(e.code())
+    }
+}
+
+impl From<&TypeError> for Diagnostic {
+    fn from(e: &TypeError) -> Self {
+        let diagnostic = Self::new(e.span(), e.message()).with_code(e.code());
+        match e {
+            TypeError::Arity {
+                definition: Some(definition),
+                ..
+            } => diagnostic.with_label(*definition, "function defined here"),
+            _ => diagnostic,
+        }
+    }
+}
+
+impl From<&EvalError> for Diagnostic {
+    fn from(e: &EvalError) -> Self {
+        let diagnostic = Self::new(e.span(), e.message()).with_code(e.code());
+        match e {
+            EvalError::Arity {
+                definition: Some(definition),
+                ..
+            } => diagnostic.with_label(*definition, "function defined here"),
+            _ => diagnostic,
+        }
+    }
+}
+
+impl From<&TranspileError> for Diagnostic {
+    fn from(e: &TranspileError) -> Self {
+        Self::new(e.span(), e.message()).with_code(e.code())
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.span.start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Expected;
+
+    #[test]
+    fn test_from_parse_error() {
+        let e = ParseError::unexpected(3, Expected::Token, Some('@'));
+        let diagnostic = Diagnostic::from(&e);
+        assert_eq!(diagnostic.span, Span::new(3, 4));
+        assert_eq!(
+            diagnostic.to_string(),
+            "expected token, found '@' at byte 3"
+        );
+        let e = ParseError::UnbalancedParen {
+            span: Span::new(0, 1),
+        };
+        assert_eq!(
+            Diagnostic::from(&e).to_string(),
+            "unbalanced parenthesis at byte 0"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let src = "(define x 1)\n(a @ b)";
+        let map = SourceMap::new(src);
+        let diagnostic = Diagnostic::from(&ParseError::unexpected(16, Expected::Token, Some('@')))
+            .with_note("remove it");
+        assert_eq!(
+            diagnostic.render(&map),
+            concat!(
+                "error[E0002]: expected token, found '@'\n",
+                " --> 2:4\n",
+                "  |\n",
+                "2 | (a @ b)\n",
+                "  |    ^\n",
+                "  = note: remove it\n",
+            )
+        );
+        let diagnostic = Diagnostic::new(Span::new(1, 20), "too long");
+        assert!(diagnostic
+            .render(&map)
+            .ends_with("1 | (define x 1)\n  |  ^^^^^^^^^^^\n"));
+        let diagnostic = Diagnostic::warning(Span::new(0, 1), "suspicious");
+        assert!(diagnostic.render(&map).starts_with("warning: suspicious\n"));
+    }
+
+    #[test]
+    fn test_render_located() {
+        let e = EvalError::Arity {
+            name: "f".to_string(),
+            expected: 2,
+            found: 3,
+            span: Span::new(30, 39),
+            definition: Some(Span::new(10, 28)),
+        };
+        let locate = |span: Span| format!("f.rsclc:{}", span.start);
+        assert_eq!(
+            Diagnostic::from(&e)
+                .with_note("check it")
+                .render_located(locate),
+            concat!(
+                "error[E0104]: `f` expected 2 arguments, found 3\n",
+                " --> f.rsclc:30\n",
+                " ::: f.rsclc:10: function defined here\n",
+                "  = note: check it\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_eval_error_labels() {
+        let src = "(define f (fn (a b) (+ a b)))\n(f 1 2 3)";
+        let e = EvalError::Arity {
+            name: "f".to_string(),
+            expected: 2,
+            found: 3,
+            span: Span::new(30, 39),
+            definition: Some(Span::new(10, 28)),
+        };
+        assert_eq!(
+            Diagnostic::from(&e).render(&SourceMap::new(src)),
+            concat!(
+                "error[E0104]: `f` expected 2 arguments, found 3\n",
+                " --> 2:1\n",
+                "  |\n",
+                "2 | (f 1 2 3)\n",
+                "  | ^^^^^^^^^\n",
+                " ::: 1:11\n",
+                "  |\n",
+                "1 | (define f (fn (a b) (+ a b)))\n",
+                "  |           ^^^^^^^^^^^^^^^^^^ function defined here\n",
+            )
+        );
+        let e = EvalError::DivisionByZero {
+            span: Span::new(0, 1),
+        };
+        assert_eq!(
+            Diagnostic::from(&e).to_string(),
+            "integer division by zero at byte 0"
+        );
+    }
+
+    #[test]
+    fn test_codes() {
+        let unknown = EvalError::UnknownIdentifier {
+            name: "x".to_string(),
+            span: Span::new(0, 1),
+        };
+        assert_eq!(Diagnostic::from(&unknown).code, Some("E0103"));
+        let unbalanced = ParseError::UnbalancedParen {
+            span: Span::new(0, 1),
+        };
+        assert_eq!(Diagnostic::from(&unbalanced).code, Some("E0001"));
+        let mismatch = TypeError::NotAFunction {
+            found: crate::typecheck::Type::Int,
+            span: Span::new(0, 1),
+        };
+        assert_eq!(Diagnostic::from(&mismatch).code, Some("E0203"));
+        // 番号の無い報告は番号を表示しない
+        let map = SourceMap::new("x");
+        let rendered = Diagnostic::warning(Span::new(0, 1), "odd").render(&map);
+        assert!(rendered.starts_with("warning: odd\n"), "{rendered}");
+        let rendered = Diagnostic::warning(Span::new(0, 1), "odd")
+            .with_code("W0001")
+            .render(&map);
+        assert!(rendered.starts_with("warning[W0001]: odd\n"), "{rendered}");
+    }
+
+    #[test]
+    fn test_render_colored() {
+        let map = SourceMap::new("(a @)");
+        let diagnostic = Diagnostic::new(Span::new(3, 4), "bad").with_code("E0002");
+        assert_eq!(
+            diagnostic.render_colored(&map),
+            concat!(
+                "\x1b[1;31merror[E0002]\x1b[0m\x1b[1m: bad\x1b[0m\n",
+                "\x1b[1;34m -->\x1b[0m 1:4\n",
+                "\x1b[1;34m  |\x1b[0m\n",
+                "\x1b[1;34m1 |\x1b[0m (a @)\n",
+                "\x1b[1;34m  |\x1b[0m    \x1b[1;31m^\x1b[0m\n",
+            )
+        );
+        let warning = Diagnostic::warning(Span::new(1, 2), "odd").render_colored(&map);
+        assert!(
+            warning.starts_with("\x1b[1;33mwarning\x1b[0m"),
+            "{warning:?}"
+        );
+        assert!(warning.contains("\x1b[1;33m^\x1b[0m"), "{warning:?}");
+
+        assert_eq!(ColorChoice::from_name("always"), Some(ColorChoice::Always));
+        assert_eq!(ColorChoice::from_name("sometimes"), None);
+        assert!(ColorChoice::Always.enabled(false));
+        assert!(!ColorChoice::Never.enabled(true));
+        assert!(!ColorChoice::Auto.enabled(false));
+    }
+}
@@ -0,0 +1,229 @@
+//! 命令列を人が読める形の一覧にする逆アセンブラ
+//!
+//! 一覧は命令列ごとの節からなる。節は命令を1行に1つずつ、位置、命令の名前、オペランドの順に並べ、
+//! 定数表や関数表を参照する命令には参照先の値を `;` の後に書き添える。行末の `@` の後は命令を生成した
+//! 式の位置で、ソースコードを渡せばバイト位置の代わりに行と桁を示し、行が変わるたびにその行の内容を挟む。
+//! ソースコードが無くても、命令列に行と桁の対応表 ([`LineTable`](crate::debug_info::LineTable))
+//! があれば、それを使って行と桁を示す。
+//! ジャンプ先になる命令は位置の前に `>` を付ける。
+//!
+//! ```
+//! use ruscal_b::{compile, parse_expr};
+//!
+//! let bytecode = compile(&parse_expr("1 + x").unwrap());
+//! let listing = bytecode.disassemble();
+//! assert!(listing.contains("Constant       0           ; 1"));
+//! assert!(listing.contains("Load           x"));
+//! ```
+
+use std::fmt::Write;
+
+use crate::bytecode::{Bytecode, Instruction};
+use crate::source_map::SourceMap;
+
+impl Bytecode {
+    /// 命令列とその中の関数の命令列を、位置をバイト位置で示して一覧にする
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        section(self, "main", None, &mut out);
+        out
+    }
+
+    /// 命令列を、`source` の行と桁で位置を示して一覧にする
+    ///
+    /// # 引数
+    /// * `source` - 命令列にコンパイルしたソースコード
+    pub fn disassemble_source(&self, source: &str) -> String {
+        let mut out = String::new();
+        section(self, "main", Some(&SourceMap::new(source)), &mut out);
+        out
+    }
+}
+
+/// 命令の名前と、オペランドを並べた文字列
+fn operands(instruction: Instruction) -> (&'static str, String) {
+    use Instruction::*;
+    match instruction {
+        Constant(n) => ("Constant", n.to_string()),
+        Pop => ("Pop", String::new()),
+        Dup => ("Dup", String::new()),
+        Load(name) => ("Load", name.to_string()),
+        Define(name) => ("Define", name.to_string()),
+        Assign(name) => ("Assign", name.to_string()),
+        ExpectNumber => ("ExpectNumber", String::new()),
+        ExpectInteger => ("ExpectInteger", String::new()),
+        ExpectOperand(name) => ("ExpectOperand", name.to_string()),
+        Add => ("Add", String::new()),
+        AddConstant(n) => ("AddConstant", n.to_string()),
+        Sub => ("Sub", String::new()),
+        Mul => ("Mul", String::new()),
+        Div => ("Div", String::new()),
+        Rem => ("Rem", String::new()),
+        Lt => ("Lt", String::new()),
+        Le => ("Le", String::new()),
+        Gt => ("Gt", String::new()),
+        Ge => ("Ge", String::new()),
+        Eq => ("Eq", String::new()),
+        Ne => ("Ne", String::new()),
+        Neg => ("Neg", String::new()),
+        Not => ("Not", String::new()),
+        Truthy => ("Truthy", String::new()),
+        Jump(target) => ("Jump", target.to_string()),
+        JumpIfFalse(target) => ("JumpIfFalse", target.to_string()),
+        Operator { name, argc } => ("Operator", format!("{name} {argc}")),
+        ExpectFunction(argc) => ("ExpectFunction", argc.to_string()),
+        Call { argc, .. } => ("Call", argc.to_string()),
+        Closure(n) => ("Closure", n.to_string()),
+        PushScope => ("PushScope", String::new()),
+        PopScope => ("PopScope", String::new()),
+        ForNext(target) => ("ForNext", target.to_string()),
+        OutsideLoop(keyword) => ("OutsideLoop", keyword.to_string()),
+        OutsideFunction => ("OutsideFunction", String::new()),
+        NoMatch => ("NoMatch", String::new()),
+        List(len) => ("List", len.to_string()),
+        Array(len) => ("Array", len.to_string()),
+        Map(len) => ("Map", len.to_string()),
+        ExpectKey => ("ExpectKey", String::new()),
+        Import(n) => ("Import", n.to_string()),
+        Index => ("Index", String::new()),
+        SetIndex => ("SetIndex", String::new()),
+        Return => ("Return", String::new()),
+    }
+}
+
+/// 命令が参照する定数や関数の説明
+fn comment(bytecode: &Bytecode, instruction: Instruction) -> Option<String> {
+    match instruction {
+        Instruction::Constant(n) | Instruction::AddConstant(n) | Instruction::Import(n) => {
+            bytecode.constants.get(n as usize).map(ToString::to_string)
+        }
+        Instruction::Closure(n) => bytecode
+            .functions
+            .get(n as usize)
+            .map(|prototype| format!("fn ({})", params(&prototype.params))),
+        Instruction::Call {
+            name: Some(name), ..
+        } => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+fn params(params: &[crate::intern::Symbol]) -> String {
+    let names: Vec<_> = params.iter().map(|param| param.as_str()).collect();
+    names.join(" ")
+}
+
+/// 1つの命令列の節を書き、続けてその中の関数の節を書く
+///
+/// 関数の節の名前は、外側の節の名前に関数表の添字を `.` でつないだものにする。
+fn section(bytecode: &Bytecode, name: &str, map: Option<&SourceMap>, out: &mut String) {
+    let _ = writeln!(out, "== {name} ==");
+    let mut targets = vec![false; bytecode.code.len() + 1];
+    for instruction in &bytecode.code {
+        if let Instruction::Jump(target)
+        | Instruction::JumpIfFalse(target)
+        | Instruction::ForNext(target) = *instruction
+        {
+            if let Some(slot) = targets.get_mut(target as usize) {
+                *slot = true;
+            }
+        }
+    }
+    let mut line = None;
+    for (pc, &instruction) in bytecode.code.iter().enumerate() {
+        let span = bytecode.spans.get(pc).copied().unwrap_or_default();
+        let start = match map {
+            Some(map) => Some(map.line_col(span.start)),
+            None => bytecode.lines.location(pc),
+        };
+        let location = match start {
+            Some(start) => {
+                if line != Some(start.line) {
+                    line = Some(start.line);
+                    let _ = match map.and_then(|map| map.line(start.line)) {
+                        Some(text) => {
+                            writeln!(out, "      ;; {:>4} | {}", start.line, text.trim_end())
+                        }
+                        None => writeln!(out, "      ;; {:>4}", start.line),
+                    };
+                }
+                start.to_string()
+            }
+            None => format!("{}..{}", span.start, span.end),
+        };
+        let (mnemonic, operands) = operands(instruction);
+        let marker = if targets[pc] { '>' } else { ' ' };
+        let mut text = format!("{marker}{pc:04}  {mnemonic:<14} {operands:<11}");
+        if let Some(comment) = comment(bytecode, instruction) {
+            let _ = write!(text, " ; {comment}");
+        }
+        let _ = writeln!(out, "{:<50} @ {location}", text.trim_end());
+    }
+    if !bytecode.constants.is_empty() {
+        let _ = writeln!(out, "constants:");
+        for (i, constant) in bytecode.constants.iter().enumerate() {
+            let _ = writeln!(out, "  {i:4}  {constant}");
+        }
+    }
+    for (i, prototype) in bytecode.functions.iter().enumerate() {
+        out.push('\n');
+        let name = format!("{name}.{i} fn ({})", params(&prototype.params));
+        section(&prototype.code, &name, map, out);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ast::{Expr, ExprKind, Span};
+    use crate::bytecode::compile;
+    use crate::infix::statements;
+
+    fn compile_str(input: &str) -> crate::bytecode::Bytecode {
+        compile(&Expr::new(
+            ExprKind::Block(statements(input).unwrap()),
+            Span::new(0, input.len()),
+        ))
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let listing =
+            compile_str("var s = \"a\"\nwhile s != \"aaa\" { s = s + \"a\" }").disassemble();
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(lines[0], "== main ==");
+        assert_eq!(
+            lines[2],
+            " 0001  Constant       0           ; \"a\"            @ 8..11"
+        );
+        // ループの先頭はジャンプ先になる
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with(">0004  Load           s")));
+        assert!(lines.contains(&"constants:"));
+        assert!(lines.contains(&"     1  \"aaa\""));
+    }
+
+    #[test]
+    fn test_functions_and_source_lines() {
+        let input = "fn f(x) {\n  x * 2\n}\nf(3)";
+        let listing = compile_str(input).disassemble_source(input);
+        assert!(listing.contains("== main.0 fn (x) =="), "{listing}");
+        assert!(listing.contains(";;    2 |   x * 2"), "{listing}");
+        assert!(
+            listing.contains("Closure        0           ; fn (x)"),
+            "{listing}"
+        );
+        assert!(
+            listing.contains("Call           1           ; f"),
+            "{listing}"
+        );
+        assert!(listing.contains("@ 2:3"), "{listing}");
+
+        // 対応表があれば、ソースコードが無くても行と桁を示す
+        let mut bytecode = compile_str(input);
+        bytecode.attach_lines(input);
+        let listing = bytecode.disassemble();
+        assert!(listing.contains(";;    2\n"), "{listing}");
+        assert!(listing.contains("@ 2:3"), "{listing}");
+    }
+}
@@ -0,0 +1,300 @@
+//! 抽象構文木 ([`Expr`]) の構造を見せるための出力
+//!
+//! どの出力も、式の種類を名前にした節と、部分式を子にした木として書き出す。
+//!
+//! * [`to_sexpr`] - 子を持つ節を `(名前 子...)` とした1行のS式
+//! * [`to_dot`] - Graphviz の `dot` で描ける有向グラフ
+//!
+//! ```
+//! use ruscal_b::dump::to_sexpr;
+//! use ruscal_b::parse_expr;
+//!
+//! let expr = parse_expr("if x { f(1) } else { -y }").unwrap();
+//! assert_eq!(to_sexpr(&expr), "(if x (block (call f 1)) (block (- y)))");
+//! ```
+
+use crate::ast::{Expr, ExprKind, MatchArm, Pattern, Statement, Template};
+use crate::eval::datum;
+
+/// 出力する木の節
+struct Node {
+    label: String,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn leaf(label: impl Into<String>) -> Self {
+        Self::new(label, vec![])
+    }
+
+    fn new(label: impl Into<String>, children: Vec<Node>) -> Self {
+        Self {
+            label: label.into(),
+            children,
+        }
+    }
+}
+
+/// 式を節に変換する
+fn expr(e: &Expr) -> Node {
+    match &e.kind {
+        ExprKind::Int(n) => Node::leaf(n.to_string()),
+        ExprKind::Float(n) => Node::leaf(format!("{n:?}")),
+        ExprKind::Str(s) => Node::leaf(format!("{s:?}")),
+        ExprKind::Bool(b) => Node::leaf(b.to_string()),
+        ExprKind::Nil => Node::leaf("nil"),
+        ExprKind::Ident(name) => Node::leaf(name.to_string()),
+        ExprKind::BinaryOp { op, lhs, rhs } => Node::new(op.symbol(), vec![expr(lhs), expr(rhs)]),
+        ExprKind::UnaryOp { op, operand } => Node::new(op.symbol(), vec![expr(operand)]),
+        ExprKind::Group(inner) => Node::new("group", vec![expr(inner)]),
+        ExprKind::Call { func, args } => Node::new(
+            "call",
+            std::iter::once(expr(func))
+                .chain(args.iter().map(expr))
+                .collect(),
+        ),
+        ExprKind::Define { name, value } => {
+            Node::new("define", vec![Node::leaf(name.to_string()), expr(value)])
+        }
+        ExprKind::Fn { params, body, .. } => {
+            let params = params.iter().map(|p| Node::leaf(p.to_string())).collect();
+            Node::new(
+                "fn",
+                std::iter::once(Node::new("params", params))
+                    .chain(body.iter().map(expr))
+                    .collect(),
+            )
+        }
+        ExprKind::Let { bindings, body } => {
+            let bindings = bindings
+                .iter()
+                .map(|(name, value)| Node::new(name.to_string(), vec![expr(value)]))
+                .collect();
+            Node::new(
+                "let",
+                std::iter::once(Node::new("bindings", bindings))
+                    .chain(body.iter().map(expr))
+                    .collect(),
+            )
+        }
+        ExprKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => Node::new(
+            "if",
+            [Some(cond), Some(then_branch), else_branch.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(|e| expr(e))
+                .collect(),
+        ),
+        ExprKind::Block(statements) => {
+            Node::new("block", statements.iter().map(statement).collect())
+        }
+        ExprKind::While { cond, body } => Node::new("while", vec![expr(cond), expr(body)]),
+        ExprKind::For {
+            var,
+            start,
+            end,
+            body,
+        } => Node::new(
+            "for",
+            vec![
+                Node::leaf(var.to_string()),
+                expr(start),
+                expr(end),
+                expr(body),
+            ],
+        ),
+        ExprKind::Break => Node::leaf("break"),
+        ExprKind::Continue => Node::leaf("continue"),
+        ExprKind::Return(value) => Node::new("return", value.iter().map(|v| expr(v)).collect()),
+        ExprKind::Match { scrutinee, arms } => Node::new(
+            "match",
+            std::iter::once(expr(scrutinee))
+                .chain(arms.iter().map(arm))
+                .collect(),
+        ),
+        ExprKind::Array(items) => Node::new("array", items.iter().map(expr).collect()),
+        ExprKind::Map(entries) => Node::new(
+            "map",
+            entries
+                .iter()
+                .map(|(key, value)| Node::new("entry", vec![expr(key), expr(value)]))
+                .collect(),
+        ),
+        ExprKind::Import(path) => Node::new("import", vec![Node::leaf(format!("{path:?}"))]),
+        ExprKind::Index { target, index } => Node::new("index", vec![expr(target), expr(index)]),
+        ExprKind::Quote(tree) => Node::new("quote", vec![Node::leaf(datum(tree).to_string())]),
+        ExprKind::Quasiquote(template) => Node::new("quasiquote", vec![self::template(template)]),
+    }
+}
+
+fn statement(statement: &Statement) -> Node {
+    match statement {
+        Statement::VarDef { name, value, .. } => {
+            Node::new("var", vec![Node::leaf(name.to_string()), expr(value)])
+        }
+        Statement::Assignment { name, value, .. } => {
+            Node::new("=", vec![Node::leaf(name.to_string()), expr(value)])
+        }
+        Statement::IndexAssignment {
+            target,
+            index,
+            value,
+            ..
+        } => Node::new("index=", vec![expr(target), expr(index), expr(value)]),
+        Statement::Expr(e) => expr(e),
+    }
+}
+
+fn arm(arm: &MatchArm) -> Node {
+    let pattern = match &arm.pattern {
+        Pattern::Wildcard => "_".to_string(),
+        Pattern::Binding(name) => name.to_string(),
+        Pattern::Int(n) => n.to_string(),
+        Pattern::Float(n) => format!("{n:?}"),
+        Pattern::Str(s) => format!("{s:?}"),
+        Pattern::Bool(b) => b.to_string(),
+        Pattern::Nil => "nil".to_string(),
+    };
+    let mut children = vec![Node::leaf(pattern)];
+    if let Some(guard) = &arm.guard {
+        children.push(Node::new("if", vec![expr(guard)]));
+    }
+    children.push(expr(&arm.body));
+    Node::new("arm", children)
+}
+
+fn template(template: &Template) -> Node {
+    match template {
+        Template::Datum(tree) => Node::leaf(datum(tree).to_string()),
+        Template::List(items, _) => Node::new("list", items.iter().map(self::template).collect()),
+        Template::Unquote(e) => Node::new("unquote", vec![expr(e)]),
+    }
+}
+
+/// 式の構造を1行のS式で書き出す関数
+///
+/// 子を持たない節は名前だけを、子を持つ節は `(名前 子...)` を書く。
+///
+/// # 引数
+/// * `expr` - 書き出す式
+///
+/// # 戻り値
+/// * `String` - 改行を含まない文字列
+pub fn to_sexpr(expr: &Expr) -> String {
+    let mut out = String::new();
+    write_sexpr(&mut out, &self::expr(expr));
+    out
+}
+
+fn write_sexpr(out: &mut String, node: &Node) {
+    if node.children.is_empty() {
+        out.push_str(&node.label);
+        return;
+    }
+    out.push('(');
+    out.push_str(&node.label);
+    for child in &node.children {
+        out.push(' ');
+        write_sexpr(out, child);
+    }
+    out.push(')');
+}
+
+/// 式の並びの構造を Graphviz の有向グラフとして書き出す関数
+///
+/// 節には出現順に `n0`、`n1`、... と名前を付け、親から子へ辺を引く。
+/// 最上位の式はそれぞれ根になる。
+///
+/// # 引数
+/// * `exprs` - 書き出す式の並び
+///
+/// # 戻り値
+/// * `String` - `digraph ast { ... }` の形の、改行で終わる文字列
+pub fn to_dot(exprs: &[Expr]) -> String {
+    let mut out = String::from("digraph ast {\n    node [shape=box];\n");
+    let mut next = 0;
+    for e in exprs {
+        write_dot(&mut out, &expr(e), &mut next);
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// 節とその子孫を書き出し、節に付けた番号を返す
+fn write_dot(out: &mut String, node: &Node, next: &mut usize) -> usize {
+    let id = *next;
+    *next += 1;
+    let label = node.label.replace('\\', "\\\\").replace('"', "\\\"");
+    out.push_str(&format!("    n{id} [label=\"{label}\"];\n"));
+    for child in &node.children {
+        let child = write_dot(out, child, next);
+        out.push_str(&format!("    n{id} -> n{child};\n"));
+    }
+    id
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::lower;
+    use crate::infix::parse_expr;
+    use crate::parser::source;
+    use crate::TokenTree;
+
+    fn lower_all(input: &str) -> Vec<Expr> {
+        let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+            panic!("failed to parse {input:?}");
+        };
+        forms.iter().map(|form| lower(form).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_sexpr() {
+        let exprs =
+            lower_all("(define f (fn (a b) (+ a b))) (let ((x 1)) (f x 2.5)) '(a \"s\") `(1 ,(g))");
+        let dumped: Vec<_> = exprs.iter().map(to_sexpr).collect();
+        assert_eq!(
+            dumped,
+            [
+                "(define f (fn (params a b) (+ a b)))",
+                "(let (bindings (x 1)) (call f x 2.5))",
+                "(quote (a \"s\"))",
+                "(quasiquote (list 1 (unquote (call g))))",
+            ]
+        );
+        let expr = parse_expr(
+            "{ var a = [1, {:}]; a[0] = nil; for i in 0..2 { break }; \
+             match a[0] { 1 if ok => \"one\", _ => return } }",
+        )
+        .unwrap();
+        assert_eq!(
+            to_sexpr(&expr),
+            "(block (var a (array 1 map)) (index= a 0 nil) (for i 0 2 (block break)) \
+             (match (index a 0) (arm 1 (if ok) \"one\") (arm _ return)))"
+        );
+    }
+
+    #[test]
+    fn test_dot() {
+        let exprs = lower_all("(+ 1 \"a\\\"b\") x");
+        assert_eq!(
+            to_dot(&exprs),
+            concat!(
+                "digraph ast {\n",
+                "    node [shape=box];\n",
+                "    n0 [label=\"+\"];\n",
+                "    n1 [label=\"1\"];\n",
+                "    n0 -> n1;\n",
+                "    n2 [label=\"\\\"a\\\\\\\"b\\\"\"];\n",
+                "    n0 -> n2;\n",
+                "    n3 [label=\"x\"];\n",
+                "}\n",
+            )
+        );
+        assert_eq!(to_dot(&[]), "digraph ast {\n    node [shape=box];\n}\n");
+    }
+}
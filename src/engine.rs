@@ -0,0 +1,240 @@
+//! 式の並びを評価する方式と、それらに共通の入り口
+//!
+//! 木をたどる評価器、スタックマシン、レジスタマシンは同じ環境と値を使い、[`Executable`] を
+//! 通して同じように式の並びを評価できる。どの方式で評価しても結果は同じになる。
+//! 仮想機械の方式は、式の並びをコンパイルした命令列に [`peephole::optimize`](crate::peephole::optimize)
+//! をかけてから実行する。
+//!
+//! ```
+//! use ruscal_b::engine::Engine;
+//! use ruscal_b::{eval::lower, parser::source, Environment, TokenTree, Value};
+//!
+//! let TokenTree::Tree(forms, _) = source("(define sq (fn (x) (* x x))) (sq 12)").unwrap() else {
+//!     unreachable!()
+//! };
+//! let exprs: Vec<_> = forms.iter().map(|form| lower(form).unwrap()).collect();
+//! for engine in Engine::ALL {
+//!     let value = engine.executable().execute(&exprs, &mut Environment::new());
+//!     assert_eq!(value, Ok(Value::I64(144)));
+//! }
+//! ```
+
+use crate::ast::Expr;
+use crate::bytecode::{compile_program, Bytecode};
+use crate::env::Environment;
+use crate::eval::{eval_expr, EvalError, Value};
+use crate::peephole::optimize;
+use crate::register_vm::RegisterVm;
+use crate::vm::Vm;
+
+/// 式の並びを評価する方式
+pub trait Executable {
+    /// 式の並びを `env` で順に評価し、最後の式の値を返す。式が無ければ `nil` を返す
+    fn execute(&mut self, exprs: &[Expr], env: &mut Environment) -> Result<Value, EvalError>;
+}
+
+/// 式の木をたどって評価する方式
+#[derive(Debug, Default)]
+pub struct TreeWalker;
+
+impl Executable for TreeWalker {
+    fn execute(&mut self, exprs: &[Expr], env: &mut Environment) -> Result<Value, EvalError> {
+        let mut last = Value::Nil;
+        for expr in exprs {
+            last = eval_expr(expr, env)?;
+        }
+        Ok(last)
+    }
+}
+
+impl Executable for Vm {
+    fn execute(&mut self, exprs: &[Expr], env: &mut Environment) -> Result<Value, EvalError> {
+        self.run(&compile_optimized(exprs), env)
+    }
+}
+
+impl Executable for RegisterVm {
+    fn execute(&mut self, exprs: &[Expr], env: &mut Environment) -> Result<Value, EvalError> {
+        self.run(&compile_optimized(exprs), env)
+    }
+}
+
+/// 式の並びをコンパイルし、覗き穴最適化をかけた命令列
+fn compile_optimized(exprs: &[Expr]) -> Bytecode {
+    let mut bytecode = compile_program(exprs);
+    optimize(&mut bytecode);
+    bytecode
+}
+
+/// 選べる評価の方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// [`TreeWalker`]
+    Tree,
+    /// [`Vm`]
+    #[default]
+    Stack,
+    /// [`RegisterVm`]
+    Register,
+}
+
+impl Engine {
+    /// すべての方式
+    pub const ALL: [Self; 3] = [Self::Tree, Self::Stack, Self::Register];
+
+    /// `--engine` に渡す `tree`、`stack`、`register` の名前から方式を得る
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "tree" => Some(Self::Tree),
+            "stack" => Some(Self::Stack),
+            "register" => Some(Self::Register),
+            _ => None,
+        }
+    }
+
+    /// この方式で評価する、新しい評価器
+    pub fn executable(self) -> Box<dyn Executable> {
+        match self {
+            Self::Tree => Box::new(TreeWalker),
+            Self::Stack => Box::new(Vm::new()),
+            Self::Register => Box::new(RegisterVm::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{ExprKind, Span};
+    use crate::eval::{lower, with_stack_size};
+    use crate::infix::statements;
+    use crate::limits::{run_limits, set_run_limits, RunLimits};
+    use crate::parser::source;
+    use crate::stdlib::register;
+    use crate::TokenTree;
+
+    /// すべての方式で評価し、結果が一致することを確かめる
+    fn agree(exprs: &[Expr], input: &str) {
+        let results: Vec<_> = Engine::ALL
+            .into_iter()
+            .map(|engine| {
+                let mut env = Environment::new();
+                register(&mut env);
+                engine.executable().execute(exprs, &mut env)
+            })
+            .collect();
+        assert_eq!(results[1], results[0], "stack vs tree: {input}");
+        assert_eq!(results[2], results[0], "register vs tree: {input}");
+    }
+
+    #[test]
+    fn test_sexpr_programs_agree() {
+        for input in [
+            include_str!("../examples/programs/fib.rscl"),
+            include_str!("../examples/programs/closures.rscl"),
+            "(/ 7 2) (/ -7 2) (/ 7 2.0) (- 5) (! 0)",
+            "(define f (fn (x y) (- x y))) (f 10 3) (f 1)",
+            "(let ((a 1) (b (+ a 1))) (* a b))",
+            "(define xs 0) (for (i 0 4) (define xs (+ xs i))) xs",
+            "(define + (fn (a b) (* a b))) (+ 3 4)",
+            "(* 9223372036854775807 2)",
+            "(define x 1) `(a (b ,x) ,(+ x 1) 'c)",
+            "(break)",
+            "(undefined 1)",
+        ] {
+            let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+                panic!("failed to parse {input:?}");
+            };
+            let exprs: Vec<_> = forms.iter().map(|form| lower(form).unwrap()).collect();
+            agree(&exprs, input);
+        }
+    }
+
+    #[test]
+    fn test_infix_programs_agree() {
+        for input in [
+            "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }\nfib(18)",
+            "var s = 0\nfor i in 0..20 { if i == 3 { continue }; if i > 15 { break }; s = s + i }\ns",
+            "var n = 10; var t = 1.0; while n > 0 { t = t * 1.5; n = n - 1 }; t",
+            "fn find(n) { for i in 0..10 { { var j = i * i; if j > n { return i } } }; -1 }\n\
+             find(20) * 100 + find(200)",
+            "var a = [1, [2, 3]]\na[1][0] = a[0] + 10\n[a, { \"k\": a[1], 0.5: nil }, len(a)]",
+            "fn f(x) { match x { 1 => \"one\", n if n > 9 => n, _ => 0 } }\n[f(1), f(10), f(5)]",
+            "fn count(n, acc) { match n { 0 => acc, _ => count(n - 1, acc + 1) } }\ncount(50, 0)",
+            "fn adder(n) { fn(x) { x + n } }\nvar add2 = adder(2)\nadd2(40)",
+            "var x = 1\nx = x + true",
+            "match 3 { 1 => 1, n if n < 0 => 2 }",
+            "var a = [1]\na[5] = 0",
+            "var x = 1; if x { return x }",
+        ] {
+            let block = Expr::new(
+                ExprKind::Block(statements(input).unwrap()),
+                Span::new(0, input.len()),
+            );
+            agree(std::slice::from_ref(&block), input);
+        }
+    }
+
+    #[test]
+    fn test_call_depth_agrees() {
+        // 呼び出しの深さの上限は `RunLimits` から取り、どの方式でもその深さまで呼び出せて、
+        // それを超えると同じエラーになる。
+        // `jit` の機能を有効にしていれば、スタックマシンは途中から機械語で再帰する
+        for (call, expected) in [
+            ("(down 4999)", Ok("4999".to_string())),
+            (
+                "(down 100000)",
+                Err(EvalError::StackOverflow {
+                    depth: 5000,
+                    stack_budget: None,
+                    span: Span::new(41, 55),
+                }),
+            ),
+        ] {
+            let input =
+                format!("(define down (fn (n) (if (== n 0) 0 (+ 1 (down (- n 1)))))) {call}");
+            let results = call_depth_results(&input, 5000);
+            for (i, result) in results.into_iter().enumerate() {
+                assert_eq!(result, expected, "engine {i}: {call}");
+            }
+        }
+    }
+
+    /// 呼び出しの深さを `max_call_depth` に制限し、どの方式でも評価した結果
+    fn call_depth_results(input: &str, max_call_depth: usize) -> Vec<Result<String, EvalError>> {
+        let (results, limit) = with_stack_size(1 << 30, || {
+            let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+                panic!("failed to parse {input:?}");
+            };
+            let exprs: Vec<_> = forms.iter().map(|form| lower(form).unwrap()).collect();
+            set_run_limits(RunLimits {
+                max_call_depth,
+                ..RunLimits::UNLIMITED
+            });
+            let mut results: Vec<_> = Engine::ALL
+                .into_iter()
+                .map(|engine| engine.executable().execute(&exprs, &mut Environment::new()))
+                .collect();
+            // `.rsclc` に書き出して読み直した命令列
+            let mut file = vec![];
+            compile_optimized(&exprs).write(&mut file).unwrap();
+            let bytecode = Bytecode::read(&mut file.as_slice()).unwrap();
+            results.push(Vm::new().run(&bytecode, &mut Environment::new()));
+            let results: Vec<_> = results
+                .into_iter()
+                .map(|result| result.map(|value| value.to_string()))
+                .collect();
+            (results, run_limits())
+        });
+        assert_eq!(limit.max_call_depth, max_call_depth);
+        results
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Engine::from_name("register"), Some(Engine::Register));
+        assert_eq!(Engine::from_name("tree"), Some(Engine::Tree));
+        assert_eq!(Engine::from_name("jit"), None);
+        assert_eq!(Engine::default(), Engine::Stack);
+    }
+}
@@ -0,0 +1,264 @@
+//! 変数の束縛を保持する環境
+//!
+//! 名前の解決は静的スコープに従う。
+//!
+//! * ブロック、`let`、`for` の繰り返し、関数の呼び出しはそれぞれ新しいスコープを開始する
+//! * 関数は定義した時点の環境を捕捉し、本体の名前は呼び出し側ではなく捕捉したスコープの連鎖から探す
+//! * 捕捉するのは束縛の値の写しではなくスコープそのものなので、後から定義や代入をした値も見える
+//! * 内側のスコープの定義は外側に漏れず、兄弟のスコープの間でも見えない
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::eval::Value;
+use crate::gc::{self, Trace};
+use crate::intern::Symbol;
+
+/// 1つのスコープで定義された束縛と、その外側のスコープへの参照
+struct Scope {
+    vars: HashMap<Symbol, Value>,
+    parent: Option<Rc<RefCell<Scope>>>,
+}
+
+/// スコープは束縛した値と外側のスコープを指す
+impl Trace for RefCell<Scope> {
+    fn trace(&self, visit: &mut dyn FnMut(usize, bool)) -> bool {
+        let Ok(scope) = self.try_borrow() else {
+            return false;
+        };
+        scope
+            .vars
+            .values()
+            .for_each(|value| gc::trace_value(value, true, visit));
+        if let Some(parent) = &scope.parent {
+            visit(gc::address(parent), true);
+        }
+        true
+    }
+
+    fn clear(&self) {
+        if let Ok(mut scope) = self.try_borrow_mut() {
+            let vars = std::mem::take(&mut scope.vars);
+            let parent = scope.parent.take();
+            drop(scope);
+            drop((vars, parent));
+        }
+    }
+}
+
+/// 入れ子のスコープを持つ変数の環境
+///
+/// スコープは外側のスコープへの参照をたどる連鎖として表し、名前は内側のスコープから順に探す。
+/// 名前は [`Symbol`] で持つので、`&str` を渡すとその都度インターナーに問い合わせる。
+/// 内側のスコープで同じ名前を定義すると外側の束縛を隠す。
+///
+/// `Environment` は現在のスコープへのハンドルなので、`clone()` したものは同じスコープを共有する。
+/// クロージャはこれを使って定義時の環境を捕捉する。
+#[derive(Clone)]
+pub struct Environment {
+    scope: Rc<RefCell<Scope>>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // クロージャが環境を捕捉すると循環するので、中身まではたどらない
+        f.debug_struct("Environment")
+            .field("depth", &self.depth())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Environment {
+    /// 大域スコープだけを持つ環境を作る
+    pub fn new() -> Self {
+        Self::with_parent(None)
+    }
+
+    fn with_parent(parent: Option<Rc<RefCell<Scope>>>) -> Self {
+        Self {
+            scope: gc::track(Rc::new(RefCell::new(Scope {
+                vars: HashMap::new(),
+                parent,
+            }))),
+        }
+    }
+
+    /// 現在のスコープの位置。[`gc`] で記録したスコープを見分けるのに使う
+    pub(crate) fn scope_address(&self) -> usize {
+        gc::address(&self.scope)
+    }
+
+    /// この環境を外側のスコープとする、新しい環境を作る
+    pub fn child(&self) -> Self {
+        Self::with_parent(Some(self.scope.clone()))
+    }
+
+    /// 新しい内側のスコープを開始する
+    pub fn push_scope(&mut self) {
+        *self = self.child();
+    }
+
+    /// 一番内側のスコープを終了し、外側のスコープに戻る
+    ///
+    /// 大域スコープは終了できない。終了したスコープもクロージャが捕捉していれば残る。
+    pub fn pop_scope(&mut self) {
+        let parent = self.scope.borrow().parent.clone();
+        if let Some(parent) = parent {
+            self.scope = parent;
+        }
+    }
+
+    /// 一番内側のスコープに変数を定義する
+    ///
+    /// 同じスコープに同名の変数があれば上書きする。
+    pub fn define(&mut self, name: impl Into<Symbol>, value: Value) {
+        self.scope.borrow_mut().vars.insert(name.into(), value);
+    }
+
+    /// 定義済みの変数に値を代入する
+    ///
+    /// 名前を内側のスコープから順に探し、最初に見つかった束縛を書き換える。
+    ///
+    /// # 戻り値
+    /// * `bool` - 代入できれば `true`。どのスコープにも定義されていなければ `false`
+    pub fn assign(&mut self, name: impl Into<Symbol>, value: Value) -> bool {
+        let name = name.into();
+        let mut scope = self.scope.clone();
+        loop {
+            if let Some(slot) = scope.borrow_mut().vars.get_mut(&name) {
+                *slot = value;
+                return true;
+            }
+            let Some(parent) = scope.borrow().parent.clone() else {
+                return false;
+            };
+            scope = parent;
+        }
+    }
+
+    /// 変数の値を内側のスコープから順に探す
+    pub fn get(&self, name: impl Into<Symbol>) -> Option<Value> {
+        let name = name.into();
+        let mut scope = self.scope.clone();
+        loop {
+            if let Some(value) = scope.borrow().vars.get(&name) {
+                return Some(value.clone());
+            }
+            let parent = scope.borrow().parent.clone()?;
+            scope = parent;
+        }
+    }
+
+    /// 現在のスコープから見える束縛を、名前の順に並べて返す
+    ///
+    /// 内側のスコープの束縛に隠された外側の束縛は含めない。
+    pub fn bindings(&self) -> Vec<(Symbol, Value)> {
+        self.collect(true)
+    }
+
+    /// 大域スコープより内側のスコープから見える束縛を、名前の順に並べて返す
+    pub fn locals(&self) -> Vec<(Symbol, Value)> {
+        self.collect(false)
+    }
+
+    fn collect(&self, global: bool) -> Vec<(Symbol, Value)> {
+        let mut seen = HashMap::new();
+        let mut scope = Some(self.scope.clone());
+        while let Some(current) = scope {
+            let parent = current.borrow().parent.clone();
+            if global || parent.is_some() {
+                for (name, value) in &current.borrow().vars {
+                    seen.entry(*name).or_insert_with(|| value.clone());
+                }
+            }
+            scope = parent;
+        }
+        let mut bindings: Vec<_> = seen.into_iter().collect();
+        bindings.sort_by_key(|(name, _)| name.as_str());
+        bindings
+    }
+
+    /// 現在のスコープの深さ。大域スコープだけなら1
+    pub fn depth(&self) -> usize {
+        let mut depth = 1;
+        let mut scope = self.scope.clone();
+        while let Some(parent) = scope.clone().borrow().parent.clone() {
+            depth += 1;
+            scope = parent;
+        }
+        depth
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shadowing() {
+        let mut env = Environment::new();
+        env.define("x", Value::I64(1));
+        env.push_scope();
+        env.define("x", Value::I64(2));
+        env.define("y", Value::I64(3));
+        assert_eq!(env.get("x"), Some(Value::I64(2)));
+        env.pop_scope();
+        assert_eq!(env.get("x"), Some(Value::I64(1)));
+        assert_eq!(env.get("y"), None);
+    }
+
+    #[test]
+    fn test_global_scope_is_kept() {
+        let mut env = Environment::new();
+        env.pop_scope();
+        env.define("x", Value::I64(1));
+        assert_eq!(env.depth(), 1);
+        assert_eq!(env.get("x"), Some(Value::I64(1)));
+    }
+
+    #[test]
+    fn test_assign() {
+        let mut env = Environment::new();
+        env.define("x", Value::I64(1));
+        env.push_scope();
+        assert!(env.assign("x", Value::I64(2)));
+        assert!(!env.assign("y", Value::I64(3)));
+        env.pop_scope();
+        assert_eq!(env.get("x"), Some(Value::I64(2)));
+        assert_eq!(env.get("y"), None);
+    }
+
+    #[test]
+    fn test_child_shares_parent() {
+        let mut global = Environment::new();
+        let child = global.child();
+        global.define("late", Value::I64(1));
+        assert_eq!(child.get("late"), Some(Value::I64(1)));
+        assert_eq!(child.depth(), 2);
+    }
+
+    #[test]
+    fn test_bindings() {
+        let mut env = Environment::new();
+        env.define("b", Value::I64(1));
+        env.define("a", Value::I64(2));
+        env.push_scope();
+        env.define("b", Value::I64(3));
+        assert_eq!(
+            env.bindings(),
+            [
+                (Symbol::intern("a"), Value::I64(2)),
+                (Symbol::intern("b"), Value::I64(3)),
+            ]
+        );
+        assert_eq!(env.locals(), [(Symbol::intern("b"), Value::I64(3))]);
+    }
+}
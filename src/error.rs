@@ -0,0 +1,141 @@
+//! クレート全体のエラーをまとめた [`Error`]
+//!
+//! 解析から評価までの各段階のエラーはそれぞれ [`std::error::Error`] を実装している。
+//! いくつかの段階を続けて呼ぶ場合は、どれも `?` で [`Error`] に変換できる。
+//!
+//! ```
+//! use ruscal_b::{eval, source, Error, TokenTree, Value};
+//!
+//! fn first(input: &str) -> Result<Value, Error> {
+//!     let TokenTree::Tree(forms, _) = source(input)? else {
+//!         unreachable!("source() always returns a tree");
+//!     };
+//!     Ok(eval(&forms[0])?)
+//! }
+//!
+//! assert_eq!(first("(+ 1 2)").unwrap(), Value::I64(3));
+//! assert!(matches!(first("(+ 1"), Err(Error::Parse(_))));
+//! assert!(matches!(first("(/ 1 0)"), Err(Error::Eval(_))));
+//! ```
+
+use std::fmt;
+
+use crate::eval::EvalError;
+use crate::json::JsonError;
+use crate::lexer::LexError;
+use crate::parser::ParseError;
+use crate::transpile::TranspileError;
+use crate::typecheck::TypeError;
+
+/// 解析から評価までのいずれかの段階で起きたエラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// 字句解析のエラー
+    Lex(LexError),
+    /// 構文解析のエラー
+    Parse(ParseError),
+    /// 型検査のエラー
+    Type(TypeError),
+    /// 評価のエラー
+    Eval(EvalError),
+    /// JSONの解析や変換のエラー
+    Json(JsonError),
+    /// Rust や WebAssembly への変換のエラー
+    Transpile(TranspileError),
+    /// ファイルを読み込めなかった。`message` は失敗の説明
+    Io { path: String, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lex(e) => write!(f, "{e}"),
+            Self::Parse(e) => write!(f, "{e}"),
+            Self::Type(e) => write!(f, "{e}"),
+            Self::Eval(e) => write!(f, "{e}"),
+            Self::Json(e) => write!(f, "{e}"),
+            Self::Transpile(e) => write!(f, "{e}"),
+            Self::Io { path, message } => write!(f, "failed to read `{path}`: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Lex(e) => Some(e),
+            Self::Parse(e) => Some(e),
+            Self::Type(e) => Some(e),
+            Self::Eval(e) => Some(e),
+            Self::Json(e) => Some(e),
+            Self::Transpile(e) => Some(e),
+            Self::Io { .. } => None,
+        }
+    }
+}
+
+impl From<LexError> for Error {
+    fn from(e: LexError) -> Self {
+        Self::Lex(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
+}
+
+impl From<TypeError> for Error {
+    fn from(e: TypeError) -> Self {
+        Self::Type(e)
+    }
+}
+
+impl From<EvalError> for Error {
+    fn from(e: EvalError) -> Self {
+        Self::Eval(e)
+    }
+}
+
+impl From<JsonError> for Error {
+    fn from(e: JsonError) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl From<TranspileError> for Error {
+    fn from(e: TranspileError) -> Self {
+        Self::Transpile(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Expected;
+    use std::error::Error as _;
+
+    /// スレッドをまたいで渡せるエラー型であることを確かめる
+    fn assert_error<E: std::error::Error + Send + Sync + 'static>() {}
+
+    #[test]
+    fn test_error_traits() {
+        assert_error::<Error>();
+        assert_error::<LexError>();
+        assert_error::<ParseError>();
+        assert_error::<TypeError>();
+        assert_error::<EvalError>();
+        assert_error::<TranspileError>();
+    }
+
+    #[test]
+    fn test_source() {
+        let lex = LexError::new(2, Expected::Token, Some('@'));
+        let e = Error::from(lex.clone());
+        assert_eq!(e.to_string(), lex.to_string());
+        assert_eq!(e.source().map(ToString::to_string), Some(lex.to_string()));
+        let boxed: Box<dyn std::error::Error + Send + Sync> = Box::new(e);
+        assert!(boxed.downcast_ref::<Error>().is_some());
+    }
+}
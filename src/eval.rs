@@ -0,0 +1,2982 @@
+//! `Expr` を評価する木構造の評価器
+//!
+//! S式の `TokenTree` は前置記法の式として `Expr` に変換してから評価する。
+
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use crate::ast::{
+    BinOp, Expr, ExprKind, MatchArm, OwnedToken, OwnedTokenTree, Pattern, Span, Statement,
+    Template, Token, TokenTree, UnOp,
+};
+use crate::bytecode::Bytecode;
+use crate::env::Environment;
+use crate::float_format::float_format;
+use crate::gc;
+use crate::intern::Symbol;
+use crate::lexer::unescape;
+use crate::limits::{self, Limit};
+use crate::module::{self, Module};
+use crate::stdlib::{char_offset, NativeFn};
+use crate::vm::Vm;
+
+/// 評価結果の値
+///
+/// [`fmt::Display`] はユーザーがソースコードに書く形で値を表示し、`Debug` は内部の構造を表示する。
+///
+/// Rust の `==`、`<` と [`Hash`] は [`Value::total_cmp`] の全順序に従い、同じ値かどうかを比べる。
+/// 言語の `==` と `<` はこれとは別の規則で、整数と浮動小数点数を数値として比べ、NaN はどの値とも等しくない。
+#[derive(Debug, Clone)]
+pub enum Value {
+    /// 浮動小数点数
+    F64(f64),
+    /// 整数
+    I64(i64),
+    /// 文字列
+    Str(Rc<str>),
+    /// 真偽値
+    Bool(bool),
+    /// 値の並び
+    List(Rc<[Value]>),
+    /// 要素を書き換えられる配列。複製した値は同じ配列を指す
+    ///
+    /// 外側の `Rc` が配列そのもので、内側の `Rc` が要素の並び。`copy` で作った写しは要素の並びを
+    /// 共有し、どちらかを初めて書き換えるときに [`Rc::make_mut`] で並びを複製する。
+    Array(Rc<RefCell<Rc<Vec<Value>>>>),
+    /// キーから値を引く書き換えられるマップ。キーは [`Value::is_key`] が真になる値に限る
+    ///
+    /// 配列と同じく、`copy` で作った写しとは書き換えるまで中身を共有する。
+    Map(Rc<RefCell<Rc<HashMap<Value, Value>>>>),
+    /// `import` で読み込んだモジュール
+    Module(Rc<Module>),
+    /// 引用したデータに現れる識別子
+    Symbol(Symbol),
+    /// ユーザーが定義した関数
+    Fn(Rc<Function>),
+    /// 組み込みの関数
+    NativeFn(Rc<NativeFn>),
+    /// 値が無いことを表す `nil`。`else` の無い `if` の条件が偽のときや空のブロックの値にもなる
+    Nil,
+}
+
+impl Value {
+    /// 要素を並べた配列を作る。循環しても [`gc::collect`] で回収できるように記録する
+    ///
+    /// 共有している要素の並びを渡すと、その並びを書き換えるまで共有する新しい配列になる。
+    pub fn array(items: impl Into<Rc<Vec<Value>>>) -> Self {
+        Self::Array(gc::track(Rc::new(RefCell::new(items.into()))))
+    }
+
+    /// キーと値の組からマップを作る。循環しても [`gc::collect`] で回収できるように記録する
+    pub fn map(entries: impl Into<Rc<HashMap<Value, Value>>>) -> Self {
+        Self::Map(gc::track(Rc::new(RefCell::new(entries.into()))))
+    }
+
+    /// 関数の値を作る。捕捉したスコープと循環しても [`gc::collect`] で回収できるように記録する
+    pub fn function(function: Function) -> Self {
+        Self::Fn(gc::track(Rc::new(function)))
+    }
+
+    /// 条件式で値を真偽として扱うときの真偽
+    ///
+    /// `false`、`nil`、整数の `0`、浮動小数点数の `0.0` と NaN、空の文字列、空のリスト、空の配列、
+    /// 空のマップを偽とし、
+    /// それ以外を真とする。
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Self::Bool(b) => *b,
+            Self::I64(n) => *n != 0,
+            Self::F64(n) => *n != 0.0 && !n.is_nan(),
+            Self::Str(s) => !s.is_empty(),
+            Self::List(items) => !items.is_empty(),
+            Self::Array(items) => !items.borrow().is_empty(),
+            Self::Map(entries) => !entries.borrow().is_empty(),
+            Self::Symbol(_) | Self::Fn(_) | Self::NativeFn(_) | Self::Module(_) => true,
+            Self::Nil => false,
+        }
+    }
+
+    /// マップのキーにできる値かどうか
+    ///
+    /// 整数、文字列、真偽値、`nil` をキーにできる。浮動小数点数は NaN が自身と等しくならず、
+    /// 配列とマップは書き換えると等しさが変わるので、キーにできない。
+    pub fn is_key(&self) -> bool {
+        matches!(
+            self,
+            Self::I64(_) | Self::Str(_) | Self::Bool(_) | Self::Nil
+        )
+    }
+}
+
+impl Value {
+    /// すべての値の全順序で、2つの値を比べる関数
+    ///
+    /// 種類の違う値は `nil`、真偽値、数値、文字列、シンボル、リスト、配列、マップ、モジュール、
+    /// 関数、組み込みの関数の順に並べる。同じ種類の値は次のように比べる。
+    ///
+    /// * 数値は整数と浮動小数点数をまとめて、値の大小を丸めずに比べる。同じ大きさなら整数を先にし、
+    ///   `-0.0` は `0.0` より前に置く。NaN はどの数値よりも後ろで、NaN 同士は等しい
+    /// * 文字列とシンボルは名前の辞書順、リストと配列は要素の辞書順
+    /// * マップはキーの順に並べた (キー, 値) の組の辞書順
+    /// * モジュールと関数は同一のものだけを等しいとし、それ以外は同じ実行の中でだけ決まった順にする
+    ///
+    /// `Equal` になるのは同じ値、つまり表示も振る舞いも区別できない値に限る。
+    /// 循環する配列やマップは、比べている途中の同じ組に戻ったらその組を等しいとするので、
+    /// 要素をたどって違いが見つからない限り等しい。
+    pub fn total_cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Bool(a), Self::Bool(b)) => a.cmp(b),
+            (Self::I64(a), Self::I64(b)) => a.cmp(b),
+            (Self::F64(a), Self::F64(b)) => match (a.is_nan(), b.is_nan()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => a.total_cmp(b),
+            },
+            (Self::I64(a), Self::F64(b)) => cmp_int_float(*a, *b).then(Ordering::Less),
+            (Self::F64(a), Self::I64(b)) => cmp_int_float(*b, *a).reverse().then(Ordering::Greater),
+            (Self::Str(a), Self::Str(b)) => a.cmp(b),
+            (Self::Symbol(a), Self::Symbol(b)) => a.as_str().cmp(b.as_str()),
+            (Self::List(a), Self::List(b)) => a.iter().cmp(b.iter()),
+            (Self::Array(a), Self::Array(b)) if Rc::ptr_eq(a, b) => Ordering::Equal,
+            (Self::Array(a), Self::Array(b)) => {
+                let Some(_visit) = Visit::enter(&COMPARING, (address(a), address(b))) else {
+                    return Ordering::Equal;
+                };
+                a.borrow().iter().cmp(b.borrow().iter())
+            }
+            (Self::Map(a), Self::Map(b)) if Rc::ptr_eq(a, b) => Ordering::Equal,
+            (Self::Map(a), Self::Map(b)) => {
+                let Some(_visit) = Visit::enter(&COMPARING, (address(a), address(b))) else {
+                    return Ordering::Equal;
+                };
+                let entries = |map: &HashMap<Value, Value>| {
+                    sorted_keys(map)
+                        .into_iter()
+                        .map(|key| {
+                            let value = map[&key].clone();
+                            (key, value)
+                        })
+                        .collect::<Vec<_>>()
+                };
+                entries(&a.borrow()).cmp(&entries(&b.borrow()))
+            }
+            (Self::Module(a), Self::Module(b)) => address(a).cmp(&address(b)),
+            (Self::Fn(a), Self::Fn(b)) => address(a).cmp(&address(b)),
+            (Self::NativeFn(a), Self::NativeFn(b)) => address(a).cmp(&address(b)),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+
+    /// 種類の違う値を [`total_cmp`](Self::total_cmp) で並べる順
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Nil => 0,
+            Self::Bool(_) => 1,
+            Self::I64(_) | Self::F64(_) => 2,
+            Self::Str(_) => 3,
+            Self::Symbol(_) => 4,
+            Self::List(_) => 5,
+            Self::Array(_) => 6,
+            Self::Map(_) => 7,
+            Self::Module(_) => 8,
+            Self::Fn(_) => 9,
+            Self::NativeFn(_) => 10,
+        }
+    }
+}
+
+/// 整数と浮動小数点数の大小を、整数を丸めずに比べる。NaN はどの整数よりも大きいとする
+fn cmp_int_float(a: i64, b: f64) -> Ordering {
+    // `i64::MIN` は -2^63 で、浮動小数点数として正確に表せる
+    const MIN: f64 = i64::MIN as f64;
+    if b.is_nan() || b >= -MIN {
+        return Ordering::Less;
+    }
+    if b < MIN {
+        return Ordering::Greater;
+    }
+    let truncated = b.trunc();
+    a.cmp(&(truncated as i64)).then_with(|| {
+        // 整数部が等しければ、小数部の符号で決まる
+        if b > truncated {
+            Ordering::Less
+        } else if b < truncated {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    })
+}
+
+/// 同一かどうかだけで比べる値の、同じ実行の中で決まった並び順
+fn address<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as usize
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.total_cmp(other) == Ordering::Equal
+    }
+}
+
+/// [`Value::total_cmp`] は NaN 同士も等しいとするので、比較は反射的になる
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.total_cmp(other)
+    }
+}
+
+/// 等しい値は同じハッシュ値になる。数値、文字列、真偽値以外は種類だけからハッシュ値を求める
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::I64(n) => n.hash(state),
+            // NaN はどれも等しいので、同じビット列にしてから求める
+            Self::F64(n) if n.is_nan() => f64::NAN.to_bits().hash(state),
+            Self::F64(n) => n.to_bits().hash(state),
+            Self::Str(s) => s.hash(state),
+            Self::Bool(b) => b.hash(state),
+            _ => {}
+        }
+    }
+}
+
+/// マップのキーを表示する順に並べる関数
+///
+/// [`Value::total_cmp`] の順、つまり `nil`、真偽値、整数、文字列の順に並べ、
+/// 同じ種類の値はその値の順に並べる。
+pub(crate) fn sorted_keys(entries: &HashMap<Value, Value>) -> Vec<Value> {
+    let mut keys: Vec<_> = entries.keys().cloned().collect();
+    keys.sort();
+    keys
+}
+
+/// 値をたどっている途中の配列とマップのアドレスの組
+type Visiting = std::thread::LocalKey<RefCell<Vec<(usize, usize)>>>;
+
+thread_local! {
+    /// 表示している途中の配列とマップ。2つ目のアドレスは使わない
+    static DISPLAYING: RefCell<Vec<(usize, usize)>> = const { RefCell::new(Vec::new()) };
+    /// 比べている途中の配列とマップの組
+    static COMPARING: RefCell<Vec<(usize, usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// 循環する値をたどるときに、たどっている途中の値を記録する。破棄すると記録を取り除く
+struct Visit(&'static Visiting);
+
+impl Visit {
+    /// `key` をたどり始める。もうたどっている途中なら `None` を返す
+    fn enter(visiting: &'static Visiting, key: (usize, usize)) -> Option<Self> {
+        visiting.with(|keys| {
+            let mut keys = keys.borrow_mut();
+            if keys.contains(&key) {
+                return None;
+            }
+            keys.push(key);
+            Some(Self(visiting))
+        })
+    }
+}
+
+impl Drop for Visit {
+    fn drop(&mut self) {
+        self.0.with(|keys| keys.borrow_mut().pop());
+    }
+}
+
+/// 配列とマップは、表示している途中の同じ値に戻ったら `[...]` や `{...}` と表示する
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::I64(n) => write!(f, "{n}"),
+            // 既定では整数と区別できるよう、`1.0` のように小数点を付けて表示する
+            Self::F64(n) => f.write_str(&float_format().format(*n)),
+            Self::Str(s) => {
+                // 文字列リテラルとして読み直せるよう、引用符で囲んでエスケープする
+                f.write_str("\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' | '\\' => write!(f, "\\{c}")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\t' => f.write_str("\\t")?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                f.write_str("\"")
+            }
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::List(items) => {
+                f.write_str("(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str(")")
+            }
+            Self::Array(items) => {
+                let Some(_visit) = Visit::enter(&DISPLAYING, (address(items), 0)) else {
+                    return f.write_str("[...]");
+                };
+                f.write_str("[")?;
+                for (i, item) in items.borrow().iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Self::Map(entries) => {
+                let Some(_visit) = Visit::enter(&DISPLAYING, (address(entries), 0)) else {
+                    return f.write_str("{...}");
+                };
+                let entries = entries.borrow();
+                if entries.is_empty() {
+                    return f.write_str("{:}");
+                }
+                f.write_str("{")?;
+                for (i, key) in sorted_keys(&entries).iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{key}: {}", entries[key])?;
+                }
+                f.write_str("}")
+            }
+            Self::Symbol(name) => write!(f, "{name}"),
+            Self::Fn(func) => write!(
+                f,
+                "<fn ({})>",
+                func.params
+                    .iter()
+                    .map(|p| p.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            Self::NativeFn(func) => write!(f, "<native fn {}>", func.name),
+            Self::Module(module) => write!(f, "<module {}>", module.name),
+            Self::Nil => f.write_str("nil"),
+        }
+    }
+}
+
+/// `fn` 式で作られる、定義時の環境を捕捉した関数
+pub struct Function {
+    /// 仮引数の名前
+    pub params: Vec<Symbol>,
+    /// 関数の本体
+    pub body: FunctionBody,
+    /// 関数を定義したときの環境
+    pub env: Environment,
+    /// 関数を定義した `fn` 式の範囲
+    pub span: Span,
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Function")
+            .field("params", &self.params)
+            .field("span", &self.span)
+            .finish_non_exhaustive()
+    }
+}
+
+/// 関数の本体の表現
+///
+/// どちらの本体を持つ関数も、木構造の評価器と [`Vm`](crate::vm::Vm) の両方から呼び出せる。
+#[derive(Debug, Clone)]
+pub enum FunctionBody {
+    /// 木構造の評価器で評価する式の並び。最後の式の値が戻り値になる
+    Tree(Rc<[Expr]>),
+    /// [`Vm`](crate::vm::Vm) で実行するコンパイル済みの命令列
+    Compiled(Rc<Bytecode>),
+}
+
+/// 関数は同じ `fn` 式の評価で作られた同一のものだけを等しいとみなす
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// 評価に失敗したときのエラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// 空の括弧 `()` を評価しようとした
+    EmptyForm { span: Span },
+    /// 関数ではない値を呼び出そうとした
+    NotAFunction { span: Span },
+    /// 定義されていない演算子や識別子を使った
+    UnknownIdentifier { name: String, span: Span },
+    /// 関数や演算子に渡した引数の数が合わない
+    ///
+    /// `span` は呼び出し式の範囲、`definition` はユーザー定義の関数の `fn` 式の範囲。
+    /// 組み込みの関数と演算子には `definition` が無い。
+    Arity {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+        definition: Option<Span>,
+    },
+    /// 可変個の引数を取る組み込みの関数に、下限 `min` より少ない引数を渡した
+    TooFewArguments {
+        name: String,
+        min: usize,
+        found: usize,
+        span: Span,
+    },
+    /// 数値として評価できない式を評価しようとした
+    NotANumber { span: Span },
+    /// 整数演算の結果が `i64` に収まらない
+    IntegerOverflow { span: Span },
+    /// 整数を0で割った
+    DivisionByZero { span: Span },
+    /// `define` や `let` などの特殊形式の書き方が正しくない
+    MalformedForm { form: &'static str, span: Span },
+    /// 整数として評価できない式を評価しようとした
+    NotAnInteger { span: Span },
+    /// ループの外で `break` や `continue` を使った
+    OutsideLoop { keyword: &'static str, span: Span },
+    /// 関数の外で `return` を使った
+    OutsideFunction { span: Span },
+    /// 関数の呼び出しが深さかスタックの上限を超えた。`depth` はそのときの呼び出しの深さ
+    ///
+    /// `stack_budget` は、深さの上限より先にスタックの予算を使い切ったときの予算のバイト数。
+    StackOverflow {
+        depth: usize,
+        stack_budget: Option<usize>,
+        span: Span,
+    },
+    /// 組み込みの関数に受け取れない種類の値を渡した
+    TypeMismatch { expected: &'static str, span: Span },
+    /// `match` のどの腕も値に一致しなかった。`value` は値の表示
+    NoMatch { value: String, span: Span },
+    /// 配列かリストの長さ `len` の範囲の外の位置 `index` を参照した
+    IndexOutOfBounds { index: i64, len: usize, span: Span },
+    /// `format` の書式文字列が正しくない。`reason` は正しくない理由
+    MalformedFormat { reason: &'static str, span: Span },
+    /// `format` の書式文字列の `{}` の数と引数の数が合わない
+    FormatArity {
+        placeholders: usize,
+        found: usize,
+        span: Span,
+    },
+    /// `import` したファイルが見つからない
+    ModuleNotFound { path: String, span: Span },
+    /// `import` が読み込んでいる途中のモジュールを読み込もうとした。`cycle` は循環するパスの並び
+    ImportCycle { cycle: Vec<String>, span: Span },
+    /// `import` したファイルの読み込み、解析、評価のいずれかに失敗した。`message` は失敗の説明
+    ModuleFailed {
+        path: String,
+        message: String,
+        span: Span,
+    },
+    /// 評価が [`set_run_limits`](crate::limits::set_run_limits) で設定した上限を超えた
+    FuelExhausted { limit: Limit, span: Span },
+    /// [`IoPolicy`](crate::io::IoPolicy) が許していない入出力をしようとした
+    ///
+    /// `operation` は標準関数の名前、`path` はファイルを扱う関数に渡したパス。
+    IoDenied {
+        operation: &'static str,
+        path: Option<String>,
+        span: Span,
+    },
+    /// ファイルか標準入力の読み書きに失敗した。`message` は失敗の説明
+    IoFailed {
+        path: String,
+        message: String,
+        span: Span,
+    },
+    /// `assert` か `assert_eq` が失敗した。`message` は失敗の説明
+    AssertionFailed { message: String, span: Span },
+    /// 実行を外から打ち切った
+    Interrupted { span: Span },
+}
+
+impl EvalError {
+    /// エラーの種類ごとに決まった、変わらない番号
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::EmptyForm { .. } => "E0101",
+            Self::NotAFunction { .. } => "E0102",
+            Self::UnknownIdentifier { .. } => "E0103",
+            Self::Arity { .. } | Self::TooFewArguments { .. } => "E0104",
+            Self::NotANumber { .. } => "E0105",
+            Self::IntegerOverflow { .. } => "E0106",
+            Self::DivisionByZero { .. } => "E0107",
+            Self::MalformedForm { .. } => "E0108",
+            Self::NotAnInteger { .. } => "E0109",
+            Self::OutsideLoop { .. } => "E0110",
+            Self::OutsideFunction { .. } => "E0111",
+            Self::StackOverflow { .. } => "E0112",
+            Self::TypeMismatch { .. } => "E0113",
+            Self::NoMatch { .. } => "E0114",
+            Self::IndexOutOfBounds { .. } => "E0115",
+            Self::MalformedFormat { .. } => "E0116",
+            Self::FormatArity { .. } => "E0117",
+            Self::ModuleNotFound { .. } => "E0118",
+            Self::ImportCycle { .. } => "E0119",
+            Self::ModuleFailed { .. } => "E0120",
+            Self::FuelExhausted { .. } => "E0121",
+            Self::IoDenied { .. } => "E0122",
+            Self::IoFailed { .. } => "E0123",
+            Self::AssertionFailed { .. } => "E0124",
+            Self::Interrupted { .. } => "E0125",
+        }
+    }
+
+    /// エラーの原因となった式の範囲
+    pub fn span(&self) -> Span {
+        match self {
+            Self::EmptyForm { span }
+            | Self::NotAFunction { span }
+            | Self::UnknownIdentifier { span, .. }
+            | Self::Arity { span, .. }
+            | Self::TooFewArguments { span, .. }
+            | Self::NotANumber { span }
+            | Self::IntegerOverflow { span }
+            | Self::DivisionByZero { span }
+            | Self::MalformedForm { span, .. }
+            | Self::NotAnInteger { span }
+            | Self::OutsideLoop { span, .. }
+            | Self::OutsideFunction { span }
+            | Self::StackOverflow { span, .. }
+            | Self::TypeMismatch { span, .. }
+            | Self::NoMatch { span, .. }
+            | Self::IndexOutOfBounds { span, .. }
+            | Self::MalformedFormat { span, .. }
+            | Self::FormatArity { span, .. }
+            | Self::ModuleNotFound { span, .. }
+            | Self::ImportCycle { span, .. }
+            | Self::ModuleFailed { span, .. }
+            | Self::FuelExhausted { span, .. }
+            | Self::IoDenied { span, .. }
+            | Self::IoFailed { span, .. }
+            | Self::AssertionFailed { span, .. }
+            | Self::Interrupted { span } => *span,
+        }
+    }
+
+    /// 位置を含まないエラーの説明
+    pub fn message(&self) -> String {
+        match self {
+            Self::EmptyForm { .. } => "cannot evaluate empty form".to_string(),
+            Self::NotAFunction { .. } => "called a value that is not a function".to_string(),
+            Self::UnknownIdentifier { name, .. } => format!("unknown identifier `{name}`"),
+            Self::Arity {
+                name,
+                expected,
+                found,
+                ..
+            } => {
+                let plural = if *expected == 1 { "" } else { "s" };
+                format!("`{name}` expected {expected} argument{plural}, found {found}")
+            }
+            Self::TooFewArguments {
+                name, min, found, ..
+            } => {
+                let plural = if *min == 1 { "" } else { "s" };
+                format!("`{name}` expected at least {min} argument{plural}, found {found}")
+            }
+            Self::NotANumber { .. } => "expected a number".to_string(),
+            Self::IntegerOverflow { .. } => "integer overflow".to_string(),
+            Self::DivisionByZero { .. } => "integer division by zero".to_string(),
+            Self::MalformedForm { form, .. } => format!("malformed `{form}` form"),
+            Self::NotAnInteger { .. } => "expected an integer".to_string(),
+            Self::OutsideLoop { keyword, .. } => format!("`{keyword}` outside of a loop"),
+            Self::OutsideFunction { .. } => "`return` outside of a function".to_string(),
+            Self::StackOverflow {
+                depth,
+                stack_budget: None,
+                ..
+            } => format!("stack overflow after {depth} nested calls"),
+            Self::StackOverflow {
+                depth,
+                stack_budget: Some(budget),
+                ..
+            } => format!(
+                "stack overflow after {depth} nested calls: the thread's stack budget of \
+                 {budget} bytes ran out before the call depth limit; \
+                 evaluate on a larger stack with `eval::with_stack_size`"
+            ),
+            Self::TypeMismatch { expected, .. } => format!("expected {expected}"),
+            Self::NoMatch { value, .. } => format!("no `match` arm matches {value}"),
+            Self::IndexOutOfBounds { index, len, .. } => {
+                format!("index {index} is out of bounds for length {len}")
+            }
+            Self::MalformedFormat { reason, .. } => format!("invalid format string: {reason}"),
+            Self::FormatArity {
+                placeholders,
+                found,
+                ..
+            } => {
+                let plural = |n: usize| if n == 1 { "" } else { "s" };
+                format!(
+                    "format string has {placeholders} placeholder{}, found {found} argument{}",
+                    plural(*placeholders),
+                    plural(*found)
+                )
+            }
+            Self::ModuleNotFound { path, .. } => format!("cannot find module `{path}`"),
+            Self::ImportCycle { cycle, .. } => format!("import cycle: {}", cycle.join(" -> ")),
+            Self::ModuleFailed { path, message, .. } => {
+                format!("failed to load module `{path}`: {message}")
+            }
+            Self::FuelExhausted { limit, .. } => {
+                format!("ran out of fuel: exceeded the limit of {limit}")
+            }
+            Self::IoDenied {
+                operation,
+                path: Some(path),
+                ..
+            } => format!("`{operation}` is not allowed to access `{path}`"),
+            Self::IoDenied { operation, .. } => format!("`{operation}` is not allowed"),
+            Self::IoFailed { path, message, .. } => format!("cannot access `{path}`: {message}"),
+            Self::AssertionFailed { message, .. } => format!("assertion failed: {message}"),
+            Self::Interrupted { .. } => "execution was interrupted".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message(), self.span().start)
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// 評価を途中で打ち切って外側へ伝える信号
+///
+/// `break` と `continue` は一番内側のループまで伝わり、そこで消費される。
+/// ループの外まで伝わった信号は [`ControlFlow::into_error`] でエラーにする。
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlFlow {
+    /// `break` によるループの脱出
+    Break { span: Span },
+    /// `continue` による次の繰り返しへの移動
+    Continue { span: Span },
+    /// `return` による関数の脱出
+    Return { value: Value, span: Span },
+    /// 評価のエラー
+    Error(EvalError),
+}
+
+impl ControlFlow {
+    /// ループで消費されなかった信号をエラーにする
+    pub fn into_error(self) -> EvalError {
+        match self {
+            Self::Break { span } => EvalError::OutsideLoop {
+                keyword: "break",
+                span,
+            },
+            Self::Continue { span } => EvalError::OutsideLoop {
+                keyword: "continue",
+                span,
+            },
+            Self::Return { span, .. } => EvalError::OutsideFunction { span },
+            Self::Error(e) => e,
+        }
+    }
+}
+
+impl From<EvalError> for ControlFlow {
+    fn from(e: EvalError) -> Self {
+        Self::Error(e)
+    }
+}
+
+/// S式の木を式として評価する関数
+///
+/// 括弧は先頭の要素を演算子、残りを引数とする前置記法の式として評価する。
+/// 演算子には `+`, `-`, `*`, `/` を使え、いずれも任意個の引数を取る。`%` は2つ以上の引数を取る。
+/// 空の環境で評価するので、変数の定義は評価後に残らない。
+///
+/// # 引数
+/// * `tree` - 評価する式
+///
+/// # 戻り値
+/// * `Result<Value, EvalError>` - 評価結果の値
+pub fn eval(tree: &TokenTree) -> Result<Value, EvalError> {
+    eval_expr(&lower(tree)?, &mut Environment::new())
+}
+
+/// S式の木を評価可能な `Expr` に変換する関数
+///
+/// 引数が2つ以上の四則演算と論理演算は左結合の二項演算に、引数が2つの比較は二項演算に、
+/// `(- x)` は符号反転に、`(! x)` は論理否定に変換し、
+/// それ以外の括弧は関数呼び出しとして扱う。ただし次の特殊形式は別に扱う。
+///
+/// * `(define name value)` - 現在のスコープに変数を定義する
+/// * `(let ((name value) ...) body ...)` - 新しいスコープで変数を順に束縛して本体を評価する
+/// * `(fn (param ...) body ...)` - 定義時の環境を捕捉する関数を作る
+/// * `(if cond then else)` - 条件の真偽で分岐する。`else` は省略できる
+/// * `(while cond body ...)` - 条件が真の間、本体を繰り返す
+/// * `(for (name start end) body ...)` - `start` から `end` の手前までの整数で本体を繰り返す
+/// * `(break)`, `(continue)` - 一番内側のループを抜ける、または次の繰り返しに進む
+/// * `(import "path")` - ファイルをモジュールとして読み込む。[`module`] を参照
+/// * `(quote datum)` - 評価せずにデータとして扱う。`'datum` と書ける
+/// * `(quasiquote template)` - 雛形の中の `(unquote expr)` だけを評価する。
+///   それぞれ `` `template `` と `,expr` と書ける
+///
+/// # 引数
+/// * `tree` - 変換する木
+///
+/// # 戻り値
+/// * `Result<Expr, EvalError>` - 変換結果の式
+///   - 空の括弧 `()` は評価できないのでエラーを返す
+pub fn lower(tree: &TokenTree) -> Result<Expr, EvalError> {
+    let (children, span) = match tree {
+        TokenTree::Token(token, span) => {
+            let kind = match token {
+                Token::Int(n) => ExprKind::Int(*n),
+                Token::Float(n) => ExprKind::Float(*n),
+                Token::StrLiteral(s) => ExprKind::Str(unescape(s)),
+                Token::Bool(b) => ExprKind::Bool(*b),
+                Token::Nil => ExprKind::Nil,
+                Token::Ident(name) => ExprKind::Ident(Symbol::intern(name)),
+                // S式の構文解析器は括弧や中置記法の記号を葉にしないが、
+                // JSONから読み戻した木には含まれうる
+                Token::LParen
+                | Token::RParen
+                | Token::Semicolon
+                | Token::LBrace
+                | Token::RBrace
+                | Token::LBracket
+                | Token::RBracket
+                | Token::Keyword(_) => {
+                    return Err(EvalError::MalformedForm {
+                        form: "token tree",
+                        span: *span,
+                    })
+                }
+            };
+            return Ok(Expr::new(kind, *span));
+        }
+        TokenTree::Tree(children, span) => (children, *span),
+    };
+    let Some((head, args)) = children.split_first() else {
+        return Err(EvalError::EmptyForm { span });
+    };
+    let head_name = match head {
+        TokenTree::Token(Token::Ident(name), _) => Some(*name),
+        _ => None,
+    };
+    match head_name {
+        Some("define") => return lower_define(args, span),
+        Some("let") => return lower_let(args, span),
+        Some("fn") => return lower_fn(args, span),
+        Some("if") => return lower_if(args, span),
+        Some("while") => return lower_while(args, span),
+        Some("for") => return lower_for(args, span),
+        Some("quote") => return lower_quote(args, span),
+        Some("import") => {
+            return match args {
+                [TokenTree::Token(Token::StrLiteral(path), _)] => {
+                    Ok(Expr::new(ExprKind::Import(unescape(path)), span))
+                }
+                _ => Err(EvalError::MalformedForm {
+                    form: "import",
+                    span,
+                }),
+            }
+        }
+        Some("quasiquote") => return lower_quasiquote(args, span),
+        Some("unquote") => {
+            return Err(EvalError::MalformedForm {
+                form: "unquote",
+                span,
+            })
+        }
+        Some("break") if args.is_empty() => return Ok(Expr::new(ExprKind::Break, span)),
+        Some("continue") if args.is_empty() => return Ok(Expr::new(ExprKind::Continue, span)),
+        _ => {}
+    }
+    let args = args.iter().map(lower).collect::<Result<Vec<_>, _>>()?;
+    let op = head_name.and_then(BinOp::from_symbol);
+    let kind = match (op, <[Expr; 1]>::try_from(args)) {
+        (Some(BinOp::Sub), Ok([operand])) => ExprKind::UnaryOp {
+            op: UnOp::Neg,
+            operand: Box::new(operand),
+        },
+        (None, Ok([operand])) if head_name == Some("!") => ExprKind::UnaryOp {
+            op: UnOp::Not,
+            operand: Box::new(operand),
+        },
+        (_, Ok([arg])) => ExprKind::Call {
+            func: Box::new(lower(head)?),
+            args: vec![arg],
+        },
+        // 比較は2つの値の間でだけ行う
+        (Some(op), Err(args)) if args.len() == 2 || (args.len() > 2 && !op.is_comparison()) => {
+            let mut args = args.into_iter();
+            let first = args.next().expect("at least two arguments");
+            let folded = args.fold(first, |lhs, rhs| {
+                let span = lhs.span.merge(rhs.span);
+                Expr::new(
+                    ExprKind::BinaryOp {
+                        op,
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs),
+                    },
+                    span,
+                )
+            });
+            // 一番外側の演算は括弧全体を範囲とする
+            folded.kind
+        }
+        (_, Err(args)) => ExprKind::Call {
+            func: Box::new(lower(head)?),
+            args,
+        },
+    };
+    Ok(Expr::new(kind, span))
+}
+
+/// `(quote datum)` を変換する関数
+fn lower_quote(args: &[TokenTree], span: Span) -> Result<Expr, EvalError> {
+    let [datum] = args else {
+        return Err(EvalError::MalformedForm {
+            form: "quote",
+            span,
+        });
+    };
+    check_datum(datum)?;
+    Ok(Expr::new(ExprKind::Quote(datum.to_owned()), span))
+}
+
+/// `(quasiquote template)` を変換する関数
+///
+/// 入れ子の深さは数えないので、内側の `quasiquote` の中の `unquote` も評価する。
+fn lower_quasiquote(args: &[TokenTree], span: Span) -> Result<Expr, EvalError> {
+    let [template] = args else {
+        return Err(EvalError::MalformedForm {
+            form: "quasiquote",
+            span,
+        });
+    };
+    Ok(Expr::new(
+        ExprKind::Quasiquote(lower_template(template)?),
+        span,
+    ))
+}
+
+fn lower_template(tree: &TokenTree) -> Result<Template, EvalError> {
+    match tree {
+        TokenTree::Tree(children, span) => match children.as_slice() {
+            [TokenTree::Token(Token::Ident("unquote"), _), expr] => {
+                Ok(Template::Unquote(Box::new(lower(expr)?)))
+            }
+            [TokenTree::Token(Token::Ident("unquote"), _), ..] => Err(EvalError::MalformedForm {
+                form: "unquote",
+                span: *span,
+            }),
+            _ if contains_unquote(tree) => Ok(Template::List(
+                children
+                    .iter()
+                    .map(lower_template)
+                    .collect::<Result<_, _>>()?,
+                *span,
+            )),
+            _ => {
+                check_datum(tree)?;
+                Ok(Template::Datum(tree.to_owned()))
+            }
+        },
+        TokenTree::Token(..) => {
+            check_datum(tree)?;
+            Ok(Template::Datum(tree.to_owned()))
+        }
+    }
+}
+
+fn contains_unquote(tree: &TokenTree) -> bool {
+    match tree {
+        TokenTree::Tree(children, _) => {
+            matches!(
+                children.first(),
+                Some(TokenTree::Token(Token::Ident("unquote"), _))
+            ) || children.iter().any(contains_unquote)
+        }
+        TokenTree::Token(..) => false,
+    }
+}
+
+/// 引用したデータが値に変換できる葉だけからなることを確かめる関数
+fn check_datum(tree: &TokenTree) -> Result<(), EvalError> {
+    match tree {
+        TokenTree::Tree(children, _) => children.iter().try_for_each(check_datum),
+        TokenTree::Token(
+            Token::LParen
+            | Token::RParen
+            | Token::Semicolon
+            | Token::LBrace
+            | Token::RBrace
+            | Token::LBracket
+            | Token::RBracket
+            | Token::Keyword(_),
+            span,
+        ) => Err(EvalError::MalformedForm {
+            form: "token tree",
+            span: *span,
+        }),
+        TokenTree::Token(..) => Ok(()),
+    }
+}
+
+/// 引用したデータを値に変換する関数
+///
+/// 括弧はリストに、識別子は [`Value::Symbol`] に、リテラルはその値になる。
+pub fn datum(tree: &OwnedTokenTree) -> Value {
+    match tree {
+        OwnedTokenTree::Tree(children, _) => Value::List(children.iter().map(datum).collect()),
+        OwnedTokenTree::Token(token, _) => match token {
+            OwnedToken::Ident(name) => Value::Symbol(*name),
+            OwnedToken::Int(n) => Value::I64(*n),
+            OwnedToken::Float(n) => Value::F64(*n),
+            OwnedToken::StrLiteral(s) => Value::Str(unescape(s).into()),
+            OwnedToken::Bool(b) => Value::Bool(*b),
+            OwnedToken::Nil => Value::Nil,
+            OwnedToken::LParen
+            | OwnedToken::RParen
+            | OwnedToken::LBrace
+            | OwnedToken::LBracket
+            | OwnedToken::RBracket
+            | OwnedToken::Keyword(_)
+            | OwnedToken::RBrace
+            | OwnedToken::Semicolon => unreachable!("lowering rejects punctuation in data"),
+        },
+    }
+}
+
+/// `(define name value)` を変換する関数
+fn lower_define(args: &[TokenTree], span: Span) -> Result<Expr, EvalError> {
+    let [TokenTree::Token(Token::Ident(name), _), value] = args else {
+        return Err(EvalError::MalformedForm {
+            form: "define",
+            span,
+        });
+    };
+    let kind = ExprKind::Define {
+        name: Symbol::intern(name),
+        value: Box::new(lower(value)?),
+    };
+    Ok(Expr::new(kind, span))
+}
+
+/// `(let ((name value) ...) body ...)` を変換する関数
+fn lower_let(args: &[TokenTree], span: Span) -> Result<Expr, EvalError> {
+    let malformed = || EvalError::MalformedForm { form: "let", span };
+    let Some((TokenTree::Tree(bindings, _), body)) = args.split_first() else {
+        return Err(malformed());
+    };
+    if body.is_empty() {
+        return Err(malformed());
+    }
+    let bindings = bindings
+        .iter()
+        .map(|binding| match binding {
+            TokenTree::Tree(pair, _) => match pair.as_slice() {
+                [TokenTree::Token(Token::Ident(name), _), value] => {
+                    Ok((Symbol::intern(name), lower(value)?))
+                }
+                _ => Err(malformed()),
+            },
+            _ => Err(malformed()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let body = body.iter().map(lower).collect::<Result<Vec<_>, _>>()?;
+    Ok(Expr::new(ExprKind::Let { bindings, body }, span))
+}
+
+/// `(if cond then)` と `(if cond then else)` を変換する関数
+fn lower_if(args: &[TokenTree], span: Span) -> Result<Expr, EvalError> {
+    let (cond, then_branch, else_branch) = match args {
+        [cond, then_branch] => (cond, then_branch, None),
+        [cond, then_branch, else_branch] => (cond, then_branch, Some(else_branch)),
+        _ => return Err(EvalError::MalformedForm { form: "if", span }),
+    };
+    let kind = ExprKind::If {
+        cond: Box::new(lower(cond)?),
+        then_branch: Box::new(lower(then_branch)?),
+        else_branch: else_branch.map(lower).transpose()?.map(Box::new),
+    };
+    Ok(Expr::new(kind, span))
+}
+
+/// ループの本体を1つの式にまとめる関数
+///
+/// 本体の式が1つならそのまま、複数なら順に評価するブロックにする。
+fn lower_loop_body(body: &[TokenTree], span: Span) -> Result<Expr, EvalError> {
+    let mut body = body.iter().map(lower).collect::<Result<Vec<_>, _>>()?;
+    if body.len() == 1 {
+        return Ok(body.remove(0));
+    }
+    let statements = body.into_iter().map(Statement::Expr).collect();
+    Ok(Expr::new(ExprKind::Block(statements), span))
+}
+
+/// `(while cond body ...)` を変換する関数
+fn lower_while(args: &[TokenTree], span: Span) -> Result<Expr, EvalError> {
+    let Some((cond, body)) = args.split_first().filter(|(_, body)| !body.is_empty()) else {
+        return Err(EvalError::MalformedForm {
+            form: "while",
+            span,
+        });
+    };
+    let kind = ExprKind::While {
+        cond: Box::new(lower(cond)?),
+        body: Box::new(lower_loop_body(body, span)?),
+    };
+    Ok(Expr::new(kind, span))
+}
+
+/// `(for (name start end) body ...)` を変換する関数
+fn lower_for(args: &[TokenTree], span: Span) -> Result<Expr, EvalError> {
+    let malformed = || EvalError::MalformedForm { form: "for", span };
+    let Some((TokenTree::Tree(range, _), body)) = args.split_first() else {
+        return Err(malformed());
+    };
+    let [TokenTree::Token(Token::Ident(var), _), start, end] = range.as_slice() else {
+        return Err(malformed());
+    };
+    if body.is_empty() {
+        return Err(malformed());
+    }
+    let kind = ExprKind::For {
+        var: Symbol::intern(var),
+        start: Box::new(lower(start)?),
+        end: Box::new(lower(end)?),
+        body: Box::new(lower_loop_body(body, span)?),
+    };
+    Ok(Expr::new(kind, span))
+}
+
+/// `(fn (param ...) body ...)` を変換する関数
+fn lower_fn(args: &[TokenTree], span: Span) -> Result<Expr, EvalError> {
+    let malformed = || EvalError::MalformedForm { form: "fn", span };
+    let Some((TokenTree::Tree(params, _), body)) = args.split_first() else {
+        return Err(malformed());
+    };
+    if body.is_empty() {
+        return Err(malformed());
+    }
+    let mut names: Vec<Symbol> = vec![];
+    for param in params {
+        match param {
+            TokenTree::Token(Token::Ident(name), _) if !names.iter().any(|n| n == name) => {
+                names.push(Symbol::intern(name))
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    let body = body.iter().map(lower).collect::<Result<Rc<[_]>, _>>()?;
+    Ok(Expr::new(
+        ExprKind::Fn {
+            params: names,
+            body,
+            signature: None,
+        },
+        span,
+    ))
+}
+
+/// 環境の中で式を評価する関数
+///
+/// # 引数
+/// * `expr` - 評価する式
+/// * `env` - 変数の束縛を探し、`define` で定義を追加する環境
+///
+/// # 戻り値
+/// * `Result<Value, EvalError>` - 評価結果の値
+pub fn eval_expr(expr: &Expr, env: &mut Environment) -> Result<Value, EvalError> {
+    exec(expr, env).map_err(ControlFlow::into_error)
+}
+
+/// 式を評価し、`break` と `continue` を打ち切りの信号として呼び出し側へ伝える関数
+fn exec(expr: &Expr, env: &mut Environment) -> Result<Value, ControlFlow> {
+    if !limits::step() {
+        return Err(ControlFlow::Error(limits::exhausted(expr.span)));
+    }
+    match &expr.kind {
+        ExprKind::Int(n) => Ok(Value::I64(*n)),
+        ExprKind::Float(n) => Ok(Value::F64(*n)),
+        ExprKind::Bool(b) => Ok(Value::Bool(*b)),
+        ExprKind::Nil => Ok(Value::Nil),
+        ExprKind::Str(s) => Ok(Value::Str(s.as_str().into())),
+        ExprKind::Ident(name) => env.get(*name).ok_or_else(|| {
+            EvalError::UnknownIdentifier {
+                name: name.to_string(),
+                span: expr.span,
+            }
+            .into()
+        }),
+        ExprKind::BinaryOp {
+            op: op @ (BinOp::And | BinOp::Or),
+            lhs,
+            rhs,
+        } => {
+            let lhs = exec(lhs, env)?.is_truthy();
+            // `&&` は左辺が偽なら、`||` は左辺が真なら右辺を評価しない
+            if lhs == (*op == BinOp::Or) {
+                return Ok(Value::Bool(lhs));
+            }
+            Ok(Value::Bool(exec(rhs, env)?.is_truthy()))
+        }
+        ExprKind::BinaryOp {
+            op: op @ (BinOp::Eq | BinOp::Ne),
+            lhs,
+            rhs,
+        } => {
+            let equal = values_equal(&exec(lhs, env)?, &exec(rhs, env)?);
+            Ok(Value::Bool(equal == (*op == BinOp::Eq)))
+        }
+        ExprKind::BinaryOp { op, lhs, rhs } => {
+            let lhs = expect_number(exec(lhs, env)?, lhs.span)?;
+            let rhs = expect_number(exec(rhs, env)?, rhs.span)?;
+            Ok(binary(*op, lhs, rhs, expr.span)?)
+        }
+        ExprKind::UnaryOp {
+            op: UnOp::Neg,
+            operand,
+        } => match expect_number(exec(operand, env)?, operand.span)? {
+            Value::I64(n) => n
+                .checked_neg()
+                .map(Value::I64)
+                .ok_or(EvalError::IntegerOverflow { span: expr.span }.into()),
+            Value::F64(n) => Ok(Value::F64(-n)),
+            _ => unreachable!("expect_number only returns numbers"),
+        },
+        ExprKind::UnaryOp {
+            op: UnOp::Not,
+            operand,
+        } => Ok(Value::Bool(!exec(operand, env)?.is_truthy())),
+        // 末尾位置に関数呼び出しを含みうる式は、呼び出しを戻ってから実行する
+        ExprKind::Call { .. }
+        | ExprKind::Group(_)
+        | ExprKind::Let { .. }
+        | ExprKind::If { .. }
+        | ExprKind::Block(_)
+        | ExprKind::Match { .. } => match exec_tail(expr, env)? {
+            Tail::Value(value) => Ok(value),
+            Tail::Call(tail) => Ok(tail.run()?),
+        },
+        ExprKind::Define { name, value } => {
+            let value = exec(value, env)?;
+            env.define(*name, value.clone());
+            Ok(value)
+        }
+        ExprKind::Fn { params, body, .. } => Ok(Value::function(Function {
+            params: params.clone(),
+            body: FunctionBody::Tree(body.clone()),
+            env: env.clone(),
+            span: expr.span,
+        })),
+        ExprKind::While { cond, body } => {
+            while exec(cond, env)?.is_truthy() {
+                match exec(body, env) {
+                    Ok(_) | Err(ControlFlow::Continue { .. }) => {}
+                    Err(ControlFlow::Break { .. }) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Value::Nil)
+        }
+        ExprKind::For {
+            var,
+            start,
+            end,
+            body,
+        } => {
+            let start = expect_integer(exec(start, env)?, start.span)?;
+            let end = expect_integer(exec(end, env)?, end.span)?;
+            for i in start..end {
+                // 繰り返しごとにスコープを作り、クロージャがその回の値を捕捉できるようにする
+                env.push_scope();
+                env.define(*var, Value::I64(i));
+                let res = exec(body, env);
+                env.pop_scope();
+                match res {
+                    Ok(_) | Err(ControlFlow::Continue { .. }) => {}
+                    Err(ControlFlow::Break { .. }) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Value::Nil)
+        }
+        ExprKind::Array(items) => make_array(items, env, expr.span),
+        ExprKind::Map(entries) => make_map(entries, env, expr.span),
+        ExprKind::Import(path) => Ok(module::import(path, expr.span)?),
+        ExprKind::Index { target, index } => {
+            let target = exec(target, env)?;
+            let index = exec(index, env)?;
+            Ok(get_index(&target, &index, expr.span)?)
+        }
+        ExprKind::Break => Err(ControlFlow::Break { span: expr.span }),
+        ExprKind::Continue => Err(ControlFlow::Continue { span: expr.span }),
+        ExprKind::Return(value) => {
+            let value = match value {
+                Some(value) => exec(value, env)?,
+                None => Value::Nil,
+            };
+            Err(ControlFlow::Return {
+                value,
+                span: expr.span,
+            })
+        }
+        ExprKind::Quote(tree) => Ok(datum(tree)),
+        ExprKind::Quasiquote(template) => instantiate(template, env),
+    }
+}
+
+/// 末尾位置の式を評価した結果
+enum Tail {
+    /// 評価を終えた値
+    Value(Value),
+    /// 呼び出し側のスタックフレームに戻ってから行う、ユーザー定義の関数の呼び出し
+    Call(TailCall),
+}
+
+/// 実引数を評価し終えた、まだ実行していない関数呼び出し
+struct TailCall {
+    function: Rc<Function>,
+    name: &'static str,
+    args: Vec<Value>,
+    span: Span,
+}
+
+impl TailCall {
+    /// 呼び出しを実行する
+    ///
+    /// 本体の末尾位置の呼び出しは再帰せずにこのループで続けて実行するので、
+    /// 末尾再帰する関数は呼び出しの深さによらず一定のスタックで動く。
+    fn run(mut self) -> Result<Value, EvalError> {
+        loop {
+            let Self {
+                function,
+                name,
+                args,
+                span,
+            } = self;
+            match call_once(&function, name, args, span)? {
+                Tail::Value(value) => return Ok(value),
+                Tail::Call(next) => self = next,
+            }
+        }
+    }
+}
+
+/// 末尾位置の式を評価する関数
+///
+/// ユーザー定義の関数の呼び出しは実行せずに [`Tail::Call`] として返し、
+/// `if` の枝、`let` とブロックの最後の式は末尾位置として続けて評価する。
+fn exec_tail(expr: &Expr, env: &mut Environment) -> Result<Tail, ControlFlow> {
+    match &expr.kind {
+        ExprKind::Call { func, args } => {
+            // 組み込みの演算子は、同じ名前の変数で隠されていない場合だけ使う
+            if let ExprKind::Ident(name) = &func.kind {
+                if env.get(*name).is_none() && BinOp::from_symbol(name.as_str()).is_some() {
+                    let args = args
+                        .iter()
+                        .map(|arg| Ok(expect_number(exec(arg, env)?, arg.span)?))
+                        .collect::<Result<Vec<_>, ControlFlow>>()?;
+                    return Ok(Tail::Value(arithmetic(
+                        name.as_str(),
+                        func.span,
+                        expr.span,
+                        &args,
+                    )?));
+                }
+            }
+            let callee = exec(func, env)?;
+            let args = args
+                .iter()
+                .map(|arg| exec(arg, env))
+                .collect::<Result<Vec<_>, _>>()?;
+            let name = match &func.kind {
+                ExprKind::Ident(name) => name.as_str(),
+                _ => "<fn>",
+            };
+            match callee {
+                Value::Fn(function) => Ok(Tail::Call(TailCall {
+                    function,
+                    name,
+                    args,
+                    span: expr.span,
+                })),
+                Value::NativeFn(function) => Ok(Tail::Value(function.call(&args, expr.span)?)),
+                _ => Err(EvalError::NotAFunction { span: func.span }.into()),
+            }
+        }
+        ExprKind::Group(inner) => exec_tail(inner, env),
+        ExprKind::Let { bindings, body } => {
+            env.push_scope();
+            let res = exec_let(bindings, body, env);
+            env.pop_scope();
+            res
+        }
+        ExprKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            if exec(cond, env)?.is_truthy() {
+                exec_tail(then_branch, env)
+            } else if let Some(else_branch) = else_branch {
+                exec_tail(else_branch, env)
+            } else {
+                Ok(Tail::Value(Value::Nil))
+            }
+        }
+        ExprKind::Block(statements) => {
+            env.push_scope();
+            let res = match statements.split_last() {
+                Some((Statement::Expr(last), init)) => {
+                    exec_statements(init, env).and_then(|_| exec_tail(last, env))
+                }
+                _ => exec_statements(statements, env)
+                    .map(|value| Tail::Value(value.unwrap_or(Value::Nil))),
+            };
+            env.pop_scope();
+            res
+        }
+        ExprKind::Match { scrutinee, arms } => {
+            let value = exec(scrutinee, env)?;
+            for arm in arms
+                .iter()
+                .filter(|arm| pattern_matches(&arm.pattern, &value))
+            {
+                env.push_scope();
+                let res = exec_arm(arm, &value, env);
+                env.pop_scope();
+                if let Some(tail) = res? {
+                    return Ok(tail);
+                }
+            }
+            Err(EvalError::NoMatch {
+                value: value.to_string(),
+                span: expr.span,
+            }
+            .into())
+        }
+        _ => exec(expr, env).map(Tail::Value),
+    }
+}
+
+/// リテラルのパターンが一致する値。どの値にも一致するパターンでは `None`
+pub(crate) fn pattern_value(pattern: &Pattern) -> Option<Value> {
+    match pattern {
+        Pattern::Wildcard | Pattern::Binding(_) => None,
+        Pattern::Int(n) => Some(Value::I64(*n)),
+        Pattern::Float(n) => Some(Value::F64(*n)),
+        Pattern::Str(s) => Some(Value::Str(s.as_str().into())),
+        Pattern::Bool(b) => Some(Value::Bool(*b)),
+        Pattern::Nil => Some(Value::Nil),
+    }
+}
+
+/// 値がパターンに一致するかどうか。リテラルとは `==` と同じ規則で比べる
+fn pattern_matches(pattern: &Pattern, value: &Value) -> bool {
+    pattern_value(pattern).is_none_or(|literal| values_equal(&literal, value))
+}
+
+/// パターンに一致した腕を、呼び出し側で開始したスコープの中で評価する関数
+///
+/// # 戻り値
+/// * `Result<Option<Tail>, ControlFlow>` - 本体を末尾位置として評価した結果
+///   - 条件が偽なら `None`
+fn exec_arm(
+    arm: &MatchArm,
+    value: &Value,
+    env: &mut Environment,
+) -> Result<Option<Tail>, ControlFlow> {
+    if let Pattern::Binding(name) = arm.pattern {
+        env.define(name, value.clone());
+    }
+    if let Some(guard) = &arm.guard {
+        if !exec(guard, env)?.is_truthy() {
+            return Ok(None);
+        }
+    }
+    exec_tail(&arm.body, env).map(Some)
+}
+
+/// 配列のリテラルの要素を順に評価して配列を作る関数
+///
+/// [`exec`] の外に出して、再帰する [`exec`] のスタックを小さく保つ。
+fn make_array(items: &[Expr], env: &mut Environment, span: Span) -> Result<Value, ControlFlow> {
+    let items = items
+        .iter()
+        .map(|item| exec(item, env))
+        .collect::<Result<Vec<_>, _>>()?;
+    let array = Value::array(items);
+    limits::allocate_value(&array, span)?;
+    Ok(array)
+}
+
+/// マップのリテラルのキーと値を順に評価してマップを作る関数
+fn make_map(
+    entries: &[(Expr, Expr)],
+    env: &mut Environment,
+    span: Span,
+) -> Result<Value, ControlFlow> {
+    let mut map = HashMap::with_capacity(entries.len());
+    for (key, value) in entries {
+        let key = expect_key(exec(key, env)?, key.span)?;
+        map.insert(key, exec(value, env)?);
+    }
+    let map = Value::map(map);
+    limits::allocate_value(&map, span)?;
+    Ok(map)
+}
+
+/// 準引用の雛形の `unquote` を評価してデータを組み立てる関数
+fn instantiate(template: &Template, env: &mut Environment) -> Result<Value, ControlFlow> {
+    match template {
+        Template::Datum(tree) => Ok(datum(tree)),
+        Template::List(items, _) => Ok(Value::List(
+            items
+                .iter()
+                .map(|item| instantiate(item, env))
+                .collect::<Result<_, _>>()?,
+        )),
+        Template::Unquote(expr) => exec(expr, env),
+    }
+}
+
+/// 値が整数か浮動小数点数であることを確かめる関数
+pub(crate) fn expect_number(value: Value, span: Span) -> Result<Value, EvalError> {
+    match value {
+        Value::I64(_) | Value::F64(_) => Ok(value),
+        _ => Err(EvalError::NotANumber { span }),
+    }
+}
+
+/// 値が整数であることを確かめる関数
+pub(crate) fn expect_integer(value: Value, span: Span) -> Result<i64, EvalError> {
+    match value {
+        Value::I64(n) => Ok(n),
+        _ => Err(EvalError::NotAnInteger { span }),
+    }
+}
+
+/// 値がマップのキーにできることを確かめる関数
+pub(crate) fn expect_key(value: Value, span: Span) -> Result<Value, EvalError> {
+    if value.is_key() {
+        Ok(value)
+    } else {
+        Err(EvalError::TypeMismatch {
+            expected: "an integer, a string, a boolean or nil as a map key",
+            span,
+        })
+    }
+}
+
+/// 配列かリストの `index` 番目の要素か、文字列の `index` 番目の文字か、モジュールの変数を返す関数
+///
+/// 位置は0から数える整数で、負の位置や長さ以上の位置はエラーにする。
+/// 文字列はバイトではなく文字で数え、1文字の文字列を返す。
+/// モジュールは変数の名前の文字列で引き、定義されていなければエラーにする。
+/// マップは位置では引けないので、`get` を使うよう促すエラーにする。
+pub(crate) fn get_index(target: &Value, index: &Value, span: Span) -> Result<Value, EvalError> {
+    if let Value::Map(_) = target {
+        return Err(EvalError::TypeMismatch {
+            expected: "an array, a list, a string or a module, not a map (use `get(map, key)`)",
+            span,
+        });
+    }
+    if let Value::Module(module) = target {
+        let Value::Str(name) = index else {
+            return Err(EvalError::TypeMismatch {
+                expected: "a string",
+                span,
+            });
+        };
+        return module
+            .get(name)
+            .ok_or_else(|| EvalError::UnknownIdentifier {
+                name: format!("{}.{name}", module.name),
+                span,
+            });
+    }
+    let index = expect_integer(index.clone(), span)?;
+    let get = |items: &[Value]| Ok(items[bound(index, items.len(), span)?].clone());
+    match target {
+        Value::Array(items) => get(&items.borrow()),
+        Value::List(items) => get(items),
+        Value::Str(s) => {
+            let start = char_offset(s, index, span)?;
+            match s.get(start..).and_then(|s| s.chars().next()) {
+                Some(c) => Ok(Value::Str(c.to_string().into())),
+                None => Err(EvalError::IndexOutOfBounds {
+                    index,
+                    len: s.chars().count(),
+                    span,
+                }),
+            }
+        }
+        _ => Err(EvalError::TypeMismatch {
+            expected: "an array, a list, a string or a module",
+            span,
+        }),
+    }
+}
+
+/// 配列の `index` 番目の要素を `value` に書き換える関数
+pub(crate) fn set_index(
+    target: &Value,
+    index: &Value,
+    value: Value,
+    span: Span,
+) -> Result<(), EvalError> {
+    if let Value::Map(_) = target {
+        return Err(EvalError::TypeMismatch {
+            expected: "an array, not a map (use `insert(map, key, value)`)",
+            span,
+        });
+    }
+    let index = expect_integer(index.clone(), span)?;
+    let Value::Array(items) = target else {
+        return Err(EvalError::TypeMismatch {
+            expected: "an array",
+            span,
+        });
+    };
+    let mut items = items.borrow_mut();
+    let i = bound(index, items.len(), span)?;
+    Rc::make_mut(&mut items)[i] = value;
+    Ok(())
+}
+
+/// 位置 `index` が長さ `len` の範囲に収まっていれば、その位置を返す
+fn bound(index: i64, len: usize, span: Span) -> Result<usize, EvalError> {
+    usize::try_from(index)
+        .ok()
+        .filter(|&i| i < len)
+        .ok_or(EvalError::IndexOutOfBounds { index, len, span })
+}
+
+/// 関数の呼び出しの深さの既定の上限。[`RunLimits::UNLIMITED`](crate::limits::RunLimits::UNLIMITED) の深さ
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// スレッドのスタックの大きさが分からないときに、関数の呼び出しに使ってよいスタックのバイト数
+///
+/// テストのスレッドの 2 MiB のスタックでも、残りで呼び出し元が動けるようにしている。
+pub const DEFAULT_STACK_BUDGET: usize = 1 << 20;
+
+thread_local! {
+    /// このスレッドで実行中のユーザー定義の関数の呼び出しの深さ
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// 一番外側の呼び出しを始めたときのスタックの位置
+    static STACK_BASE: Cell<usize> = const { Cell::new(0) };
+    /// [`set_stack_budget`] で設定した、関数の呼び出しに使ってよいスタックのバイト数
+    static STACK_BUDGET: Cell<Option<usize>> = const { Cell::new(None) };
+    /// 実行中の一番外側の呼び出しから、関数の呼び出しに使ってよいスタックのバイト数
+    static STACK_LIMIT: Cell<usize> = const { Cell::new(DEFAULT_STACK_BUDGET) };
+    /// このスレッドのスタックの一番低い位置。分からなければ `None`
+    static STACK_END: std::cell::OnceCell<Option<usize>> = const { std::cell::OnceCell::new() };
+}
+
+/// このスレッドで許す関数の呼び出しの深さ。[`RunLimits::max_call_depth`](crate::limits::RunLimits) の値
+fn max_call_depth() -> usize {
+    limits::run_limits().max_call_depth
+}
+
+/// このスレッドで関数の呼び出しに使ってよいスタックのバイト数を設定する
+///
+/// 一番外側の呼び出しから数えて、これを超えてスタックを使う呼び出しは
+/// Rust のスタックを使い切る前に [`EvalError::StackOverflow`] になる。
+/// 設定しなければ、スレッドのスタックの残りの 3/4 を予算にする。
+/// スタックの大きさが分かるプラットフォームでは、その残りの 3/4 より大きくはならない。
+pub fn set_stack_budget(bytes: usize) {
+    STACK_BUDGET.with(|budget| budget.set(Some(bytes)));
+}
+
+/// 一番外側の呼び出しを位置 `here` から始めるときの、スタックの予算のバイト数
+fn stack_limit(here: usize) -> usize {
+    let remaining = STACK_END
+        .with(|end| *end.get_or_init(stack_end))
+        .map(|end| here.saturating_sub(end) / 4 * 3);
+    match (STACK_BUDGET.with(Cell::get), remaining) {
+        (Some(budget), Some(remaining)) => budget.min(remaining),
+        (Some(bytes), None) | (None, Some(bytes)) => bytes,
+        (None, None) => DEFAULT_STACK_BUDGET,
+    }
+}
+
+/// 現在のスレッドのスタックの一番低い位置
+///
+/// スタックは低い位置に向かって伸びるので、今の位置からここまでが残りのスタックになる。
+#[cfg(target_os = "linux")]
+fn stack_end() -> Option<usize> {
+    use std::ffi::{c_int, c_void};
+
+    /// `pthread_attr_t`。どの libc の定義よりも大きく取っておく
+    #[repr(C, align(16))]
+    struct Attr([u8; 128]);
+
+    extern "C" {
+        fn pthread_self() -> usize;
+        fn pthread_getattr_np(thread: usize, attr: *mut Attr) -> c_int;
+        fn pthread_attr_getstack(
+            attr: *const Attr,
+            addr: *mut *mut c_void,
+            size: *mut usize,
+        ) -> c_int;
+        fn pthread_attr_destroy(attr: *mut Attr) -> c_int;
+    }
+
+    let mut attr = Attr([0; 128]);
+    let (mut addr, mut size) = (std::ptr::null_mut(), 0);
+    // SAFETY: `attr` は `pthread_getattr_np` が初期化してから使い、使い終えたら破棄する
+    unsafe {
+        if pthread_getattr_np(pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let found = pthread_attr_getstack(&attr, &mut addr, &mut size) == 0;
+        pthread_attr_destroy(&mut attr);
+        found.then_some(addr as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn stack_end() -> Option<usize> {
+    None
+}
+
+/// 指定した大きさのスタックを持つスレッドで関数を実行する関数
+///
+/// 外部クレートに依存しないよう、stacker のようにスタックを伸ばすのではなく、
+/// 大きなスタックを確保した新しいスレッドで実行して終わるのを待つ。
+/// 新しいスレッドでは呼び出し元の [`RunLimits`](crate::limits::RunLimits) を引き継ぎ、
+/// スタックの予算をスタックの大きさの 3/4 にする。使った量は新しいスレッドで0から数える。
+///
+/// # 引数
+/// * `stack_size` - スレッドのスタックのバイト数
+/// * `f` - 実行する関数
+///
+/// # 戻り値
+/// * `R` - `f` の戻り値。`f` がパニックすれば、そのパニックを呼び出し側で再び起こす
+pub fn with_stack_size<R: Send>(stack_size: usize, f: impl FnOnce() -> R + Send) -> R {
+    let run_limits = limits::run_limits();
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new()
+            .stack_size(stack_size)
+            .spawn_scoped(scope, move || {
+                limits::set_run_limits(run_limits);
+                set_stack_budget(stack_size / 4 * 3);
+                f()
+            })
+            .expect("failed to spawn a thread for evaluation");
+        thread
+            .join()
+            .unwrap_or_else(|payload| std::panic::resume_unwind(payload))
+    })
+}
+
+/// 実行中の関数の呼び出しを1段として数える間だけ持つ値
+pub(crate) struct CallGuard(());
+
+impl CallGuard {
+    /// 呼び出しの深さを1増やす。深さかスタックの上限を超えればエラーを返す
+    pub(crate) fn enter(span: Span) -> Result<Self, EvalError> {
+        // ローカル変数の位置を、現在のスタックの位置の目安にする
+        let marker = 0u8;
+        let here = std::ptr::addr_of!(marker) as usize;
+        let depth = CALL_DEPTH.with(Cell::get);
+        if depth == 0 {
+            STACK_BASE.with(|base| base.set(here));
+            STACK_LIMIT.with(|limit| limit.set(stack_limit(here)));
+        }
+        if depth >= max_call_depth() {
+            return Err(EvalError::StackOverflow {
+                depth,
+                stack_budget: None,
+                span,
+            });
+        }
+        let (used, limit) = (
+            STACK_BASE.with(Cell::get).abs_diff(here),
+            STACK_LIMIT.with(Cell::get),
+        );
+        if used > limit {
+            return Err(EvalError::StackOverflow {
+                depth,
+                stack_budget: Some(limit),
+                span,
+            });
+        }
+        CALL_DEPTH.with(|d| d.set(depth + 1));
+        Ok(Self(()))
+    }
+
+    /// 深さとスタックの上限を超えずに、さらに入れ子にできる呼び出しの数
+    ///
+    /// `frame` は1回の呼び出しが使うスタックのバイト数。
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    pub(crate) fn remaining(frame: usize) -> usize {
+        let marker = 0u8;
+        let here = std::ptr::addr_of!(marker) as usize;
+        let used = STACK_BASE.with(Cell::get).abs_diff(here);
+        let stack = STACK_LIMIT.with(Cell::get).saturating_sub(used) / frame.max(1);
+        let depth = max_call_depth().saturating_sub(CALL_DEPTH.with(Cell::get));
+        stack.min(depth)
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        CALL_DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+/// ユーザー定義の関数を呼び出す関数
+///
+/// 関数が捕捉した環境の内側に新しいスコープを作り、仮引数を束縛してから本体を評価する。
+///
+/// # 引数
+/// * `function` - 呼び出す関数
+/// * `name` - エラーメッセージに使う関数の名前
+/// * `args` - 評価済みの実引数
+/// * `span` - 呼び出し式の範囲
+///
+/// # 戻り値
+/// * `Result<Value, EvalError>` - 本体の最後の式の値
+pub fn call(
+    function: &Function,
+    name: &str,
+    args: Vec<Value>,
+    span: Span,
+) -> Result<Value, EvalError> {
+    match call_once(function, name, args, span)? {
+        Tail::Value(value) => Ok(value),
+        Tail::Call(tail) => tail.run(),
+    }
+}
+
+/// 関数の本体を評価し、末尾位置の呼び出しは実行せずに返す関数
+fn call_once(
+    function: &Function,
+    name: &str,
+    args: Vec<Value>,
+    span: Span,
+) -> Result<Tail, EvalError> {
+    let _guard = CallGuard::enter(span)?;
+    let mut env = bind_arguments(function, name, args, span)?;
+    match &function.body {
+        // `return` はここで値になり、関数の外のループは関数の本体から抜けられない
+        FunctionBody::Tree(body) => match exec_body(body, &mut env) {
+            Err(ControlFlow::Return { value, .. }) => Ok(Tail::Value(value)),
+            res => res.map_err(ControlFlow::into_error),
+        },
+        FunctionBody::Compiled(code) => Vm::new().run(code, &mut env).map(Tail::Value),
+    }
+}
+
+/// 関数が捕捉した環境の内側に新しいスコープを作り、仮引数に実引数を束縛する関数
+///
+/// # 戻り値
+/// * `Result<Environment, EvalError>` - 本体を評価する環境
+///   - 実引数の数が仮引数の数と異なればエラーを返す
+pub(crate) fn bind_arguments(
+    function: &Function,
+    name: &str,
+    args: Vec<Value>,
+    span: Span,
+) -> Result<Environment, EvalError> {
+    if args.len() != function.params.len() {
+        return Err(EvalError::Arity {
+            name: name.to_string(),
+            expected: function.params.len(),
+            found: args.len(),
+            span,
+            definition: Some(function.span),
+        });
+    }
+    let mut env = function.env.child();
+    for (param, arg) in function.params.iter().zip(args) {
+        env.define(*param, arg);
+    }
+    Ok(env)
+}
+
+/// `let` の束縛と本体を、呼び出し側で開始したスコープの中で評価する関数
+fn exec_let(
+    bindings: &[(Symbol, Expr)],
+    body: &[Expr],
+    env: &mut Environment,
+) -> Result<Tail, ControlFlow> {
+    for (name, value) in bindings {
+        let value = exec(value, env)?;
+        env.define(*name, value);
+    }
+    exec_body(body, env)
+}
+
+/// 空でない式の並びを順に評価し、最後の式を末尾位置として評価する関数
+fn exec_body(body: &[Expr], env: &mut Environment) -> Result<Tail, ControlFlow> {
+    let (last, init) = body.split_last().expect("bodies are never empty");
+    for expr in init {
+        exec(expr, env)?;
+    }
+    exec_tail(last, env)
+}
+
+/// ソースコード全体を評価する関数
+///
+/// `source()` が返す最上位の木の要素を、同じ環境の中で順に評価する。
+///
+/// # 引数
+/// * `forms` - 評価する式の並び
+/// * `env` - 評価に使う環境。`define` した変数は評価後も残る
+///
+/// # 戻り値
+/// * `Result<Option<Value>, EvalError>` - 最後の式の評価結果。式が無ければ `None`
+pub fn eval_forms(forms: &[TokenTree], env: &mut Environment) -> Result<Option<Value>, EvalError> {
+    let mut last = None;
+    for form in forms {
+        last = Some(eval_expr(&lower(form)?, env)?);
+    }
+    Ok(last)
+}
+
+/// 中置記法の文の並びを評価する関数
+///
+/// 定義と代入の文は、束縛した値を文の値とする。
+///
+/// # 引数
+/// * `statements` - 評価する文の並び
+/// * `env` - 評価に使う環境。`var` で定義した変数は評価後も残る
+///
+/// # 戻り値
+/// * `Result<Option<Value>, EvalError>` - 最後の文の値。文が無ければ `None`
+///   - 定義されていない変数への代入はエラーを返す
+pub fn eval_statements(
+    statements: &[Statement],
+    env: &mut Environment,
+) -> Result<Option<Value>, EvalError> {
+    exec_statements(statements, env).map_err(ControlFlow::into_error)
+}
+
+/// 文の並びを評価し、`break` と `continue` を呼び出し側へ伝える関数
+fn exec_statements(
+    statements: &[Statement],
+    env: &mut Environment,
+) -> Result<Option<Value>, ControlFlow> {
+    let mut last = None;
+    for statement in statements {
+        let value = match statement {
+            Statement::VarDef { name, value, .. } => {
+                let value = exec(value, env)?;
+                env.define(name.as_str(), value.clone());
+                value
+            }
+            Statement::Assignment { name, value, span } => {
+                let value = exec(value, env)?;
+                if !env.assign(*name, value.clone()) {
+                    return Err(EvalError::UnknownIdentifier {
+                        name: name.to_string(),
+                        span: *span,
+                    }
+                    .into());
+                }
+                value
+            }
+            Statement::IndexAssignment {
+                target,
+                index,
+                value,
+                span,
+            } => {
+                let target = exec(target, env)?;
+                let index = exec(index, env)?;
+                let value = exec(value, env)?;
+                set_index(&target, &index, value.clone(), *span)?;
+                value
+            }
+            Statement::Expr(expr) => exec(expr, env)?,
+        };
+        last = Some(value);
+    }
+    Ok(last)
+}
+
+/// 数値に四則演算か大小比較の二項演算子を適用する関数
+///
+/// 型の変換は次の規則に従う。
+///
+/// * 整数同士の演算は整数になる。`/` は0に向かって切り捨て、結果が `i64` に収まらなければエラーにする
+/// * どちらかが浮動小数点数なら、もう一方も浮動小数点数に変換してから演算する
+///
+/// # 引数
+/// * `op` - 適用する演算子
+/// * `lhs` - 左辺の数値
+/// * `rhs` - 右辺の数値
+/// * `span` - エラーを報告する式の範囲
+///
+/// # 戻り値
+/// * `Result<Value, EvalError>` - 演算結果
+pub(crate) fn binary(op: BinOp, lhs: Value, rhs: Value, span: Span) -> Result<Value, EvalError> {
+    if op.is_comparison() {
+        let ordering = compare(&lhs, &rhs);
+        return Ok(Value::Bool(match op {
+            BinOp::Lt => ordering == Some(Ordering::Less),
+            BinOp::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            BinOp::Gt => ordering == Some(Ordering::Greater),
+            BinOp::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            BinOp::Eq => ordering == Some(Ordering::Equal),
+            _ => ordering != Some(Ordering::Equal),
+        }));
+    }
+    let (lhs, rhs) = match (lhs, rhs) {
+        (Value::I64(lhs), Value::I64(rhs)) => {
+            if matches!(op, BinOp::Div | BinOp::Rem) && rhs == 0 {
+                return Err(EvalError::DivisionByZero { span });
+            }
+            let res = match op {
+                BinOp::Add => lhs.checked_add(rhs),
+                BinOp::Sub => lhs.checked_sub(rhs),
+                BinOp::Mul => lhs.checked_mul(rhs),
+                BinOp::Div => lhs.checked_div(rhs),
+                BinOp::Rem => lhs.checked_rem(rhs),
+                _ => unreachable!("only arithmetic reaches integer operations"),
+            };
+            return res
+                .map(Value::I64)
+                .ok_or(EvalError::IntegerOverflow { span });
+        }
+        (lhs, rhs) => (to_float(&lhs), to_float(&rhs)),
+    };
+    Ok(Value::F64(match op {
+        BinOp::Add => lhs + rhs,
+        BinOp::Sub => lhs - rhs,
+        BinOp::Mul => lhs * rhs,
+        BinOp::Div => lhs / rhs,
+        BinOp::Rem => lhs % rhs,
+        _ => unreachable!("only arithmetic reaches float operations"),
+    }))
+}
+
+/// 2つの数値の大小を比べる関数
+///
+/// 整数と浮動小数点数は、整数を丸めずに値の大小で比べる。`-0.0` と `0.0` は等しく、
+/// NaN との比較は `None` になる。
+pub(crate) fn compare(lhs: &Value, rhs: &Value) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (Value::I64(lhs), Value::I64(rhs)) => Some(lhs.cmp(rhs)),
+        (Value::I64(lhs), Value::F64(rhs)) if !rhs.is_nan() => Some(cmp_int_float(*lhs, *rhs)),
+        (Value::F64(lhs), Value::I64(rhs)) if !lhs.is_nan() => {
+            Some(cmp_int_float(*rhs, *lhs).reverse())
+        }
+        (lhs, rhs) => to_float(lhs).partial_cmp(&to_float(rhs)),
+    }
+}
+
+/// `==` で2つの値が等しいかを判定する関数
+///
+/// 整数と浮動小数点数は [`compare`] で数値として比べるので、NaN はどの値とも、自身とも等しくない。
+/// リスト、配列、マップは要素ごとにこの規則で比べ、それ以外は同じ種類の値同士だけを比べる。
+/// 循環する配列やマップは、[`Value::total_cmp`] と同じく比べている途中の組に戻ったら等しいとする。
+pub(crate) fn values_equal(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (Value::I64(_) | Value::F64(_), Value::I64(_) | Value::F64(_)) => {
+            compare(lhs, rhs) == Some(Ordering::Equal)
+        }
+        (Value::List(lhs), Value::List(rhs)) => {
+            lhs.len() == rhs.len() && lhs.iter().zip(rhs.iter()).all(|(l, r)| values_equal(l, r))
+        }
+        (Value::Array(lhs), Value::Array(rhs)) => {
+            // 同じ配列同士は、自身を要素に持つ配列でも要素を辿らずに等しいとする
+            Rc::ptr_eq(lhs, rhs) || {
+                let Some(_visit) = Visit::enter(&COMPARING, (address(lhs), address(rhs))) else {
+                    return true;
+                };
+                let (lhs, rhs) = (lhs.borrow(), rhs.borrow());
+                lhs.len() == rhs.len()
+                    && lhs.iter().zip(rhs.iter()).all(|(l, r)| values_equal(l, r))
+            }
+        }
+        (Value::Map(lhs), Value::Map(rhs)) => {
+            Rc::ptr_eq(lhs, rhs) || {
+                let Some(_visit) = Visit::enter(&COMPARING, (address(lhs), address(rhs))) else {
+                    return true;
+                };
+                let (lhs, rhs) = (lhs.borrow(), rhs.borrow());
+                lhs.len() == rhs.len()
+                    && lhs
+                        .iter()
+                        .all(|(key, l)| rhs.get(key).is_some_and(|r| values_equal(l, r)))
+            }
+        }
+        (lhs, rhs) => lhs == rhs,
+    }
+}
+
+/// 数値を浮動小数点数に変換する関数
+fn to_float(value: &Value) -> f64 {
+    match value {
+        Value::I64(n) => *n as f64,
+        Value::F64(n) => *n,
+        _ => unreachable!("only numbers reach arithmetic"),
+    }
+}
+
+/// 四則演算の演算子を引数に適用する関数
+///
+/// # 引数
+/// * `name` - 演算子の名前
+/// * `name_span` - 演算子の範囲
+/// * `span` - 式全体の範囲
+/// * `args` - 評価済みの数値の引数
+///
+/// # 戻り値
+/// * `Result<Value, EvalError>` - 演算結果
+///   - 引数が無い `+` と `*` はそれぞれ整数の0と1になる
+///   - `-` と `/` は引数が1つなら符号反転と逆数になる
+pub(crate) fn arithmetic(
+    name: &str,
+    name_span: Span,
+    span: Span,
+    args: &[Value],
+) -> Result<Value, EvalError> {
+    let Some(op) = BinOp::from_symbol(name) else {
+        return Err(EvalError::UnknownIdentifier {
+            name: name.to_string(),
+            span: name_span,
+        });
+    };
+    // 比較と論理演算は `lower` で二項演算になるので、ここに来るのは引数の数が誤りの場合
+    if !op.is_arithmetic() {
+        return Err(EvalError::Arity {
+            name: name.to_string(),
+            expected: 2,
+            found: args.len(),
+            span,
+            definition: None,
+        });
+    }
+    let (init, args) = match (op, args) {
+        (BinOp::Add, []) => return Ok(Value::I64(0)),
+        (BinOp::Mul, []) => return Ok(Value::I64(1)),
+        (_, []) => {
+            return Err(EvalError::TooFewArguments {
+                name: name.to_string(),
+                min: 1,
+                found: 0,
+                span,
+            })
+        }
+        (BinOp::Sub, [_]) => (Value::I64(0), args),
+        (BinOp::Div, [_]) => (Value::I64(1), args),
+        (BinOp::Rem, [_]) => {
+            return Err(EvalError::TooFewArguments {
+                name: name.to_string(),
+                min: 2,
+                found: 1,
+                span,
+            })
+        }
+        (_, [first, rest @ ..]) => (first.clone(), rest),
+    };
+    args.iter()
+        .try_fold(init, |acc, arg| binary(op, acc, arg.clone(), span))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::infix::statements;
+    use crate::limits::RunLimits;
+    use crate::parser::source;
+
+    fn eval_str(input: &str) -> Result<Option<Value>, EvalError> {
+        let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+            panic!("failed to parse {input:?}");
+        };
+        eval_forms(&forms, &mut Environment::new())
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval_str("(+ 1 (* 2 3))"), Ok(Some(Value::I64(7))));
+        assert_eq!(eval_str("(- 10 2 3)"), Ok(Some(Value::I64(5))));
+        assert_eq!(eval_str("(- 4) (/ 4.0)"), Ok(Some(Value::F64(0.25))));
+        assert_eq!(eval_str("(+)"), Ok(Some(Value::I64(0))));
+        assert_eq!(eval_str(""), Ok(None));
+    }
+
+    #[test]
+    fn test_numeric_coercion() {
+        assert_eq!(eval_str("(/ 7 2)"), Ok(Some(Value::I64(3))));
+        assert_eq!(eval_str("(/ -7 2)"), Ok(Some(Value::I64(-3))));
+        assert_eq!(eval_str("(/ 7 2.0)"), Ok(Some(Value::F64(3.5))));
+        assert_eq!(eval_str("(+ 1 0.5 1)"), Ok(Some(Value::F64(2.5))));
+        assert_eq!(eval_str("(* 0x10 0b10)"), Ok(Some(Value::I64(32))));
+        assert_eq!(eval_str("(/ 1.0 0)"), Ok(Some(Value::F64(f64::INFINITY))));
+        assert_eq!(
+            eval_str("(/ 1 0)"),
+            Err(EvalError::DivisionByZero {
+                span: Span::new(0, 7)
+            })
+        );
+        assert_eq!(
+            eval_str("(* 9223372036854775807 2)"),
+            Err(EvalError::IntegerOverflow {
+                span: Span::new(0, 25)
+            })
+        );
+        assert_eq!(Value::F64(1.).to_string(), "1.0");
+        assert_eq!(Value::I64(1).to_string(), "1");
+    }
+
+    #[test]
+    fn test_remainder() {
+        assert_eq!(eval_str("(% 7 3) (% -7 3)"), Ok(Some(Value::I64(-1))));
+        assert_eq!(eval_str("(% 7.5 2)"), Ok(Some(Value::F64(1.5))));
+        let mut env = Environment::new();
+        let program = statements("[7 % 3, 2 + 7 % 3 * 2, 7 % -3]").unwrap();
+        assert_eq!(
+            eval_statements(&program, &mut env).map(|value| value.unwrap().to_string()),
+            Ok("[1, 4, 1]".to_string())
+        );
+        assert_eq!(
+            eval_str("(% 1 0)"),
+            Err(EvalError::DivisionByZero {
+                span: Span::new(0, 7)
+            })
+        );
+        assert_eq!(
+            eval_str("(% -9223372036854775808 -1)"),
+            Err(EvalError::IntegerOverflow {
+                span: Span::new(0, 27)
+            })
+        );
+        assert!(matches!(
+            eval_str("(% 7)"),
+            Err(EvalError::TooFewArguments { min: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_signed_operands() {
+        assert_eq!(eval_str("(- 1 2)"), Ok(Some(Value::I64(-1))));
+        assert_eq!(eval_str("(+ -1 2)"), Ok(Some(Value::I64(1))));
+        assert_eq!(
+            eval_str("(-1 2)"),
+            Err(EvalError::NotAFunction {
+                span: Span::new(1, 3)
+            })
+        );
+    }
+
+    #[test]
+    fn test_lower() {
+        let Ok(TokenTree::Tree(forms, _)) = source("(- 1 2 3)") else {
+            unreachable!()
+        };
+        let expr = lower(&forms[0]).unwrap();
+        assert_eq!(expr.span, Span::new(0, 9));
+        let ExprKind::BinaryOp {
+            op: BinOp::Sub,
+            lhs,
+            ..
+        } = expr.kind
+        else {
+            panic!("expected subtraction, got {:?}", expr.kind);
+        };
+        assert_eq!(lhs.span, Span::new(3, 6));
+    }
+
+    #[test]
+    fn test_eval_infix() {
+        let expr = crate::infix::parse_expr("1 + 2 * 3 - (4 / 8.0)").unwrap();
+        assert_eq!(
+            eval_expr(&expr, &mut Environment::new()),
+            Ok(Value::F64(6.5))
+        );
+    }
+
+    #[test]
+    fn test_define_and_let() {
+        assert_eq!(
+            eval_str("(define x 2) (* x (let ((x 10) (y x)) (+ x y)))"),
+            Ok(Some(Value::I64(40)))
+        );
+        assert_eq!(
+            eval_str("(let ((y 1)) y) y"),
+            Err(EvalError::UnknownIdentifier {
+                name: "y".to_string(),
+                span: Span::new(16, 17)
+            })
+        );
+        assert_eq!(
+            eval_str("(let (x 1) x)"),
+            Err(EvalError::MalformedForm {
+                form: "let",
+                span: Span::new(0, 13)
+            })
+        );
+    }
+
+    #[test]
+    fn test_closure() {
+        assert_eq!(
+            eval_str("(define add (fn (x y) (+ x y))) (add 1 2)"),
+            Ok(Some(Value::I64(3)))
+        );
+        assert_eq!(
+            eval_str(
+                "(define adder (fn (n) (fn (x) (+ x n)))) \
+                 (define add5 (adder 5)) \
+                 (define n 100) \
+                 (add5 1)"
+            ),
+            Ok(Some(Value::I64(6)))
+        );
+        assert_eq!(eval_str("((fn (x) (* x x)) 4)"), Ok(Some(Value::I64(16))));
+    }
+
+    #[test]
+    fn test_global_defined_later() {
+        assert_eq!(
+            eval_str("(define f (fn (x) (g x))) (define g (fn (x) (* x 2))) (f 4)"),
+            Ok(Some(Value::I64(8)))
+        );
+    }
+
+    #[test]
+    fn test_call_error() {
+        assert_eq!(
+            eval_str("(define f (fn (x) x)) (f 1 2)"),
+            Err(EvalError::Arity {
+                name: "f".to_string(),
+                expected: 1,
+                found: 2,
+                span: Span::new(22, 29),
+                definition: Some(Span::new(10, 20)),
+            })
+        );
+        assert_eq!(
+            eval_str("(define x 1) (x 2)"),
+            Err(EvalError::NotAFunction {
+                span: Span::new(14, 15)
+            })
+        );
+        assert_eq!(
+            eval_str("(fn (x x) x)"),
+            Err(EvalError::MalformedForm {
+                form: "fn",
+                span: Span::new(0, 12)
+            })
+        );
+    }
+
+    #[test]
+    fn test_eval_error() {
+        assert_eq!(
+            eval_str("(+ 1 ())"),
+            Err(EvalError::EmptyForm {
+                span: Span::new(5, 7)
+            })
+        );
+        assert_eq!(
+            eval_str("(foo 1)"),
+            Err(EvalError::UnknownIdentifier {
+                name: "foo".to_string(),
+                span: Span::new(1, 4)
+            })
+        );
+        assert_eq!(
+            eval_str("(-)"),
+            Err(EvalError::TooFewArguments {
+                name: "-".to_string(),
+                min: 1,
+                found: 0,
+                span: Span::new(0, 3),
+            })
+        );
+    }
+
+    #[test]
+    fn test_eval_statements() {
+        let mut env = Environment::new();
+        let program = statements("var x = 1; var f = x; x = x + 2\nx * 10").unwrap();
+        assert_eq!(
+            eval_statements(&program, &mut env),
+            Ok(Some(Value::I64(30)))
+        );
+        assert_eq!(env.get("f"), Some(Value::I64(1)));
+        assert_eq!(
+            eval_statements(&statements("y = 1").unwrap(), &mut env),
+            Err(EvalError::UnknownIdentifier {
+                name: "y".to_string(),
+                span: Span::new(0, 5)
+            })
+        );
+    }
+
+    #[test]
+    fn test_if() {
+        assert_eq!(eval_str("(if 1 2 3)"), Ok(Some(Value::I64(2))));
+        assert_eq!(eval_str("(if (- 1 1) 2 3)"), Ok(Some(Value::I64(3))));
+        assert_eq!(eval_str("(if 0.0 2)"), Ok(Some(Value::Nil)));
+        // 選ばれなかった分岐は評価しない
+        assert_eq!(eval_str("(if 1 2 undefined)"), Ok(Some(Value::I64(2))));
+        assert_eq!(
+            eval_str("(if 1)"),
+            Err(EvalError::MalformedForm {
+                form: "if",
+                span: Span::new(0, 6)
+            })
+        );
+
+        let mut env = Environment::new();
+        let program = statements(
+            "var x = 0; var r = if x { 1 } else if x + 1 { var x = 5; x * 2 } else { 3 }; x + r",
+        )
+        .unwrap();
+        assert_eq!(
+            eval_statements(&program, &mut env),
+            Ok(Some(Value::I64(10)))
+        );
+        let program = statements("if 0 { 1 }; {}").unwrap();
+        assert_eq!(eval_statements(&program, &mut env), Ok(Some(Value::Nil)));
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut env = Environment::new();
+        let program = statements(
+            "var sum = 0\n\
+             for i in 0..10 { if i - (i / 2) * 2 { continue }; if i - 8 { sum = sum + i } else { break } }\n\
+             var n = 0; while 1 { n = n + 1; if n - 5 { } else { break } }\n\
+             sum * 100 + n",
+        )
+        .unwrap();
+        assert_eq!(
+            eval_statements(&program, &mut env),
+            Ok(Some(Value::I64(1205)))
+        );
+        assert_eq!(
+            eval_str("(define n 0) (while (- 3 n) (define n (+ n 1))) n"),
+            Ok(Some(Value::I64(3)))
+        );
+        assert_eq!(
+            eval_str("(for (i 0 1.5) i)"),
+            Err(EvalError::NotAnInteger {
+                span: Span::new(10, 13)
+            })
+        );
+        assert_eq!(
+            eval_str("((fn () (break)))"),
+            Err(EvalError::OutsideLoop {
+                keyword: "break",
+                span: Span::new(8, 15)
+            })
+        );
+    }
+
+    #[test]
+    fn test_comparison_and_logic() {
+        let mut env = Environment::new();
+        let program = statements(
+            "var a = 1 < 2 && 2 <= 2.0; var b = 1 == 1.0 && !(3 > 4) && 2 != 3; \
+             var c = 0 || 1 >= 2; a == b",
+        )
+        .unwrap();
+        assert_eq!(
+            eval_statements(&program, &mut env),
+            Ok(Some(Value::Bool(true)))
+        );
+        assert_eq!(env.get("c"), Some(Value::Bool(false)));
+        // 右辺は評価されない
+        assert_eq!(eval_str("(|| 1 undefined)"), Ok(Some(Value::Bool(true))));
+        assert_eq!(eval_str("(&& 0 undefined)"), Ok(Some(Value::Bool(false))));
+        assert_eq!(eval_str("(! (< 2 1))"), Ok(Some(Value::Bool(true))));
+        assert_eq!(
+            eval_str("(< 1 2 3)"),
+            Err(EvalError::Arity {
+                name: "<".to_string(),
+                expected: 2,
+                found: 3,
+                span: Span::new(0, 9),
+                definition: None,
+            })
+        );
+        assert_eq!(
+            eval_str("(< 1 (fn () 1))"),
+            Err(EvalError::NotANumber {
+                span: Span::new(5, 14)
+            })
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(eval_str("true"), Ok(Some(Value::Bool(true))));
+        assert_eq!(eval_str("(if false 1 nil)"), Ok(Some(Value::Nil)));
+        assert_eq!(eval_str("(== nil nil)"), Ok(Some(Value::Bool(true))));
+        assert_eq!(eval_str("(== false nil)"), Ok(Some(Value::Bool(false))));
+        assert_eq!(
+            eval_str("(define true 1)"),
+            Err(EvalError::MalformedForm {
+                form: "define",
+                span: Span::new(0, 15)
+            })
+        );
+        let values = [Value::Bool(true), Value::Bool(false), Value::Nil];
+        let shown: Vec<_> = values.iter().map(Value::to_string).collect();
+        assert_eq!(shown, ["true", "false", "nil"]);
+    }
+
+    #[test]
+    fn test_display() {
+        let list = Value::List(Rc::from([
+            Value::I64(1),
+            Value::F64(2.0),
+            Value::Str("a \"b\"\n".into()),
+            Value::List(Rc::from([])),
+            Value::Nil,
+        ]));
+        assert_eq!(list.to_string(), r#"(1 2.0 "a \"b\"\n" () nil)"#);
+        assert_eq!(
+            eval_str(r#"(define s "x\ty") s"#).map(|v| v.unwrap().to_string()),
+            Ok(r#""x\ty""#.to_string())
+        );
+        assert_eq!(
+            eval_str("(fn (a b) a)").map(|v| v.unwrap().to_string()),
+            Ok("<fn (a b)>".to_string())
+        );
+        assert_eq!(
+            eval_str(r#"(== "a" "a") (if "" 1 2)"#),
+            Ok(Some(Value::I64(2)))
+        );
+        assert_eq!(
+            eval_str(r#"(+ 1 "a")"#),
+            Err(EvalError::NotANumber {
+                span: Span::new(5, 8)
+            })
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(
+            eval_str("'(car cdr)").map(|v| v.unwrap().to_string()),
+            Ok("(car cdr)".to_string())
+        );
+        assert_eq!(eval_str("'x"), Ok(Some(Value::Symbol(Symbol::intern("x")))));
+        assert_eq!(
+            eval_str(r#"(define x 2) `(1 ,x (+ ,x 1) "s" ,'y)"#).map(|v| v.unwrap().to_string()),
+            Ok(r#"(1 2 (+ 2 1) "s" y)"#.to_string())
+        );
+        // 引用したデータは評価しないので、未定義の識別子を含んでもよい
+        assert_eq!(
+            eval_str("(== '(a 1) '(a 1.0))"),
+            Ok(Some(Value::Bool(true)))
+        );
+        assert_eq!(
+            eval_str("(quote 1 2)"),
+            Err(EvalError::MalformedForm {
+                form: "quote",
+                span: Span::new(0, 11)
+            })
+        );
+        assert_eq!(
+            eval_str(",x"),
+            Err(EvalError::MalformedForm {
+                form: "unquote",
+                span: Span::new(0, 2)
+            })
+        );
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        // 末尾呼び出しはスタックを消費しないので、深い再帰でもあふれない
+        assert_eq!(
+            eval_str(
+                "(define count (fn (n acc) (if (== n 0) acc (count (- n 1) (+ acc 1))))) (count 100000 0)"
+            ),
+            Ok(Some(Value::I64(100000)))
+        );
+        assert_eq!(
+            eval_str(
+                "(define even (fn (n) (if (== n 0) true (odd (- n 1))))) (define odd (fn (n) (let ((m (- n 1))) (if (< m 0) false (even m))))) (even 10001)"
+            ),
+            Ok(Some(Value::Bool(false)))
+        );
+        let program = statements(
+            "fn count(n, acc) { if n == 0 { acc } else { count(n - 1, acc + 1) } }; count(100000, 0)",
+        )
+        .unwrap();
+        assert_eq!(
+            eval_statements(&program, &mut Environment::new()),
+            Ok(Some(Value::I64(100000)))
+        );
+    }
+
+    fn run_infix(input: &str) -> Result<Option<Value>, EvalError> {
+        let program =
+            statements(input).unwrap_or_else(|e| panic!("failed to parse {input:?}: {e}"));
+        eval_statements(&program, &mut Environment::new())
+    }
+
+    #[test]
+    fn test_scope_shadowing() {
+        // 内側の `var` は外側の束縛を隠すだけで、書き換えない
+        assert_eq!(
+            run_infix("var x = 1\n{ var x = 2; x = 3 }\nx"),
+            Ok(Some(Value::I64(1)))
+        );
+        assert_eq!(
+            run_infix("var x = 1\n{ x = 3 }\nx"),
+            Ok(Some(Value::I64(3)))
+        );
+        // 仮引数も同じ名前の `var` で隠せる
+        assert_eq!(
+            run_infix("fn f(x) { var x = x + 1; x * 10 }\nf(1)"),
+            Ok(Some(Value::I64(20)))
+        );
+    }
+
+    #[test]
+    fn test_scope_capture() {
+        // 関数は呼び出し側ではなく、定義した場所のスコープから名前を探す
+        assert_eq!(
+            run_infix("var x = 1\nfn show() { x }\nfn call() { var x = 2; show() }\ncall()"),
+            Ok(Some(Value::I64(1)))
+        );
+        assert_eq!(
+            run_infix(
+                "fn outer() { var x = 1; fn inner() { x }; inner }\n\
+                 var f = outer()\n\
+                 var x = 99\n\
+                 f()"
+            ),
+            Ok(Some(Value::I64(1)))
+        );
+        // 捕捉した束縛は呼び出しの間で共有され、呼び出しごとに作った束縛は別になる
+        assert_eq!(
+            run_infix(
+                "fn counter() { var n = 0; fn() { n = n + 1; n } }\n\
+                 var a = counter()\n\
+                 var b = counter()\n\
+                 a(); a(); b(); a() * 10 + b()"
+            ),
+            Ok(Some(Value::I64(32)))
+        );
+        // 同じスコープの内側の関数は、後に定義したものも互いに呼び出せる
+        assert_eq!(
+            run_infix(
+                "fn parity(n) {\n\
+                 fn even(n) { if n == 0 { true } else { odd(n - 1) } }\n\
+                 fn odd(n) { if n == 0 { false } else { even(n - 1) } }\n\
+                 even(n) }\n\
+                 parity(7)"
+            ),
+            Ok(Some(Value::Bool(false)))
+        );
+    }
+
+    #[test]
+    fn test_scope_leakage() {
+        let unknown = |name: &str, start, end| {
+            Err(EvalError::UnknownIdentifier {
+                name: name.to_string(),
+                span: Span::new(start, end),
+            })
+        };
+        assert_eq!(run_infix("{ var a = 1 }\na"), unknown("a", 14, 15));
+        assert_eq!(run_infix("{ fn h() { 1 } }\nh()"), unknown("h", 17, 18));
+        // 呼び出し側のローカル変数は、呼び出された関数から見えない
+        assert_eq!(
+            run_infix("fn f() { y }\nfn g() { var y = 1; f() }\ng()"),
+            unknown("y", 9, 10)
+        );
+        // 兄弟のスコープの間でも束縛は漏れない
+        assert_eq!(
+            run_infix("fn f() { var z = 1; z }\nfn g() { z }\nf(); g()"),
+            unknown("z", 33, 34)
+        );
+        assert_eq!(
+            run_infix("for i in 0..2 { var last = i }\nlast"),
+            unknown("last", 31, 35)
+        );
+    }
+
+    #[test]
+    fn test_return() {
+        assert_eq!(
+            run_infix(
+                "fn find(n) { var i = 0; while true { if i * i > n { return i }; i = i + 1 } }\n\
+                 find(20)"
+            ),
+            Ok(Some(Value::I64(5)))
+        );
+        // `return` は一番内側の関数だけを抜ける
+        assert_eq!(
+            run_infix(
+                "fn outer() { var f = fn() { return 1; 2 }; for i in 0..3 { { return f() + 10 } }; 0 }\n\
+                 outer()"
+            ),
+            Ok(Some(Value::I64(11)))
+        );
+        assert_eq!(run_infix("fn f() { return }\nf()"), Ok(Some(Value::Nil)));
+        assert_eq!(
+            run_infix("var x = 1\n{ return x }"),
+            Err(EvalError::OutsideFunction {
+                span: Span::new(12, 20)
+            })
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let deep = "fn f(n) { if n == 0 { 0 } else { 1 + f(n - 1) } }\nf(";
+        assert_eq!(run_infix(&format!("{deep}20)")), Ok(Some(Value::I64(20))));
+        let Err(EvalError::StackOverflow {
+            depth,
+            stack_budget: Some(_),
+            span,
+        }) = run_infix(&format!("{deep}100000)"))
+        else {
+            panic!("expected the stack budget to run out");
+        };
+        assert!(depth > 20, "{depth}");
+        assert_eq!(span, Span::new(37, 45));
+        // 末尾位置の呼び出しは深さに数えない
+        assert_eq!(
+            run_infix("fn loop(n) { if n == 0 { 0 } else { loop(n - 1) } }\nloop(100000)"),
+            Ok(Some(Value::I64(0)))
+        );
+
+        limits::set_run_limits(RunLimits {
+            max_call_depth: 5,
+            ..RunLimits::UNLIMITED
+        });
+        let res = run_infix(&format!("{deep}10)"));
+        limits::set_run_limits(RunLimits::UNLIMITED);
+        assert_eq!(
+            res.map_err(|e| e.to_string()),
+            Err("stack overflow after 5 nested calls at byte 37".to_string())
+        );
+        // エラーで抜けた呼び出しも深さから除かれる
+        assert!(run_infix(&format!("{deep}1)")).is_ok());
+    }
+
+    #[test]
+    fn test_with_stack_size() {
+        limits::set_run_limits(RunLimits {
+            max_call_depth: 50_000,
+            ..RunLimits::UNLIMITED
+        });
+        let res = with_stack_size(512 << 20, || {
+            let program =
+                statements("fn f(n) { if n == 0 { 0 } else { 1 + f(n - 1) } }\nf(5000)").unwrap();
+            let res = eval_statements(&program, &mut Environment::new());
+            (max_call_depth(), res.map(|v| v.unwrap().to_string()))
+        });
+        limits::set_run_limits(RunLimits::UNLIMITED);
+        assert_eq!(res, (50_000, Ok("5000".to_string())));
+    }
+
+    #[test]
+    fn test_match() {
+        let classify = "fn classify(x) {\n\
+                          match x {\n\
+                            0 => \"zero\",\n\
+                            -1 => \"minus one\",\n\
+                            \"a\" => \"letter\",\n\
+                            nil => \"nothing\",\n\
+                            n if n > 100 => \"big\",\n\
+                            _ => \"other\"\n\
+                          }\n\
+                        }\n";
+        for (arg, expected) in [
+            ("0", "zero"),
+            ("0.0", "zero"),
+            ("-1", "minus one"),
+            ("\"a\"", "letter"),
+            ("nil", "nothing"),
+            ("101", "big"),
+            ("7", "other"),
+        ] {
+            assert_eq!(
+                run_infix(&format!("{classify}classify({arg})")),
+                Ok(Some(Value::Str(expected.into()))),
+                "{arg}"
+            );
+        }
+        // 腕の名前は腕の中だけで見え、外の同じ名前を隠す
+        assert_eq!(
+            run_infix("var n = 1\nvar m = match 5 { n => n * 2 }\nm + n"),
+            Ok(Some(Value::I64(11)))
+        );
+        // 腕の本体は末尾位置なので、深い再帰でもスタックを使わない
+        assert_eq!(
+            run_infix(
+                "fn count(n, acc) { match n { 0 => acc, _ => count(n - 1, acc + 1) } }\n\
+                 count(100000, 0)"
+            ),
+            Ok(Some(Value::I64(100000)))
+        );
+        assert_eq!(
+            run_infix("match 3 { 1 => 1, n if n < 0 => 2 }"),
+            Err(EvalError::NoMatch {
+                value: "3".to_string(),
+                span: Span::new(0, 35)
+            })
+        );
+    }
+
+    #[test]
+    fn test_array() {
+        assert_eq!(
+            run_infix("var a = [1, [2, 3], \"s\"]\na[1][0] = a[0] + 10\na")
+                .map(|v| v.unwrap().to_string()),
+            Ok("[1, [11, 3], \"s\"]".to_string())
+        );
+        // 配列を複製した値は同じ配列を指すので、関数の中での書き換えが呼び出し側から見える
+        assert_eq!(
+            run_infix("fn set(xs) { xs[0] = 5 }\nvar a = [0]\nvar b = a\nset(b)\na[0]"),
+            Ok(Some(Value::I64(5)))
+        );
+        assert_eq!(
+            run_infix("[1, 2.0] == [1.0, 2]"),
+            Ok(Some(Value::Bool(true)))
+        );
+        assert_eq!(run_infix("[] || 1"), Ok(Some(Value::Bool(true))));
+        assert_eq!(
+            run_infix("var a = [1, 2]\na[2]"),
+            Err(EvalError::IndexOutOfBounds {
+                index: 2,
+                len: 2,
+                span: Span::new(15, 19)
+            })
+        );
+        assert_eq!(
+            run_infix("var a = [1]\na[-1] = 0"),
+            Err(EvalError::IndexOutOfBounds {
+                index: -1,
+                len: 1,
+                span: Span::new(12, 21)
+            })
+        );
+        assert_eq!(
+            run_infix("[1][0.5]"),
+            Err(EvalError::NotAnInteger {
+                span: Span::new(0, 8)
+            })
+        );
+        assert_eq!(
+            run_infix("var s = \"ab\"\ns[0] = 1"),
+            Err(EvalError::TypeMismatch {
+                expected: "an array",
+                span: Span::new(13, 21)
+            })
+        );
+    }
+
+    #[test]
+    fn test_map() {
+        assert_eq!(
+            run_infix("var k = \"b\"\n{ k: [1], \"a\": nil, 2: true, -1: {:}, false: 0, nil: 1 }")
+                .map(|v| v.unwrap().to_string()),
+            Ok("{nil: 1, false: 0, -1: {:}, 2: true, \"a\": nil, \"b\": [1]}".to_string())
+        );
+        // 同じキーが続けば後の値が残る
+        assert_eq!(
+            run_infix("{ 1: 1, 1: 2 } == { 1: 2.0 }"),
+            Ok(Some(Value::Bool(true)))
+        );
+        assert_eq!(run_infix("{:} || 1"), Ok(Some(Value::Bool(true))));
+        assert_eq!(
+            run_infix("{ \"a\": 1, 0.5: 2 }"),
+            Err(EvalError::TypeMismatch {
+                expected: "an integer, a string, a boolean or nil as a map key",
+                span: Span::new(10, 13)
+            })
+        );
+        // マップは位置では引けず、エラーは `get` と `insert` を示す
+        let e = run_infix("var m = { \"k\": 1 }\nm[\"k\"]").unwrap_err();
+        assert_eq!(e.span(), Span::new(19, 25));
+        assert_eq!(
+            e.to_string(),
+            "expected an array, a list, a string or a module, not a map (use `get(map, key)`) at byte 19"
+        );
+        let e = run_infix("var m = { \"k\": 1 }\nm[\"k\"] = 2").unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("not a map (use `insert(map, key, value)`)"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn test_display_cycles() {
+        let run = |input: &str| {
+            let mut env = Environment::new();
+            crate::stdlib::register(&mut env);
+            eval_statements(&statements(input).unwrap(), &mut env).map(|v| v.unwrap().to_string())
+        };
+        assert_eq!(run("var a = [1]; a[0] = a; a"), Ok("[[...]]".into()));
+        assert_eq!(
+            run("var m = { 1: 2 }; insert(m, 1, [m, m]); to_string(m)"),
+            Ok("\"{1: [{...}, {...}]}\"".into())
+        );
+        // 循環していなければ、同じ値を何度含んでも省略しない
+        assert_eq!(run("var a = [1]; [a, a]"), Ok("[[1], [1]]".into()));
+    }
+
+    #[test]
+    fn test_compare_cycles() {
+        let run = |input: &str| {
+            let mut env = Environment::new();
+            crate::stdlib::register(&mut env);
+            eval_statements(&statements(input).unwrap(), &mut env).map(Option::unwrap)
+        };
+        let arrays = "var a = [1]; a[0] = a; var b = [1]; b[0] = b;";
+        assert_eq!(run(&format!("{arrays} a == b")), Ok(Value::Bool(true)));
+        assert_eq!(run(&format!("{arrays} a == a")), Ok(Value::Bool(true)));
+        // 循環していても、たどった先の違いは見つける
+        let different = "var a = [1, 1]; a[0] = a; var b = [1, 2]; b[0] = b;";
+        assert_eq!(run(&format!("{different} a == b")), Ok(Value::Bool(false)));
+        // 全順序も同じ規則で比べる
+        let Ok(Value::Array(pair)) = run(&format!("{different} [a, b]")) else {
+            panic!("expected an array");
+        };
+        let pair = pair.borrow();
+        assert_eq!(pair[0].cmp(&pair[1]), Ordering::Less);
+        assert_ne!(pair[0], pair[1]);
+        let Ok(Value::Array(pair)) = run(&format!("{arrays} [a, b]")) else {
+            panic!("expected an array");
+        };
+        let pair = pair.borrow();
+        assert_eq!(pair[0].cmp(&pair[1]), Ordering::Equal);
+        assert_eq!(
+            run("var m = { 1: 2 }; insert(m, 1, m); var n = { 1: 2 }; insert(n, 1, n); m == n"),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_comparison_matrix() {
+        use std::collections::hash_map::DefaultHasher;
+
+        let value = |input: &str| eval_str(input).unwrap().unwrap();
+        let nan = "(/ 0.0 0)";
+        // (左辺, 右辺, 言語の `==`, 言語の `<`。数値でなければ `None`, 全順序)
+        let matrix = [
+            ("1", "1.0", true, Some(false), Ordering::Less),
+            ("1", "2", false, Some(true), Ordering::Less),
+            ("2.5", "2", false, Some(false), Ordering::Greater),
+            (nan, nan, false, Some(false), Ordering::Equal),
+            (nan, "1", false, Some(false), Ordering::Greater),
+            ("1e308", nan, false, Some(false), Ordering::Less),
+            ("-0.0", "0.0", true, Some(false), Ordering::Less),
+            ("0", "-0.0", true, Some(false), Ordering::Less),
+            // 2^53 + 1 は浮動小数点数に変換すると 2^53 に丸まるが、丸めずに比べる
+            (
+                "9007199254740993",
+                "9007199254740992.0",
+                false,
+                Some(false),
+                Ordering::Greater,
+            ),
+            (
+                "9223372036854775807",
+                "9223372036854775808.0",
+                false,
+                Some(true),
+                Ordering::Less,
+            ),
+            ("\"a\"", "\"a\"", true, None, Ordering::Equal),
+            ("\"a\"", "\"b\"", false, None, Ordering::Less),
+            ("\"a\"", "1", false, None, Ordering::Greater),
+            ("nil", "false", false, None, Ordering::Less),
+            ("true", "0", false, None, Ordering::Less),
+            ("'(1 2)", "'(1 2.0)", true, None, Ordering::Less),
+            ("'(1 2)", "'(1 2 3)", false, None, Ordering::Less),
+            ("'a", "\"a\"", false, None, Ordering::Greater),
+        ];
+        for (lhs, rhs, equal, less, order) in matrix {
+            let case = format!("{lhs} {rhs}");
+            assert_eq!(
+                value(&format!("(== {lhs} {rhs})")),
+                Value::Bool(equal),
+                "{case}"
+            );
+            assert_eq!(
+                eval_str(&format!("(< {lhs} {rhs})")).ok().flatten(),
+                less.map(Value::Bool),
+                "{case}"
+            );
+            let (lhs, rhs) = (value(lhs), value(rhs));
+            assert_eq!(lhs.total_cmp(&rhs), order, "{case}");
+            assert_eq!(rhs.total_cmp(&lhs), order.reverse(), "{case}");
+            assert_eq!(lhs == rhs, order == Ordering::Equal, "{case}");
+            if lhs == rhs {
+                let hash = |value: &Value| {
+                    let mut hasher = DefaultHasher::new();
+                    value.hash(&mut hasher);
+                    hasher.finish()
+                };
+                assert_eq!(hash(&lhs), hash(&rhs), "{case}");
+            }
+        }
+        // 関数は同じ `fn` 式の評価で作られたものだけが等しい
+        assert_eq!(value("(define f (fn (x) x)) (== f f)"), Value::Bool(true));
+        assert_eq!(value("(== (fn (x) x) (fn (x) x))"), Value::Bool(false));
+    }
+
+    #[test]
+    fn test_total_order_sorts() {
+        let mut values = vec![
+            Value::Str("b".into()),
+            Value::F64(f64::NAN),
+            Value::I64(2),
+            Value::F64(1.5),
+            Value::Nil,
+            Value::Bool(true),
+            Value::F64(f64::NEG_INFINITY),
+            Value::I64(1),
+            Value::F64(1.0),
+            Value::Str("a".into()),
+        ];
+        values.sort();
+        assert_eq!(
+            values.iter().map(Value::to_string).collect::<Vec<_>>(),
+            ["nil", "true", "-inf", "1", "1.0", "1.5", "2", "NaN", "\"a\"", "\"b\""]
+        );
+        // 全順序では NaN 同士が等しいので、集合に入れると1つにまとまる
+        let set: std::collections::HashSet<_> = [Value::F64(f64::NAN), Value::F64(-f64::NAN)]
+            .into_iter()
+            .collect();
+        assert_eq!(set.len(), 1);
+        // キーの表示順は挿入の順によらない
+        let program = statements("{\"k\": 1, 2: 2, nil: 3, false: 4}").unwrap();
+        let map = eval_statements(&program, &mut Environment::new()).unwrap();
+        assert_eq!(
+            map.unwrap().to_string(),
+            "{nil: 3, false: 4, 2: 2, \"k\": 1}"
+        );
+    }
+}
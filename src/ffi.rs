@@ -0,0 +1,238 @@
+//! C や C++ のプログラムに組み込むための `extern "C"` の API
+//!
+//! `ffi` フィーチャーを有効にすると使える。宣言は `include/ruscal.h` にあり、
+//! cbindgen は使わずに手で合わせている。C から使うライブラリは次のように作る。
+//!
+//! ```text
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! # 所有権
+//!
+//! * 入力の文字列は呼び出しの間だけ借用し、呼び出し側が所有し続ける
+//! * [`ruscal_parse`] と [`ruscal_eval`] が返す [`RuscalResult`] とその `text` はライブラリが確保したもので、
+//!   呼び出し側は [`ruscal_free_result`] でちょうど1回解放する。`free()` で解放してはならない
+
+use std::ffi::{c_char, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::ast::TokenTree;
+use crate::diagnostics::Diagnostic;
+use crate::env::Environment;
+use crate::eval::eval_forms;
+use crate::json::ToJson;
+use crate::parser::source_recovering;
+use crate::source_map::SourceMap;
+use crate::stdlib;
+
+/// 呼び出しの結果の種類
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuscalStatus {
+    /// 成功した
+    Ok = 0,
+    /// 入力を解析できなかった
+    ParseError = 1,
+    /// 評価に失敗した
+    EvalError = 2,
+    /// 入力が UTF-8 として正しくない
+    InvalidUtf8 = 3,
+    /// ライブラリの内部でパニックが起きた
+    Panic = 4,
+}
+
+/// 呼び出しの結果
+///
+/// C から見たレイアウトを変えないよう、フィールドの追加や並べ替えはしない。
+#[repr(C)]
+#[derive(Debug)]
+pub struct RuscalResult {
+    /// 結果の種類
+    pub status: RuscalStatus,
+    /// NUL で終わる UTF-8 の文字列
+    ///
+    /// 成功すれば解析した木の JSON か評価した値の表示、失敗すればエラーの報告を持つ。
+    pub text: *mut c_char,
+    /// `text` の末尾の NUL を除いたバイト数
+    pub len: usize,
+}
+
+/// 結果を確保して、所有権を呼び出し側に渡す
+fn result(status: RuscalStatus, text: String) -> *mut RuscalResult {
+    // 文字列の途中の NUL は C の文字列として読めないので取り除く
+    let text = CString::new(text.replace('\0', "")).expect("NUL bytes are removed above");
+    let len = text.as_bytes().len();
+    Box::into_raw(Box::new(RuscalResult {
+        status,
+        text: text.into_raw(),
+        len,
+    }))
+}
+
+/// 報告を rustc に似た形で並べる
+fn render(input: &str, diagnostics: &[Diagnostic]) -> String {
+    let map = SourceMap::new(input);
+    diagnostics.iter().map(|d| d.render(&map)).collect()
+}
+
+/// 入力を文字列として読み、関数を呼んだ結果を返す
+///
+/// # Safety
+/// `input` は `len` バイトを読み出せるか、`len` が0でなければならない。
+unsafe fn call(
+    input: *const c_char,
+    len: usize,
+    f: fn(&str) -> (RuscalStatus, String),
+) -> *mut RuscalResult {
+    let bytes = if len == 0 {
+        &[][..]
+    } else {
+        // SAFETY: 呼び出し側が読み出せる範囲を渡すことを保証する
+        unsafe { std::slice::from_raw_parts(input.cast::<u8>(), len) }
+    };
+    let Ok(input) = std::str::from_utf8(bytes) else {
+        return result(
+            RuscalStatus::InvalidUtf8,
+            "input is not valid UTF-8".to_string(),
+        );
+    };
+    match catch_unwind(AssertUnwindSafe(|| f(input))) {
+        Ok((status, text)) => result(status, text),
+        Err(_) => result(RuscalStatus::Panic, "internal error".to_string()),
+    }
+}
+
+/// 解析して、木の JSON か解析エラーの報告を返す
+fn parse(input: &str) -> (RuscalStatus, String) {
+    let (tree, diagnostics) = source_recovering(input);
+    if diagnostics.is_empty() {
+        (RuscalStatus::Ok, tree.to_json().to_string())
+    } else {
+        (RuscalStatus::ParseError, render(input, &diagnostics))
+    }
+}
+
+/// 標準ライブラリ入りの新しい環境で評価して、値の表示かエラーの報告を返す
+fn eval(input: &str) -> (RuscalStatus, String) {
+    let (tree, diagnostics) = source_recovering(input);
+    if !diagnostics.is_empty() {
+        return (RuscalStatus::ParseError, render(input, &diagnostics));
+    }
+    let TokenTree::Tree(forms, _) = &tree else {
+        unreachable!("source_recovering() always returns a tree");
+    };
+    let mut env = Environment::new();
+    stdlib::register(&mut env);
+    match eval_forms(forms, &mut env) {
+        Ok(value) => (
+            RuscalStatus::Ok,
+            value.map_or_else(|| "nil".to_string(), |value| value.to_string()),
+        ),
+        Err(e) => (
+            RuscalStatus::EvalError,
+            render(input, &[Diagnostic::from(&e)]),
+        ),
+    }
+}
+
+/// ソースコードを解析する
+///
+/// 成功すれば `text` に木の JSON を、失敗すれば解析エラーの報告を持つ結果を返す。
+///
+/// # Safety
+/// `input` は `len` バイトを読み出せなければならない。`len` が0なら `input` は NULL でもよい。
+#[no_mangle]
+pub unsafe extern "C" fn ruscal_parse(input: *const c_char, len: usize) -> *mut RuscalResult {
+    // SAFETY: 引数の条件は呼び出し側が保証する
+    unsafe { call(input, len, parse) }
+}
+
+/// ソースコードを標準ライブラリ入りの新しい環境で評価する
+///
+/// 成功すれば `text` に最後の式の値の表示を、失敗すればエラーの報告を持つ結果を返す。
+///
+/// # Safety
+/// `input` は `len` バイトを読み出せなければならない。`len` が0なら `input` は NULL でもよい。
+#[no_mangle]
+pub unsafe extern "C" fn ruscal_eval(input: *const c_char, len: usize) -> *mut RuscalResult {
+    // SAFETY: 引数の条件は呼び出し側が保証する
+    unsafe { call(input, len, eval) }
+}
+
+/// [`ruscal_parse`] や [`ruscal_eval`] が返した結果を解放する
+///
+/// NULL を渡した場合は何もしない。
+///
+/// # Safety
+/// `result` はこのライブラリが返した、まだ解放していない結果か NULL でなければならない。
+#[no_mangle]
+pub unsafe extern "C" fn ruscal_free_result(result: *mut RuscalResult) {
+    if result.is_null() {
+        return;
+    }
+    // SAFETY: 呼び出し側が、result() で確保してまだ解放していない結果を渡すことを保証する
+    let result = unsafe { Box::from_raw(result) };
+    // SAFETY: text は result() で CString::into_raw したもの
+    drop(unsafe { CString::from_raw(result.text) });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+
+    /// 関数を呼んで、結果の種類と文字列を取り出してから解放する
+    fn run(
+        f: unsafe extern "C" fn(*const c_char, usize) -> *mut RuscalResult,
+        input: &[u8],
+    ) -> (RuscalStatus, String) {
+        unsafe {
+            let result = f(input.as_ptr().cast(), input.len());
+            let text = CStr::from_ptr((*result).text).to_str().unwrap().to_string();
+            assert_eq!(text.len(), (*result).len);
+            let status = (*result).status;
+            ruscal_free_result(result);
+            (status, text)
+        }
+    }
+
+    #[test]
+    fn test_parse_and_eval() {
+        let (status, text) = run(ruscal_parse, b"(a)");
+        assert_eq!(status, RuscalStatus::Ok);
+        assert!(text.starts_with(r#"{"Tree":"#));
+
+        assert_eq!(
+            run(ruscal_eval, b"(define x 20) (+ x 1)"),
+            (RuscalStatus::Ok, "21".to_string())
+        );
+        assert_eq!(run(ruscal_eval, b""), (RuscalStatus::Ok, "nil".to_string()));
+
+        let (status, text) = run(ruscal_eval, b"(a");
+        assert_eq!(status, RuscalStatus::ParseError);
+        assert!(text.starts_with("error[E0001]: "), "{text}");
+        assert_eq!(run(ruscal_eval, b"(/ 1 0)").0, RuscalStatus::EvalError);
+        assert_eq!(run(ruscal_parse, b"\xff").0, RuscalStatus::InvalidUtf8);
+
+        unsafe {
+            let result = ruscal_eval(std::ptr::null(), 0);
+            assert_eq!((*result).status, RuscalStatus::Ok);
+            ruscal_free_result(result);
+            ruscal_free_result(std::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let header = include_str!("../include/ruscal.h");
+        for name in [
+            "ruscal_parse",
+            "ruscal_eval",
+            "ruscal_free_result",
+            "RuscalResult",
+        ] {
+            assert!(header.contains(name), "{name} is missing from ruscal.h");
+        }
+        assert!(header.contains("RUSCAL_STATUS_PANIC = 4"));
+    }
+}
@@ -0,0 +1,190 @@
+//! 浮動小数点数を [`Value`](crate::Value) として表示するときの書式
+//!
+//! [`set_float_format`] で、整数の値に小数点を付けるか、どの大きさから指数表記にするか、
+//! 小数点以下を何桁まで書くかを設定する。既定の書式は `{:?}` と同じ表記で、
+//! 表示した文字列を字句解析し直すと元と同じ浮動小数点数になる。
+//! 書式はスレッドごとに持つ。
+//!
+//! ```
+//! use ruscal_b::float_format::{set_float_format, FloatFormat};
+//! use ruscal_b::Value;
+//!
+//! assert_eq!(Value::F64(1.0).to_string(), "1.0");
+//! set_float_format(FloatFormat {
+//!     precision: Some(2),
+//!     ..FloatFormat::default()
+//! });
+//! assert_eq!(Value::F64(1.0 / 3.0).to_string(), "0.33");
+//! set_float_format(FloatFormat::default());
+//! ```
+
+use std::cell::Cell;
+
+/// 浮動小数点数の書式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloatFormat {
+    /// 小数部の無い値にも `.0` を付けるなら `true`
+    ///
+    /// `false` にすると `1.0` は `1` と表示され、読み直すと整数になる。
+    pub point: bool,
+    /// 10進の指数がこの値以上なら指数表記にする
+    pub upper_exponent: i32,
+    /// 10進の指数がこの値以下なら指数表記にする
+    pub lower_exponent: i32,
+    /// 小数点以下に書く桁数。`None` なら元の値に読み戻せる最短の桁数
+    ///
+    /// 桁数を指定すると丸めるので、読み直しても元の値になるとは限らない。
+    pub precision: Option<usize>,
+}
+
+impl FloatFormat {
+    /// `{:?}` と同じ表記になる書式
+    pub const DEBUG: Self = Self {
+        point: true,
+        upper_exponent: 16,
+        lower_exponent: -5,
+        precision: None,
+    };
+
+    /// 浮動小数点数をこの書式の文字列にする
+    ///
+    /// 無限大と NaN は `inf`、`-inf`、`NaN` と書く。
+    pub fn format(&self, n: f64) -> String {
+        if !n.is_finite() {
+            return format!("{n:?}");
+        }
+        let scientific = n != 0.0 && {
+            let exponent = exponent(n);
+            exponent >= self.upper_exponent || exponent <= self.lower_exponent
+        };
+        let mut text = match (scientific, self.precision) {
+            (true, Some(precision)) => format!("{n:.precision$e}"),
+            (true, None) => format!("{n:e}"),
+            (false, Some(precision)) => format!("{n:.precision$}"),
+            (false, None) => format!("{n}"),
+        };
+        // 指数表記は小数点が無くても浮動小数点数として読める
+        if self.point && !text.contains(['.', 'e']) {
+            text.push_str(".0");
+        }
+        text
+    }
+}
+
+impl Default for FloatFormat {
+    fn default() -> Self {
+        Self::DEBUG
+    }
+}
+
+/// 0でない有限の値の、10進の指数
+///
+/// `log10` は10の累乗の近くで誤差が出るので、指数表記の文字列から読む。
+fn exponent(n: f64) -> i32 {
+    let text = format!("{n:e}");
+    text.split_once('e')
+        .and_then(|(_, exponent)| exponent.parse().ok())
+        .unwrap_or(0)
+}
+
+thread_local! {
+    /// このスレッドの書式
+    static FORMAT: Cell<FloatFormat> = const { Cell::new(FloatFormat::DEBUG) };
+}
+
+/// このスレッドで浮動小数点数を表示する書式を設定する関数
+pub fn set_float_format(format: FloatFormat) {
+    FORMAT.with(|f| f.set(format));
+}
+
+/// このスレッドで浮動小数点数を表示する書式
+pub fn float_format() -> FloatFormat {
+    FORMAT.with(Cell::get)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::Token;
+    use crate::lexer::Lexer;
+
+    const SAMPLES: &[f64] = &[
+        0.0,
+        -0.0,
+        1.0,
+        -2.5,
+        0.1,
+        1.0 / 3.0,
+        1e-4,
+        1e-5,
+        1.5e-7,
+        123456.789,
+        1e15,
+        1e16,
+        9.99e15,
+        -1.25e300,
+        f64::MIN_POSITIVE,
+        f64::MAX,
+    ];
+
+    #[test]
+    fn test_default_matches_debug() {
+        for &n in SAMPLES {
+            assert_eq!(FloatFormat::default().format(n), format!("{n:?}"));
+        }
+        for n in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+            assert_eq!(FloatFormat::default().format(n), format!("{n:?}"));
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // 既定の書式で表示した文字列は、字句解析し直すと同じ値の浮動小数点数になる
+        for &n in SAMPLES {
+            let text = FloatFormat::default().format(n);
+            let tokens: Vec<_> = Lexer::new(&text).map(|res| res.unwrap().1).collect();
+            assert_eq!(tokens, [Token::Float(n)], "{text}");
+        }
+    }
+
+    #[test]
+    fn test_options() {
+        let format = |format: FloatFormat, n: f64| format.format(n);
+        let no_point = FloatFormat {
+            point: false,
+            ..FloatFormat::default()
+        };
+        assert_eq!(format(no_point, 1.0), "1");
+        assert_eq!(format(no_point, 1.5), "1.5");
+        let scientific = FloatFormat {
+            upper_exponent: 3,
+            lower_exponent: -2,
+            ..FloatFormat::default()
+        };
+        assert_eq!(format(scientific, 999.0), "999.0");
+        assert_eq!(format(scientific, 1000.0), "1e3");
+        assert_eq!(format(scientific, 0.01), "1e-2");
+        assert_eq!(format(scientific, 0.0), "0.0");
+        let precise = FloatFormat {
+            precision: Some(3),
+            ..scientific
+        };
+        assert_eq!(format(precise, 2.0 / 3.0), "0.667");
+        assert_eq!(format(precise, 12345.0), "1.234e4");
+        let rounded = FloatFormat {
+            precision: Some(0),
+            ..FloatFormat::default()
+        };
+        assert_eq!(format(rounded, 2.5), "2.0");
+        assert_eq!(
+            format(
+                FloatFormat {
+                    point: false,
+                    ..rounded
+                },
+                2.5
+            ),
+            "2"
+        );
+    }
+}
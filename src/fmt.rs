@@ -0,0 +1,264 @@
+//! `TokenTree` を人が読みやすい形に整形するプリティプリンター
+
+use crate::ast::{Token, TokenTree};
+
+/// 木を幅に収まるよう改行とインデントを入れて整形する関数
+///
+/// 1行に収まる部分木はそのまま1行で書き、収まらない部分木は先頭の要素を括弧と同じ行に置き、
+/// 残りの要素を1つずつ改行して2文字分インデントする。
+///
+/// # 引数
+/// * `tree` - 整形する木
+/// * `width` - 1行の目安となる最大の文字数
+///
+/// # 戻り値
+/// * `String` - 整形結果の文字列。末尾に改行は付けない
+pub fn pretty(tree: &TokenTree, width: usize) -> String {
+    let mut out = String::new();
+    write_tree(&mut out, tree, 0, width);
+    out
+}
+
+/// 木を改行を入れずに1行で書き出す関数
+///
+/// # 引数
+/// * `tree` - 書き出す木
+///
+/// # 戻り値
+/// * `String` - 要素を空白1つで区切った文字列
+pub fn flat(tree: &TokenTree) -> String {
+    if let Some((mark, quoted)) = prefix_form(tree) {
+        return format!("{mark}{}", flat(quoted));
+    }
+    match tree {
+        TokenTree::Token(token, _) => token_text(token),
+        TokenTree::Tree(children, _) => {
+            let children: Vec<_> = children.iter().map(flat).collect();
+            format!("({})", children.join(" "))
+        }
+    }
+}
+
+/// 構文解析の結果をもう一度読めるソースコードに書き戻す関数
+///
+/// 最上位の式を空白1つで区切って1行に並べ、`'x` などの前置記号はそのまま書く。
+/// コメントは残らず、空白は1つにまとめるが、
+/// 書き出した文字列を [`source`](crate::source) で読むと、範囲を除いて同じ木になる。
+///
+/// # 引数
+/// * `tree` - [`source`](crate::source) が返した、入力全体を表す木
+///
+/// # 戻り値
+/// * `String` - ソースコードの文字列
+pub fn to_source(tree: &TokenTree) -> String {
+    match tree {
+        TokenTree::Token(..) => flat(tree),
+        TokenTree::Tree(forms, _) => {
+            let forms: Vec<_> = forms.iter().map(flat).collect();
+            forms.join(" ")
+        }
+    }
+}
+
+/// トークンをソースコードとして書いたときの文字列
+pub(crate) fn token_text(token: &Token) -> String {
+    match token {
+        Token::Ident(name) => name.to_string(),
+        Token::Int(n) => n.to_string(),
+        Token::Float(n) => format!("{n:?}"),
+        Token::StrLiteral(raw) => format!("\"{raw}\""),
+        Token::Bool(b) => b.to_string(),
+        Token::Nil => "nil".to_string(),
+        Token::LParen => "(".to_string(),
+        Token::RParen => ")".to_string(),
+        Token::Semicolon => ";".to_string(),
+        Token::LBrace => "{".to_string(),
+        Token::RBrace => "}".to_string(),
+        Token::LBracket => "[".to_string(),
+        Token::RBracket => "]".to_string(),
+        Token::Keyword(keyword) => keyword.name().to_string(),
+    }
+}
+
+/// `'x` のように前置記号で書かれた式ならば、(記号, 続く要素)のタプルを返す関数
+///
+/// 括弧で書いた `(quote x)` は先頭の名前の範囲が記号1文字分ではないので区別できる。
+fn prefix_form<'a, 'src>(tree: &'a TokenTree<'src>) -> Option<(char, &'a TokenTree<'src>)> {
+    let TokenTree::Tree(children, span) = tree else {
+        return None;
+    };
+    let [TokenTree::Token(Token::Ident(form), head), quoted] = children.as_slice() else {
+        return None;
+    };
+    if head.start != span.start || head.len() != 1 {
+        return None;
+    }
+    let mark = match *form {
+        "quote" => '\'',
+        "quasiquote" => '`',
+        "unquote" => ',',
+        _ => return None,
+    };
+    Some((mark, quoted))
+}
+
+/// 現在の桁位置 `column` から木を書き出す関数
+fn write_tree(out: &mut String, tree: &TokenTree, column: usize, width: usize) {
+    if let Some((mark, quoted)) = prefix_form(tree) {
+        out.push(mark);
+        write_tree(out, quoted, column + 1, width);
+        return;
+    }
+    let line = flat(tree);
+    let TokenTree::Tree(children, _) = tree else {
+        out.push_str(&line);
+        return;
+    };
+    if column + line.chars().count() <= width {
+        out.push_str(&line);
+        return;
+    }
+    out.push('(');
+    let mut children = children.iter();
+    if let Some(head) = children.next() {
+        write_tree(out, head, column + 1, width);
+    }
+    let indent = column + 2;
+    for child in children {
+        out.push('\n');
+        out.extend(std::iter::repeat_n(' ', indent));
+        write_tree(out, child, indent, width);
+    }
+    out.push(')');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{OwnedTokenTree, Span};
+    use crate::parser::source;
+
+    fn first_form(input: &str) -> TokenTree<'_> {
+        let Ok(TokenTree::Tree(mut forms, _)) = source(input) else {
+            panic!("failed to parse {input:?}");
+        };
+        forms.remove(0)
+    }
+
+    #[test]
+    fn test_flat() {
+        let tree = first_form(r#"(print   "hi\n"  (+ 1   2.5))"#);
+        assert_eq!(flat(&tree), r#"(print "hi\n" (+ 1 2.5))"#);
+    }
+
+    #[test]
+    fn test_pretty_fits() {
+        let tree = first_form("(+ 1 2)");
+        assert_eq!(pretty(&tree, 80), "(+ 1 2)");
+    }
+
+    #[test]
+    fn test_pretty_wraps() {
+        let tree = first_form("(define square (fn (x) (* x x)))");
+        assert_eq!(pretty(&tree, 20), "(define\n  square\n  (fn (x) (* x x)))");
+        assert_eq!(
+            pretty(&tree, 12),
+            "(define\n  square\n  (fn\n    (x)\n    (* x x)))"
+        );
+    }
+
+    /// 範囲を取り除いた木
+    fn shape(tree: &TokenTree) -> OwnedTokenTree {
+        match tree {
+            TokenTree::Token(token, _) => OwnedTokenTree::Token(token.to_owned(), Span::default()),
+            TokenTree::Tree(children, _) => {
+                OwnedTokenTree::Tree(children.iter().map(shape).collect(), Span::default())
+            }
+        }
+    }
+
+    /// xorshift64 による乱数
+    fn next(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// 乱数で選んだ要素と、余分な空白やコメントを含むソースコードを作る
+    fn random_source(state: &mut u64, depth: u32, out: &mut String) {
+        const ATOMS: &[&str] = &[
+            "x",
+            "null?",
+            #[cfg(not(feature = "ascii-ident"))]
+            "変数",
+            "+",
+            "-",
+            "<=",
+            "0",
+            "-12",
+            "+7",
+            "9223372036854775807",
+            "-9223372036854775808",
+            "0.5",
+            "-2.25",
+            "1e20",
+            "6.02e-23",
+            ".5",
+            "5.",
+            "true",
+            "false",
+            "nil",
+            r#""""#,
+            r#""a b""#,
+            r#""\"\\\n\t""#,
+            r#""あ""#,
+        ];
+        const TRIVIA: &[&str] = &[" ", "\t", "\r\n", " /* c */ ", " // c\n"];
+        out.push_str(TRIVIA[next(state) as usize % TRIVIA.len()]);
+        match next(state) % 8 {
+            0 => out.push('\''),
+            1 => out.push('`'),
+            2 => out.push(','),
+            _ => {}
+        }
+        if depth > 0 && next(state).is_multiple_of(3) {
+            out.push('(');
+            for _ in 0..next(state) % 5 {
+                random_source(state, depth - 1, out);
+            }
+            out.push(')');
+        } else {
+            out.push_str(ATOMS[next(state) as usize % ATOMS.len()]);
+        }
+    }
+
+    #[test]
+    fn test_to_source() {
+        let tree = source("(a  'b) /* c */ \"d\\\"\" ").unwrap();
+        assert_eq!(to_source(&tree), r#"(a 'b) "d\"""#);
+        let tree = source("(quote `(a ,b))").unwrap();
+        assert_eq!(to_source(&tree), "(quote `(a ,b))");
+        assert_eq!(to_source(&source("").unwrap()), "");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut state = 0x2545f4914f6cdd1d;
+        for _ in 0..2000 {
+            let mut input = String::new();
+            for _ in 0..next(&mut state) % 4 {
+                random_source(&mut state, 4, &mut input);
+            }
+            let tree = source(&input).unwrap_or_else(|e| panic!("{input:?}: {e}"));
+            let printed = to_source(&tree);
+            let reparsed = source(&printed).unwrap_or_else(|e| panic!("{printed:?}: {e}"));
+            assert_eq!(
+                shape(&reparsed),
+                shape(&tree),
+                "{input:?} printed as {printed:?}"
+            );
+            assert_eq!(to_source(&reparsed), printed);
+        }
+    }
+}
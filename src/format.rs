@@ -0,0 +1,271 @@
+//! ソースファイルを決まった形に書き直すフォーマッター
+//!
+//! 最上位の式を1行に1つずつ、[`pretty`](crate::fmt::pretty) と同じ規則で幅に収まるよう整形して並べる。
+//! 式の間に空行があれば1行の空行にまとめて残す。
+//!
+//! 入力は [`Cst`] として読むので、コメントも消さずに書き戻す。
+//! 式の中のコメントは、前の要素と同じ行にあればその行の末尾に、そうでなければ1行に1つずつ置く。
+//! 行コメントか複数行のブロックコメントを含む式は、幅に収まっても1行にはまとめない。
+
+use crate::cst::{Cst, CstNode, Trivia};
+use crate::diagnostics::Diagnostic;
+use crate::fmt::token_text;
+use crate::lexer::TriviaKind;
+use crate::parser::source_recovering;
+
+/// 整形結果の1行に並べる要素
+enum Item<'a, 'src> {
+    /// 最上位の式
+    Form(&'a CstNode<'src>),
+    /// 最上位の式の間にあるコメント
+    Comment(&'a Trivia<'src>),
+}
+
+/// ソースコードを整形する関数
+///
+/// # 引数
+/// * `input` - 整形するソースコード
+/// * `width` - 1行の目安となる最大の文字数
+///
+/// # 戻り値
+/// * `Result<String, Vec<Diagnostic>>` - 整形したソースコード。空でなければ改行で終わる
+///   - 解析エラーがある場合は、その報告を返す
+pub fn format_source(input: &str, width: usize) -> Result<String, Vec<Diagnostic>> {
+    let (_, diagnostics) = source_recovering(input);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    let cst = Cst::parse(input).map_err(|e| vec![Diagnostic::from(&e)])?;
+
+    let mut items = vec![];
+    for node in &cst.nodes {
+        let leading = &node.first_token().leading;
+        items.extend(comments(leading).map(|(_, comment)| (comment.span, Item::Comment(comment))));
+        items.push((node.span(), Item::Form(node)));
+    }
+    items
+        .extend(comments(&cst.trailing).map(|(_, comment)| (comment.span, Item::Comment(comment))));
+
+    let mut out = String::new();
+    let mut prev_end = None;
+    for (span, item) in items {
+        if let Some(end) = prev_end {
+            let newlines = input
+                .get(end..span.start)
+                .map_or(0, |gap| gap.matches('\n').count());
+            match (newlines, &item) {
+                // 式と同じ行に続くコメントは、その行の末尾に残す
+                (0, Item::Comment(_)) => out.push(' '),
+                (0 | 1, _) => out.push('\n'),
+                _ => out.push_str("\n\n"),
+            }
+        }
+        match item {
+            Item::Form(node) => write_node(&mut out, node, width),
+            Item::Comment(comment) => out.push_str(comment.text.trim_end()),
+        }
+        prev_end = Some(span.end);
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// 空白を除いたコメントの区切りを、前の要素との間に改行があるかどうかと組にして返す
+fn comments<'a, 'src>(
+    trivia: &'a [Trivia<'src>],
+) -> impl Iterator<Item = (bool, &'a Trivia<'src>)> {
+    trivia.iter().enumerate().filter_map(|(i, t)| {
+        let own_line = trivia[..i].iter().any(|t| t.kind == TriviaKind::Newline);
+        matches!(t.kind, TriviaKind::LineComment | TriviaKind::BlockComment)
+            .then_some((own_line, t))
+    })
+}
+
+/// 後ろに別の要素を同じ行に続けられるコメントかどうか
+fn is_inline(comment: &Trivia) -> bool {
+    comment.kind == TriviaKind::BlockComment && !comment.text.contains('\n')
+}
+
+/// 節の前のコメントだけを、要素との間に空白を1つ入れて並べた文字列
+///
+/// 後ろに要素を続けられないコメントがあれば `None` を返す。
+fn inline_comments(trivia: &[Trivia]) -> Option<Vec<String>> {
+    comments(trivia)
+        .map(|(_, comment)| is_inline(comment).then(|| comment.text.to_string()))
+        .collect()
+}
+
+/// 節を改行せずに1行で書いた文字列。1行に書けないコメントがあれば `None`
+///
+/// 節自身の前にあるコメントは含めない。
+fn flat(node: &CstNode) -> Option<String> {
+    match node {
+        CstNode::Atom(token) => Some(token_text(&token.token)),
+        CstNode::Prefixed { mark, node } => {
+            let mut out = mark.text.to_string();
+            for comment in inline_comments(&node.first_token().leading)? {
+                out.push_str(&comment);
+                out.push(' ');
+            }
+            out.push_str(&flat(node)?);
+            Some(out)
+        }
+        CstNode::List {
+            children, close, ..
+        } => {
+            let mut parts = vec![];
+            for child in children {
+                let mut part = inline_comments(&child.first_token().leading)?;
+                part.push(flat(child)?);
+                parts.push(part.join(" "));
+            }
+            parts.extend(inline_comments(&close.leading)?);
+            Some(format!("({})", parts.join(" ")))
+        }
+    }
+}
+
+/// 書き出し中の最後の行の文字数
+fn column(out: &str) -> usize {
+    out.rsplit('\n').next().unwrap_or_default().chars().count()
+}
+
+/// 改行して `indent` 文字分の空白を書く
+fn newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    out.extend(std::iter::repeat_n(' ', indent));
+}
+
+/// 要素の前のコメントを書き、続く要素を書く位置に移る
+///
+/// 前の要素と同じ行にあったコメントはその行の末尾に、そうでないものは `comment_indent` の桁から
+/// 1行ずつ書く。`break_before` が `true` か、要素を同じ行に続けられないコメントを書いたら、
+/// 改行して `item_indent` の桁に移る。
+/// 改行せずにコメントを書いたときは `true` を返すので、要素との間に空白を入れる。
+fn write_leading(
+    out: &mut String,
+    trivia: &[Trivia],
+    comment_indent: usize,
+    item_indent: usize,
+    mut break_before: bool,
+) -> bool {
+    let mut wrote = false;
+    for (own_line, comment) in comments(trivia) {
+        if own_line {
+            newline(out, comment_indent);
+        } else if !out.ends_with(['(', ' ', '\'', '`', ',']) {
+            out.push(' ');
+        }
+        out.push_str(comment.text.trim_end());
+        break_before |= own_line || !is_inline(comment);
+        wrote = true;
+    }
+    if break_before {
+        newline(out, item_indent);
+    }
+    wrote && !break_before
+}
+
+/// 今の桁から節を書き出す。節自身の前にあるコメントは書かない
+fn write_node(out: &mut String, node: &CstNode, width: usize) {
+    let column = column(out);
+    match node {
+        CstNode::Atom(token) => out.push_str(&token_text(&token.token)),
+        CstNode::Prefixed { mark, node } => {
+            out.push_str(mark.text);
+            let leading = &node.first_token().leading;
+            if write_leading(out, leading, column + 1, column + 1, false) {
+                out.push(' ');
+            }
+            write_node(out, node, width);
+        }
+        CstNode::List {
+            children, close, ..
+        } => {
+            if let Some(line) = flat(node) {
+                if column + line.chars().count() <= width {
+                    out.push_str(&line);
+                    return;
+                }
+            }
+            out.push('(');
+            let indent = column + 2;
+            for (i, child) in children.iter().enumerate() {
+                // 先頭の要素は括弧と同じ行に置き、残りは1つずつ改行してインデントする
+                let (comment_indent, item_indent) = if i == 0 {
+                    (column + 1, column + 1)
+                } else {
+                    (indent, indent)
+                };
+                let leading = &child.first_token().leading;
+                if write_leading(out, leading, comment_indent, item_indent, i > 0) {
+                    out.push(' ');
+                }
+                write_node(out, child, width);
+            }
+            write_leading(out, &close.leading, indent, column, false);
+            out.push(')');
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::Span;
+
+    #[test]
+    fn test_format_source() {
+        let input = "// header\n(define   x 1)  // one\n\n\n\n( print\n  'x )\n/* end */";
+        assert_eq!(
+            format_source(input, 80).unwrap(),
+            "// header\n(define x 1) // one\n\n(print 'x)\n/* end */\n"
+        );
+        assert_eq!(format_source(" \n", 80).unwrap(), "");
+    }
+
+    #[test]
+    fn test_format_wraps() {
+        let input = "(define square (fn (x) (* x x))) (square 3)";
+        let formatted = format_source(input, 20).unwrap();
+        assert_eq!(
+            formatted,
+            "(define\n  square\n  (fn (x) (* x x)))\n(square 3)\n"
+        );
+        assert_eq!(format_source(&formatted, 20).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_inner_comments() {
+        let cases = [
+            // 前の要素と同じ行のコメントはその行の末尾に残し、式を1行にまとめない
+            ("(a // keep me\n b)", "(a // keep me\n  b)\n"),
+            ("(a\n  // own line\n  b)", "(a\n  // own line\n  b)\n"),
+            ("(a b // end\n)", "(a\n  b // end\n)\n"),
+            ("(// head\n a b)", "(// head\n a\n  b)\n"),
+            // 1行に書けるブロックコメントは、式を1行にまとめても残す
+            ("(a   /* x */ b)", "(a /* x */ b)\n"),
+            ("(a b /* x */)", "(a b /* x */)\n"),
+            ("' /* q */ (a)", "'/* q */ (a)\n"),
+            ("(a /* x\n y */ b)", "(a /* x\n y */\n  b)\n"),
+            (
+                "(define f (fn (x)\n  // double\n  (* x 2)))",
+                "(define\n  f\n  (fn\n    (x)\n    // double\n    (* x 2)))\n",
+            ),
+        ];
+        for (input, expected) in cases {
+            let formatted = format_source(input, 80).unwrap();
+            assert_eq!(formatted, expected, "{input:?}");
+            assert_eq!(format_source(&formatted, 80).unwrap(), formatted);
+        }
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let errors = format_source("(a (b)", 80).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, Span::new(0, 1));
+    }
+}
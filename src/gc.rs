@@ -0,0 +1,330 @@
+//! 参照の循環で解放されなくなった値を回収する、循環参照のコレクター
+//!
+//! 値は [`Rc`] で共有するので、配列やマップが自身を要素に持ったり、クロージャが自身を束縛した
+//! スコープを捕捉したりすると、参照カウントが0にならずに残る。そこで、循環を作れる入れ物
+//! (配列、マップ、スコープ、関数) を作るたびに [`track`] で記録しておき、[`collect`] で次の手順で回収する。
+//!
+//! 1. 記録した入れ物ごとに、他の記録した入れ物からの参照の数を参照カウントから引く
+//! 2. 残りが正の入れ物は、[`Environment`](crate::Environment) のハンドル、仮想機械のスタック、
+//!    評価中の Rust の変数など、入れ物の外から参照されているので根とする
+//! 3. 根からたどれない入れ物は循環の中だけで参照されているので、中身を捨てて循環を切る
+//!
+//! 根を明示的に登録しなくても、評価の途中のどの時点で回収しても使用中の値は捨てない。
+//! 入れ物は記録した数が前回の回収で残った数の2倍を超えると、次に作るときに自動で回収する。
+//!
+//! ```
+//! use ruscal_b::{gc, parse_expr, eval_expr, Environment};
+//!
+//! let mut env = Environment::new();
+//! // 自身を要素に持つ配列は、変数から外れても参照カウントでは解放されない
+//! eval_expr(&parse_expr("{ var a = [1]; a[0] = a }").unwrap(), &mut env).ok();
+//! assert!(gc::collect() >= 1);
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::eval::{Function, Value};
+
+/// 自動で回収するまでに記録できる入れ物の数の最小値
+const MIN_THRESHOLD: usize = 1024;
+
+/// 循環を作れる入れ物
+pub(crate) trait Trace {
+    /// 中身の値が指す入れ物を `visit` に渡す。中身を借用できなければ `false` を返す
+    ///
+    /// `visit` の2つ目の引数は、その参照を他の入れ物から共有されずに持っているかどうか。
+    fn trace(&self, visit: &mut dyn FnMut(usize, bool)) -> bool;
+
+    /// 中身を捨てる
+    fn clear(&self);
+}
+
+/// 要素の並びを `copy` で作った写しと共有していれば、要素への参照は共有されたものとして渡す
+impl Trace for RefCell<Rc<Vec<Value>>> {
+    fn trace(&self, visit: &mut dyn FnMut(usize, bool)) -> bool {
+        let Ok(items) = self.try_borrow() else {
+            return false;
+        };
+        let owned = Rc::strong_count(&items) == 1;
+        items
+            .iter()
+            .for_each(|item| trace_value(item, owned, visit));
+        true
+    }
+
+    fn clear(&self) {
+        if let Ok(mut items) = self.try_borrow_mut() {
+            let items = std::mem::take(&mut *items);
+            drop(items);
+        }
+    }
+}
+
+impl Trace for RefCell<Rc<HashMap<Value, Value>>> {
+    fn trace(&self, visit: &mut dyn FnMut(usize, bool)) -> bool {
+        let Ok(entries) = self.try_borrow() else {
+            return false;
+        };
+        let owned = Rc::strong_count(&entries) == 1;
+        // キーは数値、文字列、真偽値、`nil` に限るので、値だけをたどる
+        entries
+            .values()
+            .for_each(|value| trace_value(value, owned, visit));
+        true
+    }
+
+    fn clear(&self) {
+        if let Ok(mut entries) = self.try_borrow_mut() {
+            let entries = std::mem::take(&mut *entries);
+            drop(entries);
+        }
+    }
+}
+
+/// 関数は捕捉したスコープを指す
+///
+/// 関数自身は書き換えられないので、関数を通る循環は必ずスコープを通る。中身を捨てなくても、
+/// そのスコープの中身を捨てれば循環は切れる。
+impl Trace for Function {
+    fn trace(&self, visit: &mut dyn FnMut(usize, bool)) -> bool {
+        visit(self.env.scope_address(), true);
+        true
+    }
+
+    fn clear(&self) {}
+}
+
+/// 値が指す入れ物を `visit` に渡す
+///
+/// リストとモジュールは記録しないので、その先の入れ物まで続けてたどる。リストが他からも
+/// 参照されていれば、その先の入れ物への参照は共有されたものとして渡す。
+/// `owned` が `false` なら、値そのものを共有されたところから指している。
+pub(crate) fn trace_value(value: &Value, owned: bool, visit: &mut dyn FnMut(usize, bool)) {
+    let mut stack = vec![(value, owned)];
+    while let Some((value, owned)) = stack.pop() {
+        match value {
+            Value::Array(items) => visit(address(items), owned),
+            Value::Map(entries) => visit(address(entries), owned),
+            Value::List(items) => {
+                let owned = owned && Rc::strong_count(items) == 1;
+                stack.extend(items.iter().map(|item| (item, owned)));
+            }
+            Value::Fn(function) => visit(address(function), owned),
+            Value::Module(module) => visit(module.env.scope_address(), false),
+            _ => {}
+        }
+    }
+}
+
+/// 入れ物の中身の位置。記録した入れ物を見分けるのに使う
+pub(crate) fn address<T: ?Sized>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+/// このスレッドで記録した入れ物
+struct Heap {
+    objects: Vec<Weak<dyn Trace>>,
+    /// 記録した数がこれを超えたら回収する
+    threshold: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = const {
+        RefCell::new(Heap {
+            objects: Vec::new(),
+            threshold: MIN_THRESHOLD,
+        })
+    };
+    /// 回収している途中なら `true`。入れ物の中身を捨てている間に回収し直さない
+    static COLLECTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// 循環を作れる入れ物を記録して返す関数
+///
+/// 記録した数が閾値を超えていれば、記録する前に [`collect`] で回収する。
+pub(crate) fn track<T: Trace + 'static>(object: Rc<T>) -> Rc<T> {
+    let due = HEAP
+        .try_with(|heap| {
+            let heap = heap.borrow();
+            heap.objects.len() >= heap.threshold
+        })
+        .unwrap_or(false);
+    if due {
+        collect();
+    }
+    let weak: Weak<dyn Trace> = Rc::downgrade(&object) as Weak<dyn Trace>;
+    // スレッドの終了中で記録できなければ、回収の対象にしないだけにする
+    let _ = HEAP.try_with(|heap| heap.borrow_mut().objects.push(weak));
+    object
+}
+
+/// このスレッドで記録している、まだ解放されていない入れ物の数
+pub fn tracked() -> usize {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.objects.len()
+    })
+}
+
+/// 循環の中だけで参照されている入れ物を回収する関数
+///
+/// # 戻り値
+/// * `usize` - 中身を捨てた入れ物の数
+pub fn collect() -> usize {
+    if COLLECTING.with(|collecting| collecting.replace(true)) {
+        return 0;
+    }
+    let objects: Vec<Rc<dyn Trace>> = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.objects.iter().filter_map(Weak::upgrade).collect()
+    });
+    let index: HashMap<usize, usize> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| (address(object), i))
+        .collect();
+
+    // 他の入れ物からの参照を除いた参照の数を求める。`objects` が持つ1つも除く
+    let mut external: Vec<usize> = objects.iter().map(|o| Rc::strong_count(o) - 1).collect();
+    let mut edges = vec![vec![]; objects.len()];
+    let mut pinned = vec![false; objects.len()];
+    for (i, object) in objects.iter().enumerate() {
+        pinned[i] = !object.trace(&mut |target, owned| {
+            if let Some(&j) = index.get(&target) {
+                edges[i].push(j);
+                if owned {
+                    external[j] = external[j].saturating_sub(1);
+                }
+            }
+        });
+    }
+
+    // 外から参照されている入れ物と、中身を借用中の入れ物からたどれるものに印を付ける
+    let mut marked = vec![false; objects.len()];
+    let mut stack: Vec<usize> = (0..objects.len())
+        .filter(|&i| external[i] > 0 || pinned[i])
+        .collect();
+    while let Some(i) = stack.pop() {
+        if std::mem::replace(&mut marked[i], true) {
+            continue;
+        }
+        stack.extend(edges[i].iter().copied().filter(|&j| !marked[j]));
+    }
+
+    let mut freed = 0;
+    for (object, marked) in objects.iter().zip(&marked) {
+        if !marked {
+            object.clear();
+            freed += 1;
+        }
+    }
+    drop(objects);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.threshold = (heap.objects.len() * 2).max(MIN_THRESHOLD);
+    });
+    COLLECTING.with(|collecting| collecting.set(false));
+    freed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::Environment;
+    use crate::eval::eval_statements;
+    use crate::infix::statements;
+
+    fn run(env: &mut Environment, input: &str) -> Value {
+        eval_statements(&statements(input).unwrap(), env)
+            .unwrap()
+            .unwrap_or(Value::Nil)
+    }
+
+    #[test]
+    fn test_collect_array_cycle() {
+        collect();
+        let mut env = Environment::new();
+        let before = tracked();
+        let array = run(&mut env, "var a = [1]; a[0] = a; a");
+        // 変数と Rust の値から参照されている間は回収しない
+        assert_eq!(collect(), 0);
+        drop(array);
+        run(&mut env, "a = nil");
+        assert!(tracked() > before);
+        assert_eq!(collect(), 1);
+        assert_eq!(tracked(), before);
+    }
+
+    #[test]
+    fn test_collect_closure_cycle() {
+        collect();
+        let mut env = Environment::new();
+        // 関数の中で定義した再帰関数は、自身を束縛したスコープを捕捉して循環する
+        let make =
+            "fn make() { var count = fn(n) { if n == 0 { 0 } else { count(n - 1) } }; count }";
+        run(&mut env, make);
+        let count = run(&mut env, "make()");
+        assert!(matches!(count, Value::Fn(_)));
+        let weak = match &count {
+            Value::Fn(function) => Rc::downgrade(function),
+            _ => unreachable!(),
+        };
+        collect();
+        // 捕捉したスコープが回収されていないので、関数はまだ呼び出せる
+        env.define("count", count);
+        assert_eq!(run(&mut env, "count(3)"), Value::I64(0));
+        run(&mut env, "count = nil");
+        assert!(weak.upgrade().is_some());
+        assert!(collect() >= 1);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_collect_shared_closure() {
+        collect();
+        let mut env = Environment::new();
+        let before = tracked();
+        // 自身を呼ぶクロージャを、別の変数や配列からも参照する
+        run(
+            &mut env,
+            "fn mk() { var f = fn(x) { f(x) }; var g = f; var a = [f]; 1 }",
+        );
+        for _ in 0..100 {
+            run(&mut env, "mk()");
+        }
+        assert!(collect() >= 100);
+        // 残るのは `mk` とそれを定義したスコープだけ
+        assert!(tracked() <= before + 2, "{} > {before} + 2", tracked());
+    }
+
+    #[test]
+    fn test_collect_keeps_reachable() {
+        collect();
+        let mut env = Environment::new();
+        run(
+            &mut env,
+            "var inner = [2]; var m = {1: inner}; inner[0] = [m]",
+        );
+        assert_eq!(collect(), 0);
+        assert_eq!(run(&mut env, "inner[0][0] == m"), Value::Bool(true));
+        run(&mut env, "inner = nil; m = nil");
+        assert_eq!(collect(), 3);
+    }
+
+    #[test]
+    fn test_collect_keeps_shared_storage() {
+        collect();
+        let mut env = Environment::new();
+        crate::stdlib::register(&mut env);
+        run(&mut env, "var a = [1]; a[0] = a; var b = copy(a); a = nil");
+        // 写しと共有している要素の並びは、元の配列が循環の中だけにあっても捨てない
+        collect();
+        assert_eq!(run(&mut env, "len(b[0])"), Value::I64(1));
+        assert_eq!(run(&mut env, "b[0][0] == b[0]"), Value::Bool(true));
+    }
+}
@@ -0,0 +1,109 @@
+//! 端末に表示するS式のソースコードに、トークンの種類ごとの色を付ける
+//!
+//! 字句解析したトークンの種類で色を決め、トークンの間の空白とコメントはそのまま残す。
+//! 色を取り除けば元の入力と同じ文字列になる。
+//!
+//! ```
+//! use ruscal_b::highlight::highlight;
+//!
+//! assert_eq!(highlight("(f 1)"), "(f \x1b[36m1\x1b[0m)");
+//! ```
+
+use crate::ast::{Keyword, Token};
+use crate::lexer::Lexer;
+
+/// 色を戻すエスケープシーケンス
+const RESET: &str = "\x1b[0m";
+
+/// トークンに付ける色のエスケープシーケンス。色を付けなければ `None`
+fn color(token: &Token) -> Option<&'static str> {
+    match token {
+        Token::Int(_) | Token::Float(_) | Token::Bool(_) | Token::Nil => Some("\x1b[36m"),
+        Token::StrLiteral(_) => Some("\x1b[32m"),
+        Token::Keyword(_) => Some("\x1b[35m"),
+        Token::Ident(name) if is_keyword(name) => Some("\x1b[35m"),
+        _ => None,
+    }
+}
+
+/// S式で特殊形式の頭に書く名前かどうか
+fn is_keyword(name: &str) -> bool {
+    Keyword::from_name(name).is_some()
+        || matches!(name, "define" | "quote" | "quasiquote" | "unquote")
+}
+
+/// S式のソースコードに色を付ける関数
+///
+/// 数値と真偽値と `nil` は水色、文字列は緑、特殊形式の名前は紫、コメントは灰色にする。
+/// 字句解析に失敗した位置から後ろには色を付けない。
+///
+/// # 引数
+/// * `input` - 色を付ける入力
+///
+/// # 戻り値
+/// * `String` - ANSI のエスケープシーケンスを加えた文字列
+pub fn highlight(input: &str) -> String {
+    let mut out = String::with_capacity(input.len() * 2);
+    let mut end = 0;
+    for res in Lexer::new(input) {
+        let Ok((span, token)) = res else {
+            out.push_str(input.get(end..).unwrap_or_default());
+            return out;
+        };
+        push_trivia(&mut out, input.get(end..span.start).unwrap_or_default());
+        let text = input.get(span.start..span.end).unwrap_or_default();
+        match color(&token) {
+            Some(color) => out.push_str(&format!("{color}{text}{RESET}")),
+            None => out.push_str(text),
+        }
+        end = span.end;
+    }
+    push_trivia(&mut out, input.get(end..).unwrap_or_default());
+    out
+}
+
+/// トークンの間の空白とコメントを書き足す。コメントを含めば灰色にする
+fn push_trivia(out: &mut String, trivia: &str) {
+    if trivia.trim().is_empty() {
+        out.push_str(trivia);
+    } else {
+        out.push_str(&format!("\x1b[90m{trivia}{RESET}"));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 色のエスケープシーケンスを取り除く
+    fn strip(s: &str) -> String {
+        let mut out = String::new();
+        let mut rest = s;
+        while let Some(pos) = rest.find('\x1b') {
+            out.push_str(&rest[..pos]);
+            rest = &rest[pos + rest[pos..].find('m').unwrap() + 1..];
+        }
+        out + rest
+    }
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(
+            highlight("(define s \"a\") // c"),
+            "(\x1b[35mdefine\x1b[0m s \x1b[32m\"a\"\x1b[0m)\x1b[90m // c\x1b[0m"
+        );
+        assert_eq!(highlight("(+ x 1.5)"), "(+ x \x1b[36m1.5\x1b[0m)");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for input in [
+            "",
+            "  (if true nil)\n",
+            "(a /* b */ 'c)",
+            "(f 1 \"unterminated",
+        ] {
+            assert_eq!(strip(&highlight(input)), input, "{input:?}");
+        }
+    }
+}
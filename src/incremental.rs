@@ -0,0 +1,468 @@
+//! 編集した範囲だけを読み直す、具象構文木の差分解析
+//!
+//! エディタで1文字打つたびに入力全体を解析し直さなくて済むように、[`Cst::reparse`] は
+//! 編集を含む最も内側の括弧の中で、編集に触れる要素だけを字句解析し直して古い木につなぎ込む。
+//! 触れていない要素は古い木から範囲をずらして使う。
+//!
+//! 編集が括弧の対応を変えるなど、局所的に読み直せない場合は入力全体を解析し直す。
+//! どちらの場合も、結果は新しい入力を [`Cst::parse`] で解析したものと同じになる。
+//!
+//! ```
+//! use ruscal_b::cst::Cst;
+//! use ruscal_b::incremental::Edit;
+//! use ruscal_b::Span;
+//!
+//! let input = "(define x 1)\n(print x)\n";
+//! let cst = Cst::parse(input).unwrap();
+//! let edit = Edit::new(Span::new(10, 11), "42");
+//! let new_input = edit.apply(input);
+//! assert_eq!(new_input, "(define x 42)\n(print x)\n");
+//! let new_cst = cst.reparse(&edit, &new_input).unwrap();
+//! assert_eq!(new_cst, Cst::parse(&new_input).unwrap());
+//! ```
+
+use crate::ast::{Span, Token};
+use crate::cst::{Cst, CstNode, CstToken, Trivia};
+use crate::lexer::TriviaKind;
+use crate::parser::{ParseError, MAX_DEPTH};
+
+/// 入力の一部を置き換える編集
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    /// 置き換える、編集前の入力での範囲
+    pub range: Span,
+    /// 範囲に入れる文字列
+    pub text: String,
+}
+
+impl Edit {
+    /// 編集を作る
+    ///
+    /// # 引数
+    /// * `range` - 置き換える範囲。空の範囲なら挿入になる
+    /// * `text` - 範囲に入れる文字列。空文字列なら削除になる
+    pub fn new(range: Span, text: impl Into<String>) -> Self {
+        Self {
+            range,
+            text: text.into(),
+        }
+    }
+
+    /// 編集前の入力に編集を適用した文字列を返す
+    ///
+    /// 範囲が入力の外や文字の途中を指す場合は、入力をそのまま返す。
+    pub fn apply(&self, input: &str) -> String {
+        let (before, after) = match (input.get(..self.range.start), input.get(self.range.end..)) {
+            (Some(before), Some(after)) if self.range.start <= self.range.end => (before, after),
+            _ => return input.to_string(),
+        };
+        let mut output = String::with_capacity(input.len() + self.text.len());
+        output.push_str(before);
+        output.push_str(&self.text);
+        output.push_str(after);
+        output
+    }
+
+    /// 編集前の位置を編集後の位置に移す。位置は置き換える範囲の外にあるものとする
+    fn shift(&self, pos: usize) -> usize {
+        if pos >= self.range.end {
+            pos - self.range.end + self.range.start + self.text.len()
+        } else {
+            pos
+        }
+    }
+}
+
+impl Cst<'_> {
+    /// 編集後の入力を、この木を元に解析し直す関数
+    ///
+    /// # 引数
+    /// * `edit` - この木の入力に加えた編集
+    /// * `input` - 編集後の入力。`edit.apply()` の結果と同じでなければならない
+    ///
+    /// # 戻り値
+    /// * `Result<Cst, ParseError>` - `Cst::parse(input)` と同じ結果
+    pub fn reparse<'new>(&self, edit: &Edit, input: &'new str) -> Result<Cst<'new>, ParseError> {
+        match splice(self, edit, input) {
+            Some(cst) => Ok(cst),
+            None => Cst::parse(input),
+        }
+    }
+}
+
+/// 節の並びと、その並びを囲む範囲
+struct Container<'a, 'src> {
+    children: &'a [CstNode<'src>],
+    /// 最初の要素より前の位置。左括弧の終わりか入力の先頭
+    start: usize,
+    /// 最後の要素より後の位置。右括弧の始まりか入力の終わり
+    end: usize,
+}
+
+/// 編集を含む最も内側の括弧の中だけを読み直し、古い木につなぎ込む
+///
+/// 局所的に読み直せなければ `None` を返す。
+pub(crate) fn splice<'new>(old: &Cst<'_>, edit: &Edit, input: &'new str) -> Option<Cst<'new>> {
+    let (a, b) = (edit.range.start, edit.range.end);
+    let old_len = old.nodes.last().map_or(0, |node| node.span().end)
+        + old.trailing.iter().map(|t| t.text.len()).sum::<usize>();
+    if a > b || b > old_len || input.len() != old_len - (b - a) + edit.text.len() {
+        return None;
+    }
+
+    // 編集を内側に含む括弧をたどる
+    let mut path = vec![];
+    let mut container = Container {
+        children: &old.nodes,
+        start: 0,
+        end: old_len,
+    };
+    'descend: loop {
+        for (index, child) in container.children.iter().enumerate() {
+            if let CstNode::List {
+                open,
+                children,
+                close,
+            } = unprefixed(child)
+            {
+                if open.span.end <= a && b <= close.span.start {
+                    path.push(index);
+                    container = Container {
+                        children,
+                        start: open.span.end,
+                        end: close.span.start,
+                    };
+                    continue 'descend;
+                }
+            }
+        }
+        break;
+    }
+
+    // 編集に触れる要素 `first..last` と、読み直す範囲 `start..end` を決める
+    let children = container.children;
+    let first = children.partition_point(|child| child.span().end < a);
+    let last = children.partition_point(|child| child.span().start <= b);
+    let start = match first {
+        0 => container.start,
+        _ => children[first - 1].span().end,
+    };
+    let end = children
+        .get(last)
+        .map_or(container.end, |child| child.span().start);
+    let new_end = edit.shift(end);
+
+    let region = Cst::parse_with_limit(input.get(start..new_end)?, MAX_DEPTH - path.len()).ok()?;
+    if new_end < input.len() && !ends_at_boundary(&region, children.get(last)) {
+        return None;
+    }
+
+    let shift_region = |pos: usize| pos + start;
+    let mut splice = Splice {
+        input,
+        shift: &|pos| edit.shift(pos),
+        first,
+        last,
+        nodes: region
+            .nodes
+            .iter()
+            .map(|node| rebase(node, input, &shift_region))
+            .collect(),
+        trailing: Some(trivia(&region.trailing, input, &shift_region)),
+    };
+    let nodes = splice.path(&old.nodes, &path);
+    let trailing = match splice.trailing.take() {
+        Some(trailing) if path.is_empty() => trailing,
+        _ => trivia(&old.trailing, input, splice.shift),
+    };
+    Some(Cst { nodes, trailing })
+}
+
+/// 読み直した要素を古い木につなぎ込むための状態
+struct Splice<'a, 'new> {
+    input: &'new str,
+    /// 古い木の位置を新しい入力の位置に移す関数
+    shift: &'a dyn Fn(usize) -> usize,
+    /// 最も内側の括弧の中で置き換える要素の範囲 `first..last`
+    first: usize,
+    last: usize,
+    /// 読み直した要素
+    nodes: Vec<CstNode<'new>>,
+    /// 読み直した範囲の終わりにある空白とコメント。置き換えた要素の次のトークンが持つ
+    trailing: Option<Vec<Trivia<'new>>>,
+}
+
+impl<'new> Splice<'_, 'new> {
+    /// 経路の順に括弧をたどり、最も内側の括弧の中身を作り直す
+    fn path(&mut self, children: &[CstNode<'_>], path: &[usize]) -> Vec<CstNode<'new>> {
+        let Some((&target, rest)) = path.split_first() else {
+            return self.children(children);
+        };
+        children
+            .iter()
+            .enumerate()
+            .map(|(index, child)| match index == target {
+                true => self.list(child, rest),
+                false => rebase(child, self.input, self.shift),
+            })
+            .collect()
+    }
+
+    /// 経路の上にある節を作り直す。前置記号はそのまま残す
+    fn list(&mut self, node: &CstNode<'_>, path: &[usize]) -> CstNode<'new> {
+        match node {
+            CstNode::Prefixed { mark, node } => CstNode::Prefixed {
+                mark: token(mark, self.input, self.shift),
+                node: Box::new(self.list(node, path)),
+            },
+            CstNode::List {
+                open,
+                children,
+                close,
+            } => {
+                let open = token(open, self.input, self.shift);
+                let children = self.path(children, path);
+                let mut close = token(close, self.input, self.shift);
+                if path.is_empty() {
+                    if let Some(leading) = self.trailing.take() {
+                        close.leading = leading;
+                    }
+                }
+                CstNode::List {
+                    open,
+                    children,
+                    close,
+                }
+            }
+            CstNode::Atom(_) => unreachable!("the path only goes through lists"),
+        }
+    }
+
+    /// 最も内側の括弧の中身を、置き換える要素を読み直した要素に替えて作り直す
+    fn children(&mut self, children: &[CstNode<'_>]) -> Vec<CstNode<'new>> {
+        let mut nodes: Vec<_> = children[..self.first]
+            .iter()
+            .map(|node| rebase(node, self.input, self.shift))
+            .collect();
+        nodes.append(&mut self.nodes);
+        for node in &children[self.last..] {
+            let mut node = rebase(node, self.input, self.shift);
+            if let Some(leading) = self.trailing.take() {
+                first_token_mut(&mut node).leading = leading;
+            }
+            nodes.push(node);
+        }
+        nodes
+    }
+}
+
+/// 読み直した範囲の最後のトークンが、続く入力とつながらずに終わるかどうか
+///
+/// 範囲の後ろの文字は変わっていなくても、範囲の中身が変われば、最後のトークンやコメントが
+/// 後ろの文字まで伸びることがある。空白か括弧で区切られていれば伸びない。
+fn ends_at_boundary(region: &Cst<'_>, next: Option<&CstNode<'_>>) -> bool {
+    match region.trailing.last() {
+        Some(trivia) => matches!(trivia.kind, TriviaKind::Whitespace | TriviaKind::Newline),
+        None => {
+            let ends_with_paren = region
+                .nodes
+                .last()
+                .is_some_and(|node| matches!(unprefixed(node), CstNode::List { .. }));
+            ends_with_paren
+                || next.is_none_or(|node| matches!(node.first_token().token, Token::LParen))
+        }
+    }
+}
+
+/// 前置記号を外した節
+fn unprefixed<'a, 'src>(mut node: &'a CstNode<'src>) -> &'a CstNode<'src> {
+    while let CstNode::Prefixed { node: inner, .. } = node {
+        node = inner;
+    }
+    node
+}
+
+fn first_token_mut<'a, 'src>(node: &'a mut CstNode<'src>) -> &'a mut CstToken<'src> {
+    match node {
+        CstNode::Atom(token)
+        | CstNode::List { open: token, .. }
+        | CstNode::Prefixed { mark: token, .. } => token,
+    }
+}
+
+/// 節の範囲を `shift` で移し、文字列を新しい入力から取り直す
+fn rebase<'new>(
+    node: &CstNode<'_>,
+    input: &'new str,
+    shift: &dyn Fn(usize) -> usize,
+) -> CstNode<'new> {
+    match node {
+        CstNode::Atom(atom) => CstNode::Atom(token(atom, input, shift)),
+        CstNode::List {
+            open,
+            children,
+            close,
+        } => CstNode::List {
+            open: token(open, input, shift),
+            children: children
+                .iter()
+                .map(|child| rebase(child, input, shift))
+                .collect(),
+            close: token(close, input, shift),
+        },
+        CstNode::Prefixed { mark, node } => CstNode::Prefixed {
+            mark: token(mark, input, shift),
+            node: Box::new(rebase(node, input, shift)),
+        },
+    }
+}
+
+/// 範囲を移す。編集の直前で終わる範囲の終わりは、挿入した文字列の後ろに移さない
+fn span(span: Span, shift: &dyn Fn(usize) -> usize) -> Span {
+    Span::new(shift(span.start), shift(span.end - 1) + 1)
+}
+
+fn token<'new>(
+    old: &CstToken<'_>,
+    input: &'new str,
+    shift: &dyn Fn(usize) -> usize,
+) -> CstToken<'new> {
+    let span = self::span(old.span, shift);
+    let text = input.get(span.start..span.end).unwrap_or_default();
+    let token = match old.token {
+        Token::Ident(_) => Token::Ident(text),
+        // 文字列リテラルは引用符の内側を持つ
+        Token::StrLiteral(_) => Token::StrLiteral(
+            text.get(1..text.len().saturating_sub(1))
+                .unwrap_or_default(),
+        ),
+        Token::Int(n) => Token::Int(n),
+        Token::Float(n) => Token::Float(n),
+        Token::Bool(b) => Token::Bool(b),
+        Token::Nil => Token::Nil,
+        Token::LParen => Token::LParen,
+        Token::RParen => Token::RParen,
+        Token::LBrace => Token::LBrace,
+        Token::RBrace => Token::RBrace,
+        Token::LBracket => Token::LBracket,
+        Token::RBracket => Token::RBracket,
+        Token::Semicolon => Token::Semicolon,
+        Token::Keyword(keyword) => Token::Keyword(keyword),
+    };
+    CstToken {
+        leading: trivia(&old.leading, input, shift),
+        token,
+        text,
+        span,
+    }
+}
+
+fn trivia<'new>(
+    old: &[Trivia<'_>],
+    input: &'new str,
+    shift: &dyn Fn(usize) -> usize,
+) -> Vec<Trivia<'new>> {
+    old.iter()
+        .map(|trivia| {
+            let span = span(trivia.span, shift);
+            Trivia {
+                kind: trivia.kind,
+                text: input.get(span.start..span.end).unwrap_or_default(),
+                span,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 編集を適用し、差分解析と全体の解析の結果を比べる
+    fn check(input: &str, range: (usize, usize), text: &str) -> Option<()> {
+        let old = Cst::parse(input).unwrap();
+        let edit = Edit::new(Span::new(range.0, range.1), text);
+        let new_input = edit.apply(input);
+        let expected = Cst::parse(&new_input);
+        assert_eq!(
+            old.reparse(&edit, &new_input),
+            expected,
+            "{input:?} -> {new_input:?}"
+        );
+        let spliced = splice(&old, &edit, &new_input)?;
+        assert_eq!(
+            Ok(&spliced),
+            expected.as_ref(),
+            "{input:?} -> {new_input:?}"
+        );
+        Some(())
+    }
+
+    #[test]
+    fn test_local_edits() {
+        let input = "(define (f x)\n  (+ x \"a b\")) // f\n'(f 1)\n";
+        let edits = [
+            ((11, 12), "y"),      // 仮引数の名前
+            ((17, 17), " "),      // 左括弧の直後
+            ((18, 18), " 1"),     // 内側の括弧への挿入
+            ((21, 26), ""),       // 文字列の削除
+            ((22, 23), "c\\\"d"), // 文字列の中身
+            ((13, 16), "\n\t"),   // 空白
+            ((29, 33), "// g"),   // 行コメント
+            ((34, 34), "(g)\n"),  // 最上位への挿入
+            ((38, 39), "2.5"),    // 前置記号の付いたリスト
+            ((input.len(), input.len()), "x"),
+            ((0, 0), "x "),
+        ];
+        for ((start, end), text) in edits {
+            assert_eq!(
+                check(input, (start, end), text),
+                Some(()),
+                "{start}..{end} {text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_fallback() {
+        let input = "(a (b c) d)\n(e)";
+        // 括弧の対応が変わる編集や、後ろのトークンとつながる編集は全体を解析し直す
+        let edits = [
+            ((4, 4), ")"),
+            ((7, 8), ""),
+            ((11, 11), " /* x"),
+            ((5, 6), "b/"),
+            ((6, 6), "x"),
+            ((0, 15), ""),
+            ((12, 12), "'"),
+        ];
+        for ((start, end), text) in edits {
+            check(input, (start, end), text);
+        }
+        assert_eq!(check(input, (6, 7), "(q)"), Some(()));
+        assert_eq!(check(input, (4, 4), "(("), None);
+    }
+
+    #[test]
+    fn test_invalid_edits() {
+        // 文字の途中や入力の外を指す編集は適用しない
+        assert_eq!(Edit::new(Span::new(1, 2), "x").apply("é"), "é");
+        assert_eq!(Edit::new(Span::new(0, 9), "x").apply("é"), "é");
+        assert_eq!(Edit::new(Span::new(2, 1), "x").apply("abc"), "abc");
+        // 編集と合わない入力を渡しても、パニックせずに解析する
+        let cst = Cst::parse("(\"é\")").unwrap();
+        let _ = cst.reparse(&Edit::new(Span::new(0, 0), ""), "(あ");
+        let _ = cst.reparse(&Edit::new(Span::new(1, 3), "\""), "(\")");
+    }
+
+    #[test]
+    fn test_typing() {
+        // 1文字ずつ打つ途中で全体を解析できるたびに、次の1文字を差分解析する
+        let target = "(define (sq x)\n  (* x x)) // \"s\"\n'(a \"b\")";
+        for (i, c) in target.char_indices() {
+            if Cst::parse(&target[..i]).is_ok() {
+                check(&target[..i], (i, i), &c.to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,1003 @@
+//! 中置記法の式を解析して `Expr` を組み立てる構文解析器
+//!
+//! 演算子の優先順位は優先順位上昇法 (precedence climbing) で扱う。
+//! プログラム全体は `;` か改行で区切られた文の並びとして [`statements`] で解析する。
+
+use std::rc::Rc;
+
+use crate::ast::{
+    BinOp, Expr, ExprKind, Keyword, MatchArm, Pattern, Precedence, Signature, Span, Statement,
+    Token, TypeName, UnOp,
+};
+use crate::intern::Symbol;
+use crate::lexer::{ends_operand, unescape};
+use crate::parser::{Expected, ParseError};
+use crate::token_stream::TokenStream;
+
+/// 中置記法の式を解析する関数
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+///
+/// # 戻り値
+/// * `Result<Expr, ParseError>` - 解析結果の式
+///   - 式の後に余分なトークンが続く場合はエラーを返す
+pub fn parse_expr(input: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser::new(input)?;
+    let expr = parser.expr()?;
+    match parser.tokens.peek() {
+        None => Ok(expr),
+        Some((span, Token::RParen)) => Err(ParseError::UnbalancedParen { span: *span }),
+        Some((span, _)) => Err(parser.tokens.error_at(*span, Expected::EndOfInput)),
+    }
+}
+
+/// 中置記法のプログラムを文の並びとして解析する関数
+///
+/// `if cond { ... } else { ... }` は値を持つ式として、式を書ける場所ならどこにでも書ける。
+/// ループは `while cond { ... }` と `for i in start..end { ... }` で書き、値は `nil` になる。
+/// 文は `;` か、括弧の外で被演算子の直後に現れた改行で区切る。空の文は読み飛ばす。
+/// `[a, b]` は配列を作り、`a[i]` と `a[i] = v` で要素を読み書きする。
+/// `{ key: value }` はマップを作る。`{` の直後に1つのトークンのキーと `:` が続くときだけマップとして読み、
+/// それ以外はブロックとして読む。
+///
+/// ```text
+/// var x = 1
+/// x = x + 2; x * 3
+/// ```
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+///
+/// # 戻り値
+/// * `Result<Vec<Statement>, ParseError>` - 現れた順の文のリスト
+///   - 文の後に区切り以外のトークンが続く場合はエラーを返す
+pub fn statements(input: &str) -> Result<Vec<Statement>, ParseError> {
+    let mut parser = Parser::new(input)?;
+    Ok(parser.block_body(false)?.0)
+}
+
+/// 字句解析を済ませたトークン列を先頭から読み進める構文解析器
+struct Parser<'src> {
+    tokens: TokenStream<'src>,
+}
+
+impl<'src> Parser<'src> {
+    /// 入力全体をトークン列に分割して構文解析器を作る
+    ///
+    /// 被演算子の直後の `+` と `-` は、数字が続いていても二項演算子として読む。
+    fn new(src: &'src str) -> Result<Self, ParseError> {
+        Ok(Self {
+            tokens: TokenStream::infix(src)?,
+        })
+    }
+
+    /// 次のトークンが二項演算子であれば、その演算子を返す
+    fn peek_binop(&self) -> Option<BinOp> {
+        match self.tokens.peek() {
+            Some((_, Token::Ident(symbol))) => BinOp::from_symbol(symbol),
+            _ => None,
+        }
+    }
+
+    /// 区切られた文の並びを解析する
+    ///
+    /// # 引数
+    /// * `in_block` - `{` の内側を解析しているなら `true`。そのときは `}` まで読み進める
+    ///
+    /// # 戻り値
+    /// * `Result<(Vec<Statement>, Option<Span>), ParseError>` - (文のリスト, 読み終えた `}` の範囲)のタプル
+    fn block_body(&mut self, in_block: bool) -> Result<(Vec<Statement>, Option<Span>), ParseError> {
+        let mut statements = vec![];
+        loop {
+            while self.tokens.eat(&Token::Semicolon).is_some() {}
+            match self.tokens.peek() {
+                None if in_block => return Err(self.tokens.error_at_end(Expected::RBrace)),
+                None => return Ok((statements, None)),
+                Some((span, Token::RBrace)) if in_block => {
+                    let span = *span;
+                    self.tokens.bump();
+                    return Ok((statements, Some(span)));
+                }
+                _ => {}
+            }
+            statements.push(self.statement()?);
+            match self.tokens.peek() {
+                None => {}
+                Some((_, Token::Semicolon)) => {
+                    self.tokens.bump();
+                }
+                Some((_, Token::RBrace)) if in_block => {}
+                Some((span, Token::RParen)) => {
+                    return Err(ParseError::UnbalancedParen { span: *span })
+                }
+                Some((span, _)) => return Err(self.tokens.error_at(*span, Expected::Semicolon)),
+            }
+        }
+    }
+
+    /// `{` で始まるブロックを解析する
+    fn block(&mut self) -> Result<Expr, ParseError> {
+        let open = self.tokens.expect(&Token::LBrace, Expected::LBrace)?;
+        let (statements, close) = self.block_body(true)?;
+        let close = close.ok_or_else(|| self.tokens.error_at_end(Expected::RBrace))?;
+        Ok(Expr::new(ExprKind::Block(statements), open.merge(close)))
+    }
+
+    /// `if` キーワードに続く条件と分岐を解析する
+    ///
+    /// 改行の後に `else` が続く場合も同じ `if` の分岐として読む。
+    /// `else if` は `else` の分岐に `if` 式を置いたものとして扱う。
+    fn if_expr(&mut self, start: Span) -> Result<Expr, ParseError> {
+        let cond = self.expr()?;
+        let then_branch = self.block()?;
+        let mut look = 0;
+        while let Some((_, Token::Semicolon)) = self.tokens.peek_nth(look) {
+            look += 1;
+        }
+        let else_branch = match self.tokens.peek_nth(look) {
+            Some((_, Token::Keyword(Keyword::Else))) => {
+                for _ in 0..=look {
+                    self.tokens.bump();
+                }
+                match self.tokens.eat(&Token::Keyword(Keyword::If)) {
+                    Some(span) => Some(Box::new(self.if_expr(span)?)),
+                    None => Some(Box::new(self.block()?)),
+                }
+            }
+            _ => None,
+        };
+        let end = else_branch.as_ref().map_or(then_branch.span, |e| e.span);
+        let kind = ExprKind::If {
+            cond: Box::new(cond),
+            then_branch: Box::new(then_branch),
+            else_branch,
+        };
+        Ok(Expr::new(kind, start.merge(end)))
+    }
+
+    /// `for` キーワードに続く `name in start..end { ... }` を解析する
+    fn for_expr(&mut self, start_span: Span) -> Result<Expr, ParseError> {
+        let var = self.ident()?;
+        self.tokens
+            .expect(&Token::Keyword(Keyword::In), Expected::In)?;
+        let start = self.expr()?;
+        self.tokens.expect(&Token::Ident(".."), Expected::DotDot)?;
+        let end = self.expr()?;
+        let body = self.block()?;
+        let span = start_span.merge(body.span);
+        let kind = ExprKind::For {
+            var,
+            start: Box::new(start),
+            end: Box::new(end),
+            body: Box::new(body),
+        };
+        Ok(Expr::new(kind, span))
+    }
+
+    /// `match` キーワードに続く値と `{ pattern if guard => body, ... }` を解析する
+    ///
+    /// 腕は `,` か改行で区切り、最後の腕の後の区切りは省略できる。
+    fn match_expr(&mut self, start: Span) -> Result<Expr, ParseError> {
+        let scrutinee = self.expr()?;
+        self.tokens.expect(&Token::LBrace, Expected::LBrace)?;
+        let mut arms = vec![];
+        let end = loop {
+            while self.tokens.eat(&Token::Semicolon).is_some() {}
+            match self.tokens.peek() {
+                Some((span, Token::RBrace)) => {
+                    let span = *span;
+                    self.tokens.bump();
+                    break span;
+                }
+                None => return Err(self.tokens.error_at_end(Expected::RBrace)),
+                _ => {}
+            }
+            arms.push(self.match_arm()?);
+            match self.tokens.peek() {
+                Some((_, Token::Ident(","))) | Some((_, Token::Semicolon)) => {
+                    self.tokens.bump();
+                }
+                Some((_, Token::RBrace)) => {}
+                Some((span, _)) => return Err(self.tokens.error_at(*span, Expected::RBrace)),
+                None => return Err(self.tokens.error_at_end(Expected::RBrace)),
+            }
+        };
+        let kind = ExprKind::Match {
+            scrutinee: Box::new(scrutinee),
+            arms,
+        };
+        Ok(Expr::new(kind, start.merge(end)))
+    }
+
+    /// `match` の腕を1つ解析する
+    fn match_arm(&mut self) -> Result<MatchArm, ParseError> {
+        let (pattern, pattern_span) = self.pattern()?;
+        let guard = match self.tokens.eat(&Token::Keyword(Keyword::If)) {
+            Some(_) => Some(self.expr()?),
+            None => None,
+        };
+        self.tokens
+            .expect(&Token::Ident("=>"), Expected::FatArrow)?;
+        let body = self.expr()?;
+        Ok(MatchArm {
+            pattern,
+            pattern_span,
+            guard,
+            body,
+        })
+    }
+
+    /// リテラル、`_`、束縛する名前のいずれかのパターンを解析する
+    fn pattern(&mut self) -> Result<(Pattern, Span), ParseError> {
+        let Some((span, token)) = self.tokens.bump() else {
+            return Err(self.tokens.error_at_end(Expected::Pattern));
+        };
+        let pattern = match token {
+            Token::Int(n) => Pattern::Int(*n),
+            Token::Float(n) => Pattern::Float(*n),
+            Token::StrLiteral(s) => Pattern::Str(unescape(s)),
+            Token::Bool(b) => Pattern::Bool(*b),
+            Token::Nil => Pattern::Nil,
+            Token::Ident("-") => {
+                return match self.tokens.bump() {
+                    Some((end, Token::Int(n))) => Ok((Pattern::Int(-n), span.merge(end))),
+                    Some((end, Token::Float(n))) => Ok((Pattern::Float(-n), span.merge(end))),
+                    Some((end, _)) => Err(self.tokens.error_at(end, Expected::Number)),
+                    None => Err(self.tokens.error_at_end(Expected::Number)),
+                };
+            }
+            Token::Ident("_") => Pattern::Wildcard,
+            Token::Ident(name) if ends_operand(token) => Pattern::Binding(Symbol::intern(name)),
+            _ => return Err(self.tokens.error_at(span, Expected::Pattern)),
+        };
+        Ok((pattern, span))
+    }
+
+    /// `fn` キーワードに続く `(param: type, ...) -> type { ... }` を解析する
+    ///
+    /// 仮引数と戻り値の型注釈はそれぞれ省略できる。
+    fn fn_expr(&mut self, start: Span) -> Result<Expr, ParseError> {
+        self.tokens.expect(&Token::LParen, Expected::LParen)?;
+        let mut params = vec![];
+        let mut signature = Signature::default();
+        if self.tokens.eat(&Token::RParen).is_none() {
+            loop {
+                params.push(self.ident()?);
+                let annotation = match self.tokens.eat(&Token::Ident(":")) {
+                    Some(_) => Some(self.type_name()?),
+                    None => None,
+                };
+                signature.params.push(annotation);
+                match self.tokens.bump() {
+                    Some((_, Token::Ident(","))) => {}
+                    Some((_, Token::RParen)) => break,
+                    Some((span, _)) => return Err(self.tokens.error_at(span, Expected::Comma)),
+                    None => return Err(self.tokens.error_at_end(Expected::Comma)),
+                }
+            }
+        }
+        if self.tokens.eat(&Token::Ident("->")).is_some() {
+            signature.ret = Some(self.type_name()?);
+        }
+        let body = self.block()?;
+        let span = start.merge(body.span);
+        let annotated = signature.ret.is_some() || signature.params.iter().any(Option::is_some);
+        let kind = ExprKind::Fn {
+            params,
+            body: Rc::new([body]),
+            signature: annotated.then_some(signature),
+        };
+        Ok(Expr::new(kind, span))
+    }
+
+    /// 型注釈の型の名前を読む
+    fn type_name(&mut self) -> Result<TypeName, ParseError> {
+        match self.tokens.bump() {
+            Some((_, Token::Nil)) => Ok(TypeName::Nil),
+            Some((span, Token::Ident(name))) => TypeName::from_name(name)
+                .ok_or_else(|| self.tokens.error_at(span, Expected::TypeName)),
+            Some((span, _)) => Err(self.tokens.error_at(span, Expected::TypeName)),
+            None => Err(self.tokens.error_at_end(Expected::TypeName)),
+        }
+    }
+
+    /// 変数の定義、代入、式のいずれかの文を解析する
+    ///
+    /// `fn name(...) { ... }` は関数を値とする変数の定義として扱う。
+    fn statement(&mut self) -> Result<Statement, ParseError> {
+        match (self.tokens.peek(), self.tokens.peek_nth(1)) {
+            (Some((start, Token::Keyword(Keyword::Fn))), Some((_, token @ Token::Ident(_))))
+                if ends_operand(token) =>
+            {
+                let start = *start;
+                self.tokens.bump();
+                let name = self.ident()?;
+                let value = self.fn_expr(start)?;
+                let span = value.span;
+                Ok(Statement::VarDef { name, value, span })
+            }
+            (Some((start, Token::Keyword(Keyword::Var))), _) => {
+                let start = *start;
+                self.tokens.bump();
+                let name = self.ident()?;
+                self.tokens.expect(&Token::Ident("="), Expected::Equals)?;
+                let value = self.expr()?;
+                let span = start.merge(value.span);
+                Ok(Statement::VarDef { name, value, span })
+            }
+            (Some((start, token @ Token::Ident(name))), Some((_, Token::Ident("="))))
+                if ends_operand(token) =>
+            {
+                let (start, name) = (*start, Symbol::intern(name));
+                self.tokens.bump();
+                self.tokens.bump();
+                let value = self.expr()?;
+                let span = start.merge(value.span);
+                Ok(Statement::Assignment { name, value, span })
+            }
+            _ => {
+                let expr = self.expr()?;
+                let ExprKind::Index { target, index } = expr.kind else {
+                    return Ok(Statement::Expr(expr));
+                };
+                if self.tokens.eat(&Token::Ident("=")).is_none() {
+                    let kind = ExprKind::Index { target, index };
+                    return Ok(Statement::Expr(Expr::new(kind, expr.span)));
+                }
+                let value = self.expr()?;
+                let span = expr.span.merge(value.span);
+                Ok(Statement::IndexAssignment {
+                    target: *target,
+                    index: *index,
+                    value,
+                    span,
+                })
+            }
+        }
+    }
+
+    /// 変数名になる識別子を読む
+    fn ident(&mut self) -> Result<Symbol, ParseError> {
+        match self.tokens.bump() {
+            Some((_, token @ Token::Ident(name))) if ends_operand(token) => {
+                Ok(Symbol::intern(name))
+            }
+            Some((span, _)) => Err(self.tokens.error_at(span, Expected::Ident)),
+            None => Err(self.tokens.error_at_end(Expected::Ident)),
+        }
+    }
+
+    /// 式を解析する
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        self.binary(Precedence::Or)
+    }
+
+    /// 優先順位が `min_prec` 以上の二項演算子だけをまとめて式を解析する
+    ///
+    /// 同じ優先順位の演算子は左結合になる。
+    fn binary(&mut self, min_prec: Precedence) -> Result<Expr, ParseError> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek_binop() {
+            let prec = op.precedence();
+            if prec < min_prec {
+                break;
+            }
+            self.tokens.bump();
+            let rhs = self.binary(prec.tighter())?;
+            let span = lhs.span.merge(rhs.span);
+            lhs = Expr::new(
+                ExprKind::BinaryOp {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                span,
+            );
+        }
+        Ok(lhs)
+    }
+
+    /// 前置の単項演算子が付いた式を解析する
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let (span, op) = match self.tokens.peek() {
+            Some((span, Token::Ident("-"))) => (*span, UnOp::Neg),
+            Some((span, Token::Ident("!"))) => (*span, UnOp::Not),
+            _ => return self.postfix(),
+        };
+        self.tokens.bump();
+        let operand = self.unary()?;
+        let span = span.merge(operand.span);
+        Ok(Expr::new(
+            ExprKind::UnaryOp {
+                op,
+                operand: Box::new(operand),
+            },
+            span,
+        ))
+    }
+
+    /// 後ろに `(args, ...)` が続く関数呼び出しと `[index]` が続く要素の参照を解析する
+    fn postfix(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.primary()?;
+        loop {
+            match self.tokens.peek() {
+                Some((open, Token::LParen)) => {
+                    let open = *open;
+                    self.tokens.bump();
+                    let (args, close) = self.list(open, Token::RParen)?;
+                    let span = expr.span.merge(close);
+                    let kind = ExprKind::Call {
+                        func: Box::new(expr),
+                        args,
+                    };
+                    expr = Expr::new(kind, span);
+                }
+                Some((_, Token::LBracket)) => {
+                    self.tokens.bump();
+                    let index = self.expr()?;
+                    let close = self.tokens.expect(&Token::RBracket, Expected::RBracket)?;
+                    let span = expr.span.merge(close);
+                    let kind = ExprKind::Index {
+                        target: Box::new(expr),
+                        index: Box::new(index),
+                    };
+                    expr = Expr::new(kind, span);
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    /// 読み終えた `{` の後がマップのリテラルかどうか
+    ///
+    /// 空のマップ `{:}` か、リテラルか名前の1つのトークンのキーに `:` が続けばマップとする。
+    /// `{` の直後の `-1` は符号付きの数値リテラルとして1つのトークンになる。
+    fn at_map(&self) -> bool {
+        let key = match self.tokens.peek() {
+            Some((_, Token::Ident(":"))) => 0,
+            Some((_, token @ (Token::Int(_) | Token::Float(_) | Token::StrLiteral(_))))
+            | Some((_, token @ (Token::Bool(_) | Token::Nil | Token::Ident(_))))
+                if ends_operand(token) =>
+            {
+                1
+            }
+            _ => return false,
+        };
+        matches!(self.tokens.peek_nth(key), Some((_, Token::Ident(":"))))
+    }
+
+    /// `{` に続く `key: value, ...}` を解析する
+    ///
+    /// 項目は `,` か改行で区切り、最後の項目の後の区切りは省略できる。
+    fn map(&mut self, start: Span) -> Result<Expr, ParseError> {
+        if self.tokens.eat(&Token::Ident(":")).is_some() {
+            let end = self.tokens.expect(&Token::RBrace, Expected::RBrace)?;
+            return Ok(Expr::new(ExprKind::Map(vec![]), start.merge(end)));
+        }
+        let mut entries = vec![];
+        let end = loop {
+            while self.tokens.eat(&Token::Semicolon).is_some() {}
+            match self.tokens.peek() {
+                Some((span, Token::RBrace)) => {
+                    let span = *span;
+                    self.tokens.bump();
+                    break span;
+                }
+                None => return Err(self.tokens.error_at_end(Expected::RBrace)),
+                _ => {}
+            }
+            let key = self.expr()?;
+            self.tokens.expect(&Token::Ident(":"), Expected::Colon)?;
+            let value = self.expr()?;
+            entries.push((key, value));
+            match self.tokens.peek() {
+                Some((_, Token::Ident(","))) | Some((_, Token::Semicolon)) => {
+                    self.tokens.bump();
+                }
+                Some((_, Token::RBrace)) => {}
+                Some((span, _)) => return Err(self.tokens.error_at(*span, Expected::RBrace)),
+                None => return Err(self.tokens.error_at_end(Expected::RBrace)),
+            }
+        };
+        Ok(Expr::new(ExprKind::Map(entries), start.merge(end)))
+    }
+
+    /// `open` に続く `,` 区切りの式の並びを `close` まで解析する
+    ///
+    /// # 戻り値
+    /// * `Result<(Vec<Expr>, Span), ParseError>` - (式のリスト, 閉じる括弧の範囲)のタプル
+    fn list(&mut self, open: Span, close: Token) -> Result<(Vec<Expr>, Span), ParseError> {
+        let mut items = vec![];
+        let unbalanced = |parser: &Self| match close {
+            Token::RParen => ParseError::UnbalancedParen { span: open },
+            _ => parser.tokens.error_at_end(Expected::RBracket),
+        };
+        loop {
+            match self.tokens.peek() {
+                Some((span, token)) if *token == close => {
+                    let span = *span;
+                    self.tokens.bump();
+                    return Ok((items, span));
+                }
+                _ => {}
+            }
+            items.push(self.expr()?);
+            match self.tokens.bump() {
+                Some((_, Token::Ident(","))) => {}
+                Some((span, token)) if *token == close => return Ok((items, span)),
+                Some((span, _)) => return Err(self.tokens.error_at(span, Expected::Comma)),
+                None => return Err(unbalanced(self)),
+            }
+        }
+    }
+
+    /// リテラル、識別子、括弧で囲まれた式を解析する
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let Some((span, token)) = self.tokens.bump() else {
+            return Err(self.tokens.error_at_end(Expected::Expression));
+        };
+        let kind = match token {
+            Token::Int(n) => ExprKind::Int(*n),
+            Token::Float(n) => ExprKind::Float(*n),
+            Token::StrLiteral(s) => ExprKind::Str(unescape(s)),
+            Token::Bool(b) => ExprKind::Bool(*b),
+            Token::Nil => ExprKind::Nil,
+            Token::Keyword(Keyword::If) => return self.if_expr(span),
+            Token::Keyword(Keyword::Match) => return self.match_expr(span),
+            Token::Keyword(Keyword::Import) => match self.tokens.bump() {
+                Some((path, Token::StrLiteral(s))) => {
+                    let kind = ExprKind::Import(unescape(s));
+                    return Ok(Expr::new(kind, span.merge(path)));
+                }
+                Some((span, _)) => return Err(self.tokens.error_at(span, Expected::StrLiteral)),
+                None => return Err(self.tokens.error_at_end(Expected::StrLiteral)),
+            },
+            Token::Keyword(Keyword::While) => {
+                let cond = self.expr()?;
+                let body = self.block()?;
+                let span = span.merge(body.span);
+                let kind = ExprKind::While {
+                    cond: Box::new(cond),
+                    body: Box::new(body),
+                };
+                return Ok(Expr::new(kind, span));
+            }
+            Token::Keyword(Keyword::For) => return self.for_expr(span),
+            Token::Keyword(Keyword::Fn) => return self.fn_expr(span),
+            Token::Keyword(Keyword::Break) => ExprKind::Break,
+            Token::Keyword(Keyword::Continue) => ExprKind::Continue,
+            Token::Keyword(Keyword::Return) => {
+                // 文の終わりが続けば値を省略したものとする
+                if matches!(
+                    self.tokens.peek(),
+                    None | Some((_, Token::Semicolon | Token::RBrace))
+                ) {
+                    ExprKind::Return(None)
+                } else {
+                    let value = self.expr()?;
+                    let span = span.merge(value.span);
+                    return Ok(Expr::new(ExprKind::Return(Some(Box::new(value))), span));
+                }
+            }
+            Token::LBrace => {
+                if self.at_map() {
+                    return self.map(span);
+                }
+                self.tokens.back();
+                return self.block();
+            }
+            Token::LBracket => {
+                let (items, close) = self.list(span, Token::RBracket)?;
+                return Ok(Expr::new(ExprKind::Array(items), span.merge(close)));
+            }
+            Token::Ident(name) if ends_operand(token) => ExprKind::Ident(Symbol::intern(name)),
+            Token::LParen => {
+                let inner = self.expr()?;
+                return match self.tokens.bump() {
+                    Some((close, Token::RParen)) => Ok(Expr::new(
+                        ExprKind::Group(Box::new(inner)),
+                        span.merge(close),
+                    )),
+                    Some((span, _)) => Err(self.tokens.error_at(span, Expected::RParen)),
+                    None => Err(ParseError::UnbalancedParen { span }),
+                };
+            }
+            _ => return Err(self.tokens.error_at(span, Expected::Expression)),
+        };
+        Ok(Expr::new(kind, span))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 式を括弧付きのS式風の文字列にして、木の形を比べやすくする
+    fn show(expr: &Expr) -> String {
+        match &expr.kind {
+            ExprKind::Int(n) => n.to_string(),
+            ExprKind::Float(n) => format!("{n:?}"),
+            ExprKind::Str(s) => format!("{s:?}"),
+            ExprKind::Ident(name) => name.to_string(),
+            ExprKind::BinaryOp { op, lhs, rhs } => {
+                format!("({} {} {})", op.symbol(), show(lhs), show(rhs))
+            }
+            ExprKind::UnaryOp {
+                op: UnOp::Neg,
+                operand,
+            } => format!("(neg {})", show(operand)),
+            ExprKind::UnaryOp { op, operand } => format!("({} {})", op.symbol(), show(operand)),
+            ExprKind::Group(inner) => show(inner),
+            ExprKind::Call { func, args } => {
+                let args: Vec<_> = args.iter().map(show).collect();
+                format!("({} {})", show(func), args.join(" "))
+            }
+            ExprKind::Array(items) => {
+                let items: Vec<_> = items.iter().map(show).collect();
+                format!("[{}]", items.join(", "))
+            }
+            ExprKind::Map(entries) => {
+                let entries: Vec<_> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", show(key), show(value)))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            ExprKind::Index { target, index } => format!("{}[{}]", show(target), show(index)),
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch: Some(else_branch),
+            } => format!(
+                "(if {} {} {})",
+                show(cond),
+                show(then_branch),
+                show(else_branch)
+            ),
+            ExprKind::If {
+                cond, then_branch, ..
+            } => format!("(if {} {})", show(cond), show(then_branch)),
+            ExprKind::Block(statements) => {
+                let statements: Vec<_> = statements
+                    .iter()
+                    .map(|statement| match statement {
+                        Statement::Expr(expr) => show(expr),
+                        statement => format!("{statement:?}"),
+                    })
+                    .collect();
+                format!("{{{}}}", statements.join("; "))
+            }
+            kind => format!("{kind:?}"),
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let expr = parse_expr("1 + 2 * 3 - (4 / 5)").unwrap();
+        assert_eq!(show(&expr), "(- (+ 1 (* 2 3)) (/ 4 5))");
+        assert_eq!(expr.span, Span::new(0, 19));
+        assert_eq!(show(&parse_expr("- x * y").unwrap()), "(* (neg x) y)");
+        assert_eq!(show(&parse_expr("a - b - c").unwrap()), "(- (- a b) c)");
+        assert_eq!(
+            show(&parse_expr("a || !b && c + 1 < d == e").unwrap()),
+            "(|| a (&& (! b) (== (< (+ c 1) d) e)))"
+        );
+    }
+
+    #[test]
+    fn test_group() {
+        // 優先順位を変える括弧、関数呼び出しの括弧、配列の角括弧はそれぞれ別の節になる
+        let expr = parse_expr("(a + b) * f(c)[0]").unwrap();
+        let ExprKind::BinaryOp { lhs, rhs, .. } = &expr.kind else {
+            panic!("{expr:?}");
+        };
+        let ExprKind::Group(inner) = &lhs.kind else {
+            panic!("{lhs:?}");
+        };
+        assert_eq!(lhs.span, Span::new(0, 7));
+        assert_eq!(inner.span, Span::new(1, 6));
+        let ExprKind::Index { target, .. } = &rhs.kind else {
+            panic!("{rhs:?}");
+        };
+        assert!(matches!(target.kind, ExprKind::Call { .. }));
+        assert!(matches!(
+            parse_expr("[(1)]").unwrap().kind,
+            ExprKind::Array(ref items) if matches!(items[0].kind, ExprKind::Group(_))
+        ));
+        assert!(matches!(
+            parse_expr("((x))").unwrap().kind,
+            ExprKind::Group(ref inner) if matches!(inner.kind, ExprKind::Group(_))
+        ));
+    }
+
+    #[test]
+    fn test_signed_numbers() {
+        assert_eq!(show(&parse_expr("1-2").unwrap()), "(- 1 2)");
+        assert_eq!(show(&parse_expr("1 - -2").unwrap()), "(- 1 -2)");
+        assert_eq!(show(&parse_expr("(-1)").unwrap()), "-1");
+        assert_eq!(show(&parse_expr("x+1").unwrap()), "(+ x 1)");
+        assert_eq!(show(&parse_expr("(1)-1").unwrap()), "(- 1 1)");
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert_eq!(
+            parse_expr("1 +"),
+            Err(ParseError::unexpected(3, Expected::Expression, None))
+        );
+        assert_eq!(
+            parse_expr("(1 + 2"),
+            Err(ParseError::UnbalancedParen {
+                span: Span::new(0, 1)
+            })
+        );
+        assert_eq!(
+            parse_expr("1 2"),
+            Err(ParseError::unexpected(2, Expected::EndOfInput, Some('2')))
+        );
+    }
+
+    #[test]
+    fn test_statements() {
+        let program = statements("var x = 1\nx = x +\n  2; x * (3\n)\n\n").unwrap();
+        assert_eq!(program.len(), 3);
+        let Statement::VarDef { name, value, span } = &program[0] else {
+            panic!("expected a definition: {:?}", program[0]);
+        };
+        assert_eq!(
+            (name.as_str(), show(value), *span),
+            ("x", "1".to_string(), Span::new(0, 9))
+        );
+        let Statement::Assignment { name, value, .. } = &program[1] else {
+            panic!("expected an assignment: {:?}", program[1]);
+        };
+        assert_eq!((name.as_str(), show(value)), ("x", "(+ x 2)".to_string()));
+        let Statement::Expr(expr) = &program[2] else {
+            panic!("expected an expression: {:?}", program[2]);
+        };
+        assert_eq!(show(expr), "(* x 3)");
+        assert_eq!(statements(" ; // comment\n"), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_statement_error() {
+        assert_eq!(
+            statements("var 1 = 2"),
+            Err(ParseError::unexpected(4, Expected::Ident, Some('1')))
+        );
+        assert_eq!(
+            statements("var x 2"),
+            Err(ParseError::unexpected(6, Expected::Equals, Some('2')))
+        );
+        assert_eq!(
+            statements("x = 1 2"),
+            Err(ParseError::unexpected(6, Expected::Semicolon, Some('2')))
+        );
+    }
+
+    #[test]
+    fn test_if() {
+        let expr = parse_expr("if a { 1 } else if b { 2 } else { 3 }").unwrap();
+        assert_eq!(show(&expr), "(if a {1} (if b {2} {3}))");
+        assert_eq!(expr.span, Span::new(0, 37));
+        // 内側の `if` に続く `else` は内側の `if` に付く
+        let expr = parse_expr("if a { if b { 1 } else { 2 } }").unwrap();
+        assert_eq!(show(&expr), "(if a {(if b {1} {2})})");
+        let expr = parse_expr("1 + if a {\n  2\n}\nelse { 3 }").unwrap();
+        assert_eq!(show(&expr), "(+ 1 (if a {2} {3}))");
+        assert_eq!(
+            parse_expr("if a 1"),
+            Err(ParseError::unexpected(5, Expected::LBrace, Some('1')))
+        );
+        assert_eq!(
+            parse_expr("if a { 1"),
+            Err(ParseError::unexpected(8, Expected::RBrace, None))
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let expr = parse_expr("while x { break }").unwrap();
+        assert!(matches!(expr.kind, ExprKind::While { .. }));
+        let expr = parse_expr("for i in 0..n + 1 { continue }").unwrap();
+        let ExprKind::For {
+            var, start, end, ..
+        } = &expr.kind
+        else {
+            panic!("expected a for loop: {expr:?}");
+        };
+        assert_eq!(
+            (var.as_str(), show(start), show(end)),
+            ("i", "0".to_string(), "(+ n 1)".to_string())
+        );
+        assert_eq!(
+            parse_expr("for i 0..1 {}"),
+            Err(ParseError::unexpected(6, Expected::In, Some('0')))
+        );
+        assert_eq!(
+            parse_expr("for i in 0 1 {}"),
+            Err(ParseError::unexpected(11, Expected::DotDot, Some('1')))
+        );
+    }
+
+    #[test]
+    fn test_fn() {
+        let program = statements("fn add(a: f64, b) -> f64 { a + b }; add(1, 2)(3)").unwrap();
+        let Statement::VarDef { name, value, .. } = &program[0] else {
+            panic!("expected a definition: {program:?}");
+        };
+        let ExprKind::Fn {
+            params, signature, ..
+        } = &value.kind
+        else {
+            panic!("expected a function: {value:?}");
+        };
+        assert_eq!((name.as_str(), params.len()), ("add", 2));
+        assert_eq!(
+            signature,
+            &Some(Signature {
+                params: vec![Some(TypeName::Float), None],
+                ret: Some(TypeName::Float)
+            })
+        );
+        let Statement::Expr(call) = &program[1] else {
+            panic!("expected an expression: {program:?}");
+        };
+        assert_eq!(show(call), "((add 1 2) 3)");
+        let expr = parse_expr("fn (x) { x }").unwrap();
+        assert!(matches!(
+            expr.kind,
+            ExprKind::Fn {
+                signature: None,
+                ..
+            }
+        ));
+        assert_eq!(
+            parse_expr("fn (x: int) { x }"),
+            Err(ParseError::unexpected(7, Expected::TypeName, Some('i')))
+        );
+        assert_eq!(
+            parse_expr("f(1 2)"),
+            Err(ParseError::unexpected(4, Expected::Comma, Some('2')))
+        );
+    }
+
+    #[test]
+    fn test_return() {
+        let program = statements("fn f(x) { if x { return x + 1; }; return }").unwrap();
+        let Statement::VarDef { value, .. } = &program[0] else {
+            panic!("expected a definition: {program:?}");
+        };
+        let ExprKind::Fn { body, .. } = &value.kind else {
+            panic!("expected a function: {value:?}");
+        };
+        let [ExprKind::Block(statements)] = &body.iter().map(|e| &e.kind).collect::<Vec<_>>()[..]
+        else {
+            panic!("expected a block body: {body:?}");
+        };
+        let Statement::Expr(Expr {
+            kind: ExprKind::Return(None),
+            span,
+        }) = &statements[1]
+        else {
+            panic!("expected a bare return: {statements:?}");
+        };
+        assert_eq!(*span, Span::new(34, 40));
+        let expr = parse_expr("return 1 + 2").unwrap();
+        let ExprKind::Return(Some(value)) = &expr.kind else {
+            panic!("expected a return: {expr:?}");
+        };
+        assert_eq!(
+            (show(value), expr.span),
+            ("(+ 1 2)".to_string(), Span::new(0, 12))
+        );
+    }
+
+    #[test]
+    fn test_match() {
+        let expr = parse_expr("match x {\n  1 => \"one\",\n  -2 if y => 0.5\n  n => n\n}").unwrap();
+        let ExprKind::Match { scrutinee, arms } = &expr.kind else {
+            panic!("expected a match: {expr:?}");
+        };
+        assert_eq!(
+            (show(scrutinee), expr.span),
+            ("x".to_string(), Span::new(0, 51))
+        );
+        let patterns: Vec<_> = arms.iter().map(|arm| arm.pattern.clone()).collect();
+        assert_eq!(
+            patterns,
+            [
+                Pattern::Int(1),
+                Pattern::Int(-2),
+                Pattern::Binding(Symbol::intern("n"))
+            ]
+        );
+        assert_eq!(arms[1].pattern_span, Span::new(26, 28));
+        assert_eq!(arms[1].guard.as_ref().map(show), Some("y".to_string()));
+        assert!(arms[2].is_catch_all() && !arms[0].is_catch_all());
+        assert!(parse_expr("match x { }").is_ok());
+        assert_eq!(
+            parse_expr("match x { (1) => 2 }"),
+            Err(ParseError::unexpected(10, Expected::Pattern, Some('(')))
+        );
+        assert_eq!(
+            parse_expr("match x { 1 = 2 }"),
+            Err(ParseError::unexpected(12, Expected::FatArrow, Some('=')))
+        );
+    }
+
+    #[test]
+    fn test_array() {
+        assert_eq!(
+            show(&parse_expr("[1, [2, x + 1], f(y)[0]][i][j]").unwrap()),
+            "[1, [2, (+ x 1)], (f y)[0]][i][j]"
+        );
+        assert_eq!(show(&parse_expr("[\n  1,\n  2,\n]").unwrap()), "[1, 2]");
+        let program = statements("var a = []\na[0] = 1\na[0]").unwrap();
+        let Statement::IndexAssignment {
+            target,
+            index,
+            value,
+            span,
+        } = &program[1]
+        else {
+            panic!("expected an index assignment: {program:?}");
+        };
+        assert_eq!(
+            (show(target), show(index), show(value), *span),
+            (
+                "a".to_string(),
+                "0".to_string(),
+                "1".to_string(),
+                Span::new(11, 19)
+            )
+        );
+        assert!(matches!(&program[2], Statement::Expr(e) if show(e) == "a[0]"));
+        assert_eq!(
+            parse_expr("a[0 1]"),
+            Err(ParseError::unexpected(4, Expected::RBracket, Some('1')))
+        );
+        assert_eq!(
+            parse_expr("[1 2]"),
+            Err(ParseError::unexpected(3, Expected::Comma, Some('2')))
+        );
+    }
+
+    #[test]
+    fn test_map() {
+        assert_eq!(
+            show(&parse_expr("{ \"a\": 1 + 2, x: {:}, -1: [] }").unwrap()),
+            "{\"a\": (+ 1 2), x: {}, -1: []}"
+        );
+        assert_eq!(
+            show(&parse_expr("{\n  \"a\": 1,\n  \"b\": 2\n}").unwrap()),
+            "{\"a\": 1, \"b\": 2}"
+        );
+        // キーと `:` が続かなければブロックとして読む
+        assert!(matches!(
+            parse_expr("{ x }").unwrap().kind,
+            ExprKind::Block(_)
+        ));
+        assert!(matches!(parse_expr("{}").unwrap().kind, ExprKind::Block(_)));
+        assert_eq!(parse_expr("{:}").unwrap().span, Span::new(0, 3));
+        assert_eq!(
+            parse_expr("{ \"a\": 1, \"b\" 2 }"),
+            Err(ParseError::unexpected(14, Expected::Colon, Some('2')))
+        );
+    }
+
+    #[test]
+    fn test_import() {
+        let expr = parse_expr("import \"lib/a.rscl\"[\"f\"](1)").unwrap();
+        assert_eq!(show(&expr), "(Import(\"lib/a.rscl\")[\"f\"] 1)");
+        assert_eq!(
+            parse_expr("import \"a\"").unwrap(),
+            Expr::new(ExprKind::Import("a".to_string()), Span::new(0, 10))
+        );
+        assert_eq!(
+            parse_expr("import a"),
+            Err(ParseError::unexpected(7, Expected::StrLiteral, Some('a')))
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! 識別子の文字列を整数の `Symbol` に置き換えるインターナー
+//!
+//! 構文木と環境は名前を [`Symbol`] で持つので、名前の比較やハッシュは整数の比較で済む。
+//! [`Symbol::intern`] はプロセス全体で共有する1つのインターナーを使い、
+//! 登録した文字列はプロセスが終わるまで解放しない。
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+/// インターナーに登録された文字列を指す整数のハンドル
+///
+/// 同じ文字列からは常に同じ `Symbol` が得られる。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    /// 文字列を共有のインターナーに登録してハンドルを得る
+    pub fn intern(name: &str) -> Self {
+        global()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .intern(name)
+    }
+
+    /// ハンドルが指す文字列
+    pub fn as_str(self) -> &'static str {
+        global()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .resolve(self)
+            .expect("symbols are only created by the global interner")
+    }
+
+    /// ハンドルの整数値
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Self::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Self::intern(&name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Self {
+        Self::intern(name)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 文字列と `Symbol` の対応表
+///
+/// 登録した文字列は `'static` な参照として返せるよう、解放せずに持ち続ける。
+#[derive(Debug, Default)]
+pub struct Interner {
+    symbols: HashMap<&'static str, Symbol>,
+    names: Vec<&'static str>,
+}
+
+impl Interner {
+    /// 空の対応表を作る
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 文字列を登録してハンドルを得る関数
+    ///
+    /// # 引数
+    /// * `name` - 登録する文字列
+    ///
+    /// # 戻り値
+    /// * `Symbol` - 登録済みの文字列なら以前と同じハンドル、そうでなければ新しいハンドル
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(symbol) = self.symbols.get(name) {
+            return *symbol;
+        }
+        let symbol = Symbol(u32::try_from(self.names.len()).expect("too many symbols"));
+        let name: &'static str = Box::leak(name.into());
+        self.names.push(name);
+        self.symbols.insert(name, symbol);
+        symbol
+    }
+
+    /// ハンドルが指す文字列を得る。別の対応表で作られたハンドルなら `None`
+    pub fn resolve(&self, symbol: Symbol) -> Option<&'static str> {
+        self.names.get(symbol.0 as usize).copied()
+    }
+
+    /// 登録された文字列の数
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// 文字列が1つも登録されていないかどうか
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// プロセス全体で共有するインターナー
+fn global() -> &'static Mutex<Interner> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(|| Mutex::new(Interner::new()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_interner() {
+        let mut interner = Interner::new();
+        let a = interner.intern("a");
+        let b = interner.intern("変数");
+        assert_eq!(interner.intern("a"), a);
+        assert_ne!(a, b);
+        assert_eq!(interner.resolve(b), Some("変数"));
+        assert_eq!(interner.len(), 2);
+    }
+
+    #[test]
+    fn test_symbol() {
+        let x = Symbol::intern("x");
+        assert_eq!(Symbol::from(String::from("x")), x);
+        assert_eq!(x.as_str(), "x");
+        assert_eq!(x, "x");
+        assert_eq!(format!("{x} {x:?}"), "x \"x\"");
+    }
+}
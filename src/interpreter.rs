@@ -0,0 +1,506 @@
+//! 大域環境を組み立ててからプログラムを評価する [`Interpreter`]
+//!
+//! 組み込む側は [`Interpreter::builder`] で、標準関数を外したり、独自の関数や変数を足したりしてから
+//! 利用者のコードを評価できる。
+//!
+//! ```
+//! use ruscal_b::{Interpreter, Value};
+//!
+//! let mut interpreter = Interpreter::builder()
+//!     .disable_stdlib()
+//!     .with_global("limit", Value::I64(10))
+//!     .with_native_fn("id", |args, _| Ok(args[0].clone()))
+//!     .build();
+//! assert_eq!(interpreter.run("(id limit)").unwrap(), Some(Value::I64(10)));
+//! assert!(interpreter.run("(len \"a\")").is_err());
+//! ```
+//!
+//! `import` で読み込むモジュールは、ここでの設定に関わらず標準関数だけを定義した環境で評価する。
+
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::ast::{Span, TokenTree};
+use crate::env::Environment;
+use crate::error::Error;
+use crate::eval::{eval_expr, eval_forms, eval_statements, lower, EvalError, Value};
+use crate::infix::statements;
+use crate::io::{io_policy, set_io_policy, IoPolicy};
+use crate::limits::{self, set_run_limits, RunLimits, Usage};
+use crate::module;
+use crate::parser::source;
+use crate::stdlib::{self, Arity, NativeFn};
+use crate::typecheck::check;
+
+/// 設定した大域環境でプログラムを評価するインタプリタ
+///
+/// 評価した定義は同じ環境に残るので、続けて評価するプログラムから参照できる。
+///
+/// 関数の呼び出しには、評価するスレッドのスタックの残りの 3/4 までしか使わない。
+/// [`RunLimits::max_call_depth`] まで深く呼び出すには、大きなスタックを持つスレッドで作って評価する。
+#[derive(Debug)]
+pub struct Interpreter {
+    env: Environment,
+    limits: Option<RunLimits>,
+    io: IoPolicy,
+}
+
+impl Interpreter {
+    /// 標準関数だけを定義したインタプリタを作る
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    /// 大域環境を設定するビルダーを作る
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder::new()
+    }
+
+    /// S式のソースコードを解析し、各式を順に評価する
+    ///
+    /// # 戻り値
+    /// * `Result<Option<Value>, Error>` - 最後の式の値。式が無ければ `None`
+    pub fn run(&mut self, input: &str) -> Result<Option<Value>, Error> {
+        let _session = self.start();
+        let TokenTree::Tree(forms, _) = source(input)? else {
+            unreachable!("source() always returns a tree");
+        };
+        Ok(eval_forms(&forms, &mut self.env)?)
+    }
+
+    /// 中置記法のソースコードを解析し、各文を順に評価する
+    ///
+    /// # 戻り値
+    /// * `Result<Option<Value>, Error>` - 最後の文が式ならその値
+    pub fn run_infix(&mut self, input: &str) -> Result<Option<Value>, Error> {
+        let _session = self.start();
+        Ok(eval_statements(&statements(input)?, &mut self.env)?)
+    }
+
+    /// S式のソースコードを解析し、型検査してから各式を順に評価する
+    ///
+    /// 型の誤りがあれば、どの式も評価せずに最初の誤りを返す。
+    /// 前の呼び出しで定義した変数は、型検査では型の分からない値として扱う。
+    ///
+    /// ```
+    /// use ruscal_b::{Error, Interpreter, Value};
+    ///
+    /// let mut interpreter = Interpreter::new();
+    /// assert_eq!(interpreter.eval_str("(define x 40)"), Ok(Value::I64(40)));
+    /// assert_eq!(interpreter.eval_str("(+ x 2)"), Ok(Value::I64(42)));
+    /// assert_eq!(interpreter.eval_str(""), Ok(Value::Nil));
+    /// assert!(matches!(interpreter.eval_str("(+ 1 \"a\")"), Err(Error::Type(_))));
+    /// ```
+    ///
+    /// # 戻り値
+    /// * `Result<Value, Error>` - 最後の式の値。式が無ければ `nil`
+    pub fn eval_str(&mut self, src: &str) -> Result<Value, Error> {
+        let _session = self.start();
+        let TokenTree::Tree(forms, _) = source(src)? else {
+            unreachable!("source() always returns a tree");
+        };
+        let exprs = forms.iter().map(lower).collect::<Result<Vec<_>, _>>()?;
+        if let Err(errors) = check(&exprs) {
+            let first = errors.into_iter().next();
+            return Err(first.expect("check() reports at least one error").into());
+        }
+        let mut last = Value::Nil;
+        for expr in &exprs {
+            last = eval_expr(expr, &mut self.env)?;
+        }
+        Ok(last)
+    }
+
+    /// S式のソースファイルを読み込み、[`Self::eval_str`] と同じく型検査してから評価する
+    ///
+    /// ファイルの中の `import` は、先にファイルのあるディレクトリから探す。
+    /// [`IoPolicy`] で許していなくても、そのディレクトリのファイルは読み込める。
+    ///
+    /// # 戻り値
+    /// * `Result<Value, Error>` - 最後の式の値。読み込めなければ [`Error::Io`] を返す
+    pub fn eval_file(&mut self, path: impl AsRef<Path>) -> Result<Value, Error> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path).map_err(|e| Error::Io {
+            path: path.display().to_string(),
+            message: e.to_string(),
+        })?;
+        let search_paths = module::search_paths();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => ".".into(),
+        };
+        let io = self.io.clone();
+        self.io = io.clone().allow_import(&dir);
+        module::set_search_paths(std::iter::once(dir).chain(search_paths.clone()).collect());
+        let res = self.eval_str(&src);
+        module::set_search_paths(search_paths);
+        self.io = io;
+        res
+    }
+
+    /// 上限を設定していれば、使った量を0に戻してこれからの評価に設定する。入出力に許す操作も設定する
+    ///
+    /// 返す値を捨てると、スレッドの上限と入出力に許す操作を呼び出す前のものに戻す。
+    fn start(&self) -> Session {
+        let session = Session {
+            io: io_policy(),
+            usage: self.limits.map(|_| limits::usage()),
+        };
+        if let Some(limits) = self.limits {
+            set_run_limits(limits);
+        }
+        set_io_policy(self.io.clone());
+        session
+    }
+
+    /// 大域環境
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+
+    /// 書き換えられる大域環境
+    pub fn env_mut(&mut self) -> &mut Environment {
+        &mut self.env
+    }
+}
+
+/// 評価を終えたら、インタプリタが設定したスレッドの状態を元に戻す値
+///
+/// エラーで抜けても戻すので、信頼して多くを許したインタプリタの設定が、同じスレッドで後から
+/// 評価するプログラムに残らない。
+struct Session {
+    io: IoPolicy,
+    usage: Option<Usage>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        set_io_policy(std::mem::take(&mut self.io));
+        if let Some(usage) = self.usage {
+            limits::restore_usage(usage);
+        }
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [`Interpreter`] の大域環境の設定
+///
+/// 標準関数を先に定義し、追加した関数と変数を追加した順に定義する。
+/// 同じ名前を使えば、標準関数や先に追加したものを隠せる。
+#[derive(Debug)]
+pub struct InterpreterBuilder {
+    stdlib: bool,
+    globals: Vec<(String, Value)>,
+    limits: Option<RunLimits>,
+    io: IoPolicy,
+}
+
+impl InterpreterBuilder {
+    /// 標準関数を定義する設定を作る
+    pub fn new() -> Self {
+        Self {
+            stdlib: true,
+            globals: vec![],
+            limits: None,
+            io: IoPolicy::DENY_ALL,
+        }
+    }
+
+    /// Rust で実装した関数を追加する
+    ///
+    /// 引数の数は確かめないので、必要なら `func` の中で確かめる。
+    /// 呼び出す前に数を確かめたい場合は [`NativeFn::new`] で作って [`Self::with_global`] に渡す。
+    ///
+    /// # 引数
+    /// * `name` - 関数の名前
+    /// * `func` - 評価済みの実引数と呼び出し式の範囲を受け取る関数
+    pub fn with_native_fn(
+        self,
+        name: &str,
+        func: impl Fn(&[Value], Span) -> Result<Value, EvalError> + 'static,
+    ) -> Self {
+        let function = NativeFn::new(name, Arity::AtLeast(0), func);
+        self.with_global(name, Value::NativeFn(Rc::new(function)))
+    }
+
+    /// 大域変数を追加する
+    pub fn with_global(mut self, name: &str, value: Value) -> Self {
+        self.globals.push((name.to_string(), value));
+        self
+    }
+
+    /// 評価の上限を設定する
+    ///
+    /// [`Interpreter::run`] などを呼ぶたびに、使った量を0に戻してこの上限を設定し、終われば元に戻す。
+    /// 設定しなければ、スレッドに設定済みの上限をそのまま使う。
+    pub fn with_limits(mut self, limits: RunLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// 入出力の標準関数に許す操作を設定する
+    ///
+    /// 設定しなければ [`IoPolicy::DENY_ALL`] で、ファイルも標準入力も扱えない。
+    pub fn with_io_policy(mut self, policy: IoPolicy) -> Self {
+        self.io = policy;
+        self
+    }
+
+    /// 標準関数を定義しない
+    pub fn disable_stdlib(mut self) -> Self {
+        self.stdlib = false;
+        self
+    }
+
+    /// 設定した大域環境を持つインタプリタを作る
+    pub fn build(self) -> Interpreter {
+        let mut env = Environment::new();
+        if self.stdlib {
+            stdlib::register(&mut env);
+        }
+        for (name, value) in self.globals {
+            env.define(name.as_str(), value);
+        }
+        Interpreter {
+            env,
+            limits: self.limits,
+            io: self.io,
+        }
+    }
+}
+
+impl Default for InterpreterBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let mut interpreter = Interpreter::builder()
+            .with_global("base", Value::I64(40))
+            .with_native_fn("answer", |args, _| match args {
+                [Value::I64(n)] => Ok(Value::I64(n + 2)),
+                _ => Ok(Value::Nil),
+            })
+            .build();
+        assert_eq!(interpreter.run("(answer base)"), Ok(Some(Value::I64(42))));
+        assert_eq!(interpreter.run("(answer)"), Ok(Some(Value::Nil)));
+        // 定義は次の評価にも残り、記法をまたいで参照できる
+        assert_eq!(
+            interpreter.run("(define x (len \"abc\"))"),
+            Ok(Some(Value::I64(3)))
+        );
+        assert_eq!(interpreter.run_infix("answer(x)"), Ok(Some(Value::I64(5))));
+        assert_eq!(interpreter.env().get("x"), Some(Value::I64(3)));
+    }
+
+    #[test]
+    fn test_disable_stdlib() {
+        let mut interpreter = Interpreter::builder().disable_stdlib().build();
+        assert_eq!(
+            interpreter.run("(len \"a\")"),
+            Err(Error::Eval(EvalError::UnknownIdentifier {
+                name: "len".to_string(),
+                span: Span::new(1, 4)
+            }))
+        );
+        // 言語に組み込みの演算子は標準関数を外しても使える
+        assert_eq!(interpreter.run("(+ 1 2)"), Ok(Some(Value::I64(3))));
+        assert!(matches!(interpreter.run("(+ 1"), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn test_native_fn_state() {
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let mut interpreter = Interpreter::builder()
+            // 標準関数と同じ名前で追加すれば隠せる
+            .with_native_fn("len", move |_, _| {
+                counter.set(counter.get() + 1);
+                Ok(Value::I64(counter.get()))
+            })
+            .build();
+        assert_eq!(
+            interpreter.run("(len \"abc\") (len)"),
+            Ok(Some(Value::I64(2)))
+        );
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut interpreter = Interpreter::builder()
+            .with_limits(RunLimits {
+                max_steps: Some(100),
+                ..RunLimits::UNLIMITED
+            })
+            .build();
+        assert!(matches!(
+            interpreter.run_infix("while true {}"),
+            Err(Error::Eval(EvalError::FuelExhausted { .. }))
+        ));
+        // 評価するたびに使った手数を数え直す
+        for _ in 0..3 {
+            assert_eq!(
+                interpreter.run_infix("var n = 0\nwhile n < 10 { n = n + 1 }\nn"),
+                Ok(Some(Value::I64(10)))
+            );
+        }
+        set_run_limits(RunLimits::UNLIMITED);
+    }
+
+    #[test]
+    fn test_call_depth() {
+        let thread = std::thread::Builder::new().stack_size(512 << 20).spawn(|| {
+            let mut interpreter = Interpreter::builder()
+                .with_limits(RunLimits {
+                    max_call_depth: 3000,
+                    ..RunLimits::UNLIMITED
+                })
+                .build();
+            let program = "(define f (fn (n) (if (== n 0) 0 (+ 1 (f (- n 1))))))";
+            interpreter.run(program).unwrap();
+            let mut eval = |src| {
+                interpreter
+                    .eval_str(src)
+                    .map(|value| value.to_string())
+                    .map_err(|e| e.to_string())
+            };
+            (eval("(f 2999)"), eval("(f 3000)"))
+        });
+        let (reached, exceeded) = thread.unwrap().join().unwrap();
+        assert_eq!(reached, Ok("2999".to_string()));
+        let exceeded = exceeded.unwrap_err();
+        assert!(
+            exceeded.contains("stack overflow after 3000 nested calls")
+                && !exceeded.contains("stack budget"),
+            "{exceeded}"
+        );
+    }
+
+    #[test]
+    fn test_settings_do_not_leak() {
+        let read = || {
+            let program = statements("read_file(\"Cargo.toml\")").unwrap();
+            let mut env = Environment::new();
+            stdlib::register(&mut env);
+            eval_statements(&program, &mut env)
+        };
+        let mut trusted = Interpreter::builder()
+            .with_io_policy(IoPolicy::DENY_ALL.allow_read("."))
+            .with_limits(RunLimits {
+                max_steps: Some(1000),
+                ..RunLimits::UNLIMITED
+            })
+            .build();
+        assert!(trusted.run("(read_file \"Cargo.toml\")").is_ok());
+        assert!(matches!(read(), Err(EvalError::IoDenied { .. })));
+        assert_eq!(limits::run_limits(), RunLimits::UNLIMITED);
+        // エラーで抜けても元に戻す
+        assert!(trusted
+            .run_infix("read_file(\"Cargo.toml\") + nil")
+            .is_err());
+        assert!(matches!(read(), Err(EvalError::IoDenied { .. })));
+        assert!(trusted.eval_str("(+ 1").is_err());
+        assert_eq!(limits::run_limits(), RunLimits::UNLIMITED);
+    }
+
+    #[test]
+    fn test_example_programs() {
+        let run = |input| Interpreter::new().run(input).unwrap().unwrap().to_string();
+        assert_eq!(
+            run(include_str!("../examples/programs/fib.rscl")),
+            "(55 610)"
+        );
+        assert_eq!(
+            run(include_str!("../examples/programs/closures.rscl")),
+            "(11 12 13)"
+        );
+        assert_eq!(
+            run(include_str!("../examples/programs/fizzbuzz.rscl")),
+            "nil"
+        );
+        // 乱数の種を決めているので、何度実行しても同じ見積もりになる
+        assert_eq!(
+            run(include_str!("../examples/programs/monte_carlo.rscl")),
+            "3.096"
+        );
+    }
+
+    #[test]
+    fn test_language_tests() {
+        // `ruscal test` と同じく、`test_` で始まる関数をすべて呼び出す
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .run(include_str!("../examples/tests/core.test.rscl"))
+            .unwrap();
+        let tests: Vec<_> = interpreter
+            .env()
+            .bindings()
+            .into_iter()
+            .filter(|(name, _)| name.as_str().starts_with("test_"))
+            .collect();
+        assert_eq!(tests.len(), 5);
+        for (name, _) in tests {
+            assert_eq!(interpreter.run(&format!("({name})")), Ok(Some(Value::Nil)));
+        }
+    }
+
+    #[test]
+    fn test_eval_str() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter
+                .eval_str("(define sq (fn (x) (* x x)))")
+                .map(|v| v.to_string()),
+            Ok("<fn (x)>".to_string())
+        );
+        // 定義は次の呼び出しに残る
+        assert_eq!(interpreter.eval_str("(sq 7)"), Ok(Value::I64(49)));
+        assert!(matches!(interpreter.eval_str("(sq"), Err(Error::Parse(_))));
+        assert!(matches!(
+            interpreter.eval_str("(define y 1) (+ y \"a\")"),
+            Err(Error::Type(_))
+        ));
+        // 型の誤りがあれば何も評価しない
+        assert_eq!(interpreter.env().get("y"), None);
+        assert!(matches!(
+            interpreter.eval_str("(/ 1 0)"),
+            Err(Error::Eval(EvalError::DivisionByZero { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_eval_file() {
+        let dir = std::env::temp_dir().join(format!("ruscal-eval-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.rscl"), "(define base 40)").unwrap();
+        std::fs::write(
+            dir.join("main.rscl"),
+            "(define lib (import \"lib.rscl\")) (+ (get lib \"base\") 2)",
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.eval_file(dir.join("main.rscl")),
+            Ok(Value::I64(42))
+        );
+        assert!(matches!(
+            interpreter.eval_file(dir.join("missing.rscl")),
+            Err(Error::Io { .. })
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,436 @@
+//! ファイル、標準入力、コマンドライン引数を扱う標準関数と、その使用を許す範囲
+//!
+//! `read_file`、`write_file`、`read_line`、`args` は、スレッドに設定した [`IoPolicy`] が
+//! 許したものだけを扱える。既定の [`IoPolicy::DENY_ALL`] は何も許さないので、信頼できない
+//! プログラムを組み込んで実行しても、組み込む側が許さない限りファイルを読み書きできない。
+//! 許していない操作は [`EvalError::IoDenied`] で失敗する。
+//!
+//! 読み書きできるファイルはディレクトリで許し、その中にあるファイルだけを扱える。パスは
+//! シンボリックリンクと `..` を解決してから確かめるので、許したディレクトリの外は指せない。
+//! 書き込むファイル自体がシンボリックリンクなら、指す先にかかわらず書き込まない。
+//! `import` でモジュールとして読み込むファイルも、読むことか読み込むことを許したディレクトリの
+//! 中になければならない。
+//! 許す範囲はスレッドごとに持ち、[`Interpreter`](crate::Interpreter) は評価を始めるたびに
+//! 自身の設定を [`set_io_policy`] で設定し、評価を終えると元の設定に戻す。
+//!
+//! ```
+//! use ruscal_b::io::IoPolicy;
+//! use ruscal_b::{Error, EvalError, Interpreter, Value};
+//!
+//! let mut sandboxed = Interpreter::new();
+//! assert!(matches!(
+//!     sandboxed.run("(read_file \"Cargo.toml\")"),
+//!     Err(Error::Eval(EvalError::IoDenied { .. }))
+//! ));
+//! let mut trusted = Interpreter::builder()
+//!     .with_io_policy(IoPolicy::DENY_ALL.allow_read(".").with_args(["-v"]))
+//!     .build();
+//! assert!(trusted.run("(read_file \"Cargo.toml\")").is_ok());
+//! assert_eq!(trusted.run("(len (args))"), Ok(Some(Value::I64(1))));
+//! ```
+
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::ast::Span;
+use crate::eval::{EvalError, Value};
+use crate::limits;
+use crate::stdlib::{Arity, Builtin};
+
+/// 入出力の標準関数に許す操作
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IoPolicy {
+    /// 中のファイルを読めるディレクトリ
+    pub read: Vec<PathBuf>,
+    /// 中のファイルに書き込めるディレクトリ
+    pub write: Vec<PathBuf>,
+    /// 中のファイルを `import` でモジュールとして読み込めるディレクトリ。`read` のディレクトリからも読み込める
+    pub import: Vec<PathBuf>,
+    /// 標準入力を読めるなら `true`
+    pub stdin: bool,
+    /// `args` が返すコマンドライン引数
+    pub args: Vec<String>,
+}
+
+impl IoPolicy {
+    /// 何も許さない設定。コマンドライン引数も渡さない
+    pub const DENY_ALL: Self = Self {
+        read: Vec::new(),
+        write: Vec::new(),
+        import: Vec::new(),
+        stdin: false,
+        args: Vec::new(),
+    };
+
+    /// `dir` の中のファイルを読むことを許す
+    pub fn allow_read(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.read.push(dir.into());
+        self
+    }
+
+    /// `dir` の中のファイルに書き込むことを許す
+    pub fn allow_write(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.write.push(dir.into());
+        self
+    }
+
+    /// `dir` の中のファイルを `import` で読み込むことを許す。`read_file` では読めない
+    pub fn allow_import(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.import.push(dir.into());
+        self
+    }
+
+    /// 標準入力を読むことを許す
+    pub fn allow_stdin(mut self) -> Self {
+        self.stdin = true;
+        self
+    }
+
+    /// `args` が返すコマンドライン引数を設定する
+    pub fn with_args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+}
+
+thread_local! {
+    /// このスレッドで許す操作
+    static POLICY: RefCell<IoPolicy> = const { RefCell::new(IoPolicy::DENY_ALL) };
+}
+
+/// このスレッドで入出力の標準関数に許す操作を設定する関数
+pub fn set_io_policy(policy: IoPolicy) {
+    POLICY.with(|p| *p.borrow_mut() = policy);
+}
+
+/// このスレッドで入出力の標準関数に許す操作
+pub fn io_policy() -> IoPolicy {
+    POLICY.with(|p| p.borrow().clone())
+}
+
+/// 入出力の標準関数の一覧
+pub(crate) static FUNCTIONS: &[Builtin] = &[
+    Builtin::new("read_file", Arity::Exact(1), read_file),
+    Builtin::new("write_file", Arity::Exact(2), write_file),
+    Builtin::new("read_line", Arity::Exact(0), read_line),
+    Builtin::new("args", Arity::Exact(0), args),
+];
+
+/// パスの文字列の引数
+fn expect_path(value: &Value, span: Span) -> Result<&str, EvalError> {
+    match value {
+        Value::Str(path) => Ok(path),
+        _ => Err(EvalError::TypeMismatch {
+            expected: "a string",
+            span,
+        }),
+    }
+}
+
+/// `O_NOFOLLOW` の値。開くファイルがシンボリックリンクなら開かずに失敗させる
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64"
+    )
+))]
+const O_NOFOLLOW: i32 = 0o100000;
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64"
+    ))
+))]
+const O_NOFOLLOW: i32 = 0o400000;
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+const O_NOFOLLOW: i32 = 0x100;
+
+/// `.` と `..` を字面の上で取り除いた絶対パス。ファイルシステムには触れない
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().ok()?.join(path)
+    };
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    Some(normalized)
+}
+
+/// `path` を解決し、`dirs` のどれかの中にあれば解決したパスを返す
+///
+/// まず字面の上で `dirs` の中にあるかを確かめ、外なら有無を調べずに拒むので、許していない場所に
+/// ファイルがあるかどうかは分からない。書き込むファイルはまだ無いことがあるので、`exists` が
+/// `false` なら親のディレクトリだけを解決し、ファイル自体がシンボリックリンクなら拒む。
+fn resolve(
+    operation: &'static str,
+    path: &str,
+    dirs: &[PathBuf],
+    exists: bool,
+    span: Span,
+) -> Result<PathBuf, EvalError> {
+    let denied = || EvalError::IoDenied {
+        operation,
+        path: Some(path.to_string()),
+        span,
+    };
+    let inside = |path: &Path, canonical: bool| {
+        dirs.iter()
+            .filter_map(|dir| match canonical {
+                true => dir.canonicalize().ok(),
+                false => normalize(dir),
+            })
+            .any(|dir| path.starts_with(dir))
+    };
+    let normalized = normalize(Path::new(path)).ok_or_else(denied)?;
+    if !(inside(&normalized, false) || inside(&normalized, true)) {
+        return Err(denied());
+    }
+    let failed = |e: std::io::Error| io_failed(path, e, span);
+    let resolved = if exists {
+        Path::new(path).canonicalize().map_err(failed)?
+    } else {
+        let name = Path::new(path).file_name().ok_or_else(denied)?;
+        let metadata = std::fs::symlink_metadata(path);
+        if metadata.is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Err(denied());
+        }
+        let parent = match Path::new(path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        parent.canonicalize().map_err(failed)?.join(name)
+    };
+    if inside(&resolved, true) {
+        Ok(resolved)
+    } else {
+        Err(denied())
+    }
+}
+
+/// 読み書きに失敗したことを表すエラーを作る
+fn io_failed(path: &str, e: std::io::Error, span: Span) -> EvalError {
+    EvalError::IoFailed {
+        path: path.to_string(),
+        message: e.to_string(),
+        span,
+    }
+}
+
+/// `import` で読み込むファイルが、読むか読み込むことを許したディレクトリの中にあれば正規化したパスを返す
+///
+/// 許していなければ、ファイルがあるかどうかを確かめる前に [`EvalError::IoDenied`] を返す。
+pub(crate) fn resolve_import(path: &Path, span: Span) -> Result<PathBuf, EvalError> {
+    let dirs: Vec<_> = POLICY.with(|p| {
+        let p = p.borrow();
+        p.read.iter().chain(&p.import).cloned().collect()
+    });
+    resolve("import", &path.to_string_lossy(), &dirs, true, span)
+}
+
+/// 許したディレクトリの中のファイルを UTF-8 の文字列として読む
+fn read_file(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let path = expect_path(&args[0], span)?;
+    let resolved = POLICY.with(|p| resolve("read_file", path, &p.borrow().read, true, span))?;
+    let text = std::fs::read_to_string(resolved).map_err(|e| io_failed(path, e, span))?;
+    limits::allocate(text.len(), span)?;
+    Ok(Value::Str(text.into()))
+}
+
+/// 許したディレクトリの中のファイルに、2つ目の引数の文字列を書き込む。ファイルが有れば置き換える
+fn write_file(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let path = expect_path(&args[0], span)?;
+    let Value::Str(content) = &args[1] else {
+        return Err(EvalError::TypeMismatch {
+            expected: "a string",
+            span,
+        });
+    };
+    let resolved = POLICY.with(|p| resolve("write_file", path, &p.borrow().write, false, span))?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // 確かめた後にシンボリックリンクに差し替えられても、その先には書き込まない
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    ))]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, O_NOFOLLOW);
+    options
+        .open(resolved)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| io_failed(path, e, span))?;
+    Ok(Value::Nil)
+}
+
+/// 標準入力を1行読み、行末の改行を除いて返す。入力が終わっていれば `nil` を返す
+fn read_line(_args: &[Value], span: Span) -> Result<Value, EvalError> {
+    if !POLICY.with(|p| p.borrow().stdin) {
+        return Err(EvalError::IoDenied {
+            operation: "read_line",
+            path: None,
+            span,
+        });
+    }
+    let mut line = String::new();
+    let read = std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| io_failed("<stdin>", e, span))?;
+    if read == 0 {
+        return Ok(Value::Nil);
+    }
+    let trimmed = line.strip_suffix('\n').unwrap_or(&line);
+    let trimmed = trimmed.strip_suffix('\r').unwrap_or(trimmed);
+    limits::allocate(trimmed.len(), span)?;
+    Ok(Value::Str(trimmed.into()))
+}
+
+/// 設定したコマンドライン引数を文字列の配列で返す
+fn args(_args: &[Value], _span: Span) -> Result<Value, EvalError> {
+    let args = POLICY.with(|p| {
+        p.borrow()
+            .args
+            .iter()
+            .map(|arg| Value::Str(arg.as_str().into()))
+            .collect::<Vec<_>>()
+    });
+    Ok(Value::array(args))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::Environment;
+    use crate::stdlib::register;
+
+    fn run(input: &str) -> Result<Value, EvalError> {
+        let mut env = Environment::new();
+        register(&mut env);
+        crate::eval::eval_statements(&crate::infix::statements(input).unwrap(), &mut env)
+            .map(Option::unwrap)
+    }
+
+    /// テストごとに別のディレクトリを作る
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ruscal-io-{}-{test}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_deny_all() {
+        set_io_policy(IoPolicy::DENY_ALL);
+        assert_eq!(
+            run("read_file(\"Cargo.toml\")"),
+            Err(EvalError::IoDenied {
+                operation: "read_file",
+                path: Some("Cargo.toml".to_string()),
+                span: Span::new(0, 23)
+            })
+        );
+        assert!(matches!(
+            run("write_file(\"out.txt\", \"x\")"),
+            Err(EvalError::IoDenied { .. })
+        ));
+        assert_eq!(
+            run("read_line()"),
+            Err(EvalError::IoDenied {
+                operation: "read_line",
+                path: None,
+                span: Span::new(0, 11)
+            })
+        );
+        assert_eq!(run("len(args())"), Ok(Value::I64(0)));
+    }
+
+    #[test]
+    fn test_read_and_write() {
+        let dir = temp_dir("read-write");
+        let file = dir.join("data.txt");
+        let file = file.to_str().unwrap();
+        set_io_policy(IoPolicy::DENY_ALL.allow_read(&dir).allow_write(&dir));
+        let program = format!("write_file({file:?}, \"héllo\\n\"); read_file({file:?})");
+        assert_eq!(run(&program), Ok(Value::Str("héllo\n".into())));
+        let missing = format!("read_file({:?})", dir.join("missing.txt").to_str().unwrap());
+        assert!(matches!(run(&missing), Err(EvalError::IoFailed { .. })));
+        // 許したディレクトリの外は `..` を通しても指せない
+        let outside = format!(
+            "read_file({:?})",
+            dir.join("..").join("x").to_str().unwrap()
+        );
+        // 外のファイルは有無にかかわらず拒み、有るかどうかを明かさない
+        assert!(matches!(run(&outside), Err(EvalError::IoDenied { .. })));
+        let escape = format!(
+            "write_file({:?}, \"x\")",
+            dir.join("..").join("x.txt").to_str().unwrap()
+        );
+        assert!(matches!(run(&escape), Err(EvalError::IoDenied { .. })));
+        // 読むことだけを許したディレクトリには書き込めない
+        set_io_policy(IoPolicy::DENY_ALL.allow_read(&dir).with_args(["a", "b"]));
+        assert!(matches!(
+            run(&format!("write_file({file:?}, \"x\")")),
+            Err(EvalError::IoDenied { .. })
+        ));
+        assert_eq!(
+            run("args()").map(|v| v.to_string()),
+            Ok("[\"a\", \"b\"]".into())
+        );
+        set_io_policy(IoPolicy::DENY_ALL);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_through_symlink() {
+        let dir = temp_dir("symlink");
+        let allowed = dir.join("allowed");
+        std::fs::create_dir_all(&allowed).unwrap();
+        let existing = dir.join("existing.txt");
+        std::fs::write(&existing, "original").unwrap();
+        let missing = dir.join("missing.txt");
+        let to_existing = allowed.join("existing");
+        let dangling = allowed.join("dangling");
+        std::os::unix::fs::symlink(&existing, &to_existing).unwrap();
+        std::os::unix::fs::symlink(&missing, &dangling).unwrap();
+        set_io_policy(IoPolicy::DENY_ALL.allow_write(&allowed));
+        for link in [&to_existing, &dangling] {
+            let program = format!("write_file({:?}, \"x\")", link.to_str().unwrap());
+            assert!(
+                matches!(run(&program), Err(EvalError::IoDenied { .. })),
+                "{link:?}"
+            );
+        }
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "original");
+        assert!(!missing.exists());
+        set_io_policy(IoPolicy::DENY_ALL);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1088 @@
+//! 数値を計算する関数を x86-64 の機械語にコンパイルして実行する JIT
+//!
+//! `jit` フィーチャーを有効にすると、x86-64 の Linux で使える。[`Vm`](crate::vm::Vm) が
+//! コンパイル済みの関数を同じ型の引数で [`THRESHOLD`] 回呼び出すと、その命令列を引数の型に
+//! 合わせて機械語に変換し、以降の呼び出しは機械語で実行する。
+//!
+//! 変換できるのは、整数、浮動小数点数、真偽値、`nil` だけを扱い、仮引数と関数の中で定義した
+//! 変数、自身の再帰呼び出しだけを使う関数に限る。それ以外の命令を含む関数や、変数の型が
+//! 定まらない関数は変換せず、今まで通り仮想機械で実行する。変換した関数には副作用が無いので、
+//! 整数の桁あふれ、0での除算、呼び出しの深さの上限に達したときは機械語の実行を捨て、
+//! 仮想機械で最初から実行し直す。そのためエラーは仮想機械と同じものになる。
+//!
+//! 外部のクレートに依存しないよう、Cranelift などは使わずに命令を直接機械語に変換する。
+//! 手数か経過時間の上限を設定している間と、プロファイラーで計測している間は使わない。
+//!
+//! ```
+//! use ruscal_b::ast::{Expr, ExprKind, Span};
+//! use ruscal_b::{compile, jit, statements, Environment, Value, Vm};
+//!
+//! let input = "fn f(n) { n * 2 + 1 }; var t = 0; for i in 0..100 { t = t + f(i) }; t";
+//! let program = Expr::new(ExprKind::Block(statements(input).unwrap()), Span::new(0, input.len()));
+//! let value = Vm::new().run(&compile(&program), &mut Environment::new()).unwrap();
+//! assert_eq!(value, Value::I64(10_000));
+//! assert_eq!(jit::compiled_functions(), 1);
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::{c_int, c_void};
+use std::rc::{Rc, Weak};
+
+use crate::bytecode::{Bytecode, Instruction};
+use crate::eval::{CallGuard, Function, Value};
+use crate::intern::Symbol;
+use crate::limits;
+
+/// 機械語に変換するまでに、同じ型の引数で呼び出す回数
+pub const THRESHOLD: u32 = 10;
+
+/// 状態を持っておく、命令列と引数の型の組の最大の数
+const CAPACITY: usize = 1024;
+
+thread_local! {
+    /// JIT を使うなら `true`
+    static ENABLED: Cell<bool> = const { Cell::new(true) };
+    /// 命令列と引数の型の組ごとの状態
+    static CACHE: RefCell<Cache> = RefCell::new(Cache {
+        entries: HashMap::new(),
+        clock: 0,
+    });
+}
+
+/// 命令列のアドレスと引数の型の組ごとの状態
+///
+/// 命令列は弱い参照で持ち、命令列が捨てられるのを妨げない。状態が [`CAPACITY`] に達したら、
+/// 捨てられた命令列の状態と、それでも多ければ最近使っていない半分を除き、その機械語を解放する。
+struct Cache {
+    entries: HashMap<(usize, Vec<Kind>), Entry>,
+    /// 状態を引くたびに1増やす、最後に使った時期を比べるための値
+    clock: u64,
+}
+
+impl Cache {
+    /// 命令列 `code` を引数の型 `kinds` で呼び出すときの状態
+    fn entry(&mut self, code: &Rc<Bytecode>, kinds: Vec<Kind>) -> &mut Entry {
+        let key = (Rc::as_ptr(code) as usize, kinds);
+        if self.entries.len() >= CAPACITY && !self.entries.contains_key(&key) {
+            self.evict();
+        }
+        self.clock += 1;
+        let entry = self.entries.entry(key).or_insert_with(|| Entry::new(code));
+        entry.used = self.clock;
+        entry
+    }
+
+    /// 捨てられた命令列の状態を除き、それでも多ければ最近使っていない半分を除く
+    fn evict(&mut self) {
+        self.entries.retain(|_, entry| entry.is_live());
+        if self.entries.len() >= CAPACITY / 2 {
+            let mut used: Vec<_> = self.entries.values().map(|entry| entry.used).collect();
+            let middle = used.len() / 2;
+            let (_, &mut median, _) = used.select_nth_unstable(middle);
+            self.entries.retain(|_, entry| entry.used > median);
+        }
+    }
+}
+
+/// このスレッドで JIT を使うかどうかを設定する関数。既定では使う
+pub fn set_jit_enabled(enabled: bool) {
+    ENABLED.with(|e| e.set(enabled));
+}
+
+/// このスレッドで JIT を使うなら `true` を返す関数
+pub fn jit_enabled() -> bool {
+    ENABLED.with(Cell::get)
+}
+
+/// このスレッドで機械語に変換した、命令列と引数の型の組の数
+pub fn compiled_functions() -> usize {
+    CACHE.with(|cache| {
+        cache
+            .borrow()
+            .entries
+            .values()
+            .filter(|entry| matches!(entry.tier, Tier::Compiled(_)))
+            .count()
+    })
+}
+
+/// 命令列と引数の型の組の状態
+struct Entry {
+    /// 命令列
+    ///
+    /// 弱い参照でも割り当ては残るので、キーのアドレスが他の命令列に使い回されることはない。
+    code: Weak<Bytecode>,
+    tier: Tier,
+    /// 最後に使ったときの [`Cache::clock`]
+    used: u64,
+}
+
+impl Entry {
+    fn new(code: &Rc<Bytecode>) -> Self {
+        Self {
+            code: Rc::downgrade(code),
+            tier: Tier::Cold(0),
+            used: 0,
+        }
+    }
+
+    /// 命令列がまだ捨てられていなければ `true`
+    fn is_live(&self) -> bool {
+        self.code.strong_count() > 0
+    }
+}
+
+enum Tier {
+    /// まだ変換していない。呼び出した回数を数える
+    Cold(u32),
+    /// 変換した機械語
+    Compiled(Rc<Native>),
+    /// 変換できない
+    Rejected,
+}
+
+/// コンパイル済みの関数を機械語で呼び出す関数
+///
+/// 機械語で実行できて結果が得られれば、その値を返す。まだ変換していないか、変換できないか、
+/// 実行を途中で捨てたなら `None` を返し、呼び出す側が仮想機械で実行する。
+pub(crate) fn call(function: &Function, code: &Rc<Bytecode>, args: &[Value]) -> Option<Value> {
+    if !jit_enabled() || args.len() != function.params.len() {
+        return None;
+    }
+    let limits = limits::run_limits();
+    if limits.max_steps.is_some() || limits.wall_clock.is_some() {
+        return None;
+    }
+    let kinds = args.iter().map(Kind::of).collect::<Option<Vec<_>>>()?;
+    let native = CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let entry = cache.entry(code, kinds.clone());
+        match &mut entry.tier {
+            Tier::Cold(calls) if *calls + 1 < THRESHOLD => {
+                *calls += 1;
+                None
+            }
+            Tier::Cold(_) => {
+                entry.tier = match compile(code, &function.params, &kinds) {
+                    Some(native) => Tier::Compiled(Rc::new(native)),
+                    None => Tier::Rejected,
+                };
+                match &entry.tier {
+                    Tier::Compiled(native) => Some(native.clone()),
+                    _ => None,
+                }
+            }
+            Tier::Compiled(native) => Some(native.clone()),
+            Tier::Rejected => None,
+        }
+    })?;
+    // 呼び出す関数の名前が、今も自身を指していることを確かめる
+    let recursive = native.callees.iter().all(|&name| {
+        matches!(function.env.get(name), Some(Value::Fn(f)) if std::ptr::eq(Rc::as_ptr(&f), function))
+    });
+    if !recursive {
+        return None;
+    }
+    let args: Vec<_> = args.iter().map(bits).collect();
+    let budget = CallGuard::remaining(native.frame) + 1;
+    native.run(&args, budget).map(|bits| native.ret.value(bits))
+}
+
+/// 機械語で扱う値の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Int,
+    Float,
+    Bool,
+    Nil,
+    /// 再帰呼び出しする自身の関数。値は持たない
+    Callee,
+}
+
+impl Kind {
+    fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::I64(_) => Some(Self::Int),
+            Value::F64(_) => Some(Self::Float),
+            Value::Bool(_) => Some(Self::Bool),
+            Value::Nil => Some(Self::Nil),
+            _ => None,
+        }
+    }
+
+    fn is_number(self) -> bool {
+        matches!(self, Self::Int | Self::Float)
+    }
+
+    /// 機械語の64ビットの値を、この種類の値に戻す
+    fn value(self, bits: i64) -> Value {
+        match self {
+            Self::Int => Value::I64(bits),
+            Self::Float => Value::F64(f64::from_bits(bits as u64)),
+            Self::Bool => Value::Bool(bits != 0),
+            Self::Nil | Self::Callee => Value::Nil,
+        }
+    }
+}
+
+/// 値を機械語で扱う64ビットの値にする。[`Kind::of`] が種類を返す値だけを渡す
+fn bits(value: &Value) -> i64 {
+    match value {
+        Value::I64(n) => *n,
+        Value::F64(n) => n.to_bits() as i64,
+        Value::Bool(b) => i64::from(*b),
+        _ => 0,
+    }
+}
+
+/// ある命令を実行する直前の状態
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    /// スタックの値の種類
+    stack: Vec<Kind>,
+    /// スコープごとの、変数の名前と局所変数の番号
+    scopes: Vec<Vec<(Symbol, usize)>>,
+}
+
+impl State {
+    fn lookup(&self, name: Symbol) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| {
+            scope
+                .iter()
+                .rev()
+                .find(|(n, _)| *n == name)
+                .map(|&(_, id)| id)
+        })
+    }
+}
+
+/// 変換できない理由
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reject {
+    /// 再帰呼び出しの戻り値の種類を仮定しないと決まらない
+    NeedsReturn,
+    /// 変換できない命令か、種類の定まらない値がある
+    Unsupported,
+}
+
+/// 命令列の値の種類を調べた結果
+#[derive(Debug)]
+struct Plan {
+    /// 命令ごとの、実行する直前の状態。実行されない命令は `None`
+    states: Vec<Option<State>>,
+    /// 局所変数の種類。仮引数を先に並べる
+    locals: Vec<Kind>,
+    /// `Define` の位置ごとの、定義する局所変数の番号
+    defines: HashMap<usize, usize>,
+    /// 戻り値の種類
+    ret: Kind,
+    /// 再帰呼び出しに使う関数の名前
+    callees: Vec<Symbol>,
+    /// スタックの最大の深さ
+    depth: usize,
+}
+
+/// 命令列を先頭からたどり、各命令の時点の値の種類を決める関数
+///
+/// 合流する位置では、どの経路から来てもスタックと変数の種類が同じでなければならない。
+/// `ret` は再帰呼び出しの戻り値として仮定する種類で、実際の戻り値と一致しなければ拒む。
+fn analyze(
+    code: &Bytecode,
+    params: &[Symbol],
+    kinds: &[Kind],
+    ret: Option<Kind>,
+) -> Result<Plan, Reject> {
+    use Instruction::*;
+    let unsupported = Reject::Unsupported;
+    let mut plan = Plan {
+        states: vec![None; code.code.len()],
+        locals: kinds.to_vec(),
+        defines: HashMap::new(),
+        ret: Kind::Nil,
+        callees: Vec::new(),
+        depth: 0,
+    };
+    let mut returned = None;
+    let entry = State {
+        stack: Vec::new(),
+        scopes: vec![params.iter().copied().zip(0..).collect()],
+    };
+    let mut work = vec![(0, entry)];
+    while let Some((pc, state)) = work.pop() {
+        let Some(slot) = plan.states.get_mut(pc) else {
+            return Err(unsupported);
+        };
+        match slot {
+            Some(existing) if *existing == state => continue,
+            Some(_) => return Err(unsupported),
+            None => *slot = Some(state.clone()),
+        }
+        let mut s = state;
+        let top = s.stack.last().copied();
+        let mut next = vec![pc + 1];
+        match code.code[pc] {
+            Constant(index) => {
+                let kind = code.constants.get(index as usize).and_then(Kind::of);
+                s.stack.push(kind.ok_or(Reject::Unsupported)?);
+            }
+            Pop => {
+                s.stack.pop();
+            }
+            Dup => s.stack.push(top.ok_or(Reject::Unsupported)?),
+            Load(name) => match s.lookup(name) {
+                Some(id) => s.stack.push(plan.locals[id]),
+                None => {
+                    if !plan.callees.contains(&name) {
+                        plan.callees.push(name);
+                    }
+                    s.stack.push(Kind::Callee);
+                }
+            },
+            Define(name) => {
+                let kind = top
+                    .filter(|&k| k != Kind::Callee)
+                    .ok_or(Reject::Unsupported)?;
+                let id = *plan.defines.entry(pc).or_insert_with(|| {
+                    plan.locals.push(kind);
+                    plan.locals.len() - 1
+                });
+                if plan.locals[id] != kind {
+                    return Err(unsupported);
+                }
+                s.scopes
+                    .last_mut()
+                    .ok_or(Reject::Unsupported)?
+                    .push((name, id));
+            }
+            Assign(name) => {
+                let id = s.lookup(name).ok_or(Reject::Unsupported)?;
+                if top != Some(plan.locals[id]) {
+                    return Err(unsupported);
+                }
+            }
+            ExpectNumber if top.is_some_and(Kind::is_number) => {}
+            ExpectInteger if top == Some(Kind::Int) => {}
+            Add | Sub | Mul | Div => {
+                let (a, b) = pop2(&mut s.stack)?;
+                if !a.is_number() || !b.is_number() {
+                    return Err(unsupported);
+                }
+                let int = a == Kind::Int && b == Kind::Int;
+                s.stack.push(if int { Kind::Int } else { Kind::Float });
+            }
+            // 浮動小数点数の剰余を求める命令は無いので、整数の剰余だけを変換する
+            Rem => {
+                let (a, b) = pop2(&mut s.stack)?;
+                if a != Kind::Int || b != Kind::Int {
+                    return Err(unsupported);
+                }
+                s.stack.push(Kind::Int);
+            }
+            AddConstant(index) => {
+                let a = s.stack.pop().filter(|k| k.is_number());
+                let b = code.constants.get(index as usize).and_then(Kind::of);
+                let (Some(a), Some(b)) = (a, b.filter(|k| k.is_number())) else {
+                    return Err(unsupported);
+                };
+                // 生成する機械語は定数をスタックの次の位置に置く
+                plan.depth = plan.depth.max(s.stack.len() + 2);
+                let int = a == Kind::Int && b == Kind::Int;
+                s.stack.push(if int { Kind::Int } else { Kind::Float });
+            }
+            Lt | Le | Gt | Ge => {
+                let (a, b) = pop2(&mut s.stack)?;
+                if a != b || !a.is_number() {
+                    return Err(unsupported);
+                }
+                s.stack.push(Kind::Bool);
+            }
+            Eq | Ne => {
+                let (a, b) = pop2(&mut s.stack)?;
+                // 整数と浮動小数点数は値で比べるので、種類が違っても等しくなり得る
+                if a == Kind::Callee
+                    || b == Kind::Callee
+                    || (a != b && a.is_number() && b.is_number())
+                {
+                    return Err(unsupported);
+                }
+                s.stack.push(Kind::Bool);
+            }
+            Neg if top.is_some_and(Kind::is_number) => {}
+            Not | Truthy if top.is_some_and(|k| k != Kind::Callee) => {
+                s.stack.pop();
+                s.stack.push(Kind::Bool);
+            }
+            Jump(target) => next = vec![target as usize],
+            JumpIfFalse(target) => {
+                if s.stack.pop().is_none_or(|k| k == Kind::Callee) {
+                    return Err(unsupported);
+                }
+                next.push(target as usize);
+            }
+            ExpectFunction(argc) => {
+                let callee = s.stack.len().checked_sub(argc as usize + 1);
+                if callee.map(|i| s.stack[i]) != Some(Kind::Callee) {
+                    return Err(unsupported);
+                }
+            }
+            Call { argc, .. } => {
+                let base = s.stack.len().checked_sub(argc as usize + 1);
+                let Some(base) = base.filter(|_| argc as usize == params.len()) else {
+                    return Err(unsupported);
+                };
+                if s.stack[base] != Kind::Callee || s.stack[base + 1..] != *kinds {
+                    return Err(unsupported);
+                }
+                s.stack.truncate(base);
+                s.stack.push(ret.ok_or(Reject::NeedsReturn)?);
+            }
+            ForNext(target) => {
+                let len = s.stack.len();
+                if len < 2 || s.stack[len - 2..] != [Kind::Int, Kind::Int] {
+                    return Err(unsupported);
+                }
+                let done = State {
+                    stack: s.stack.clone(),
+                    scopes: s.scopes.clone(),
+                };
+                work.push((target as usize, done));
+                next = vec![pc + 1];
+                s.stack.push(Kind::Int);
+            }
+            PushScope => s.scopes.push(Vec::new()),
+            PopScope => {
+                if s.scopes.len() < 2 {
+                    return Err(unsupported);
+                }
+                s.scopes.pop();
+            }
+            Return => {
+                let kind = top
+                    .filter(|&k| k != Kind::Callee)
+                    .ok_or(Reject::Unsupported)?;
+                if returned.is_some_and(|r| r != kind) {
+                    return Err(unsupported);
+                }
+                returned = Some(kind);
+                next.clear();
+            }
+            _ => return Err(unsupported),
+        }
+        plan.depth = plan.depth.max(s.stack.len());
+        for target in next {
+            work.push((target, s.clone()));
+        }
+    }
+    plan.ret = returned.ok_or(Reject::Unsupported)?;
+    if ret.is_some_and(|r| r != plan.ret) {
+        return Err(unsupported);
+    }
+    Ok(plan)
+}
+
+/// スタックの先頭の2つの種類を取り出す
+fn pop2(stack: &mut Vec<Kind>) -> Result<(Kind, Kind), Reject> {
+    let b = stack.pop().ok_or(Reject::Unsupported)?;
+    let a = stack.pop().ok_or(Reject::Unsupported)?;
+    Ok((a, b))
+}
+
+/// 命令列を引数の種類に合わせて機械語に変換する関数。変換できなければ `None` を返す
+fn compile(code: &Bytecode, params: &[Symbol], kinds: &[Kind]) -> Option<Native> {
+    let plan = match analyze(code, params, kinds, None) {
+        Ok(plan) => plan,
+        // 再帰呼び出しの戻り値は、仮定した種類と実際の戻り値が一致するものを探す
+        Err(Reject::NeedsReturn) => [Kind::Int, Kind::Float, Kind::Bool, Kind::Nil]
+            .into_iter()
+            .find_map(|ret| analyze(code, params, kinds, Some(ret)).ok())?,
+        Err(Reject::Unsupported) => return None,
+    };
+    let slots = plan.locals.len() + plan.depth;
+    // 戻り先、保存した rbp、局所変数とスタック、引数
+    let frame = 8 * (2 + slots + params.len());
+    let bytes = generate(code, &plan, params.len());
+    Native::new(&bytes, plan.ret, plan.callees, frame)
+}
+
+/// 汎用レジスタの番号
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+
+/// 機械語を書き出す領域
+struct Asm {
+    bytes: Vec<u8>,
+    /// ラベルごとの位置。まだ決まっていなければ `None`
+    labels: Vec<Option<usize>>,
+    /// 後で埋める32ビットの相対位置と、その飛び先のラベル
+    fixups: Vec<(usize, usize)>,
+}
+
+impl Asm {
+    fn new(labels: usize) -> Self {
+        Self {
+            bytes: Vec::new(),
+            labels: vec![None; labels],
+            fixups: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// 新しいラベル
+    fn label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn bind(&mut self, label: usize) {
+        self.labels[label] = Some(self.bytes.len());
+    }
+
+    /// `opcode` に続けて、ラベルへの32ビットの相対位置を書く。ジャンプと呼び出しに使う
+    fn jump(&mut self, opcode: &[u8], label: usize) {
+        self.emit(opcode);
+        self.fixups.push((self.bytes.len(), label));
+        self.emit(&[0; 4]);
+    }
+
+    /// `opcode` に続けて、レジスタか拡張した命令の番号 `reg` と `[rbp + disp]` を指す ModRM を書く
+    fn mem(&mut self, opcode: &[u8], reg: u8, disp: i32) {
+        self.emit(opcode);
+        self.emit(&[0x80 | (reg & 7) << 3 | 5]);
+        self.emit(&disp.to_le_bytes());
+    }
+
+    /// `mov reg, [rbp + disp]`
+    fn load(&mut self, reg: u8, disp: i32) {
+        self.mem(&[0x48, 0x8b], reg, disp);
+    }
+
+    /// `mov [rbp + disp], reg`
+    fn store(&mut self, disp: i32, reg: u8) {
+        self.mem(&[0x48, 0x89], reg, disp);
+    }
+
+    /// `mov reg, imm64`
+    fn mov_imm(&mut self, reg: u8, imm: i64) {
+        self.emit(&[0x48, 0xb8 + reg]);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// 種類が `kind` の `[rbp + disp]` の値を、浮動小数点数にして `xmm` に読む
+    fn load_float(&mut self, xmm: u8, kind: Kind, disp: i32) {
+        match kind {
+            // cvtsi2sd
+            Kind::Int => self.mem(&[0xf2, 0x48, 0x0f, 0x2a], xmm, disp),
+            // movsd
+            _ => self.mem(&[0xf2, 0x0f, 0x10], xmm, disp),
+        }
+    }
+
+    /// 種類が `a` と `b` の `[rbp + da]` と `[rbp + db]` の四則演算の結果を `[rbp + da]` に書く
+    ///
+    /// 整数の桁あふれや0による除算では `bail` に飛び、仮想機械でエラーにする。
+    fn arithmetic(
+        &mut self,
+        op: Instruction,
+        (a, da): (Kind, i32),
+        (b, db): (Kind, i32),
+        bail: usize,
+    ) {
+        use Instruction::*;
+        if a == Kind::Int && b == Kind::Int {
+            self.load(RAX, da);
+            match op {
+                Add => self.mem(&[0x48, 0x03], RAX, db),
+                Sub => self.mem(&[0x48, 0x2b], RAX, db),
+                Mul => self.mem(&[0x48, 0x0f, 0xaf], RAX, db),
+                _ => {
+                    // 0で割るか、最小の値を -1 で割るなら仮想機械でエラーにする
+                    let divide = self.label();
+                    self.load(RCX, db);
+                    self.emit(&[0x48, 0x85, 0xc9]);
+                    self.jump(&[0x0f, 0x84], bail);
+                    self.emit(&[0x48, 0x83, 0xf9, 0xff]);
+                    self.jump(&[0x0f, 0x85], divide);
+                    self.mov_imm(RDX, i64::MIN);
+                    self.emit(&[0x48, 0x39, 0xd0]);
+                    self.jump(&[0x0f, 0x84], bail);
+                    self.bind(divide);
+                    self.emit(&[0x48, 0x99, 0x48, 0xf7, 0xf9]);
+                    if op == Rem {
+                        // mov rax, rdx
+                        self.emit(&[0x48, 0x89, 0xd0]);
+                    }
+                }
+            }
+            if !matches!(op, Div | Rem) {
+                self.jump(&[0x0f, 0x80], bail);
+            }
+            self.store(da, RAX);
+        } else {
+            self.load_float(0, a, da);
+            self.load_float(1, b, db);
+            let op = match op {
+                Add => 0x58,
+                Sub => 0x5c,
+                Mul => 0x59,
+                _ => 0x5e,
+            };
+            self.emit(&[0xf2, 0x0f, op, 0xc1]);
+            self.mem(&[0xf2, 0x0f, 0x11], 0, da);
+        }
+    }
+
+    /// 種類が `kind` の `[rbp + disp]` の値の真偽を `al` に求める
+    fn truthy(&mut self, kind: Kind, disp: i32) {
+        match kind {
+            Kind::Int => {
+                // cmp qword [rbp + disp], 0; setne al
+                self.mem(&[0x48, 0x83], 7, disp);
+                self.emit(&[0x00, 0x0f, 0x95, 0xc0]);
+            }
+            Kind::Float => {
+                // 0 と NaN は偽。ucomisd は NaN との比較で ZF を立てる
+                self.load_float(0, kind, disp);
+                self.emit(&[
+                    0x66, 0x0f, 0x57, 0xc9, 0x66, 0x0f, 0x2e, 0xc1, 0x0f, 0x95, 0xc0,
+                ]);
+            }
+            Kind::Bool => self.load(RAX, disp),
+            Kind::Nil | Kind::Callee => self.emit(&[0x31, 0xc0]),
+        }
+    }
+
+    /// 飛び先を埋めた機械語
+    fn finish(mut self) -> Vec<u8> {
+        for &(at, label) in &self.fixups {
+            let target = self.labels[label].expect("every used label is bound");
+            let rel = target as i64 - (at as i64 + 4);
+            let rel = i32::try_from(rel).expect("code fits in 2 GiB");
+            self.bytes[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        self.bytes
+    }
+}
+
+/// `[rbp + disp]` の `disp`。局所変数とスタックの値を、番号の順に rbp の下に並べる
+fn disp(slot: usize) -> i32 {
+    -8 * (slot as i32 + 1)
+}
+
+/// 調べた結果に従って命令列を機械語にする関数
+///
+/// 先頭は Rust から呼ぶ入り口で、`extern "sysv64" fn(*mut Context, *const i64) -> i64` の形をとる。
+/// 入り口は呼び出し先が保存するレジスタを退避し、r15 に [`Context`]、r14 に残りの呼び出しの数を
+/// 置いて、引数を積んでから関数の本体を呼ぶ。本体は途中で実行を捨てるとき、`status` に1を書いて
+/// 入り口で保存したスタックの位置に戻る。本体は引数をスタックで受け取り、戻り値を rax で返す。
+fn generate(code: &Bytecode, plan: &Plan, argc: usize) -> Vec<u8> {
+    use Instruction::*;
+    let n = code.code.len();
+    let mut asm = Asm::new(n + 1);
+    let (body, bail) = (asm.label(), asm.label());
+    // 退避して r15 と r14 を設定し、スタックの位置を保存する
+    asm.emit(&[0x53, 0x55, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+    asm.emit(&[0x49, 0x89, 0xff, 0x4d, 0x8b, 0x77, 0x10, 0x49, 0x89, 0x27]);
+    for i in 0..argc {
+        // push qword [rsi + 8 * i]
+        asm.emit(&[0xff, 0xb6]);
+        asm.emit(&(8 * i as i32).to_le_bytes());
+    }
+    asm.jump(&[0xe8], body);
+    let epilogue = [
+        0x49, 0x8b, 0x27, 0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5d, 0x5b, 0xc3,
+    ];
+    asm.emit(&epilogue);
+    asm.bind(bail);
+    // mov qword [r15 + 8], 1
+    asm.emit(&[0x49, 0xc7, 0x47, 0x08, 0x01, 0x00, 0x00, 0x00]);
+    asm.emit(&epilogue);
+
+    asm.bind(body);
+    // dec r14; js bail; push rbp; mov rbp, rsp; sub rsp, 8 * slots
+    asm.emit(&[0x49, 0xff, 0xce]);
+    asm.jump(&[0x0f, 0x88], bail);
+    asm.emit(&[0x55, 0x48, 0x89, 0xe5, 0x48, 0x81, 0xec]);
+    let locals = plan.locals.len();
+    asm.emit(&(8 * (locals + plan.depth) as i32).to_le_bytes());
+    for i in 0..argc {
+        asm.load(RAX, 16 + 8 * (argc - 1 - i) as i32);
+        asm.store(disp(i), RAX);
+    }
+    let stack = |i: usize| disp(locals + i);
+    for (pc, instruction) in code.code.iter().enumerate() {
+        asm.bind(pc);
+        let Some(state) = &plan.states[pc] else {
+            continue;
+        };
+        let d = state.stack.len();
+        match *instruction {
+            Constant(index) => {
+                asm.mov_imm(RAX, bits(&code.constants[index as usize]));
+                asm.store(stack(d), RAX);
+            }
+            Dup => {
+                asm.load(RAX, stack(d - 1));
+                asm.store(stack(d), RAX);
+            }
+            Load(name) => {
+                if let Some(id) = state.lookup(name) {
+                    asm.load(RAX, disp(id));
+                    asm.store(stack(d), RAX);
+                }
+            }
+            Define(_) | Assign(_) => {
+                let id = match *instruction {
+                    Define(_) => plan.defines[&pc],
+                    Assign(name) => state.lookup(name).expect("analyzed an assignment"),
+                    _ => unreachable!(),
+                };
+                asm.load(RAX, stack(d - 1));
+                asm.store(disp(id), RAX);
+            }
+            Add | Sub | Mul | Div | Rem => {
+                let (a, b) = (state.stack[d - 2], state.stack[d - 1]);
+                asm.arithmetic(*instruction, (a, stack(d - 2)), (b, stack(d - 1)), bail);
+            }
+            AddConstant(index) => {
+                // 定数を空いている次の位置に置き、`Constant` と `Add` の並びと同じように計算する
+                let constant = &code.constants[index as usize];
+                let b = Kind::of(constant).expect("analyzed a numeric constant");
+                asm.mov_imm(RAX, bits(constant));
+                asm.store(stack(d), RAX);
+                asm.arithmetic(Add, (state.stack[d - 1], stack(d - 1)), (b, stack(d)), bail);
+            }
+            Lt | Le | Gt | Ge | Eq | Ne => {
+                let (a, b) = (state.stack[d - 2], state.stack[d - 1]);
+                let (da, db) = (stack(d - 2), stack(d - 1));
+                let equal = *instruction == Eq;
+                if a != b {
+                    asm.mov_imm(RAX, i64::from(!equal));
+                } else if a == Kind::Nil {
+                    asm.mov_imm(RAX, i64::from(equal));
+                } else if a == Kind::Float {
+                    asm.load_float(0, a, da);
+                    asm.load_float(1, b, db);
+                    // ucomisd の後に条件を al に求める。NaN との比較は `!=` だけが真になる
+                    asm.emit(match *instruction {
+                        Lt => &[0x66, 0x0f, 0x2e, 0xc8, 0x0f, 0x97, 0xc0],
+                        Le => &[0x66, 0x0f, 0x2e, 0xc8, 0x0f, 0x93, 0xc0],
+                        Gt => &[0x66, 0x0f, 0x2e, 0xc1, 0x0f, 0x97, 0xc0],
+                        Ge => &[0x66, 0x0f, 0x2e, 0xc1, 0x0f, 0x93, 0xc0],
+                        // sete al; setnp cl; and al, cl
+                        Eq => &[
+                            0x66, 0x0f, 0x2e, 0xc1, 0x0f, 0x94, 0xc0, 0x0f, 0x9b, 0xc1, 0x20, 0xc8,
+                        ],
+                        // setne al; setp cl; or al, cl
+                        _ => &[
+                            0x66, 0x0f, 0x2e, 0xc1, 0x0f, 0x95, 0xc0, 0x0f, 0x9a, 0xc1, 0x08, 0xc8,
+                        ],
+                    });
+                    asm.emit(&[0x0f, 0xb6, 0xc0]);
+                } else {
+                    let set = match *instruction {
+                        Lt => 0x9c,
+                        Le => 0x9e,
+                        Gt => 0x9f,
+                        Ge => 0x9d,
+                        Eq => 0x94,
+                        _ => 0x95,
+                    };
+                    asm.load(RAX, da);
+                    asm.mem(&[0x48, 0x3b], RAX, db);
+                    asm.emit(&[0x0f, set, 0xc0, 0x0f, 0xb6, 0xc0]);
+                }
+                asm.store(da, RAX);
+            }
+            Neg => {
+                asm.load(RAX, stack(d - 1));
+                if state.stack[d - 1] == Kind::Int {
+                    asm.emit(&[0x48, 0xf7, 0xd8]);
+                    asm.jump(&[0x0f, 0x80], bail);
+                } else {
+                    // 符号のビットを反転する
+                    asm.emit(&[0x48, 0x0f, 0xba, 0xf8, 0x3f]);
+                }
+                asm.store(stack(d - 1), RAX);
+            }
+            Not | Truthy => {
+                asm.truthy(state.stack[d - 1], stack(d - 1));
+                asm.emit(&[0x0f, 0xb6, 0xc0]);
+                if *instruction == Not {
+                    asm.emit(&[0x83, 0xf0, 0x01]);
+                }
+                asm.store(stack(d - 1), RAX);
+            }
+            Jump(target) => asm.jump(&[0xe9], target as usize),
+            JumpIfFalse(target) => {
+                asm.truthy(state.stack[d - 1], stack(d - 1));
+                asm.emit(&[0x84, 0xc0]);
+                asm.jump(&[0x0f, 0x84], target as usize);
+            }
+            Call { argc, .. } => {
+                let argc = argc as usize;
+                for i in d - argc..d {
+                    // push qword [rbp + disp]
+                    asm.mem(&[0xff], 6, stack(i));
+                }
+                asm.jump(&[0xe8], body);
+                asm.emit(&[0x48, 0x81, 0xc4]);
+                asm.emit(&(8 * argc as i32).to_le_bytes());
+                asm.store(stack(d - argc - 1), RAX);
+            }
+            ForNext(target) => {
+                // 現在の値が終わりの値以上なら抜け、そうでなければ1増やしてから元の値を積む
+                asm.load(RAX, stack(d - 2));
+                asm.mem(&[0x48, 0x3b], RAX, stack(d - 1));
+                asm.jump(&[0x0f, 0x8d], target as usize);
+                asm.emit(&[0x48, 0x8d, 0x48, 0x01]);
+                asm.store(stack(d - 2), RCX);
+                asm.store(stack(d), RAX);
+            }
+            Return => {
+                // mov rax, [top]; inc r14; leave; ret
+                asm.load(RAX, stack(d - 1));
+                asm.emit(&[0x49, 0xff, 0xc6, 0xc9, 0xc3]);
+            }
+            _ => {}
+        }
+    }
+    asm.bind(n);
+    asm.finish()
+}
+
+/// 機械語の入り口に渡す状態
+#[repr(C)]
+struct Context {
+    /// 入り口で保存したスタックの位置
+    entry_rsp: u64,
+    /// 実行を途中で捨てたら1
+    status: u64,
+    /// 入れ子にできる呼び出しの残りの数
+    budget: u64,
+}
+
+/// 機械語の入り口の型
+type EntryFn = unsafe extern "sysv64" fn(*mut Context, *const i64) -> i64;
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_PRIVATE: c_int = 2;
+const MAP_ANONYMOUS: c_int = 0x20;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+/// 実行できるメモリに置いた、変換済みの関数
+struct Native {
+    memory: *mut c_void,
+    len: usize,
+    /// 戻り値の種類
+    ret: Kind,
+    /// 自身を指していなければならない関数の名前
+    callees: Vec<Symbol>,
+    /// 1回の呼び出しが使うスタックのバイト数
+    frame: usize,
+}
+
+impl Native {
+    /// 機械語を書き込み、実行できるようにする。メモリを確保できなければ `None` を返す
+    fn new(code: &[u8], ret: Kind, callees: Vec<Symbol>, frame: usize) -> Option<Self> {
+        let len = code.len().max(1);
+        // SAFETY: 新しく匿名のメモリを割り当てるだけで、既存のメモリには触れない
+        let memory = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if memory as isize == -1 {
+            return None;
+        }
+        let native = Self {
+            memory,
+            len,
+            ret,
+            callees,
+            frame,
+        };
+        // SAFETY: `memory` は書き込める `len` バイトの領域で、`code` とは重ならない
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), memory.cast::<u8>(), code.len()) };
+        // SAFETY: `memory` は `mmap` で割り当てた `len` バイトの領域
+        if unsafe { mprotect(memory, len, PROT_READ | PROT_EXEC) } != 0 {
+            return None;
+        }
+        Some(native)
+    }
+
+    /// 引数を渡して実行する。途中で実行を捨てたら `None` を返す
+    fn run(&self, args: &[i64], budget: usize) -> Option<i64> {
+        let mut context = Context {
+            entry_rsp: 0,
+            status: 0,
+            budget: budget as u64,
+        };
+        // SAFETY: `memory` の先頭は `generate` が書いた `EntryFn` の形の入り口で、
+        // 引数の数は変換したときの仮引数の数と同じ
+        let result = unsafe {
+            let entry = std::mem::transmute::<*mut c_void, EntryFn>(self.memory);
+            entry(&mut context, args.as_ptr())
+        };
+        (context.status == 0).then_some(result)
+    }
+}
+
+impl Drop for Native {
+    fn drop(&mut self) {
+        // SAFETY: `memory` は `mmap` で割り当てた `len` バイトの領域で、他から参照されていない
+        unsafe { munmap(self.memory, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{Expr, ExprKind, Span};
+    use crate::bytecode::compile;
+    use crate::env::Environment;
+    use crate::eval::EvalError;
+    use crate::infix::statements;
+    use crate::peephole::optimize;
+    use crate::stdlib::register;
+    use crate::vm::Vm;
+
+    /// JIT を使うかどうかを設定して、中置記法のプログラムを仮想機械で実行する
+    fn run(input: &str, enabled: bool) -> Result<Value, EvalError> {
+        run_code(&compile_str(input), enabled)
+    }
+
+    fn compile_str(input: &str) -> Bytecode {
+        compile(&Expr::new(
+            ExprKind::Block(statements(input).unwrap()),
+            Span::new(0, input.len()),
+        ))
+    }
+
+    fn run_code(code: &Bytecode, enabled: bool) -> Result<Value, EvalError> {
+        let mut env = Environment::new();
+        register(&mut env);
+        set_jit_enabled(enabled);
+        let result = Vm::new().run(code, &mut env);
+        set_jit_enabled(true);
+        result
+    }
+
+    #[test]
+    fn test_same_results() {
+        let programs = [
+            "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }; fib(20)",
+            "fn sum(n) { var t = 0.0; for i in 0..n { t = t + i * 0.5 }; t }; \
+             var r = 0.0; for k in 0..20 { r = r + sum(k * 10) }; r",
+            "fn f(x, y) { var q = x / y; if q == 3 && !(x < 0) { -q } else { q } }; \
+             var t = 0; for i in 1..50 { t = t + f(i * 7, 2) }; t",
+            "fn g(x) { x / 3.0 > 1.5 || x != x }; var t = 0; for i in 0..50 { if g(i * 1.0) { t = t + 1 } }; t",
+            "fn h(n) { if n == nil { 0 } else { n } }; var t = 0; for i in 0..20 { t = t + h(i) }; t",
+            "fn r(x, y) { x % y + x / y }; var t = 0; for i in 1..50 { t = t + r(i * 7, -5) }; t",
+            "fn c(n) { var t = 0.0; for i in 0..n { t = t + 1.5 }; t + 1 }; \
+             var t = 0; for i in 0..20 { t = c(i) }; t",
+        ];
+        for program in programs {
+            let before = compiled_functions();
+            assert_eq!(run(program, true), run(program, false), "{program}");
+            assert!(compiled_functions() > before, "{program}");
+            // 覗き穴最適化でまとめた命令も変換できる
+            let mut code = compile_str(program);
+            optimize(&mut code);
+            let before = compiled_functions();
+            assert_eq!(run_code(&code, true), run(program, false), "{program}");
+            assert!(compiled_functions() > before, "{program}");
+        }
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        let entries = || CACHE.with(|cache| cache.borrow().entries.len());
+        let touch = |code: &Rc<Bytecode>| {
+            CACHE.with(|cache| {
+                cache.borrow_mut().entry(code, vec![Kind::Int]);
+            })
+        };
+        // 捨てた命令列の状態は、上限に達したときに除く
+        for _ in 0..CAPACITY * 2 {
+            touch(&Rc::new(Bytecode::default()));
+        }
+        assert!(entries() <= CAPACITY, "{}", entries());
+        // 生きている命令列が多すぎれば、最近使ったものを残して除く
+        let live: Vec<_> = (0..CAPACITY * 2)
+            .map(|_| Rc::new(Bytecode::default()))
+            .collect();
+        live.iter().for_each(touch);
+        assert!(entries() <= CAPACITY, "{}", entries());
+        let last = live.last().unwrap();
+        let key = (Rc::as_ptr(last) as usize, vec![Kind::Int]);
+        assert!(CACHE.with(|cache| cache.borrow().entries.contains_key(&key)));
+        // 命令列は弱い参照で持つので、状態が残っていても捨てられる
+        let weak = Rc::downgrade(last);
+        drop(live);
+        assert_eq!(weak.strong_count(), 0);
+    }
+
+    #[test]
+    fn test_bailout() {
+        // 桁あふれと0での除算は、仮想機械で実行し直して同じエラーにする
+        let overflow = "fn fact(n) { if n < 2 { 1 } else { n * fact(n - 1) } }; \
+                        var t = 0; for i in 0..20 { t = fact(i) }; fact(30)";
+        assert!(matches!(
+            run(overflow, true),
+            Err(EvalError::IntegerOverflow { .. })
+        ));
+        assert_eq!(run(overflow, true), run(overflow, false));
+        let divide = "fn d(n) { 100 / n }; var t = 0; for i in 1..20 { t = t + d(i) }; d(0)";
+        assert_eq!(run(divide, true), run(divide, false));
+        let remainder = "fn r(n) { 100 % n }; var t = 0; for i in 1..20 { t = t + r(i) }; r(0)";
+        assert!(matches!(
+            run(remainder, true),
+            Err(EvalError::DivisionByZero { .. })
+        ));
+        assert_eq!(run(remainder, true), run(remainder, false));
+        let deep = "fn down(n) { if n == 0 { 0 } else { down(n - 1) + 1 } }; \
+                    var t = 0; for i in 0..20 { t = down(i) }; down(1000000)";
+        assert!(matches!(
+            run(deep, true),
+            Err(EvalError::StackOverflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        // 標準関数を呼ぶ関数と、変数の型が変わる関数は変換しない
+        let before = compiled_functions();
+        let program = "fn f(n) { len([n]) + n }; fn g(n) { var x = 0; if n > 3 { x = 0.5 }; x }; \
+                       var t = 0; for i in 0..30 { t = t + f(i) + g(i) }; t";
+        assert_eq!(run(program, true), run(program, false));
+        assert_eq!(compiled_functions(), before);
+        assert_eq!(
+            analyze(&Bytecode::default(), &[], &[], None).unwrap_err(),
+            Reject::Unsupported
+        );
+    }
+}
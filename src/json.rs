@@ -0,0 +1,1195 @@
+//! 解析結果を外部のツールに渡すためのJSON表現
+//!
+//! 外部クレートに依存しないよう、必要な分だけを手書きで実装している。
+//! 列挙型は serde の既定の表現に合わせて `{"Variant": 値}` の形で書き出し、
+//! 構造体はフィールド名をキーとするオブジェクトにする。
+//! 書き出したJSONは [`Json::parse`] と [`FromJson`] で読み戻せる。
+
+use std::fmt::{self, Write};
+
+use crate::ast::{
+    BinOp, Expr, ExprKind, Keyword, MatchArm, OwnedToken, OwnedTokenTree, Pattern, Signature, Span,
+    Statement, Template, Token, TokenTree, TypeName, UnOp,
+};
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::intern::Symbol;
+
+/// JSONの値
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// キーの順序を保つため、連想配列ではなく組のリストで持つ
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// 1つのキーだけを持つオブジェクトを作る
+    pub fn tagged(tag: &str, value: Json) -> Self {
+        Self::Object(vec![(tag.to_string(), value)])
+    }
+
+    /// JSONの文字列を解析する関数
+    ///
+    /// # 引数
+    /// * `input` - 解析するJSONの文字列。前後の空白は無視する
+    ///
+    /// # 戻り値
+    /// * `Result<Json, JsonError>` - 解析したJSONの値、または構文エラー
+    pub fn parse(input: &str) -> Result<Json, JsonError> {
+        let mut parser = JsonParser { input, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != input.len() {
+            return Err(parser.error());
+        }
+        Ok(value)
+    }
+
+    /// オブジェクトのキーに対応する値を得る
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Self::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+/// JSONの解析や変換のエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError {
+    /// JSONとして正しくない文字列
+    Syntax { offset: usize },
+    /// JSONとしては正しいが、変換先の型が期待する形と異なる
+    Shape { expected: &'static str },
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax { offset } => write!(f, "invalid JSON at byte {offset}"),
+            Self::Shape { expected } => write!(f, "expected {expected} in JSON"),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+/// 再帰下降でJSONを解析するパーサー
+struct JsonParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self) -> JsonError {
+        JsonError::Syntax { offset: self.pos }
+    }
+
+    /// まだ読んでいない入力
+    fn rest(&self) -> &str {
+        self.input.get(self.pos..).unwrap_or_default()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    /// 空白を読み飛ばしてから `expected` の文字を読む
+    fn expect(&mut self, expected: char) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() != Some(expected) {
+            return Err(self.error());
+        }
+        self.pos += expected.len_utf8();
+        Ok(())
+    }
+
+    /// `word` で始まっていればそれを読んで `value` を返す
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        if !self.rest().starts_with(word) {
+            return Err(self.error());
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('n') => self.keyword("null", Json::Null),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => {
+                self.pos += 1;
+                let items = self.sequence(']', Self::value)?;
+                Ok(Json::Array(items))
+            }
+            Some('{') => {
+                self.pos += 1;
+                let fields = self.sequence('}', |p| {
+                    p.skip_whitespace();
+                    let key = p.string()?;
+                    p.expect(':')?;
+                    Ok((key, p.value()?))
+                })?;
+                Ok(Json::Object(fields))
+            }
+            Some('-' | '0'..='9') => self.number(),
+            _ => Err(self.error()),
+        }
+    }
+
+    /// `close` で終わるカンマ区切りの要素の並びを読む。開き括弧は読み終えている前提
+    fn sequence<T>(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<T, JsonError>,
+    ) -> Result<Vec<T>, JsonError> {
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(close) {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    return Ok(items);
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+            .unwrap_or(rest.len());
+        let digits = rest.get(..len).ok_or_else(|| self.error())?;
+        let n = digits.parse().map_err(|_| self.error())?;
+        self.pos += len;
+        Ok(Json::Number(n))
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        if self.peek() != Some('"') {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let c = self.peek().ok_or_else(|| self.error())?;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escape = self.peek().ok_or_else(|| self.error())?;
+                    self.pos += escape.len_utf8();
+                    out.push(match escape {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.unicode_escape()?,
+                        _ => return Err(self.error()),
+                    });
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    /// `\u` に続く16進数4桁を読む。サロゲートペアにも対応する
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| self.error());
+        }
+        if !self.rest().starts_with("\\u") {
+            return Err(self.error());
+        }
+        self.pos += 2;
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err(self.error());
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+            .ok_or_else(|| self.error())
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .input
+            .get(self.pos..self.pos.saturating_add(4))
+            .ok_or_else(|| self.error())?;
+        let n = u32::from_str_radix(digits, 16).map_err(|_| self.error())?;
+        self.pos += 4;
+        Ok(n)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            // JSONは無限大とNaNを表せないので null にする
+            Self::Number(n) if !n.is_finite() => f.write_str("null"),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write_string(f, s),
+            Self::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_char(']')
+            }
+            Self::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i != 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+/// 文字列をエスケープしてJSONの文字列として書き出す
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// JSONの値に変換できる型
+pub trait ToJson {
+    /// JSONの値に変換する
+    fn to_json(&self) -> Json;
+}
+
+/// JSONの値から組み立てられる型
+///
+/// 生存期間 `'a` は変換元のJSONの値のもので、[`Token`] のように
+/// 文字列を借用する型はJSONの文字列をそのまま借用する。
+pub trait FromJson<'a>: Sized {
+    /// JSONの値から組み立てる
+    fn from_json(json: &'a Json) -> Result<Self, JsonError>;
+}
+
+impl ToJson for Span {
+    fn to_json(&self) -> Json {
+        Json::Object(vec![
+            ("start".to_string(), Json::Number(self.start as f64)),
+            ("end".to_string(), Json::Number(self.end as f64)),
+        ])
+    }
+}
+
+impl ToJson for Token<'_> {
+    fn to_json(&self) -> Json {
+        match self {
+            Token::Ident(s) => Json::tagged("Ident", Json::String(s.to_string())),
+            Token::Int(n) => Json::tagged("Int", Json::Number(*n as f64)),
+            Token::Float(n) => Json::tagged("Float", Json::Number(*n)),
+            Token::StrLiteral(s) => Json::tagged("StrLiteral", Json::String(s.to_string())),
+            Token::Bool(b) => Json::tagged("Bool", Json::Bool(*b)),
+            Token::Nil => Json::String("Nil".to_string()),
+            Token::LParen => Json::String("LParen".to_string()),
+            Token::RParen => Json::String("RParen".to_string()),
+            Token::Semicolon => Json::String("Semicolon".to_string()),
+            Token::LBrace => Json::String("LBrace".to_string()),
+            Token::RBrace => Json::String("RBrace".to_string()),
+            Token::LBracket => Json::String("LBracket".to_string()),
+            Token::RBracket => Json::String("RBracket".to_string()),
+            Token::Keyword(keyword) => {
+                Json::tagged("Keyword", Json::String(keyword.name().to_string()))
+            }
+        }
+    }
+}
+
+impl ToJson for TokenTree<'_> {
+    fn to_json(&self) -> Json {
+        match self {
+            TokenTree::Token(token, span) => {
+                Json::tagged("Token", Json::Array(vec![token.to_json(), span.to_json()]))
+            }
+            TokenTree::Tree(children, span) => Json::tagged(
+                "Tree",
+                Json::Array(vec![
+                    Json::Array(children.iter().map(ToJson::to_json).collect()),
+                    span.to_json(),
+                ]),
+            ),
+        }
+    }
+}
+
+/// 借用しない木も [`TokenTree`] と同じ形で書き出す
+impl ToJson for OwnedToken {
+    fn to_json(&self) -> Json {
+        match self {
+            OwnedToken::Ident(name) => Token::Ident(name.as_str()).to_json(),
+            OwnedToken::Int(n) => Token::Int(*n).to_json(),
+            OwnedToken::Float(n) => Token::Float(*n).to_json(),
+            OwnedToken::StrLiteral(s) => Token::StrLiteral(s).to_json(),
+            OwnedToken::Bool(b) => Token::Bool(*b).to_json(),
+            OwnedToken::Nil => Token::Nil.to_json(),
+            OwnedToken::LParen => Token::LParen.to_json(),
+            OwnedToken::RParen => Token::RParen.to_json(),
+            OwnedToken::Semicolon => Token::Semicolon.to_json(),
+            OwnedToken::LBrace => Token::LBrace.to_json(),
+            OwnedToken::RBrace => Token::RBrace.to_json(),
+            OwnedToken::LBracket => Token::LBracket.to_json(),
+            OwnedToken::RBracket => Token::RBracket.to_json(),
+            OwnedToken::Keyword(keyword) => Token::Keyword(*keyword).to_json(),
+        }
+    }
+}
+
+impl ToJson for OwnedTokenTree {
+    fn to_json(&self) -> Json {
+        match self {
+            OwnedTokenTree::Token(token, span) => {
+                Json::tagged("Token", Json::Array(vec![token.to_json(), span.to_json()]))
+            }
+            OwnedTokenTree::Tree(children, span) => Json::tagged(
+                "Tree",
+                Json::Array(vec![
+                    Json::Array(children.iter().map(ToJson::to_json).collect()),
+                    span.to_json(),
+                ]),
+            ),
+        }
+    }
+}
+
+impl ToJson for Template {
+    fn to_json(&self) -> Json {
+        match self {
+            Template::Datum(tree) => Json::tagged("Datum", tree.to_json()),
+            Template::List(items, span) => Json::tagged(
+                "List",
+                Json::Array(vec![
+                    Json::Array(items.iter().map(ToJson::to_json).collect()),
+                    span.to_json(),
+                ]),
+            ),
+            Template::Unquote(expr) => Json::tagged("Unquote", expr.to_json()),
+        }
+    }
+}
+
+impl ToJson for Expr {
+    fn to_json(&self) -> Json {
+        Json::Object(vec![
+            ("kind".to_string(), self.kind.to_json()),
+            ("span".to_string(), self.span.to_json()),
+        ])
+    }
+}
+
+impl ToJson for ExprKind {
+    fn to_json(&self) -> Json {
+        let fields = |fields: Vec<(&str, Json)>| {
+            Json::Object(
+                fields
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+            )
+        };
+        let exprs = |exprs: &[Expr]| Json::Array(exprs.iter().map(ToJson::to_json).collect());
+        let strings = |names: &[Symbol]| {
+            Json::Array(names.iter().map(|n| Json::String(n.to_string())).collect())
+        };
+        match self {
+            Self::Int(n) => Json::tagged("Int", Json::Number(*n as f64)),
+            Self::Float(n) => Json::tagged("Float", Json::Number(*n)),
+            Self::Str(s) => Json::tagged("Str", Json::String(s.clone())),
+            Self::Bool(b) => Json::tagged("Bool", Json::Bool(*b)),
+            Self::Nil => Json::String("Nil".to_string()),
+            Self::Ident(name) => Json::tagged("Ident", Json::String(name.to_string())),
+            Self::BinaryOp { op, lhs, rhs } => Json::tagged(
+                "BinaryOp",
+                fields(vec![
+                    ("op", Json::String(format!("{op:?}"))),
+                    ("lhs", lhs.to_json()),
+                    ("rhs", rhs.to_json()),
+                ]),
+            ),
+            Self::UnaryOp { op, operand } => Json::tagged(
+                "UnaryOp",
+                fields(vec![
+                    ("op", Json::String(format!("{op:?}"))),
+                    ("operand", operand.to_json()),
+                ]),
+            ),
+            Self::Group(inner) => Json::tagged("Group", inner.to_json()),
+            Self::Call { func, args } => Json::tagged(
+                "Call",
+                fields(vec![("func", func.to_json()), ("args", exprs(args))]),
+            ),
+            Self::Define { name, value } => Json::tagged(
+                "Define",
+                fields(vec![
+                    ("name", Json::String(name.to_string())),
+                    ("value", value.to_json()),
+                ]),
+            ),
+            Self::Fn {
+                params,
+                body,
+                signature,
+            } => Json::tagged(
+                "Fn",
+                fields(vec![
+                    ("params", strings(params)),
+                    ("body", exprs(body)),
+                    (
+                        "signature",
+                        signature.as_ref().map_or(Json::Null, ToJson::to_json),
+                    ),
+                ]),
+            ),
+            Self::Let { bindings, body } => {
+                let bindings = bindings
+                    .iter()
+                    .map(|(name, value)| {
+                        Json::Array(vec![Json::String(name.to_string()), value.to_json()])
+                    })
+                    .collect();
+                Json::tagged(
+                    "Let",
+                    fields(vec![
+                        ("bindings", Json::Array(bindings)),
+                        ("body", exprs(body)),
+                    ]),
+                )
+            }
+            Self::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => Json::tagged(
+                "If",
+                fields(vec![
+                    ("cond", cond.to_json()),
+                    ("then_branch", then_branch.to_json()),
+                    (
+                        "else_branch",
+                        else_branch.as_ref().map_or(Json::Null, |e| e.to_json()),
+                    ),
+                ]),
+            ),
+            Self::Block(statements) => Json::tagged(
+                "Block",
+                Json::Array(statements.iter().map(ToJson::to_json).collect()),
+            ),
+            Self::While { cond, body } => Json::tagged(
+                "While",
+                fields(vec![("cond", cond.to_json()), ("body", body.to_json())]),
+            ),
+            Self::For {
+                var,
+                start,
+                end,
+                body,
+            } => Json::tagged(
+                "For",
+                fields(vec![
+                    ("var", Json::String(var.to_string())),
+                    ("start", start.to_json()),
+                    ("end", end.to_json()),
+                    ("body", body.to_json()),
+                ]),
+            ),
+            Self::Break => Json::String("Break".to_string()),
+            Self::Continue => Json::String("Continue".to_string()),
+            Self::Return(value) => Json::tagged(
+                "Return",
+                value.as_ref().map_or(Json::Null, |value| value.to_json()),
+            ),
+            Self::Array(items) => Json::tagged("Array", exprs(items)),
+            Self::Import(path) => Json::tagged("Import", Json::String(path.clone())),
+            Self::Map(entries) => Json::tagged(
+                "Map",
+                Json::Array(
+                    entries
+                        .iter()
+                        .map(|(key, value)| Json::Array(vec![key.to_json(), value.to_json()]))
+                        .collect(),
+                ),
+            ),
+            Self::Index { target, index } => Json::tagged(
+                "Index",
+                fields(vec![
+                    ("target", target.to_json()),
+                    ("index", index.to_json()),
+                ]),
+            ),
+            Self::Match { scrutinee, arms } => Json::tagged(
+                "Match",
+                fields(vec![
+                    ("scrutinee", scrutinee.to_json()),
+                    (
+                        "arms",
+                        Json::Array(arms.iter().map(ToJson::to_json).collect()),
+                    ),
+                ]),
+            ),
+            Self::Quote(tree) => Json::tagged("Quote", tree.to_json()),
+            Self::Quasiquote(template) => Json::tagged("Quasiquote", template.to_json()),
+        }
+    }
+}
+
+impl ToJson for Signature {
+    fn to_json(&self) -> Json {
+        let type_name =
+            |t: &Option<TypeName>| t.map_or(Json::Null, |t| Json::String(t.name().to_string()));
+        Json::Object(vec![
+            (
+                "params".to_string(),
+                Json::Array(self.params.iter().map(type_name).collect()),
+            ),
+            ("ret".to_string(), type_name(&self.ret)),
+        ])
+    }
+}
+
+impl ToJson for Diagnostic {
+    fn to_json(&self) -> Json {
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        Json::Object(vec![
+            ("severity".to_string(), Json::String(severity.to_string())),
+            (
+                "code".to_string(),
+                self.code
+                    .map_or(Json::Null, |code| Json::String(code.to_string())),
+            ),
+            ("span".to_string(), self.span.to_json()),
+            ("message".to_string(), Json::String(self.message.clone())),
+            (
+                "note".to_string(),
+                self.note.clone().map_or(Json::Null, Json::String),
+            ),
+            (
+                "labels".to_string(),
+                Json::Array(
+                    self.labels
+                        .iter()
+                        .map(|label| {
+                            Json::Object(vec![
+                                ("span".to_string(), label.span.to_json()),
+                                ("message".to_string(), Json::String(label.message.clone())),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+}
+
+impl ToJson for MatchArm {
+    fn to_json(&self) -> Json {
+        Json::Object(vec![
+            ("pattern".to_string(), self.pattern.to_json()),
+            ("pattern_span".to_string(), self.pattern_span.to_json()),
+            (
+                "guard".to_string(),
+                self.guard.as_ref().map_or(Json::Null, ToJson::to_json),
+            ),
+            ("body".to_string(), self.body.to_json()),
+        ])
+    }
+}
+
+impl ToJson for Pattern {
+    fn to_json(&self) -> Json {
+        match self {
+            Self::Wildcard => Json::String("Wildcard".to_string()),
+            Self::Binding(name) => Json::tagged("Binding", Json::String(name.to_string())),
+            Self::Int(n) => Json::tagged("Int", Json::Number(*n as f64)),
+            Self::Float(n) => Json::tagged("Float", Json::Number(*n)),
+            Self::Str(s) => Json::tagged("Str", Json::String(s.clone())),
+            Self::Bool(b) => Json::tagged("Bool", Json::Bool(*b)),
+            Self::Nil => Json::String("Nil".to_string()),
+        }
+    }
+}
+
+impl ToJson for Statement {
+    fn to_json(&self) -> Json {
+        let definition = |tag, name: &Symbol, value: &Expr, span: &Span| {
+            Json::tagged(
+                tag,
+                Json::Object(vec![
+                    ("name".to_string(), Json::String(name.to_string())),
+                    ("value".to_string(), value.to_json()),
+                    ("span".to_string(), span.to_json()),
+                ]),
+            )
+        };
+        match self {
+            Self::VarDef { name, value, span } => definition("VarDef", name, value, span),
+            Self::Assignment { name, value, span } => definition("Assignment", name, value, span),
+            Self::IndexAssignment {
+                target,
+                index,
+                value,
+                span,
+            } => Json::tagged(
+                "IndexAssignment",
+                Json::Object(vec![
+                    ("target".to_string(), target.to_json()),
+                    ("index".to_string(), index.to_json()),
+                    ("value".to_string(), value.to_json()),
+                    ("span".to_string(), span.to_json()),
+                ]),
+            ),
+            Self::Expr(expr) => Json::tagged("Expr", expr.to_json()),
+        }
+    }
+}
+
+/// 期待する形と異なるときのエラーを作る
+fn shape(expected: &'static str) -> JsonError {
+    JsonError::Shape { expected }
+}
+
+/// 外部タグ付きの列挙型の値をタグと中身に分ける
+///
+/// 中身を持たない列挙子は文字列だけで表されるので、中身は `None` になる。
+fn variant(json: &Json) -> Result<(&str, Option<&Json>), JsonError> {
+    match json {
+        Json::String(tag) => Ok((tag, None)),
+        Json::Object(fields) if fields.len() == 1 => Ok((&fields[0].0, Some(&fields[0].1))),
+        _ => Err(shape("enum variant")),
+    }
+}
+
+/// オブジェクトの必須のフィールドを得る
+fn field<'a>(json: &'a Json, key: &'static str) -> Result<&'a Json, JsonError> {
+    json.get(key).ok_or(shape(key))
+}
+
+fn as_str(json: &Json) -> Result<&str, JsonError> {
+    match json {
+        Json::String(s) => Ok(s),
+        _ => Err(shape("string")),
+    }
+}
+
+fn as_array(json: &Json) -> Result<&[Json], JsonError> {
+    match json {
+        Json::Array(items) => Ok(items),
+        _ => Err(shape("array")),
+    }
+}
+
+fn as_bool(json: &Json) -> Result<bool, JsonError> {
+    match json {
+        Json::Bool(b) => Ok(*b),
+        _ => Err(shape("boolean")),
+    }
+}
+
+fn as_f64(json: &Json) -> Result<f64, JsonError> {
+    match json {
+        Json::Number(n) => Ok(*n),
+        _ => Err(shape("number")),
+    }
+}
+
+fn as_i64(json: &Json) -> Result<i64, JsonError> {
+    let n = as_f64(json)?;
+    if n.fract() != 0.0 || n < i64::MIN as f64 || n >= i64::MAX as f64 {
+        return Err(shape("integer"));
+    }
+    Ok(n as i64)
+}
+
+fn as_usize(json: &Json) -> Result<usize, JsonError> {
+    usize::try_from(as_i64(json)?).map_err(|_| shape("non-negative integer"))
+}
+
+/// 2つの要素を持つ配列を分解する
+fn pair(json: &Json) -> Result<(&Json, &Json), JsonError> {
+    match as_array(json)? {
+        [a, b] => Ok((a, b)),
+        _ => Err(shape("pair")),
+    }
+}
+
+impl FromJson<'_> for Span {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        let start = as_usize(field(json, "start")?)?;
+        let end = as_usize(field(json, "end")?)?;
+        Ok(Span::new(start, end))
+    }
+}
+
+impl<'a> FromJson<'a> for Token<'a> {
+    fn from_json(json: &'a Json) -> Result<Self, JsonError> {
+        Ok(match variant(json)? {
+            ("Ident", Some(s)) => Token::Ident(as_str(s)?),
+            ("Int", Some(n)) => Token::Int(as_i64(n)?),
+            ("Float", Some(n)) => Token::Float(as_f64(n)?),
+            ("StrLiteral", Some(s)) => Token::StrLiteral(as_str(s)?),
+            ("Bool", Some(b)) => Token::Bool(as_bool(b)?),
+            ("Nil", None) => Token::Nil,
+            ("LParen", None) => Token::LParen,
+            ("RParen", None) => Token::RParen,
+            ("Semicolon", None) => Token::Semicolon,
+            ("LBrace", None) => Token::LBrace,
+            ("RBrace", None) => Token::RBrace,
+            ("LBracket", None) => Token::LBracket,
+            ("RBracket", None) => Token::RBracket,
+            ("Keyword", Some(name)) => {
+                Token::Keyword(Keyword::from_name(as_str(name)?).ok_or_else(|| shape("keyword"))?)
+            }
+            _ => return Err(shape("token")),
+        })
+    }
+}
+
+impl<'a> FromJson<'a> for TokenTree<'a> {
+    fn from_json(json: &'a Json) -> Result<Self, JsonError> {
+        match variant(json)? {
+            ("Token", Some(value)) => {
+                let (token, span) = pair(value)?;
+                Ok(TokenTree::Token(
+                    Token::from_json(token)?,
+                    Span::from_json(span)?,
+                ))
+            }
+            ("Tree", Some(value)) => {
+                let (children, span) = pair(value)?;
+                let children = as_array(children)?
+                    .iter()
+                    .map(TokenTree::from_json)
+                    .collect::<Result<_, _>>()?;
+                Ok(TokenTree::Tree(children, Span::from_json(span)?))
+            }
+            _ => Err(shape("token tree")),
+        }
+    }
+}
+
+impl FromJson<'_> for OwnedTokenTree {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        TokenTree::from_json(json).map(|tree| tree.to_owned())
+    }
+}
+
+impl FromJson<'_> for Template {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match variant(json)? {
+            ("Datum", Some(tree)) => Ok(Template::Datum(OwnedTokenTree::from_json(tree)?)),
+            ("List", Some(value)) => {
+                let (items, span) = pair(value)?;
+                let items = as_array(items)?
+                    .iter()
+                    .map(Template::from_json)
+                    .collect::<Result<_, _>>()?;
+                Ok(Template::List(items, Span::from_json(span)?))
+            }
+            ("Unquote", Some(expr)) => Ok(Template::Unquote(Box::new(Expr::from_json(expr)?))),
+            _ => Err(shape("template")),
+        }
+    }
+}
+
+impl FromJson<'_> for MatchArm {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        Ok(MatchArm {
+            pattern: Pattern::from_json(field(json, "pattern")?)?,
+            pattern_span: Span::from_json(field(json, "pattern_span")?)?,
+            guard: match field(json, "guard")? {
+                Json::Null => None,
+                guard => Some(Expr::from_json(guard)?),
+            },
+            body: Expr::from_json(field(json, "body")?)?,
+        })
+    }
+}
+
+impl FromJson<'_> for Pattern {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        Ok(match variant(json)? {
+            ("Wildcard", None) => Self::Wildcard,
+            ("Binding", Some(name)) => Self::Binding(Symbol::intern(as_str(name)?)),
+            ("Int", Some(n)) => Self::Int(as_i64(n)?),
+            ("Float", Some(n)) => Self::Float(as_f64(n)?),
+            ("Str", Some(s)) => Self::Str(as_str(s)?.to_string()),
+            ("Bool", Some(b)) => Self::Bool(as_bool(b)?),
+            ("Nil", None) => Self::Nil,
+            _ => return Err(shape("pattern")),
+        })
+    }
+}
+
+impl FromJson<'_> for Expr {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        let kind = ExprKind::from_json(field(json, "kind")?)?;
+        let span = Span::from_json(field(json, "span")?)?;
+        Ok(Expr::new(kind, span))
+    }
+}
+
+impl FromJson<'_> for ExprKind {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        let expr = |json: &Json, key| Expr::from_json(field(json, key)?).map(Box::new);
+        let exprs = |json: &Json, key| {
+            as_array(field(json, key)?)?
+                .iter()
+                .map(Expr::from_json)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let symbol = |json: &Json, key| as_str(field(json, key)?).map(Symbol::intern);
+        Ok(match variant(json)? {
+            ("Int", Some(n)) => Self::Int(as_i64(n)?),
+            ("Float", Some(n)) => Self::Float(as_f64(n)?),
+            ("Str", Some(s)) => Self::Str(as_str(s)?.to_string()),
+            ("Bool", Some(b)) => Self::Bool(as_bool(b)?),
+            ("Nil", None) => Self::Nil,
+            ("Ident", Some(s)) => Self::Ident(Symbol::intern(as_str(s)?)),
+            ("BinaryOp", Some(v)) => Self::BinaryOp {
+                op: BinOp::from_json(field(v, "op")?)?,
+                lhs: expr(v, "lhs")?,
+                rhs: expr(v, "rhs")?,
+            },
+            ("UnaryOp", Some(v)) => Self::UnaryOp {
+                op: UnOp::from_json(field(v, "op")?)?,
+                operand: expr(v, "operand")?,
+            },
+            ("Group", Some(v)) => Self::Group(Box::new(Expr::from_json(v)?)),
+            ("Call", Some(v)) => Self::Call {
+                func: expr(v, "func")?,
+                args: exprs(v, "args")?,
+            },
+            ("Define", Some(v)) => Self::Define {
+                name: symbol(v, "name")?,
+                value: expr(v, "value")?,
+            },
+            ("Fn", Some(v)) => Self::Fn {
+                params: as_array(field(v, "params")?)?
+                    .iter()
+                    .map(|p| as_str(p).map(Symbol::intern))
+                    .collect::<Result<_, _>>()?,
+                body: exprs(v, "body")?.into(),
+                // 型注釈を持たなかった頃の出力も読めるよう、省略を許す
+                signature: match v.get("signature") {
+                    None | Some(Json::Null) => None,
+                    Some(s) => Some(Signature::from_json(s)?),
+                },
+            },
+            ("Let", Some(v)) => Self::Let {
+                bindings: as_array(field(v, "bindings")?)?
+                    .iter()
+                    .map(|binding| {
+                        let (name, value) = pair(binding)?;
+                        Ok((Symbol::intern(as_str(name)?), Expr::from_json(value)?))
+                    })
+                    .collect::<Result<_, JsonError>>()?,
+                body: exprs(v, "body")?,
+            },
+            ("If", Some(v)) => Self::If {
+                cond: expr(v, "cond")?,
+                then_branch: expr(v, "then_branch")?,
+                else_branch: match field(v, "else_branch")? {
+                    Json::Null => None,
+                    e => Some(Box::new(Expr::from_json(e)?)),
+                },
+            },
+            ("Block", Some(v)) => Self::Block(
+                as_array(v)?
+                    .iter()
+                    .map(Statement::from_json)
+                    .collect::<Result<_, _>>()?,
+            ),
+            ("While", Some(v)) => Self::While {
+                cond: expr(v, "cond")?,
+                body: expr(v, "body")?,
+            },
+            ("For", Some(v)) => Self::For {
+                var: symbol(v, "var")?,
+                start: expr(v, "start")?,
+                end: expr(v, "end")?,
+                body: expr(v, "body")?,
+            },
+            ("Break", None) => Self::Break,
+            ("Continue", None) => Self::Continue,
+            ("Return", Some(Json::Null)) => Self::Return(None),
+            ("Return", Some(value)) => Self::Return(Some(Box::new(Expr::from_json(value)?))),
+            ("Array", Some(v)) => Self::Array(
+                as_array(v)?
+                    .iter()
+                    .map(Expr::from_json)
+                    .collect::<Result<_, _>>()?,
+            ),
+            ("Import", Some(v)) => Self::Import(as_str(v)?.to_string()),
+            ("Map", Some(v)) => Self::Map(
+                as_array(v)?
+                    .iter()
+                    .map(|entry| {
+                        let (key, value) = pair(entry)?;
+                        Ok((Expr::from_json(key)?, Expr::from_json(value)?))
+                    })
+                    .collect::<Result<_, JsonError>>()?,
+            ),
+            ("Index", Some(v)) => Self::Index {
+                target: expr(v, "target")?,
+                index: expr(v, "index")?,
+            },
+            ("Match", Some(v)) => Self::Match {
+                scrutinee: expr(v, "scrutinee")?,
+                arms: as_array(field(v, "arms")?)?
+                    .iter()
+                    .map(MatchArm::from_json)
+                    .collect::<Result<_, _>>()?,
+            },
+            ("Quote", Some(tree)) => Self::Quote(OwnedTokenTree::from_json(tree)?),
+            ("Quasiquote", Some(template)) => Self::Quasiquote(Template::from_json(template)?),
+            _ => return Err(shape("expression")),
+        })
+    }
+}
+
+impl FromJson<'_> for Signature {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        let type_name = |json: &Json| match json {
+            Json::Null => Ok(None),
+            json => TypeName::from_name(as_str(json)?)
+                .map(Some)
+                .ok_or(shape("type name")),
+        };
+        Ok(Self {
+            params: as_array(field(json, "params")?)?
+                .iter()
+                .map(type_name)
+                .collect::<Result<_, _>>()?,
+            ret: type_name(field(json, "ret")?)?,
+        })
+    }
+}
+
+impl FromJson<'_> for Statement {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        let definition = |v: &Json| -> Result<_, JsonError> {
+            Ok((
+                Symbol::intern(as_str(field(v, "name")?)?),
+                Expr::from_json(field(v, "value")?)?,
+                Span::from_json(field(v, "span")?)?,
+            ))
+        };
+        match variant(json)? {
+            ("VarDef", Some(v)) => {
+                let (name, value, span) = definition(v)?;
+                Ok(Self::VarDef { name, value, span })
+            }
+            ("Assignment", Some(v)) => {
+                let (name, value, span) = definition(v)?;
+                Ok(Self::Assignment { name, value, span })
+            }
+            ("IndexAssignment", Some(v)) => Ok(Self::IndexAssignment {
+                target: Expr::from_json(field(v, "target")?)?,
+                index: Expr::from_json(field(v, "index")?)?,
+                value: Expr::from_json(field(v, "value")?)?,
+                span: Span::from_json(field(v, "span")?)?,
+            }),
+            ("Expr", Some(v)) => Expr::from_json(v).map(Self::Expr),
+            _ => Err(shape("statement")),
+        }
+    }
+}
+
+impl FromJson<'_> for BinOp {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match as_str(json)? {
+            "Add" => Ok(Self::Add),
+            "Sub" => Ok(Self::Sub),
+            "Mul" => Ok(Self::Mul),
+            "Div" => Ok(Self::Div),
+            "Rem" => Ok(Self::Rem),
+            "Lt" => Ok(Self::Lt),
+            "Le" => Ok(Self::Le),
+            "Gt" => Ok(Self::Gt),
+            "Ge" => Ok(Self::Ge),
+            "Eq" => Ok(Self::Eq),
+            "Ne" => Ok(Self::Ne),
+            "And" => Ok(Self::And),
+            "Or" => Ok(Self::Or),
+            _ => Err(shape("binary operator")),
+        }
+    }
+}
+
+impl FromJson<'_> for UnOp {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match as_str(json)? {
+            "Neg" => Ok(Self::Neg),
+            "Not" => Ok(Self::Not),
+            _ => Err(shape("unary operator")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::lower;
+    use crate::infix::parse_expr;
+    use crate::parser::source;
+
+    #[test]
+    fn test_display() {
+        let json = Json::Object(vec![
+            (
+                "a".to_string(),
+                Json::Array(vec![Json::Null, Json::Bool(true)]),
+            ),
+            ("b".to_string(), Json::String("x\"\n".to_string())),
+            ("c".to_string(), Json::Number(1.5)),
+        ]);
+        assert_eq!(json.to_string(), r#"{"a":[null,true],"b":"x\"\n","c":1.5}"#);
+    }
+
+    #[test]
+    fn test_diagnostic() {
+        let diagnostic = Diagnostic::warning(Span::new(1, 3), "odd")
+            .with_code("W0101")
+            .with_note("why")
+            .with_label(Span::new(0, 1), "here");
+        assert_eq!(
+            diagnostic.to_json().to_string(),
+            concat!(
+                r#"{"severity":"Warning","code":"W0101","span":{"start":1,"end":3},"message":"odd","#,
+                r#""note":"why","#,
+                r#""labels":[{"span":{"start":0,"end":1},"message":"here"}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_token_tree() {
+        let tree = source("(a 1)").unwrap();
+        assert_eq!(
+            tree.to_json().to_string(),
+            concat!(
+                r#"{"Tree":[[{"Tree":[[{"Token":[{"Ident":"a"},{"start":1,"end":2}]},"#,
+                r#"{"Token":[{"Int":1},{"start":3,"end":4}]}],{"start":0,"end":5}]}],"#,
+                r#"{"start":0,"end":5}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let json = Json::parse(
+            r#" {"a": [null, true, false], "b": "x\"\n\u00e9\ud83d\ude00", "c": -1.5e2} "#,
+        );
+        assert_eq!(
+            json,
+            Ok(Json::Object(vec![
+                (
+                    "a".to_string(),
+                    Json::Array(vec![Json::Null, Json::Bool(true), Json::Bool(false)]),
+                ),
+                ("b".to_string(), Json::String("x\"\né😀".to_string())),
+                ("c".to_string(), Json::Number(-150.0)),
+            ]))
+        );
+        assert_eq!(Json::parse("[1,]"), Err(JsonError::Syntax { offset: 3 }));
+        assert_eq!(Json::parse("[1] 2"), Err(JsonError::Syntax { offset: 4 }));
+        assert_eq!(Json::parse("\"\\é\""), Err(JsonError::Syntax { offset: 4 }));
+        assert_eq!(
+            Json::parse("[1e999999, \"\\u12"),
+            Err(JsonError::Syntax { offset: 14 })
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tree = source(r#"(a "b\n" (1 2.5 true nil))"#).unwrap();
+        let json = Json::parse(&tree.to_json().to_string()).unwrap();
+        assert_eq!(TokenTree::from_json(&json), Ok(tree));
+
+        let Ok(TokenTree::Tree(forms, _)) =
+            source(r#"(let ((f (fn (x y) (f x)))) (define z "s") (f z 1)) `(a ,(+ 1 2) '"c")"#)
+        else {
+            panic!("failed to parse");
+        };
+        let exprs = [
+            parse_expr("1 + -2 * (x / 3.5) <= 4 || !y && false == nil").unwrap(),
+            parse_expr("if x { var y = 1; y = 2 } else if z { 3 }").unwrap(),
+            parse_expr("for i in 0..n { while i { break }; continue }").unwrap(),
+            parse_expr("{ var a = [1, [2]]; a[0] = a[1][0]; match a { 1 if x => 2, _ => 3 } }")
+                .unwrap(),
+            parse_expr("[{:}, { \"k\": 1, -2: { x: nil } }]").unwrap(),
+            parse_expr("import \"lib/a.rscl\"").unwrap(),
+            lower(&forms[0]).unwrap(),
+            lower(&forms[1]).unwrap(),
+        ];
+        for expr in exprs {
+            let json = Json::parse(&expr.to_json().to_string()).unwrap();
+            assert_eq!(Expr::from_json(&json), Ok(expr));
+        }
+    }
+
+    #[test]
+    fn test_from_json_error() {
+        let json =
+            Json::parse(r#"{"kind": {"Unknown": 1}, "span": {"start": 0, "end": 1}}"#).unwrap();
+        assert_eq!(
+            Expr::from_json(&json),
+            Err(JsonError::Shape {
+                expected: "expression"
+            })
+        );
+        let json = Json::parse(r#"{"start": -1, "end": 1}"#).unwrap();
+        assert!(Span::from_json(&json).is_err());
+    }
+}
@@ -0,0 +1,1305 @@
+//! 文字列をトークンに分割する字句解析器
+
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::ast::{Keyword, Span, Token};
+use crate::combinator::{many0, or, ParseFn};
+use crate::parser::{Expected, ParserOptions};
+
+/// 字句解析に失敗したときのエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexError {
+    /// 期待していない文字が現れた
+    Unexpected {
+        /// 入力の先頭からのバイト位置
+        offset: usize,
+        /// 期待していた要素
+        expected: Expected,
+        /// 実際に現れた文字。入力の終わりなら `None`
+        found: Option<char>,
+    },
+    /// 数値として読み始めた部分が、数値として解釈できない
+    ///
+    /// `1.2.3` や `0x`、`i64` に収まらない整数などで起きる。
+    InvalidNumber {
+        /// 数値として読んだ部分の範囲。続く空白か括弧の手前までを含む
+        span: Span,
+        /// 範囲の文字列
+        literal: String,
+    },
+}
+
+impl LexError {
+    /// 期待していない文字が現れたことを表すエラーを作る
+    pub fn new(offset: usize, expected: Expected, found: Option<char>) -> Self {
+        Self::Unexpected {
+            offset,
+            expected,
+            found,
+        }
+    }
+
+    /// エラーの先頭のバイト位置
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::Unexpected { offset, .. } => *offset,
+            Self::InvalidNumber { span, .. } => span.start,
+        }
+    }
+
+    /// 部分文字列を基準にしたエラー位置を、`base` バイトだけ後ろにずらす
+    fn offset_by(mut self, base: usize) -> Self {
+        match &mut self {
+            Self::Unexpected { offset, .. } => *offset += base,
+            Self::InvalidNumber { span, .. } => {
+                *span = Span::new(span.start + base, span.end + base)
+            }
+        }
+        self
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unexpected {
+                offset,
+                expected,
+                found: Some(c),
+            } => write!(f, "expected {expected}, found {c:?} at byte {offset}"),
+            Self::Unexpected {
+                offset,
+                expected,
+                found: None,
+            } => write!(
+                f,
+                "expected {expected}, found end of input at byte {offset}"
+            ),
+            Self::InvalidNumber { span, literal } => {
+                write!(f, "invalid number {literal:?} at byte {}", span.start)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+
+/// ソースコードを先頭から順にトークンへ分割するイテレーター
+///
+/// トークンは必要になった分だけ読み進める。エラーを返した後は何も返さない。
+///
+/// ```
+/// use ruscal_b::lexer::Lexer;
+/// use ruscal_b::{Span, Token};
+///
+/// let tokens: Vec<_> = Lexer::new("(a 1)").collect::<Result<_, _>>().unwrap();
+/// assert_eq!(tokens[1], (Span::new(1, 2), Token::Ident("a")));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Lexer<'src> {
+    cursor: Cursor<'src>,
+    /// `+` と `-` を被演算子の直後では常に演算子として読むかどうか
+    infix: bool,
+    after_operand: bool,
+    /// 閉じていない括弧の数。括弧の内側の改行は文を区切らない
+    depth: usize,
+    /// 最後に読み始めたトークンの先頭のバイト位置
+    token_start: usize,
+    failed: bool,
+}
+
+impl<'src> Lexer<'src> {
+    /// S式の規則で字句解析するイテレーターを作る
+    pub fn new(src: &'src str) -> Self {
+        Self::with_options(src, ParserOptions::new())
+    }
+
+    /// 設定を指定して、S式の規則で字句解析するイテレーターを作る
+    ///
+    /// 設定のうち、識別子とコメント、数値リテラルの項目を使う。
+    pub fn with_options(src: &'src str, options: ParserOptions) -> Self {
+        Self {
+            cursor: Cursor {
+                options,
+                ..Cursor::new(src)
+            },
+            infix: false,
+            after_operand: false,
+            depth: 0,
+            token_start: 0,
+            failed: false,
+        }
+    }
+
+    /// 中置記法の規則で字句解析するイテレーターを作る
+    ///
+    /// 被演算子の直後の `+` と `-` は、数字が続いていても二項演算子として読む。
+    /// 括弧の外で被演算子の直後に現れた改行は、文の区切りとして [`Token::Semicolon`] にする。
+    pub fn infix(src: &'src str) -> Self {
+        Self {
+            infix: true,
+            ..Self::new(src)
+        }
+    }
+
+    /// 次に読むトークンの先頭のバイト位置
+    pub fn offset(&self) -> usize {
+        self.cursor.pos
+    }
+
+    /// まだ読んでいない入力
+    pub fn rest(&self) -> &'src str {
+        self.cursor.rest()
+    }
+
+    /// エラーを返した後、問題のある部分を読み飛ばして字句解析を再開する
+    ///
+    /// 読めない文字はその1文字だけを読み飛ばす。
+    /// 数値や文字列のように途中で誤りが見つかったトークンは、トークン全体を読み飛ばす。
+    ///
+    /// # 引数
+    /// * `error` - 直前にこの字句解析器が返したエラー
+    pub fn recover(&mut self, error: &LexError) {
+        let cursor = &mut self.cursor;
+        match error {
+            LexError::Unexpected {
+                offset,
+                expected: Expected::Token,
+                ..
+            } => {
+                cursor.pos = *offset;
+                cursor.bump();
+            }
+            LexError::InvalidNumber { span, .. } => cursor.pos = span.end,
+            LexError::Unexpected { .. } => {
+                cursor.pos = self.token_start;
+                if cursor.eat("\"") {
+                    cursor.skip_string_body();
+                } else {
+                    cursor.eat_while(|c| !is_delimiter(c));
+                }
+            }
+        }
+        self.failed = false;
+    }
+
+    /// 中置記法で、文の区切りにならない改行と空白、コメントを読み飛ばす
+    ///
+    /// # 戻り値
+    /// * `Option<Span>` - 文の区切りになる改行があれば、その範囲
+    fn skip_newlines(&mut self) -> Option<Span> {
+        let cursor = &mut self.cursor;
+        loop {
+            cursor.whitespace();
+            // 行コメントの後の改行は区切りとして残す
+            if cursor.comment() {
+                continue;
+            }
+            if cursor.eat("\n") {
+                let start = cursor.pos - 1;
+                if self.after_operand && self.depth == 0 {
+                    return Some(Span::new(start, start + 1));
+                }
+            } else {
+                return None;
+            }
+        }
+    }
+}
+
+impl<'src> Iterator for Lexer<'src> {
+    type Item = Result<(Span, Token<'src>), LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if self.infix {
+            if let Some(span) = self.skip_newlines() {
+                self.after_operand = false;
+                return Some(Ok((span, Token::Semicolon)));
+            }
+        }
+        self.cursor.skip_trivia();
+        if self.cursor.is_empty() {
+            return None;
+        }
+        self.token_start = self.cursor.pos;
+        match self.cursor.token(self.infix && self.after_operand) {
+            // S式には文もブロックも無いので、中置記法の記号は読めない文字として扱う
+            Ok((
+                span,
+                Token::Semicolon
+                | Token::LBrace
+                | Token::RBrace
+                | Token::LBracket
+                | Token::RBracket,
+            )) if !self.infix => {
+                self.failed = true;
+                let found = self
+                    .cursor
+                    .src
+                    .get(span.start..)
+                    .and_then(|s| s.chars().next());
+                Some(Err(LexError::new(span.start, Expected::Token, found)))
+            }
+            Ok((span, token)) => {
+                // 中置記法では予約語の表にある名前を識別子と区別する
+                let token = match token {
+                    Token::Ident(name) if self.infix => {
+                        Keyword::from_name(name).map_or(token, Token::Keyword)
+                    }
+                    token => token,
+                };
+                self.after_operand = self.infix && ends_operand(&token);
+                match token {
+                    Token::LParen | Token::LBracket => self.depth += 1,
+                    Token::RParen | Token::RBracket => self.depth = self.depth.saturating_sub(1),
+                    _ => {}
+                }
+                Some(Ok((span, token)))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// 入力の中で次に読む位置
+///
+/// 位置をバイト単位で持つ。ASCII の文字は UTF-8 を復号せずに1バイトとして読み、
+/// それ以外の文字だけを復号するので、識別子や文字列に含まれる ASCII 以外の文字も1文字ずつ正しく読める。
+#[derive(Debug, Clone, Copy)]
+struct Cursor<'src> {
+    src: &'src str,
+    /// 次に読む文字の先頭のバイト位置。常に文字の境界を指す
+    pos: usize,
+    options: ParserOptions,
+}
+
+impl<'src> Cursor<'src> {
+    fn new(src: &'src str) -> Self {
+        Self {
+            src,
+            pos: 0,
+            options: ParserOptions::new(),
+        }
+    }
+
+    /// まだ読んでいない入力
+    fn rest(&self) -> &'src str {
+        self.src.get(self.pos..).unwrap_or_default()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.src.len()
+    }
+
+    /// 位置 `start` から読み進めた位置までの入力
+    fn since(&self, start: usize) -> &'src str {
+        self.src.get(start..self.pos).unwrap_or_default()
+    }
+
+    /// 現在の位置から `n` バイト後のバイト
+    fn byte_at(&self, n: usize) -> Option<u8> {
+        self.src.as_bytes().get(self.pos + n).copied()
+    }
+
+    /// 次の文字を見る
+    fn peek(&self) -> Option<char> {
+        match self.byte_at(0)? {
+            b if b.is_ascii() => Some(char::from(b)),
+            _ => self.rest().chars().next(),
+        }
+    }
+
+    /// 次の文字を1つ読み進める
+    fn bump(&mut self) {
+        if let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    /// 入力が `prefix` で続いていれば、その分だけ読み進める
+    ///
+    /// # 戻り値
+    /// * `bool` - 読み進めたなら `true`
+    fn eat(&mut self, prefix: &str) -> bool {
+        let found = self.rest().starts_with(prefix);
+        if found {
+            self.pos += prefix.len();
+        }
+        found
+    }
+
+    /// 条件を満たす文字が続く限り読み進める
+    fn eat_while(&mut self, pred: impl Fn(char) -> bool) {
+        while let Some(c) = self.peek().filter(|c| pred(*c)) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    /// 現在の位置で `expected` が見つからなかったことを表すエラー
+    fn error(&self, expected: Expected) -> LexError {
+        LexError::new(self.pos, expected, self.peek())
+    }
+
+    /// 改行以外の空白文字を読み飛ばす
+    ///
+    /// 中置記法では改行が文の区切りになるので、改行だけは呼び出し側で扱う。
+    fn whitespace(&mut self) {
+        loop {
+            match self.byte_at(0) {
+                // ASCII の空白は文字に分解せずにバイト単位で読み飛ばす
+                Some(b' ' | b'\t' | b'\r' | 0x0b | 0x0c) => self.pos += 1,
+                Some(b) if b.is_ascii() => return,
+                Some(_) => match self.peek() {
+                    Some(c) if c.is_whitespace() => self.pos += c.len_utf8(),
+                    _ => return,
+                },
+                None => return,
+            }
+        }
+    }
+
+    /// 改行を含む空白文字とコメントを読み飛ばす
+    fn skip_trivia(&mut self) {
+        loop {
+            self.whitespace();
+            if !self.eat("\n") && !self.comment() {
+                return;
+            }
+        }
+    }
+
+    /// 設定で有効になっているコメントを1つ読み飛ばす
+    ///
+    /// 行コメントは改行の手前までを読み飛ばし、改行は残す。
+    ///
+    /// # 戻り値
+    /// * `bool` - コメントを読み飛ばしたなら `true`
+    fn comment(&mut self) -> bool {
+        let rest = self.rest();
+        if self.options.line_comments && rest.starts_with("//") {
+            self.pos += rest.find('\n').unwrap_or(rest.len());
+        } else if self.options.block_comments && rest.starts_with("/*") {
+            self.block_comment();
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// `/*` から、入れ子を数えながら対応する `*/` の直後までを読み飛ばす
+    fn block_comment(&mut self) {
+        let mut depth = 0;
+        while !self.is_empty() {
+            if self.eat("/*") {
+                depth += 1;
+            } else if self.eat("*/") {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            } else {
+                self.bump();
+            }
+        }
+    }
+
+    /// 誤りのある文字列リテラルの、閉じる引用符の直後までを読み飛ばす
+    ///
+    /// 開く引用符の直後から読み始める。閉じていなければ入力の終わりまでを読み飛ばす。
+    fn skip_string_body(&mut self) {
+        while let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return,
+                '\\' => self.bump(),
+                _ => {}
+            }
+        }
+    }
+
+    /// 先頭の空白とコメントを読み飛ばしてから、トークンを1つ読む
+    ///
+    /// # 引数
+    /// * `after_operand` - 直前のトークンが被演算子なら `true`
+    ///
+    /// # 戻り値
+    /// * `Result<(Span, Token), LexError>` - (トークンの範囲, 解析結果のトークン)のタプル
+    fn token(&mut self, after_operand: bool) -> Result<(Span, Token<'src>), LexError> {
+        self.skip_trivia();
+        let start = self.pos;
+        let token = match self.peek() {
+            Some(c) if ident_start(c, self.options.unicode_idents) => self.ident()?,
+            Some('+' | '-') if after_operand || !self.sign_starts_number() => self.operator()?,
+            Some('.') if self.rest().starts_with("..") => self.operator()?,
+            Some('-' | '+' | '.' | '0'..='9') => self.number()?,
+            Some('*' | '/' | '%' | '=' | '<' | '>' | '!' | '&' | '|' | ':' | ',' | '\'' | '`') => {
+                self.operator()?
+            }
+            Some('"') => self.string()?,
+            Some(c @ (';' | '{' | '}' | '[' | ']' | '(' | ')')) => {
+                self.pos += 1;
+                match c {
+                    ';' => Token::Semicolon,
+                    '{' => Token::LBrace,
+                    '}' => Token::RBrace,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    '(' => Token::LParen,
+                    _ => Token::RParen,
+                }
+            }
+            _ => return Err(self.error(Expected::Token)),
+        };
+        Ok((Span::new(start, self.pos), token))
+    }
+
+    /// 符号の後に数値が続いているかどうかを判定する
+    fn sign_starts_number(&self) -> bool {
+        matches!(self.byte_at(1), Some(b'.' | b'0'..=b'9'))
+    }
+
+    /// 識別子（文字か `_` で始まり、その後に文字、数字、`_` が続く文字列）を読む
+    ///
+    /// `変数1` のような ASCII 以外の文字を含む識別子も受け付ける。
+    /// `null?` のように、述語の名前の末尾には `?` を1つ付けられる。
+    /// `true`、`false`、`nil` は識別子ではなくリテラルのトークンとして返す。
+    ///
+    /// # 戻り値
+    /// * `Result<Token, LexError>` - 識別子で始まっていない場合はエラーを返す
+    fn ident(&mut self) -> Result<Token<'src>, LexError> {
+        let start = self.pos;
+        let unicode = self.options.unicode_idents;
+        if !self.peek().is_some_and(|c| ident_start(c, unicode)) {
+            return Err(self.error(Expected::Ident));
+        }
+        self.bump();
+        self.eat_while(|c| ident_continue(c, unicode));
+        self.eat("?");
+        Ok(match self.since(start) {
+            "true" => Token::Bool(true),
+            "false" => Token::Bool(false),
+            "nil" => Token::Nil,
+            name => Token::Ident(name),
+        })
+    }
+
+    /// 数値を読む
+    ///
+    /// 次の形式を受け付け、数字の間には区切りとして `_` を書ける。
+    ///
+    /// * 10進数の整数 `1_000_000` は `Token::Int` になる
+    /// * `0x1F` の16進数と `0b1010` の2進数は `Token::Int` になる
+    /// * `1.5` や `.5` の小数と `1e-3` の指数表記は `Token::Float` になる
+    ///
+    /// # 戻り値
+    /// * `Result<Token, LexError>` - 解析結果のトークン
+    ///   - `+` や `1.2.3` のように数値として解釈できない場合や、整数が `i64` に収まらない場合は、
+    ///     [`LexError::InvalidNumber`] を返す
+    fn number(&mut self) -> Result<Token<'src>, LexError> {
+        let start = self.pos;
+        let res = self.number_literal();
+        res.map_err(|()| {
+            // 読めなかった数値は、続く空白か括弧の手前までを1つの誤ったリテラルとする
+            self.eat_while(|c| !is_delimiter(c));
+            LexError::InvalidNumber {
+                span: Span::new(start, self.pos),
+                literal: self.since(start).to_string(),
+            }
+        })
+    }
+
+    /// 数値を読む。数値として解釈できなければ `Err(())` を返す
+    fn number_literal(&mut self) -> Result<Token<'src>, ()> {
+        let start = self.pos;
+        let negative = self.eat("-");
+        if !negative {
+            self.eat("+");
+        }
+
+        for (prefix, radix) in [("0x", 16), ("0X", 16), ("0b", 2), ("0B", 2)] {
+            if !self.eat(prefix) {
+                continue;
+            }
+            let digits = self.pos;
+            self.eat_while(|c| c.is_digit(radix) || c == '_');
+            let mut literal = without_separators(self.since(digits));
+            if negative {
+                literal.to_mut().insert(0, '-');
+            }
+            let value = i64::from_str_radix(&literal, radix).map_err(drop)?;
+            return Ok(if self.options.int_literals {
+                Token::Int(value)
+            } else {
+                Token::Float(value as f64)
+            });
+        }
+
+        if self.byte_at(0) == Some(b'_') {
+            return Err(());
+        }
+        let body = self.pos;
+        self.eat_while(|c| matches!(c, '.' | '_' | '0'..='9'));
+        // `0..10` の `..` は範囲の記号なので数値に含めない
+        if let Some(pos) = self.since(body).find("..") {
+            self.pos = body + pos;
+        }
+        let mut is_float = self.since(body).contains('.');
+        let mantissa = self.pos;
+        if self.eat("e") || self.eat("E") {
+            if !self.eat("+") {
+                self.eat("-");
+            }
+            // `e` の後に数字が無ければ指数とはみなさず、その手前までを数値とする
+            if self.byte_at(0).is_some_and(|b| b.is_ascii_digit()) {
+                self.eat_while(|c| c.is_ascii_digit() || c == '_');
+                is_float = true;
+            } else {
+                self.pos = mantissa;
+            }
+        }
+        let literal = without_separators(self.since(start));
+        if is_float || !self.options.int_literals {
+            literal.parse().map(Token::Float).map_err(drop)
+        } else {
+            literal.parse().map(Token::Int).map_err(drop)
+        }
+    }
+
+    /// 演算子を読む
+    ///
+    /// 演算子は関数名として扱うため、識別子のトークンとして返す。
+    /// `<=` のように複数の文字からなる演算子は、できるだけ長く読む。
+    fn operator(&mut self) -> Result<Token<'src>, LexError> {
+        let start = self.pos;
+        match longest_operator(self.rest()) {
+            Some(op) => {
+                self.pos += op.len();
+                Ok(Token::Ident(self.since(start)))
+            }
+            None => Err(self.error(Expected::Token)),
+        }
+    }
+
+    /// 文字列リテラル（ダブルクォートで囲まれた文字列）を読む
+    ///
+    /// エスケープシーケンスとして `\n`, `\t`, `\"`, `\\` を受け付ける。
+    ///
+    /// # 戻り値
+    /// * `Result<Token, LexError>` - 解析結果のトークン
+    ///   - 閉じ引用符が無い場合や未知のエスケープシーケンスを含む場合は、その位置を指すエラーを返す
+    fn string(&mut self) -> Result<Token<'src>, LexError> {
+        if !self.eat("\"") {
+            return Err(self.error(Expected::StrLiteral));
+        }
+        let start = self.pos;
+        loop {
+            // 引用符とエスケープ以外の文字は調べる必要が無いので、まとめて読み飛ばす
+            let rest = self.rest();
+            self.pos += rest.find(['"', '\\']).unwrap_or(rest.len());
+            match self.byte_at(0) {
+                Some(b'"') => break,
+                Some(_) => {
+                    self.pos += 1;
+                    if !matches!(self.byte_at(0), Some(b'n' | b't' | b'"' | b'\\')) {
+                        return Err(self.error(Expected::Escape));
+                    }
+                    self.pos += 1;
+                }
+                None => return Err(self.error(Expected::Quote)),
+            }
+        }
+        let literal = self.since(start);
+        self.pos += 1;
+        Ok(Token::StrLiteral(literal))
+    }
+}
+
+/// トークンの区切りになる文字かどうかを判定する関数
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')')
+}
+
+/// 入力の先頭からトークンを1つ読み取る関数
+///
+/// 先頭の空白とコメントは読み飛ばし、最初の文字を見てどのトークンとして解析するかを決める。
+/// 数字が直後に続く `+` と `-` は数値の符号として扱う。
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+/// * `offset` - `input` の先頭がソースコード全体の何バイト目にあたるか
+///
+/// # 戻り値
+/// * `Result<(&str, Span, Token), LexError>` - (残りの入力文字列, トークンの範囲, 解析結果のトークン)のタプル
+///   - 範囲とエラー位置はソースコード全体の先頭からのバイト位置で表す
+pub fn token(input: &str, offset: usize) -> Result<(&str, Span, Token<'_>), LexError> {
+    token_with(input, offset, false)
+}
+
+/// 直前のトークンを考慮して、入力の先頭からトークンを1つ読み取る関数
+///
+/// 中置記法では `1-2` の `-` を二項演算子として読む必要がある。
+/// 直前のトークンが被演算子で終わっている場合は、`+` と `-` を常に演算子として扱う。
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+/// * `offset` - `input` の先頭がソースコード全体の何バイト目にあたるか
+/// * `after_operand` - 直前のトークンが被演算子（数値、名前、文字列、右括弧、`}`）なら `true`
+///
+/// # 戻り値
+/// * `Result<(&str, Span, Token), LexError>` - (残りの入力文字列, トークンの範囲, 解析結果のトークン)のタプル
+pub fn token_with(
+    input: &str,
+    offset: usize,
+    after_operand: bool,
+) -> Result<(&str, Span, Token<'_>), LexError> {
+    let mut cursor = Cursor::new(input);
+    let (span, token) = cursor
+        .token(after_operand)
+        .map_err(|e| e.offset_by(offset))?;
+    let span = Span::new(offset + span.start, offset + span.end);
+    Ok((cursor.rest(), span, token))
+}
+
+/// トークンが被演算子として式を終えるものかどうかを判定する関数
+///
+/// # 引数
+/// * `token` - 判定するトークン
+///
+/// # 戻り値
+/// * `bool` - 数値、文字列、演算子以外の名前、右括弧、`}`、`]`、
+///   `break`、`continue`、`return` なら `true`
+pub fn ends_operand(token: &Token) -> bool {
+    match token {
+        Token::Int(_)
+        | Token::Float(_)
+        | Token::StrLiteral(_)
+        | Token::Bool(_)
+        | Token::Nil
+        | Token::RParen
+        | Token::RBrace
+        | Token::RBracket => true,
+        // 演算子は記号で、名前は文字か `_` で始まるので、先頭の文字だけで区別できる
+        Token::Ident(name) => name.starts_with(is_ident_start),
+        Token::Keyword(keyword) => matches!(
+            keyword,
+            Keyword::Break | Keyword::Continue | Keyword::Return
+        ),
+        Token::LParen | Token::LBrace | Token::LBracket | Token::Semicolon => false,
+    }
+}
+
+/// 改行を含む空白文字とコメントを読み飛ばす関数
+///
+/// 空白文字には `char::is_whitespace` が真になる文字を使う。
+/// `// ...` の行コメントは改行文字まで、`/* ... */` のブロックコメントは入れ子を数えながら読み飛ばす。
+/// 閉じられていないブロックコメントは入力の終わりまでをコメントとみなす。
+///
+/// # 引数
+/// * `input` - 読み飛ばす対象の文字列
+///
+/// # 戻り値
+/// * `&str` - 空白とコメントを読み飛ばした後の文字列
+pub fn skip_trivia(input: &str) -> &str {
+    let mut cursor = Cursor::new(input);
+    cursor.skip_trivia();
+    cursor.rest()
+}
+
+/// 字句解析で読み飛ばす部分の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    /// 改行文字を含まない空白文字の並び
+    Whitespace,
+    /// 改行文字 `\n`
+    Newline,
+    /// `//` から行末の直前までの行コメント
+    LineComment,
+    /// `/* ... */` のブロックコメント
+    BlockComment,
+}
+
+/// 先頭の空白とコメントを、空白の並び、改行、コメントの区切りに分ける関数
+///
+/// 空白とコメント以外の文字が現れたところで止める。
+///
+/// # 引数
+/// * `input` - 対象の文字列
+///
+/// # 戻り値
+/// * `Vec<(TriviaKind, Span)>` - 区切りごとの種類と範囲
+pub fn split_trivia(input: &str) -> Vec<(TriviaKind, Span)> {
+    let piece = or(
+        or(
+            trivia_piece(TriviaKind::Whitespace, |cursor| {
+                cursor.whitespace();
+                true
+            }),
+            trivia_piece(TriviaKind::Newline, |cursor| cursor.eat("\n")),
+        ),
+        or(
+            trivia_piece(TriviaKind::LineComment, |cursor| {
+                cursor.rest().starts_with("//") && cursor.comment()
+            }),
+            trivia_piece(TriviaKind::BlockComment, Cursor::comment),
+        ),
+    );
+    let pieces = many0(piece)
+        .parse(input)
+        .map_or_else(Vec::new, |(_, pieces)| pieces);
+    let mut start = 0;
+    pieces
+        .into_iter()
+        .map(|(kind, len)| {
+            let span = Span::new(start, start + len);
+            start = span.end;
+            (kind, span)
+        })
+        .collect()
+}
+
+/// `eat` で読み進められた分を、種類 `kind` の区切りとその長さとして読む規則
+fn trivia_piece<'src>(
+    kind: TriviaKind,
+    eat: fn(&mut Cursor<'src>) -> bool,
+) -> impl ParseFn<'src, (TriviaKind, usize)> {
+    move |input: &'src str| {
+        let mut cursor = Cursor::new(input);
+        (eat(&mut cursor) && cursor.pos > 0).then(|| (cursor.rest(), (kind, cursor.pos)))
+    }
+}
+
+/// ソースコードに含まれるコメントの範囲を先頭から順に集める関数
+///
+/// 文字列リテラルの中の `//` や `/*` はコメントとみなさない。行コメントの範囲は改行文字を含まない。
+/// 読めないトークンは1文字ずつ読み飛ばして探し続ける。
+///
+/// # 引数
+/// * `input` - 対象の文字列
+///
+/// # 戻り値
+/// * `Vec<Span>` - コメントごとの範囲
+pub fn comments(input: &str) -> Vec<Span> {
+    let mut cursor = Cursor::new(input);
+    let mut spans = vec![];
+    loop {
+        cursor.whitespace();
+        if cursor.eat("\n") {
+            continue;
+        }
+        let start = cursor.pos;
+        if cursor.comment() {
+            spans.push(Span::new(start, cursor.pos));
+        } else if cursor.is_empty() {
+            return spans;
+        } else if cursor.token(false).is_err() {
+            cursor.pos = start;
+            cursor.bump();
+        }
+    }
+}
+
+/// 識別子の先頭に使える文字かどうかを判定する関数
+///
+/// UAX #31 の XID_Start に `_` を加えたものを近似として、Unicode の Alphabetic 属性で判定する。
+/// `ascii-ident` フィーチャーを有効にすると ASCII の英字と `_` だけに制限する。
+pub fn is_ident_start(c: char) -> bool {
+    ident_start(c, !cfg!(feature = "ascii-ident"))
+}
+
+/// 識別子の2文字目以降に使える文字かどうかを判定する関数
+///
+/// UAX #31 の XID_Continue を、Unicode の Alphabetic 属性と Numeric 属性で近似する。
+/// `ascii-ident` フィーチャーを有効にすると ASCII の英数字と `_` だけに制限する。
+pub fn is_ident_continue(c: char) -> bool {
+    ident_continue(c, !cfg!(feature = "ascii-ident"))
+}
+
+/// `unicode` が `false` なら ASCII の英字と `_` だけを識別子の先頭に使える文字とする
+fn ident_start(c: char, unicode: bool) -> bool {
+    if unicode {
+        c.is_alphabetic() || c == '_'
+    } else {
+        c.is_ascii_alphabetic() || c == '_'
+    }
+}
+
+/// `unicode` が `false` なら ASCII の英数字と `_` だけを識別子の2文字目以降に使える文字とする
+fn ident_continue(c: char, unicode: bool) -> bool {
+    if unicode {
+        c.is_alphanumeric() || c == '_'
+    } else {
+        c.is_ascii_alphanumeric() || c == '_'
+    }
+}
+
+/// 数字の区切りの `_` を取り除く関数
+///
+/// ほとんどの数値は `_` を含まないので、含む場合だけ新しい文字列を作る。
+fn without_separators(literal: &str) -> Cow<'_, str> {
+    if literal.contains('_') {
+        Cow::Owned(literal.replace('_', ""))
+    } else {
+        Cow::Borrowed(literal)
+    }
+}
+
+/// 演算子として読む記号
+///
+/// 入力の先頭に合う記号のうち最も長いものを読むので、並べる順番は問わない。
+/// 演算子を増やすときは、ここに足すだけでよい。
+pub const OPERATORS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "..", "->", "=>", "+", "-", "*", "/", "%", "=", "<", ">",
+    "!", ":", ",", "'", "`",
+];
+
+/// 入力の先頭に合う [`OPERATORS`] の記号のうち、最も長いものを返す関数
+///
+/// # 戻り値
+/// * `Option<&str>` - 合う記号。どの記号も合わなければ `None`
+pub fn longest_operator(input: &str) -> Option<&'static str> {
+    OPERATORS
+        .iter()
+        .copied()
+        .filter(|op| input.starts_with(op))
+        .max_by_key(|op| op.len())
+}
+
+/// 文字列リテラルのエスケープシーケンスを展開する関数
+///
+/// # 引数
+/// * `raw` - `Token::StrLiteral` が保持する引用符の内側の文字列
+///
+/// # 戻り値
+/// * `String` - エスケープシーケンスを対応する文字に置き換えた文字列
+pub fn unescape(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 入力の先頭から `read` でトークンを1つ読み、残りの入力とともに返す
+    fn read<'a>(
+        input: &'a str,
+        read: impl FnOnce(&mut Cursor<'a>) -> Result<Token<'a>, LexError>,
+    ) -> Result<(&'a str, Token<'a>), LexError> {
+        let mut cursor = Cursor::new(input);
+        let token = read(&mut cursor)?;
+        Ok((cursor.rest(), token))
+    }
+
+    fn ident(input: &str) -> Result<(&str, Token<'_>), LexError> {
+        read(input, Cursor::ident)
+    }
+
+    fn number(input: &str) -> Result<(&str, Token<'_>), LexError> {
+        read(input, Cursor::number)
+    }
+
+    fn string(input: &str) -> Result<(&str, Token<'_>), LexError> {
+        read(input, Cursor::string)
+    }
+
+    #[test]
+    fn test_lexer() {
+        let tokens: Vec<_> = Lexer::new("(a -1)").collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Ok((Span::new(0, 1), Token::LParen)),
+                Ok((Span::new(1, 2), Token::Ident("a"))),
+                Ok((Span::new(3, 5), Token::Int(-1))),
+                Ok((Span::new(5, 6), Token::RParen)),
+            ]
+        );
+        let tokens: Vec<_> = Lexer::infix("a-1").map(|t| t.map(|(_, t)| t)).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Ok(Token::Ident("a")),
+                Ok(Token::Ident("-")),
+                Ok(Token::Int(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexer_stops_after_error() {
+        let mut lexer = Lexer::new("a @ b");
+        assert!(matches!(lexer.next(), Some(Ok(_))));
+        assert_eq!(
+            lexer.next(),
+            Some(Err(LexError::new(2, Expected::Token, Some('@'))))
+        );
+        assert_eq!(lexer.next(), None);
+    }
+
+    #[test]
+    fn test_recover() {
+        let mut lexer = Lexer::new(r#"a @ "x\q y" 1.2.3 b"#);
+        let mut tokens = vec![];
+        let mut errors = vec![];
+        while let Some(res) = lexer.next() {
+            match res {
+                Ok((_, token)) => tokens.push(token),
+                Err(e) => {
+                    lexer.recover(&e);
+                    errors.push(e.offset());
+                }
+            }
+        }
+        assert_eq!(tokens, vec![Token::Ident("a"), Token::Ident("b")]);
+        assert_eq!(errors, vec![2, 7, 12]);
+    }
+
+    #[test]
+    fn test_whitespace() {
+        let mut cursor = Cursor::new("  \t\r\u{3000}a");
+        cursor.whitespace();
+        assert_eq!(cursor.rest(), "a");
+        // 改行は中置記法で文の区切りになるので、ここでは読み飛ばさない
+        let mut cursor = Cursor::new(" \n a");
+        cursor.whitespace();
+        assert_eq!(cursor.rest(), "\n a");
+    }
+
+    #[test]
+    fn test_multiline() {
+        let program = "(define (square x)\r\n\t(* x x)) // square\n\n(square\t3)\n";
+        let tokens: Vec<_> = Lexer::new(program).map(|t| t.unwrap().1).collect();
+        assert_eq!(tokens.len(), 16);
+        assert_eq!(tokens[12], Token::LParen);
+        assert_eq!(tokens.last(), Some(&Token::RParen));
+
+        let tokens: Vec<_> = Lexer::infix("x = 1\r\n\ty = 2\n")
+            .map(|t| t.unwrap().1)
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Ident("x"),
+                Token::Ident("="),
+                Token::Int(1),
+                Token::Semicolon,
+                Token::Ident("y"),
+                Token::Ident("="),
+                Token::Int(2),
+                Token::Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skip_trivia() {
+        assert_eq!(skip_trivia("  // comment\nfoo"), "foo");
+        assert_eq!(skip_trivia("/* a /* nested */ b */ foo"), "foo");
+        assert_eq!(skip_trivia("/* unterminated"), "");
+        assert_eq!(skip_trivia("// a\n// b\n  bar"), "bar");
+        assert_eq!(skip_trivia("\r\n\t /* c */\n baz"), "baz");
+    }
+
+    #[test]
+    fn test_token() {
+        assert_eq!(token("  (a", 0), Ok(("a", Span::new(2, 3), Token::LParen)));
+        assert_eq!(
+            token("ab cd", 10),
+            Ok((" cd", Span::new(10, 12), Token::Ident("ab")))
+        );
+        assert_eq!(
+            token("  @", 0),
+            Err(LexError::new(2, Expected::Token, Some('@')))
+        );
+        assert_eq!(
+            token(r#" "ab"#, 5),
+            Err(LexError::new(9, Expected::Quote, None))
+        );
+    }
+
+    #[test]
+    fn test_ident() {
+        assert_eq!(ident("Adam"), Ok(("", Token::Ident("Adam"))));
+        assert_eq!(ident("true)"), Ok((")", Token::Bool(true))));
+        assert_eq!(ident("false"), Ok(("", Token::Bool(false))));
+        assert_eq!(ident("nil"), Ok(("", Token::Nil)));
+        assert_eq!(ident("nil_or"), Ok(("", Token::Ident("nil_or"))));
+        assert_eq!(ident("null??"), Ok(("?", Token::Ident("null?"))));
+    }
+
+    #[test]
+    #[cfg(not(feature = "ascii-ident"))]
+    fn test_unicode_ident() {
+        assert_eq!(ident("変数1 = 2"), Ok((" = 2", Token::Ident("変数1"))));
+        assert_eq!(
+            token(" _tmp_x", 0),
+            Ok(("", Span::new(1, 7), Token::Ident("_tmp_x")))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "ascii-ident")]
+    fn test_ascii_ident() {
+        assert_eq!(
+            token("変数", 0),
+            Err(LexError::new(0, Expected::Token, Some('変')))
+        );
+        assert_eq!(ident("a_1変"), Ok(("変", Token::Ident("a_1"))));
+    }
+
+    #[test]
+    fn test_number() {
+        assert_eq!(number("123.45 "), Ok((" ", Token::Float(123.45))));
+        assert_eq!(number("1_000_000)"), Ok((")", Token::Int(1_000_000))));
+        assert_eq!(number("0x1F"), Ok(("", Token::Int(31))));
+        assert_eq!(number("-0b1010"), Ok(("", Token::Int(-10))));
+        assert_eq!(number("1e-3"), Ok(("", Token::Float(1e-3))));
+        assert_eq!(number("2E10 "), Ok((" ", Token::Float(2e10))));
+        assert_eq!(number("3else"), Ok(("else", Token::Int(3))));
+        assert_eq!(number(".5"), Ok(("", Token::Float(0.5))));
+    }
+
+    #[test]
+    fn test_invalid_number() {
+        let invalid = |start, end, literal: &str| LexError::InvalidNumber {
+            span: Span::new(start, end),
+            literal: literal.to_string(),
+        };
+        assert_eq!(number("+ 1"), Err(invalid(0, 1, "+")));
+        assert_eq!(number("0x)"), Err(invalid(0, 2, "0x")));
+        assert_eq!(
+            number("9223372036854775808"),
+            Err(invalid(0, 19, "9223372036854775808"))
+        );
+        // 誤ったリテラルは、続く空白か括弧の手前までを範囲にする
+        assert_eq!(token("1.2.3 x", 0), Err(invalid(0, 5, "1.2.3")));
+        assert_eq!(token("-.)", 6), Err(invalid(6, 8, "-.")));
+        assert_eq!(number("--5"), Err(invalid(0, 3, "--5")));
+        // トークンとしては、`-` の後に数値の `-5` が続くものとして読む
+        assert_eq!(
+            token("--5", 0),
+            Ok(("-5", Span::new(0, 1), Token::Ident("-")))
+        );
+        assert_eq!(number("."), Err(invalid(0, 1, ".")));
+
+        let mut lexer = Lexer::new("(a 1.2.3 b)");
+        let errors: Vec<_> = lexer.by_ref().filter_map(Result::err).collect();
+        assert_eq!(errors, [invalid(3, 8, "1.2.3")]);
+        assert_eq!(
+            invalid(3, 8, "1.2.3").to_string(),
+            "invalid number \"1.2.3\" at byte 3"
+        );
+    }
+
+    #[test]
+    fn test_operator() {
+        assert_eq!(
+            token("+ 1", 0),
+            Ok((" 1", Span::new(0, 1), Token::Ident("+")))
+        );
+        assert_eq!(token("-1", 0), Ok(("", Span::new(0, 2), Token::Int(-1))));
+        assert_eq!(
+            token("*)", 0),
+            Ok((")", Span::new(0, 1), Token::Ident("*")))
+        );
+        assert_eq!(
+            token("<=1", 0),
+            Ok(("1", Span::new(0, 2), Token::Ident("<=")))
+        );
+        assert_eq!(
+            token("!= !x", 0),
+            Ok((" !x", Span::new(0, 2), Token::Ident("!=")))
+        );
+        assert_eq!(
+            token("&x", 0),
+            Err(LexError::new(0, Expected::Token, Some('&')))
+        );
+        let tokens: Vec<_> = Lexer::infix("0..5").map(|t| t.unwrap().1).collect();
+        assert_eq!(
+            tokens,
+            vec![Token::Int(0), Token::Ident(".."), Token::Int(5)]
+        );
+    }
+
+    #[test]
+    fn test_longest_operator() {
+        let tokens: Vec<_> = Lexer::new("a<=b").map(|t| t.unwrap().1).collect();
+        assert_eq!(
+            tokens,
+            vec![Token::Ident("a"), Token::Ident("<="), Token::Ident("b")]
+        );
+        for op in ["==", "!=", "<=", ">=", "->", "=>"] {
+            let input = format!("x{op}y");
+            let tokens: Vec<_> = Lexer::infix(&input).map(|t| t.unwrap().1).collect();
+            assert_eq!(tokens[1], Token::Ident(op), "{input:?}");
+        }
+        // 表のどの記号も、それ自身としてそのまま読める
+        for op in OPERATORS {
+            assert_eq!(longest_operator(op), Some(*op));
+        }
+        assert_eq!(longest_operator("=>x"), Some("=>"));
+        assert_eq!(longest_operator("=x"), Some("="));
+        assert_eq!(longest_operator("@"), None);
+    }
+
+    #[test]
+    fn test_punctuation() {
+        let tokens: Vec<_> = Lexer::infix("xs[i]%2+1").map(|t| t.unwrap().1).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Ident("xs"),
+                Token::LBracket,
+                Token::Ident("i"),
+                Token::RBracket,
+                Token::Ident("%"),
+                Token::Int(2),
+                Token::Ident("+"),
+                Token::Int(1),
+            ]
+        );
+        // S式では角括弧は読めない文字として扱う
+        let tokens: Vec<_> = Lexer::new("(% a [b])").collect();
+        assert_eq!(tokens[1], Ok((Span::new(1, 2), Token::Ident("%"))));
+        assert_eq!(
+            tokens.last(),
+            Some(&Err(LexError::new(5, Expected::Token, Some('['))))
+        );
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(r#"a\"b\\c\n\t"#), "a\"b\\c\n\t");
+    }
+
+    #[test]
+    fn test_sign_after_operand() {
+        assert_eq!(
+            token_with("-2", 1, true),
+            Ok(("2", Span::new(1, 2), Token::Ident("-")))
+        );
+        assert_eq!(
+            token_with("-2", 1, false),
+            Ok(("", Span::new(1, 3), Token::Int(-2)))
+        );
+        assert!(ends_operand(&Token::RParen));
+        assert!(!ends_operand(&Token::Ident("-")));
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(
+            string(r#""hello world")"#),
+            Ok((")", Token::StrLiteral("hello world")))
+        );
+        assert_eq!(
+            string(r#""a\"b\\c\n\t" "#),
+            Ok((" ", Token::StrLiteral(r#"a\"b\\c\n\t"#)))
+        );
+        assert_eq!(
+            string(r#""unterminated"#),
+            Err(LexError::new(13, Expected::Quote, None))
+        );
+        assert_eq!(
+            string(r#""bad\q""#),
+            Err(LexError::new(5, Expected::Escape, Some('q')))
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let input = "(a // one\n \"// no\" /* two /* nested */ */ @) // three";
+        let found: Vec<_> = comments(input)
+            .into_iter()
+            .map(|span| &input[span.start..span.end])
+            .collect();
+        assert_eq!(found, ["// one", "/* two /* nested */ */", "// three"]);
+        assert!(comments("(a \"/* b */\")").is_empty());
+    }
+
+    #[test]
+    fn test_split_trivia() {
+        let pieces: Vec<_> = split_trivia(" \t\r\n// a\n/* b */x")
+            .into_iter()
+            .map(|(kind, span)| (kind, span.start, span.end))
+            .collect();
+        assert_eq!(
+            pieces,
+            [
+                (TriviaKind::Whitespace, 0, 3),
+                (TriviaKind::Newline, 3, 4),
+                (TriviaKind::LineComment, 4, 8),
+                (TriviaKind::Newline, 8, 9),
+                (TriviaKind::BlockComment, 9, 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keywords() {
+        let tokens: Vec<_> = Lexer::infix("if iffy { break }\nelse")
+            .map(|t| t.unwrap().1)
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Keyword(Keyword::If),
+                Token::Ident("iffy"),
+                Token::LBrace,
+                Token::Keyword(Keyword::Break),
+                Token::RBrace,
+                Token::Semicolon,
+                Token::Keyword(Keyword::Else),
+            ]
+        );
+        // S式では特殊形式の名前も識別子のまま
+        let tokens: Vec<_> = Lexer::new("(if x)").map(|t| t.unwrap().1).collect();
+        assert_eq!(tokens[1], Token::Ident("if"));
+    }
+
+    #[test]
+    fn test_char_boundaries() {
+        // 数値や演算子の直後に ASCII 以外の文字が続いても、文字の途中で切らない
+        assert_eq!(number("1é"), Ok(("é", Token::Int(1))));
+        let inputs = [
+            "é", "1é", "-é", "+é", ".é", "0xé", "1eé", "..é", "\"é", "\"\\é\"", "変数)", "a\u{0}",
+            "/* é", "é // é",
+        ];
+        for input in inputs {
+            for lexer in [Lexer::new(input), Lexer::infix(input)] {
+                for res in lexer {
+                    let span = match res {
+                        Ok((span, _)) => span,
+                        Err(LexError::InvalidNumber { span, .. }) => span,
+                        Err(e) => Span::new(e.offset(), e.offset()),
+                    };
+                    assert!(span.end <= input.len(), "{input:?}: {span:?}");
+                    assert!(
+                        input.is_char_boundary(span.start) && input.is_char_boundary(span.end),
+                        "{input:?}: {span:?}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! S式風の小さな言語のための字句解析器、構文解析器と評価器
+//!
+//! 他のクレートから組み込んで使えるように、トークンの型と解析関数を公開する。
+//! フロントエンドはS式 ([`source`]) と中置記法 ([`parse_expr`]) の2つがあり、
+//! どちらも最終的には [`Expr`] として評価される。
+
+// 文字の途中や入力の外を指す位置で文字列を切り出すとパニックするので、`str::get` で切り出す
+#![cfg_attr(not(test), deny(clippy::string_slice))]
+
+#[cfg(feature = "alt-parser")]
+pub mod alt_parser;
+pub mod ast;
+pub mod bytecode;
+pub mod combinator;
+pub mod cst;
+pub mod debug_info;
+pub mod debugger;
+pub mod diagnostics;
+pub mod disasm;
+pub mod dump;
+pub mod engine;
+pub mod env;
+pub mod error;
+pub mod eval;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod float_format;
+pub mod fmt;
+pub mod format;
+pub mod gc;
+pub mod highlight;
+pub mod incremental;
+pub mod infix;
+pub mod intern;
+pub mod interpreter;
+pub mod io;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+pub mod json;
+pub mod lexer;
+pub mod limits;
+pub mod line_editor;
+pub mod lint;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod math;
+pub mod module;
+pub mod optimize;
+pub mod parser;
+pub mod peephole;
+pub mod profile;
+pub mod register_vm;
+pub mod repl;
+pub mod rsclc;
+pub mod source_map;
+pub mod stdlib;
+pub mod stream;
+pub mod token_stream;
+pub mod transpile;
+pub mod typecheck;
+pub mod visit;
+pub mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod wasm_codegen;
+
+pub use ast::{
+    BinOp, Expr, ExprKind, Keyword, MatchArm, OwnedToken, OwnedTokenTree, Pattern, Precedence,
+    Span, Statement, Token, TokenTree, UnOp,
+};
+pub use bytecode::{compile, compile_program, Bytecode, Instruction};
+pub use diagnostics::{ColorChoice, Diagnostic, Label, Severity};
+pub use env::Environment;
+pub use error::Error;
+pub use eval::{
+    eval, eval_expr, eval_statements, ControlFlow, EvalError, Function, FunctionBody, Value,
+};
+pub use float_format::{set_float_format, FloatFormat};
+pub use infix::{parse_expr, statements};
+pub use intern::Symbol;
+pub use interpreter::{Interpreter, InterpreterBuilder};
+pub use lexer::{LexError, Lexer};
+pub use limits::{set_run_limits, Limit, RunLimits};
+pub use parser::{
+    source, source_recovering, source_with, source_with_limit, source_with_options, Expected,
+    ParseError, ParserOptions,
+};
+pub use source_map::{LineCol, SourceMap};
+pub use stdlib::NativeFn;
+pub use stream::Parser;
+pub use typecheck::{check, check_statements, warnings, Type, TypeError};
+pub use visit::{walk, Visitor};
+pub use vm::Vm;
@@ -0,0 +1,305 @@
+//! 評価の手数、確保する値の大きさ、経過時間の上限
+//!
+//! 信頼できないプログラムを組み込んで実行しても止まるように、[`set_run_limits`] で上限を設定する。
+//! 木をたどる評価器は式を1つ評価するたびに、仮想機械は命令を1つ実行するたびに1手を数える。
+//! 上限を超えると、評価は [`EvalError::FuelExhausted`] で終わる。
+//!
+//! 確保する値の大きさは、配列、マップ、リスト、文字列を作ったり伸ばしたりしたときの
+//! おおよそのバイト数を足し合わせたもので、解放した分は差し引かない。
+//! 上限と使った量はスレッドごとに持つ。
+
+use std::cell::Cell;
+use std::fmt;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use crate::ast::Span;
+use crate::eval::{EvalError, Value, DEFAULT_MAX_CALL_DEPTH};
+
+/// 経過時間を確かめる間隔の手数
+///
+/// 時刻を毎回読むと遅くなるので、この手数ごとにだけ確かめる。
+const CLOCK_INTERVAL: u64 = 1024;
+
+/// 評価に使ってよい量の上限。`None` の項目は制限しない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunLimits {
+    /// 評価できる手数
+    pub max_steps: Option<u64>,
+    /// 値のために確保できるおおよそのバイト数
+    pub max_heap: Option<usize>,
+    /// 上限を設定してから評価を続けられる時間
+    pub wall_clock: Option<Duration>,
+    /// 関数の呼び出しを入れ子にできる深さ。末尾位置の呼び出しは数えない
+    ///
+    /// Rust のスタックを使い切らないよう、この項目は常に制限する。
+    /// どの評価の方式も、この深さを超える呼び出しを [`EvalError::StackOverflow`] にする。
+    /// ただし呼び出しにはスレッドのスタックの残りの 3/4 までしか使わないので、
+    /// スタックが足りなければこの深さより手前で同じエラーになる。
+    /// 深い再帰を許すなら [`with_stack_size`](crate::eval::with_stack_size) で評価する。
+    pub max_call_depth: usize,
+}
+
+impl RunLimits {
+    /// 手数、バイト数、時間を制限せず、呼び出しの深さを既定の上限にする上限
+    pub const UNLIMITED: Self = Self {
+        max_steps: None,
+        max_heap: None,
+        wall_clock: None,
+        max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+    };
+}
+
+impl Default for RunLimits {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
+
+/// 使い切った上限の種類と、その大きさ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// 手数の上限
+    Steps(u64),
+    /// 確保する値のバイト数の上限
+    Heap(usize),
+    /// 経過時間の上限
+    WallClock(Duration),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Steps(steps) => write!(f, "{steps} steps"),
+            Self::Heap(bytes) => write!(f, "{bytes} bytes of heap"),
+            Self::WallClock(duration) => write!(f, "{duration:?} of wall-clock time"),
+        }
+    }
+}
+
+thread_local! {
+    /// このスレッドの上限
+    static LIMITS: Cell<RunLimits> = const { Cell::new(RunLimits::UNLIMITED) };
+    /// 上限を設定してから数えた手数
+    static STEPS: Cell<u64> = const { Cell::new(0) };
+    /// 上限を設定してから確保した値のバイト数
+    static HEAP: Cell<usize> = const { Cell::new(0) };
+    /// 評価を打ち切る時刻
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// このスレッドの上限を設定する関数
+///
+/// 使った手数とバイト数を0に戻し、経過時間もこの呼び出しから数え直す。
+/// 続けて評価するプログラムは、次に設定するまで同じ上限を分け合う。
+pub fn set_run_limits(limits: RunLimits) {
+    LIMITS.with(|l| l.set(limits));
+    STEPS.with(|s| s.set(0));
+    HEAP.with(|h| h.set(0));
+    DEADLINE.with(|d| d.set(limits.wall_clock.map(|limit| Instant::now() + limit)));
+}
+
+/// このスレッドの上限と、それまでに使った量
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Usage {
+    limits: RunLimits,
+    steps: u64,
+    heap: usize,
+    deadline: Option<Instant>,
+}
+
+/// このスレッドの上限と使った量を取り出す。[`restore_usage`] で元に戻せる
+pub(crate) fn usage() -> Usage {
+    Usage {
+        limits: run_limits(),
+        steps: STEPS.with(Cell::get),
+        heap: HEAP.with(Cell::get),
+        deadline: DEADLINE.with(Cell::get),
+    }
+}
+
+/// [`usage`] で取り出した上限と使った量に戻す
+pub(crate) fn restore_usage(usage: Usage) {
+    LIMITS.with(|l| l.set(usage.limits));
+    STEPS.with(|s| s.set(usage.steps));
+    HEAP.with(|h| h.set(usage.heap));
+    DEADLINE.with(|d| d.set(usage.deadline));
+}
+
+/// このスレッドの上限
+pub fn run_limits() -> RunLimits {
+    LIMITS.with(Cell::get)
+}
+
+/// 1手を数え、手数か経過時間の上限を超えていなければ `true` を返す
+///
+/// 木をたどる評価器の再帰する関数のスタックを大きくしないよう、エラーは [`exhausted`] で別に作る。
+pub(crate) fn step() -> bool {
+    let steps = STEPS.with(|s| {
+        s.set(s.get() + 1);
+        s.get()
+    });
+    let limits = run_limits();
+    if limits.max_steps.is_some_and(|max| steps > max) {
+        return false;
+    }
+    !(steps.is_multiple_of(CLOCK_INTERVAL)
+        && DEADLINE
+            .with(Cell::get)
+            .is_some_and(|deadline| Instant::now() >= deadline))
+}
+
+/// [`step`] が `false` を返したときの、超えた上限のエラー
+#[cold]
+pub(crate) fn exhausted(span: Span) -> EvalError {
+    let limits = run_limits();
+    let limit = match (limits.max_steps, limits.wall_clock) {
+        (Some(max), _) if STEPS.with(Cell::get) > max => Limit::Steps(max),
+        (_, Some(limit)) => Limit::WallClock(limit),
+        _ => unreachable!("step() only fails when a limit is exceeded"),
+    };
+    EvalError::FuelExhausted { limit, span }
+}
+
+/// `bytes` バイトの確保を数え、上限を超えればエラーを返す
+pub(crate) fn allocate(bytes: usize, span: Span) -> Result<(), EvalError> {
+    let heap = HEAP.with(|h| {
+        h.set(h.get().saturating_add(bytes));
+        h.get()
+    });
+    match run_limits().max_heap {
+        Some(max) if heap > max => Err(EvalError::FuelExhausted {
+            limit: Limit::Heap(max),
+            span,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// 新しく作った値のおおよそのバイト数を数え、上限を超えればエラーを返す
+///
+/// 要素が指す先の値は、それぞれを作ったときに数えているので含めない。
+pub(crate) fn allocate_value(value: &Value, span: Span) -> Result<(), EvalError> {
+    let bytes = match value {
+        Value::Str(s) => s.len(),
+        Value::List(items) => items.len() * size_of::<Value>(),
+        Value::Array(items) => items.borrow().len() * size_of::<Value>(),
+        Value::Map(entries) => entries.borrow().len() * 2 * size_of::<Value>(),
+        _ => return Ok(()),
+    };
+    allocate(bytes, span)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{Expr, ExprKind};
+    use crate::bytecode::compile;
+    use crate::env::Environment;
+    use crate::eval::eval_statements;
+    use crate::infix::statements;
+    use crate::stdlib;
+    use crate::vm::Vm;
+
+    fn run(input: &str, limits: RunLimits) -> Result<Option<Value>, EvalError> {
+        let mut env = Environment::new();
+        stdlib::register(&mut env);
+        set_run_limits(limits);
+        let result = eval_statements(&statements(input).unwrap(), &mut env);
+        set_run_limits(RunLimits::UNLIMITED);
+        result
+    }
+
+    fn run_vm(input: &str, limits: RunLimits) -> Result<Value, EvalError> {
+        let block = Expr::new(
+            ExprKind::Block(statements(input).unwrap()),
+            Span::new(0, input.len()),
+        );
+        let mut env = Environment::new();
+        stdlib::register(&mut env);
+        set_run_limits(limits);
+        let result = Vm::new().run(&compile(&block), &mut env);
+        set_run_limits(RunLimits::UNLIMITED);
+        result
+    }
+
+    #[test]
+    fn test_max_steps() {
+        let limits = RunLimits {
+            max_steps: Some(1000),
+            ..RunLimits::UNLIMITED
+        };
+        assert_eq!(
+            run("while true {}", limits),
+            Err(EvalError::FuelExhausted {
+                limit: Limit::Steps(1000),
+                span: Span::new(11, 13)
+            })
+        );
+        assert!(matches!(
+            run_vm("while true {}", limits),
+            Err(EvalError::FuelExhausted {
+                limit: Limit::Steps(1000),
+                ..
+            })
+        ));
+        assert_eq!(
+            run("var n = 0\nwhile n < 10 { n = n + 1 }\nn", limits),
+            Ok(Some(Value::I64(10)))
+        );
+        assert_eq!(
+            run("while true {}", limits).unwrap_err().message(),
+            "ran out of fuel: exceeded the limit of 1000 steps"
+        );
+    }
+
+    #[test]
+    fn test_max_heap() {
+        let limits = RunLimits {
+            max_heap: Some(1000),
+            ..RunLimits::UNLIMITED
+        };
+        let input = "var a = []\nwhile true { push(a, 1) }";
+        assert!(matches!(
+            run(input, limits),
+            Err(EvalError::FuelExhausted {
+                limit: Limit::Heap(1000),
+                ..
+            })
+        ));
+        assert!(matches!(
+            run_vm(input, limits),
+            Err(EvalError::FuelExhausted {
+                limit: Limit::Heap(1000),
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("var s = \"ab\"\nwhile true { s = concat(s, s) }", limits),
+            Err(EvalError::FuelExhausted { .. })
+        ));
+        assert_eq!(run("[1, 2, 3]", limits).map(|_| ()), Ok(()));
+    }
+
+    #[test]
+    fn test_wall_clock() {
+        let limits = RunLimits {
+            wall_clock: Some(Duration::from_millis(10)),
+            ..RunLimits::UNLIMITED
+        };
+        assert!(matches!(
+            run("while true {}", limits),
+            Err(EvalError::FuelExhausted {
+                limit: Limit::WallClock(_),
+                ..
+            })
+        ));
+        assert!(matches!(
+            run_vm("while true {}", limits),
+            Err(EvalError::FuelExhausted {
+                limit: Limit::WallClock(_),
+                ..
+            })
+        ));
+    }
+}
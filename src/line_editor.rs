@@ -0,0 +1,538 @@
+//! 端末で1行を編集しながら読み込む、補完つきの行エディタ
+//!
+//! [`read_line`] はキーを1つずつ読み、文字の挿入と削除、カーソルの移動、Tab キーでの補完と、
+//! 上下のキーでの履歴の呼び出しを行う。
+//! 端末がキーを1つずつ渡し、入力を自分で表示しないよう、読み込む間は [`RawMode`] で端末の
+//! 行単位の入力とエコーを止めておく。止められない端末や環境では、呼ぶ側が行単位の読み込みに戻す。
+//!
+//! ```
+//! use ruscal_b::line_editor::read_line;
+//!
+//! let complete = |line: &str, pos: usize| (pos - 2, vec![format!("{}rt", &line[pos - 2..pos])]);
+//! let mut output = vec![];
+//! let line = read_line(&mut "(sq\t 2)\r".as_bytes(), &mut output, "> ", &[], &complete).unwrap();
+//! assert_eq!(line.as_deref(), Some("(sqrt 2)"));
+//!
+//! let history = ["(+ 1 2)".to_string()];
+//! let line = read_line(&mut "\x1b[A\r".as_bytes(), &mut output, "> ", &history, &complete).unwrap();
+//! assert_eq!(line.as_deref(), Some("(+ 1 2)"));
+//! ```
+
+use std::io::{self, Read, Write};
+
+/// 補完の候補を探す関数。行と位置のバイト数から、(置き換える部分の先頭のバイト位置, 候補) を返す
+pub type Complete<'a> = dyn Fn(&str, usize) -> (usize, Vec<String>) + 'a;
+
+/// 読み込んだキー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    /// 上のキーか Ctrl-P。1つ前の履歴
+    Up,
+    /// 下のキーか Ctrl-N。1つ後の履歴
+    Down,
+    /// Ctrl-D。行が空なら入力の終わり
+    EndOfInput,
+    /// Ctrl-U。カーソルの前を消す
+    KillBefore,
+    /// 使わないキー
+    Ignored,
+}
+
+/// 編集中の行
+#[derive(Debug, Default)]
+struct Buffer {
+    chars: Vec<char>,
+    /// カーソルの手前にある文字の数
+    cursor: usize,
+    /// 呼び出している履歴の添字。履歴をたどっていなければ `None`
+    recalled: Option<usize>,
+    /// 履歴をたどり始める前に編集していた行
+    draft: Vec<char>,
+}
+
+impl Buffer {
+    fn line(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    /// カーソルの位置のバイト数
+    fn byte_cursor(&self) -> usize {
+        self.chars[..self.cursor].iter().map(|c| c.len_utf8()).sum()
+    }
+
+    fn insert(&mut self, text: &str) {
+        for c in text.chars() {
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    /// 行を `chars` に置き換え、カーソルを行末に置く
+    fn replace(&mut self, chars: Vec<char>) {
+        self.chars = chars;
+        self.cursor = self.chars.len();
+    }
+
+    /// 古いものから順に並んだ `history` の、1つ前の項目を呼び出す
+    fn recall_previous(&mut self, history: &[String]) {
+        let index = self.recalled.unwrap_or(history.len());
+        if index == 0 {
+            return;
+        }
+        if self.recalled.is_none() {
+            self.draft = std::mem::take(&mut self.chars);
+        }
+        self.recalled = Some(index - 1);
+        self.replace(history[index - 1].chars().collect());
+    }
+
+    /// 1つ後の項目を呼び出す。最後の項目の後は、履歴をたどる前に編集していた行に戻る
+    fn recall_next(&mut self, history: &[String]) {
+        let Some(index) = self.recalled else {
+            return;
+        };
+        match history.get(index + 1) {
+            Some(entry) => {
+                self.recalled = Some(index + 1);
+                self.replace(entry.chars().collect());
+            }
+            None => {
+                self.recalled = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.replace(draft);
+            }
+        }
+    }
+
+    /// キーに従って行を編集する。行を読み終えたら、読み込んだ行か入力の終わりを返す
+    fn key(
+        &mut self,
+        key: Key,
+        output: &mut impl Write,
+        prompt: &str,
+        history: &[String],
+        complete: &Complete,
+    ) -> io::Result<Option<Option<String>>> {
+        match key {
+            Key::Char(c) => self.insert(c.encode_utf8(&mut [0; 4])),
+            Key::Enter => {
+                output.write_all(b"\r\n")?;
+                return Ok(Some(Some(self.line())));
+            }
+            Key::EndOfInput if self.chars.is_empty() => {
+                output.write_all(b"\r\n")?;
+                return Ok(Some(None));
+            }
+            Key::EndOfInput | Key::Delete => {
+                if self.cursor < self.chars.len() {
+                    self.chars.remove(self.cursor);
+                }
+            }
+            Key::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.chars.remove(self.cursor);
+                }
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.chars.len(),
+            Key::Up => self.recall_previous(history),
+            Key::Down => self.recall_next(history),
+            Key::KillBefore => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Tab => self.complete(output, prompt, complete)?,
+            Key::Ignored => {}
+        }
+        self.redraw(output, prompt)?;
+        Ok(None)
+    }
+
+    /// カーソルの手前の書きかけの部分を、候補に共通する先頭の部分まで伸ばす
+    ///
+    /// 伸ばせなければ、候補が無いならベルを鳴らし、複数あるなら次の行に並べる。
+    fn complete(
+        &mut self,
+        output: &mut impl Write,
+        prompt: &str,
+        complete: &Complete,
+    ) -> io::Result<()> {
+        let line = self.line();
+        let (start, candidates) = complete(&line, self.byte_cursor());
+        let Some(start) = line.get(..start).map(|before| before.chars().count()) else {
+            return Ok(());
+        };
+        let start = start.min(self.cursor);
+        let typed: String = self.chars[start..self.cursor].iter().collect();
+        let common = common_prefix(&candidates);
+        if common.chars().count() > typed.chars().count() && common.starts_with(&typed) {
+            self.chars.drain(start..self.cursor);
+            self.cursor = start;
+            self.insert(&common);
+        } else if candidates.len() > 1 {
+            write!(output, "\r\n{}\r\n", candidates.join("  "))?;
+            output.write_all(prompt.as_bytes())?;
+        } else if candidates.is_empty() {
+            output.write_all(b"\x07")?;
+        }
+        Ok(())
+    }
+
+    /// 行を表示し直し、カーソルを編集している位置に戻す
+    fn redraw(&self, output: &mut impl Write, prompt: &str) -> io::Result<()> {
+        write!(output, "\r{prompt}{}\x1b[K", self.line())?;
+        let after = self.chars.len() - self.cursor;
+        if after > 0 {
+            write!(output, "\x1b[{after}D")?;
+        }
+        output.flush()
+    }
+}
+
+/// 候補のすべてに共通する先頭の部分
+fn common_prefix(candidates: &[String]) -> String {
+    let Some((first, rest)) = candidates.split_first() else {
+        return String::new();
+    };
+    let mut len = first.chars().count();
+    for candidate in rest {
+        len = len.min(
+            first
+                .chars()
+                .zip(candidate.chars())
+                .take_while(|(a, b)| a == b)
+                .count(),
+        );
+    }
+    first.chars().take(len).collect()
+}
+
+/// 次のキーを読む。入力が終わっていれば `None` を返す
+fn read_key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(None);
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x01 => Key::Home,
+        0x05 => Key::End,
+        0x02 => Key::Left,
+        0x06 => Key::Right,
+        0x10 => Key::Up,
+        0x0e => Key::Down,
+        0x04 => Key::EndOfInput,
+        0x15 => Key::KillBefore,
+        0x1b => read_escape(input)?,
+        byte if byte < 0x20 => Key::Ignored,
+        byte => {
+            // UTF-8 の続きのバイトを先頭のバイトが示す数だけ読む
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                bytes.extend(read_byte(input)?);
+            }
+            match std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                Some(c) => Key::Char(c),
+                None => Key::Ignored,
+            }
+        }
+    };
+    Ok(Some(key))
+}
+
+/// `ESC [` に続くカーソルキーなどの並びを読む
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    if read_byte(input)? != Some(b'[') {
+        return Ok(Key::Ignored);
+    }
+    let mut params = vec![];
+    loop {
+        match read_byte(input)? {
+            Some(byte @ b'0'..=b'9') | Some(byte @ b';') => params.push(byte),
+            Some(b'A') => return Ok(Key::Up),
+            Some(b'B') => return Ok(Key::Down),
+            Some(b'C') => return Ok(Key::Right),
+            Some(b'D') => return Ok(Key::Left),
+            Some(b'H') => return Ok(Key::Home),
+            Some(b'F') => return Ok(Key::End),
+            Some(b'~') => {
+                return Ok(match params.as_slice() {
+                    b"1" | b"7" => Key::Home,
+                    b"4" | b"8" => Key::End,
+                    b"3" => Key::Delete,
+                    _ => Key::Ignored,
+                })
+            }
+            _ => return Ok(Key::Ignored),
+        }
+    }
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// プロンプトを表示し、キーを読みながら1行を編集して返す
+///
+/// Enter キーで行を読み終え、空の行で Ctrl-D を押すか入力が終わると `None` を返す。
+/// Tab キーを押すと、`complete` が返す候補でカーソルの手前を補完する。
+/// 上下のキーを押すと、`history` の項目を新しいものから順に呼び出して行を置き換える。
+///
+/// # 引数
+/// * `input` - キーを読み込む入力。端末なら [`RawMode`] にしておく
+/// * `output` - プロンプトと編集中の行を書き出す出力
+/// * `prompt` - 行の先頭に表示するプロンプト
+/// * `history` - 古いものから順に並んだ、これまでに入力した行
+/// * `complete` - 補完の候補を探す関数
+pub fn read_line(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    prompt: &str,
+    history: &[String],
+    complete: &Complete,
+) -> io::Result<Option<String>> {
+    output.write_all(prompt.as_bytes())?;
+    output.flush()?;
+    let mut buffer = Buffer::default();
+    loop {
+        let Some(key) = read_key(input)? else {
+            // 行の途中で入力が終われば、そこまでを1行とする
+            output.write_all(b"\r\n")?;
+            return Ok((!buffer.chars.is_empty()).then(|| buffer.line()));
+        };
+        if let Some(line) = buffer.key(key, output, prompt, history, complete)? {
+            return Ok(line);
+        }
+    }
+}
+
+/// 標準入力の端末の、行単位の入力とエコーを止めている間の状態。破棄すると元に戻す
+pub struct RawMode {
+    saved: termios::Termios,
+}
+
+impl RawMode {
+    /// 標準入力が端末なら、行単位の入力とエコーを止める
+    ///
+    /// 標準入力が端末でないか、端末の設定を変えられない環境では `None` を返す。
+    pub fn enable() -> Option<Self> {
+        termios::enable().map(|saved| Self { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        termios::restore(&self.saved);
+    }
+}
+
+/// 端末の設定を変えられない環境の代わりの関数
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+)))]
+mod termios {
+    pub(super) struct Termios;
+
+    pub(super) fn enable() -> Option<Termios> {
+        None
+    }
+
+    pub(super) fn restore(_saved: &Termios) {}
+}
+
+/// Linux の端末の設定を読み書きする `termios` の関数
+///
+/// 定数の値と構造体の配置は、ここに挙げたアーキテクチャで共通のもの。
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+mod termios {
+    use std::ffi::c_int;
+
+    /// 標準入力のファイル記述子
+    const STDIN: c_int = 0;
+    /// 設定をすぐに変える
+    const TCSANOW: c_int = 0;
+    /// 行単位の入力
+    const ICANON: u32 = 0o2;
+    /// 入力した文字の表示
+    const ECHO: u32 = 0o10;
+    /// 読み込みが返るまでに待つ文字の数の添字
+    const VMIN: usize = 6;
+    /// 読み込みが返るまでの待ち時間の添字
+    const VTIME: usize = 5;
+
+    /// glibc と musl の `struct termios`
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub(super) struct Termios {
+        c_iflag: u32,
+        c_oflag: u32,
+        c_cflag: u32,
+        c_lflag: u32,
+        c_line: u8,
+        c_cc: [u8; 32],
+        c_ispeed: u32,
+        c_ospeed: u32,
+    }
+
+    extern "C" {
+        fn isatty(fd: c_int) -> c_int;
+        fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+        fn tcsetattr(fd: c_int, action: c_int, termios: *const Termios) -> c_int;
+    }
+
+    /// 行単位の入力とエコーを止め、元の設定を返す
+    pub(super) fn enable() -> Option<Termios> {
+        let mut saved = Termios {
+            c_iflag: 0,
+            c_oflag: 0,
+            c_cflag: 0,
+            c_lflag: 0,
+            c_line: 0,
+            c_cc: [0; 32],
+            c_ispeed: 0,
+            c_ospeed: 0,
+        };
+        // SAFETY: `saved` は `struct termios` と同じ配置で、呼び出しの間だけ書き込まれる
+        if unsafe { isatty(STDIN) } != 1 || unsafe { tcgetattr(STDIN, &mut saved) } != 0 {
+            return None;
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(ICANON | ECHO);
+        raw.c_cc[VMIN] = 1;
+        raw.c_cc[VTIME] = 0;
+        // SAFETY: `raw` は `tcgetattr` で読んだ設定を変えたもの
+        (unsafe { tcsetattr(STDIN, TCSANOW, &raw) } == 0).then_some(saved)
+    }
+
+    /// `enable` で返した設定に戻す
+    pub(super) fn restore(saved: &Termios) {
+        // SAFETY: `saved` は `tcgetattr` で読んだ設定
+        unsafe { tcsetattr(STDIN, TCSANOW, saved) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NAMES: &[&str] = &["sqrt", "square", "str", "upper"];
+
+    /// `NAMES` から、カーソルの手前の英字で始まる名前を候補にする
+    fn complete(line: &str, pos: usize) -> (usize, Vec<String>) {
+        let before = line.get(..pos).unwrap();
+        let start = before
+            .rfind(|c: char| !c.is_ascii_alphabetic())
+            .map_or(0, |i| i + 1);
+        let prefix = before.get(start..).unwrap();
+        let candidates = NAMES
+            .iter()
+            .filter(|name| !prefix.is_empty() && name.starts_with(prefix))
+            .map(|name| name.to_string())
+            .collect();
+        (start, candidates)
+    }
+
+    fn edit(keys: &str) -> (Option<String>, String) {
+        edit_with_history(keys, &[])
+    }
+
+    fn edit_with_history(keys: &str, history: &[String]) -> (Option<String>, String) {
+        let mut output = vec![];
+        let line = read_line(&mut keys.as_bytes(), &mut output, "> ", history, &complete).unwrap();
+        (line, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_editing() {
+        assert_eq!(edit("abc\r").0.as_deref(), Some("abc"));
+        assert_eq!(edit("ab\x7fc\n").0.as_deref(), Some("ac"));
+        // カーソルを動かした位置に挿入し、消す
+        assert_eq!(edit("ab\x1b[Dc\r").0.as_deref(), Some("acb"));
+        assert_eq!(edit("abc\x01x\x05y\r").0.as_deref(), Some("xabcy"));
+        assert_eq!(edit("abc\x1b[D\x1b[D\x1b[3~\r").0.as_deref(), Some("ac"));
+        assert_eq!(edit("ab\x15c\r").0.as_deref(), Some("c"));
+        assert_eq!(edit("é\x7fあ\r").0.as_deref(), Some("あ"));
+        // 空の行の Ctrl-D と、何も打たずに終わった入力は入力の終わり
+        assert_eq!(edit("\x04").0, None);
+        assert_eq!(edit("").0, None);
+        assert_eq!(edit("ab").0.as_deref(), Some("ab"));
+    }
+
+    #[test]
+    fn test_completion() {
+        // 候補が1つならその名前に、複数なら共通する先頭まで補完する
+        assert_eq!(edit("(up\t 1)\r").0.as_deref(), Some("(upper 1)"));
+        assert_eq!(edit("(squ\t\r").0.as_deref(), Some("(square"));
+        assert_eq!(edit("(sq\tr\t 2)\r").0.as_deref(), Some("(sqrt 2)"));
+        assert_eq!(edit("(st\t\x1b[D\x1b[D\tx\r").0.as_deref(), Some("(sxtr"));
+        // 伸ばせなければ候補を並べ、プロンプトと行を表示し直す
+        let (line, output) = edit("(s\t\r");
+        assert_eq!(line.as_deref(), Some("(s"));
+        assert!(output.contains("\r\nsqrt  square  str\r\n> "), "{output:?}");
+        // 候補が無ければベルを鳴らす
+        let (line, output) = edit("(zz\t\r");
+        assert_eq!(line.as_deref(), Some("(zz"));
+        assert!(output.contains('\x07'), "{output:?}");
+    }
+
+    #[test]
+    fn test_history() {
+        let history = ["(a 1)".to_string(), "(b 2)".to_string()];
+        let recall = |keys: &str| edit_with_history(keys, &history).0;
+        // 上のキーで新しいものから順に呼び出し、最も古い項目で止まる
+        assert_eq!(recall("\x1b[A\r").as_deref(), Some("(b 2)"));
+        assert_eq!(recall("\x1b[A\x1b[A\x1b[A\r").as_deref(), Some("(a 1)"));
+        assert_eq!(recall("\x10\x10\x0e\r").as_deref(), Some("(b 2)"));
+        // 呼び出した行も編集でき、最後の項目を過ぎると編集していた行に戻る
+        assert_eq!(recall("\x1b[A\x7f\x7f3)\r").as_deref(), Some("(b 3)"));
+        assert_eq!(recall("(c\x1b[A\x1b[B\r").as_deref(), Some("(c"));
+        assert_eq!(recall("(c\x1b[B\r").as_deref(), Some("(c"));
+        assert_eq!(edit("\x1b[A\r").0.as_deref(), Some(""));
+    }
+}
@@ -0,0 +1,373 @@
+//! 解析できたソースコードから、誤りの疑いがある書き方を探すリンター
+//!
+//! 規則は [`Rule`] を実装して [`Linter::with_rule`] で加える。
+//! [`Linter::new`] には次の組み込みの規則が入っている。
+//!
+//! * [`EmptyTree`] - 要素の無い `()`
+//! * [`AdjacentTrees`] - `(a)(b)` のように空白を挟まずに並んだ部分木
+//! * [`DeepNesting`] - 深く入れ子になった括弧
+//! * [`UnusedAtom`] - 値を使われない最上位のトークン
+//!
+//! ```
+//! use ruscal_b::lint::Linter;
+//! use ruscal_b::source;
+//!
+//! let tree = source("(print ()) 1 (+ 1 2)").unwrap();
+//! let warnings = Linter::new().check(&tree);
+//! assert_eq!(warnings.len(), 2);
+//! ```
+
+use crate::ast::{Span, Token, TokenTree};
+use crate::diagnostics::Diagnostic;
+use crate::visit::{walk, walk_tree, Visitor};
+
+/// リンターの規則
+pub trait Rule {
+    /// 報告の補足に表示する規則の名前
+    fn name(&self) -> &'static str;
+
+    /// 報告に付ける種類の番号。組み込みの規則は `W01xx` を使う
+    fn code(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// 最上位の式を調べて、見つかった問題を報告に加える
+    ///
+    /// # 引数
+    /// * `forms` - 入力全体の最上位の式
+    /// * `diagnostics` - 報告を加える先
+    fn check(&self, forms: &[TokenTree], diagnostics: &mut Vec<Diagnostic>);
+}
+
+/// 規則の集まりを順に適用するリンター
+pub struct Linter {
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl Default for Linter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Linter {
+    /// 組み込みの規則をすべて入れたリンターを作る
+    pub fn new() -> Self {
+        Self::empty()
+            .with_rule(EmptyTree)
+            .with_rule(AdjacentTrees)
+            .with_rule(DeepNesting::default())
+            .with_rule(UnusedAtom)
+    }
+
+    /// 規則を1つも入れないリンターを作る
+    pub fn empty() -> Self {
+        Self { rules: vec![] }
+    }
+
+    /// 規則を加える
+    pub fn with_rule(mut self, rule: impl Rule + 'static) -> Self {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    /// 木にすべての規則を適用する関数
+    ///
+    /// 補足の無い報告には、報告した規則の名前を補足として添える。
+    /// 番号の無い報告には、規則の [`Rule::code`] を付ける。
+    ///
+    /// # 引数
+    /// * `tree` - [`source`](crate::source) が返した、入力全体を表す木
+    ///
+    /// # 戻り値
+    /// * `Vec<Diagnostic>` - 見つかった問題の報告を、ソースコード上の位置の順に並べたもの
+    pub fn check(&self, tree: &TokenTree) -> Vec<Diagnostic> {
+        let forms = match tree {
+            TokenTree::Tree(forms, _) => forms.as_slice(),
+            TokenTree::Token(..) => std::slice::from_ref(tree),
+        };
+        let mut all = vec![];
+        for rule in &self.rules {
+            let mut diagnostics = vec![];
+            rule.check(forms, &mut diagnostics);
+            for diagnostic in &mut diagnostics {
+                diagnostic.code = diagnostic.code.or(rule.code());
+                if diagnostic.note.is_none() {
+                    diagnostic.note = Some(format!("reported by the `{}` rule", rule.name()));
+                }
+            }
+            all.append(&mut diagnostics);
+        }
+        // 並べ替えは安定なので、同じ位置の報告は規則を加えた順に並ぶ
+        all.sort_by_key(|diagnostic| diagnostic.span.start);
+        all
+    }
+}
+
+/// 部分木を訪れるたびに関数を呼ぶ訪問者
+struct EachTree<F>(F);
+
+impl<'src, F: FnMut(&[TokenTree<'src>], Span)> Visitor<'src> for EachTree<F> {
+    fn enter_tree(&mut self, children: &[TokenTree<'src>], span: Span) {
+        (self.0)(children, span);
+    }
+}
+
+/// 要素の無い `()` を報告する規則
+///
+/// `'()` のように引用された `()` は空のリストとして使われるので報告しない。
+pub struct EmptyTree;
+
+impl EmptyTree {
+    fn check_siblings(siblings: &[TokenTree], diagnostics: &mut Vec<Diagnostic>) {
+        let quoted = matches!(
+            siblings.first(),
+            Some(TokenTree::Token(Token::Ident("quote" | "quasiquote"), _))
+        );
+        for (i, sibling) in siblings.iter().enumerate() {
+            if matches!(sibling, TokenTree::Tree(children, _) if children.is_empty())
+                && !(quoted && i == 1)
+            {
+                diagnostics.push(Diagnostic::warning(sibling.span(), "empty expression `()`"));
+            }
+        }
+    }
+}
+
+impl Rule for EmptyTree {
+    fn name(&self) -> &'static str {
+        "empty-tree"
+    }
+
+    fn code(&self) -> Option<&'static str> {
+        Some("W0101")
+    }
+
+    fn check(&self, forms: &[TokenTree], diagnostics: &mut Vec<Diagnostic>) {
+        Self::check_siblings(forms, diagnostics);
+        let mut visitor = EachTree(|children: &[TokenTree], _| {
+            Self::check_siblings(children, diagnostics);
+        });
+        for form in forms {
+            walk(form, &mut visitor);
+        }
+    }
+}
+
+/// `(a)(b)` のように空白を挟まずに並んだ部分木を報告する規則
+///
+/// 閉じ括弧の位置を間違えて、1つの式を2つに分けてしまった疑いがある。
+/// `(a)'(b)` のように前置記号を挟む場合は報告しない。
+pub struct AdjacentTrees;
+
+impl AdjacentTrees {
+    fn check_siblings(siblings: &[TokenTree], diagnostics: &mut Vec<Diagnostic>) {
+        for pair in siblings.windows(2) {
+            let (TokenTree::Tree(_, left), TokenTree::Tree(children, right)) = (&pair[0], &pair[1])
+            else {
+                continue;
+            };
+            // 前置記号から組み立てた式は、先頭の名前が部分木と同じ位置から始まる
+            let prefixed = children
+                .first()
+                .is_some_and(|head| head.span().start == right.start);
+            if left.end == right.start && !prefixed {
+                diagnostics.push(
+                    Diagnostic::warning(Span::new(left.end - 1, right.start + 1), "`)(` without a space")
+                        .with_note("separate the expressions with a space, or check where the parenthesis closes"),
+                );
+            }
+        }
+    }
+}
+
+impl Rule for AdjacentTrees {
+    fn name(&self) -> &'static str {
+        "adjacent-trees"
+    }
+
+    fn code(&self) -> Option<&'static str> {
+        Some("W0102")
+    }
+
+    fn check(&self, forms: &[TokenTree], diagnostics: &mut Vec<Diagnostic>) {
+        Self::check_siblings(forms, diagnostics);
+        let mut visitor = EachTree(|children: &[TokenTree], _| {
+            Self::check_siblings(children, diagnostics);
+        });
+        for form in forms {
+            walk(form, &mut visitor);
+        }
+    }
+}
+
+/// 深く入れ子になった括弧を報告する規則
+///
+/// 最上位の式ごとに、最初に深さを超えた部分木だけを報告する。
+pub struct DeepNesting {
+    /// 報告せずに許す入れ子の深さ
+    pub max_depth: usize,
+}
+
+impl Default for DeepNesting {
+    fn default() -> Self {
+        Self { max_depth: 32 }
+    }
+}
+
+/// 深さを数えながら、最初に深さを超えた部分木を探す訪問者
+struct FindDeep {
+    max_depth: usize,
+    depth: usize,
+    found: Option<Span>,
+}
+
+impl<'src> Visitor<'src> for FindDeep {
+    fn visit_tree(&mut self, children: &[TokenTree<'src>], span: Span) {
+        if self.found.is_some() {
+            return;
+        }
+        if self.depth == self.max_depth {
+            self.found = Some(span);
+            return;
+        }
+        self.depth += 1;
+        walk_tree(self, children, span);
+        self.depth -= 1;
+    }
+}
+
+impl Rule for DeepNesting {
+    fn name(&self) -> &'static str {
+        "deep-nesting"
+    }
+
+    fn code(&self) -> Option<&'static str> {
+        Some("W0103")
+    }
+
+    fn check(&self, forms: &[TokenTree], diagnostics: &mut Vec<Diagnostic>) {
+        for form in forms {
+            let mut visitor = FindDeep {
+                max_depth: self.max_depth,
+                depth: 0,
+                found: None,
+            };
+            walk(form, &mut visitor);
+            if let Some(span) = visitor.found {
+                diagnostics.push(Diagnostic::warning(
+                    span,
+                    format!("parentheses nested deeper than {}", self.max_depth),
+                ));
+            }
+        }
+    }
+}
+
+/// 値を使われない最上位のトークンを報告する規則
+///
+/// プログラムの値になる最後の式は報告しない。
+pub struct UnusedAtom;
+
+impl Rule for UnusedAtom {
+    fn name(&self) -> &'static str {
+        "unused-atom"
+    }
+
+    fn code(&self) -> Option<&'static str> {
+        Some("W0104")
+    }
+
+    fn check(&self, forms: &[TokenTree], diagnostics: &mut Vec<Diagnostic>) {
+        let Some((_, init)) = forms.split_last() else {
+            return;
+        };
+        for form in init {
+            let TokenTree::Token(token, span) = form else {
+                continue;
+            };
+            let what = match token {
+                Token::Ident(_) => "variable",
+                _ => "literal",
+            };
+            diagnostics.push(Diagnostic::warning(
+                *span,
+                format!("unused {what} at the top level"),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::source;
+
+    /// 報告を (範囲の文字列, 説明) の組にする
+    fn lint<'a>(linter: &Linter, input: &'a str) -> Vec<(&'a str, String)> {
+        let tree = source(input).unwrap();
+        linter
+            .check(&tree)
+            .into_iter()
+            .map(|d| (&input[d.span.start..d.span.end], d.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_builtin_rules() {
+        let linter = Linter::new();
+        assert_eq!(
+            lint(&linter, "x (f ()) (g (a)(b)) \"s\""),
+            [
+                ("x", "unused variable at the top level".to_string()),
+                ("()", "empty expression `()`".to_string()),
+                (")(", "`)(` without a space".to_string()),
+            ]
+        );
+        assert!(lint(&linter, "(f (a) (b)) (g 'x '() (a)'(b)) 1").is_empty());
+        assert!(lint(&linter, "").is_empty());
+
+        let deep = format!("{}x{}", "(".repeat(40), ")".repeat(40));
+        let found = lint(&linter, &deep);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, &deep[32..49]);
+    }
+
+    #[test]
+    fn test_custom_rule() {
+        /// `print` の呼び出しを報告する
+        struct NoPrint;
+
+        impl Rule for NoPrint {
+            fn name(&self) -> &'static str {
+                "no-print"
+            }
+
+            fn check(&self, forms: &[TokenTree], diagnostics: &mut Vec<Diagnostic>) {
+                for form in forms {
+                    if let TokenTree::Tree(children, span) = form {
+                        if let Some(TokenTree::Token(Token::Ident("print"), _)) = children.first() {
+                            diagnostics.push(Diagnostic::warning(*span, "print call"));
+                        }
+                    }
+                }
+            }
+        }
+
+        let linter = Linter::empty()
+            .with_rule(NoPrint)
+            .with_rule(DeepNesting { max_depth: 1 });
+        let tree = source("(print (f x)) ()").unwrap();
+        let diagnostics = linter.check(&tree);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].message, "print call");
+        assert_eq!(
+            diagnostics[0].note.as_deref(),
+            Some("reported by the `no-print` rule")
+        );
+        // 番号を決めていない規則の報告には番号を付けない
+        assert_eq!(diagnostics[0].code, None);
+        assert_eq!(diagnostics[1].span, Span::new(7, 12));
+        assert_eq!(diagnostics[1].code, Some("W0103"));
+    }
+}
@@ -0,0 +1,583 @@
+//! 標準入出力で Language Server Protocol を話すサーバー
+//!
+//! `lsp` フィーチャーを有効にすると使え、`ruscal-lsp` バイナリから起動する。
+//! 外部クレートに依存しないよう、JSON-RPC のやり取りは [`json`](crate::json) で手書きしている。
+//!
+//! 対応している機能は次の通り。
+//!
+//! * 文書を開く、変更するたびに、解析エラーとリンターの警告を `textDocument/publishDiagnostics` で送る
+//! * `textDocument/documentSymbol` で `define` した名前を返す
+//! * `textDocument/documentHighlight` でカーソル位置の括弧と対応する括弧を返す
+//!
+//! 文書の同期は毎回全文を受け取る方式で、位置の桁は UTF-16 の単位で数える。
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::ast::{Span, Token, TokenTree};
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::json::Json;
+use crate::lint::Linter;
+use crate::parser::source_recovering;
+use crate::source_map::SourceMap;
+use crate::visit::{walk, Visitor};
+
+/// メッセージがJSONとして読めないときのエラーコード
+const PARSE_ERROR: f64 = -32700.0;
+/// `shutdown` の後に要求を受け取ったときのエラーコード
+const INVALID_REQUEST: f64 = -32600.0;
+/// 要求に対応するメソッドが無いときのエラーコード
+const METHOD_NOT_FOUND: f64 = -32601.0;
+/// 要求の引数が正しくないときのエラーコード
+const INVALID_PARAMS: f64 = -32602.0;
+
+/// `SymbolKind.Function`
+const SYMBOL_FUNCTION: f64 = 12.0;
+/// `SymbolKind.Variable`
+const SYMBOL_VARIABLE: f64 = 13.0;
+
+/// 標準入出力などでクライアントとやり取りし、`exit` 通知を受け取るまで応答し続ける関数
+///
+/// # 引数
+/// * `input` - クライアントからのメッセージを読む入力
+/// * `output` - 応答と通知を書き出す出力
+///
+/// # 戻り値
+/// * `io::Result<()>` - `exit` 通知か入力の終わりで `Ok` を返す。読み書きに失敗すればそのエラー
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut server = Server::default();
+    while let Some(body) = read_message(&mut input)? {
+        let replies = match Json::parse(&body) {
+            Ok(message) => server.handle(&message),
+            Err(e) => vec![error_reply(Json::Null, PARSE_ERROR, e.to_string())],
+        };
+        for reply in replies {
+            write_message(&mut output, &reply)?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// `Content-Length` ヘッダーの付いたメッセージを1つ読む関数
+///
+/// # 戻り値
+/// * `io::Result<Option<String>>` - メッセージの本文。入力の終わりなら `None`
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing Content-Length header",
+        ));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// メッセージに `Content-Length` ヘッダーを付けて書き出す関数
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()
+}
+
+/// キーと値の組からオブジェクトを作る
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        fields
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+/// 開いている文書と、終了の状態
+#[derive(Default)]
+struct Server {
+    /// URI ごとの文書の全文
+    documents: HashMap<String, String>,
+    /// `shutdown` 要求を受け取ったかどうか
+    shut_down: bool,
+    /// `exit` 通知を受け取ったかどうか
+    exited: bool,
+}
+
+impl Server {
+    /// メッセージを1つ処理して、クライアントに送るメッセージを返す関数
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = match message.get("method") {
+            Some(Json::String(method)) => method.as_str(),
+            _ => return vec![],
+        };
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let Some(id) = message.get("id") else {
+            return self.notify(method, params);
+        };
+        let result = match method {
+            _ if self.shut_down => Err(INVALID_REQUEST),
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Json::Null)
+            }
+            "textDocument/documentSymbol" => self
+                .document(params)
+                .map(|text| Json::Array(document_symbols(text))),
+            "textDocument/documentHighlight" => self.document(params).and_then(|text| {
+                let offset =
+                    position(params).map(|(line, character)| offset_at(text, line, character));
+                let offset = offset.ok_or(INVALID_PARAMS)?;
+                Ok(Json::Array(bracket_highlights(text, offset)))
+            }),
+            _ => Err(METHOD_NOT_FOUND),
+        };
+        let reply = match result {
+            Ok(result) => object(vec![
+                ("jsonrpc", Json::String("2.0".to_string())),
+                ("id", id.clone()),
+                ("result", result),
+            ]),
+            Err(code) => error_reply(id.clone(), code, format!("cannot handle {method}")),
+        };
+        vec![reply]
+    }
+
+    /// 通知を処理する関数
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        if method == "exit" {
+            self.exited = true;
+            return vec![];
+        }
+        let Some(uri) = uri(params) else {
+            return vec![];
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let Some(Json::String(text)) =
+                    params.get("textDocument").and_then(|d| d.get("text"))
+                else {
+                    return vec![];
+                };
+                self.documents.insert(uri.to_string(), text.clone());
+            }
+            "textDocument/didChange" => {
+                // 全文を送る同期方式なので、最後の変更の内容がそのまま新しい全文になる
+                let Some(Json::Array(changes)) = params.get("contentChanges") else {
+                    return vec![];
+                };
+                let Some(Json::String(text)) = changes.last().and_then(|c| c.get("text")) else {
+                    return vec![];
+                };
+                self.documents.insert(uri.to_string(), text.clone());
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, "", vec![])];
+            }
+            _ => return vec![],
+        }
+        let text = &self.documents[uri];
+        vec![publish_diagnostics(uri, text, check(text))]
+    }
+
+    /// 要求の対象の文書の全文
+    fn document(&self, params: &Json) -> Result<&str, f64> {
+        uri(params)
+            .and_then(|uri| self.documents.get(uri))
+            .map(String::as_str)
+            .ok_or(INVALID_PARAMS)
+    }
+}
+
+/// 要求へのエラーの応答を作る
+fn error_reply(id: Json, code: f64, message: String) -> Json {
+    object(vec![
+        ("jsonrpc", Json::String("2.0".to_string())),
+        ("id", id),
+        (
+            "error",
+            object(vec![
+                ("code", Json::Number(code)),
+                ("message", Json::String(message)),
+            ]),
+        ),
+    ])
+}
+
+/// `initialize` 要求に返すサーバーの機能
+fn capabilities() -> Json {
+    object(vec![
+        (
+            "capabilities",
+            object(vec![
+                // TextDocumentSyncKind.Full
+                ("textDocumentSync", Json::Number(1.0)),
+                ("documentSymbolProvider", Json::Bool(true)),
+                ("documentHighlightProvider", Json::Bool(true)),
+            ]),
+        ),
+        (
+            "serverInfo",
+            object(vec![
+                ("name", Json::String("ruscal-lsp".to_string())),
+                (
+                    "version",
+                    Json::String(env!("CARGO_PKG_VERSION").to_string()),
+                ),
+            ]),
+        ),
+    ])
+}
+
+/// 引数の `textDocument.uri`
+fn uri(params: &Json) -> Option<&str> {
+    match params.get("textDocument")?.get("uri")? {
+        Json::String(uri) => Some(uri),
+        _ => None,
+    }
+}
+
+/// 引数の `position` の (行, 桁) の組
+fn position(params: &Json) -> Option<(usize, usize)> {
+    let position = params.get("position")?;
+    let (Some(Json::Number(line)), Some(Json::Number(character))) =
+        (position.get("line"), position.get("character"))
+    else {
+        return None;
+    };
+    Some((*line as usize, *character as usize))
+}
+
+/// 文書を解析し、解析エラーが無ければリンターの警告を集める関数
+fn check(text: &str) -> Vec<Diagnostic> {
+    let (tree, diagnostics) = source_recovering(text);
+    if diagnostics.is_empty() {
+        Linter::new().check(&tree)
+    } else {
+        diagnostics
+    }
+}
+
+/// `textDocument/publishDiagnostics` 通知を作る
+fn publish_diagnostics(uri: &str, text: &str, diagnostics: Vec<Diagnostic>) -> Json {
+    let map = SourceMap::new(text);
+    let diagnostics = diagnostics
+        .into_iter()
+        .map(|diagnostic| {
+            let severity = match diagnostic.severity {
+                Severity::Error => 1.0,
+                Severity::Warning => 2.0,
+            };
+            let message = match diagnostic.note {
+                Some(note) => format!("{}\n{note}", diagnostic.message),
+                None => diagnostic.message,
+            };
+            let mut fields = vec![
+                ("range", range(&map, diagnostic.span)),
+                ("severity", Json::Number(severity)),
+                ("source", Json::String("ruscal".to_string())),
+                ("message", Json::String(message)),
+            ];
+            if let Some(code) = diagnostic.code {
+                fields.push(("code", Json::String(code.to_string())));
+            }
+            object(fields)
+        })
+        .collect();
+    object(vec![
+        ("jsonrpc", Json::String("2.0".to_string())),
+        (
+            "method",
+            Json::String("textDocument/publishDiagnostics".to_string()),
+        ),
+        (
+            "params",
+            object(vec![
+                ("uri", Json::String(uri.to_string())),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        ),
+    ])
+}
+
+/// バイト位置を、0から数える行と UTF-16 の単位で数える桁の `Position` にする
+fn position_at(map: &SourceMap, offset: usize) -> Json {
+    let line = map.line_col(offset).line;
+    let start = map.line_span(line).map_or(0, |span| span.start);
+    let offset = offset.clamp(start, map.source().len());
+    let character = map
+        .source()
+        .get(start..)
+        .unwrap_or_default()
+        .char_indices()
+        .take_while(|&(i, c)| start + i + c.len_utf8() <= offset)
+        .map(|(_, c)| c.len_utf16())
+        .sum::<usize>();
+    object(vec![
+        ("line", Json::Number((line - 1) as f64)),
+        ("character", Json::Number(character as f64)),
+    ])
+}
+
+/// 範囲を `Range` にする
+fn range(map: &SourceMap, span: Span) -> Json {
+    object(vec![
+        ("start", position_at(map, span.start)),
+        ("end", position_at(map, span.end)),
+    ])
+}
+
+/// 0から数える行と UTF-16 の単位で数える桁を、バイト位置にする関数
+///
+/// 行や桁が文書の外を指す場合は、文書や行の終わりとみなす。
+fn offset_at(text: &str, line: usize, character: usize) -> usize {
+    let map = SourceMap::new(text);
+    let Some(span) = map.line_span(line + 1) else {
+        return text.len();
+    };
+    let mut units = 0;
+    for (i, c) in text
+        .get(span.start..span.end)
+        .unwrap_or_default()
+        .char_indices()
+    {
+        if units >= character {
+            return span.start + i;
+        }
+        units += c.len_utf16();
+    }
+    span.end
+}
+
+/// 文書の `define` した名前を `DocumentSymbol` の一覧にする関数
+///
+/// `(define name (fn ...))` は関数、それ以外は変数とし、内側の `define` は子にする。
+fn document_symbols(text: &str) -> Vec<Json> {
+    let (tree, _) = source_recovering(text);
+    let map = SourceMap::new(text);
+    let TokenTree::Tree(forms, _) = &tree else {
+        return vec![];
+    };
+    symbols(&map, forms)
+}
+
+/// 木の並びに含まれる `define` を探す
+fn symbols(map: &SourceMap, trees: &[TokenTree]) -> Vec<Json> {
+    let mut found = vec![];
+    for tree in trees {
+        let TokenTree::Tree(children, span) = tree else {
+            continue;
+        };
+        let [TokenTree::Token(Token::Ident("define"), _), TokenTree::Token(Token::Ident(name), name_span), value] =
+            children.as_slice()
+        else {
+            found.extend(symbols(map, children));
+            continue;
+        };
+        let kind = match value {
+            TokenTree::Tree(value, _)
+                if matches!(value.first(), Some(TokenTree::Token(Token::Ident("fn"), _))) =>
+            {
+                SYMBOL_FUNCTION
+            }
+            _ => SYMBOL_VARIABLE,
+        };
+        found.push(object(vec![
+            ("name", Json::String(name.to_string())),
+            ("kind", Json::Number(kind)),
+            ("range", range(map, *span)),
+            ("selectionRange", range(map, *name_span)),
+            (
+                "children",
+                Json::Array(symbols(map, std::slice::from_ref(value))),
+            ),
+        ]));
+    }
+    found
+}
+
+/// カーソルの位置で開くか閉じる括弧を探す訪問者
+///
+/// 閉じ括弧はその直後にカーソルがある場合も含め、最も内側の組を選ぶ。
+struct FindBrackets<'a> {
+    text: &'a str,
+    offset: usize,
+    found: Option<(Span, Span)>,
+}
+
+impl<'src> Visitor<'src> for FindBrackets<'_> {
+    fn enter_tree(&mut self, children: &[TokenTree<'src>], span: Span) {
+        let _ = children;
+        let bytes = self.text.as_bytes();
+        // 前置記号から組み立てた式や、閉じられていない括弧は対象にしない
+        if span.is_empty() || bytes[span.start] != b'(' || bytes[span.end - 1] != b')' {
+            return;
+        }
+        let open = Span::new(span.start, span.start + 1);
+        let close = Span::new(span.end - 1, span.end);
+        if self.offset == open.start || self.offset == close.start || self.offset == close.end {
+            self.found = Some((open, close));
+        }
+    }
+}
+
+/// カーソル位置の括弧と対応する括弧を `DocumentHighlight` の一覧にする関数
+fn bracket_highlights(text: &str, offset: usize) -> Vec<Json> {
+    let (tree, _) = source_recovering(text);
+    let TokenTree::Tree(forms, _) = &tree else {
+        return vec![];
+    };
+    let mut visitor = FindBrackets {
+        text,
+        offset,
+        found: None,
+    };
+    for form in forms {
+        walk(form, &mut visitor);
+    }
+    let map = SourceMap::new(text);
+    visitor
+        .found
+        .into_iter()
+        .flat_map(|(open, close)| [open, close])
+        .map(|span| object(vec![("range", range(&map, span))]))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// メッセージを `Content-Length` 付きでつなげる
+    fn frame(messages: &[&str]) -> Vec<u8> {
+        let mut out = vec![];
+        for message in messages {
+            write!(out, "Content-Length: {}\r\n\r\n{message}", message.len()).unwrap();
+        }
+        out
+    }
+
+    /// 書き出されたメッセージを読み戻す
+    fn replies(output: &[u8]) -> Vec<Json> {
+        let mut input = output;
+        let mut replies = vec![];
+        while let Some(body) = read_message(&mut input).unwrap() {
+            replies.push(Json::parse(&body).unwrap());
+        }
+        replies
+    }
+
+    fn number(json: &Json, path: &[&str]) -> f64 {
+        let value = path.iter().fold(json, |json, key| json.get(key).unwrap());
+        let Json::Number(n) = value else {
+            panic!("{value} is not a number");
+        };
+        *n
+    }
+
+    #[test]
+    fn test_session() {
+        let input = frame(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.rscl","languageId":"ruscal","version":1,"text":"(define f (fn (x) x))\n(f ()"}}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.rscl","version":2},"contentChanges":[{"text":"(define f (fn (x) x))\n(f ())"}]}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/documentSymbol","params":{"textDocument":{"uri":"file:///a.rscl"}}}"#,
+            r#"{"jsonrpc":"2.0","id":"h","method":"textDocument/documentHighlight","params":{"textDocument":{"uri":"file:///a.rscl"},"position":{"line":1,"character":6}}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"unknown","params":{}}"#,
+            "{not json",
+            r#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ]);
+        let mut output = vec![];
+        serve(input.as_slice(), &mut output).unwrap();
+        let replies = replies(&output);
+        assert_eq!(replies.len(), 8);
+
+        assert_eq!(
+            replies[0]
+                .get("result")
+                .unwrap()
+                .get("capabilities")
+                .unwrap()
+                .get("documentSymbolProvider"),
+            Some(&Json::Bool(true))
+        );
+
+        // 開いたときは閉じられていない括弧のエラー、変更後は空の `()` の警告
+        let Some(Json::Array(errors)) = replies[1].get("params").unwrap().get("diagnostics") else {
+            panic!("no diagnostics in {}", replies[1]);
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(number(&errors[0], &["severity"]), 1.0);
+        assert_eq!(
+            errors[0].get("code"),
+            Some(&Json::String("E0001".to_string()))
+        );
+        let Some(Json::Array(warnings)) = replies[2].get("params").unwrap().get("diagnostics")
+        else {
+            panic!("no diagnostics in {}", replies[2]);
+        };
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(number(&warnings[0], &["severity"]), 2.0);
+        assert_eq!(number(&warnings[0], &["range", "start", "line"]), 1.0);
+        assert_eq!(number(&warnings[0], &["range", "start", "character"]), 3.0);
+
+        let Some(Json::Array(symbols)) = replies[3].get("result") else {
+            panic!("no symbols in {}", replies[3]);
+        };
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].get("name"), Some(&Json::String("f".to_string())));
+        assert_eq!(number(&symbols[0], &["kind"]), SYMBOL_FUNCTION);
+
+        assert_eq!(replies[4].get("id"), Some(&Json::String("h".to_string())));
+        let Some(Json::Array(highlights)) = replies[4].get("result") else {
+            panic!("no highlights in {}", replies[4]);
+        };
+        let characters: Vec<_> = highlights
+            .iter()
+            .map(|h| number(h, &["range", "start", "character"]))
+            .collect();
+        assert_eq!(characters, [0.0, 5.0]);
+
+        assert_eq!(number(&replies[5], &["error", "code"]), METHOD_NOT_FOUND);
+        assert_eq!(number(&replies[6], &["error", "code"]), PARSE_ERROR);
+        assert_eq!(replies[7].get("result"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn test_utf16_positions() {
+        let text = "(a \"𝄞\")\n(b)";
+        let map = SourceMap::new(text);
+        let end = position_at(&map, text.find(')').unwrap());
+        assert_eq!(number(&end, &["character"]), 7.0);
+        // 文字の途中を指す位置は、その文字の先頭とみなす
+        let inside = position_at(&map, text.find('𝄞').unwrap() + 1);
+        assert_eq!(number(&inside, &["character"]), 4.0);
+        assert_eq!(offset_at(text, 0, 7), text.find(')').unwrap());
+        assert_eq!(offset_at(text, 1, 1), text.rfind('b').unwrap());
+        assert_eq!(offset_at(text, 1, 99), text.len());
+        assert_eq!(offset_at(text, 5, 0), text.len());
+    }
+}
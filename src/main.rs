@@ -1,210 +1,987 @@
-fn main() {
-    let s = "Hello world";
-    println!("source: {:?}, parsed:\n {:?}", s, source(s));
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-    let s = "(123  456 ) world";
-    println!("source: {:?}, parsed:\n {:?}", s, source(s));
+use ruscal_b::debugger::Debugger;
+use ruscal_b::dump::{to_dot, to_sexpr};
+use ruscal_b::engine::Engine;
+use ruscal_b::eval::{lower, with_stack_size, EvalError};
+use ruscal_b::fmt::pretty;
+use ruscal_b::format::format_source;
+use ruscal_b::io::{set_io_policy, IoPolicy};
+use ruscal_b::json::{Json, ToJson};
+use ruscal_b::line_editor::RawMode;
+use ruscal_b::lint::Linter;
+use ruscal_b::module;
+use ruscal_b::optimize::fold_constants;
+use ruscal_b::parser::source;
+use ruscal_b::peephole::optimize;
+use ruscal_b::profile;
+use ruscal_b::register_vm::RegisterVm;
+use ruscal_b::rsclc::MAGIC;
+use ruscal_b::source_map::LineCol;
+use ruscal_b::stdlib;
+use ruscal_b::transpile;
+use ruscal_b::wasm_codegen;
+use ruscal_b::{
+    check, compile_program, repl, source_recovering, Bytecode, ColorChoice, Diagnostic,
+    Environment, Expr, SourceMap, Span, Statement, TokenTree, Value, Vm,
+};
 
-    let s = "((car cdr) cdr)";
-    println!("source: {:?}, parsed:\n {:?}", s, source(s));
+const USAGE: &str = "\
+usage: ruscal [--error-format=<human|json>] [--color=<when>] <command> [options]
 
-    let s = "()())))((()))";
-    println!("source: {:?}, parsed:\n {:?}", s, source(s));
-}
+commands:
+  parse <file> [options]   parse a file (`-` reads stdin) and print the tree
+  ast <file> [--format <f>]
+                           print the abstract syntax tree of a file (`-` reads stdin)
+  compile <file> [-o <out>] [--dump-bytecode]
+                           compile a file to optimized bytecode (default: <file>.rsclc)
+  dis <file> [--source <src>]
+                           print the bytecode of a compiled .rsclc file (or of a compiled
+                           source file) with source lines from <src>
+  transpile <file> [-o <out>]
+                           translate a file whose types are known into a standalone
+                           Rust source file (default: <file>.rs)
+  build [--target=wasm] <file> [-o <out>]
+                           compile a file whose types are known into a WebAssembly
+                           module (default: <file>.wasm)
+  run <file> [options] [-- <args>...]
+                           run a source file or a compiled .rsclc file
+  debug <file>             run a source file line by line, reading debugger commands from
+                           stdin (`help` lists them)
+  fmt <file> [options]     print a file (`-` reads stdin) in canonical formatting
+  lint <file>              report suspicious code in a file (`-` reads stdin)
+  test [<path>]...         run the `*.test.rscl` files in the given files or directories
+                           (default: the current directory) and report the failures
+  repl                     start an interactive session (`:help` lists its commands, Tab
+                           completes names; input is appended to ~/.ruscal_history)
+
+ast options:
+  --format <f>   one of `sexpr` (default), `debug`, `json` or `dot` (a Graphviz graph)
+
+global options:
+  --error-format=json   report errors and warnings as one JSON object per line on stderr
+  --color=<when>        one of `auto` (default), `always` or `never`; `auto` colors output
+                        on a terminal unless NO_COLOR is set
+
+parse options:
+  --json         print the tree as JSON
+  --ast          print the evaluated expressions as JSON
+  --debug        print the tree with Rust's debug formatting
+  --width <n>    wrap pretty-printed output at <n> columns (default: 80)
+
+run options:
+  -I <dir>       also look for imported modules in <dir> (after the file's directory)
+  --allow-read <dir>
+                 let `read_file` read files inside <dir>
+  --allow-write <dir>
+                 let `write_file` write files inside <dir>
+  --allow-stdin  let `read_line` read standard input
+  --engine <e>   evaluate with `tree` (walk the syntax tree), `stack` (default; the stack
+                 bytecode VM) or `register` (the register VM)
+  --dump-bytecode
+                 print the bytecode before and after the peephole optimization on stderr
+                 (also accepted by `compile`)
+  --profile      time each top-level form and compiled function and print a report
+                 of the hot spots on stderr
+  -- <args>...   pass the remaining arguments to the program as `args()`
 
-/// 次の文字を進める関数
+fmt options:
+  --width <n>    wrap at <n> columns (default: 80)
+  --write        rewrite the file in place instead of printing it
+  --check        print nothing and fail if the file is not formatted";
+
+/// プログラムを評価するスレッドのスタックのバイト数
 ///
-/// # 引数
-/// * `input` - 進める対象の文字列
+/// 木をたどる評価器は1回の呼び出しに数十 KiB のスタックを使うことがあるので、スタックの予算より先に
+/// 既定の呼び出しの深さの上限に届き、どの評価の方式でも同じ深さで止まるよう大きめに取る。
+/// 使わない分のスタックは実際には確保されない。
+const STACK_SIZE: usize = if cfg!(target_pointer_width = "64") {
+    1 << 30
+} else {
+    256 << 20
+};
+
+/// `true` ならエラーと警告の報告を JSON で書き出す
 ///
-/// # 戻り値
-/// * `&str` - 進めた後の文字列
-fn advance_char(input: &str) -> &str {
-    let mut chars = input.chars();
-    chars.next();
-    chars.as_str()
+/// `run` は別のスレッドで実行するので、スレッドごとの値ではなく全体で1つの値にする。
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// `true` なら人が読む形式の報告に色を付ける
+static COLOR_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// `run` で実行するプログラム
+enum Program {
+    /// ソースコードを読み込み、定数を畳み込んだ式の並び
+    Source(Vec<Expr>),
+    /// `.rsclc` 形式のファイルから読み込んだ命令列
+    Compiled(Bytecode),
 }
 
-/// 次の文字を見る関数
-///
-/// # 引数
-/// * `input` - 見る対象の文字列
-///
-/// # 戻り値
-/// * `Option<char>` - 次の文字
-fn peek_char(input: &str) -> Option<char> {
-    input.chars().next()
+/// 解析結果の出力形式
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Pretty,
+    Debug,
+    Json,
+    Ast,
 }
 
-/// ソースコードを解析してトークンのリストを返す関数
-///
-/// # 引数
-/// * `input` - 解析対象の文字列
-///
-/// # 戻り値
-/// * `Vec<Token>` - 解析結果のトークンのリスト
-fn source(mut input: &str) -> (&str, TokenTree) {
-    let mut tokens = vec![];
-    while !input.is_empty() {
-        input = if let Some((next_input, token)) = token(input) {
-            match token {
-                Token::LParen => {
-                    let (next_input, tt) = source(next_input);
-                    tokens.push(tt);
-                    next_input
+fn main() -> ExitCode {
+    let mut args: Vec<String> = vec![];
+    let mut color = ColorChoice::Auto;
+    for arg in std::env::args().skip(1) {
+        if let Some(when) = arg.strip_prefix("--color=") {
+            match ColorChoice::from_name(when) {
+                Some(choice) => color = choice,
+                None => return usage_error("--color expects auto, always or never"),
+            }
+            continue;
+        }
+        match arg.strip_prefix("--error-format=") {
+            Some("json") => JSON_ERRORS.store(true, Ordering::Relaxed),
+            Some("human") => JSON_ERRORS.store(false, Ordering::Relaxed),
+            Some(format) => return usage_error(&format!("unknown error format: {format}")),
+            None => args.push(arg),
+        }
+    }
+    COLOR_ERRORS.store(color.enabled(io::stderr().is_terminal()), Ordering::Relaxed);
+    match args.first().map(String::as_str) {
+        Some("parse") => parse(&args[1..]),
+        Some("ast") => ast(&args[1..]),
+        Some("compile") => compile(&args[1..]),
+        Some("dis") => dis(&args[1..]),
+        Some("transpile") => transpile(&args[1..]),
+        Some("build") => build(&args[1..]),
+        Some("run") => with_stack_size(STACK_SIZE, || run(&args[1..])),
+        Some("debug") => with_stack_size(STACK_SIZE, || debug(&args[1..])),
+        Some("fmt") => fmt(&args[1..]),
+        Some("lint") => lint(&args[1..]),
+        Some("test") => with_stack_size(STACK_SIZE, || test(&args[1..])),
+        Some("repl") => {
+            let history = repl::history_path();
+            let color = color.enabled(io::stdout().is_terminal());
+            let session = move || {
+                set_io_policy(IoPolicy::DENY_ALL.allow_import("."));
+                let session = repl::Repl::new().with_color(color);
+                let mut session = match history {
+                    Some(path) => session.with_history(path),
+                    None => session,
+                };
+                // 端末ならキーを1つずつ読んで Tab キーで補完し、そうでなければ行ごとに読む
+                match RawMode::enable() {
+                    Some(_raw) => session.run_editing(io::stdin().lock(), io::stdout()),
+                    None => session.run(io::stdin().lock(), io::stdout()),
                 }
-                Token::RParen => return (next_input, TokenTree::Tree(tokens)),
-                _ => {
-                    tokens.push(TokenTree::Token(token));
-                    next_input
+            };
+            match with_stack_size(STACK_SIZE, session) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("error: {e}");
+                    ExitCode::FAILURE
                 }
             }
-        } else {
-            break;
         }
+        Some("-h" | "--help") => {
+            println!("{USAGE}");
+            ExitCode::SUCCESS
+        }
+        Some(cmd) => usage_error(&format!("unknown command: {cmd}")),
+        None => usage_error("missing command"),
     }
-    (input, TokenTree::Tree(tokens))
 }
 
-#[derive(Debug, PartialEq)]
-enum Token<'src> {
-    Ident(&'src str),
-    Number(f64),
-    LParen,
-    RParen,
+/// `parse` サブコマンド
+fn parse(args: &[String]) -> ExitCode {
+    let mut format = OutputFormat::Pretty;
+    let mut width = 80;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => format = OutputFormat::Json,
+            "--debug" => format = OutputFormat::Debug,
+            "--ast" => format = OutputFormat::Ast,
+            "--width" => match args.next().map(|n| n.parse()) {
+                Some(Ok(n)) => width = n,
+                _ => return usage_error("--width expects a number"),
+            },
+            opt if opt.starts_with("--") => return usage_error(&format!("unknown option: {opt}")),
+            _ if path.is_some() => return usage_error("too many input files"),
+            file => path = Some(file),
+        }
+    }
+    let Some(path) = path else {
+        return usage_error("missing input file");
+    };
+
+    let input = match read_input(path) {
+        Ok(input) => input,
+        Err(e) => {
+            return fail(path, e);
+        }
+    };
+    let tree = match parse_or_report(path, &input) {
+        Ok(tree) => tree,
+        Err(code) => return code,
+    };
+    let TokenTree::Tree(forms, _) = &tree else {
+        unreachable!("source_recovering() always returns a tree");
+    };
+    match format {
+        OutputFormat::Pretty => {
+            for form in forms {
+                println!("{}", pretty(form, width));
+            }
+        }
+        OutputFormat::Debug => println!("{tree:#?}"),
+        OutputFormat::Json => println!("{}", tree.to_json()),
+        OutputFormat::Ast => match forms.iter().map(lower).collect::<Result<Vec<_>, _>>() {
+            Ok(exprs) => println!(
+                "{}",
+                Json::Array(exprs.iter().map(ToJson::to_json).collect())
+            ),
+            Err(e) => {
+                report(path, Some(&input), &[Diagnostic::from(&e)]);
+                return ExitCode::FAILURE;
+            }
+        },
+    }
+    ExitCode::SUCCESS
+}
+
+/// `fmt` サブコマンド
+fn fmt(args: &[String]) -> ExitCode {
+    let mut width = 80;
+    let mut write = false;
+    let mut check = false;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--write" => write = true,
+            "--check" => check = true,
+            "--width" => match args.next().map(|n| n.parse()) {
+                Some(Ok(n)) => width = n,
+                _ => return usage_error("--width expects a number"),
+            },
+            opt if opt.starts_with("--") => return usage_error(&format!("unknown option: {opt}")),
+            _ if path.is_some() => return usage_error("too many input files"),
+            file => path = Some(file),
+        }
+    }
+    let Some(path) = path else {
+        return usage_error("missing input file");
+    };
+    if write && (check || path == "-") {
+        return usage_error("--write needs a file and cannot be combined with --check");
+    }
+
+    let input = match read_input(path) {
+        Ok(input) => input,
+        Err(e) => {
+            return fail(path, e);
+        }
+    };
+    let formatted = match format_source(&input, width) {
+        Ok(formatted) => formatted,
+        Err(diagnostics) => {
+            report(path, Some(&input), &diagnostics);
+            summary(format_args!(
+                "error: {path}: could not format due to {} error(s)",
+                diagnostics.len()
+            ));
+            return ExitCode::FAILURE;
+        }
+    };
+    if check {
+        if formatted == input {
+            return ExitCode::SUCCESS;
+        }
+        return fail(path, "not formatted");
+    }
+    if write {
+        if formatted != input {
+            if let Err(e) = std::fs::write(path, formatted) {
+                return fail(path, e);
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+    print!("{formatted}");
+    ExitCode::SUCCESS
 }
 
-#[derive(Debug, PartialEq)]
-enum TokenTree<'src> {
-    Token(Token<'src>),
-    Tree(Vec<TokenTree<'src>>),
+/// `lint` サブコマンド
+fn lint(args: &[String]) -> ExitCode {
+    let [path] = args else {
+        return usage_error("lint expects exactly one file");
+    };
+    let input = match read_input(path) {
+        Ok(input) => input,
+        Err(e) => {
+            return fail(path, e);
+        }
+    };
+    let tree = match parse_or_report(path, &input) {
+        Ok(tree) => tree,
+        Err(code) => return code,
+    };
+    let warnings = Linter::new().check(&tree);
+    if warnings.is_empty() {
+        return ExitCode::SUCCESS;
+    }
+    report(path, Some(&input), &warnings);
+    summary(format_args!(
+        "warning: {path}: {} warning(s)",
+        warnings.len()
+    ));
+    ExitCode::FAILURE
 }
 
-fn token(input: &str) -> Option<(&str, Token)> {
-    if let Some(res) = ident(whitespace(input)) {
-        return Some(res);
+/// `test` サブコマンド
+///
+/// ファイルごとに、最上位で定義した引数の無い `test_` で始まる名前の関数を、定義した順に呼び出す。
+/// エラーにならずに戻ったテストを成功とし、そのような関数の無いファイルはファイル全体を1つのテストとする。
+/// 失敗したテストが1つでもあれば失敗の終了コードを返す。
+fn test(args: &[String]) -> ExitCode {
+    if let Some(opt) = args.iter().find(|arg| arg.starts_with('-')) {
+        return usage_error(&format!("unknown option: {opt}"));
+    }
+    let roots: Vec<PathBuf> = if args.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        args.iter().map(PathBuf::from).collect()
+    };
+    let mut files = vec![];
+    for root in roots {
+        if root.is_dir() {
+            find_tests(&root, &mut files);
+        } else {
+            files.push(root);
+        }
+    }
+    if files.is_empty() {
+        return fail("test", "no `*.test.rscl` files found");
+    }
+    let (mut passed, mut failed) = (0, 0);
+    for file in &files {
+        let (p, f) = run_tests(&file.display().to_string());
+        passed += p;
+        failed += f;
     }
+    let result = if failed == 0 { "ok" } else { "FAILED" };
+    println!("\ntest result: {result}. {passed} passed; {failed} failed");
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
 
-    if let Some(res) = number(whitespace(input)) {
-        return Some(res);
+/// ディレクトリの中の `*.test.rscl` を、名前の順に再帰して探す
+///
+/// `.` で始まる名前のディレクトリと `target` は探さない。
+fn find_tests(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+    paths.sort();
+    for path in paths {
+        let name = path
+            .file_name()
+            .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+        if path.is_dir() {
+            if !name.starts_with('.') && name != "target" {
+                find_tests(&path, files);
+            }
+        } else if name.ends_with(".test.rscl") {
+            files.push(path);
+        }
     }
+}
 
-    if let Some(res) = lparen(whitespace(input)) {
-        return Some(res);
+/// 1つのファイルのテストを実行し、成功と失敗の数を返す
+fn run_tests(path: &str) -> (usize, usize) {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    set_io_policy(IoPolicy::DENY_ALL.allow_import(&dir));
+    module::set_search_paths(vec![dir]);
+    let Ok((input, exprs)) = load_program(path) else {
+        println!("test {path} ... FAILED");
+        return (0, 1);
+    };
+    let mut env = Environment::new();
+    stdlib::register(&mut env);
+    let mut vm = Vm::new();
+    if let Err(e) = vm.run(&compile_program(&exprs), &mut env) {
+        println!("test {path} ... FAILED");
+        report(path, Some(&input), &[Diagnostic::from(&e)]);
+        return (0, 1);
+    }
+    let mut tests: Vec<_> = env
+        .bindings()
+        .into_iter()
+        .filter_map(|(name, value)| match value {
+            Value::Fn(function)
+                if name.as_str().starts_with("test_") && function.params.is_empty() =>
+            {
+                Some((function.span.start, name))
+            }
+            _ => None,
+        })
+        .collect();
+    if tests.is_empty() {
+        println!("test {path} ... ok");
+        return (1, 0);
+    }
+    tests.sort();
+    let (mut passed, mut failed) = (0, 0);
+    for (_, name) in tests {
+        let call = format!("({name})");
+        let TokenTree::Tree(forms, _) = source(&call).expect("a call parses") else {
+            unreachable!("source() always returns a tree");
+        };
+        let call = lower(&forms[0]).expect("a call lowers");
+        match vm.run(&ruscal_b::compile(&call), &mut env) {
+            Ok(_) => {
+                println!("test {path}::{name} ... ok");
+                passed += 1;
+            }
+            Err(e) => {
+                println!("test {path}::{name} ... FAILED");
+                report(path, Some(&input), &[Diagnostic::from(&e)]);
+                failed += 1;
+            }
+        }
     }
+    (passed, failed)
+}
 
-    if let Some(res) = rparen(whitespace(input)) {
-        return Some(res);
+/// `ast` サブコマンド
+fn ast(args: &[String]) -> ExitCode {
+    let mut format = "sexpr";
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => match args.next().map(String::as_str) {
+                Some(f @ ("sexpr" | "debug" | "json" | "dot")) => format = f,
+                _ => return usage_error("--format expects sexpr, debug, json or dot"),
+            },
+            opt if opt.starts_with("--") => return usage_error(&format!("unknown option: {opt}")),
+            _ if path.is_some() => return usage_error("too many input files"),
+            file => path = Some(file),
+        }
     }
+    let Some(path) = path else {
+        return usage_error("missing input file");
+    };
 
-    None
+    let input = match read_input(path) {
+        Ok(input) => input,
+        Err(e) => return fail(path, e),
+    };
+    let TokenTree::Tree(forms, _) = (match parse_or_report(path, &input) {
+        Ok(tree) => tree,
+        Err(code) => return code,
+    }) else {
+        unreachable!("source_recovering() always returns a tree");
+    };
+    let exprs = match forms.iter().map(lower).collect::<Result<Vec<_>, _>>() {
+        Ok(exprs) => exprs,
+        Err(e) => {
+            report(path, Some(&input), &[Diagnostic::from(&e)]);
+            return ExitCode::FAILURE;
+        }
+    };
+    match format {
+        "debug" => println!("{exprs:#?}"),
+        "json" => println!(
+            "{}",
+            Json::Array(exprs.iter().map(ToJson::to_json).collect())
+        ),
+        "dot" => print!("{}", to_dot(&exprs)),
+        _ => {
+            for expr in &exprs {
+                println!("{}", to_sexpr(expr));
+            }
+        }
+    }
+    ExitCode::SUCCESS
 }
 
-fn whitespace(mut input: &str) -> &str {
-    while matches!(peek_char(input), Some(' ')) {
-        let mut chars = input.chars();
-        chars.next();
-        input = chars.as_str();
+/// `compile` サブコマンド
+fn compile(args: &[String]) -> ExitCode {
+    let dump = args.iter().any(|arg| arg == "--dump-bytecode");
+    let args: Vec<_> = args
+        .iter()
+        .filter(|arg| *arg != "--dump-bytecode")
+        .collect();
+    let (path, out) = match args[..] {
+        [path] => (path, Path::new(path).with_extension("rsclc")),
+        [path, opt, out] if opt == "-o" => (path, out.into()),
+        _ => return usage_error("compile expects <file> [-o <out>] [--dump-bytecode]"),
+    };
+    let (input, exprs) = match load_program(path) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+    let mut bytecode = compile_optimized(&exprs, dump.then_some(&input));
+    bytecode.attach_lines(&input);
+    let res = File::create(&out).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        bytecode.write(&mut writer)?;
+        writer.flush()
+    });
+    if let Err(e) = res {
+        return fail(&out.display().to_string(), e);
     }
-    input
+    ExitCode::SUCCESS
 }
 
-/// 識別子（アルファベットで始まり、その後にアルファベットまたは数字が続く文字列）を解析する関数
+/// `dis` サブコマンド
 ///
-/// # 引数
-/// * `input` - 解析対象の文字列
-///
-/// # 戻り値
-/// * `(&str, Option<Token>)` - (残りの入力文字列, 解析結果のトークン)のタプル
-///   - 識別子として解析できた場合は `Some(Token::Indent)` を返す
-///   - 解析できなかった場合は `None` を返す
-fn ident(mut input: &str) -> Option<(&str, Token)> {
-    let start = input;
-    if matches!(peek_char(input), Some(_x @ ('a'..='z' | 'A'..='Z'))) {
-        input = advance_char(input);
-        while matches!(
-            peek_char(input),
-            Some(_x @ ('a'..='z' | 'A'..='Z' | '0'..='9'))
-        ) {
-            input = advance_char(input);
-        }
-        Some((input, Token::Ident(&start[..(start.len() - input.len())])))
+/// `.rsclc` 形式のファイルは読み込んだ命令列を、ソースコードは `compile` と同じ命令列を一覧にする。
+/// ソースコードが分かれば、命令の位置をその行と桁で示す。
+fn dis(args: &[String]) -> ExitCode {
+    let (path, source) = match args {
+        [path] => (path, None),
+        [path, opt, source] if opt == "--source" => (path, Some(source)),
+        _ => return usage_error("dis expects <file> [--source <src>]"),
+    };
+    let (bytecode, input) = if is_compiled(path) {
+        let res = File::open(path).and_then(|file| Bytecode::read(&mut BufReader::new(file)));
+        let bytecode = match res {
+            Ok(bytecode) => bytecode,
+            Err(e) => return fail(path, e),
+        };
+        match source.map(|source| read_input(source).map_err(|e| fail(source, e))) {
+            Some(Err(code)) => return code,
+            input => (bytecode, input.and_then(Result::ok)),
+        }
     } else {
-        None
+        match load_program(path) {
+            Ok((input, exprs)) => (compile_optimized(&exprs, None), Some(input)),
+            Err(code) => return code,
+        }
+    };
+    match input {
+        Some(input) => print!("{}", bytecode.disassemble_source(&input)),
+        None => print!("{}", bytecode.disassemble()),
     }
+    ExitCode::SUCCESS
 }
 
-/// 数値を解析する関数
+/// `transpile` サブコマンド
 ///
-/// # 引数
-/// * `input` - 解析対象の文字列
+/// 最上位の式を文として [`transpile::transpile`] に渡し、生成した Rust のソースを書き出す。
+fn transpile(args: &[String]) -> ExitCode {
+    let (path, out) = match args {
+        [path] => (path, Path::new(path).with_extension("rs")),
+        [path, opt, out] if opt == "-o" => (path, out.into()),
+        _ => return usage_error("transpile expects <file> [-o <out>]"),
+    };
+    let (input, exprs) = match load_program(path) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+    let statements: Vec<_> = exprs.into_iter().map(Statement::Expr).collect();
+    let rust = match transpile::transpile(&statements) {
+        Ok(rust) => rust,
+        Err(e) => {
+            report(path, Some(&input), &[Diagnostic::from(&e)]);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = std::fs::write(&out, rust) {
+        return fail(&out.display().to_string(), e);
+    }
+    ExitCode::SUCCESS
+}
+
+/// `build` サブコマンド
 ///
-/// # 戻り値
-/// * `(&str, Option<Token>)` - (残りの入力文字列, 解析結果のトークン)のタプル
-fn number(mut input: &str) -> Option<(&str, Token)> {
-    let start = input;
-    if matches!(peek_char(input), Some(_x @ ('-' | '+' | '.' | '0'..='9'))) {
-        input = advance_char(input);
-        while matches!(peek_char(input), Some(_x @ ('.' | '0'..='9'))) {
-            input = advance_char(input);
-        }
-        if let Ok(num) = start[..(start.len() - input.len())].parse::<f64>() {
-            Some((input, Token::Number(num)))
-        } else {
-            None
+/// 出力の形式は今は `wasm` だけで、最上位の式を文として [`wasm_codegen::compile`] に渡し、
+/// 生成したモジュールを書き出す。
+fn build(args: &[String]) -> ExitCode {
+    let mut target = "wasm";
+    let mut args = args;
+    if let Some((first, rest)) = args.split_first() {
+        if let Some(name) = first.strip_prefix("--target=") {
+            target = name;
+            args = rest;
+        }
+    }
+    if target != "wasm" {
+        return usage_error(&format!("unknown build target: {target}"));
+    }
+    let (path, out) = match args {
+        [path] => (path, Path::new(path).with_extension("wasm")),
+        [path, opt, out] if opt == "-o" => (path, out.into()),
+        _ => return usage_error("build expects [--target=wasm] <file> [-o <out>]"),
+    };
+    let (input, exprs) = match load_program(path) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+    let statements: Vec<_> = exprs.into_iter().map(Statement::Expr).collect();
+    let module = match wasm_codegen::compile(&statements) {
+        Ok(module) => module,
+        Err(e) => {
+            report(path, Some(&input), &[Diagnostic::from(&e)]);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = std::fs::write(&out, module) {
+        return fail(&out.display().to_string(), e);
+    }
+    ExitCode::SUCCESS
+}
+
+/// `run` サブコマンド
+///
+/// ファイルが `.rsclc` 形式ならそのまま、そうでなければコンパイルしてから実行する。
+/// `import` はファイルのあるディレクトリ、`-I` で指定したディレクトリの順に探し、それらの中のファイルだけを読み込む。
+fn run(args: &[String]) -> ExitCode {
+    let mut search_paths = vec![];
+    let mut policy = IoPolicy::DENY_ALL;
+    let mut profiling = false;
+    let mut dump = false;
+    let mut engine = Engine::default();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--engine" => match args.next().and_then(|name| Engine::from_name(name)) {
+                Some(name) => engine = name,
+                None => return usage_error("--engine expects tree, stack or register"),
+            },
+            "-I" => match args.next() {
+                Some(dir) => search_paths.push(PathBuf::from(dir)),
+                None => return usage_error("-I expects a directory"),
+            },
+            "--allow-read" => match args.next() {
+                Some(dir) => policy = policy.allow_read(dir),
+                None => return usage_error("--allow-read expects a directory"),
+            },
+            "--allow-write" => match args.next() {
+                Some(dir) => policy = policy.allow_write(dir),
+                None => return usage_error("--allow-write expects a directory"),
+            },
+            "--allow-stdin" => policy = policy.allow_stdin(),
+            "--profile" => profiling = true,
+            "--dump-bytecode" => dump = true,
+            "--" => {
+                policy = policy.with_args(args.by_ref());
+                break;
+            }
+            opt if opt.starts_with('-') && opt != "-" => {
+                return usage_error(&format!("unknown option: {opt}"))
+            }
+            _ if path.is_some() => return usage_error("run expects exactly one file"),
+            file => path = Some(file),
+        }
+    }
+    let Some(path) = path else {
+        return usage_error("run expects exactly one file");
+    };
+    let dir = match Path::new(path).parent() {
+        Some(dir) if path != "-" && !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    search_paths.insert(0, dir);
+    // モジュールを探すディレクトリからは、`--allow-read` が無くても `import` できる
+    for dir in &search_paths {
+        policy = policy.allow_import(dir);
+    }
+    module::set_search_paths(search_paths);
+    // コンパイル済みのファイルにはソースコードが無いので、エラーの位置を行で示せない
+    let (input, program) = if is_compiled(path) {
+        if engine == Engine::Tree {
+            return usage_error("the tree engine cannot run a compiled file");
+        }
+        let res = File::open(path).and_then(|file| Bytecode::read(&mut BufReader::new(file)));
+        match res {
+            Ok(bytecode) => (None, Program::Compiled(bytecode)),
+            Err(e) => {
+                return fail(path, e);
+            }
         }
     } else {
-        None
+        match load_program(path) {
+            Ok((input, exprs)) => (Some(input), Program::Source(exprs)),
+            Err(code) => return code,
+        }
+    };
+    if dump {
+        match &program {
+            Program::Source(exprs) => {
+                compile_optimized(exprs, input.as_deref());
+            }
+            Program::Compiled(bytecode) => eprint!("; {path}\n{}", bytecode.disassemble()),
+        }
+    }
+    set_io_policy(policy);
+    let mut env = Environment::new();
+    stdlib::register(&mut env);
+    if profiling {
+        profile::start_profile();
+    }
+    let mut res = Ok(Value::Nil);
+    match &program {
+        Program::Compiled(bytecode) => {
+            let _profile = profile::enter("<top level>", Span::default());
+            res = match engine {
+                Engine::Register => RegisterVm::new().run(bytecode, &mut env),
+                _ => Vm::new().run(bytecode, &mut env),
+            };
+        }
+        // 計測するときは、最上位の式ごとに時間を測れるよう式を1つずつ評価する
+        Program::Source(exprs) if profiling => {
+            let mut executable = engine.executable();
+            for expr in exprs {
+                let _profile = profile::enter("<top level>", expr.span);
+                res = executable.execute(std::slice::from_ref(expr), &mut env);
+                if res.is_err() {
+                    break;
+                }
+            }
+        }
+        Program::Source(exprs) => res = engine.executable().execute(exprs, &mut env),
+    }
+    if profiling {
+        eprint!("{}", profile::finish_profile().report(input.as_deref()));
+    }
+    match res {
+        Ok(value) => {
+            println!("{value}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            match &program {
+                Program::Compiled(bytecode) => {
+                    report_compiled(path, bytecode, &Diagnostic::from(&e))
+                }
+                Program::Source(_) => report(path, input.as_deref(), &[Diagnostic::from(&e)]),
+            }
+            ExitCode::FAILURE
+        }
     }
 }
 
-/// 左括弧を解析する関数
+/// `debug` サブコマンド
 ///
-/// # 引数
-/// * `input` - 解析対象の文字列
+/// 命令の位置が行と対応するよう、覗き穴最適化をかけずにコンパイルして [`Debugger`] の下で実行する。
+/// デバッガのコマンドは標準入力から読むので、ファイルは標準入力から読まない。
+fn debug(args: &[String]) -> ExitCode {
+    let [path] = args else {
+        return usage_error("debug expects exactly one file");
+    };
+    if path == "-" || is_compiled(path) {
+        return usage_error("debug expects a source file");
+    }
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    set_io_policy(IoPolicy::DENY_ALL.allow_import(&dir));
+    module::set_search_paths(vec![dir]);
+    let (input, exprs) = match load_program(path) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+    let mut bytecode = compile_program(&exprs);
+    bytecode.attach_lines(&input);
+    let mut env = Environment::new();
+    stdlib::register(&mut env);
+    let debugger = Debugger::new(input.as_str(), io::stdin().lock(), io::stdout());
+    match Vm::new().with_hook(debugger).run(&bytecode, &mut env) {
+        Ok(value) => {
+            println!("{value}");
+            ExitCode::SUCCESS
+        }
+        Err(EvalError::Interrupted { .. }) => ExitCode::SUCCESS,
+        Err(e) => {
+            report(path, Some(&input), &[Diagnostic::from(&e)]);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// 式の並びをコンパイルし、覗き穴最適化をかけた命令列を返す
 ///
-/// # 戻り値
-/// * `(&str, Option<Token>)` - (残りの入力文字列, 解析結果のトークン)のタプル
-fn lparen(mut input: &str) -> Option<(&str, Token)> {
-    if matches!(peek_char(input), Some('(')) {
-        input = advance_char(input);
-        Some((input, Token::LParen))
-    } else {
-        None
+/// `dump` にソースコードを渡すと、最適化の前と後の命令列の一覧を標準エラー出力に書き出す。
+fn compile_optimized(exprs: &[Expr], dump: Option<&str>) -> Bytecode {
+    let mut bytecode = compile_program(exprs);
+    if let Some(input) = dump {
+        eprint!(
+            "; before peephole optimization\n{}",
+            bytecode.disassemble_source(input)
+        );
+    }
+    optimize(&mut bytecode);
+    if let Some(input) = dump {
+        eprint!(
+            "; after peephole optimization\n{}",
+            bytecode.disassemble_source(input)
+        );
+    }
+    bytecode
+}
+
+/// ファイルが `.rsclc` 形式のマジックナンバーで始まるかどうか
+fn is_compiled(path: &str) -> bool {
+    let mut magic = [0; 4];
+    path != "-"
+        && File::open(path)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .is_ok()
+        && &magic == MAGIC
+}
+
+/// ソースコードのファイルを読み込み、その内容と定数を畳み込んだ式の並びを返す
+///
+/// 失敗した場合はエラーを表示し、終了コードを返す。
+fn load_program(path: &str) -> Result<(String, Vec<Expr>), ExitCode> {
+    let input = read_input(path).map_err(|e| fail(path, e))?;
+    let TokenTree::Tree(forms, _) = parse_or_report(path, &input)? else {
+        unreachable!("source_recovering() always returns a tree");
+    };
+    let mut exprs = forms
+        .iter()
+        .map(lower)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            report(path, Some(&input), &[Diagnostic::from(&e)]);
+            ExitCode::FAILURE
+        })?;
+    if let Err(errors) = check(&exprs) {
+        let diagnostics: Vec<_> = errors.iter().map(Diagnostic::from).collect();
+        report(path, Some(&input), &diagnostics);
+        summary(format_args!(
+            "error: {path}: could not compile due to {} type error(s)",
+            errors.len()
+        ));
+        return Err(ExitCode::FAILURE);
+    }
+    exprs.iter_mut().for_each(fold_constants);
+    Ok((input, exprs))
+}
+
+/// S式のソースコードを解析し、エラーがあればすべて表示する
+fn parse_or_report<'src>(path: &str, input: &'src str) -> Result<TokenTree<'src>, ExitCode> {
+    let (tree, diagnostics) = source_recovering(input);
+    if diagnostics.is_empty() {
+        return Ok(tree);
     }
+    report(path, Some(input), &diagnostics);
+    summary(format_args!(
+        "error: {path}: could not parse due to {} error(s)",
+        diagnostics.len()
+    ));
+    Err(ExitCode::FAILURE)
 }
-/// 右括弧を解析する関数
+
+/// 報告をすべて標準エラー出力に書き出す
 ///
-/// # 引数
-/// * `input` - 解析対象の文字列
+/// `--error-format=json` なら、報告ごとにファイル名と、ソースコードがあれば行と桁を加えた
+/// JSON のオブジェクトを1行ずつ書き出す。
+fn report(path: &str, input: Option<&str>, diagnostics: &[Diagnostic]) {
+    let map = input.map(SourceMap::new);
+    for diagnostic in diagnostics {
+        if !JSON_ERRORS.load(Ordering::Relaxed) {
+            match &map {
+                Some(map) if COLOR_ERRORS.load(Ordering::Relaxed) => {
+                    eprint!("{}", diagnostic.render_colored(map))
+                }
+                Some(map) => eprint!("{}", diagnostic.render(map)),
+                None => eprintln!("{}: {path}: {diagnostic}", diagnostic.severity),
+            }
+            continue;
+        }
+        report_json(
+            path,
+            diagnostic,
+            map.as_ref().map(|map| map.line_col(diagnostic.span.start)),
+        );
+    }
+}
+
+/// コンパイル済みのファイルを実行したときのエラーを表示する
 ///
-/// # 戻り値
-/// * `(&str, Option<Token>)` - (残りの入力文字列, 解析結果のトークン)のタプル
-fn rparen(mut input: &str) -> Option<(&str, Token)> {
-    if matches!(peek_char(input), Some(')')) {
-        input = advance_char(input);
-        Some((input, Token::RParen))
+/// ファイルにはソースコードが無いので、ソースファイルのエラーと同じ形で、行の代わりに位置だけを示す。
+/// 位置は命令列の行と桁の対応表から求め、対応表に無ければバイトの位置で示す。
+fn report_compiled(path: &str, bytecode: &Bytecode, diagnostic: &Diagnostic) {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        report_json(path, diagnostic, bytecode.locate(diagnostic.span));
+        return;
+    }
+    let locate = |span: Span| match bytecode.locate(span) {
+        Some(at) => format!("{path}:{at}"),
+        None => format!("{path}, byte {}", span.start),
+    };
+    if COLOR_ERRORS.load(Ordering::Relaxed) {
+        eprint!("{}", diagnostic.render_located_colored(locate));
     } else {
-        None
+        eprint!("{}", diagnostic.render_located(locate));
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// エラーや警告を1行の JSON として表示する
+fn report_json(path: &str, diagnostic: &Diagnostic, at: Option<LineCol>) {
+    let Json::Object(mut fields) = diagnostic.to_json() else {
+        unreachable!("a diagnostic is always converted to an object");
+    };
+    fields.insert(0, ("file".to_string(), Json::String(path.to_string())));
+    if let Some(at) = at {
+        fields.push(("line".to_string(), Json::Number(at.line as f64)));
+        fields.push(("column".to_string(), Json::Number(at.column as f64)));
+    }
+    eprintln!("{}", Json::Object(fields));
+}
 
-    #[test]
-    fn test_whitespace() {
-        assert_eq!(whitespace("    "), "");
+/// 入出力の失敗など、ソースコード上の位置を持たないエラーを書き出して終了コードを返す
+fn fail(path: &str, message: impl std::fmt::Display) -> ExitCode {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        let fields = vec![
+            ("file".to_string(), Json::String(path.to_string())),
+            ("severity".to_string(), Json::String("Error".to_string())),
+            ("code".to_string(), Json::Null),
+            ("message".to_string(), Json::String(message.to_string())),
+        ];
+        eprintln!("{}", Json::Object(fields));
+    } else {
+        eprintln!("error: {path}: {message}");
     }
+    ExitCode::FAILURE
+}
 
-    #[test]
-    fn test_ident() {
-        assert_eq!(ident("Adam"), Some(("", Token::Ident("Adam"))));
+/// 報告の数をまとめた行を書き出す。`--error-format=json` では何も書き出さない
+fn summary(line: std::fmt::Arguments) {
+    if !JSON_ERRORS.load(Ordering::Relaxed) {
+        eprintln!("{line}");
     }
+}
 
-    #[test]
-    fn test_number() {
-        assert_eq!(number("123.45 "), Some((" ", Token::Number(123.45))));
+/// ファイル、または `-` ならば標準入力の内容をすべて読み込む
+fn read_input(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut buf = String::new();
+        io::stdin().read_to_string(&mut buf)?;
+        Ok(buf)
+    } else {
+        std::fs::read_to_string(path)
     }
 }
+
+fn usage_error(msg: &str) -> ExitCode {
+    eprintln!("error: {msg}\n\n{USAGE}");
+    ExitCode::from(2)
+}
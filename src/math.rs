@@ -0,0 +1,265 @@
+//! 大域環境に `math` の名前で登録する、数学の関数をまとめたモジュール
+//!
+//! 関数はモジュールの変数として持ち、中置記法では `math["sin"](x)`、S式では
+//! `((get math "sin") x)` で呼び出す。三角関数、指数と対数、累乗、丸めのほか、
+//! 定数 `pi` と `e`、乱数の関数 `seed`、`rand`、`rand_int` を持つ。
+//!
+//! 乱数は種から決まる擬似乱数で、スレッドごとに状態を持つ。種の既定値は決まっているので、
+//! `seed` を呼ばなくても実行するたびに同じ並びになる。例のプログラムやベンチマークの結果を
+//! 実行ごとに変えないため。
+//!
+//! ```
+//! use ruscal_b::{Interpreter, Value};
+//!
+//! let mut interpreter = Interpreter::new();
+//! let run = |interpreter: &mut Interpreter, input| interpreter.run_infix(input).unwrap().unwrap();
+//! assert_eq!(run(&mut interpreter, "math[\"pow\"](2, 10)"), Value::I64(1024));
+//! let first = run(&mut interpreter, "math[\"seed\"](7); math[\"rand\"]()");
+//! assert_eq!(run(&mut interpreter, "math[\"seed\"](7); math[\"rand\"]()"), first);
+//! ```
+
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::ast::Span;
+use crate::env::Environment;
+use crate::eval::{expect_integer, expect_number, EvalError, Value};
+use crate::module::Module;
+use crate::stdlib::{Arity, Builtin};
+
+/// 乱数の種の既定値
+const DEFAULT_SEED: u64 = 0x853c_49e6_748f_ea9b;
+
+/// `math` の関数の一覧
+pub static FUNCTIONS: &[Builtin] = &[
+    Builtin::new("sin", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::sin)
+    }),
+    Builtin::new("cos", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::cos)
+    }),
+    Builtin::new("tan", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::tan)
+    }),
+    Builtin::new("asin", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::asin)
+    }),
+    Builtin::new("acos", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::acos)
+    }),
+    Builtin::new("atan", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::atan)
+    }),
+    Builtin::new("atan2", Arity::Exact(2), atan2),
+    Builtin::new("exp", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::exp)
+    }),
+    Builtin::new("ln", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::ln)
+    }),
+    Builtin::new("log", Arity::Exact(2), log),
+    Builtin::new("log2", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::log2)
+    }),
+    Builtin::new("log10", Arity::Exact(1), |args, span| {
+        unary(args, span, f64::log10)
+    }),
+    Builtin::new("pow", Arity::Exact(2), pow),
+    Builtin::new("floor", Arity::Exact(1), |args, span| {
+        round(args, span, f64::floor)
+    }),
+    Builtin::new("ceil", Arity::Exact(1), |args, span| {
+        round(args, span, f64::ceil)
+    }),
+    Builtin::new("round", Arity::Exact(1), |args, span| {
+        round(args, span, f64::round)
+    }),
+    Builtin::new("trunc", Arity::Exact(1), |args, span| {
+        round(args, span, f64::trunc)
+    }),
+    Builtin::new("seed", Arity::Exact(1), seed),
+    Builtin::new("rand", Arity::Exact(0), rand),
+    Builtin::new("rand_int", Arity::Exact(2), rand_int),
+];
+
+/// `math` のモジュールを作る関数
+///
+/// 関数と定数を定義した環境を持つ、ファイルを読み込まないモジュールを返す。
+pub fn module() -> Value {
+    let mut env = Environment::new();
+    for function in FUNCTIONS {
+        env.define(function.name, function.to_value());
+    }
+    env.define("pi", Value::F64(std::f64::consts::PI));
+    env.define("e", Value::F64(std::f64::consts::E));
+    Value::Module(Rc::new(Module {
+        name: "math".to_string(),
+        path: PathBuf::new(),
+        env,
+    }))
+}
+
+/// 数値の引数を浮動小数点数にする
+fn float(value: &Value, span: Span) -> Result<f64, EvalError> {
+    match expect_number(value.clone(), span)? {
+        Value::I64(n) => Ok(n as f64),
+        Value::F64(n) => Ok(n),
+        _ => unreachable!("expect_number only returns numbers"),
+    }
+}
+
+/// 1つの数値を浮動小数点数として `f` に渡す。定義域の外なら `f` と同じく NaN を返す
+fn unary(args: &[Value], span: Span, f: fn(f64) -> f64) -> Result<Value, EvalError> {
+    Ok(Value::F64(f(float(&args[0], span)?)))
+}
+
+/// `y / x` の逆正接を、2つの符号から象限を決めて返す
+fn atan2(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let (y, x) = (float(&args[0], span)?, float(&args[1], span)?);
+    Ok(Value::F64(y.atan2(x)))
+}
+
+/// 2つ目の引数を底とした1つ目の引数の対数
+fn log(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let (x, base) = (float(&args[0], span)?, float(&args[1], span)?);
+    Ok(Value::F64(x.log(base)))
+}
+
+/// 累乗。整数の0以上の整数乗は整数で返し、それ以外は浮動小数点数で返す
+fn pow(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    match (expect_number(args[0].clone(), span)?, &args[1]) {
+        (Value::I64(base), Value::I64(exponent)) if *exponent >= 0 => u32::try_from(*exponent)
+            .ok()
+            .and_then(|exponent| base.checked_pow(exponent))
+            .map(Value::I64)
+            .ok_or(EvalError::IntegerOverflow { span }),
+        (base, exponent) => Ok(Value::F64(float(&base, span)?.powf(float(exponent, span)?))),
+    }
+}
+
+/// 浮動小数点数を `f` で整数の値に丸める。整数はそのまま返す
+fn round(args: &[Value], span: Span, f: fn(f64) -> f64) -> Result<Value, EvalError> {
+    match expect_number(args[0].clone(), span)? {
+        Value::F64(n) => Ok(Value::F64(f(n))),
+        n => Ok(n),
+    }
+}
+
+thread_local! {
+    /// このスレッドの乱数の状態
+    static STATE: Cell<u64> = const { Cell::new(DEFAULT_SEED) };
+}
+
+/// 次の64ビットの乱数。splitmix64 で状態を進める
+fn next() -> u64 {
+    STATE.with(|state| {
+        let next = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(next);
+        let mut z = next;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
+}
+
+/// 整数の種で乱数の状態を設定する。同じ種からは同じ並びの乱数になる
+fn seed(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let seed = expect_integer(args[0].clone(), span)?;
+    STATE.with(|state| state.set(seed as u64));
+    Ok(Value::Nil)
+}
+
+/// 0以上1未満の浮動小数点数の乱数
+fn rand(_args: &[Value], _span: Span) -> Result<Value, EvalError> {
+    Ok(Value::F64((next() >> 11) as f64 / (1u64 << 53) as f64))
+}
+
+/// 1つ目の引数以上、2つ目の引数未満の整数の乱数
+fn rand_int(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let low = expect_integer(args[0].clone(), span)?;
+    let high = expect_integer(args[1].clone(), span)?;
+    if low >= high {
+        return Err(EvalError::TypeMismatch {
+            expected: "a non-empty range",
+            span,
+        });
+    }
+    let width = high.abs_diff(low);
+    // 64ビットの乱数に幅を掛けた上位64ビットを使い、剰余より偏りを小さくする
+    let offset = ((u128::from(next()) * u128::from(width)) >> 64) as u64;
+    Ok(Value::I64(low.wrapping_add_unsigned(offset)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stdlib::register;
+
+    fn run(input: &str) -> Result<Value, EvalError> {
+        let mut env = Environment::new();
+        register(&mut env);
+        crate::eval::eval_statements(&crate::infix::statements(input).unwrap(), &mut env)
+            .map(Option::unwrap)
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(run("math[\"pow\"](2, 10)"), Ok(Value::I64(1024)));
+        assert_eq!(run("math[\"pow\"](2, -1)"), Ok(Value::F64(0.5)));
+        assert_eq!(run("math[\"pow\"](4, 0.5)"), Ok(Value::F64(2.0)));
+        assert_eq!(
+            run("math[\"pow\"](2, 64)"),
+            Err(EvalError::IntegerOverflow {
+                span: Span::new(0, 18)
+            })
+        );
+        assert_eq!(run("math[\"floor\"](-1.5)"), Ok(Value::F64(-2.0)));
+        assert_eq!(run("math[\"ceil\"](1.2)"), Ok(Value::F64(2.0)));
+        assert_eq!(run("math[\"round\"](3)"), Ok(Value::I64(3)));
+        assert_eq!(run("math[\"sin\"](0)"), Ok(Value::F64(0.0)));
+        assert_eq!(run("math[\"cos\"](math[\"pi\"])"), Ok(Value::F64(-1.0)));
+        assert_eq!(run("math[\"log\"](8, 2)"), Ok(Value::F64(3.0)));
+        assert_eq!(run("math[\"ln\"](math[\"e\"])"), Ok(Value::F64(1.0)));
+        assert_eq!(
+            run("math[\"sin\"](\"x\")"),
+            Err(EvalError::NotANumber {
+                span: Span::new(0, 16)
+            })
+        );
+    }
+
+    #[test]
+    fn test_seeded_random() {
+        let draws =
+            "math[\"seed\"](42); [math[\"rand\"](), math[\"rand\"](), math[\"rand_int\"](1, 7)]";
+        let first = run(draws).unwrap();
+        assert_eq!(run(draws).unwrap(), first);
+        assert_ne!(
+            run("math[\"seed\"](43); [math[\"rand\"](), math[\"rand\"](), math[\"rand_int\"](1, 7)]")
+                .unwrap(),
+            first
+        );
+        for _ in 0..1000 {
+            let Value::F64(n) = rand(&[], Span::new(0, 0)).unwrap() else {
+                unreachable!()
+            };
+            assert!((0.0..1.0).contains(&n));
+            let range = [Value::I64(i64::MIN), Value::I64(i64::MAX)];
+            assert!(rand_int(&range, Span::new(0, 0)).is_ok());
+            let Value::I64(n) =
+                rand_int(&[Value::I64(-2), Value::I64(3)], Span::new(0, 0)).unwrap()
+            else {
+                unreachable!()
+            };
+            assert!((-2..3).contains(&n));
+        }
+        assert_eq!(
+            run("math[\"rand_int\"](3, 3)"),
+            Err(EvalError::TypeMismatch {
+                expected: "a non-empty range",
+                span: Span::new(0, 22)
+            })
+        );
+    }
+}
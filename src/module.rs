@@ -0,0 +1,355 @@
+//! 他のファイルのプログラムをモジュールとして読み込む仕組み
+//!
+//! S式の `(import "path")` と中置記法の `import "path"` は、S式のソースファイルを読み込み、
+//! 標準関数だけを定義した新しい大域環境で評価して、その環境を持つモジュールの値を返す。
+//! モジュールで定義した変数は `module["name"]` か `(get module "name")` で参照する。
+//!
+//! ファイルは次の順に探す。
+//!
+//! 1. 絶対パスならそのパス
+//! 2. モジュールの中の `import` なら、そのモジュールのファイルがあるディレクトリ
+//! 3. [`set_search_paths`] で設定したディレクトリを順に。既定はカレントディレクトリだけ
+//!
+//! 読み込めるのは、スレッドの [`IoPolicy`](crate::io::IoPolicy) が読むことか読み込むことを許した
+//! ディレクトリの中のファイルだけで、それ以外は [`EvalError::IoDenied`] で失敗する。
+//! 同じファイルは一度だけ評価し、2回目以降の `import` は同じモジュールの値を返す。
+//! 読み込んでいる途中のモジュールを再び読み込もうとすると、循環としてエラーにする。
+//! 検索パスと読み込んだモジュールはスレッドごとに持つ。
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::ast::{Span, TokenTree};
+use crate::env::Environment;
+use crate::eval::{eval_forms, EvalError, Value};
+use crate::io;
+use crate::parser::source;
+use crate::stdlib;
+
+/// 読み込んだファイルの変数を持つモジュール
+pub struct Module {
+    /// 拡張子を除いたファイル名
+    pub name: String,
+    /// ファイルの正規化したパス。[`math`](crate::math) のような組み込みのモジュールでは空
+    pub path: PathBuf,
+    /// ファイルを評価した大域環境
+    pub env: Environment,
+}
+
+impl Module {
+    /// モジュールで定義した変数の値
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.env.get(name)
+    }
+}
+
+impl fmt::Debug for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Module")
+            .field("name", &self.name)
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// モジュールは同じファイルを読み込んだ同一のものだけを等しいとみなす
+impl PartialEq for Module {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// モジュールの検索パスと読み込みの状態
+struct Loader {
+    search_paths: Vec<PathBuf>,
+    cache: HashMap<PathBuf, Rc<Module>>,
+    /// 読み込んでいる途中のファイルと、`import` に書かれたパスの組。外側から順に並べる
+    loading: Vec<(PathBuf, String)>,
+}
+
+thread_local! {
+    static LOADER: RefCell<Loader> = RefCell::new(Loader {
+        search_paths: vec![PathBuf::from(".")],
+        cache: HashMap::new(),
+        loading: vec![],
+    });
+}
+
+/// モジュールの検索パスを設定する関数
+///
+/// 設定は呼び出したスレッドにだけ効き、すでに読み込んだモジュールはそのまま使い続ける。
+///
+/// # 引数
+/// * `paths` - 順に探すディレクトリ
+pub fn set_search_paths(paths: Vec<PathBuf>) {
+    LOADER.with(|loader| loader.borrow_mut().search_paths = paths);
+}
+
+/// 現在のスレッドのモジュールの検索パス
+pub fn search_paths() -> Vec<PathBuf> {
+    LOADER.with(|loader| loader.borrow().search_paths.clone())
+}
+
+/// `import` で指定したファイルをモジュールとして読み込む関数
+///
+/// # 引数
+/// * `path` - `import` に書かれたパス
+/// * `span` - `import` 式の範囲
+///
+/// # 戻り値
+/// * `Result<Value, EvalError>` - モジュールの値
+///   - ファイルが見つからない、読み込みが循環している、ファイルの解析か評価に失敗した場合はエラーを返す
+pub fn import(path: &str, span: Span) -> Result<Value, EvalError> {
+    let resolved = resolve(path, span)?;
+    let cycle = LOADER.with(|loader| {
+        let loader = loader.borrow();
+        if let Some(module) = loader.cache.get(&resolved) {
+            return Err(Ok(Value::Module(module.clone())));
+        }
+        let start = loader.loading.iter().position(|(p, _)| *p == resolved);
+        Ok(start.map(|start| {
+            let mut cycle: Vec<_> = loader.loading[start..]
+                .iter()
+                .map(|(_, name)| name.clone())
+                .collect();
+            cycle.push(path.to_string());
+            cycle
+        }))
+    });
+    match cycle {
+        Err(cached) => return cached,
+        Ok(Some(cycle)) => return Err(EvalError::ImportCycle { cycle, span }),
+        Ok(None) => {}
+    }
+    // 読み込むモジュールの中の `import` からも参照するので、評価する間は借用を手放しておく
+    LOADER.with(|loader| {
+        loader
+            .borrow_mut()
+            .loading
+            .push((resolved.clone(), path.to_string()))
+    });
+    let module = load(&resolved);
+    LOADER.with(|loader| loader.borrow_mut().loading.pop());
+    let module = Rc::new(module.map_err(|message| EvalError::ModuleFailed {
+        path: path.to_string(),
+        message,
+        span,
+    })?);
+    LOADER.with(|loader| loader.borrow_mut().cache.insert(resolved, module.clone()));
+    Ok(Value::Module(module))
+}
+
+/// `import` に書かれたパスを、存在するファイルの正規化したパスにする
+///
+/// 探す場所ごとに、[`IoPolicy`](crate::io::IoPolicy) が許したディレクトリの中かどうかを
+/// ファイルがあるかどうかより先に確かめる。許した場所に見つからず、許していない場所があれば
+/// [`EvalError::IoDenied`] を返す。
+fn resolve(path: &str, span: Span) -> Result<PathBuf, EvalError> {
+    let file = Path::new(path);
+    let candidates: Vec<_> = if file.is_absolute() {
+        vec![file.to_path_buf()]
+    } else {
+        let (importer, search_paths) = LOADER.with(|loader| {
+            let loader = loader.borrow();
+            let importer = loader
+                .loading
+                .last()
+                .and_then(|(file, _)| file.parent().map(Path::to_path_buf));
+            (importer, loader.search_paths.clone())
+        });
+        importer
+            .into_iter()
+            .chain(search_paths)
+            .map(|dir| dir.join(file))
+            .collect()
+    };
+    let mut denied = None;
+    for candidate in candidates {
+        match io::resolve_import(&candidate, span) {
+            Ok(resolved) if resolved.is_file() => return Ok(resolved),
+            Ok(_) | Err(EvalError::IoFailed { .. }) => {}
+            Err(_) => {
+                denied.get_or_insert(EvalError::IoDenied {
+                    operation: "import",
+                    path: Some(path.to_string()),
+                    span,
+                });
+            }
+        }
+    }
+    Err(denied.unwrap_or_else(|| EvalError::ModuleNotFound {
+        path: path.to_string(),
+        span,
+    }))
+}
+
+/// ファイルを読み込んで評価する。失敗すればその理由を返す
+fn load(path: &Path) -> Result<Module, String> {
+    let input = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let TokenTree::Tree(forms, _) = source(&input).map_err(|e| e.to_string())? else {
+        unreachable!("source() always returns a tree");
+    };
+    let mut env = Environment::new();
+    stdlib::register(&mut env);
+    eval_forms(&forms, &mut env).map_err(|e| e.to_string())?;
+    let name = path
+        .file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    Ok(Module {
+        name,
+        path: path.to_path_buf(),
+        env,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::eval_statements;
+    use crate::infix::statements;
+
+    /// テストごとに別のディレクトリにファイルを書き出す
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ruscal-module-{}-{test}", std::process::id()));
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn run(dir: &Path, input: &str) -> Result<Option<Value>, EvalError> {
+        set_search_paths(vec![dir.to_path_buf()]);
+        io::set_io_policy(io::IoPolicy::DENY_ALL.allow_import(dir));
+        let mut env = Environment::new();
+        stdlib::register(&mut env);
+        eval_statements(&statements(input).unwrap(), &mut env)
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = write_files(
+            "import",
+            &[
+                (
+                    "lib/math.rscl",
+                    r#"(define util (import "util.rscl"))
+                       (define square (fn (x) (* x x ((get util "one")))))"#,
+                ),
+                ("lib/util.rscl", "(define one (fn () 1))"),
+            ],
+        );
+        // モジュールの中の `import` は、そのモジュールのディレクトリから探す
+        assert_eq!(
+            run(
+                &dir,
+                "var math = import \"lib/math.rscl\"\nmath[\"square\"](3)"
+            ),
+            Ok(Some(Value::I64(9)))
+        );
+        assert_eq!(
+            run(
+                &dir,
+                "var m = import \"lib/math.rscl\"\nget(m, \"missing\")"
+            ),
+            Ok(Some(Value::Nil))
+        );
+        // 同じファイルは一度だけ評価し、同じモジュールを返す
+        assert_eq!(
+            run(
+                &dir,
+                "import \"lib/math.rscl\" == import \"./lib/../lib/math.rscl\""
+            ),
+            Ok(Some(Value::Bool(true)))
+        );
+        assert_eq!(
+            run(&dir, "(import \"lib/util.rscl\")[\"nope\"]"),
+            Err(EvalError::UnknownIdentifier {
+                name: "util.nope".to_string(),
+                span: Span::new(0, 32)
+            })
+        );
+        assert_eq!(
+            run(&dir, "import \"lib/util.rscl\"").map(|v| v.unwrap().to_string()),
+            Ok("<module util>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let dir = write_files(
+            "errors",
+            &[
+                ("a.rscl", r#"(define b (import "b.rscl"))"#),
+                ("b.rscl", r#"(import "a.rscl")"#),
+                ("bad.rscl", "(+ 1"),
+            ],
+        );
+        assert_eq!(
+            run(&dir, "import \"nowhere.rscl\""),
+            Err(EvalError::ModuleNotFound {
+                path: "nowhere.rscl".to_string(),
+                span: Span::new(0, 21)
+            })
+        );
+        // 循環は外側の読み込みの失敗として報告する
+        let Err(EvalError::ModuleFailed { path, message, .. }) = run(&dir, "import \"a.rscl\"")
+        else {
+            panic!("expected the import to fail");
+        };
+        assert_eq!(path, "a.rscl");
+        assert_eq!(
+            message,
+            "failed to load module `b.rscl`: import cycle: a.rscl -> b.rscl -> a.rscl at byte 0 at byte 10"
+        );
+        let Err(e) = run(&dir, "import \"bad.rscl\"") else {
+            panic!("expected the import to fail");
+        };
+        assert!(
+            e.message()
+                .starts_with("failed to load module `bad.rscl`: "),
+            "{e}"
+        );
+        // 失敗した読み込みは途中の状態を残さない
+        assert!(LOADER.with(|loader| loader.borrow().loading.is_empty()));
+    }
+
+    #[test]
+    fn test_import_denied() {
+        let dir = write_files(
+            "denied",
+            &[
+                ("allowed/lib.rscl", "(define one 1)"),
+                ("secret/key.txt", "hunter2_api_key"),
+            ],
+        );
+        let secret = dir.join("secret/key.txt").display().to_string();
+        let denied = |input: &str| match run(&dir.join("allowed"), input) {
+            Err(EvalError::IoDenied { operation, .. }) => operation == "import",
+            _ => false,
+        };
+        // 許したディレクトリの外のファイルは、絶対パスでも `..` でも読まず、内容を漏らさない
+        assert!(denied(&format!("import \"{secret}\"")));
+        assert!(denied("import \"../secret/key.txt\""));
+        assert!(denied(&format!(
+            "import \"{}/nowhere.rscl\"",
+            dir.display()
+        )));
+        assert!(run(&dir.join("allowed"), "import \"lib.rscl\"").is_ok());
+        // 何も許さない設定では、読み込んだことのあるモジュールも読み込めない
+        set_search_paths(vec![dir.join("allowed")]);
+        io::set_io_policy(io::IoPolicy::DENY_ALL);
+        let mut env = Environment::new();
+        let res = eval_statements(&statements("import \"lib.rscl\"").unwrap(), &mut env);
+        assert!(matches!(res, Err(EvalError::IoDenied { .. })), "{res:?}");
+        let Err(e) = crate::Interpreter::new().run(&format!("(import \"{secret}\")")) else {
+            panic!("expected the import to be denied");
+        };
+        assert!(!e.to_string().contains("hunter2"), "{e}");
+        assert!(matches!(e, crate::Error::Eval(EvalError::IoDenied { .. })));
+    }
+}
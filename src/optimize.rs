@@ -0,0 +1,264 @@
+//! 評価の前に `Expr` を書き換える最適化
+
+use crate::ast::{BinOp, Expr, ExprKind, Span, Statement, Template, UnOp};
+use crate::eval::{binary, values_equal, Value};
+
+/// 定数だけからなる部分式をあらかじめ評価して、その値のリテラルに置き換える関数
+///
+/// 四則演算、比較、論理演算、単項演算と、条件が定数の `if` を畳み込む。
+/// 0による除算や桁あふれのように評価するとエラーになる部分式は、
+/// 実行時に同じエラーを報告できるようにそのまま残す。
+/// 識別子は同じ名前の変数で隠せるので、演算子の呼び出し `(+)` のような関数呼び出しは畳み込まない。
+///
+/// # 引数
+/// * `expr` - 書き換える式
+pub fn fold_constants(expr: &mut Expr) {
+    let span = expr.span;
+    match &mut expr.kind {
+        ExprKind::Int(_)
+        | ExprKind::Float(_)
+        | ExprKind::Str(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Nil
+        | ExprKind::Ident(_)
+        | ExprKind::Break
+        | ExprKind::Continue
+        | ExprKind::Import(_)
+        | ExprKind::Quote(_) => {}
+        ExprKind::Quasiquote(template) => fold_template(template),
+        ExprKind::BinaryOp { op, lhs, rhs } => {
+            fold_constants(lhs);
+            fold_constants(rhs);
+            if let Some(kind) = fold_binary(*op, lhs, rhs) {
+                expr.kind = kind;
+            }
+        }
+        ExprKind::UnaryOp { op, operand } => {
+            fold_constants(operand);
+            let value = match (*op, literal(operand)) {
+                (UnOp::Neg, Some(Value::I64(n))) => n.checked_neg().map(Value::I64),
+                (UnOp::Neg, Some(Value::F64(n))) => Some(Value::F64(-n)),
+                (UnOp::Not, Some(value)) => Some(Value::Bool(!value.is_truthy())),
+                _ => None,
+            };
+            if let Some(kind) = value.and_then(from_value) {
+                expr.kind = kind;
+            }
+        }
+        // 括弧の中が定数になれば、括弧を外して外側の演算でも畳み込めるようにする
+        ExprKind::Group(inner) => {
+            fold_constants(inner);
+            if let Some(kind) = literal(inner).and_then(from_value) {
+                expr.kind = kind;
+            }
+        }
+        ExprKind::Call { func, args } => {
+            fold_constants(func);
+            args.iter_mut().for_each(fold_constants);
+        }
+        ExprKind::Array(items) => items.iter_mut().for_each(fold_constants),
+        ExprKind::Map(entries) => {
+            for (key, value) in entries {
+                fold_constants(key);
+                fold_constants(value);
+            }
+        }
+        ExprKind::Index { target, index } => {
+            fold_constants(target);
+            fold_constants(index);
+        }
+        ExprKind::Define { value, .. } => fold_constants(value),
+        ExprKind::Let { bindings, body } => {
+            bindings
+                .iter_mut()
+                .for_each(|(_, value)| fold_constants(value));
+            body.iter_mut().for_each(fold_constants);
+        }
+        ExprKind::Fn { body, .. } => {
+            let mut folded = body.to_vec();
+            folded.iter_mut().for_each(fold_constants);
+            *body = folded.into();
+        }
+        ExprKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            fold_constants(cond);
+            fold_constants(then_branch);
+            if let Some(else_branch) = else_branch {
+                fold_constants(else_branch);
+            }
+            // `if` の枝はスコープを作らないので、選ばれる枝でそのまま置き換えられる
+            if let Some(cond) = literal(cond) {
+                let nil = Expr::new(ExprKind::Nil, span);
+                *expr = if cond.is_truthy() {
+                    std::mem::replace(then_branch, nil)
+                } else {
+                    else_branch.take().map_or(nil, |else_branch| *else_branch)
+                };
+            }
+        }
+        ExprKind::Block(statements) => statements.iter_mut().for_each(fold_statement),
+        ExprKind::Match { scrutinee, arms } => {
+            fold_constants(scrutinee);
+            for arm in arms {
+                if let Some(guard) = &mut arm.guard {
+                    fold_constants(guard);
+                }
+                fold_constants(&mut arm.body);
+            }
+        }
+        ExprKind::Return(value) => {
+            if let Some(value) = value {
+                fold_constants(value);
+            }
+        }
+        ExprKind::While { cond, body } => {
+            fold_constants(cond);
+            fold_constants(body);
+        }
+        ExprKind::For {
+            start, end, body, ..
+        } => {
+            fold_constants(start);
+            fold_constants(end);
+            fold_constants(body);
+        }
+    }
+}
+
+/// 準引用の雛形に埋め込む式の定数を畳み込む
+fn fold_template(template: &mut Template) {
+    match template {
+        Template::Datum(_) => {}
+        Template::List(items, _) => items.iter_mut().for_each(fold_template),
+        Template::Unquote(expr) => fold_constants(expr),
+    }
+}
+
+/// 文に含まれる式の定数を畳み込む関数
+pub fn fold_statement(statement: &mut Statement) {
+    match statement {
+        Statement::VarDef { value, .. } | Statement::Assignment { value, .. } => {
+            fold_constants(value)
+        }
+        Statement::IndexAssignment {
+            target,
+            index,
+            value,
+            ..
+        } => {
+            fold_constants(target);
+            fold_constants(index);
+            fold_constants(value);
+        }
+        Statement::Expr(expr) => fold_constants(expr),
+    }
+}
+
+/// 両辺が畳み込み済みの二項演算を畳み込む
+fn fold_binary(op: BinOp, lhs: &Expr, rhs: &Expr) -> Option<ExprKind> {
+    let lhs = literal(lhs)?;
+    let value = match op {
+        // 左辺だけで結果が決まる場合は、右辺が定数でなくても畳み込める
+        BinOp::And | BinOp::Or if lhs.is_truthy() == (op == BinOp::Or) => {
+            Value::Bool(lhs.is_truthy())
+        }
+        BinOp::And | BinOp::Or => return None,
+        BinOp::Eq | BinOp::Ne => {
+            Value::Bool(values_equal(&lhs, &literal(rhs)?) == (op == BinOp::Eq))
+        }
+        _ => match (lhs, literal(rhs)?) {
+            (lhs @ (Value::I64(_) | Value::F64(_)), rhs @ (Value::I64(_) | Value::F64(_))) => {
+                // エラーになる演算は畳み込まないので、エラーの範囲は使わない
+                binary(op, lhs, rhs, Span::default()).ok()?
+            }
+            _ => return None,
+        },
+    };
+    from_value(value)
+}
+
+/// 式がリテラルならその値
+fn literal(expr: &Expr) -> Option<Value> {
+    match &expr.kind {
+        ExprKind::Int(n) => Some(Value::I64(*n)),
+        ExprKind::Float(n) => Some(Value::F64(*n)),
+        ExprKind::Str(s) => Some(Value::Str(s.as_str().into())),
+        ExprKind::Bool(b) => Some(Value::Bool(*b)),
+        ExprKind::Nil => Some(Value::Nil),
+        _ => None,
+    }
+}
+
+/// 値を表すリテラル
+fn from_value(value: Value) -> Option<ExprKind> {
+    match value {
+        Value::I64(n) => Some(ExprKind::Int(n)),
+        Value::F64(n) => Some(ExprKind::Float(n)),
+        Value::Bool(b) => Some(ExprKind::Bool(b)),
+        Value::Nil => Some(ExprKind::Nil),
+        Value::Str(s) => Some(ExprKind::Str(s.to_string())),
+        Value::List(_)
+        | Value::Array(_)
+        | Value::Map(_)
+        | Value::Module(_)
+        | Value::Symbol(_)
+        | Value::Fn(_)
+        | Value::NativeFn(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::lower;
+    use crate::infix::parse_expr;
+    use crate::parser::source;
+    use crate::TokenTree;
+
+    fn fold_str(input: &str) -> ExprKind {
+        let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+            panic!("failed to parse {input:?}");
+        };
+        let mut expr = lower(&forms[0]).unwrap();
+        fold_constants(&mut expr);
+        expr.kind
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        assert_eq!(fold_str("(+ 1 (* 2 3))"), ExprKind::Int(7));
+        assert_eq!(fold_str("(/ 1 2.0)"), ExprKind::Float(0.5));
+        assert_eq!(fold_str("(< 1 2)"), ExprKind::Bool(true));
+        assert_eq!(fold_str("(! (== 1 1.0))"), ExprKind::Bool(false));
+        assert_eq!(fold_str("(- (- 1 2))"), ExprKind::Int(1));
+        assert_eq!(fold_str("(if (> 1 2) x 3)"), ExprKind::Int(3));
+        assert_eq!(fold_str("(|| 1 x)"), ExprKind::Bool(true));
+    }
+
+    #[test]
+    fn test_partial_fold() {
+        let mut expr = parse_expr("x * (2 + 3)").unwrap();
+        fold_constants(&mut expr);
+        let ExprKind::BinaryOp { op, lhs, rhs } = expr.kind else {
+            panic!("{expr:?}");
+        };
+        assert_eq!(op, BinOp::Mul);
+        assert!(matches!(lhs.kind, ExprKind::Ident(_)));
+        assert_eq!(*rhs, Expr::new(ExprKind::Int(5), Span::new(4, 11)));
+    }
+
+    #[test]
+    fn test_errors_are_kept() {
+        assert!(matches!(fold_str("(/ 1 0)"), ExprKind::BinaryOp { .. }));
+        assert!(matches!(
+            fold_str("(+ 9223372036854775807 1)"),
+            ExprKind::BinaryOp { .. }
+        ));
+        assert!(matches!(fold_str("(+ 1 nil)"), ExprKind::BinaryOp { .. }));
+        // 演算子は変数で隠せるので、関数呼び出しのままにする
+        assert!(matches!(fold_str("(+ 1)"), ExprKind::Call { .. }));
+    }
+}
@@ -0,0 +1,838 @@
+//! トークン列から括弧の入れ子構造を組み立てる構文解析器
+
+use std::fmt;
+
+use crate::ast::{Span, Token, TokenTree};
+use crate::diagnostics::Diagnostic;
+use crate::lexer::{LexError, Lexer};
+use crate::source_map::{Located, SourceMap};
+
+/// 解析時に期待していた要素の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    /// 何らかのトークン
+    Token,
+    /// 式
+    Expression,
+    /// 識別子
+    Ident,
+    /// 数値リテラル
+    Number,
+    /// 文字列リテラル
+    StrLiteral,
+    /// 文字列リテラル内のエスケープシーケンス
+    Escape,
+    /// 文字列リテラルを閉じる引用符
+    Quote,
+    /// 左括弧
+    LParen,
+    /// 右括弧
+    RParen,
+    /// ブロックを開く `{`
+    LBrace,
+    /// ブロックを閉じる `}`
+    RBrace,
+    /// 配列と要素の参照を閉じる `]`
+    RBracket,
+    /// マップのキーと値を区切る `:`
+    Colon,
+    /// 代入の `=`
+    Equals,
+    /// `for` の `in`
+    In,
+    /// 範囲の `..`
+    DotDot,
+    /// 文の区切りの `;` か改行
+    Semicolon,
+    /// 引数を区切る `,`
+    Comma,
+    /// 型注釈の型の名前
+    TypeName,
+    /// `match` の腕のパターン
+    Pattern,
+    /// `match` の腕のパターンと本体を区切る `=>`
+    FatArrow,
+    /// 入力の終わり
+    EndOfInput,
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Token => "token",
+            Self::Expression => "expression",
+            Self::Ident => "identifier",
+            Self::Number => "number",
+            Self::StrLiteral => "string literal",
+            Self::Escape => "escape sequence",
+            Self::Quote => "closing quote",
+            Self::LParen => "'('",
+            Self::RParen => "')'",
+            Self::LBrace => "'{'",
+            Self::RBrace => "'}'",
+            Self::RBracket => "']'",
+            Self::Colon => "':'",
+            Self::Equals => "'='",
+            Self::In => "'in'",
+            Self::DotDot => "'..'",
+            Self::Semicolon => "';' or newline",
+            Self::Comma => "',' or ')'",
+            Self::TypeName => "type name",
+            Self::Pattern => "pattern",
+            Self::FatArrow => "'=>'",
+            Self::EndOfInput => "end of input",
+        };
+        f.write_str(s)
+    }
+}
+
+/// 解析に失敗したときのエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// 期待していない文字が現れた
+    Unexpected {
+        /// 入力の先頭からのバイト位置
+        offset: usize,
+        /// 期待していた要素
+        expected: Expected,
+        /// 実際に現れた文字。入力の終わりなら `None`
+        found: Option<char>,
+    },
+    /// 対応する相手のいない括弧がある
+    UnbalancedParen {
+        /// 閉じられていない `(`、または余分な `)` の範囲
+        span: Span,
+    },
+    /// 括弧の入れ子が深すぎる
+    TooDeep {
+        /// 最大の深さを超えた `(` の範囲
+        span: Span,
+        /// 入れ子にできる最大の深さ
+        max_depth: usize,
+    },
+    /// 数値として解釈できないリテラルがある
+    InvalidNumber {
+        /// リテラルの範囲
+        span: Span,
+        /// リテラルの文字列
+        literal: String,
+    },
+}
+
+impl ParseError {
+    /// 期待していない文字が現れたことを表すエラーを作る
+    pub fn unexpected(offset: usize, expected: Expected, found: Option<char>) -> Self {
+        Self::Unexpected {
+            offset,
+            expected,
+            found,
+        }
+    }
+
+    /// エラーの種類ごとに決まった、変わらない番号
+    ///
+    /// 番号の付け方は [`diagnostics`](crate::diagnostics) を参照。
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnbalancedParen { .. } => "E0001",
+            Self::Unexpected { .. } => "E0002",
+            Self::TooDeep { .. } => "E0003",
+            Self::InvalidNumber { .. } => "E0004",
+        }
+    }
+
+    /// エラーのバイト位置
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::Unexpected { offset, .. } => *offset,
+            Self::UnbalancedParen { span }
+            | Self::TooDeep { span, .. }
+            | Self::InvalidNumber { span, .. } => span.start,
+        }
+    }
+
+    /// 位置をバイト数ではなく行と桁で表したエラーの説明を得る
+    ///
+    /// ```
+    /// use ruscal_b::{source, SourceMap};
+    ///
+    /// let input = "(a // comment\n  @)";
+    /// let e = source(input).unwrap_err();
+    /// let located = e.locate(&SourceMap::new(input));
+    /// assert_eq!(located.to_string(), "expected token, found '@' at 2:3");
+    /// ```
+    pub fn locate(&self, map: &SourceMap) -> Located {
+        Diagnostic::from(self).locate(map)
+    }
+}
+
+impl From<LexError> for ParseError {
+    fn from(e: LexError) -> Self {
+        match e {
+            LexError::Unexpected {
+                offset,
+                expected,
+                found,
+            } => Self::unexpected(offset, expected, found),
+            LexError::InvalidNumber { span, literal } => Self::InvalidNumber { span, literal },
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unexpected {
+                offset,
+                expected,
+                found,
+            } => LexError::new(*offset, *expected, *found).fmt(f),
+            Self::UnbalancedParen { span } => {
+                write!(f, "unbalanced parenthesis at byte {}", span.start)
+            }
+            Self::TooDeep { span, max_depth } => write!(
+                f,
+                "parentheses nested deeper than {max_depth} at byte {}",
+                span.start
+            ),
+            Self::InvalidNumber { span, literal } => {
+                write!(f, "invalid number {literal:?} at byte {}", span.start)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// [`source`] などが受け付ける括弧の入れ子の最大の深さ
+///
+/// 解析の後で木をたどる評価器などは入れ子の深さだけ再帰するので、スタックが溢れない深さに制限する。
+pub const MAX_DEPTH: usize = 1000;
+
+/// 字句解析と構文解析の設定
+///
+/// [`ParserOptions::new`] の既定値は [`source`] と同じ規則になる。
+/// 変えたい項目だけをメソッドで指定し、[`source_with_options`] や [`Lexer::with_options`] に渡す。
+///
+/// ```
+/// use ruscal_b::parser::{source_with_options, ParserOptions};
+/// use ruscal_b::{Token, TokenTree};
+///
+/// let options = ParserOptions::new().int_literals(false).line_comments(false);
+/// let TokenTree::Tree(forms, _) = source_with_options("1", options).unwrap() else {
+///     unreachable!();
+/// };
+/// assert!(matches!(forms[0], TokenTree::Token(Token::Float(_), _)));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParserOptions {
+    pub(crate) strict: bool,
+    pub(crate) max_depth: usize,
+    pub(crate) unicode_idents: bool,
+    pub(crate) line_comments: bool,
+    pub(crate) block_comments: bool,
+    pub(crate) int_literals: bool,
+}
+
+impl ParserOptions {
+    /// [`source`] と同じ規則の設定を作る
+    pub fn new() -> Self {
+        Self {
+            strict: true,
+            max_depth: MAX_DEPTH,
+            unicode_idents: !cfg!(feature = "ascii-ident"),
+            line_comments: true,
+            block_comments: true,
+            int_literals: true,
+        }
+    }
+
+    /// `true` なら余分な右括弧をエラーにし、`false` なら読み飛ばす。既定値は `true`
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// 括弧を入れ子にできる最大の深さ。既定値は [`MAX_DEPTH`]
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// `true` なら識別子に ASCII 以外の文字を使える
+    ///
+    /// 既定値は `true` で、`ascii-ident` フィーチャーを有効にすると `false` になる。
+    pub fn unicode_idents(mut self, unicode_idents: bool) -> Self {
+        self.unicode_idents = unicode_idents;
+        self
+    }
+
+    /// `true` なら `// ...` を行コメントとして読み飛ばす。既定値は `true`
+    pub fn line_comments(mut self, line_comments: bool) -> Self {
+        self.line_comments = line_comments;
+        self
+    }
+
+    /// `true` なら `/* ... */` をブロックコメントとして読み飛ばす。既定値は `true`
+    pub fn block_comments(mut self, block_comments: bool) -> Self {
+        self.block_comments = block_comments;
+        self
+    }
+
+    /// `true` なら小数点も指数も無い数値を `Token::Int` にし、`false` ならすべての数値を `Token::Float` にする
+    ///
+    /// 既定値は `true`。
+    pub fn int_literals(mut self, int_literals: bool) -> Self {
+        self.int_literals = int_literals;
+        self
+    }
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ソースコードを解析してトークンの木を返す関数
+///
+/// 余分な右括弧をエラーとする厳格モードで解析する。
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+///
+/// # 戻り値
+/// * `Result<TokenTree, ParseError>` - 入力全体を1つの `TokenTree::Tree` にまとめた解析結果
+///   - 最上位の木の範囲は入力全体になる
+///   - 対応の取れない括弧や解析できない文字があればエラーを返す
+pub fn source(input: &str) -> Result<TokenTree<'_>, ParseError> {
+    source_with_options(input, ParserOptions::new())
+}
+
+/// 括弧の扱いを指定してソースコードを解析する関数
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+/// * `strict` - `true` なら余分な右括弧を `ParseError::UnbalancedParen` とし、
+///   `false` なら読み飛ばして解析を続ける
+///
+/// # 戻り値
+/// * `Result<TokenTree, ParseError>` - 入力全体を1つの `TokenTree::Tree` にまとめた解析結果
+///   - 閉じられていない左括弧は `strict` に関わらずエラーになる
+pub fn source_with(input: &str, strict: bool) -> Result<TokenTree<'_>, ParseError> {
+    source_with_options(input, ParserOptions::new().strict(strict))
+}
+
+/// 括弧の扱いと入れ子の最大の深さを指定してソースコードを解析する関数
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+/// * `strict` - `true` なら余分な右括弧を `ParseError::UnbalancedParen` とする
+/// * `max_depth` - 括弧を入れ子にできる最大の深さ
+///
+/// # 戻り値
+/// * `Result<TokenTree, ParseError>` - 入力全体を1つの `TokenTree::Tree` にまとめた解析結果
+///   - 入れ子が `max_depth` より深ければ `ParseError::TooDeep` を返す
+pub fn source_with_limit(
+    input: &str,
+    strict: bool,
+    max_depth: usize,
+) -> Result<TokenTree<'_>, ParseError> {
+    source_with_options(
+        input,
+        ParserOptions::new().strict(strict).max_depth(max_depth),
+    )
+}
+
+/// 設定を指定してソースコードを解析する関数
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+/// * `options` - 字句解析と構文解析の設定
+///
+/// # 戻り値
+/// * `Result<TokenTree, ParseError>` - 入力全体を1つの `TokenTree::Tree` にまとめた解析結果
+pub fn source_with_options(
+    input: &str,
+    options: ParserOptions,
+) -> Result<TokenTree<'_>, ParseError> {
+    let mut lexer = Lexer::with_options(input, options);
+    let mut tokens = vec![];
+    loop {
+        let (mut children, stray) = tree(&mut lexer, options.max_depth, None)?;
+        tokens.append(&mut children);
+        match stray {
+            None => break,
+            Some(span) if options.strict => return Err(ParseError::UnbalancedParen { span }),
+            Some(_) => {}
+        }
+    }
+    Ok(TokenTree::Tree(tokens, Span::new(0, input.len())))
+}
+
+/// エラーから回復しながらソースコードを解析する関数
+///
+/// エラーが見つかっても解析を止めず、報告を記録して続きから解析を再開する。
+///
+/// * 読めない文字はその1文字を、誤りのある数値や文字列はそのトークン全体を読み飛ばす
+/// * 余分な右括弧は読み飛ばす
+/// * 閉じられていない左括弧は入力の終わりで閉じたものとみなす
+/// * [`MAX_DEPTH`] より深く入れ子になった括弧は、対応する右括弧までを読み飛ばす
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+///
+/// # 戻り値
+/// * `(TokenTree, Vec<Diagnostic>)` - (読み取れた部分から組み立てた木, 見つかったエラーの報告)のタプル
+///   - 報告が空なら、木は [`source`] の結果と同じになる
+pub fn source_recovering(input: &str) -> (TokenTree<'_>, Vec<Diagnostic>) {
+    let mut lexer = Lexer::new(input);
+    let mut tokens = vec![];
+    let mut diagnostics = vec![];
+    loop {
+        let (mut children, stray) = tree(&mut lexer, MAX_DEPTH, Some(&mut diagnostics))
+            .expect("recovering parse never fails");
+        tokens.append(&mut children);
+        match stray {
+            None => break,
+            Some(span) => diagnostics.push(
+                Diagnostic::from(&ParseError::UnbalancedParen { span })
+                    .with_note("remove this `)` or add a matching `(` before it"),
+            ),
+        }
+    }
+    (
+        TokenTree::Tree(tokens, Span::new(0, input.len())),
+        diagnostics,
+    )
+}
+
+/// 閉じていない括弧の中で読んだ要素
+struct Frame<'src> {
+    /// 左括弧の範囲。最上位なら `None`
+    open: Option<Span>,
+    tokens: Vec<TokenTree<'src>>,
+    /// 次の要素に付ける `'` などの前置記号と、その範囲
+    prefixes: Vec<(&'static str, Span)>,
+}
+
+impl<'src> Frame<'src> {
+    fn new(open: Option<Span>) -> Self {
+        Self {
+            open,
+            tokens: vec![],
+            prefixes: vec![],
+        }
+    }
+
+    /// 読み終えた要素に前置記号を付けて加える
+    fn push(&mut self, mut node: TokenTree<'src>) {
+        // `'x` は `(quote x)` と同じ木にする
+        while let Some((form, prefix)) = self.prefixes.pop() {
+            let span = prefix.merge(node.span());
+            node = TokenTree::Tree(
+                vec![TokenTree::Token(Token::Ident(form), prefix), node],
+                span,
+            );
+        }
+        self.tokens.push(node);
+    }
+}
+
+/// 最上位の要素を、余分な右括弧か入力の終わりまで解析する関数
+///
+/// 入れ子の深さに比例してスタックを使わないよう、閉じていない括弧ごとの要素を `Vec` に積んで解析する。
+///
+/// # 引数
+/// * `lexer` - トークンを読み出す字句解析器
+/// * `max_depth` - 括弧を入れ子にできる最大の深さ
+/// * `diagnostics` - エラーから回復する場合は、報告を記録する先
+///
+/// # 戻り値
+/// * `Result<(Vec<TokenTree>, Option<Span>), ParseError>` - (最上位の要素, 解析を止めた右括弧の範囲)のタプル
+///   - 余分な右括弧で解析を止めてその範囲を返し、入力の終わりまで読めば `None` を返す
+fn tree<'src>(
+    lexer: &mut Lexer<'src>,
+    max_depth: usize,
+    mut diagnostics: Option<&mut Vec<Diagnostic>>,
+) -> Result<(Vec<TokenTree<'src>>, Option<Span>), ParseError> {
+    // 先頭は最上位で、その後に閉じていない括弧を外側から順に並べる
+    let mut stack = vec![Frame::new(None)];
+    while let Some(res) = lexer.next() {
+        let (span, token) = match (res, diagnostics.as_deref_mut()) {
+            (Ok(token), _) => token,
+            (Err(e), Some(diagnostics)) => {
+                diagnostics.push(Diagnostic::from(&ParseError::from(e.clone())));
+                lexer.recover(&e);
+                continue;
+            }
+            (Err(e), None) => return Err(e.into()),
+        };
+        let depth = stack.len() - 1;
+        let frame = stack.last_mut().expect("the top level is never popped");
+        let node = match token {
+            Token::Ident(prefix) if quote_form(prefix).is_some() => {
+                frame
+                    .prefixes
+                    .push((quote_form(prefix).expect("checked above"), span));
+                continue;
+            }
+            Token::LParen if depth >= max_depth => {
+                let e = ParseError::TooDeep { span, max_depth };
+                let Some(diagnostics) = diagnostics.as_deref_mut() else {
+                    return Err(e);
+                };
+                diagnostics.push(
+                    Diagnostic::from(&e).with_note("the parenthesized expression is skipped"),
+                );
+                frame.prefixes.clear();
+                skip_tree(lexer);
+                continue;
+            }
+            Token::LParen => {
+                stack.push(Frame::new(Some(span)));
+                continue;
+            }
+            Token::RParen => {
+                dangling_prefix(
+                    &mut frame.prefixes,
+                    span,
+                    Some(')'),
+                    diagnostics.as_deref_mut(),
+                )?;
+                let Some(open) = frame.open else {
+                    return Ok((std::mem::take(&mut frame.tokens), Some(span)));
+                };
+                let frame = stack.pop().expect("checked above");
+                TokenTree::Tree(frame.tokens, open.merge(span))
+            }
+            _ => TokenTree::Token(token, span),
+        };
+        stack
+            .last_mut()
+            .expect("the top level is never popped")
+            .push(node);
+    }
+    // 閉じられていない括弧を内側から順に報告する
+    let end = Span::new(lexer.offset(), lexer.offset());
+    loop {
+        let mut frame = stack.pop().expect("the top level is never popped");
+        dangling_prefix(&mut frame.prefixes, end, None, diagnostics.as_deref_mut())?;
+        let Some(open) = frame.open else {
+            return Ok((frame.tokens, None));
+        };
+        let e = ParseError::UnbalancedParen { span: open };
+        let Some(diagnostics) = diagnostics.as_deref_mut() else {
+            return Err(e);
+        };
+        diagnostics.push(Diagnostic::from(&e).with_note("this `(` is never closed"));
+        let node = TokenTree::Tree(frame.tokens, open.merge(end));
+        stack
+            .last_mut()
+            .expect("the top level is never popped")
+            .push(node);
+    }
+}
+
+/// 左括弧を読んだ直後から、対応する右括弧までを読み飛ばす関数
+///
+/// 読み飛ばす部分の字句解析のエラーは報告しない。
+fn skip_tree(lexer: &mut Lexer) {
+    let mut depth = 1;
+    while depth > 0 {
+        match lexer.next() {
+            Some(Ok((_, Token::LParen))) => depth += 1,
+            Some(Ok((_, Token::RParen))) => depth -= 1,
+            Some(Ok(_)) => {}
+            Some(Err(e)) => lexer.recover(&e),
+            None => break,
+        }
+    }
+}
+
+/// 前置記号が表す特殊形式の名前
+///
+/// `'x`、`` `x ``、`,x` はそれぞれ `(quote x)`、`(quasiquote x)`、`(unquote x)` として読む。
+pub(crate) fn quote_form(prefix: &str) -> Option<&'static str> {
+    match prefix {
+        "'" => Some("quote"),
+        "`" => Some("quasiquote"),
+        "," => Some("unquote"),
+        _ => None,
+    }
+}
+
+/// 続く要素の無い前置記号をエラーにする
+///
+/// エラーから回復する場合は報告を記録し、前置記号を読み飛ばす。
+fn dangling_prefix(
+    prefixes: &mut Vec<(&'static str, Span)>,
+    at: Span,
+    found: Option<char>,
+    diagnostics: Option<&mut Vec<Diagnostic>>,
+) -> Result<(), ParseError> {
+    if prefixes.is_empty() {
+        return Ok(());
+    }
+    prefixes.clear();
+    let e = ParseError::unexpected(at.start, Expected::Expression, found);
+    match diagnostics {
+        Some(diagnostics) => {
+            diagnostics.push(Diagnostic::from(&e));
+            Ok(())
+        }
+        None => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_source() {
+        assert_eq!(
+            source("((car cdr) 1)"),
+            Ok(TokenTree::Tree(
+                vec![TokenTree::Tree(
+                    vec![
+                        TokenTree::Tree(
+                            vec![
+                                TokenTree::Token(Token::Ident("car"), Span::new(2, 5)),
+                                TokenTree::Token(Token::Ident("cdr"), Span::new(6, 9)),
+                            ],
+                            Span::new(1, 10)
+                        ),
+                        TokenTree::Token(Token::Int(1), Span::new(11, 12)),
+                    ],
+                    Span::new(0, 13)
+                )],
+                Span::new(0, 13)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_source_error() {
+        assert_eq!(
+            source("(a @)"),
+            Err(ParseError::unexpected(3, Expected::Token, Some('@')))
+        );
+        // 符号や小数点だけの数値は、リテラルの範囲をエラーにする
+        let invalid = |start, end, literal: &str| ParseError::InvalidNumber {
+            span: Span::new(start, end),
+            literal: literal.to_string(),
+        };
+        assert_eq!(source("(+ . -)"), Err(invalid(3, 4, ".")));
+        assert_eq!(source("-."), Err(invalid(0, 2, "-.")));
+        assert_eq!(source("(f 1.2.3)"), Err(invalid(3, 8, "1.2.3")));
+        assert_eq!(invalid(3, 8, "1.2.3").code(), "E0004");
+        assert_eq!(
+            source("+"),
+            Ok(TokenTree::Tree(
+                vec![TokenTree::Token(Token::Ident("+"), Span::new(0, 1))],
+                Span::new(0, 1)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_paren() {
+        assert_eq!(
+            source("()())))((()))"),
+            Err(ParseError::UnbalancedParen {
+                span: Span::new(4, 5)
+            })
+        );
+        assert_eq!(
+            source("(a (b)"),
+            Err(ParseError::UnbalancedParen {
+                span: Span::new(0, 1)
+            })
+        );
+        assert_eq!(
+            source_with("(a (b)", false),
+            Err(ParseError::UnbalancedParen {
+                span: Span::new(0, 1)
+            })
+        );
+        let Ok(TokenTree::Tree(children, _)) = source_with("()())))((()))", false) else {
+            panic!("lenient mode should skip extra closers");
+        };
+        assert_eq!(children.len(), 3);
+        assert_eq!(children[2].span(), Span::new(7, 13));
+    }
+
+    #[test]
+    fn test_source_recovering() {
+        let (tree, diagnostics) = source_recovering("(a @ #b) ) (c");
+        assert_eq!(
+            tree,
+            TokenTree::Tree(
+                vec![
+                    TokenTree::Tree(
+                        vec![
+                            TokenTree::Token(Token::Ident("a"), Span::new(1, 2)),
+                            TokenTree::Token(Token::Ident("b"), Span::new(6, 7)),
+                        ],
+                        Span::new(0, 8)
+                    ),
+                    TokenTree::Tree(
+                        vec![TokenTree::Token(Token::Ident("c"), Span::new(12, 13))],
+                        Span::new(11, 13)
+                    ),
+                ],
+                Span::new(0, 13)
+            )
+        );
+        let messages: Vec<_> = diagnostics.iter().map(Diagnostic::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "expected token, found '@' at byte 3",
+                "expected token, found '#' at byte 5",
+                "unbalanced parenthesis at byte 9",
+                "unbalanced parenthesis at byte 11",
+            ]
+        );
+        let (_, diagnostics) = source_recovering("(a (b))");
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_quote() {
+        let quoted = |form: &'static str, prefix: Span, datum| {
+            let span = prefix.merge(TokenTree::span(&datum));
+            TokenTree::Tree(
+                vec![TokenTree::Token(Token::Ident(form), prefix), datum],
+                span,
+            )
+        };
+        assert_eq!(
+            source("'`(a ,b)"),
+            Ok(TokenTree::Tree(
+                vec![quoted(
+                    "quote",
+                    Span::new(0, 1),
+                    quoted(
+                        "quasiquote",
+                        Span::new(1, 2),
+                        TokenTree::Tree(
+                            vec![
+                                TokenTree::Token(Token::Ident("a"), Span::new(3, 4)),
+                                quoted(
+                                    "unquote",
+                                    Span::new(5, 6),
+                                    TokenTree::Token(Token::Ident("b"), Span::new(6, 7))
+                                ),
+                            ],
+                            Span::new(2, 8)
+                        )
+                    )
+                )],
+                Span::new(0, 8)
+            ))
+        );
+        assert_eq!(
+            source("(a ')"),
+            Err(ParseError::unexpected(4, Expected::Expression, Some(')')))
+        );
+        assert_eq!(
+            source("a '"),
+            Err(ParseError::unexpected(3, Expected::Expression, None))
+        );
+    }
+
+    #[test]
+    fn test_too_deep() {
+        let deep = |depth: usize| format!("{}{}", "(".repeat(depth), ")".repeat(depth));
+        // 再帰せずに解析するので、スタックを溢れさせずにエラーを返せる
+        assert_eq!(
+            source(&deep(100_000)),
+            Err(ParseError::TooDeep {
+                span: Span::new(MAX_DEPTH, MAX_DEPTH + 1),
+                max_depth: MAX_DEPTH,
+            })
+        );
+        assert!(source(&deep(MAX_DEPTH)).is_ok());
+        assert!(source_with_limit(&deep(5000), true, 5000).is_ok());
+        assert!(source_with_limit("(a (b))", true, 1).is_err());
+
+        let input = format!("(a {}) b", deep(MAX_DEPTH));
+        let (tree, diagnostics) = source_recovering(&input);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].span, Span::new(MAX_DEPTH + 2, MAX_DEPTH + 3));
+        let TokenTree::Tree(forms, _) = tree else {
+            unreachable!();
+        };
+        assert_eq!(forms.len(), 2);
+    }
+
+    #[test]
+    fn test_options() {
+        let forms = |input, options| match source_with_options(input, options) {
+            Ok(TokenTree::Tree(forms, _)) => {
+                Ok(forms.iter().map(TokenTree::to_owned).collect::<Vec<_>>())
+            }
+            Ok(tree) => unreachable!("{tree:?}"),
+            Err(e) => Err(e),
+        };
+        let default = ParserOptions::new();
+        assert_eq!(
+            forms("(a) b)", default.strict(false)),
+            forms("(a) b", default)
+        );
+        assert!(matches!(
+            forms("((a))", default.max_depth(1)),
+            Err(ParseError::TooDeep { max_depth: 1, .. })
+        ));
+        assert_eq!(
+            forms("変数", default.unicode_idents(false)),
+            Err(ParseError::unexpected(0, Expected::Token, Some('変')))
+        );
+        let Ok(tokens) = forms("a // b\n/* c */", default.line_comments(false)) else {
+            panic!();
+        };
+        assert_eq!(tokens.len(), 4);
+        let Ok(tokens) = forms("a // b\n/* c */", default.block_comments(false)) else {
+            panic!();
+        };
+        assert_eq!(tokens.len(), 6);
+        let tree = source_with_options("1 0x10 2.5", default.int_literals(false)).unwrap();
+        let TokenTree::Tree(numbers, _) = tree else {
+            unreachable!();
+        };
+        let numbers: Vec<_> = numbers
+            .iter()
+            .map(|tree| match tree {
+                TokenTree::Token(Token::Float(n), _) => *n,
+                tree => panic!("{tree:?}"),
+            })
+            .collect();
+        assert_eq!(numbers, vec![1.0, 16.0, 2.5]);
+    }
+
+    #[test]
+    fn test_fuzz_corpus() {
+        // ファジングの種にした入力は、どの解析でもパニックせず、文字の境界を指すエラーを返す
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus");
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let Ok(input) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            if let Err(e) = source(&input) {
+                let offset = e.offset();
+                assert!(input.is_char_boundary(offset), "{path:?}: {e:?}");
+            }
+            let (tree, _) = source_recovering(&input);
+            assert_eq!(tree.span(), Span::new(0, input.len()), "{path:?}");
+            let _ = crate::parse_expr(&input);
+            let _ = crate::statements(&input);
+            let _ = crate::cst::Cst::parse(&input);
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+}
@@ -0,0 +1,299 @@
+//! コンパイルした命令列を書き換える覗き穴最適化
+//!
+//! [`compile`](crate::bytecode::compile) は式ごとに決まった形の命令を並べるので、隣り合う命令の間に
+//! 無駄が残る。[`optimize`] は短い命令の並びを、実行した結果とエラーが同じになる別の並びに置き換える。
+//!
+//! ```
+//! use ruscal_b::bytecode::Instruction;
+//! use ruscal_b::peephole::optimize;
+//! use ruscal_b::{compile, parse_expr};
+//!
+//! let mut bytecode = compile(&parse_expr("{ var x = 1; x + 2 }").unwrap());
+//! optimize(&mut bytecode);
+//! assert!(bytecode.code.contains(&Instruction::AddConstant(1)));
+//! ```
+
+use std::rc::Rc;
+
+use crate::bytecode::{Bytecode, Instruction};
+use crate::eval::Value;
+
+/// 命令列と、その中の関数の命令列を最適化する関数
+///
+/// 次の書き換えを、変わらなくなるまで繰り返す。
+///
+/// * ジャンプ先が無条件のジャンプなら、その先に直接ジャンプする
+/// * 次の命令へのジャンプと、どこからも到達しない命令を取り除く
+/// * 定数で決まる条件ジャンプを、無条件のジャンプにするか取り除く
+/// * 数値の定数を積んで足す並びを [`Instruction::AddConstant`] にまとめる
+/// * 定数の種類の検査、積んですぐ捨てる値、定義や代入の直後の同じ変数の読み込みを取り除く
+///
+/// 並びの途中にジャンプしてくる命令があれば、その並びは書き換えない。
+/// 行と桁の対応表は、残る命令に合わせて付け直す。
+///
+/// # 引数
+/// * `bytecode` - 書き換える命令列
+pub fn optimize(bytecode: &mut Bytecode) {
+    loop {
+        let threaded = thread_jumps(bytecode);
+        let simplified = simplify(bytecode);
+        if !(remove_unreachable(bytecode) || threaded || simplified) {
+            break;
+        }
+    }
+    for prototype in &mut bytecode.functions {
+        optimize(Rc::make_mut(&mut Rc::make_mut(prototype).code));
+    }
+}
+
+/// 命令のジャンプ先
+fn target(instruction: Instruction) -> Option<u32> {
+    match instruction {
+        Instruction::Jump(target)
+        | Instruction::JumpIfFalse(target)
+        | Instruction::ForNext(target) => Some(target),
+        _ => None,
+    }
+}
+
+/// 命令のジャンプ先を書き換える
+fn retarget(instruction: &mut Instruction, f: impl Fn(u32) -> u32) {
+    if let Instruction::Jump(target)
+    | Instruction::JumpIfFalse(target)
+    | Instruction::ForNext(target) = instruction
+    {
+        *target = f(*target);
+    }
+}
+
+/// 無条件のジャンプに飛ぶジャンプを、最後のジャンプ先に直接飛ぶようにする
+fn thread_jumps(bytecode: &mut Bytecode) -> bool {
+    let mut changed = false;
+    for pc in 0..bytecode.code.len() {
+        let Some(mut to) = target(bytecode.code[pc]) else {
+            continue;
+        };
+        // ジャンプだけの無限ループでも止まるよう、たどる回数を命令の数までにする
+        for _ in 0..bytecode.code.len() {
+            match bytecode.code.get(to as usize) {
+                Some(&Instruction::Jump(next)) if next != to => to = next,
+                _ => break,
+            }
+        }
+        if target(bytecode.code[pc]) != Some(to) {
+            retarget(&mut bytecode.code[pc], |_| to);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// 隣り合う命令の並びを、より短い並びに置き換える
+fn simplify(bytecode: &mut Bytecode) -> bool {
+    let code = &mut bytecode.code;
+    let mut is_target = vec![false; code.len() + 1];
+    for &instruction in code.iter() {
+        if let Some(to) = target(instruction) {
+            is_target[to as usize] = true;
+        }
+    }
+    let constants = &bytecode.constants;
+    let number = |index: u32| matches!(constants[index as usize], Value::I64(_) | Value::F64(_));
+    let mut removed = vec![false; code.len()];
+    let mut pc = 0;
+    while pc < code.len() {
+        // 並びの2つ目以降の命令にジャンプしてくるなら、その並びは書き換えない
+        let window = |len: usize| -> Option<&[Instruction]> {
+            let end = pc + len;
+            (end <= code.len() && !is_target[pc + 1..end].contains(&true)).then(|| &code[pc..end])
+        };
+        let len = match (window(3), window(2)) {
+            (Some(&[Instruction::Define(a), Instruction::Pop, Instruction::Load(b)]), _)
+            | (Some(&[Instruction::Assign(a), Instruction::Pop, Instruction::Load(b)]), _)
+                if a == b =>
+            {
+                removed[pc + 1] = true;
+                removed[pc + 2] = true;
+                3
+            }
+            (_, Some(&[Instruction::Constant(_) | Instruction::Dup, Instruction::Pop])) => {
+                removed[pc] = true;
+                removed[pc + 1] = true;
+                2
+            }
+            (_, Some(&[Instruction::Constant(index), Instruction::ExpectNumber]))
+                if number(index) =>
+            {
+                removed[pc + 1] = true;
+                2
+            }
+            (_, Some(&[Instruction::Constant(index), Instruction::ExpectInteger]))
+                if matches!(constants[index as usize], Value::I64(_)) =>
+            {
+                removed[pc + 1] = true;
+                2
+            }
+            (_, Some(&[Instruction::Constant(index), Instruction::Add])) if number(index) => {
+                // 和を計算する命令の位置に置き、エラーの位置を変えない
+                removed[pc] = true;
+                code[pc + 1] = Instruction::AddConstant(index);
+                2
+            }
+            (_, Some(&[Instruction::Constant(index), Instruction::JumpIfFalse(to)])) => {
+                removed[pc] = true;
+                if constants[index as usize].is_truthy() {
+                    removed[pc + 1] = true;
+                } else {
+                    code[pc + 1] = Instruction::Jump(to);
+                }
+                2
+            }
+            _ => {
+                if code[pc] == Instruction::Jump(pc as u32 + 1) {
+                    removed[pc] = true;
+                }
+                1
+            }
+        };
+        pc += len;
+    }
+    compact(bytecode, &removed)
+}
+
+/// 先頭から到達しない命令を取り除く
+///
+/// 命令列は [`Instruction::Return`] で終わらなければならないので、最後の命令は残す。
+fn remove_unreachable(bytecode: &mut Bytecode) -> bool {
+    let len = bytecode.code.len();
+    let mut reachable = vec![false; len];
+    let mut work = vec![0];
+    while let Some(pc) = work.pop() {
+        if pc >= len || reachable[pc] {
+            continue;
+        }
+        reachable[pc] = true;
+        let instruction = bytecode.code[pc];
+        work.extend(target(instruction).map(|to| to as usize));
+        if !matches!(instruction, Instruction::Jump(_) | Instruction::Return) {
+            work.push(pc + 1);
+        }
+    }
+    if let Some(last) = reachable.last_mut() {
+        *last = true;
+    }
+    let removed: Vec<_> = reachable.iter().map(|&reachable| !reachable).collect();
+    compact(bytecode, &removed)
+}
+
+/// 取り除く印の付いた命令を取り除き、ジャンプ先を付け直す。命令を取り除いたかどうかを返す
+///
+/// 取り除いた命令へのジャンプは、その後に残る最初の命令へのジャンプになる。
+fn compact(bytecode: &mut Bytecode, removed: &[bool]) -> bool {
+    if !removed.contains(&true) {
+        return false;
+    }
+    let mut map = Vec::with_capacity(removed.len() + 1);
+    let mut next = 0;
+    for &removed in removed {
+        map.push(next);
+        next += u32::from(!removed);
+    }
+    map.push(next);
+    let mut flags = removed.iter();
+    bytecode
+        .code
+        .retain(|_| !flags.next().expect("a flag for each instruction"));
+    let mut flags = removed.iter();
+    bytecode
+        .spans
+        .retain(|_| !flags.next().expect("a flag for each span"));
+    for instruction in &mut bytecode.code {
+        retarget(instruction, |to| map[to as usize]);
+    }
+    bytecode.lines.remap(|pc| map[pc]);
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{Expr, ExprKind, Span};
+    use crate::bytecode::compile;
+    use crate::env::Environment;
+    use crate::infix::statements;
+    use crate::stdlib::register;
+    use crate::vm::Vm;
+    use Instruction::*;
+
+    fn compile_str(input: &str) -> Bytecode {
+        let block = Expr::new(
+            ExprKind::Block(statements(input).unwrap()),
+            Span::new(0, input.len()),
+        );
+        compile(&block)
+    }
+
+    fn run(bytecode: &Bytecode) -> Result<Value, crate::eval::EvalError> {
+        let mut env = Environment::new();
+        register(&mut env);
+        Vm::new().run(bytecode, &mut env)
+    }
+
+    #[test]
+    fn test_rewrites() {
+        let mut bytecode = compile_str("var x = 1\nx = x + 2\nx");
+        optimize(&mut bytecode);
+        assert_eq!(
+            bytecode.code,
+            [
+                PushScope,
+                Constant(0),
+                Define("x".into()),
+                ExpectNumber,
+                AddConstant(1),
+                Assign("x".into()),
+                PopScope,
+                Return,
+            ]
+        );
+        assert_eq!(bytecode.spans.len(), bytecode.code.len());
+    }
+
+    #[test]
+    fn test_jumps() {
+        // `while true` の条件の検査と、`if false` の到達しない腕を取り除く
+        let mut bytecode = compile_str(
+            "var n = 0; while true { n = n + 1; if n > 4 { break } }; if false { 1 } else { n }",
+        );
+        optimize(&mut bytecode);
+        for (pc, &instruction) in bytecode.code.iter().enumerate() {
+            assert_ne!(instruction, Jump(pc as u32 + 1), "at {pc}");
+            if let JumpIfFalse(_) = instruction {
+                assert!(!matches!(bytecode.code[pc - 1], Constant(_)), "at {pc}");
+            }
+            if let Some(to) = target(instruction) {
+                assert!(!matches!(bytecode.code[to as usize], Jump(_)), "at {pc}");
+            }
+        }
+        assert_eq!(run(&bytecode), Ok(Value::I64(5)));
+    }
+
+    #[test]
+    fn test_same_results() {
+        for input in [
+            "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }\nfib(15)",
+            "var s = 0\nfor i in 0..20 { if i == 3 { continue }; if i > 15 { break }; s = s + i }\ns",
+            "var t = 0.5; var n = 3; while n > 0 { t = t + 1; n = n - 1 }; t",
+            "fn adder(n) { fn(x) { x + n } }\nvar add2 = adder(2)\nadd2(40)",
+            "var x = \"a\"\nx + 1",
+            "var x = 9223372036854775807\nx + 1",
+            "var x = 1\nvar y = x\ny + 0.5",
+        ] {
+            let original = compile_str(input);
+            let mut optimized = original.clone();
+            optimize(&mut optimized);
+            assert!(optimized.code.len() < original.code.len(), "{input}");
+            assert_eq!(run(&optimized), run(&original), "{input}");
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! 最上位の式とコンパイル済みの関数ごとに実行時間を測るプロファイラー
+//!
+//! [`start_profile`] から [`finish_profile`] までの間、[`Vm`](crate::vm::Vm) がコンパイル済みの
+//! 関数を呼び出すたびに、呼び出しの回数と経過時間を関数ごとに足し合わせる。最上位の式は
+//! 実行する側が [`enter`] で区切って測る。測った結果は、他の項目を除いた自身の時間が長い順に
+//! [`Profile::report`] で表にする。
+//!
+//! 計測はスレッドごとに行い、計測していない間は呼び出しごとに真偽値を1つ読むだけで済む。
+//!
+//! ```
+//! use ruscal_b::{compile_program, eval::lower, parser::source, profile, Environment, TokenTree, Vm};
+//!
+//! let TokenTree::Tree(forms, _) = source("(define f (fn (n) (* n 2))) (f 1) (f 2)").unwrap() else {
+//!     unreachable!()
+//! };
+//! let exprs: Vec<_> = forms.iter().map(|form| lower(form).unwrap()).collect();
+//! profile::start_profile();
+//! Vm::new().run(&compile_program(&exprs), &mut Environment::new()).unwrap();
+//! let profile = profile::finish_profile();
+//! assert_eq!(profile.entries()[0].name, "f");
+//! assert_eq!(profile.entries()[0].calls, 2);
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::ast::Span;
+use crate::source_map::SourceMap;
+
+/// 1つの項目の計測結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// 関数を呼び出した名前か、最上位の式を表す名前
+    pub name: String,
+    /// 関数を定義した `fn` 式か、最上位の式の範囲
+    pub span: Span,
+    /// 呼び出した回数
+    pub calls: u64,
+    /// 呼び出しから戻るまでの時間の合計。再帰した呼び出しは一番外側だけを数える
+    pub total: Duration,
+    /// `total` から、中で呼び出した他の項目の時間を除いた時間
+    pub own: Duration,
+}
+
+/// 計測を終えた結果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    entries: Vec<Entry>,
+}
+
+impl Profile {
+    /// 自身の時間が長い順に並べた項目
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// 項目を1行ずつ並べた表
+    ///
+    /// `source` を渡せば、項目の範囲を行と桁で示す。渡さなければバイト位置で示す。
+    pub fn report(&self, source: Option<&str>) -> String {
+        let map = source.map(SourceMap::new);
+        let whole: Duration = self.entries.iter().map(|entry| entry.own).sum();
+        let mut report = format!(
+            "{:>6}  {:>10}  {:>10}  {:>8}  name\n",
+            "own%", "own ms", "total ms", "calls"
+        );
+        for entry in &self.entries {
+            let share = if whole.is_zero() {
+                0.0
+            } else {
+                100.0 * entry.own.as_secs_f64() / whole.as_secs_f64()
+            };
+            let at = match &map {
+                Some(map) => map.line_col(entry.span.start).to_string(),
+                None => format!("byte {}", entry.span.start),
+            };
+            let _ = writeln!(
+                report,
+                "{share:>5.1}%  {:>10.3}  {:>10.3}  {:>8}  {} ({at})",
+                entry.own.as_secs_f64() * 1e3,
+                entry.total.as_secs_f64() * 1e3,
+                entry.calls,
+                entry.name,
+            );
+        }
+        report
+    }
+}
+
+/// 計測中の状態
+#[derive(Default)]
+struct Profiler {
+    entries: Vec<Entry>,
+    /// 名前と範囲の始まりと終わりから、項目の位置を引く表
+    index: HashMap<(String, usize, usize), usize>,
+    /// 実行中の項目ごとの、入れ子になった呼び出しの数
+    active: Vec<usize>,
+    /// 実行中の呼び出しと、その中で呼び出した他の項目の時間。外側から順に並べる
+    stack: Vec<(usize, Instant, Duration)>,
+}
+
+thread_local! {
+    /// 計測しているなら `true`
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    /// このスレッドの計測の状態
+    static PROFILER: RefCell<Profiler> = RefCell::new(Profiler::default());
+}
+
+/// このスレッドの計測を始める関数。それまでの結果は捨てる
+pub fn start_profile() {
+    PROFILER.with(|p| *p.borrow_mut() = Profiler::default());
+    ENABLED.with(|e| e.set(true));
+}
+
+/// このスレッドの計測を終え、結果を返す関数
+pub fn finish_profile() -> Profile {
+    ENABLED.with(|e| e.set(false));
+    let mut entries = PROFILER.with(|p| std::mem::take(&mut *p.borrow_mut()).entries);
+    entries.sort_by(|a, b| b.own.cmp(&a.own).then(a.span.start.cmp(&b.span.start)));
+    Profile { entries }
+}
+
+/// 項目の実行を測り始める関数
+///
+/// 返した値を捨てたときに、経過時間をその項目に足す。計測していなければ `None` を返す。
+pub fn enter(name: &str, span: Span) -> Option<ProfileGuard> {
+    if !ENABLED.with(Cell::get) {
+        return None;
+    }
+    PROFILER.with(|p| {
+        let mut p = p.borrow_mut();
+        let p = &mut *p;
+        let key = (name.to_string(), span.start, span.end);
+        let id = match p.index.get(&key) {
+            Some(&id) => id,
+            None => {
+                p.entries.push(Entry {
+                    name: key.0.clone(),
+                    span,
+                    calls: 0,
+                    total: Duration::ZERO,
+                    own: Duration::ZERO,
+                });
+                p.active.push(0);
+                p.index.insert(key, p.entries.len() - 1);
+                p.entries.len() - 1
+            }
+        };
+        p.entries[id].calls += 1;
+        p.active[id] += 1;
+        p.stack.push((id, Instant::now(), Duration::ZERO));
+    });
+    Some(ProfileGuard { _private: () })
+}
+
+/// 実行中の項目。捨てると経過時間を記録する
+#[must_use]
+pub struct ProfileGuard {
+    _private: (),
+}
+
+impl Drop for ProfileGuard {
+    fn drop(&mut self) {
+        let _ = PROFILER.try_with(|p| {
+            let mut p = p.borrow_mut();
+            let Some((id, start, children)) = p.stack.pop() else {
+                return;
+            };
+            let elapsed = start.elapsed();
+            p.active[id] -= 1;
+            let outermost = p.active[id] == 0;
+            let entry = &mut p.entries[id];
+            entry.own += elapsed.saturating_sub(children);
+            if outermost {
+                entry.total += elapsed;
+            }
+            if let Some((_, _, children)) = p.stack.last_mut() {
+                *children += elapsed;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nested_entries() {
+        start_profile();
+        {
+            let _outer = enter("outer", Span::new(0, 1));
+            for _ in 0..3 {
+                let _inner = enter("inner", Span::new(2, 3));
+                std::thread::sleep(Duration::from_millis(2));
+            }
+            // 再帰した呼び出しの時間は合計に二重に数えない
+            let _again = enter("outer", Span::new(0, 1));
+        }
+        let profile = finish_profile();
+        let [first, second] = profile.entries() else {
+            panic!("{profile:?}");
+        };
+        assert_eq!((first.name.as_str(), first.calls), ("inner", 3));
+        assert_eq!((second.name.as_str(), second.calls), ("outer", 2));
+        assert!(first.own >= Duration::from_millis(6));
+        assert!(second.total >= first.total);
+        assert!(second.own < first.own);
+        // 計測を終えた後は記録しない
+        assert!(enter("late", Span::new(0, 0)).is_none());
+        assert!(finish_profile().entries().is_empty());
+    }
+
+    #[test]
+    fn test_report() {
+        let profile = Profile {
+            entries: vec![Entry {
+                name: "f".to_string(),
+                span: Span::new(4, 9),
+                calls: 2,
+                total: Duration::from_millis(3),
+                own: Duration::from_millis(1),
+            }],
+        };
+        let report = profile.report(Some("1\n2\n(fn ...)"));
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "  own%      own ms    total ms     calls  name",
+                "100.0%       1.000       3.000         2  f (3:1)",
+            ]
+        );
+        assert!(profile.report(None).ends_with("f (byte 4)\n"));
+    }
+}
@@ -0,0 +1,862 @@
+//! スタックマシンの命令列をレジスタマシンの命令列に変換して実行する仮想機械
+//!
+//! [`translate`] は [`Bytecode`] の各命令の時点のスタックの深さを求め、深さ `i` の値を
+//! レジスタ `i` に割り当てる。値を積んで取り出すだけの命令の組はレジスタを読み書きする1つの命令に
+//! なり、定数はそれを使う命令のオペランドに直接埋め込む。型が分かっている定数の確認は省く。
+//!
+//! [`RegisterVm`] は呼び出しごとにレジスタの並びを確保して命令を実行する。環境と値は
+//! [`Vm`](crate::vm::Vm) と同じものを使うので、同じ命令列はどちらで実行しても同じ結果になる。
+//! ただし命令の数が減るので、手数の上限に達するまでに進める量は多くなる。
+//!
+//! ```
+//! use ruscal_b::register_vm::{translate, RegisterVm};
+//! use ruscal_b::{compile_program, eval::lower, parser::source, Environment, TokenTree, Value};
+//!
+//! let TokenTree::Tree(forms, _) = source("(define x 2) (+ (* x 3) 1)").unwrap() else {
+//!     unreachable!()
+//! };
+//! let exprs: Vec<_> = forms.iter().map(|form| lower(form).unwrap()).collect();
+//! let bytecode = compile_program(&exprs);
+//! assert!(translate(&bytecode).code.len() < bytecode.code.len());
+//! let value = RegisterVm::new().run(&bytecode, &mut Environment::new()).unwrap();
+//! assert_eq!(value, Value::I64(7));
+//! ```
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::{BinOp, Span};
+use crate::bytecode::{Bytecode, Instruction, Prototype};
+use crate::env::Environment;
+use crate::eval::{
+    arithmetic, binary, bind_arguments, call, expect_integer, expect_key, expect_number, get_index,
+    set_index, values_equal, CallGuard, EvalError, Function, FunctionBody, Value,
+};
+use crate::intern::Symbol;
+use crate::limits;
+use crate::module;
+use crate::profile;
+
+/// 命令が読む値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// レジスタの値
+    Reg(u32),
+    /// 定数表の値
+    Const(u32),
+}
+
+/// レジスタマシンの命令
+///
+/// レジスタは呼び出しごとの番号で表す。複数の値を使う命令は、指定したレジスタから続く
+/// レジスタを順に読む。ジャンプ先は同じ命令列の中の位置で表す。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// 値を `dst` に書く
+    Move { dst: u32, src: Operand },
+    /// 変数の値を `dst` に書く
+    Load { dst: u32, name: Symbol },
+    /// 値で一番内側のスコープに変数を定義する
+    Define { name: Symbol, src: Operand },
+    /// 値を定義済みの変数に代入する
+    Assign { name: Symbol, src: Operand },
+    /// 値が数値であることを確かめる
+    ExpectNumber(Operand),
+    /// 値が整数であることを確かめる
+    ExpectInteger(Operand),
+    /// 演算子が変数で隠されていなければ、値が数値であることを確かめる
+    ExpectOperand { name: Symbol, src: Operand },
+    /// 値をマップのキーにして `dst` に書く
+    ExpectKey { dst: u32, src: Operand },
+    /// 二項演算の結果を `dst` に書く
+    Binary {
+        op: BinOp,
+        dst: u32,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    /// 数値の符号を反転して `dst` に書く
+    Neg { dst: u32, src: Operand },
+    /// 値の真偽を反転した真偽値を `dst` に書く
+    Not { dst: u32, src: Operand },
+    /// 値の真偽を表す真偽値を `dst` に書く
+    Truthy { dst: u32, src: Operand },
+    /// 無条件にジャンプする
+    Jump(u32),
+    /// 値が偽ならジャンプする
+    JumpIfFalse { cond: Operand, target: u32 },
+    /// `base` から `argc` 個のレジスタに組み込みの演算子を適用し、結果を `base` に書く
+    Operator { name: Symbol, base: u32, argc: u32 },
+    /// `callee` の値が関数であることを確かめる
+    ExpectFunction(u32),
+    /// `callee` の関数に続く `argc` 個のレジスタを渡して呼び出し、結果を `callee` に書く
+    Call {
+        name: Option<Symbol>,
+        callee: u32,
+        argc: u32,
+    },
+    /// 関数表の関数から、現在の環境を捕捉した関数を作って `dst` に書く
+    Closure { dst: u32, index: u32 },
+    /// 新しい内側のスコープを開始する
+    PushScope,
+    /// 一番内側のスコープを終了する
+    PopScope,
+    /// `for` の繰り返しを1回進める
+    ///
+    /// `counter` の現在の値が次のレジスタの終わりの値より小さければ、現在の値を1増やしてから
+    /// 増やす前の値をその次のレジスタに書き、そうでなければジャンプする。
+    ForNext { counter: u32, target: u32 },
+    /// ループの外で `break` や `continue` を使ったエラーにする
+    OutsideLoop(&'static str),
+    /// 関数の外で `return` を使ったエラーにする
+    OutsideFunction,
+    /// `match` のどの腕にも値が一致しなかったエラーにする
+    NoMatch(Operand),
+    /// `base` から `len` 個のレジスタの値を並べたリストを `base` に書く
+    List { base: u32, len: u32 },
+    /// `base` から `len` 個のレジスタの値を並べた配列を `base` に書く
+    Array { base: u32, len: u32 },
+    /// `base` から `len` 組のキーと値のレジスタの値を持つマップを `base` に書く
+    Map { base: u32, len: u32 },
+    /// 定数表の文字列のパスのファイルをモジュールとして読み込み、`dst` に書く
+    Import { dst: u32, index: u32 },
+    /// `target` の配列の `index` の位置の要素を `target` に書く
+    Index { target: u32, index: Operand },
+    /// `base` の配列の、次のレジスタの位置の要素をその次のレジスタの値に書き換え、値を `base` に書く
+    SetIndex(u32),
+    /// 値を戻り値として命令列の実行を終える
+    Return(Operand),
+}
+
+/// レジスタマシンの命令列
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RegisterCode {
+    /// 実行する命令
+    pub code: Vec<Op>,
+    /// 各命令の元になった命令の範囲。実行時のエラーの位置に使う
+    pub spans: Vec<Span>,
+    /// [`Operand::Const`] が参照する定数表
+    pub constants: Vec<Value>,
+    /// [`Op::Closure`] が参照する関数表
+    pub functions: Vec<Rc<Prototype>>,
+    /// 1回の実行に使うレジスタの数
+    pub registers: usize,
+}
+
+/// 命令列の各命令の時点のスタックの深さを求める関数。実行されない命令は `None` になる
+fn depths(bytecode: &Bytecode) -> Vec<Option<usize>> {
+    let mut depths = vec![None; bytecode.code.len()];
+    let mut work = vec![(0, 0usize)];
+    while let Some((pc, depth)) = work.pop() {
+        let Some(slot) = depths.get_mut(pc) else {
+            continue;
+        };
+        if let Some(known) = *slot {
+            debug_assert_eq!(known, depth, "the compiler keeps the stack balanced");
+            continue;
+        }
+        *slot = Some(depth);
+        let after = depth.saturating_add_signed(bytecode.code[pc].stack_effect());
+        match bytecode.code[pc] {
+            Instruction::Jump(target) => work.push((target as usize, depth)),
+            Instruction::JumpIfFalse(target) => {
+                work.push((target as usize, after));
+                work.push((pc + 1, after));
+            }
+            Instruction::ForNext(target) => {
+                work.push((target as usize, depth));
+                work.push((pc + 1, after));
+            }
+            Instruction::Return
+            | Instruction::OutsideLoop(_)
+            | Instruction::OutsideFunction
+            | Instruction::NoMatch => {}
+            _ => work.push((pc + 1, after)),
+        }
+    }
+    depths
+}
+
+/// 変換中の状態
+struct Translator<'a> {
+    bytecode: &'a Bytecode,
+    out: RegisterCode,
+    /// スタックの深さごとの値。深さ `i` の値はレジスタ `i` か、まだレジスタに書いていない定数
+    slots: Vec<Operand>,
+    span: Span,
+}
+
+impl Translator<'_> {
+    fn emit(&mut self, op: Op) {
+        self.out.code.push(op);
+        self.out.spans.push(self.span);
+    }
+
+    fn pop(&mut self) -> Operand {
+        self.slots
+            .pop()
+            .expect("the compiler keeps the stack balanced")
+    }
+
+    fn top(&self) -> Operand {
+        *self
+            .slots
+            .last()
+            .expect("the compiler keeps the stack balanced")
+    }
+
+    /// 結果を書くレジスタとして、深さ `depth` の値をレジスタ `depth` に置く
+    fn push_reg(&mut self, depth: usize) -> u32 {
+        self.slots.truncate(depth);
+        self.slots.push(Operand::Reg(depth as u32));
+        depth as u32
+    }
+
+    /// 深さ `from` 以上の定数をレジスタに書き、`from` のレジスタの番号を返す
+    fn materialize(&mut self, from: usize) -> u32 {
+        for depth in from..self.slots.len() {
+            if let Operand::Const(index) = self.slots[depth] {
+                let dst = depth as u32;
+                self.emit(Op::Move {
+                    dst,
+                    src: Operand::Const(index),
+                });
+                self.slots[depth] = Operand::Reg(dst);
+            }
+        }
+        from as u32
+    }
+
+    /// スタックの上から `n` 個の値が始まる深さ
+    fn base(&self, n: usize) -> usize {
+        self.slots.len() - n
+    }
+
+    /// 定数が確かめる条件を満たすと分かっていれば `true`
+    fn known(&self, operand: Operand, check: fn(Value, Span) -> bool) -> bool {
+        match operand {
+            Operand::Const(index) => {
+                check(self.bytecode.constants[index as usize].clone(), self.span)
+            }
+            Operand::Reg(_) => false,
+        }
+    }
+
+    /// 1つの命令を変換する。続く命令に進まない命令なら `false` を返す
+    fn instruction(&mut self, instruction: Instruction) -> bool {
+        let depth = self.slots.len();
+        match instruction {
+            Instruction::Constant(index) => self.slots.push(Operand::Const(index)),
+            Instruction::Pop => {
+                self.pop();
+            }
+            Instruction::Dup => match self.top() {
+                Operand::Const(index) => self.slots.push(Operand::Const(index)),
+                src => {
+                    let dst = self.push_reg(depth);
+                    self.emit(Op::Move { dst, src });
+                }
+            },
+            Instruction::Load(name) => {
+                let dst = self.push_reg(depth);
+                self.emit(Op::Load { dst, name });
+            }
+            Instruction::Define(name) => self.emit(Op::Define {
+                name,
+                src: self.top(),
+            }),
+            Instruction::Assign(name) => self.emit(Op::Assign {
+                name,
+                src: self.top(),
+            }),
+            Instruction::ExpectNumber => {
+                if !self.known(self.top(), |v, span| expect_number(v, span).is_ok()) {
+                    self.emit(Op::ExpectNumber(self.top()));
+                }
+            }
+            Instruction::ExpectInteger => {
+                if !self.known(self.top(), |v, span| expect_integer(v, span).is_ok()) {
+                    self.emit(Op::ExpectInteger(self.top()));
+                }
+            }
+            Instruction::ExpectOperand(name) => {
+                if !self.known(self.top(), |v, span| expect_number(v, span).is_ok()) {
+                    self.emit(Op::ExpectOperand {
+                        name,
+                        src: self.top(),
+                    });
+                }
+            }
+            Instruction::ExpectKey => {
+                let src = self.pop();
+                let dst = self.push_reg(depth - 1);
+                self.emit(Op::ExpectKey { dst, src });
+            }
+            Instruction::Neg | Instruction::Not | Instruction::Truthy => {
+                let src = self.pop();
+                let dst = self.push_reg(depth - 1);
+                self.emit(match instruction {
+                    Instruction::Neg => Op::Neg { dst, src },
+                    Instruction::Not => Op::Not { dst, src },
+                    _ => Op::Truthy { dst, src },
+                });
+            }
+            Instruction::Jump(target) => {
+                self.materialize(0);
+                self.emit(Op::Jump(target));
+                return false;
+            }
+            Instruction::JumpIfFalse(target) => {
+                let cond = self.pop();
+                self.materialize(0);
+                self.emit(Op::JumpIfFalse { cond, target });
+            }
+            Instruction::Operator { name, argc } => {
+                let base = self.materialize(self.base(argc as usize));
+                self.emit(Op::Operator { name, base, argc });
+                self.push_reg(base as usize);
+            }
+            Instruction::ExpectFunction(argc) => {
+                let callee = self.base(argc as usize + 1);
+                if let Operand::Const(index) = self.slots[callee] {
+                    // 定数は関数ではないので、レジスタに書いてから確かめてエラーにする
+                    self.emit(Op::Move {
+                        dst: callee as u32,
+                        src: Operand::Const(index),
+                    });
+                    self.slots[callee] = Operand::Reg(callee as u32);
+                }
+                self.emit(Op::ExpectFunction(callee as u32));
+            }
+            Instruction::Call { name, argc } => {
+                let callee = self.materialize(self.base(argc as usize + 1));
+                self.emit(Op::Call { name, callee, argc });
+                self.push_reg(callee as usize);
+            }
+            Instruction::Closure(index) => {
+                let dst = self.push_reg(depth);
+                self.emit(Op::Closure { dst, index });
+            }
+            Instruction::PushScope => self.emit(Op::PushScope),
+            Instruction::PopScope => self.emit(Op::PopScope),
+            Instruction::ForNext(target) => {
+                self.materialize(0);
+                let counter = (depth - 2) as u32;
+                self.emit(Op::ForNext { counter, target });
+                self.push_reg(depth);
+            }
+            Instruction::OutsideLoop(keyword) => {
+                self.emit(Op::OutsideLoop(keyword));
+                return false;
+            }
+            Instruction::OutsideFunction => {
+                self.emit(Op::OutsideFunction);
+                return false;
+            }
+            Instruction::NoMatch => {
+                let src = self.pop();
+                self.emit(Op::NoMatch(src));
+                return false;
+            }
+            Instruction::List(len) | Instruction::Array(len) | Instruction::Map(len) => {
+                let count = match instruction {
+                    Instruction::Map(_) => 2 * len as usize,
+                    _ => len as usize,
+                };
+                let base = self.materialize(self.base(count));
+                self.emit(match instruction {
+                    Instruction::List(_) => Op::List { base, len },
+                    Instruction::Array(_) => Op::Array { base, len },
+                    _ => Op::Map { base, len },
+                });
+                self.push_reg(base as usize);
+            }
+            Instruction::AddConstant(index) => {
+                let lhs = self.pop();
+                let dst = self.push_reg(depth - 1);
+                let rhs = Operand::Const(index);
+                self.emit(Op::Binary {
+                    op: BinOp::Add,
+                    dst,
+                    lhs,
+                    rhs,
+                });
+            }
+            Instruction::Import(index) => {
+                let dst = self.push_reg(depth);
+                self.emit(Op::Import { dst, index });
+            }
+            Instruction::Index => {
+                let index = self.pop();
+                let target = self.materialize(depth - 2);
+                self.emit(Op::Index { target, index });
+            }
+            Instruction::SetIndex => {
+                let base = self.materialize(depth - 3);
+                self.emit(Op::SetIndex(base));
+                self.push_reg(base as usize);
+            }
+            Instruction::Return => {
+                let src = self.pop();
+                self.emit(Op::Return(src));
+                return false;
+            }
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Rem
+            | Instruction::Lt
+            | Instruction::Le
+            | Instruction::Gt
+            | Instruction::Ge
+            | Instruction::Eq
+            | Instruction::Ne => {
+                let op = instruction.binary_op().expect("matched a binary operator");
+                let rhs = self.pop();
+                let lhs = self.pop();
+                let dst = self.push_reg(depth - 2);
+                self.emit(Op::Binary { op, dst, lhs, rhs });
+            }
+        }
+        true
+    }
+}
+
+/// スタックマシンの命令列をレジスタマシンの命令列に変換する関数
+///
+/// ジャンプ先の命令の時点では、スタックのすべての値がレジスタにあるようにする。
+///
+/// # 引数
+/// * `bytecode` - 変換する命令列
+///
+/// # 戻り値
+/// * `RegisterCode` - 同じ結果になるレジスタマシンの命令列
+pub fn translate(bytecode: &Bytecode) -> RegisterCode {
+    let depths = depths(bytecode);
+    let mut targets = vec![false; bytecode.code.len() + 1];
+    for instruction in &bytecode.code {
+        if let Instruction::Jump(target)
+        | Instruction::JumpIfFalse(target)
+        | Instruction::ForNext(target) = *instruction
+        {
+            targets[target as usize] = true;
+        }
+    }
+    let mut translator = Translator {
+        bytecode,
+        out: RegisterCode {
+            constants: bytecode.constants.clone(),
+            functions: bytecode.functions.clone(),
+            registers: depths.iter().flatten().max().map_or(0, |&depth| depth + 1),
+            ..RegisterCode::default()
+        },
+        slots: Vec::new(),
+        span: Span::default(),
+    };
+    // 元の命令の位置ごとの、変換した命令の位置
+    let mut starts = vec![0; bytecode.code.len() + 1];
+    let mut reachable = true;
+    for (pc, &instruction) in bytecode.code.iter().enumerate() {
+        if targets[pc] && reachable {
+            // 続けて実行するときも、ジャンプして来たときと同じくすべての値をレジスタに置く
+            translator.materialize(0);
+        }
+        starts[pc] = translator.out.code.len() as u32;
+        let Some(depth) = depths[pc] else {
+            reachable = false;
+            continue;
+        };
+        if targets[pc] || !reachable {
+            translator.slots = (0..depth as u32).map(Operand::Reg).collect();
+        }
+        translator.span = bytecode.spans[pc];
+        reachable = translator.instruction(instruction);
+    }
+    starts[bytecode.code.len()] = translator.out.code.len() as u32;
+    let mut out = translator.out;
+    for op in &mut out.code {
+        match op {
+            Op::Jump(target) | Op::JumpIfFalse { target, .. } | Op::ForNext { target, .. } => {
+                *target = starts[*target as usize]
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+/// [`RegisterCode`] を実行するレジスタマシン
+///
+/// レジスタの並びは関数の呼び出しをまたいで共有し、呼び出しごとに末尾に確保する。
+/// 関数の本体は初めて呼び出したときに変換し、変換した結果を使い回す。
+#[derive(Debug, Default)]
+pub struct RegisterVm {
+    registers: Vec<Value>,
+    /// 関数の本体の命令列のアドレスごとの、命令列と変換した結果
+    ///
+    /// 命令列を持っておくのは、キーにしたアドレスが他の命令列に使い回されないようにするため。
+    functions: HashMap<usize, (Rc<Bytecode>, Rc<RegisterCode>)>,
+}
+
+impl RegisterVm {
+    /// 空のレジスタを持つレジスタマシンを作る
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 命令列をレジスタマシンの命令列に変換して実行する
+    ///
+    /// # 引数
+    /// * `bytecode` - 実行する命令列
+    /// * `env` - 変数の束縛を探し、`define` で定義を追加する環境
+    ///
+    /// # 戻り値
+    /// * `Result<Value, EvalError>` - 命令列が返した値
+    pub fn run(&mut self, bytecode: &Bytecode, env: &mut Environment) -> Result<Value, EvalError> {
+        let (base, saved) = (self.registers.len(), env.clone());
+        let res = self.execute(&translate(bytecode), env);
+        // エラーで中断したときは、実行中に確保したレジスタを捨て、開始したスコープを終了する
+        if res.is_err() {
+            self.registers.truncate(base);
+            *env = saved;
+        }
+        res
+    }
+
+    /// オペランドの値を複製して読む
+    fn read(&self, code: &RegisterCode, base: usize, operand: Operand) -> Value {
+        match operand {
+            Operand::Reg(reg) => self.registers[base + reg as usize].clone(),
+            Operand::Const(index) => code.constants[index as usize].clone(),
+        }
+    }
+
+    /// オペランドの値を取り出す。レジスタの値は、以降の命令から読まれないので複製しない
+    fn take(&mut self, code: &RegisterCode, base: usize, operand: Operand) -> Value {
+        match operand {
+            Operand::Reg(reg) => {
+                std::mem::replace(&mut self.registers[base + reg as usize], Value::Nil)
+            }
+            Operand::Const(index) => code.constants[index as usize].clone(),
+        }
+    }
+
+    /// `start` から `len` 個のレジスタの値を取り出す
+    fn take_range(&mut self, start: usize, len: usize) -> Vec<Value> {
+        self.registers[start..start + len]
+            .iter_mut()
+            .map(|value| std::mem::replace(value, Value::Nil))
+            .collect()
+    }
+
+    fn execute(&mut self, code: &RegisterCode, env: &mut Environment) -> Result<Value, EvalError> {
+        let (base, mut pc) = (self.registers.len(), 0);
+        self.registers.resize(base + code.registers, Value::Nil);
+        loop {
+            let op = code.code[pc];
+            let span = code.spans[pc];
+            pc += 1;
+            if !limits::step() {
+                return Err(limits::exhausted(span));
+            }
+            match op {
+                Op::Move { dst, src } => {
+                    self.registers[base + dst as usize] = self.read(code, base, src);
+                }
+                Op::Load { dst, name } => {
+                    let value = env.get(name).ok_or_else(|| EvalError::UnknownIdentifier {
+                        name: name.to_string(),
+                        span,
+                    })?;
+                    self.registers[base + dst as usize] = value;
+                }
+                Op::Define { name, src } => env.define(name, self.read(code, base, src)),
+                Op::Assign { name, src } => {
+                    if !env.assign(name, self.read(code, base, src)) {
+                        return Err(EvalError::UnknownIdentifier {
+                            name: name.to_string(),
+                            span,
+                        });
+                    }
+                }
+                Op::ExpectNumber(src) => {
+                    expect_number(self.read(code, base, src), span)?;
+                }
+                Op::ExpectInteger(src) => {
+                    expect_integer(self.read(code, base, src), span)?;
+                }
+                Op::ExpectOperand { name, src } => {
+                    if env.get(name).is_none() {
+                        expect_number(self.read(code, base, src), span)?;
+                    }
+                }
+                Op::ExpectKey { dst, src } => {
+                    let key = self.take(code, base, src);
+                    self.registers[base + dst as usize] = expect_key(key, span)?;
+                }
+                Op::Binary { op, dst, lhs, rhs } => {
+                    let rhs = self.take(code, base, rhs);
+                    let lhs = self.take(code, base, lhs);
+                    let value = match op {
+                        BinOp::Eq | BinOp::Ne => {
+                            Value::Bool(values_equal(&lhs, &rhs) == (op == BinOp::Eq))
+                        }
+                        _ => binary(op, lhs, rhs, span)?,
+                    };
+                    self.registers[base + dst as usize] = value;
+                }
+                Op::Neg { dst, src } => {
+                    let value = match self.take(code, base, src) {
+                        Value::I64(n) => n
+                            .checked_neg()
+                            .map(Value::I64)
+                            .ok_or(EvalError::IntegerOverflow { span })?,
+                        Value::F64(n) => Value::F64(-n),
+                        _ => unreachable!("ExpectNumber precedes Neg"),
+                    };
+                    self.registers[base + dst as usize] = value;
+                }
+                Op::Not { dst, src } => {
+                    let value = self.take(code, base, src);
+                    self.registers[base + dst as usize] = Value::Bool(!value.is_truthy());
+                }
+                Op::Truthy { dst, src } => {
+                    let value = self.take(code, base, src);
+                    self.registers[base + dst as usize] = Value::Bool(value.is_truthy());
+                }
+                Op::Jump(target) => pc = target as usize,
+                Op::JumpIfFalse { cond, target } => {
+                    if !self.take(code, base, cond).is_truthy() {
+                        pc = target as usize;
+                    }
+                }
+                Op::Operator {
+                    name,
+                    base: first,
+                    argc,
+                } => {
+                    let args = self.take_range(base + first as usize, argc as usize);
+                    let value = match env.get(name) {
+                        // 同じ名前の変数があれば、組み込みの演算子の代わりに呼び出す
+                        Some(Value::Fn(function)) => {
+                            self.call(&function, name.as_str(), args, span)?
+                        }
+                        Some(Value::NativeFn(function)) => function.call(&args, span)?,
+                        Some(_) => return Err(EvalError::NotAFunction { span }),
+                        None => arithmetic(name.as_str(), span, span, &args)?,
+                    };
+                    self.registers[base + first as usize] = value;
+                }
+                Op::ExpectFunction(callee) => {
+                    let callee = &self.registers[base + callee as usize];
+                    if !matches!(callee, Value::Fn(_) | Value::NativeFn(_)) {
+                        return Err(EvalError::NotAFunction { span });
+                    }
+                }
+                Op::Call { name, callee, argc } => {
+                    let at = base + callee as usize;
+                    let args = self.take_range(at + 1, argc as usize);
+                    let name = name.map_or("<fn>", |name| name.as_str());
+                    let value = match std::mem::replace(&mut self.registers[at], Value::Nil) {
+                        Value::Fn(function) => self.call(&function, name, args, span)?,
+                        Value::NativeFn(function) => function.call(&args, span)?,
+                        _ => unreachable!("ExpectFunction precedes Call"),
+                    };
+                    self.registers[at] = value;
+                }
+                Op::Closure { dst, index } => {
+                    let prototype = &code.functions[index as usize];
+                    self.registers[base + dst as usize] = Value::function(Function {
+                        params: prototype.params.clone(),
+                        body: FunctionBody::Compiled(prototype.code.clone()),
+                        env: env.clone(),
+                        span: prototype.span,
+                    });
+                }
+                Op::PushScope => env.push_scope(),
+                Op::PopScope => env.pop_scope(),
+                Op::ForNext { counter, target } => {
+                    let at = base + counter as usize;
+                    let (Value::I64(i), Value::I64(end)) =
+                        (&self.registers[at], &self.registers[at + 1])
+                    else {
+                        unreachable!("ExpectInteger precedes ForNext");
+                    };
+                    let (i, end) = (*i, *end);
+                    if i < end {
+                        self.registers[at] = Value::I64(i + 1);
+                        self.registers[at + 2] = Value::I64(i);
+                    } else {
+                        pc = target as usize;
+                    }
+                }
+                Op::OutsideLoop(keyword) => return Err(EvalError::OutsideLoop { keyword, span }),
+                Op::OutsideFunction => return Err(EvalError::OutsideFunction { span }),
+                Op::NoMatch(src) => {
+                    let value = self.read(code, base, src).to_string();
+                    return Err(EvalError::NoMatch { value, span });
+                }
+                Op::List { base: first, len } => {
+                    let items = self.take_range(base + first as usize, len as usize);
+                    let list = Value::List(items.into());
+                    limits::allocate_value(&list, span)?;
+                    self.registers[base + first as usize] = list;
+                }
+                Op::Array { base: first, len } => {
+                    let items = self.take_range(base + first as usize, len as usize);
+                    let array = Value::array(items);
+                    limits::allocate_value(&array, span)?;
+                    self.registers[base + first as usize] = array;
+                }
+                Op::Map { base: first, len } => {
+                    let items = self.take_range(base + first as usize, 2 * len as usize);
+                    let mut entries = HashMap::with_capacity(len as usize);
+                    let mut items = items.into_iter();
+                    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                        entries.insert(key, value);
+                    }
+                    let map = Value::map(entries);
+                    limits::allocate_value(&map, span)?;
+                    self.registers[base + first as usize] = map;
+                }
+                Op::Import { dst, index } => {
+                    let Value::Str(path) = &code.constants[index as usize] else {
+                        unreachable!("Import refers to a string constant");
+                    };
+                    self.registers[base + dst as usize] = module::import(path, span)?;
+                }
+                Op::Index { target, index } => {
+                    let index = self.take(code, base, index);
+                    let at = base + target as usize;
+                    let value = get_index(&self.registers[at], &index, span)?;
+                    self.registers[at] = value;
+                }
+                Op::SetIndex(first) => {
+                    let at = base + first as usize;
+                    let [target, index, value] = <[Value; 3]>::try_from(self.take_range(at, 3))
+                        .expect("took three registers");
+                    set_index(&target, &index, value.clone(), span)?;
+                    self.registers[at] = value;
+                }
+                Op::Return(src) => {
+                    let value = self.take(code, base, src);
+                    self.registers.truncate(base);
+                    return Ok(value);
+                }
+            }
+        }
+    }
+
+    /// 関数を呼び出す
+    ///
+    /// コンパイル済みの関数はこのレジスタマシンで、それ以外は木構造の評価器で実行する。
+    fn call(
+        &mut self,
+        function: &Function,
+        name: &str,
+        args: Vec<Value>,
+        span: Span,
+    ) -> Result<Value, EvalError> {
+        match &function.body {
+            FunctionBody::Compiled(bytecode) => {
+                let _guard = CallGuard::enter(span)?;
+                let _profile = profile::enter(name, function.span);
+                let code = self
+                    .functions
+                    .entry(Rc::as_ptr(bytecode) as usize)
+                    .or_insert_with(|| (bytecode.clone(), Rc::new(translate(bytecode))))
+                    .1
+                    .clone();
+                let mut env = bind_arguments(function, name, args, span)?;
+                self.execute(&code, &mut env)
+            }
+            FunctionBody::Tree(_) => call(function, name, args, span),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{Expr, ExprKind};
+    use crate::bytecode::{compile, compile_program};
+    use crate::eval::lower;
+    use crate::infix::statements;
+    use crate::parser::source;
+    use crate::vm::Vm;
+    use crate::TokenTree;
+
+    /// 中置記法のプログラムを1つの命令列にコンパイルする
+    fn compile_statements(input: &str) -> Bytecode {
+        let statements = statements(input).unwrap();
+        compile(&Expr::new(
+            ExprKind::Block(statements),
+            Span::new(0, input.len()),
+        ))
+    }
+
+    #[test]
+    fn test_translate() {
+        let Ok(TokenTree::Tree(forms, _)) = source("(define x 1) (if (< x 2) (+ x 10) 0)") else {
+            unreachable!()
+        };
+        let exprs: Vec<_> = forms.iter().map(|form| lower(form).unwrap()).collect();
+        let code = translate(&compile_program(&exprs));
+        // 定数はオペランドに埋め込み、分岐の先では値をレジスタに置く
+        assert!(code.code.contains(&Op::Binary {
+            op: BinOp::Lt,
+            dst: 0,
+            lhs: Operand::Reg(0),
+            rhs: Operand::Const(1),
+        }));
+        assert!(matches!(
+            code.code.last(),
+            Some(Op::Return(Operand::Reg(0)))
+        ));
+        assert_eq!(code.registers, 3);
+        for op in &code.code {
+            if let Op::Jump(target) | Op::JumpIfFalse { target, .. } = op {
+                assert!((*target as usize) < code.code.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_matches_stack_vm() {
+        for input in [
+            "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }\nfib(15)",
+            "var s = 0\nfor i in 0..10 { if i == 3 { continue }; if i > 7 { break }; s = s + i }\ns",
+            "fn find(n) { for i in 0..10 { { var j = i * i; if j > n { return i } } }; -1 }\n\
+             find(20) * 100 + find(200)",
+            "var a = [1, [2, 3]]\na[1][0] = a[0] + 10\n[a, { \"k\": a[1] }, -(2.5)]",
+            "fn f(x) { match x { 1 => \"one\", n if n > 9 => n, _ => 0 } }\n[f(1), f(10), f(5)]",
+            "var x = 1\nx = x + true",
+            "1 / 0",
+            "[7 % 3, -7 % 3, 7.5 % 2]",
+            "1 % 0",
+            "var m = -9223372036854775807 - 1\nm % -1",
+            "(fn(a, b) { a - b })(10, 3, 1)",
+            "match 3 { 1 => 1 }",
+            "var a = [1]\na[5] = 0",
+        ] {
+            let bytecode = compile_statements(input);
+            let expected = Vm::new().run(&bytecode, &mut Environment::new());
+            let actual = RegisterVm::new().run(&bytecode, &mut Environment::new());
+            assert_eq!(actual, expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_errors_restore_env() {
+        let mut env = Environment::new();
+        let mut vm = RegisterVm::new();
+        let err = vm.run(
+            &compile_statements("var x = 1\n{ var y = 2; y + nil }"),
+            &mut env,
+        );
+        assert!(err.is_err());
+        assert!(vm.registers.is_empty());
+        assert_eq!(env.get("y"), None);
+    }
+}
@@ -0,0 +1,571 @@
+//! 式を対話的に読み込んで解析結果を表示するREPL
+//!
+//! `:` で始まる行はコマンドとして扱う。使えるコマンドは `:help` で表示する。
+//! [`Repl::with_history`] で履歴のファイルを指定すると、そのファイルから前回までの入力を読み込み、
+//! 入力した行をそのファイルの末尾に書き足す。
+//! [`Repl::run_editing`] では、上下のキーで読み込んだ履歴と今回の入力を呼び出せる。
+//! [`Repl::with_color`] で色を付けると、解析した入力を色付きで表示し直してから結果を表示する。
+//! [`Repl::run_editing`] は端末のキーを1つずつ読み、Tab キーで名前を補完しながら行を編集させる。
+
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::ast::{Expr, TokenTree};
+use crate::diagnostics::Diagnostic;
+use crate::dump::to_sexpr;
+use crate::eval::{lower, Value};
+use crate::float_format::{float_format, set_float_format, FloatFormat};
+use crate::highlight::highlight;
+use crate::interpreter::Interpreter;
+use crate::lexer::is_ident_continue;
+use crate::line_editor::read_line;
+use crate::parser::{source, Expected, ParseError};
+use crate::source_map::SourceMap;
+use crate::typecheck::check;
+
+/// 1行目の入力を促すプロンプト
+const PROMPT: &str = "> ";
+/// 括弧が閉じていないときに続きの入力を促すプロンプト
+const CONTINUATION_PROMPT: &str = "... ";
+/// ホームディレクトリに置く履歴のファイルの名前
+const HISTORY_FILE: &str = ".ruscal_history";
+
+/// `:help` で表示するコマンドの一覧
+const HELP: &str = "\
+:help          show this message
+:env           list the variables defined by :load
+:type <expr>   show the type of an expression
+:ast <expr>    show the abstract syntax tree of an expression
+:load <file>   evaluate a file and keep its definitions
+:complete <s>  list the names that complete <s>
+:float [<opt>] show or change how floats are printed:
+               point on|off, precision <n>|auto, exponents <upper> <lower>, reset
+:quit          leave the REPL";
+
+/// REPLを実行する関数
+///
+/// 履歴を残さない [`Repl`] で [`Repl::run`] を呼ぶ。
+///
+/// # 引数
+/// * `input` - 式を読み込む入力
+/// * `output` - プロンプトと結果を書き出す出力
+///
+/// # 戻り値
+/// * `io::Result<()>` - 入出力に失敗した場合はエラーを返す
+pub fn run(input: impl BufRead, output: impl Write) -> io::Result<()> {
+    Repl::new().run(input, output)
+}
+
+/// 既定の履歴のファイル `~/.ruscal_history` のパス
+///
+/// # 戻り値
+/// * `Option<PathBuf>` - 環境変数 `HOME` が無ければ `None`
+pub fn history_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(Path::new(&home).join(HISTORY_FILE))
+}
+
+/// `:load` で読み込んだ定義を保持するREPLのセッション
+#[derive(Debug, Default)]
+pub struct Repl {
+    interpreter: Interpreter,
+    history: Option<PathBuf>,
+    /// 古いものから順に並んだ、これまでの入力
+    entries: Vec<String>,
+    color: bool,
+}
+
+impl Repl {
+    /// 標準関数だけを定義し、履歴を残さないセッションを作る
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `path` のファイルの各行を前回までの入力として読み込み、入力した行をその末尾に書き足す
+    ///
+    /// ファイルが無いか読めなければ、前回までの入力は無いものとする。
+    pub fn with_history(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        if let Ok(text) = std::fs::read_to_string(&path) {
+            self.entries = text
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        self.history = Some(path);
+        self
+    }
+
+    /// `color` が `true` なら、入力と結果とエラーに端末で表示する色を付ける
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// 入力が終わるか `:quit` を読むまで、1式ずつ読み込んで解析結果の `TokenTree` を表示する
+    ///
+    /// 行末で括弧や文字列が閉じていない場合は、次の行を続きとして読み込む。
+    ///
+    /// # 戻り値
+    /// * `io::Result<()>` - 入出力に失敗した場合はエラーを返す。履歴を書けなくても続ける
+    pub fn run(&mut self, mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+        self.run_lines(output, |_, prompt, output| {
+            output.write_all(prompt.as_bytes())?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(None);
+            }
+            Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()))
+        })
+    }
+
+    /// [`run`](Self::run) と同じく評価するが、行をキーごとに読んで編集させる
+    ///
+    /// Tab キーを押すと、カーソルの手前の名前を [`complete`](Self::complete) の候補で補完する。
+    /// 上下のキーを押すと、これまでの入力を新しいものから順に呼び出す。
+    /// 端末から読むときは、[`RawMode`](crate::line_editor::RawMode) で端末がキーを1つずつ渡すように
+    /// しておく。
+    ///
+    /// # 戻り値
+    /// * `io::Result<()>` - 入出力に失敗した場合はエラーを返す。履歴を書けなくても続ける
+    pub fn run_editing(&mut self, mut input: impl Read, output: impl Write) -> io::Result<()> {
+        self.run_lines(output, |repl, prompt, output| {
+            read_line(&mut input, output, prompt, &repl.entries, &|line, pos| {
+                repl.complete(line, pos)
+            })
+        })
+    }
+
+    /// `next_line` がプロンプトを表示して読む行を、入力が終わるか `:quit` を読むまで評価する
+    fn run_lines<W: Write>(
+        &mut self,
+        mut output: W,
+        mut next_line: impl FnMut(&Self, &str, &mut W) -> io::Result<Option<String>>,
+    ) -> io::Result<()> {
+        let mut buf = String::new();
+        loop {
+            let prompt = if buf.is_empty() {
+                PROMPT
+            } else {
+                CONTINUATION_PROMPT
+            };
+            let Some(line) = next_line(self, prompt, &mut output)? else {
+                return Ok(());
+            };
+            let line = line.as_str();
+            if buf.is_empty() {
+                if let Some(command) = line.trim_start().strip_prefix(':') {
+                    self.record(line);
+                    if !self.command(command, &mut output)? {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            }
+            // 行コメントが次の行まで続かないよう、改行でつなぐ
+            if !buf.is_empty() {
+                buf.push('\n');
+            }
+            buf.push_str(line);
+
+            // 結果を入力から切り離しておき、次の入力のためにバッファを空にする
+            let result = match source(&buf) {
+                Ok(tree) => Ok(tree.to_owned()),
+                Err(e) if is_incomplete(&buf, &e) => continue,
+                Err(e) => Err(e),
+            };
+            self.record(&buf);
+            match result {
+                Ok(tree) if self.color => writeln!(output, "{}\n{tree:?}", highlight(&buf))?,
+                Ok(tree) => writeln!(output, "{tree:?}")?,
+                Err(e) if self.color => {
+                    let map = SourceMap::new(&buf);
+                    write!(output, "{}", Diagnostic::from(&e).render_colored(&map))?;
+                }
+                Err(e) => writeln!(output, "error: {e}")?,
+            }
+            buf.clear();
+        }
+    }
+
+    /// 入力の `pos` バイト目の手前にある識別子を補完する候補を探す
+    ///
+    /// 候補は標準関数と `:load` で定義した名前のうち、識別子の書きかけの部分で始まるものを名前の順に並べる。
+    ///
+    /// ```
+    /// use ruscal_b::repl::Repl;
+    ///
+    /// let repl = Repl::new();
+    /// assert_eq!(repl.complete("(sq", 3), (1, vec!["sqrt".to_string()]));
+    /// ```
+    ///
+    /// # 戻り値
+    /// * `(usize, Vec<String>)` - (置き換える部分の先頭のバイト位置, 候補のリスト)のタプル
+    pub fn complete(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        // 行の外や文字の途中を指す位置は、その手前の文字の境界にずらす
+        let mut pos = pos.min(line.len());
+        while !line.is_char_boundary(pos) {
+            pos -= 1;
+        }
+        let before = line.get(..pos).unwrap_or_default();
+        let start = before
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| is_ident_continue(c) || c == '?')
+            .last()
+            .map_or(pos, |(i, _)| i);
+        let prefix = before.get(start..).unwrap_or_default();
+        if prefix.is_empty() {
+            return (pos, vec![]);
+        }
+        let candidates = self
+            .interpreter
+            .env()
+            .bindings()
+            .into_iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| name.starts_with(prefix))
+            .map(str::to_string)
+            .collect();
+        (start, candidates)
+    }
+
+    /// `:` に続くコマンドを実行する
+    ///
+    /// # 戻り値
+    /// * `io::Result<bool>` - `:quit` なら `false`
+    fn command(&mut self, command: &str, output: &mut impl Write) -> io::Result<bool> {
+        let (name, arg) = command
+            .split_once(char::is_whitespace)
+            .unwrap_or((command, ""));
+        let arg = arg.trim();
+        match name {
+            "help" => writeln!(output, "{HELP}")?,
+            "quit" | "q" => return Ok(false),
+            "env" => {
+                // 標準関数と組み込みのモジュールは毎回同じなので、`:load` で定義した変数だけを表示する
+                for (name, value) in self.interpreter.env().bindings() {
+                    let builtin = match &value {
+                        Value::NativeFn(_) => true,
+                        Value::Module(module) => module.path.as_os_str().is_empty(),
+                        _ => false,
+                    };
+                    if !builtin {
+                        writeln!(output, "{name} = {value}")?;
+                    }
+                }
+            }
+            "type" => match type_of(arg) {
+                Ok(ty) => writeln!(output, "{ty}")?,
+                Err(e) => self.error(output, e)?,
+            },
+            "ast" => match ast_of(arg) {
+                Ok(exprs) => exprs
+                    .iter()
+                    .try_for_each(|expr| writeln!(output, "{expr}"))?,
+                Err(e) => self.error(output, e)?,
+            },
+            "load" if arg.is_empty() => self.error(output, ":load expects a file")?,
+            "load" => match self.interpreter.eval_file(arg) {
+                Ok(value) => writeln!(output, "{value}")?,
+                Err(e) => self.error(output, e)?,
+            },
+            "float" => match float_setting(float_format(), arg) {
+                Ok(format) => {
+                    set_float_format(format);
+                    let precision = format
+                        .precision
+                        .map_or("auto".to_string(), |n| n.to_string());
+                    writeln!(
+                        output,
+                        "point {}, precision {precision}, exponents {} {}",
+                        if format.point { "on" } else { "off" },
+                        format.upper_exponent,
+                        format.lower_exponent
+                    )?;
+                }
+                Err(e) => self.error(output, e)?,
+            },
+            "complete" => {
+                let (_, candidates) = self.complete(arg, arg.len());
+                writeln!(output, "{}", candidates.join(" "))?;
+            }
+            _ => self.error(output, format!("unknown command `:{name}` (try :help)"))?,
+        }
+        Ok(true)
+    }
+
+    /// コマンドの失敗を、色を付けるなら赤い `error` に続けて書き出す
+    fn error(&self, output: &mut impl Write, message: impl std::fmt::Display) -> io::Result<()> {
+        if self.color {
+            writeln!(output, "\x1b[1;31merror\x1b[0m: {message}")
+        } else {
+            writeln!(output, "error: {message}")
+        }
+    }
+
+    /// 入力を履歴に加え、履歴のファイルを指定していれば書き足す
+    fn record(&mut self, entry: &str) {
+        self.entries.push(entry.to_string());
+        let Some(path) = &self.history else {
+            return;
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path);
+        // 履歴を書けなくても、入力の評価は続けられる
+        let _ = file.and_then(|mut file| writeln!(file, "{entry}"));
+    }
+}
+
+/// `:float` の引数に従って書式を変える。引数が空なら書式をそのまま返す
+fn float_setting(mut format: FloatFormat, arg: &str) -> Result<FloatFormat, String> {
+    let words: Vec<_> = arg.split_whitespace().collect();
+    fn number<T: std::str::FromStr>(word: &str) -> Result<T, String> {
+        word.parse()
+            .map_err(|_| format!("expected a number, found `{word}`"))
+    }
+    match words.as_slice() {
+        [] => {}
+        ["reset"] => format = FloatFormat::default(),
+        ["point", "on"] => format.point = true,
+        ["point", "off"] => format.point = false,
+        ["precision", "auto"] => format.precision = None,
+        ["precision", n] => format.precision = Some(number(n)?),
+        ["exponents", upper, lower] => {
+            format.upper_exponent = number(upper)?;
+            format.lower_exponent = number(lower)?;
+        }
+        _ => return Err(format!("unknown float setting `{arg}` (try :help)")),
+    }
+    Ok(format)
+}
+
+/// 式を解析して、最上位の式の並びにする
+fn lower_all(input: &str) -> Result<Vec<Expr>, String> {
+    let TokenTree::Tree(forms, _) = source(input).map_err(|e| e.to_string())? else {
+        unreachable!("source() always returns a tree");
+    };
+    forms
+        .iter()
+        .map(|form| lower(form).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// `:type` で表示する、最後の式の型
+fn type_of(input: &str) -> Result<String, String> {
+    let exprs = lower_all(input)?;
+    match check(&exprs) {
+        Ok(ty) => Ok(ty.to_string()),
+        Err(errors) => Err(errors[0].to_string()),
+    }
+}
+
+/// `:ast` で表示する、式ごとの1行のS式
+fn ast_of(input: &str) -> Result<Vec<String>, String> {
+    Ok(lower_all(input)?.iter().map(to_sexpr).collect())
+}
+
+/// 入力の続きを読めば解析が成功する可能性があるかどうかを判定する関数
+///
+/// # 引数
+/// * `input` - 解析した文字列
+/// * `err` - 解析時のエラー
+///
+/// # 戻り値
+/// * `bool` - 閉じられていない `(` や文字列リテラルで終わっている場合は `true`
+fn is_incomplete(input: &str, err: &ParseError) -> bool {
+    match err {
+        ParseError::UnbalancedParen { span } => input.get(span.start..span.end) == Some("("),
+        ParseError::Unexpected {
+            expected: Expected::Quote,
+            found: None,
+            ..
+        } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_str(input: &str) -> String {
+        let mut output = vec![];
+        run(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(
+            run_str("a\n"),
+            "> Tree([Token(Ident(\"a\"), Span { start: 0, end: 1 })], Span { start: 0, end: 1 })\n> \n"
+        );
+    }
+
+    #[test]
+    fn test_continuation() {
+        let output = run_str("(a\nb)\n");
+        assert!(output.starts_with("> ... Tree("), "{output}");
+        assert!(output.contains("Ident(\"b\")"), "{output}");
+        let output = run_str("(a // comment\r\nb)\n");
+        assert!(output.contains("Ident(\"b\")"), "{output}");
+    }
+
+    #[test]
+    fn test_error() {
+        assert_eq!(
+            run_str("a)\n"),
+            "> error: unbalanced parenthesis at byte 1\n> \n"
+        );
+    }
+
+    #[test]
+    fn test_commands() {
+        let output = run_str(":type (+ 1 2.5)\n:type (+ 1 \"a\")\n:ast (if x (f 1))\n:nope\n");
+        assert_eq!(
+            output,
+            "> f64\n\
+             > error: expected a number, found str at byte 5\n\
+             > (if x (call f 1))\n\
+             > error: unknown command `:nope` (try :help)\n\
+             > \n"
+        );
+        assert!(run_str(":help\n").contains(":load <file>"));
+        // `:quit` の後の入力は読まない
+        assert_eq!(run_str(":quit\na\n"), "> ");
+    }
+
+    #[test]
+    fn test_float_command() {
+        let output = run_str(":float\n:float precision 2\n:float point off\n:float precision x\n");
+        assert_eq!(
+            output,
+            "> point on, precision auto, exponents 16 -5\n\
+             > point on, precision 2, exponents 16 -5\n\
+             > point off, precision 2, exponents 16 -5\n\
+             > error: expected a number, found `x`\n\
+             > \n"
+        );
+        // 書式はスレッドごとなので、同じスレッドで表示する値に効く
+        assert_eq!(Value::F64(1.0 / 3.0).to_string(), "0.33");
+        assert_eq!(Value::F64(2.0).to_string(), "2.00");
+        run_str(":float precision auto\n");
+        assert_eq!(Value::F64(2.0).to_string(), "2");
+        run_str(":float exponents 3 -3\n:float reset\n");
+        assert_eq!(Value::F64(2.0).to_string(), "2.0");
+        assert!(run_str(":float sci\n").contains("unknown float setting"));
+    }
+
+    #[test]
+    fn test_load_and_env() {
+        let dir = std::env::temp_dir().join(format!("ruscal-repl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("defs.rscl");
+        std::fs::write(&file, "(define b 2) (define a \"x\")").unwrap();
+        let history = dir.join("history");
+
+        let input = format!(":load {}\n:env\n(a\nb)\n:load\n", file.display());
+        let mut output = vec![];
+        Repl::new()
+            .with_history(&history)
+            .run(input.as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "> \"x\"\n> a = \"x\"\nb = 2\n> ... {:?}\n> error: :load expects a file\n> \n",
+                source("(a\nb)").unwrap()
+            )
+        );
+        assert_eq!(
+            std::fs::read_to_string(&history).unwrap(),
+            format!(":load {}\n:env\n(a\nb)\n:load\n", file.display())
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_history_recall() {
+        let dir = std::env::temp_dir().join(format!("ruscal-recall-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let history = dir.join("history");
+        std::fs::write(&history, "(first 1)\n\n(second 2)\n").unwrap();
+
+        // 前回までの入力を上のキーで呼び出し、今回の入力もその後に呼び出せる
+        let mut output = vec![];
+        Repl::new()
+            .with_history(&history)
+            .run_editing(
+                "\x1b[A\x1b[A\r(third)\r\x1b[A\x1b[A\r".as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("Ident(\"first\")").count(), 2, "{output:?}");
+        assert!(output.contains("Ident(\"third\")"), "{output:?}");
+        assert_eq!(
+            std::fs::read_to_string(&history).unwrap(),
+            "(first 1)\n\n(second 2)\n(first 1)\n(third)\n(first 1)\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.complete("(+ 1 (sq", 8), (6, vec!["sqrt".to_string()]));
+        assert_eq!(repl.complete("(nu x)", 3), (1, vec!["null?".to_string()]));
+        assert_eq!(repl.complete("(sq", 1), (1, vec![]));
+        assert_eq!(repl.complete("nope", 4), (0, vec![]));
+        // 文字の途中や行の外を指す位置は、手前の文字の境界とみなす
+        assert_eq!(repl.complete("(sqé", 4), (1, vec!["sqrt".to_string()]));
+        assert_eq!(repl.complete("(sq", 99), (1, vec!["sqrt".to_string()]));
+        // `:load` で定義した名前も補完する
+        repl.interpreter.env_mut().define("square", Value::I64(1));
+        assert_eq!(repl.complete("sq", 2).1, ["sqrt", "square"]);
+        assert_eq!(run_str(":complete up\n"), "> upper\n> \n");
+    }
+
+    #[test]
+    fn test_tab_completion() {
+        let mut output = vec![];
+        Repl::new()
+            .run_editing("(sq\t 4)\r:complete upp\r".as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        // Tab キーで補完した行を評価し、その後のコマンドも読む
+        assert!(output.contains("Ident(\"sqrt\")"), "{output:?}");
+        assert!(output.contains("\nupper\n"), "{output:?}");
+        assert!(output.ends_with("> \r\n"), "{output:?}");
+    }
+
+    #[test]
+    fn test_color() {
+        let run_colored = |input: &str| {
+            let mut output = vec![];
+            Repl::new()
+                .with_color(true)
+                .run(input.as_bytes(), &mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let output = run_colored("(f 1)\n");
+        assert!(
+            output.starts_with("> (f \x1b[36m1\x1b[0m)\nTree("),
+            "{output:?}"
+        );
+        let output = run_colored("a)\n:nope\n");
+        assert!(
+            output.starts_with("> \x1b[1;31merror[E0001]\x1b[0m"),
+            "{output:?}"
+        );
+        assert!(
+            output.contains("> \x1b[1;31merror\x1b[0m: unknown command"),
+            "{output:?}"
+        );
+    }
+}
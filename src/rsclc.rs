@@ -0,0 +1,591 @@
+//! コンパイル済みの命令列を保存する `.rsclc` ファイル形式
+//!
+//! ファイルはマジックナンバー `RSCL` とバージョン番号で始まり、識別子の文字列表、
+//! 最上位の命令列が続く。数値はすべてリトルエンディアンで書き出す。
+//!
+//! ```text
+//! file      = "RSCL" version:u16 symbols:table bytecode
+//! table     = count:u32 (len:u32 utf8-bytes)*
+//! bytecode  = count:u32 constant* count:u32 prototype* count:u32 (instruction span)* lines
+//! prototype = count:u32 symbol:u32* span bytecode
+//! span      = start:u64 end:u64
+//! lines     = count:u32 (pc:u32 line:u32 column:u32)*
+//! ```
+//!
+//! `lines` は [`LineTable`] で、バージョン2で加えた。バージョン1のファイルも読み込めるが、
+//! 読み込んだ命令列の対応表は空になる。
+//!
+//! 識別子は文字列表の添字で表すので、読み込んだ命令列の [`Symbol`] は
+//! 書き出したプロセスとは異なる値になりうる。
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use crate::ast::Span;
+use crate::bytecode::{Bytecode, Instruction, Prototype};
+use crate::debug_info::{LineRow, LineTable};
+use crate::eval::Value;
+use crate::intern::Symbol;
+use crate::source_map::LineCol;
+
+/// ファイルの先頭に置くマジックナンバー
+pub const MAGIC: &[u8; 4] = b"RSCL";
+/// ファイル形式のバージョン。互換性の無い変更をしたら上げる
+pub const VERSION: u16 = 2;
+
+impl Bytecode {
+    /// 命令列を `.rsclc` 形式で書き出す
+    ///
+    /// # 引数
+    /// * `writer` - 書き出し先
+    ///
+    /// # 戻り値
+    /// * `io::Result<()>` - 書き出しに失敗した場合はエラーを返す
+    ///   - 定数表に関数があれば `InvalidInput` のエラーを返す
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut symbols = SymbolTable::default();
+        let mut body = vec![];
+        write_bytecode(self, &mut symbols, &mut body)?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        write_u32(writer, symbols.names.len())?;
+        for name in &symbols.names {
+            write_u32(writer, name.len())?;
+            writer.write_all(name.as_bytes())?;
+        }
+        writer.write_all(&body)
+    }
+
+    /// `.rsclc` 形式の命令列を読み込む
+    ///
+    /// # 引数
+    /// * `reader` - 読み込み元
+    ///
+    /// # 戻り値
+    /// * `io::Result<Bytecode>` - 読み込んだ命令列
+    ///   - マジックナンバーやバージョンが異なる場合や内容が壊れている場合は `InvalidData` のエラーを返す
+    pub fn read(reader: &mut impl Read) -> io::Result<Bytecode> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a compiled ruscal file"));
+        }
+        let version = u16::from_le_bytes(read_array(reader)?);
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid(&format!(
+                "unsupported file version {version} (expected {VERSION})"
+            )));
+        }
+        let symbols = (0..read_u32(reader)?)
+            .map(|_| {
+                let mut name = vec![0; read_u32(reader)? as usize];
+                reader.read_exact(&mut name)?;
+                let name = String::from_utf8(name).map_err(|_| invalid("invalid identifier"))?;
+                Ok(Symbol::intern(&name))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        read_bytecode(reader, &symbols, version)
+    }
+}
+
+/// 書き出す識別子と文字列表の添字の対応
+#[derive(Default)]
+struct SymbolTable {
+    indices: HashMap<Symbol, u32>,
+    names: Vec<&'static str>,
+}
+
+impl SymbolTable {
+    fn index(&mut self, symbol: Symbol) -> u32 {
+        *self.indices.entry(symbol).or_insert_with(|| {
+            self.names.push(symbol.as_str());
+            self.names.len() as u32 - 1
+        })
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_u32(writer: &mut impl Write, n: usize) -> io::Result<()> {
+    let n = u32::try_from(n).map_err(|_| io::Error::other("too many items to write"))?;
+    writer.write_all(&n.to_le_bytes())
+}
+
+fn write_span(writer: &mut impl Write, span: Span) -> io::Result<()> {
+    writer.write_all(&(span.start as u64).to_le_bytes())?;
+    writer.write_all(&(span.end as u64).to_le_bytes())
+}
+
+fn write_bytecode(
+    bytecode: &Bytecode,
+    symbols: &mut SymbolTable,
+    w: &mut Vec<u8>,
+) -> io::Result<()> {
+    write_u32(w, bytecode.constants.len())?;
+    for constant in &bytecode.constants {
+        write_constant(constant, w)?;
+    }
+    write_u32(w, bytecode.functions.len())?;
+    for function in &bytecode.functions {
+        write_u32(w, function.params.len())?;
+        for param in &function.params {
+            w.extend(symbols.index(*param).to_le_bytes());
+        }
+        write_span(w, function.span)?;
+        write_bytecode(&function.code, symbols, w)?;
+    }
+    write_u32(w, bytecode.code.len())?;
+    for (instruction, span) in bytecode.code.iter().zip(&bytecode.spans) {
+        write_instruction(*instruction, symbols, w);
+        write_span(w, *span)?;
+    }
+    write_u32(w, bytecode.lines.rows.len())?;
+    for row in &bytecode.lines.rows {
+        w.extend(row.pc.to_le_bytes());
+        write_u32(w, row.start.line)?;
+        write_u32(w, row.start.column)?;
+    }
+    Ok(())
+}
+
+/// 命令を命令コードと被演算子として書き出す
+fn write_constant(constant: &Value, w: &mut Vec<u8>) -> io::Result<()> {
+    match constant {
+        Value::I64(n) => {
+            w.push(0);
+            w.extend(n.to_le_bytes());
+        }
+        Value::F64(n) => {
+            w.push(1);
+            w.extend(n.to_le_bytes());
+        }
+        Value::Bool(b) => w.extend([2, *b as u8]),
+        Value::Nil => w.push(3),
+        Value::Str(s) => {
+            w.push(4);
+            write_u32(w, s.len())?;
+            w.extend(s.as_bytes());
+        }
+        Value::Symbol(name) => {
+            w.push(6);
+            write_u32(w, name.as_str().len())?;
+            w.extend(name.as_str().as_bytes());
+        }
+        Value::List(items) => {
+            w.push(5);
+            write_u32(w, items.len())?;
+            for item in items.iter() {
+                write_constant(item, w)?;
+            }
+        }
+        Value::Fn(_) | Value::NativeFn(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "functions cannot be stored in the constant pool",
+            ))
+        }
+        // 配列とマップは書き換えられるので、実行するたびに `Array` と `Map` 命令で作る
+        Value::Array(_) | Value::Map(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "arrays and maps cannot be stored in the constant pool",
+            ))
+        }
+        Value::Module(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "modules cannot be stored in the constant pool",
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn write_instruction(instruction: Instruction, symbols: &mut SymbolTable, w: &mut Vec<u8>) {
+    let mut symbol = |w: &mut Vec<u8>, s| w.extend(symbols.index(s).to_le_bytes());
+    match instruction {
+        Instruction::Constant(n) => {
+            w.push(0);
+            w.extend(n.to_le_bytes());
+        }
+        Instruction::Pop => w.push(1),
+        Instruction::Dup => w.push(2),
+        Instruction::Load(s) => {
+            w.push(3);
+            symbol(w, s);
+        }
+        Instruction::Define(s) => {
+            w.push(4);
+            symbol(w, s);
+        }
+        Instruction::Assign(s) => {
+            w.push(5);
+            symbol(w, s);
+        }
+        Instruction::ExpectNumber => w.push(6),
+        Instruction::ExpectInteger => w.push(7),
+        Instruction::ExpectOperand(s) => {
+            w.push(8);
+            symbol(w, s);
+        }
+        Instruction::Add => w.push(9),
+        Instruction::AddConstant(n) => {
+            w.push(44);
+            w.extend(n.to_le_bytes());
+        }
+        Instruction::Sub => w.push(10),
+        Instruction::Mul => w.push(11),
+        Instruction::Div => w.push(12),
+        Instruction::Rem => w.push(45),
+        Instruction::Lt => w.push(13),
+        Instruction::Le => w.push(14),
+        Instruction::Gt => w.push(15),
+        Instruction::Ge => w.push(16),
+        Instruction::Eq => w.push(17),
+        Instruction::Ne => w.push(18),
+        Instruction::Neg => w.push(19),
+        Instruction::Not => w.push(20),
+        Instruction::Truthy => w.push(21),
+        Instruction::Jump(n) => {
+            w.push(22);
+            w.extend(n.to_le_bytes());
+        }
+        Instruction::JumpIfFalse(n) => {
+            w.push(23);
+            w.extend(n.to_le_bytes());
+        }
+        Instruction::Operator { name, argc } => {
+            w.push(24);
+            symbol(w, name);
+            w.extend(argc.to_le_bytes());
+        }
+        Instruction::ExpectFunction(n) => {
+            w.push(25);
+            w.extend(n.to_le_bytes());
+        }
+        Instruction::Call { name, argc } => {
+            match name {
+                Some(name) => {
+                    w.push(26);
+                    symbol(w, name);
+                }
+                None => w.push(27),
+            }
+            w.extend(argc.to_le_bytes());
+        }
+        Instruction::Closure(n) => {
+            w.push(28);
+            w.extend(n.to_le_bytes());
+        }
+        Instruction::PushScope => w.push(29),
+        Instruction::PopScope => w.push(30),
+        Instruction::ForNext(n) => {
+            w.push(31);
+            w.extend(n.to_le_bytes());
+        }
+        Instruction::OutsideLoop(keyword) => w.extend([33, (keyword == "continue") as u8]),
+        Instruction::Return => w.push(34),
+        Instruction::OutsideFunction => w.push(36),
+        Instruction::NoMatch => w.push(37),
+        Instruction::Array(len) => {
+            w.push(38);
+            w.extend(len.to_le_bytes());
+        }
+        Instruction::Index => w.push(39),
+        Instruction::SetIndex => w.push(40),
+        Instruction::Map(len) => {
+            w.push(41);
+            w.extend(len.to_le_bytes());
+        }
+        Instruction::ExpectKey => w.push(42),
+        Instruction::Import(n) => {
+            w.push(43);
+            w.extend(n.to_le_bytes());
+        }
+        Instruction::List(len) => {
+            w.push(35);
+            w.extend(len.to_le_bytes());
+        }
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    Ok(read_array::<1>(reader)?[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_span(reader: &mut impl Read) -> io::Result<Span> {
+    let start = u64::from_le_bytes(read_array(reader)?);
+    let end = u64::from_le_bytes(read_array(reader)?);
+    match (usize::try_from(start), usize::try_from(end)) {
+        (Ok(start), Ok(end)) if start <= end => Ok(Span::new(start, end)),
+        _ => Err(invalid("invalid span")),
+    }
+}
+
+fn read_symbol(reader: &mut impl Read, symbols: &[Symbol]) -> io::Result<Symbol> {
+    let index = read_u32(reader)?;
+    symbols
+        .get(index as usize)
+        .copied()
+        .ok_or_else(|| invalid("identifier index out of range"))
+}
+
+fn read_bytecode(reader: &mut impl Read, symbols: &[Symbol], version: u16) -> io::Result<Bytecode> {
+    let mut bytecode = Bytecode::default();
+    for _ in 0..read_u32(reader)? {
+        bytecode.constants.push(read_constant(reader)?);
+    }
+    for _ in 0..read_u32(reader)? {
+        let params = (0..read_u32(reader)?)
+            .map(|_| read_symbol(reader, symbols))
+            .collect::<io::Result<_>>()?;
+        let span = read_span(reader)?;
+        let code = Rc::new(read_bytecode(reader, symbols, version)?);
+        bytecode
+            .functions
+            .push(Rc::new(Prototype { params, code, span }));
+    }
+    for _ in 0..read_u32(reader)? {
+        bytecode.code.push(read_instruction(reader, symbols)?);
+        bytecode.spans.push(read_span(reader)?);
+    }
+    if version >= 2 {
+        bytecode.lines = read_lines(reader)?;
+    }
+    validate(&bytecode)?;
+    Ok(bytecode)
+}
+
+fn read_lines(reader: &mut impl Read) -> io::Result<LineTable> {
+    let rows = (0..read_u32(reader)?)
+        .map(|_| {
+            let pc = read_u32(reader)?;
+            let line = read_u32(reader)? as usize;
+            let column = read_u32(reader)? as usize;
+            Ok(LineRow {
+                pc,
+                start: LineCol { line, column },
+            })
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(LineTable { rows })
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    // 壊れた長さで大きな領域を確保しないよう、読めた分だけ伸ばす
+    let len = read_u32(reader)? as u64;
+    let mut s = vec![];
+    reader.by_ref().take(len).read_to_end(&mut s)?;
+    if s.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(s).map_err(|_| invalid("invalid UTF-8 in constant"))
+}
+
+fn read_constant(reader: &mut impl Read) -> io::Result<Value> {
+    Ok(match read_u8(reader)? {
+        0 => Value::I64(i64::from_le_bytes(read_array(reader)?)),
+        1 => Value::F64(f64::from_le_bytes(read_array(reader)?)),
+        2 => Value::Bool(read_u8(reader)? != 0),
+        3 => Value::Nil,
+        4 => Value::Str(read_string(reader)?.into()),
+        6 => Value::Symbol(Symbol::intern(&read_string(reader)?)),
+        5 => Value::List(
+            (0..read_u32(reader)?)
+                .map(|_| read_constant(reader))
+                .collect::<io::Result<_>>()?,
+        ),
+        _ => return Err(invalid("unknown constant tag")),
+    })
+}
+
+fn read_instruction(reader: &mut impl Read, symbols: &[Symbol]) -> io::Result<Instruction> {
+    Ok(match read_u8(reader)? {
+        0 => Instruction::Constant(read_u32(reader)?),
+        1 => Instruction::Pop,
+        2 => Instruction::Dup,
+        3 => Instruction::Load(read_symbol(reader, symbols)?),
+        4 => Instruction::Define(read_symbol(reader, symbols)?),
+        5 => Instruction::Assign(read_symbol(reader, symbols)?),
+        6 => Instruction::ExpectNumber,
+        7 => Instruction::ExpectInteger,
+        8 => Instruction::ExpectOperand(read_symbol(reader, symbols)?),
+        9 => Instruction::Add,
+        10 => Instruction::Sub,
+        11 => Instruction::Mul,
+        12 => Instruction::Div,
+        13 => Instruction::Lt,
+        14 => Instruction::Le,
+        15 => Instruction::Gt,
+        16 => Instruction::Ge,
+        17 => Instruction::Eq,
+        18 => Instruction::Ne,
+        19 => Instruction::Neg,
+        20 => Instruction::Not,
+        21 => Instruction::Truthy,
+        22 => Instruction::Jump(read_u32(reader)?),
+        23 => Instruction::JumpIfFalse(read_u32(reader)?),
+        24 => Instruction::Operator {
+            name: read_symbol(reader, symbols)?,
+            argc: read_u32(reader)?,
+        },
+        25 => Instruction::ExpectFunction(read_u32(reader)?),
+        26 => Instruction::Call {
+            name: Some(read_symbol(reader, symbols)?),
+            argc: read_u32(reader)?,
+        },
+        27 => Instruction::Call {
+            name: None,
+            argc: read_u32(reader)?,
+        },
+        28 => Instruction::Closure(read_u32(reader)?),
+        29 => Instruction::PushScope,
+        30 => Instruction::PopScope,
+        31 => Instruction::ForNext(read_u32(reader)?),
+        // 32 は文字列を値として扱う前のエラーの命令で、今は使わない
+        33 => Instruction::OutsideLoop(match read_u8(reader)? {
+            0 => "break",
+            _ => "continue",
+        }),
+        34 => Instruction::Return,
+        35 => Instruction::List(read_u32(reader)?),
+        36 => Instruction::OutsideFunction,
+        37 => Instruction::NoMatch,
+        38 => Instruction::Array(read_u32(reader)?),
+        39 => Instruction::Index,
+        40 => Instruction::SetIndex,
+        41 => Instruction::Map(read_u32(reader)?),
+        42 => Instruction::ExpectKey,
+        43 => Instruction::Import(read_u32(reader)?),
+        44 => Instruction::AddConstant(read_u32(reader)?),
+        45 => Instruction::Rem,
+        _ => return Err(invalid("unknown instruction")),
+    })
+}
+
+/// 読み込んだ命令列の参照が範囲内にあることを確かめる
+///
+/// スタックの深さまでは検査しないので、手で作ったファイルは実行時に異常終了しうる。
+fn validate(bytecode: &Bytecode) -> io::Result<()> {
+    let len = bytecode.code.len() as u32;
+    let in_range = |instruction: &Instruction| match *instruction {
+        Instruction::Constant(n) | Instruction::AddConstant(n) => {
+            (n as usize) < bytecode.constants.len()
+        }
+        Instruction::Closure(n) => (n as usize) < bytecode.functions.len(),
+        Instruction::Jump(n) | Instruction::JumpIfFalse(n) | Instruction::ForNext(n) => n < len,
+        _ => true,
+    };
+    if !bytecode.code.iter().all(in_range) {
+        return Err(invalid("operand out of range"));
+    }
+    let rows = &bytecode.lines.rows;
+    if rows.last().is_some_and(|row| row.pc >= len) || rows.windows(2).any(|w| w[0].pc >= w[1].pc) {
+        return Err(invalid("line table out of order"));
+    }
+    if bytecode.code.last() != Some(&Instruction::Return) {
+        return Err(invalid("bytecode does not end with a return"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bytecode::compile;
+    use crate::env::Environment;
+    use crate::eval::lower;
+    use crate::parser::source;
+    use crate::vm::Vm;
+    use crate::TokenTree;
+
+    fn compile_str(input: &str) -> Bytecode {
+        let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+            panic!("failed to parse {input:?}");
+        };
+        compile(&lower(&forms[0]).unwrap())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytecode = compile_str(
+            "(let ((f (fn (n) (if (< n 2) n (+ (f (- n 1)) 0.5)))) (g (fn () (break)))) (f 3))",
+        );
+        let mut buf = vec![];
+        bytecode.write(&mut buf).unwrap();
+        assert!(buf.starts_with(b"RSCL\x02\x00"));
+        let read = Bytecode::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(read, bytecode);
+        assert_eq!(
+            Vm::new().run(&read, &mut Environment::new()),
+            Ok(Value::F64(2.0))
+        );
+
+        let mut bytecode = compile_str(r#""héllo\n""#);
+        bytecode.constants.push(Value::List(Rc::from([
+            Value::Str("a".into()),
+            Value::List(Rc::from([Value::I64(1)])),
+        ])));
+        let mut buf = vec![];
+        bytecode.write(&mut buf).unwrap();
+        assert_eq!(Bytecode::read(&mut buf.as_slice()).unwrap(), bytecode);
+    }
+
+    #[test]
+    fn test_lines() {
+        let input = "(define f (fn (n)\n  (* n 2)))\n(f 3)";
+        let mut bytecode = compile_str(input);
+        bytecode.attach_lines(input);
+        let mut buf = vec![];
+        bytecode.write(&mut buf).unwrap();
+        let read = Bytecode::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(read, bytecode);
+        assert!(!read.functions[0].code.lines.is_empty());
+
+        // バージョン1のファイルには対応表が無い
+        let mut buf = vec![];
+        compile_str("(+ 1 2)").write(&mut buf).unwrap();
+        buf[4] = 1;
+        buf.truncate(buf.len() - 4);
+        let read = Bytecode::read(&mut buf.as_slice()).unwrap();
+        assert!(read.lines.is_empty());
+        assert_eq!(
+            Vm::new().run(&read, &mut Environment::new()),
+            Ok(Value::I64(3))
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut buf = vec![];
+        compile_str("(+ 1 2)").write(&mut buf).unwrap();
+
+        let mut wrong_magic = buf.clone();
+        wrong_magic[0] = b'X';
+        let err = Bytecode::read(&mut wrong_magic.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut wrong_version = buf.clone();
+        wrong_version[4] = 3;
+        let err = Bytecode::read(&mut wrong_version.as_slice()).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported file version 3 (expected 2)");
+
+        let truncated = &buf[..buf.len() - 1];
+        let err = Bytecode::read(&mut &truncated[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,141 @@
+//! バイト位置と行・桁の対応
+
+use std::fmt;
+
+use crate::ast::Span;
+
+/// 1から数える行番号と桁番号の組
+///
+/// 桁はバイト数ではなく文字数で数える。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LineCol {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for LineCol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// ソースコードの各行の先頭位置を覚えておき、バイト位置を行と桁に変換する表
+#[derive(Debug, Clone)]
+pub struct SourceMap<'src> {
+    src: &'src str,
+    /// 各行の先頭のバイト位置。最初の要素は常に0
+    line_starts: Vec<usize>,
+}
+
+impl<'src> SourceMap<'src> {
+    /// ソースコードの改行の位置を調べて表を作る
+    pub fn new(src: &'src str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { src, line_starts }
+    }
+
+    /// 元のソースコード
+    pub fn source(&self) -> &'src str {
+        self.src
+    }
+
+    /// 行の数。末尾が改行で終わる場合は、その後の空の行も数える
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// バイト位置を行と桁に変換する関数
+    ///
+    /// # 引数
+    /// * `offset` - ソースコードの先頭からのバイト位置。入力の長さを超える場合は入力の終わりとみなす
+    ///
+    /// # 戻り値
+    /// * `LineCol` - 1から数える行番号と桁番号
+    pub fn line_col(&self, offset: usize) -> LineCol {
+        let offset = self.floor_char_boundary(offset);
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let column = self
+            .line_starts
+            .get(line)
+            .and_then(|&start| self.src.get(start..offset))
+            .map_or(0, |text| text.chars().count())
+            + 1;
+        LineCol {
+            line: line + 1,
+            column,
+        }
+    }
+
+    /// 1から数える行番号の行が覆う範囲。行末の改行は含まない
+    pub fn line_span(&self, line: usize) -> Option<Span> {
+        let start = *self.line_starts.get(line.checked_sub(1)?)?;
+        let end = self
+            .line_starts
+            .get(line)
+            .map_or(self.src.len(), |next| next - 1);
+        Some(Span::new(start, end))
+    }
+
+    /// 1から数える行番号の行の内容。行末の改行は含まない
+    pub fn line(&self, line: usize) -> Option<&'src str> {
+        let span = self.line_span(line)?;
+        Some(self.src.get(span.start..span.end)?.trim_end_matches('\r'))
+    }
+
+    /// 文字の途中を指すバイト位置を、その文字の先頭にずらす
+    fn floor_char_boundary(&self, offset: usize) -> usize {
+        let mut offset = offset.min(self.src.len());
+        while !self.src.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        offset
+    }
+}
+
+/// 位置を行と桁で表示するエラーの説明
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Located {
+    /// 位置を含まないエラーの説明
+    pub message: String,
+    /// エラーの位置
+    pub at: LineCol,
+}
+
+impl fmt::Display for Located {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.at)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let map = SourceMap::new("ab\nçd\n\nx");
+        let at = |offset| map.line_col(offset).to_string();
+        assert_eq!(at(0), "1:1");
+        assert_eq!(at(2), "1:3");
+        assert_eq!(at(3), "2:1");
+        // `ç` は2バイトだが1桁と数える
+        assert_eq!(at(5), "2:2");
+        assert_eq!(at(4), "2:1");
+        assert_eq!(at(7), "3:1");
+        assert_eq!(at(8), "4:1");
+        assert_eq!(at(100), "4:2");
+    }
+
+    #[test]
+    fn test_lines() {
+        let map = SourceMap::new("ab\r\ncd\n");
+        assert_eq!(map.line_count(), 3);
+        assert_eq!(map.line(1), Some("ab"));
+        assert_eq!(map.line_span(2), Some(Span::new(4, 6)));
+        assert_eq!(map.line(3), Some(""));
+        assert_eq!(map.line(0), None);
+        assert_eq!(map.line(4), None);
+    }
+}
@@ -0,0 +1,1214 @@
+//! 大域環境に登録する組み込みの標準関数
+//!
+//! 標準関数はすべて [`Builtin`] として定義し、評価済みの実引数の並びと呼び出し式の範囲を受け取る
+//! 同じ呼び出し規約で呼び出す。引数の数は呼び出す前に [`Arity`] で確かめる。
+//!
+//! 文字列を扱う関数は、バイトではなく文字 (`char`) を単位として位置と長さを数える。
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::mem::size_of;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::ast::Span;
+use crate::env::Environment;
+use crate::eval::{
+    compare, expect_integer, expect_key, expect_number, sorted_keys, values_equal, EvalError, Value,
+};
+use crate::io;
+use crate::limits;
+use crate::math;
+
+/// 関数が受け取る引数の数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    /// ちょうどこの数の引数を受け取る
+    Exact(usize),
+    /// この数以上の任意の数の引数を受け取る
+    AtLeast(usize),
+}
+
+impl Arity {
+    /// 引数の数 `n` を受け取れるかどうか
+    pub fn accepts(self, n: usize) -> bool {
+        match self {
+            Self::Exact(arity) => n == arity,
+            Self::AtLeast(min) => n >= min,
+        }
+    }
+}
+
+/// [`NativeFn`] が呼び出す関数の型
+///
+/// 評価済みの実引数と呼び出し式の範囲を受け取る。
+pub type NativeFnImpl = dyn Fn(&[Value], Span) -> Result<Value, EvalError>;
+
+/// Rust で実装した関数
+///
+/// 標準関数のほか、組み込む側が [`NativeFn::new`] で作った関数も同じように呼び出せる。
+pub struct NativeFn {
+    /// 大域環境に登録する名前
+    pub name: String,
+    /// 受け取る引数の数
+    pub arity: Arity,
+    func: Box<NativeFnImpl>,
+}
+
+impl NativeFn {
+    /// 関数を作る
+    ///
+    /// # 引数
+    /// * `name` - エラーの報告や表示に使う名前
+    /// * `arity` - 受け取る引数の数。呼び出す前に確かめる
+    /// * `func` - 評価済みの実引数と呼び出し式の範囲を受け取る関数
+    pub fn new(
+        name: impl Into<String>,
+        arity: Arity,
+        func: impl Fn(&[Value], Span) -> Result<Value, EvalError> + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            arity,
+            func: Box::new(func),
+        }
+    }
+
+    /// 引数の数を確かめてから関数を呼び出す
+    ///
+    /// # 引数
+    /// * `args` - 評価済みの実引数
+    /// * `span` - 呼び出し式の範囲
+    ///
+    /// # 戻り値
+    /// * `Result<Value, EvalError>` - 関数の戻り値
+    pub fn call(&self, args: &[Value], span: Span) -> Result<Value, EvalError> {
+        match self.arity {
+            _ if self.arity.accepts(args.len()) => {}
+            Arity::Exact(expected) => {
+                return Err(EvalError::Arity {
+                    name: self.name.to_string(),
+                    expected,
+                    found: args.len(),
+                    span,
+                    definition: None,
+                })
+            }
+            Arity::AtLeast(min) => {
+                return Err(EvalError::TooFewArguments {
+                    name: self.name.to_string(),
+                    min,
+                    found: args.len(),
+                    span,
+                })
+            }
+        }
+        let result = (self.func)(args, span)?;
+        limits::allocate_value(&result, span)?;
+        Ok(result)
+    }
+}
+
+impl fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeFn")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// 組み込みの関数は同じ定義を指すものだけを等しいとみなす
+impl PartialEq for NativeFn {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// 標準関数の定義
+pub struct Builtin {
+    /// 大域環境に登録する名前
+    pub name: &'static str,
+    /// 受け取る引数の数
+    pub arity: Arity,
+    func: fn(&[Value], Span) -> Result<Value, EvalError>,
+}
+
+impl Builtin {
+    /// 標準関数を定義する。公開しない関数の一覧を作るのに使う
+    pub(crate) const fn new(
+        name: &'static str,
+        arity: Arity,
+        func: fn(&[Value], Span) -> Result<Value, EvalError>,
+    ) -> Self {
+        Self { name, arity, func }
+    }
+
+    /// 呼び出せる関数の値を作る
+    pub fn to_value(&self) -> Value {
+        Value::NativeFn(Rc::new(NativeFn::new(self.name, self.arity, self.func)))
+    }
+}
+
+/// 標準関数の一覧
+pub static FUNCTIONS: &[Builtin] = &[
+    Builtin {
+        name: "sqrt",
+        arity: Arity::Exact(1),
+        func: sqrt,
+    },
+    Builtin {
+        name: "abs",
+        arity: Arity::Exact(1),
+        func: abs,
+    },
+    Builtin {
+        name: "min",
+        arity: Arity::AtLeast(1),
+        func: min,
+    },
+    Builtin {
+        name: "max",
+        arity: Arity::AtLeast(1),
+        func: max,
+    },
+    Builtin {
+        name: "len",
+        arity: Arity::Exact(1),
+        func: len,
+    },
+    Builtin {
+        name: "print",
+        arity: Arity::AtLeast(0),
+        func: print,
+    },
+    Builtin {
+        name: "to_string",
+        arity: Arity::Exact(1),
+        func: to_string,
+    },
+    Builtin {
+        name: "parse_num",
+        arity: Arity::Exact(1),
+        func: parse_num,
+    },
+    Builtin {
+        name: "cons",
+        arity: Arity::Exact(2),
+        func: cons,
+    },
+    Builtin {
+        name: "car",
+        arity: Arity::Exact(1),
+        func: car,
+    },
+    Builtin {
+        name: "cdr",
+        arity: Arity::Exact(1),
+        func: cdr,
+    },
+    Builtin {
+        name: "list",
+        arity: Arity::AtLeast(0),
+        func: list,
+    },
+    Builtin {
+        name: "null?",
+        arity: Arity::Exact(1),
+        func: is_null,
+    },
+    Builtin {
+        name: "push",
+        arity: Arity::Exact(2),
+        func: push,
+    },
+    Builtin {
+        name: "pop",
+        arity: Arity::Exact(1),
+        func: pop,
+    },
+    Builtin {
+        name: "copy",
+        arity: Arity::Exact(1),
+        func: copy,
+    },
+    Builtin {
+        name: "get",
+        arity: Arity::Exact(2),
+        func: get,
+    },
+    Builtin {
+        name: "insert",
+        arity: Arity::Exact(3),
+        func: insert,
+    },
+    Builtin {
+        name: "remove",
+        arity: Arity::Exact(2),
+        func: remove,
+    },
+    Builtin {
+        name: "keys",
+        arity: Arity::Exact(1),
+        func: keys,
+    },
+    Builtin {
+        name: "concat",
+        arity: Arity::AtLeast(0),
+        func: concat,
+    },
+    Builtin {
+        name: "substr",
+        arity: Arity::Exact(3),
+        func: substr,
+    },
+    Builtin {
+        name: "split",
+        arity: Arity::Exact(2),
+        func: split,
+    },
+    Builtin {
+        name: "find",
+        arity: Arity::Exact(2),
+        func: find,
+    },
+    Builtin {
+        name: "upper",
+        arity: Arity::Exact(1),
+        func: upper,
+    },
+    Builtin {
+        name: "lower",
+        arity: Arity::Exact(1),
+        func: lower,
+    },
+    Builtin {
+        name: "compare",
+        arity: Arity::Exact(2),
+        func: compare_strings,
+    },
+    Builtin {
+        name: "format",
+        arity: Arity::AtLeast(1),
+        func: format,
+    },
+    Builtin {
+        name: "printf",
+        arity: Arity::AtLeast(1),
+        func: printf,
+    },
+    Builtin {
+        name: "assert",
+        arity: Arity::AtLeast(1),
+        func: assert,
+    },
+    Builtin {
+        name: "assert_eq",
+        arity: Arity::AtLeast(2),
+        func: assert_eq,
+    },
+    Builtin {
+        name: "clock",
+        arity: Arity::Exact(0),
+        func: clock,
+    },
+    Builtin {
+        name: "now",
+        arity: Arity::Exact(0),
+        func: now,
+    },
+];
+
+/// 標準関数を環境に定義する関数
+///
+/// 標準関数のほか、[`io`] の入出力の関数と、数学の関数をまとめた [`math`] のモジュールを
+/// `math` の名前で定義する。
+/// 同じ名前の変数をあとから定義すれば、標準関数を隠せる。
+///
+/// # 引数
+/// * `env` - 標準関数を定義する環境
+pub fn register(env: &mut Environment) {
+    for function in FUNCTIONS.iter().chain(io::FUNCTIONS) {
+        env.define(function.name, function.to_value());
+    }
+    env.define("math", math::module());
+}
+
+fn sqrt(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    match expect_number(args[0].clone(), span)? {
+        Value::I64(n) => Ok(Value::F64((n as f64).sqrt())),
+        Value::F64(n) => Ok(Value::F64(n.sqrt())),
+        _ => unreachable!("expect_number only returns numbers"),
+    }
+}
+
+fn abs(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    match expect_number(args[0].clone(), span)? {
+        Value::I64(n) => n
+            .checked_abs()
+            .map(Value::I64)
+            .ok_or(EvalError::IntegerOverflow { span }),
+        Value::F64(n) => Ok(Value::F64(n.abs())),
+        _ => unreachable!("expect_number only returns numbers"),
+    }
+}
+
+/// 引数のうち `keep` の順序で最も先に来る値を、整数か浮動小数点数かを変えずに返す
+fn select(args: &[Value], span: Span, keep: Ordering) -> Result<Value, EvalError> {
+    let mut best = expect_number(args[0].clone(), span)?;
+    for arg in &args[1..] {
+        let arg = expect_number(arg.clone(), span)?;
+        if compare(&arg, &best) == Some(keep) {
+            best = arg;
+        }
+    }
+    Ok(best)
+}
+
+fn min(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    select(args, span, Ordering::Less)
+}
+
+fn max(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    select(args, span, Ordering::Greater)
+}
+
+fn len(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let len = match &args[0] {
+        Value::Str(s) => s.chars().count(),
+        Value::List(items) => items.len(),
+        Value::Array(items) => items.borrow().len(),
+        Value::Map(entries) => entries.borrow().len(),
+        _ => {
+            return Err(EvalError::TypeMismatch {
+                expected: "a string, a list, an array or a map",
+                span,
+            })
+        }
+    };
+    Ok(Value::I64(len as i64))
+}
+
+/// 引数を空白で区切って標準出力に書き出す。文字列は引用符で囲まずに書き出す
+fn print(args: &[Value], _span: Span) -> Result<Value, EvalError> {
+    let shown: Vec<_> = args.iter().map(display).collect();
+    println!("{}", shown.join(" "));
+    Ok(Value::Nil)
+}
+
+fn to_string(args: &[Value], _span: Span) -> Result<Value, EvalError> {
+    Ok(Value::Str(display(&args[0]).into()))
+}
+
+/// 文字列を数値として読む。整数として読めなければ浮動小数点数として読み、どちらでもなければ `nil` を返す
+fn parse_num(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let s = expect_str(&args[0], span)?.trim();
+    Ok(s.parse()
+        .map(Value::I64)
+        .or_else(|_| s.parse().map(Value::F64))
+        .unwrap_or(Value::Nil))
+}
+
+/// 値が文字列であることを確かめる
+fn expect_str(value: &Value, span: Span) -> Result<&str, EvalError> {
+    match value {
+        Value::Str(s) => Ok(s),
+        _ => Err(EvalError::TypeMismatch {
+            expected: "a string",
+            span,
+        }),
+    }
+}
+
+/// 文字列の先頭から `index` 文字目のバイト位置。`index` は文字数と等しくてもよい
+///
+/// # 戻り値
+/// * `Result<usize, EvalError>` - バイト位置。負の位置や文字数を超える位置はエラーにする
+pub(crate) fn char_offset(s: &str, index: i64, span: Span) -> Result<usize, EvalError> {
+    let out_of_bounds = || EvalError::IndexOutOfBounds {
+        index,
+        len: s.chars().count(),
+        span,
+    };
+    let n = usize::try_from(index).map_err(|_| out_of_bounds())?;
+    s.char_indices()
+        .map(|(i, _)| i)
+        .chain([s.len()])
+        .nth(n)
+        .ok_or_else(out_of_bounds)
+}
+
+/// 引数の文字列をつなげた文字列を返す
+fn concat(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let mut result = String::new();
+    for arg in args {
+        result.push_str(expect_str(arg, span)?);
+    }
+    Ok(Value::Str(result.into()))
+}
+
+/// 文字列の `start` 文字目から `end` 文字目の手前までを返す。`end` が `start` より前なら空の文字列を返す
+fn substr(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let s = expect_str(&args[0], span)?;
+    let start = expect_integer(args[1].clone(), span)?;
+    let end = expect_integer(args[2].clone(), span)?.max(start);
+    let (start, end) = (char_offset(s, start, span)?, char_offset(s, end, span)?);
+    Ok(Value::Str(s.get(start..end).unwrap_or_default().into()))
+}
+
+/// 文字列を区切りの文字列で分けた配列を返す。区切りが空の文字列なら1文字ずつに分ける
+fn split(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let s = expect_str(&args[0], span)?;
+    let sep = expect_str(&args[1], span)?;
+    let parts: Vec<_> = if sep.is_empty() {
+        s.chars()
+            .map(|c| Value::Str(c.to_string().into()))
+            .collect()
+    } else {
+        s.split(sep).map(|part| Value::Str(part.into())).collect()
+    };
+    Ok(Value::array(parts))
+}
+
+/// 文字列の中で部分文字列が最初に現れる文字の位置を返す。現れなければ `nil` を返す
+fn find(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let s = expect_str(&args[0], span)?;
+    let pattern = expect_str(&args[1], span)?;
+    Ok(s.find(pattern).map_or(Value::Nil, |at| {
+        Value::I64(s.char_indices().take_while(|&(i, _)| i < at).count() as i64)
+    }))
+}
+
+fn upper(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    Ok(Value::Str(
+        expect_str(&args[0], span)?.to_uppercase().into(),
+    ))
+}
+
+fn lower(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    Ok(Value::Str(
+        expect_str(&args[0], span)?.to_lowercase().into(),
+    ))
+}
+
+/// 2つの文字列を文字の符号位置の辞書順で比べ、前なら `-1`、等しければ `0`、後なら `1` を返す
+fn compare_strings(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let lhs = expect_str(&args[0], span)?;
+    let rhs = expect_str(&args[1], span)?;
+    Ok(Value::I64(lhs.cmp(rhs) as i64))
+}
+
+/// 書式文字列を区切った部分
+#[derive(Debug, PartialEq)]
+enum Piece {
+    /// そのまま書き出す文字列。`{{` と `}}` は展開済み
+    Literal(String),
+    /// 引数を文字列はそのままに書き出す `{}`
+    Display,
+    /// 引数をソースコードに書く形で書き出す `{:?}`
+    Debug,
+}
+
+/// 書式文字列を書き出す部分の並びに区切る関数
+///
+/// Rust の `format!` と同じく `{{` と `}}` で波括弧そのものを書く。
+/// 置き換える部分は `{}` と `{:?}` だけを受け付ける。
+///
+/// # 戻り値
+/// * `Result<Vec<Piece>, &'static str>` - 部分の並び。書式が正しくなければその理由
+fn parse_format(fmt: &str) -> Result<Vec<Piece>, &'static str> {
+    let mut pieces = vec![];
+    let mut literal = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                literal.push(c);
+            }
+            ('{', _) => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => spec.push(c),
+                        None => return Err("unclosed `{`"),
+                    }
+                }
+                let piece = match spec.as_str() {
+                    "" => Piece::Display,
+                    ":?" => Piece::Debug,
+                    _ => return Err("only `{}` and `{:?}` placeholders are supported"),
+                };
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                pieces.push(piece);
+            }
+            ('}', _) => return Err("unmatched `}`"),
+            (c, _) => literal.push(c),
+        }
+    }
+    pieces.push(Piece::Literal(literal));
+    pieces.retain(|piece| *piece != Piece::Literal(String::new()));
+    Ok(pieces)
+}
+
+/// 1つ目の引数の書式文字列の `{}` を、残りの引数で順に置き換えた文字列を返す
+fn format(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let pieces = parse_format(expect_str(&args[0], span)?)
+        .map_err(|reason| EvalError::MalformedFormat { reason, span })?;
+    let placeholders = pieces
+        .iter()
+        .filter(|piece| !matches!(piece, Piece::Literal(_)))
+        .count();
+    let mut args = args[1..].iter();
+    if placeholders != args.len() {
+        return Err(EvalError::FormatArity {
+            placeholders,
+            found: args.len(),
+            span,
+        });
+    }
+    let mut result = String::new();
+    for piece in &pieces {
+        match piece {
+            Piece::Literal(s) => result.push_str(s),
+            Piece::Display => result.push_str(&display(args.next().expect("counted above"))),
+            Piece::Debug => result.push_str(&args.next().expect("counted above").to_string()),
+        }
+    }
+    Ok(Value::Str(result.into()))
+}
+
+/// `format` と同じく書式を整えた文字列を、改行を加えずに標準出力に書き出す
+fn printf(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let Value::Str(s) = format(args, span)? else {
+        unreachable!("format returns a string");
+    };
+    let mut stdout = std::io::stdout();
+    // 書き出せなくてもプログラムは止めない。`print` も同じく出力の失敗を報告しない
+    let _ = stdout.write_all(s.as_bytes()).and_then(|()| stdout.flush());
+    Ok(Value::Nil)
+}
+
+/// 値がリストであることを確かめる
+fn expect_list(value: &Value, span: Span) -> Result<&[Value], EvalError> {
+    match value {
+        Value::List(items) => Ok(items),
+        _ => Err(EvalError::TypeMismatch {
+            expected: "a list",
+            span,
+        }),
+    }
+}
+
+/// 空でないリストの先頭の要素と残りの要素
+fn split_list(value: &Value, span: Span) -> Result<(&Value, &[Value]), EvalError> {
+    expect_list(value, span)?
+        .split_first()
+        .ok_or(EvalError::TypeMismatch {
+            expected: "a non-empty list",
+            span,
+        })
+}
+
+/// 2つ目の引数のリストの先頭に1つ目の引数を加えたリストを返す
+///
+/// リストは要素を連続して並べて持つので、`cons` と `cdr` は要素を複製した新しいリストを作る。
+fn cons(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let rest = expect_list(&args[1], span)?;
+    let items: Vec<_> = std::iter::once(args[0].clone())
+        .chain(rest.iter().cloned())
+        .collect();
+    Ok(Value::List(items.into()))
+}
+
+fn car(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    Ok(split_list(&args[0], span)?.0.clone())
+}
+
+fn cdr(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    Ok(Value::List(split_list(&args[0], span)?.1.into()))
+}
+
+fn list(args: &[Value], _span: Span) -> Result<Value, EvalError> {
+    Ok(Value::List(args.into()))
+}
+
+/// 空のリストと `nil` を真とする
+fn is_null(args: &[Value], _span: Span) -> Result<Value, EvalError> {
+    let null = match &args[0] {
+        Value::List(items) => items.is_empty(),
+        value => *value == Value::Nil,
+    };
+    Ok(Value::Bool(null))
+}
+
+/// 値が配列であることを確かめる
+fn expect_array(value: &Value, span: Span) -> Result<&RefCell<Rc<Vec<Value>>>, EvalError> {
+    match value {
+        Value::Array(items) => Ok(items),
+        _ => Err(EvalError::TypeMismatch {
+            expected: "an array",
+            span,
+        }),
+    }
+}
+
+/// 1つ目の引数の配列の末尾に2つ目の引数を加える。リストと違い、配列そのものを書き換える
+fn push(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let items = expect_array(&args[0], span)?;
+    limits::allocate(size_of::<Value>(), span)?;
+    Rc::make_mut(&mut items.borrow_mut()).push(args[1].clone());
+    Ok(Value::Nil)
+}
+
+/// 配列の末尾の要素を取り除いて返す
+fn pop(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    Rc::make_mut(&mut expect_array(&args[0], span)?.borrow_mut())
+        .pop()
+        .ok_or(EvalError::TypeMismatch {
+            expected: "a non-empty array",
+            span,
+        })
+}
+
+/// 配列やマップの写しを作る。他の値はそのまま返す
+///
+/// 配列とマップは代入や関数の引数に渡しても同じものを指すので、別のものとして書き換えたいときに使う。
+/// 写しは中身を共有して作るので長さによらず速く、どちらかを初めて書き換えるときに中身を複製する。
+/// 要素は複製しないので、要素の配列を書き換えると両方から見える。
+fn copy(args: &[Value], _span: Span) -> Result<Value, EvalError> {
+    Ok(match &args[0] {
+        Value::Array(items) => Value::array(Rc::clone(&items.borrow())),
+        Value::Map(entries) => Value::map(Rc::clone(&entries.borrow())),
+        value => value.clone(),
+    })
+}
+
+/// 値がマップであることを確かめる
+fn expect_map(value: &Value, span: Span) -> Result<&RefCell<Rc<HashMap<Value, Value>>>, EvalError> {
+    match value {
+        Value::Map(entries) => Ok(entries),
+        _ => Err(EvalError::TypeMismatch {
+            expected: "a map",
+            span,
+        }),
+    }
+}
+
+/// マップからキーの値を引く。キーが無ければ `nil` を返す
+///
+/// モジュールからは変数の名前の文字列で値を引き、定義されていなければ `nil` を返す。
+fn get(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    if let Value::Module(module) = &args[0] {
+        let name = expect_str(&args[1], span)?;
+        return Ok(module.get(name).unwrap_or(Value::Nil));
+    }
+    let entries = expect_map(&args[0], span)?;
+    let key = expect_key(args[1].clone(), span)?;
+    let value = entries.borrow().get(&key).cloned();
+    Ok(value.unwrap_or(Value::Nil))
+}
+
+/// マップのキーに値を対応付け、それまで対応付けていた値を返す。無ければ `nil` を返す
+fn insert(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let entries = expect_map(&args[0], span)?;
+    let key = expect_key(args[1].clone(), span)?;
+    let old = Rc::make_mut(&mut entries.borrow_mut()).insert(key, args[2].clone());
+    if old.is_none() {
+        limits::allocate(2 * size_of::<Value>(), span)?;
+    }
+    Ok(old.unwrap_or(Value::Nil))
+}
+
+/// マップからキーを取り除き、対応付けていた値を返す。無ければ `nil` を返す
+fn remove(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let entries = expect_map(&args[0], span)?;
+    let key = expect_key(args[1].clone(), span)?;
+    let old = Rc::make_mut(&mut entries.borrow_mut()).remove(&key);
+    Ok(old.unwrap_or(Value::Nil))
+}
+
+/// 省略できる最後の引数を確かめる。`max` より多い引数はエラーにする
+fn optional_message<'a>(
+    name: &str,
+    args: &'a [Value],
+    max: usize,
+    span: Span,
+) -> Result<Option<&'a Value>, EvalError> {
+    if args.len() > max {
+        return Err(EvalError::Arity {
+            name: name.to_string(),
+            expected: max,
+            found: args.len(),
+            span,
+            definition: None,
+        });
+    }
+    Ok(args.get(max - 1).filter(|_| args.len() == max))
+}
+
+/// 1つ目の引数が偽なら失敗する。2つ目の引数を渡せば、失敗の説明に使う
+fn assert(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let message = optional_message("assert", args, 2, span)?;
+    if args[0].is_truthy() {
+        return Ok(Value::Nil);
+    }
+    let message = match message {
+        Some(Value::Str(s)) => s.to_string(),
+        Some(value) => value.to_string(),
+        None => format!("{} is not true", args[0]),
+    };
+    Err(EvalError::AssertionFailed { message, span })
+}
+
+/// 2つの引数が `==` で等しくなければ失敗する。3つ目の引数を渡せば、失敗の説明に加える
+fn assert_eq(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let message = optional_message("assert_eq", args, 3, span)?;
+    if values_equal(&args[0], &args[1]) {
+        return Ok(Value::Nil);
+    }
+    let mut text = format!("{} != {}", args[0], args[1]);
+    match message {
+        Some(Value::Str(s)) => text = format!("{s}: {text}"),
+        Some(value) => text = format!("{value}: {text}"),
+        None => {}
+    }
+    Err(EvalError::AssertionFailed {
+        message: text,
+        span,
+    })
+}
+
+/// 初めて `clock` を呼び出してからの経過秒数を浮動小数点数で返す
+///
+/// 時刻を戻しても減らない時計で測るので、2回の呼び出しの差を処理の時間として使える。
+fn clock(_args: &[Value], _span: Span) -> Result<Value, EvalError> {
+    static START: OnceLock<Instant> = OnceLock::new();
+    Ok(Value::F64(
+        START.get_or_init(Instant::now).elapsed().as_secs_f64(),
+    ))
+}
+
+/// 1970年1月1日 (UTC) からの経過秒数を浮動小数点数で返す
+fn now(_args: &[Value], _span: Span) -> Result<Value, EvalError> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+    Ok(Value::F64(elapsed))
+}
+
+/// マップのキーを、マップを表示するときと同じ順に並べた配列を返す
+fn keys(args: &[Value], span: Span) -> Result<Value, EvalError> {
+    let keys = sorted_keys(&expect_map(&args[0], span)?.borrow());
+    Ok(Value::array(keys))
+}
+
+/// 文字列はそのまま、それ以外の値はソースコードに書く形にした文字列
+fn display(value: &Value) -> String {
+    match value {
+        Value::Str(s) => s.to_string(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::eval_forms;
+    use crate::parser::source;
+    use crate::TokenTree;
+
+    fn eval_str(input: &str) -> Result<Value, EvalError> {
+        let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+            panic!("failed to parse {input:?}");
+        };
+        let mut env = Environment::new();
+        register(&mut env);
+        eval_forms(&forms, &mut env).map(Option::unwrap)
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(eval_str("(sqrt 16)"), Ok(Value::F64(4.0)));
+        assert_eq!(eval_str("(abs -3)"), Ok(Value::I64(3)));
+        assert_eq!(eval_str("(abs -2.5)"), Ok(Value::F64(2.5)));
+        assert_eq!(eval_str("(min 3 1.5 2)"), Ok(Value::F64(1.5)));
+        assert_eq!(eval_str("(max 3 1.5 2)"), Ok(Value::I64(3)));
+        assert_eq!(eval_str(r#"(len "héllo")"#), Ok(Value::I64(5)));
+        assert_eq!(eval_str(r#"(to_string "a")"#), Ok(Value::Str("a".into())));
+        assert_eq!(eval_str("(to_string 1.0)"), Ok(Value::Str("1.0".into())));
+        assert_eq!(eval_str(r#"(parse_num " 42 ")"#), Ok(Value::I64(42)));
+        assert_eq!(eval_str(r#"(parse_num "0.5")"#), Ok(Value::F64(0.5)));
+        assert_eq!(eval_str(r#"(parse_num "x")"#), Ok(Value::Nil));
+        assert_eq!(eval_str("(print)"), Ok(Value::Nil));
+        // 標準関数は値として渡せ、同じ名前の変数で隠せる
+        assert_eq!(
+            eval_str("(define f abs) (f -1) (define abs 0) abs"),
+            Ok(Value::I64(0))
+        );
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(
+            eval_str("(cons 1 (list 2 3))").map(|v| v.to_string()),
+            Ok("(1 2 3)".to_string())
+        );
+        assert_eq!(
+            eval_str("(car '(car cdr))"),
+            Ok(Value::Symbol("car".into()))
+        );
+        assert_eq!(
+            eval_str("(cdr '(1 (2) 3))").map(|v| v.to_string()),
+            Ok("((2) 3)".to_string())
+        );
+        assert_eq!(eval_str("(null? (cdr (list 1)))"), Ok(Value::Bool(true)));
+        assert_eq!(eval_str("(null? nil)"), Ok(Value::Bool(true)));
+        assert_eq!(eval_str("(null? '(nil))"), Ok(Value::Bool(false)));
+        assert_eq!(eval_str("(len (list))"), Ok(Value::I64(0)));
+        assert_eq!(
+            eval_str(
+                "(define sum (fn (xs) (if (null? xs) 0 (+ (car xs) (sum (cdr xs)))))) (sum '(1 2 3))"
+            ),
+            Ok(Value::I64(6))
+        );
+        assert_eq!(
+            eval_str("(car (list))"),
+            Err(EvalError::TypeMismatch {
+                expected: "a non-empty list",
+                span: Span::new(0, 12)
+            })
+        );
+        assert_eq!(
+            eval_str("(cons 1 2)"),
+            Err(EvalError::TypeMismatch {
+                expected: "a list",
+                span: Span::new(0, 10)
+            })
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            eval_str("(sqrt 1 2)"),
+            Err(EvalError::Arity {
+                name: "sqrt".to_string(),
+                expected: 1,
+                found: 2,
+                span: Span::new(0, 10),
+                definition: None,
+            })
+        );
+        assert_eq!(
+            eval_str("(min)"),
+            Err(EvalError::TooFewArguments {
+                name: "min".to_string(),
+                min: 1,
+                found: 0,
+                span: Span::new(0, 5),
+            })
+        );
+        // 可変個の引数を取る関数は、引数の数の下限を示す
+        assert_eq!(
+            eval_str("(max)").map_err(|e| e.to_string()),
+            Err("`max` expected at least 1 argument, found 0 at byte 0".to_string())
+        );
+        assert_eq!(
+            eval_str("(len 1)"),
+            Err(EvalError::TypeMismatch {
+                expected: "a string, a list, an array or a map",
+                span: Span::new(0, 7)
+            })
+        );
+        assert_eq!(
+            eval_str("(abs -9223372036854775808)"),
+            Err(EvalError::IntegerOverflow {
+                span: Span::new(0, 26)
+            })
+        );
+    }
+
+    #[test]
+    fn test_arrays() {
+        let run = |input: &str| {
+            let mut env = Environment::new();
+            register(&mut env);
+            crate::eval::eval_statements(&crate::infix::statements(input).unwrap(), &mut env)
+                .map(Option::unwrap)
+        };
+        assert_eq!(
+            run("var a = [1]\npush(a, [2])\npush(a, 3)\nvar last = pop(a)\n[len(a), last, a]")
+                .map(|v| v.to_string()),
+            Ok("[2, 3, [1, [2]]]".to_string())
+        );
+        assert_eq!(
+            run("pop([])"),
+            Err(EvalError::TypeMismatch {
+                expected: "a non-empty array",
+                span: Span::new(0, 7)
+            })
+        );
+        assert_eq!(
+            run("push(list(1), 2)"),
+            Err(EvalError::TypeMismatch {
+                expected: "an array",
+                span: Span::new(0, 16)
+            })
+        );
+    }
+
+    #[test]
+    fn test_reference_semantics() {
+        let run = |input: &str| {
+            let mut env = Environment::new();
+            register(&mut env);
+            crate::eval::eval_statements(&crate::infix::statements(input).unwrap(), &mut env)
+                .map(|v| v.unwrap().to_string())
+        };
+        // 配列とマップは代入しても関数に渡しても同じものを指す
+        assert_eq!(
+            run("var a = [1, 2]; var b = a; b[0] = 9; a"),
+            Ok("[9, 2]".into())
+        );
+        assert_eq!(
+            run("fn add(xs) { push(xs, 3) }; var a = [1]; add(a); a"),
+            Ok("[1, 3]".into())
+        );
+        assert_eq!(
+            run("var m = {1: 2}; var n = m; insert(n, 3, 4); len(keys(m))"),
+            Ok("2".into())
+        );
+        // `copy` の写しは書き換えても元に影響しない
+        assert_eq!(
+            run("var a = [1, 2]; var b = copy(a); b[0] = 9; push(b, 3); [a, b]"),
+            Ok("[[1, 2], [9, 2, 3]]".into())
+        );
+        assert_eq!(
+            run("var m = {1: 2}; var n = copy(m); remove(n, 1); [len(keys(m)), len(keys(n))]"),
+            Ok("[1, 0]".into())
+        );
+        // 要素は複製しない
+        assert_eq!(
+            run("var a = [[1]]; var b = copy(a); b[0][0] = 2; a"),
+            Ok("[[2]]".into())
+        );
+        assert_eq!(run("copy(list(1, 2))"), Ok("(1 2)".into()));
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let storage = |value: &Value| match value {
+            Value::Array(items) => Rc::clone(&items.borrow()),
+            _ => unreachable!(),
+        };
+        let a = Value::array(vec![Value::I64(1), Value::I64(2)]);
+        let b = copy(std::slice::from_ref(&a), Span::new(0, 0)).unwrap();
+        // 書き換えるまでは要素の並びを共有する
+        assert!(Rc::ptr_eq(&storage(&a), &storage(&b)));
+        push(&[b.clone(), Value::I64(3)], Span::new(0, 0)).unwrap();
+        assert!(!Rc::ptr_eq(&storage(&a), &storage(&b)));
+        assert_eq!(a.to_string(), "[1, 2]");
+        assert_eq!(b.to_string(), "[1, 2, 3]");
+        // 共有していなければ複製せずにその場で書き換える
+        let before = Rc::as_ptr(&storage(&b));
+        push(&[b.clone(), Value::I64(4)], Span::new(0, 0)).unwrap();
+        assert_eq!(Rc::as_ptr(&storage(&b)), before);
+    }
+
+    #[test]
+    fn test_maps() {
+        let run = |input: &str| {
+            let mut env = Environment::new();
+            register(&mut env);
+            crate::eval::eval_statements(&crate::infix::statements(input).unwrap(), &mut env)
+                .map(Option::unwrap)
+        };
+        assert_eq!(
+            run("var m = { \"a\": 1 }\n\
+                 var old = insert(m, \"a\", 2)\n\
+                 insert(m, 3, \"c\")\n\
+                 var gone = remove(m, 3)\n\
+                 [old, gone, get(m, \"a\"), get(m, \"z\"), remove(m, 3), keys(m), len(m)]")
+            .map(|v| v.to_string()),
+            Ok("[1, \"c\", 2, nil, nil, [\"a\"], 1]".to_string())
+        );
+        assert_eq!(
+            run("keys({ \"b\": 1, 2: 1, \"a\": 1, true: 1 })").map(|v| v.to_string()),
+            Ok("[true, 2, \"a\", \"b\"]".to_string())
+        );
+        assert_eq!(
+            run("insert({:}, [1], 2)"),
+            Err(EvalError::TypeMismatch {
+                expected: "an integer, a string, a boolean or nil as a map key",
+                span: Span::new(0, 19)
+            })
+        );
+        assert_eq!(
+            run("get([1], 0)"),
+            Err(EvalError::TypeMismatch {
+                expected: "a map",
+                span: Span::new(0, 11)
+            })
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        let run = |input: &str| {
+            let mut env = Environment::new();
+            register(&mut env);
+            crate::eval::eval_statements(&crate::infix::statements(input).unwrap(), &mut env)
+                .map(|v| v.unwrap().to_string())
+        };
+        assert_eq!(
+            run(r#"concat("日本", "語", "")"#),
+            Ok(r#""日本語""#.to_string())
+        );
+        assert_eq!(run(r#"concat()"#), Ok(r#""""#.to_string()));
+        // 位置はバイトではなく文字で数える
+        assert_eq!(
+            run(
+                r#"var s = "héllo, 世界"; [substr(s, 1, 4), substr(s, 7, 9), substr(s, 3, 1), s[8]]"#
+            ),
+            Ok(r#"["éll", "世界", "", "界"]"#.to_string())
+        );
+        assert_eq!(run(r#"find("héllo", "llo")"#), Ok("2".to_string()));
+        assert_eq!(run(r#"find("héllo", "x")"#), Ok("nil".to_string()));
+        assert_eq!(
+            run(r#"[split("a,b,,c", ","), split("añ", "")]"#),
+            Ok(r#"[["a", "b", "", "c"], ["a", "ñ"]]"#.to_string())
+        );
+        assert_eq!(
+            run(r#"[upper("straße"), lower("ÀB")]"#),
+            Ok(r#"["STRASSE", "àb"]"#.to_string())
+        );
+        assert_eq!(
+            run(r#"[compare("a", "b"), compare("b", "b"), compare("é", "z")]"#),
+            Ok("[-1, 0, 1]".to_string())
+        );
+        assert_eq!(
+            run(r#"substr("héllo", 2, 6)"#),
+            Err(EvalError::IndexOutOfBounds {
+                index: 6,
+                len: 5,
+                span: Span::new(0, 22)
+            })
+        );
+        assert_eq!(
+            run(r#""ab"[2]"#),
+            Err(EvalError::IndexOutOfBounds {
+                index: 2,
+                len: 2,
+                span: Span::new(0, 7)
+            })
+        );
+        assert_eq!(
+            run(r#"concat("a", 1)"#),
+            Err(EvalError::TypeMismatch {
+                expected: "a string",
+                span: Span::new(0, 14)
+            })
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            parse_format("{{x}} = {}{:?}"),
+            Ok(vec![
+                Piece::Literal("{x} = ".to_string()),
+                Piece::Display,
+                Piece::Debug
+            ])
+        );
+        assert_eq!(parse_format("a {"), Err("unclosed `{`"));
+        assert_eq!(parse_format("a } b"), Err("unmatched `}`"));
+        assert_eq!(
+            parse_format("{0}"),
+            Err("only `{}` and `{:?}` placeholders are supported")
+        );
+        assert_eq!(
+            eval_str(r#"(format "{} + {} = {:?}, {}" 1 2.5 "3.5" "ok")"#),
+            Ok(Value::Str(r#"1 + 2.5 = "3.5", ok"#.into()))
+        );
+        assert_eq!(
+            eval_str(r#"(format "{}" (list 1 "a"))"#),
+            Ok(Value::Str(r#"(1 "a")"#.into()))
+        );
+        assert_eq!(eval_str(r#"(printf "")"#), Ok(Value::Nil));
+        assert_eq!(
+            eval_str(r#"(format "{} {}" 1)"#),
+            Err(EvalError::FormatArity {
+                placeholders: 2,
+                found: 1,
+                span: Span::new(0, 18)
+            })
+        );
+        assert_eq!(
+            eval_str(r#"(format "{" 1)"#).map_err(|e| e.to_string()),
+            Err("invalid format string: unclosed `{` at byte 0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_time() {
+        let span = Span::new(0, 0);
+        let (Ok(Value::F64(start)), Ok(Value::F64(end))) = (clock(&[], span), clock(&[], span))
+        else {
+            panic!("clock returns a float");
+        };
+        assert!(0.0 <= start && start <= end);
+        let Ok(Value::F64(now)) = now(&[], span) else {
+            panic!("now returns a float");
+        };
+        // 2020年より後
+        assert!(now > 1.6e9);
+    }
+
+    #[test]
+    fn test_assertions() {
+        let run = |input: &str| {
+            let mut env = Environment::new();
+            register(&mut env);
+            crate::eval::eval_statements(&crate::infix::statements(input).unwrap(), &mut env)
+                .map(Option::unwrap)
+        };
+        assert_eq!(run("assert(1 < 2)"), Ok(Value::Nil));
+        assert_eq!(run("assert_eq([1, 2.0], [1, 2])"), Ok(Value::Nil));
+        assert_eq!(
+            run("assert(1 > 2)"),
+            Err(EvalError::AssertionFailed {
+                message: "false is not true".to_string(),
+                span: Span::new(0, 13)
+            })
+        );
+        assert_eq!(
+            run("assert(nil, \"no value\")"),
+            Err(EvalError::AssertionFailed {
+                message: "no value".to_string(),
+                span: Span::new(0, 23)
+            })
+        );
+        assert_eq!(
+            run("assert_eq(\"a\", 1, \"mixed\")"),
+            Err(EvalError::AssertionFailed {
+                message: "mixed: \"a\" != 1".to_string(),
+                span: Span::new(0, 26)
+            })
+        );
+        assert!(matches!(
+            run("assert(true, 1, 2)"),
+            Err(EvalError::Arity {
+                expected: 2,
+                found: 3,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,463 @@
+//! 入力を少しずつ受け取りながらS式を解析するストリーミング構文解析器
+//!
+//! [`source`](crate::source) は入力全体を1つの `&str` として受け取るが、[`Parser`] は入力を
+//! 任意の位置で区切った断片ごとに受け取る。読み終えていないトークンの分だけを保持し、
+//! 最上位の式を読み終えるたびに [`OwnedTokenTree`] として返すので、大きなファイルも
+//! 全体をメモリに読み込まずに解析できる。
+
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::ast::{OwnedToken, OwnedTokenTree, Span, Token};
+use crate::lexer::{skip_trivia, split_trivia, token_with, LexError, TriviaKind};
+use crate::parser::{quote_form, Expected, ParseError};
+
+/// 閉じていない括弧の中で読んだ要素
+struct Frame {
+    /// 左括弧の範囲
+    open: Span,
+    children: Vec<OwnedTokenTree>,
+    /// 次の要素に付ける `'` などの前置記号と、その範囲
+    prefixes: Vec<(&'static str, Span)>,
+}
+
+/// 断片の境界をまたいで読んでいるコメント
+#[derive(Debug, Clone, Copy)]
+enum Comment {
+    /// 行コメント。改行で終わる
+    Line,
+    /// ブロックコメント。`depth` は閉じていない `/*` の数
+    Block { depth: usize },
+}
+
+/// 入力を断片ごとに受け取るS式の構文解析器
+///
+/// 断片の境界がトークンの途中にあってもよい。範囲は最初の断片の先頭からのバイト位置で表す。
+///
+/// ```
+/// use ruscal_b::stream::Parser;
+///
+/// let mut parser = Parser::new();
+/// assert!(parser.feed("(a (b").unwrap().is_empty());
+/// let forms = parser.feed("c) 1) 2").unwrap();
+/// assert_eq!(forms.len(), 1);
+/// assert_eq!(parser.finish().unwrap().len(), 1);
+/// ```
+#[derive(Default)]
+pub struct Parser {
+    /// まだトークンとして読み終えていない入力
+    pending: String,
+    /// `pending` の先頭のバイト位置
+    offset: usize,
+    /// 閉じていない括弧。一番外側を先頭に置く
+    frames: Vec<Frame>,
+    /// 最上位の次の要素に付ける前置記号
+    prefixes: Vec<(&'static str, Span)>,
+    /// `pending` の先頭で読みかけのコメント
+    ///
+    /// 読んだところまでは `pending` から除くので、長いコメントを断片ごとに読み直さない。
+    comment: Option<Comment>,
+}
+
+impl Parser {
+    /// 何も読んでいない構文解析器を作る
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 入力の続きを解析する
+    ///
+    /// 断片の終わりで途切れているかもしれないトークンは、次の断片か [`Parser::finish`] まで読まずに残す。
+    ///
+    /// # 引数
+    /// * `chunk` - 入力の続き
+    ///
+    /// # 戻り値
+    /// * `Result<Vec<OwnedTokenTree>, ParseError>` - この断片で読み終えた最上位の式
+    ///   - 対応の取れない括弧や解析できない文字があればエラーを返す
+    pub fn feed(&mut self, chunk: &str) -> Result<Vec<OwnedTokenTree>, ParseError> {
+        self.pending.push_str(chunk);
+        self.parse_pending(false)
+    }
+
+    /// 入力の終わりまでを解析する
+    ///
+    /// # 戻り値
+    /// * `Result<Vec<OwnedTokenTree>, ParseError>` - 残りの入力で読み終えた最上位の式
+    ///   - 閉じられていない左括弧があればエラーを返す
+    pub fn finish(mut self) -> Result<Vec<OwnedTokenTree>, ParseError> {
+        let forms = self.parse_pending(true)?;
+        let end = self.offset;
+        if let Some(frame) = self.frames.last() {
+            return Err(ParseError::UnbalancedParen { span: frame.open });
+        }
+        dangling_prefix(&self.prefixes, Span::new(end, end), None)?;
+        Ok(forms)
+    }
+
+    fn parse_pending(&mut self, at_end: bool) -> Result<Vec<OwnedTokenTree>, ParseError> {
+        let mut forms = vec![];
+        let mut pos = 0;
+        loop {
+            if let Some(comment) = self.comment {
+                let rest = self.pending.get(pos..).unwrap_or_default();
+                let (read, comment) = skip_comment(rest, comment);
+                pos += read;
+                self.comment = comment;
+                if comment.is_some() {
+                    // 閉じていないブロックコメントは入力の終わりまで続く
+                    if at_end {
+                        pos = self.pending.len();
+                        self.comment = None;
+                    }
+                    break;
+                }
+            }
+            let rest = self.pending.get(pos..).unwrap_or_default();
+            if skip_trivia(rest).is_empty() {
+                // 最後のコメントは次の断片に続くかもしれないので、先頭から読みかけのコメントとして読む
+                match split_trivia(rest).pop() {
+                    Some((TriviaKind::LineComment, span)) if !at_end => {
+                        pos += span.start;
+                        self.comment = Some(Comment::Line);
+                        continue;
+                    }
+                    Some((TriviaKind::BlockComment, span)) if !at_end => {
+                        pos += span.start;
+                        self.comment = Some(Comment::Block { depth: 0 });
+                        continue;
+                    }
+                    _ => pos = self.pending.len(),
+                }
+                break;
+            }
+            let (after, span, token) = match token_with(rest, self.offset + pos, false) {
+                // 入力の終わりで止まったトークンは、続きを読めば長くなるかもしれない
+                Ok((after, ..)) if after.is_empty() && !at_end => break,
+                Err(e)
+                    if !at_end
+                        && rest
+                            .get(e.offset().saturating_sub(self.offset + pos)..)
+                            .is_some_and(|rest| unfinished(rest, &e)) =>
+                {
+                    break
+                }
+                Ok(token) => token,
+                Err(e) => return Err(e.into()),
+            };
+            let token = match token {
+                // S式には文もブロックも無いので、中置記法の記号は読めない文字として扱う
+                Token::Semicolon
+                | Token::LBrace
+                | Token::RBrace
+                | Token::LBracket
+                | Token::RBracket => {
+                    let found = rest
+                        .get(span.start.saturating_sub(self.offset + pos)..)
+                        .and_then(|rest| rest.chars().next());
+                    return Err(ParseError::unexpected(span.start, Expected::Token, found));
+                }
+                token => token.to_owned(),
+            };
+            pos = self.pending.len() - after.len();
+            if let Some(form) = self.token(token, span)? {
+                forms.push(form);
+            }
+        }
+        self.pending.drain(..pos);
+        self.offset += pos;
+        Ok(forms)
+    }
+
+    /// トークンを1つ読み、最上位の式を読み終えたらそれを返す
+    fn token(
+        &mut self,
+        token: OwnedToken,
+        span: Span,
+    ) -> Result<Option<OwnedTokenTree>, ParseError> {
+        let node = match token {
+            OwnedToken::Ident(name) if quote_form(name.as_str()).is_some() => {
+                let form = quote_form(name.as_str()).expect("checked above");
+                self.prefixes_mut().push((form, span));
+                return Ok(None);
+            }
+            OwnedToken::LParen => {
+                self.frames.push(Frame {
+                    open: span,
+                    children: vec![],
+                    prefixes: vec![],
+                });
+                return Ok(None);
+            }
+            OwnedToken::RParen => {
+                let Some(frame) = self.frames.pop() else {
+                    return Err(ParseError::UnbalancedParen { span });
+                };
+                dangling_prefix(&frame.prefixes, span, Some(')'))?;
+                OwnedTokenTree::Tree(frame.children, frame.open.merge(span))
+            }
+            token => OwnedTokenTree::Token(token, span),
+        };
+        Ok(self.push(node))
+    }
+
+    fn prefixes_mut(&mut self) -> &mut Vec<(&'static str, Span)> {
+        match self.frames.last_mut() {
+            Some(frame) => &mut frame.prefixes,
+            None => &mut self.prefixes,
+        }
+    }
+
+    /// 読み終えた要素に前置記号を付け、閉じていない括弧の中か最上位に置く
+    fn push(&mut self, mut node: OwnedTokenTree) -> Option<OwnedTokenTree> {
+        // `'x` は `(quote x)` と同じ木にする
+        while let Some((form, prefix)) = self.prefixes_mut().pop() {
+            let span = prefix.merge(node.span());
+            let head = OwnedTokenTree::Token(OwnedToken::Ident(form.into()), prefix);
+            node = OwnedTokenTree::Tree(vec![head, node], span);
+        }
+        match self.frames.last_mut() {
+            Some(frame) => {
+                frame.children.push(node);
+                None
+            }
+            None => Some(node),
+        }
+    }
+}
+
+/// 字句解析のエラーが、続きの入力を読めば解消するかもしれないなら `true`
+///
+/// `0x` のように入力の終わりで止まった数値は、空白や括弧で区切られるまで読み続ける。
+fn unfinished(rest: &str, e: &LexError) -> bool {
+    matches!(e, LexError::Unexpected { found: None, .. })
+        || !rest.contains(|c: char| c.is_whitespace() || matches!(c, '(' | ')'))
+}
+
+/// 読みかけのコメントの続きを読む
+///
+/// # 戻り値
+/// * `(usize, Option<Comment>)` - (読み終えたバイト数, コメントが終わっていなければその状態)のタプル
+///   - ブロックコメントの最後の `/` や `*` は、次の断片と合わせて読むので残す
+fn skip_comment(rest: &str, comment: Comment) -> (usize, Option<Comment>) {
+    let mut depth = match comment {
+        Comment::Line => {
+            return match rest.find('\n') {
+                Some(end) => (end, None),
+                None => (rest.len(), Some(comment)),
+            }
+        }
+        Comment::Block { depth } => depth,
+    };
+    let bytes = rest.as_bytes();
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        match &bytes[i..i + 2] {
+            b"/*" => {
+                depth += 1;
+                i += 2;
+            }
+            b"*/" => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return (i, None);
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    if bytes.get(i).is_some_and(|b| !matches!(b, b'/' | b'*')) {
+        i = bytes.len();
+    }
+    (i, Some(Comment::Block { depth }))
+}
+
+/// 続く要素の無い前置記号をエラーにする
+fn dangling_prefix(
+    prefixes: &[(&'static str, Span)],
+    at: Span,
+    found: Option<char>,
+) -> Result<(), ParseError> {
+    if prefixes.is_empty() {
+        Ok(())
+    } else {
+        Err(ParseError::unexpected(
+            at.start,
+            Expected::Expression,
+            found,
+        ))
+    }
+}
+
+/// [`parse_reader`] が失敗したときのエラー
+#[derive(Debug)]
+pub enum StreamError {
+    /// 入力の読み込みに失敗した、または入力が UTF-8 ではなかった
+    Io(io::Error),
+    /// 入力を解析できなかった
+    Parse(ParseError),
+}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ParseError> for StreamError {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::Parse(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+/// 入力を一定の大きさずつ読みながら解析する関数
+///
+/// 文字の途中で区切れた UTF-8 のバイト列は、続きを読んでから解析する。
+///
+/// # 引数
+/// * `reader` - 解析する入力
+/// * `each` - 最上位の式を読み終えるたびに呼び出す関数
+///
+/// # 戻り値
+/// * `Result<(), StreamError>` - 入力の読み込みか解析に失敗した場合はエラーを返す
+pub fn parse_reader(
+    mut reader: impl Read,
+    mut each: impl FnMut(OwnedTokenTree),
+) -> Result<(), StreamError> {
+    let mut parser = Parser::new();
+    let mut buf = vec![0; 64 * 1024];
+    // 前回の読み込みの末尾にあった、文字の途中までのバイト列の長さ
+    let mut carry = 0;
+    loop {
+        let n = match reader.read(&mut buf[carry..]) {
+            Ok(0) if carry > 0 => return Err(invalid_utf8().into()),
+            Ok(0) => break,
+            Ok(n) => carry + n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let valid = match std::str::from_utf8(&buf[..n]) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err(invalid_utf8().into()),
+        };
+        let chunk = std::str::from_utf8(&buf[..valid]).expect("checked above");
+        parser.feed(chunk)?.into_iter().for_each(&mut each);
+        buf.copy_within(valid..n, 0);
+        carry = n - valid;
+    }
+    parser.finish()?.into_iter().for_each(each);
+    Ok(())
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::source;
+    use crate::TokenTree;
+
+    /// 入力全体を [`source`] で解析した結果の、最上位の要素
+    fn whole(input: &str) -> Result<Vec<OwnedTokenTree>, ParseError> {
+        let TokenTree::Tree(forms, _) = source(input)? else {
+            unreachable!("source() always returns a tree");
+        };
+        Ok(forms.iter().map(TokenTree::to_owned).collect())
+    }
+
+    /// 入力を `size` バイトごとの断片に区切って解析する
+    fn chunked(input: &str, size: usize) -> Result<Vec<OwnedTokenTree>, ParseError> {
+        let mut parser = Parser::new();
+        let mut forms = vec![];
+        let mut rest = input;
+        while !rest.is_empty() {
+            let mut end = size.min(rest.len());
+            while !rest.is_char_boundary(end) {
+                end += 1;
+            }
+            forms.extend(parser.feed(&rest[..end])?);
+            rest = &rest[end..];
+        }
+        forms.extend(parser.finish()?);
+        Ok(forms)
+    }
+
+    #[test]
+    fn test_matches_source() {
+        for input in [
+            r#"(define x 12.5e3) (f "a \" b" 'x `(1 ,y)) ; "#,
+            "(a /* (nested /* comment */) */ b) // tail",
+            "(変数 -1 +2 0x1F) (<= a b) nil true",
+            "(a (b (c)))  ",
+            "(define x\r\n\t(+ 1 2))\n// done\n",
+        ] {
+            for size in 1..=input.len() {
+                assert_eq!(chunked(input, size), whole(input), "{input:?} by {size}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_long_comments() {
+        // 読みかけのコメントは読んだところまで捨て、断片ごとに先頭から読み直さない
+        let mut parser = Parser::new();
+        assert!(parser.feed("(a /* x").unwrap().is_empty());
+        for _ in 0..10_000 {
+            assert!(parser.feed(" /* y */ (z) /").unwrap().is_empty());
+            assert_eq!(parser.pending, "/");
+        }
+        let forms = parser.feed("/ */ b) // tail").unwrap();
+        assert_eq!(forms.len(), 1);
+        assert!(parser.pending.is_empty());
+        assert!(parser.feed(" too\n1").unwrap().is_empty());
+        assert_eq!(parser.finish().unwrap().len(), 1);
+
+        let input = "(a /*/ b */ c) /**/ // x\n(d /* e */) /* f /* g */ h";
+        for size in 1..=input.len() {
+            assert_eq!(chunked(input, size), whole(input), "{input:?} by {size}");
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        for input in ["(a (b)", "a)", "(a ')", "'", "(a @)", r#"("abc"#] {
+            for size in 1..=input.len() {
+                assert_eq!(chunked(input, size), whole(input), "{input:?} by {size}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_reader() {
+        let input = "(a \"é\") ".repeat(20000);
+        let mut count = 0;
+        parse_reader(input.as_bytes(), |form| {
+            assert!(matches!(form, OwnedTokenTree::Tree(..)));
+            count += 1;
+        })
+        .unwrap();
+        assert_eq!(count, 20000);
+        assert!(matches!(
+            parse_reader(&b"(a \xff)"[..], |_| {}),
+            Err(StreamError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,186 @@
+//! 字句解析を済ませたトークン列を、先読みしながら先頭から読み進めるための型
+//!
+//! 構文解析器は [`TokenStream`] の [`peek_nth`](TokenStream::peek_nth) で先のトークンを見て規則を選び、
+//! [`eat_if`](TokenStream::eat_if) で省略できる記号を、[`expect`](TokenStream::expect)
+//! で必ず現れる記号を読む。読めなかった位置と期待していた要素は [`ParseError`] にまとめて返す。
+//!
+//! ```
+//! use ruscal_b::parser::Expected;
+//! use ruscal_b::token_stream::TokenStream;
+//! use ruscal_b::{Span, Token};
+//!
+//! let mut tokens = TokenStream::infix("f(1)").unwrap();
+//! assert_eq!(tokens.peek_nth(1), Some(&(Span::new(1, 2), Token::LParen)));
+//! assert_eq!(tokens.eat_if(|t| matches!(t, Token::Ident(_))), Some(Span::new(0, 1)));
+//! assert_eq!(tokens.expect(&Token::LParen, Expected::LParen), Ok(Span::new(1, 2)));
+//! assert!(tokens.expect(&Token::RParen, Expected::RParen).is_err());
+//! ```
+
+use crate::ast::{Span, Token};
+use crate::lexer::Lexer;
+use crate::parser::{Expected, ParseError};
+
+/// 先読みできるトークン列
+#[derive(Debug)]
+pub struct TokenStream<'src> {
+    src: &'src str,
+    tokens: Vec<(Span, Token<'src>)>,
+    pos: usize,
+}
+
+impl<'src> TokenStream<'src> {
+    /// `src` を字句解析したトークン列から作る
+    ///
+    /// # 引数
+    /// * `src` - トークンの範囲が指す入力。エラーに入れる文字を取り出すのに使う
+    /// * `tokens` - 現れた順のトークンとその範囲
+    pub fn new(src: &'src str, tokens: Vec<(Span, Token<'src>)>) -> Self {
+        Self {
+            src,
+            tokens,
+            pos: 0,
+        }
+    }
+
+    /// 入力全体を [`Lexer::infix`] で中置記法のトークン列に分割して作る
+    ///
+    /// # 戻り値
+    /// * `Result<TokenStream, ParseError>` - 字句解析に失敗した場合は最初のエラーを返す
+    pub fn infix(src: &'src str) -> Result<Self, ParseError> {
+        let tokens = Lexer::infix(src).collect::<Result<_, _>>()?;
+        Ok(Self::new(src, tokens))
+    }
+
+    /// 次のトークンを読み進めずに見る
+    pub fn peek(&self) -> Option<&(Span, Token<'src>)> {
+        self.peek_nth(0)
+    }
+
+    /// 次から数えて `n` 番目のトークンを読み進めずに見る。`peek_nth(0)` は [`peek`](Self::peek) と同じ
+    pub fn peek_nth(&self, n: usize) -> Option<&(Span, Token<'src>)> {
+        self.tokens.get(self.pos + n)
+    }
+
+    /// 次のトークンを読み進める
+    pub fn bump(&mut self) -> Option<(Span, &Token<'src>)> {
+        let (span, token) = self.tokens.get(self.pos)?;
+        self.pos += 1;
+        Some((*span, token))
+    }
+
+    /// 直前に読み進めたトークンを読んでいない状態に戻す
+    pub fn back(&mut self) {
+        self.pos = self.pos.saturating_sub(1);
+    }
+
+    /// 次のトークンが `pred` を満たすときだけ読み進める
+    ///
+    /// # 戻り値
+    /// * `Option<Span>` - 読み進めたトークンの範囲。読み進めなければ `None`
+    pub fn eat_if(&mut self, pred: impl FnOnce(&Token<'src>) -> bool) -> Option<Span> {
+        match self.peek() {
+            Some((span, token)) if pred(token) => {
+                let span = *span;
+                self.pos += 1;
+                Some(span)
+            }
+            _ => None,
+        }
+    }
+
+    /// 次のトークンが `token` と等しいときだけ読み進める
+    pub fn eat(&mut self, token: &Token) -> Option<Span> {
+        self.eat_if(|t| t == token)
+    }
+
+    /// 次のトークンとして `token` を読む
+    ///
+    /// # 戻り値
+    /// * `Result<Span, ParseError>` - 読んだトークンの範囲
+    ///   - 別のトークンが続くか入力が終わっていれば、その位置で `expected` を期待していたことを表すエラーを返す
+    pub fn expect(&mut self, token: &Token, expected: Expected) -> Result<Span, ParseError> {
+        self.eat(token).ok_or_else(|| self.error_here(expected))
+    }
+
+    /// 入力の終わりまで読み進めたなら `true`
+    pub fn is_at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    /// 次のトークンの位置、または入力の終わりで `expected` を期待していたことを表すエラーを作る
+    pub fn error_here(&self, expected: Expected) -> ParseError {
+        match self.peek() {
+            Some((span, _)) => self.error_at(*span, expected),
+            None => self.error_at_end(expected),
+        }
+    }
+
+    /// `span` の位置で `expected` を期待していたことを表すエラーを作る
+    pub fn error_at(&self, span: Span, expected: Expected) -> ParseError {
+        let found = self.src.get(span.start..).and_then(|s| s.chars().next());
+        ParseError::unexpected(span.start, expected, found)
+    }
+
+    /// 入力の終わりで `expected` を期待していたことを表すエラーを作る
+    pub fn error_at_end(&self, expected: Expected) -> ParseError {
+        ParseError::unexpected(self.src.len(), expected, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peek() {
+        let mut tokens = TokenStream::infix("a + 1").unwrap();
+        assert_eq!(tokens.peek(), Some(&(Span::new(0, 1), Token::Ident("a"))));
+        assert_eq!(tokens.peek_nth(2), Some(&(Span::new(4, 5), Token::Int(1))));
+        assert_eq!(tokens.peek_nth(3), None);
+        assert_eq!(tokens.bump(), Some((Span::new(0, 1), &Token::Ident("a"))));
+        assert_eq!(tokens.peek_nth(1), Some(&(Span::new(4, 5), Token::Int(1))));
+        tokens.back();
+        assert_eq!(tokens.peek(), Some(&(Span::new(0, 1), Token::Ident("a"))));
+        while tokens.bump().is_some() {}
+        assert!(tokens.is_at_end());
+        assert_eq!(tokens.peek(), None);
+    }
+
+    #[test]
+    fn test_eat_and_expect() {
+        let mut tokens = TokenStream::infix("; x").unwrap();
+        assert_eq!(tokens.eat(&Token::RBrace), None);
+        assert_eq!(tokens.eat(&Token::Semicolon), Some(Span::new(0, 1)));
+        assert_eq!(
+            tokens.expect(&Token::LBrace, Expected::LBrace),
+            Err(ParseError::unexpected(2, Expected::LBrace, Some('x')))
+        );
+        assert_eq!(
+            tokens.eat_if(|t| matches!(t, Token::Ident(_))),
+            Some(Span::new(2, 3))
+        );
+        assert_eq!(
+            tokens.expect(&Token::RBrace, Expected::RBrace),
+            Err(ParseError::unexpected(3, Expected::RBrace, None))
+        );
+        assert!(TokenStream::infix("\"a").is_err());
+    }
+
+    #[test]
+    fn test_error_at_char_boundary() {
+        let tokens = TokenStream::new("é", vec![]);
+        // 文字の途中や入力の外を指す範囲でも、パニックせずに文字を `None` とする
+        assert_eq!(
+            tokens.error_at(Span::new(1, 2), Expected::Token),
+            ParseError::unexpected(1, Expected::Token, None)
+        );
+        assert_eq!(
+            tokens.error_at(Span::new(9, 9), Expected::Token),
+            ParseError::unexpected(9, Expected::Token, None)
+        );
+        assert_eq!(
+            tokens.error_at(Span::new(0, 2), Expected::Token),
+            ParseError::unexpected(0, Expected::Token, Some('é'))
+        );
+    }
+}
@@ -0,0 +1,1441 @@
+//! 型の決まるプログラムを、単独でコンパイルできる Rust のソースファイルに変換するバックエンド
+//!
+//! 最上位で定義した関数は Rust の関数に、それ以外の最上位の文は `main` 関数の本体になる。
+//! 整数、浮動小数点数、真偽値、文字列、`nil` をそれぞれ `i64`、`f64`、`bool`、`String`、`()` で表し、
+//! 演算は Rust の演算子と、整数の桁あふれと0での除算を評価器と同じ説明で報告して終了する
+//! 小さな補助関数に変換する。最後の文の値は、`ruscal run` と同じく終わりに表示する。
+//!
+//! 関数の仮引数の型は、型注釈があればそれを、なければ最初に呼び出した場所の引数の型を使う。
+//! 戻り値の型も注釈が無ければ本体から推論する。一度も呼び出さず注釈も無い関数は書き出さない。
+//! 関数値、配列、マップ、`match` など、Rust の値で表せない構文は [`TranspileError`] で拒否する。
+//!
+//! ```
+//! use ruscal_b::statements;
+//! use ruscal_b::transpile::transpile;
+//!
+//! let program = statements("fn square(x: i64) -> i64 { x * x }; square(7)").unwrap();
+//! let rust = transpile(&program).unwrap();
+//! assert!(rust.contains("fn square(x: i64) -> i64 {"));
+//! assert!(rust.contains("rt::mul(x, x)"));
+//! ```
+//!
+//! S式のプログラムは、最上位の式を [`Statement::Expr`] で包めば変換できる。
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use crate::ast::{BinOp, Expr, ExprKind, Span, Statement, TypeName, UnOp};
+use crate::intern::Symbol;
+
+/// 式の型。`None` は推論の途中でまだ決まっていないか、`return` のように値にならない式
+pub(crate) type Ty = Option<TypeName>;
+
+/// Rust のソースや WebAssembly のモジュールに変換できないプログラムの誤り
+#[derive(Debug, Clone, PartialEq)]
+pub enum TranspileError {
+    /// 変換先の値で表せない構文を使った。`construct` はその構文の説明
+    Unsupported { construct: &'static str, span: Span },
+    /// 式の型を決められない
+    CannotInfer { span: Span },
+    /// 期待する型と異なる型の式がある
+    Mismatch {
+        expected: TypeName,
+        found: TypeName,
+        span: Span,
+    },
+    /// 数値を期待する演算に数値以外の式を渡した
+    NotANumber { found: TypeName, span: Span },
+    /// 局所変数でも最上位の関数でもない名前を使った
+    UnknownIdentifier { name: String, span: Span },
+    /// 関数に渡した引数の数が仮引数の数と合わない
+    Arity {
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    /// 一度も呼び出さず型注釈も無いので、型の決まらない関数を公開しようとした
+    Unexported { name: String, span: Span },
+}
+
+impl TranspileError {
+    /// エラーの種類ごとに決まった、変わらない番号
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Unsupported { .. } => "E0301",
+            Self::CannotInfer { .. } => "E0302",
+            Self::Mismatch { .. } => "E0303",
+            Self::NotANumber { .. } => "E0304",
+            Self::UnknownIdentifier { .. } => "E0305",
+            Self::Arity { .. } => "E0306",
+            Self::Unexported { .. } => "E0307",
+        }
+    }
+
+    /// 誤りのある式の範囲
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Unsupported { span, .. }
+            | Self::CannotInfer { span }
+            | Self::Mismatch { span, .. }
+            | Self::NotANumber { span, .. }
+            | Self::UnknownIdentifier { span, .. }
+            | Self::Arity { span, .. }
+            | Self::Unexported { span, .. } => *span,
+        }
+    }
+
+    /// 位置を含まない誤りの説明
+    pub fn message(&self) -> String {
+        match self {
+            Self::Unsupported { construct, .. } => {
+                format!("cannot compile {construct} ahead of time")
+            }
+            Self::CannotInfer { .. } => {
+                "cannot infer the type of this expression; add type annotations to the function"
+                    .to_string()
+            }
+            Self::Mismatch {
+                expected, found, ..
+            } => format!(
+                "mismatched types: expected {}, found {}",
+                expected.name(),
+                found.name()
+            ),
+            Self::NotANumber { found, .. } => {
+                format!("expected a number, found {}", found.name())
+            }
+            Self::UnknownIdentifier { name, .. } => {
+                format!("`{name}` is neither a local variable nor a top-level function")
+            }
+            Self::Arity {
+                expected, found, ..
+            } => {
+                let plural = if *expected == 1 { "" } else { "s" };
+                format!("expected {expected} argument{plural}, found {found}")
+            }
+            Self::Unexported { name, .. } => format!(
+                "cannot export `{name}` because it is never called; \
+                 call it or add type annotations to its parameters"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for TranspileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message(), self.span().start)
+    }
+}
+
+impl std::error::Error for TranspileError {}
+
+/// 生成したファイルの先頭に置く注記と属性
+const HEADER: &str = "\
+// Generated by `ruscal transpile`.
+#![allow(unused_mut, unused_parens, unused_variables, unreachable_code, dead_code)]
+";
+
+/// 生成したファイルの末尾に置く、演算の補助関数
+const RUNTIME: &str = "\
+mod rt {
+    pub fn fail(message: &str) -> ! {
+        eprintln!(\"error: {message}\");
+        std::process::exit(1)
+    }
+
+    pub fn add(a: i64, b: i64) -> i64 {
+        a.checked_add(b).unwrap_or_else(|| fail(\"integer overflow\"))
+    }
+
+    pub fn sub(a: i64, b: i64) -> i64 {
+        a.checked_sub(b).unwrap_or_else(|| fail(\"integer overflow\"))
+    }
+
+    pub fn mul(a: i64, b: i64) -> i64 {
+        a.checked_mul(b).unwrap_or_else(|| fail(\"integer overflow\"))
+    }
+
+    pub fn div(a: i64, b: i64) -> i64 {
+        if b == 0 {
+            fail(\"integer division by zero\")
+        }
+        a.checked_div(b).unwrap_or_else(|| fail(\"integer overflow\"))
+    }
+
+    pub fn rem(a: i64, b: i64) -> i64 {
+        if b == 0 {
+            fail(\"integer division by zero\")
+        }
+        a.checked_rem(b).unwrap_or_else(|| fail(\"integer overflow\"))
+    }
+
+    pub fn neg(a: i64) -> i64 {
+        a.checked_neg().unwrap_or_else(|| fail(\"integer overflow\"))
+    }
+
+    pub fn abs(a: i64) -> i64 {
+        a.checked_abs().unwrap_or_else(|| fail(\"integer overflow\"))
+    }
+
+    pub fn truthy(n: f64) -> bool {
+        n != 0.0 && !n.is_nan()
+    }
+
+    pub fn quote(s: &str) -> String {
+        let mut quoted = String::from(\"\\\"\");
+        for c in s.chars() {
+            match c {
+                '\"' | '\\\\' => {
+                    quoted.push('\\\\');
+                    quoted.push(c);
+                }
+                '\\n' => quoted.push_str(\"\\\\n\"),
+                '\\t' => quoted.push_str(\"\\\\t\"),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('\"');
+        quoted
+    }
+}
+";
+
+/// 生成した名前と衝突するので、そのままでは使えない名前
+const RESERVED: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "main", "match", "mod", "move", "mut", "override", "priv", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield", "_",
+];
+
+/// 最上位の文の並びを Rust のソースファイルに変換する関数
+///
+/// # 戻り値
+/// * `Result<String, TranspileError>` - `main` 関数を持つ Rust のソース
+///   - Rust の値で表せない構文や、型の合わない式があればエラーを返す
+pub fn transpile(statements: &[Statement]) -> Result<String, TranspileError> {
+    let program = Program::new(statements)?;
+    let mut transpiler = Transpiler {
+        functions: program.functions,
+        index: program.index,
+    };
+    let mut cx = Context::new(vec![], false, false);
+    let (body, ty) = transpiler.items(&mut cx, &program.main, false)?;
+    transpiler.instantiate_annotated()?;
+
+    let mut rust = format!("{HEADER}\n");
+    for function in &transpiler.functions {
+        if let Some(code) = &function.code {
+            let _ = writeln!(rust, "{code}\n");
+        }
+    }
+    let body = match ty {
+        Some(TypeName::Nil) | None => format!("{}\nprintln!(\"nil\");", statement(body)),
+        Some(ty) => {
+            let (value, ty) = (String::from("value"), Some(ty));
+            format!(
+                "let value = {};\n{}",
+                block(&body),
+                print(&[(value, ty)], Span::new(0, 0), true, false)?
+            )
+        }
+    };
+    let _ = writeln!(rust, "fn main() {}\n", block(&body));
+    rust.push_str(RUNTIME);
+    Ok(rust)
+}
+
+/// 最上位の文を、関数の定義とそれ以外の文に分けたもの
+pub(crate) struct Program<'a, C> {
+    pub(crate) functions: Vec<Function<'a, C>>,
+    /// 関数の名前から `functions` の位置を引く表
+    pub(crate) index: HashMap<Symbol, usize>,
+    /// 関数の定義以外の最上位の文
+    pub(crate) main: Vec<Item<'a>>,
+}
+
+impl<'a, C> Program<'a, C> {
+    /// 最上位の `fn` の定義を関数にし、残りの文を並べる
+    pub(crate) fn new(statements: &'a [Statement]) -> Result<Self, TranspileError> {
+        let mut program = Self {
+            functions: vec![],
+            index: HashMap::new(),
+            main: vec![],
+        };
+        for statement in statements {
+            let (name, expr) = match item(statement)? {
+                Item::Define(name, expr) if matches!(expr.kind, ExprKind::Fn { .. }) => {
+                    (name, expr)
+                }
+                item => {
+                    program.main.push(item);
+                    continue;
+                }
+            };
+            let ExprKind::Fn {
+                params,
+                body,
+                signature,
+            } = &expr.kind
+            else {
+                unreachable!("only function definitions reach here");
+            };
+            if program.index.contains_key(&name) {
+                return Err(TranspileError::Unsupported {
+                    construct: "a redefinition of a function",
+                    span: statement.span(),
+                });
+            }
+            let signature = signature.as_ref();
+            program.index.insert(name, program.functions.len());
+            program.functions.push(Function {
+                name,
+                params,
+                body,
+                span: expr.span,
+                param_types: signature
+                    .and_then(|signature| signature.params.iter().copied().collect()),
+                ret: signature.and_then(|signature| signature.ret),
+                state: State::Pending,
+                code: None,
+            });
+        }
+        Ok(program)
+    }
+}
+
+/// 最上位で定義した関数
+pub(crate) struct Function<'a, C> {
+    pub(crate) name: Symbol,
+    pub(crate) params: &'a [Symbol],
+    pub(crate) body: &'a [Expr],
+    pub(crate) span: Span,
+    /// 仮引数の型。注釈が無く、まだ呼び出していなければ `None`
+    pub(crate) param_types: Option<Vec<TypeName>>,
+    /// 戻り値の型。注釈が無く、まだ推論していなければ `None`
+    pub(crate) ret: Ty,
+    pub(crate) state: State,
+    /// 変換した関数
+    pub(crate) code: Option<C>,
+}
+
+/// 関数を変換する段階
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    /// まだ変換していない
+    Pending,
+    /// 本体を変換している途中。本体から再帰で呼び出したときにこの状態になる
+    Converting,
+    /// 変換を終えた
+    Done,
+}
+
+/// 最上位の関数の型を、型注釈と呼び出した場所から決めながら変換するバックエンド
+///
+/// 関数は型の決まった最初の呼び出しで変換する。戻り値の型の注釈が無ければ、本体を一度仮に
+/// 変換して型を推論してから、決まった型でもう一度変換する。
+pub(crate) trait Backend<'a> {
+    /// 変換した関数
+    type Code;
+
+    /// 最上位の関数の一覧
+    fn functions(&mut self) -> &mut [Function<'a, Self::Code>];
+
+    /// 関数の本体を変換し、変換した関数と本体の型を返す
+    ///
+    /// `tentative` なら戻り値の型を推論するための仮の変換で、再帰した呼び出しの型はまだ決まっていない。
+    fn function_body(
+        &mut self,
+        id: usize,
+        tentative: bool,
+    ) -> Result<(Self::Code, Ty), TranspileError>;
+
+    /// 関数を引数の型で呼び出せるか確かめ、まだなら本体を変換して、戻り値の型を返す
+    fn instantiate(
+        &mut self,
+        tentative: bool,
+        id: usize,
+        args: &[Ty],
+        span: Span,
+    ) -> Result<Ty, TranspileError> {
+        let function = &mut self.functions()[id];
+        if args.len() != function.params.len() {
+            return Err(TranspileError::Arity {
+                expected: function.params.len(),
+                found: args.len(),
+                span,
+            });
+        }
+        match &function.param_types {
+            Some(types) => {
+                for (&expected, &arg) in types.iter().zip(args) {
+                    join(Some(expected), arg, span)?;
+                }
+            }
+            None => match args.iter().copied().collect::<Option<Vec<_>>>() {
+                Some(types) => function.param_types = Some(types),
+                None if tentative => return Ok(None),
+                None => return Err(TranspileError::CannotInfer { span }),
+            },
+        }
+        if function.state != State::Pending {
+            return Ok(function.ret);
+        }
+        function.state = State::Converting;
+        if function.ret.is_none() {
+            let (_, ret) = self.function_body(id, true)?;
+            let function = &mut self.functions()[id];
+            let span = function.span;
+            function.ret = Some(ret.ok_or(TranspileError::CannotInfer { span })?);
+        }
+        let (code, _) = self.function_body(id, false)?;
+        let function = &mut self.functions()[id];
+        function.code = Some(code);
+        function.state = State::Done;
+        Ok(function.ret)
+    }
+
+    /// 呼び出していなくても、型注釈で型の決まる関数を変換する
+    fn instantiate_annotated(&mut self) -> Result<(), TranspileError> {
+        for id in 0..self.functions().len() {
+            let function = &self.functions()[id];
+            if let Some(types) = function.param_types.clone() {
+                let args: Vec<_> = types.into_iter().map(Some).collect();
+                let span = function.span;
+                self.instantiate(false, id, &args, span)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rust のソースに変換するバックエンド
+struct Transpiler<'a> {
+    functions: Vec<Function<'a, String>>,
+    index: HashMap<Symbol, usize>,
+}
+
+/// 局所変数
+struct Local {
+    /// Rust のソースでの名前
+    rust: String,
+    ty: Ty,
+}
+
+/// 1つの関数の本体か、`main` の本体を変換している間の状態
+struct Context {
+    scopes: Vec<HashMap<Symbol, Local>>,
+    /// 関数の本体なら `true`。`return` を使える
+    function: bool,
+    /// 戻り値の型を推論するための仮の変換なら `true`
+    ///
+    /// 再帰した呼び出しの型がまだ決まっていないので、型の決まらない式を誤りにしない。
+    tentative: bool,
+    /// `return` で返す値の型
+    returns: Vec<(Ty, Span)>,
+    /// 囲んでいるループの数
+    loops: usize,
+    /// 本体で代入した仮引数
+    assigned: Vec<Symbol>,
+}
+
+impl Context {
+    fn new(params: Vec<(Symbol, TypeName)>, function: bool, tentative: bool) -> Self {
+        let scope = params
+            .into_iter()
+            .map(|(name, ty)| {
+                let local = Local {
+                    rust: rust_name(name.as_str()),
+                    ty: Some(ty),
+                };
+                (name, local)
+            })
+            .collect();
+        Self {
+            scopes: vec![scope],
+            function,
+            tentative,
+            returns: vec![],
+            loops: 0,
+            assigned: vec![],
+        }
+    }
+
+    fn lookup(&mut self, name: Symbol) -> Option<&mut Local> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(&name))
+    }
+
+    /// 型が決まっていなければ、仮の変換でない限り誤りにする
+    fn require(&self, ty: Ty, span: Span) -> Result<Ty, TranspileError> {
+        match ty {
+            None if !self.tentative => Err(TranspileError::CannotInfer { span }),
+            ty => Ok(ty),
+        }
+    }
+}
+
+/// 式の並びや文の並びの1つの要素
+#[derive(Clone, Copy)]
+pub(crate) enum Item<'a> {
+    Define(Symbol, &'a Expr),
+    Assign(Symbol, &'a Expr, Span),
+    Expr(&'a Expr),
+}
+
+/// 文を並びの要素にする
+pub(crate) fn item(statement: &Statement) -> Result<Item<'_>, TranspileError> {
+    Ok(match statement {
+        Statement::VarDef { name, value, .. } => Item::Define(*name, value),
+        Statement::Assignment { name, value, span } => Item::Assign(*name, value, *span),
+        Statement::IndexAssignment { span, .. } => {
+            return Err(TranspileError::Unsupported {
+                construct: "an index assignment",
+                span: *span,
+            })
+        }
+        Statement::Expr(expr) => expr_item(expr),
+    })
+}
+
+/// 式を並びの要素にする。S式の `define` は変数の定義になる
+pub(crate) fn expr_item(expr: &Expr) -> Item<'_> {
+    match &expr.kind {
+        ExprKind::Define { name, value } => Item::Define(*name, value),
+        _ => Item::Expr(expr),
+    }
+}
+
+impl<'a> Backend<'a> for Transpiler<'a> {
+    type Code = String;
+
+    fn functions(&mut self) -> &mut [Function<'a, String>] {
+        &mut self.functions
+    }
+
+    fn function_body(
+        &mut self,
+        id: usize,
+        tentative: bool,
+    ) -> Result<(String, Ty), TranspileError> {
+        let function = &self.functions[id];
+        let (name, params, body, span, ret) = (
+            function.name,
+            function.params,
+            function.body,
+            function.span,
+            function.ret,
+        );
+        let types = function
+            .param_types
+            .clone()
+            .expect("parameter types are known before converting the body");
+        let typed: Vec<_> = params.iter().copied().zip(types.iter().copied()).collect();
+        let mut cx = Context::new(typed.clone(), true, tentative);
+        // 中置記法の関数の本体は1つのブロックなので、中の文を直接並べる
+        let (code, ty) = match body {
+            [body] => self.body(&mut cx, body, false)?,
+            _ => {
+                let items: Vec<_> = body.iter().map(expr_item).collect();
+                self.items(&mut cx, &items, false)?
+            }
+        };
+        let mut found = ty;
+        for &(ty, span) in &cx.returns {
+            found = join(found, ty, span)?;
+        }
+        let ty = join(ret, found, span)?;
+
+        let params: Vec<_> = typed
+            .iter()
+            .map(|(param, ty)| {
+                let mutable = if cx.assigned.contains(param) {
+                    "mut "
+                } else {
+                    ""
+                };
+                format!("{mutable}{}: {}", rust_name(param.as_str()), rust_type(*ty))
+            })
+            .collect();
+        let ret = match ty {
+            Some(TypeName::Nil) | None => String::new(),
+            Some(ty) => format!(" -> {}", rust_type(ty)),
+        };
+        let code = format!(
+            "fn {}({}){ret} {}",
+            rust_name(name.as_str()),
+            params.join(", "),
+            block(&code)
+        );
+        Ok((code, ty))
+    }
+}
+
+impl Transpiler<'_> {
+    /// 並びを新しいスコープで変換し、文を並べたソースと最後の要素の型を返す
+    ///
+    /// `discard` なら並びの値を使わないので、最後の要素も値を捨てる文にする。
+    fn items(
+        &mut self,
+        cx: &mut Context,
+        items: &[Item],
+        discard: bool,
+    ) -> Result<(String, Ty), TranspileError> {
+        cx.scopes.push(HashMap::new());
+        let res = self.items_in_scope(cx, items, discard);
+        cx.scopes.pop();
+        res
+    }
+
+    fn items_in_scope(
+        &mut self,
+        cx: &mut Context,
+        items: &[Item],
+        discard: bool,
+    ) -> Result<(String, Ty), TranspileError> {
+        let mut lines = vec![];
+        let mut last = (String::from("()"), Some(TypeName::Nil));
+        for (i, item) in items.iter().enumerate() {
+            let is_last = i + 1 == items.len();
+            match *item {
+                Item::Define(name, value) => {
+                    let (code, ty) = self.expr(cx, value)?;
+                    let rust = rust_name(name.as_str());
+                    lines.push(format!("let mut {rust} = {};", unparen(&code)));
+                    cx.scopes
+                        .last_mut()
+                        .expect("a scope is pushed before converting items")
+                        .insert(
+                            name,
+                            Local {
+                                rust: rust.clone(),
+                                ty,
+                            },
+                        );
+                    last = (rust, ty);
+                }
+                Item::Assign(name, value, span) => {
+                    let (code, ty) = self.expr(cx, value)?;
+                    let is_param = cx.scopes[0].contains_key(&name)
+                        && !cx.scopes[1..].iter().any(|scope| scope.contains_key(&name));
+                    let Some(local) = cx.lookup(name) else {
+                        return Err(TranspileError::UnknownIdentifier {
+                            name: name.to_string(),
+                            span,
+                        });
+                    };
+                    local.ty = join(local.ty, ty, value.span)?;
+                    let rust = local.rust.clone();
+                    lines.push(format!("{rust} = {};", unparen(&code)));
+                    if is_param {
+                        cx.assigned.push(name);
+                    }
+                    last = (rust, ty);
+                }
+                Item::Expr(expr) if is_last && !discard => {
+                    let (code, ty) = self.expr(cx, expr)?;
+                    last = (unparen(&code), ty);
+                }
+                Item::Expr(expr) => {
+                    let code = self.discarded(cx, expr)?;
+                    lines.push(statement(code));
+                }
+            }
+        }
+        if discard {
+            return Ok((lines.join("\n"), Some(TypeName::Nil)));
+        }
+        if lines.is_empty() {
+            return Ok(last);
+        }
+        let (tail, ty) = last;
+        if ty != Some(TypeName::Nil) || !matches!(items.last(), Some(Item::Define(..))) {
+            lines.push(tail);
+        }
+        Ok((lines.join("\n"), ty))
+    }
+
+    /// 式の本体を変換する。`{ ... }` のブロックは中の文を直接並べる
+    fn body(
+        &mut self,
+        cx: &mut Context,
+        expr: &Expr,
+        discard: bool,
+    ) -> Result<(String, Ty), TranspileError> {
+        match &expr.kind {
+            ExprKind::Block(statements) => {
+                let items = statements.iter().map(item).collect::<Result<Vec<_>, _>>()?;
+                self.items(cx, &items, discard)
+            }
+            _ if discard => Ok((self.discarded(cx, expr)?, Some(TypeName::Nil))),
+            _ => {
+                let (code, ty) = self.expr(cx, expr)?;
+                Ok((unparen(&code), ty))
+            }
+        }
+    }
+
+    /// 値を使わない位置の式を変換する
+    ///
+    /// `if` とブロックの値も捨てるので、`if` の腕の型は揃っていなくてよい。
+    /// 値を持つ式は、使われない値を rustc が警告しないよう `let _ = ...;` で捨てる。
+    fn discarded(&mut self, cx: &mut Context, expr: &Expr) -> Result<String, TranspileError> {
+        match &expr.kind {
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let else_branch = else_branch.as_deref();
+                Ok(self
+                    .if_expr(cx, cond, then_branch, else_branch, false, true)?
+                    .0)
+            }
+            ExprKind::Block(_) => Ok(block(&self.body(cx, expr, true)?.0)),
+            ExprKind::Let { bindings, body } => {
+                let items = let_items(bindings, body);
+                Ok(block(&self.items(cx, &items, true)?.0))
+            }
+            ExprKind::Break | ExprKind::Continue | ExprKind::Return(_) => {
+                Ok(self.expr(cx, expr)?.0)
+            }
+            _ => match self.expr(cx, expr)? {
+                (code, Some(TypeName::Nil)) => Ok(unparen(&code)),
+                (code, _) => Ok(format!("let _ = {};", unparen(&code))),
+            },
+        }
+    }
+
+    /// 式を Rust の式に変換し、その型と一緒に返す
+    fn expr(&mut self, cx: &mut Context, expr: &Expr) -> Result<(String, Ty), TranspileError> {
+        let span = expr.span;
+        let unsupported = |construct| Err(TranspileError::Unsupported { construct, span });
+        Ok(match &expr.kind {
+            ExprKind::Int(n) => {
+                let code = match i32::try_from(*n) {
+                    Ok(_) if *n < 0 => format!("({n})"),
+                    Ok(_) => n.to_string(),
+                    Err(_) if *n < 0 => format!("({n}_i64)"),
+                    Err(_) => format!("{n}_i64"),
+                };
+                (code, Some(TypeName::Int))
+            }
+            ExprKind::Float(n) => {
+                let code = if n.is_nan() {
+                    "f64::NAN".to_string()
+                } else if n.is_infinite() {
+                    let sign = if *n < 0.0 { "-" } else { "" };
+                    format!("({sign}f64::INFINITY)")
+                } else if n.is_sign_negative() {
+                    format!("({n:?}_f64)")
+                } else {
+                    format!("{n:?}_f64")
+                };
+                (code, Some(TypeName::Float))
+            }
+            ExprKind::Str(s) => (format!("String::from({s:?})"), Some(TypeName::Str)),
+            ExprKind::Bool(b) => (b.to_string(), Some(TypeName::Bool)),
+            ExprKind::Nil => ("()".to_string(), Some(TypeName::Nil)),
+            ExprKind::Ident(name) => match cx.lookup(*name) {
+                Some(Local {
+                    rust,
+                    ty: ty @ Some(TypeName::Str),
+                }) => (format!("{rust}.clone()"), *ty),
+                Some(Local { rust, ty }) => (rust.clone(), *ty),
+                None if self.index.contains_key(name) => return unsupported("a function value"),
+                None => {
+                    return Err(TranspileError::UnknownIdentifier {
+                        name: name.to_string(),
+                        span,
+                    })
+                }
+            },
+            ExprKind::BinaryOp { op, lhs, rhs } => {
+                let (lhs_code, lhs_ty) = self.expr(cx, lhs)?;
+                let (rhs_code, rhs_ty) = self.expr(cx, rhs)?;
+                binary(
+                    *op,
+                    (lhs_code, lhs_ty, lhs.span),
+                    (rhs_code, rhs_ty, rhs.span),
+                )?
+            }
+            ExprKind::UnaryOp { op, operand } => {
+                let (code, ty) = self.expr(cx, operand)?;
+                match (op, ty) {
+                    (UnOp::Not, ty) => (format!("(!{})", truthy(&code, ty)), Some(TypeName::Bool)),
+                    (UnOp::Neg, Some(TypeName::Int)) => {
+                        (format!("rt::neg({})", unparen(&code)), ty)
+                    }
+                    (UnOp::Neg, Some(TypeName::Float) | None) => {
+                        (format!("(-{})", atom(&code)), ty)
+                    }
+                    (UnOp::Neg, Some(found)) => {
+                        return Err(TranspileError::NotANumber {
+                            found,
+                            span: operand.span,
+                        })
+                    }
+                }
+            }
+            ExprKind::Group(inner) => {
+                let (code, ty) = self.expr(cx, inner)?;
+                (atom(&code), ty)
+            }
+            ExprKind::Call { func, args } => self.call(cx, func, args, span)?,
+            ExprKind::Define { .. } => return unsupported("a definition inside an expression"),
+            ExprKind::Fn { .. } => return unsupported("a closure"),
+            ExprKind::Let { bindings, body } => {
+                let (code, ty) = self.items(cx, &let_items(bindings, body), false)?;
+                (block(&code), ty)
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let else_branch = else_branch.as_deref();
+                self.if_expr(cx, cond, then_branch, else_branch, false, false)?
+            }
+            ExprKind::Block(_) => {
+                let (code, ty) = self.body(cx, expr, false)?;
+                (block(&code), ty)
+            }
+            ExprKind::While { cond, body } => {
+                let (cond, cond_ty) = self.expr(cx, cond)?;
+                cx.loops += 1;
+                let res = self.body(cx, body, true);
+                cx.loops -= 1;
+                let (body, _) = res?;
+                let code = format!(
+                    "while {} {}",
+                    unparen(&truthy(&cond, cond_ty)),
+                    block(&statement(body))
+                );
+                (code, Some(TypeName::Nil))
+            }
+            ExprKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                let mut bound = |expr: &Expr| -> Result<String, TranspileError> {
+                    let (code, ty) = self.expr(cx, expr)?;
+                    join(Some(TypeName::Int), ty, expr.span)?;
+                    Ok(atom(&code))
+                };
+                let (start, end) = (bound(start)?, bound(end)?);
+                let rust = rust_name(var.as_str());
+                let local = Local {
+                    rust: rust.clone(),
+                    ty: Some(TypeName::Int),
+                };
+                cx.scopes.push(HashMap::from([(*var, local)]));
+                cx.loops += 1;
+                let res = self.body(cx, body, true);
+                cx.loops -= 1;
+                cx.scopes.pop();
+                let (body, _) = res?;
+                let code = format!(
+                    "for mut {rust} in {start}..{end} {}",
+                    block(&statement(body))
+                );
+                (code, Some(TypeName::Nil))
+            }
+            ExprKind::Break | ExprKind::Continue if cx.loops == 0 => {
+                return unsupported("a jump outside of a loop")
+            }
+            ExprKind::Break => ("break".to_string(), None),
+            ExprKind::Continue => ("continue".to_string(), None),
+            ExprKind::Return(_) if !cx.function => {
+                return unsupported("a return outside of a function")
+            }
+            ExprKind::Return(value) => {
+                let (code, ty) = match value {
+                    Some(value) => {
+                        let (code, ty) = self.expr(cx, value)?;
+                        (format!("return {code}"), ty)
+                    }
+                    None => ("return".to_string(), Some(TypeName::Nil)),
+                };
+                cx.returns.push((ty, span));
+                (code, None)
+            }
+            ExprKind::Match { .. } => return unsupported("a match expression"),
+            ExprKind::Array(_) => return unsupported("an array"),
+            ExprKind::Map(_) => return unsupported("a map"),
+            ExprKind::Import(_) => return unsupported("an import"),
+            ExprKind::Index { .. } => return unsupported("an index expression"),
+            ExprKind::Quote(_) | ExprKind::Quasiquote(_) => return unsupported("quoted data"),
+        })
+    }
+
+    /// `if` 式を変換する
+    ///
+    /// `else if` で続く並びは、どの腕も複数行で書く。`chained` なら並びの2つ目以降の `if`。
+    /// `discard` なら値を捨てる位置の `if` なので、腕の型を揃えない。
+    fn if_expr(
+        &mut self,
+        cx: &mut Context,
+        cond: &Expr,
+        then_branch: &Expr,
+        else_branch: Option<&Expr>,
+        chained: bool,
+        discard: bool,
+    ) -> Result<(String, Ty), TranspileError> {
+        let (cond, cond_ty) = self.expr(cx, cond)?;
+        let cond = unparen(&truthy(&cond, cond_ty));
+        let (then_code, then_ty) = self.body(cx, then_branch, discard)?;
+        let Some(else_branch) = else_branch else {
+            // 条件が偽なら `nil` になるので、値を使うなら本体も `nil` でなければならない
+            join(Some(TypeName::Nil), then_ty, then_branch.span)?;
+            let code = format!("if {cond} {}", block(&statement(then_code)));
+            return Ok((code, Some(TypeName::Nil)));
+        };
+        let (else_code, else_ty, nested) = match &else_branch.kind {
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let nested = else_branch.as_deref();
+                let (code, ty) = self.if_expr(cx, cond, then_branch, nested, true, discard)?;
+                (code, ty, true)
+            }
+            _ => {
+                let (code, ty) = self.body(cx, else_branch, discard)?;
+                (code, ty, false)
+            }
+        };
+        let ty = join(then_ty, else_ty, else_branch.span)?;
+        let inline = format!("if {cond} {} else {}", block(&then_code), block(&else_code));
+        if !chained && !nested && !inline.contains('\n') && inline.len() <= 60 {
+            return Ok((inline, ty));
+        }
+        let else_code = if nested {
+            else_code
+        } else {
+            block_lines(&else_code)
+        };
+        let code = format!("if {cond} {} else {else_code}", block_lines(&then_code));
+        Ok((code, ty))
+    }
+
+    /// 最上位の関数か、一部の標準関数の呼び出しを変換する
+    fn call(
+        &mut self,
+        cx: &mut Context,
+        func: &Expr,
+        args: &[Expr],
+        span: Span,
+    ) -> Result<(String, Ty), TranspileError> {
+        let ExprKind::Ident(name) = func.kind else {
+            return Err(TranspileError::Unsupported {
+                construct: "a call of a computed function",
+                span,
+            });
+        };
+        if cx.lookup(name).is_some() {
+            return Err(TranspileError::Unsupported {
+                construct: "a call of a local variable",
+                span,
+            });
+        }
+        let args = args
+            .iter()
+            .map(|arg| self.expr(cx, arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(&id) = self.index.get(&name) {
+            let types: Vec<_> = args.iter().map(|(_, ty)| *ty).collect();
+            let ty = self.instantiate(cx.tentative, id, &types, span)?;
+            let args: Vec<_> = args.iter().map(|(code, _)| unparen(code)).collect();
+            let code = format!("{}({})", rust_name(name.as_str()), args.join(", "));
+            return Ok((code, ty));
+        }
+        let arity = |expected: usize| {
+            if args.len() == expected {
+                Ok(())
+            } else {
+                Err(TranspileError::Arity {
+                    expected,
+                    found: args.len(),
+                    span,
+                })
+            }
+        };
+        match name.as_str() {
+            "print" => {
+                let args = args
+                    .into_iter()
+                    .map(|(code, ty)| Ok((code, cx.require(ty, span)?)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((
+                    print(&args, span, false, cx.tentative)?,
+                    Some(TypeName::Nil),
+                ))
+            }
+            "to_string" => {
+                arity(1)?;
+                let (code, ty) = &args[0];
+                let code = match cx.require(*ty, span)? {
+                    Some(TypeName::Str) => code.clone(),
+                    Some(TypeName::Float) => format!("format!(\"{{:?}}\", {code})"),
+                    Some(TypeName::Nil) => format!("{{ {code}; String::from(\"nil\") }}"),
+                    _ => format!("format!(\"{{}}\", {code})"),
+                };
+                Ok((code, Some(TypeName::Str)))
+            }
+            "sqrt" => {
+                arity(1)?;
+                let (code, ty) = &args[0];
+                let code = match number(*ty, span)? {
+                    Some(TypeName::Int) => format!("({} as f64).sqrt()", atom(code)),
+                    _ => format!("{}.sqrt()", atom(code)),
+                };
+                Ok((code, Some(TypeName::Float)))
+            }
+            "abs" => {
+                arity(1)?;
+                let (code, ty) = &args[0];
+                let code = match number(*ty, span)? {
+                    Some(TypeName::Int) => format!("rt::abs({})", unparen(code)),
+                    _ => format!("{}.abs()", atom(code)),
+                };
+                Ok((code, *ty))
+            }
+            _ => Err(TranspileError::UnknownIdentifier {
+                name: name.to_string(),
+                span: func.span,
+            }),
+        }
+    }
+}
+
+/// `let` の束縛と本体を並びにする
+pub(crate) fn let_items<'a>(bindings: &'a [(Symbol, Expr)], body: &'a [Expr]) -> Vec<Item<'a>> {
+    let bindings = bindings
+        .iter()
+        .map(|(name, value)| Item::Define(*name, value));
+    bindings.chain(body.iter().map(expr_item)).collect()
+}
+
+/// 2つの式の型が同じであることを確かめる。一方が決まっていなければ他方の型にする
+pub(crate) fn join(expected: Ty, found: Ty, span: Span) -> Result<Ty, TranspileError> {
+    match (expected, found) {
+        (Some(expected), Some(found)) if expected != found => Err(TranspileError::Mismatch {
+            expected,
+            found,
+            span,
+        }),
+        (Some(ty), _) | (None, Some(ty)) => Ok(Some(ty)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// 数値の型であることを確かめる
+pub(crate) fn number(ty: Ty, span: Span) -> Result<Ty, TranspileError> {
+    match ty {
+        Some(found @ (TypeName::Bool | TypeName::Str | TypeName::Nil)) => {
+            Err(TranspileError::NotANumber { found, span })
+        }
+        ty => Ok(ty),
+    }
+}
+
+/// 変換した演算の項。Rust の式、型、元の式の範囲
+type Operand = (String, Ty, Span);
+
+/// 二項演算を変換する
+fn binary(
+    op: BinOp,
+    (lhs, lhs_ty, lhs_span): Operand,
+    (rhs, rhs_ty, rhs_span): Operand,
+) -> Result<(String, Ty), TranspileError> {
+    let symbol = match op {
+        BinOp::And | BinOp::Or => {
+            let symbol = if op == BinOp::And { "&&" } else { "||" };
+            let code = format!(
+                "({} {symbol} {})",
+                truthy(&lhs, lhs_ty),
+                truthy(&rhs, rhs_ty)
+            );
+            return Ok((code, Some(TypeName::Bool)));
+        }
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::Rem => "%",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+        BinOp::Eq => "==",
+        BinOp::Ne => "!=",
+    };
+    let numeric = |ty: Ty| matches!(ty, Some(TypeName::Int | TypeName::Float) | None);
+    if matches!(op, BinOp::Eq | BinOp::Ne) && !(numeric(lhs_ty) && numeric(rhs_ty)) {
+        join(lhs_ty, rhs_ty, rhs_span)?;
+        let code = format!("({} {symbol} {})", atom(&lhs), atom(&rhs));
+        return Ok((code, Some(TypeName::Bool)));
+    }
+    let lhs_ty = number(lhs_ty, lhs_span)?;
+    let rhs_ty = number(rhs_ty, rhs_span)?;
+    let ty = match (lhs_ty, rhs_ty) {
+        (Some(TypeName::Float), _) | (_, Some(TypeName::Float)) => Some(TypeName::Float),
+        (Some(TypeName::Int), _) | (_, Some(TypeName::Int)) => Some(TypeName::Int),
+        _ => None,
+    };
+    let result = if op.is_comparison() {
+        Some(TypeName::Bool)
+    } else {
+        ty
+    };
+    if ty == Some(TypeName::Int) && !op.is_comparison() {
+        let helper = match op {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Rem => "rem",
+            _ => "div",
+        };
+        let code = format!("rt::{helper}({}, {})", unparen(&lhs), unparen(&rhs));
+        return Ok((code, result));
+    }
+    // 整数と浮動小数点数の演算は、整数を浮動小数点数にしてから計算する
+    let operand = |code: &str, side: Ty| {
+        if side == Some(TypeName::Int) && ty == Some(TypeName::Float) {
+            format!("({} as f64)", atom(code))
+        } else {
+            atom(code)
+        }
+    };
+    let code = format!(
+        "({} {symbol} {})",
+        operand(&lhs, lhs_ty),
+        operand(&rhs, rhs_ty)
+    );
+    Ok((code, result))
+}
+
+/// 値を条件に使うときの真偽値に変換する。`false` と `nil`、0、空の文字列が偽になる
+fn truthy(code: &str, ty: Ty) -> String {
+    match ty {
+        Some(TypeName::Bool) | None => code.to_string(),
+        Some(TypeName::Int) => format!("({} != 0)", atom(code)),
+        Some(TypeName::Float) => format!("rt::truthy({code})"),
+        Some(TypeName::Str) => format!("(!{}.is_empty())", atom(code)),
+        Some(TypeName::Nil) => format!("{{ {code}; false }}"),
+    }
+}
+
+/// 値を空白で区切って1行に書き出す `println!` を作る
+///
+/// `quote` が `true` なら、最後の値を表示するときと同じく文字列を引用符で囲む。
+fn print(
+    args: &[(String, Ty)],
+    span: Span,
+    quote: bool,
+    tentative: bool,
+) -> Result<String, TranspileError> {
+    let mut pattern = vec![];
+    let mut values = vec![];
+    for (code, ty) in args {
+        let code = unparen(code);
+        let (placeholder, value) = match ty {
+            Some(TypeName::Float) => ("{:?}", code.clone()),
+            Some(TypeName::Nil) => ("{}", format!("{{ {code}; \"nil\" }}")),
+            Some(TypeName::Str) if quote => ("{}", format!("rt::quote(&{code})")),
+            Some(_) => ("{}", code.clone()),
+            None if tentative => ("{:?}", code.clone()),
+            None => return Err(TranspileError::CannotInfer { span }),
+        };
+        pattern.push(placeholder);
+        values.push(value);
+    }
+    let mut code = format!("println!(\"{}\"", pattern.join(" "));
+    for value in values {
+        let _ = write!(code, ", {value}");
+    }
+    code.push(')');
+    Ok(code)
+}
+
+/// 型の名前に対応する Rust の型
+fn rust_type(ty: TypeName) -> &'static str {
+    match ty {
+        TypeName::Int => "i64",
+        TypeName::Float => "f64",
+        TypeName::Bool => "bool",
+        TypeName::Str => "String",
+        TypeName::Nil => "()",
+    }
+}
+
+/// 変数や関数の名前を Rust の識別子にする
+///
+/// 識別子に使えない文字は `_` で囲んだ16進数の文字の番号に置き換え、Rust の予約語や
+/// 生成した名前と重なる名前には `r_` を前に付ける。
+fn rust_name(name: &str) -> String {
+    let mut rust = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            rust.push(c);
+        } else {
+            let _ = write!(rust, "_{:x}_", c as u32);
+        }
+    }
+    if rust.starts_with(|c: char| c.is_ascii_digit()) || RESERVED.contains(&rust.as_str()) {
+        rust.insert_str(0, "r_");
+    }
+    rust
+}
+
+/// 演算子の優先順位に関わらず1つの項として使えるよう、必要なら括弧で囲む
+///
+/// 名前、数値、括弧で囲んだ式と、`f(...)` や `x.clone()` のような呼び出しはそのまま使う。
+fn atom(code: &str) -> String {
+    let args = code.trim_start_matches(|c: char| c.is_ascii_alphanumeric() || "_.:!".contains(c));
+    if args.is_empty() || args.starts_with('(') && balanced(args) {
+        code.to_string()
+    } else {
+        format!("({code})")
+    }
+}
+
+/// 先頭の `(` に対応する `)` が末尾にあるかどうか
+fn balanced(code: &str) -> bool {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in code.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1 == code.len();
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// 値を捨てる文にする。ブロックで終わる制御構文には `;` を付けない
+fn statement(code: String) -> String {
+    let control = ["if ", "while ", "for "]
+        .iter()
+        .any(|keyword| code.starts_with(keyword));
+    if code.ends_with(';') || control && code.ends_with('}') {
+        code
+    } else {
+        code + ";"
+    }
+}
+
+/// 文の並びを `{ ... }` で囲む。1行に収まる短い並びは1行で書く
+fn block(body: &str) -> String {
+    if !body.contains('\n') && body.len() <= 60 {
+        return format!("{{ {body} }}");
+    }
+    block_lines(body)
+}
+
+/// 文の並びを複数行の `{ ... }` で囲む
+fn block_lines(body: &str) -> String {
+    format!("{{\n    {}\n}}", body.replace('\n', "\n    "))
+}
+
+/// 式全体を囲む括弧を外す
+fn unparen(code: &str) -> String {
+    match code
+        .strip_prefix('(')
+        .and_then(|inner| inner.strip_suffix(')'))
+    {
+        Some(inner) if balanced(code) => inner.to_string(),
+        _ => code.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::lower;
+    use crate::infix::statements;
+    use crate::parser::source;
+    use crate::TokenTree;
+
+    fn rust(input: &str) -> Result<String, TranspileError> {
+        transpile(&statements(input).unwrap())
+    }
+
+    /// 生成したソースから、補助関数と先頭の注記を除いた部分
+    fn items(rust: &str) -> &str {
+        let start = rust.find("\nfn ").map_or(0, |i| i + 1);
+        let end = rust.find("mod rt {").unwrap_or(rust.len());
+        rust.get(start..end).unwrap_or_default().trim_end()
+    }
+
+    #[test]
+    fn test_annotated_functions() {
+        let rust = rust(
+            "fn mean(a: f64, b: i64) -> f64 { (a + b) / 2 };
+             fn count(n: i64) -> i64 {
+                 var total = 0;
+                 for i in 0..n { if i == 3 { continue }; total = total + i };
+                 total
+             };
+             print(mean(1.5, 2), count(10)); \"done\"",
+        )
+        .unwrap();
+        assert!(rust.starts_with(HEADER));
+        assert_eq!(
+            items(&rust),
+            "\
+fn mean(a: f64, b: i64) -> f64 { (a + (b as f64)) / (2 as f64) }
+
+fn count(n: i64) -> i64 {
+    let mut total = 0;
+    for mut i in 0..n {
+        if i == 3 { continue; }
+        total = rt::add(total, i);
+    }
+    total
+}
+
+fn main() {
+    let value = {
+        println!(\"{:?} {}\", mean(1.5_f64, 2), count(10));
+        String::from(\"done\")
+    };
+    println!(\"{}\", rt::quote(&value))
+}"
+        );
+    }
+
+    #[test]
+    fn test_inferred_types() {
+        // S式の関数は注釈が無いので、呼び出した引数の型と本体から型を決める
+        let TokenTree::Tree(forms, _) = source(
+            "(define divisible? (fn (n d) (== (* (/ n d) d) n)))
+             (define label (fn (n) (if (divisible? n 3) \"Fizz\" (to_string n))))
+             (define unused (fn (x) x))
+             (print (label 9) (label 10))",
+        )
+        .unwrap() else {
+            unreachable!()
+        };
+        let program: Vec<_> = forms
+            .iter()
+            .map(|form| Statement::Expr(lower(form).unwrap()))
+            .collect();
+        assert_eq!(
+            items(&transpile(&program).unwrap()),
+            "\
+fn divisible_3f_(n: i64, d: i64) -> bool { rt::mul(rt::div(n, d), d) == n }
+
+fn label(n: i64) -> String {
+    if divisible_3f_(n, 3) {
+        String::from(\"Fizz\")
+    } else {
+        format!(\"{}\", n)
+    }
+}
+
+fn main() {
+    println!(\"{} {}\", label(9), label(10));
+    println!(\"nil\");
+}"
+        );
+        // 再帰した呼び出しの型は、もう一方の腕から決める
+        let fib = rust("fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }; fib(20)");
+        assert!(fib.unwrap().contains("fn fib(n: i64) -> i64 {"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            rust("fn f(x) { [x] }; f(1)"),
+            Err(TranspileError::Unsupported {
+                construct: "an array",
+                span: Span::new(10, 13)
+            })
+        );
+        assert_eq!(
+            rust("fn f(x) { x }; f(1) + f(2.0)"),
+            Err(TranspileError::Mismatch {
+                expected: TypeName::Int,
+                found: TypeName::Float,
+                span: Span::new(22, 28)
+            })
+        );
+        assert_eq!(
+            rust("fn h(n) { h(n) }; h(1)"),
+            Err(TranspileError::CannotInfer {
+                span: Span::new(0, 16)
+            })
+        );
+        assert_eq!(
+            rust("var a = 1; fn k() { a }; k()"),
+            Err(TranspileError::UnknownIdentifier {
+                name: "a".to_string(),
+                span: Span::new(20, 21)
+            })
+        );
+        // 値を使う `if` は、`else` が無ければ本体も `nil` でなければならない
+        assert!(matches!(
+            rust("fn c(n) { if n > 0 { 1 } }; c(2)"),
+            Err(TranspileError::Mismatch { .. })
+        ));
+        assert!(rust("fn c(n) { if n > 0 { 1 }; 0 }; c(2)").is_ok());
+        assert_eq!(
+            rust("true + 1").map_err(|e| e.message()),
+            Err("expected a number, found bool".to_string())
+        );
+    }
+
+    #[test]
+    fn test_discarded_values_compile_without_warnings() {
+        let program = rust(
+            "fn f(x) { x * 2 }; -3; f(2); 1 + 2.5; 1 == 2; \"s\"; \
+             for i in 0..2 { i; f(i); if i > 0 { i } else { 0 } }; print(f(4))",
+        )
+        .unwrap();
+        assert!(
+            items(&program).contains("\n    let _ = f(2);\n"),
+            "{program}"
+        );
+        // rustc が無い環境では、生成したコードのコンパイルを確かめない
+        let dir = std::env::temp_dir().join(format!("ruscal-transpile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("main.rs");
+        std::fs::write(&source, &program).unwrap();
+        let output = std::process::Command::new("rustc")
+            .args(["--edition", "2021", "-D", "warnings", "--out-dir"])
+            .arg(&dir)
+            .arg(&source)
+            .output();
+        let _ = std::fs::remove_dir_all(&dir);
+        let Ok(output) = output else {
+            return;
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{stderr}\n{program}");
+        assert!(stderr.is_empty(), "{stderr}");
+    }
+}
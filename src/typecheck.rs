@@ -0,0 +1,938 @@
+//! 評価の前に式の型を推論して検査する型検査器
+//!
+//! 型は単一化 (unification) で推論する。定義した関数の型は使う場所ごとに型変数を新しくするので、
+//! `fn (x) { x }` のような関数は異なる型の引数で呼び出せる。
+//! 静的に決められない値の型は [`Type::Any`] とし、どの型とも矛盾しないものとして扱うので、
+//! 実行時に型が決まるプログラムも拒否しない。
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::ast::{
+    BinOp, Expr, ExprKind, MatchArm, Pattern, Span, Statement, Template, TypeName, UnOp,
+};
+use crate::diagnostics::Diagnostic;
+use crate::intern::Symbol;
+
+/// 式の型
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    /// 整数 `i64`
+    Int,
+    /// 浮動小数点数 `f64`
+    Float,
+    /// 真偽値 `bool`
+    Bool,
+    /// 文字列 `str`
+    Str,
+    /// `nil`
+    Nil,
+    /// 関数
+    Fn { params: Vec<Type>, ret: Box<Type> },
+    /// 静的には決められない型。どの型とも矛盾しない
+    Any,
+    /// 推論中のまだ決まっていない型
+    Var(u32),
+}
+
+impl Type {
+    fn is_numeric(&self) -> bool {
+        matches!(self, Self::Int | Self::Float | Self::Any | Self::Var(_))
+    }
+}
+
+impl From<TypeName> for Type {
+    fn from(name: TypeName) -> Self {
+        match name {
+            TypeName::Int => Self::Int,
+            TypeName::Float => Self::Float,
+            TypeName::Bool => Self::Bool,
+            TypeName::Str => Self::Str,
+            TypeName::Nil => Self::Nil,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int => f.write_str("i64"),
+            Self::Float => f.write_str("f64"),
+            Self::Bool => f.write_str("bool"),
+            Self::Str => f.write_str("str"),
+            Self::Nil => f.write_str("nil"),
+            Self::Fn { params, ret } => {
+                let params: Vec<_> = params.iter().map(Type::to_string).collect();
+                write!(f, "fn({}) -> {ret}", params.join(", "))
+            }
+            Self::Any => f.write_str("any"),
+            Self::Var(_) => f.write_str("_"),
+        }
+    }
+}
+
+/// 型検査で見つかった誤り
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeError {
+    /// 期待する型と異なる型の式がある
+    Mismatch {
+        expected: Type,
+        found: Type,
+        span: Span,
+    },
+    /// 数値を期待する演算に数値以外の式を渡した
+    NotANumber { found: Type, span: Span },
+    /// 関数ではない型の式を呼び出した
+    NotAFunction { found: Type, span: Span },
+    /// 関数に渡した引数の数が仮引数の数と合わない
+    ///
+    /// `definition` は呼び出した関数を定義した `fn` 式の範囲で、静的に分かる場合だけ持つ。
+    Arity {
+        expected: usize,
+        found: usize,
+        span: Span,
+        definition: Option<Span>,
+    },
+}
+
+impl TypeError {
+    /// エラーの種類ごとに決まった、変わらない番号
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Mismatch { .. } => "E0201",
+            Self::NotANumber { .. } => "E0202",
+            Self::NotAFunction { .. } => "E0203",
+            Self::Arity { .. } => "E0204",
+        }
+    }
+
+    /// 誤りのある式の範囲
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Mismatch { span, .. }
+            | Self::NotANumber { span, .. }
+            | Self::NotAFunction { span, .. }
+            | Self::Arity { span, .. } => *span,
+        }
+    }
+
+    /// 位置を含まない誤りの説明
+    pub fn message(&self) -> String {
+        match self {
+            Self::Mismatch {
+                expected, found, ..
+            } => format!("mismatched types: expected {expected}, found {found}"),
+            Self::NotANumber { found, .. } => format!("expected a number, found {found}"),
+            Self::NotAFunction { found, .. } => {
+                format!("called a value of type {found}, which is not a function")
+            }
+            Self::Arity {
+                expected, found, ..
+            } => {
+                let plural = if *expected == 1 { "" } else { "s" };
+                format!("expected {expected} argument{plural}, found {found}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message(), self.span().start)
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// 最上位の式の並びを型検査する関数
+///
+/// 式は同じスコープの中で順に検査するので、`define` した変数の型は後の式から参照できる。
+///
+/// # 引数
+/// * `exprs` - 検査する式の並び
+///
+/// # 戻り値
+/// * `Result<Type, Vec<TypeError>>` - 最後の式の型。式が無ければ `nil`
+///   - 誤りがあれば、見つかったすべての誤りを返す
+pub fn check(exprs: &[Expr]) -> Result<Type, Vec<TypeError>> {
+    let mut checker = Checker::new();
+    let mut last = Type::Nil;
+    for expr in exprs {
+        last = checker.infer(expr);
+    }
+    checker.finish(last)
+}
+
+/// 中置記法の文の並びを型検査する関数
+///
+/// # 引数
+/// * `statements` - 検査する文の並び
+///
+/// # 戻り値
+/// * `Result<Type, Vec<TypeError>>` - 最後の文の型。文が無ければ `nil`
+///   - 誤りがあれば、見つかったすべての誤りを返す
+pub fn check_statements(statements: &[Statement]) -> Result<Type, Vec<TypeError>> {
+    let mut checker = Checker::new();
+    let last = checker.statements(statements);
+    checker.finish(last)
+}
+
+/// 中置記法の文の並びを型検査し、誤りではないが疑わしい書き方を警告する関数
+///
+/// 今は、どの腕にも一致しない値がありうる `match` を警告する。
+/// 照合する値の型が `bool` や `nil` と分かれば、その値をすべて挙げた `match` は警告しない。
+///
+/// # 引数
+/// * `statements` - 検査する文の並び
+///
+/// # 戻り値
+/// * `Vec<Diagnostic>` - 警告の報告。型の誤りは [`check_statements`] が報告するので含めない
+pub fn warnings(statements: &[Statement]) -> Vec<Diagnostic> {
+    let mut checker = Checker::new();
+    checker.statements(statements);
+    checker.warnings
+}
+
+/// 照合する値の型のすべての値を `match` の腕が覆っているかどうか
+fn is_exhaustive(ty: &Type, arms: &[MatchArm]) -> bool {
+    let covers = |pattern: Pattern| {
+        arms.iter()
+            .any(|arm| arm.guard.is_none() && arm.pattern == pattern)
+    };
+    arms.iter().any(MatchArm::is_catch_all)
+        || match ty {
+            Type::Bool => covers(Pattern::Bool(true)) && covers(Pattern::Bool(false)),
+            Type::Nil => covers(Pattern::Nil),
+            _ => false,
+        }
+}
+
+/// 型変数を量化した型。使う場所ごとに `vars` を新しい型変数に置き換える
+struct Scheme {
+    vars: Vec<u32>,
+    ty: Type,
+    /// 変数の値が `fn` 式なら、その範囲
+    definition: Option<Span>,
+}
+
+impl Scheme {
+    fn mono(ty: Type) -> Self {
+        Self {
+            vars: vec![],
+            ty,
+            definition: None,
+        }
+    }
+}
+
+/// 型変数の束縛と変数の型を保持する型検査器
+struct Checker {
+    /// 型変数ごとの束縛。まだ決まっていなければ `None`
+    bindings: Vec<Option<Type>>,
+    /// 内側のスコープを末尾に置いた、変数の型の表
+    scopes: Vec<HashMap<Symbol, Scheme>>,
+    /// 数値の演算に使われたので、整数か浮動小数点数にしか束縛できない型変数
+    numeric: HashSet<u32>,
+    errors: Vec<TypeError>,
+    warnings: Vec<Diagnostic>,
+}
+
+impl Checker {
+    fn new() -> Self {
+        Self {
+            bindings: vec![],
+            scopes: vec![HashMap::new()],
+            numeric: HashSet::new(),
+            errors: vec![],
+            warnings: vec![],
+        }
+    }
+
+    fn finish(self, last: Type) -> Result<Type, Vec<TypeError>> {
+        if self.errors.is_empty() {
+            Ok(self.resolve(&last))
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.bindings.push(None);
+        Type::Var(self.bindings.len() as u32 - 1)
+    }
+
+    /// 束縛済みの型変数をたどった、最も外側の型
+    fn shallow(&self, ty: &Type) -> Type {
+        let mut ty = ty.clone();
+        while let Type::Var(v) = ty {
+            match &self.bindings[v as usize] {
+                Some(bound) => ty = bound.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// 束縛済みの型変数をすべて置き換えた型
+    fn resolve(&self, ty: &Type) -> Type {
+        match self.shallow(ty) {
+            Type::Fn { params, ret } => Type::Fn {
+                params: params.iter().map(|p| self.resolve(p)).collect(),
+                ret: Box::new(self.resolve(&ret)),
+            },
+            ty => ty,
+        }
+    }
+
+    fn free_vars(&self, ty: &Type, out: &mut HashSet<u32>) {
+        match self.shallow(ty) {
+            Type::Var(v) => {
+                out.insert(v);
+            }
+            Type::Fn { params, ret } => {
+                for param in &params {
+                    self.free_vars(param, out);
+                }
+                self.free_vars(&ret, out);
+            }
+            _ => {}
+        }
+    }
+
+    /// 2つの型を同じ型にできるよう型変数を束縛する
+    ///
+    /// # 戻り値
+    /// * `bool` - 矛盾する型であれば `false`
+    fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        match (self.shallow(a), self.shallow(b)) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Var(a), Type::Var(b)) if a == b => true,
+            (Type::Var(v), ty) | (ty, Type::Var(v)) => {
+                let mut vars = HashSet::new();
+                self.free_vars(&ty, &mut vars);
+                // `t = fn(t) -> t` のような無限の型は作らない
+                if vars.contains(&v) {
+                    return false;
+                }
+                if self.numeric.contains(&v) {
+                    match ty {
+                        Type::Var(other) => {
+                            self.numeric.insert(other);
+                        }
+                        Type::Int | Type::Float => {}
+                        _ => return false,
+                    }
+                }
+                self.bindings[v as usize] = Some(ty);
+                true
+            }
+            (
+                Type::Fn { params, ret },
+                Type::Fn {
+                    params: other_params,
+                    ret: other_ret,
+                },
+            ) => {
+                params.len() == other_params.len()
+                    && params
+                        .iter()
+                        .zip(&other_params)
+                        .all(|(a, b)| self.unify(a, b))
+                    && self.unify(&ret, &other_ret)
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// 式の型 `found` が `expected` と矛盾すれば誤りを記録する
+    ///
+    /// 数値に限られた型変数と矛盾したときは、数値ではないことを誤りとする。
+    fn expect(&mut self, expected: &Type, found: &Type, span: Span) {
+        if !self.unify(expected, found) {
+            let (expected, found) = (self.resolve(expected), self.resolve(found));
+            if matches!(expected, Type::Var(v) if self.numeric.contains(&v)) {
+                self.errors.push(TypeError::NotANumber { found, span });
+                return;
+            }
+            self.errors.push(TypeError::Mismatch {
+                expected,
+                found,
+                span,
+            });
+        }
+    }
+
+    /// 式の型が数値でなければ誤りを記録する
+    ///
+    /// まだ決まっていない型変数は、以後は数値にしか束縛できないようにする。
+    fn expect_number(&mut self, found: &Type, span: Span) {
+        let found = self.resolve(found);
+        if let Type::Var(v) = found {
+            self.numeric.insert(v);
+        } else if !found.is_numeric() {
+            self.errors.push(TypeError::NotANumber { found, span });
+        }
+    }
+
+    /// 分岐の両方の値を取りうる型
+    ///
+    /// 同じ型でなければ [`Type::Any`] になる。
+    /// 分岐ごとに違う型の値を返してよいので、型変数を他方の型に束縛はしない。
+    fn join(&self, a: &Type, b: &Type) -> Type {
+        let (a, b) = (self.resolve(a), self.resolve(b));
+        if a == b {
+            a
+        } else {
+            Type::Any
+        }
+    }
+
+    /// 変数の型を内側のスコープから探し、量化した型変数を新しくする
+    fn lookup(&mut self, name: Symbol) -> Option<Type> {
+        let scheme = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name))?;
+        let (vars, ty) = (scheme.vars.clone(), scheme.ty.clone());
+        let fresh: HashMap<u32, Type> = vars
+            .into_iter()
+            .map(|v| {
+                let ty = self.fresh();
+                if let (true, Type::Var(new)) = (self.numeric.contains(&v), &ty) {
+                    self.numeric.insert(*new);
+                }
+                (v, ty)
+            })
+            .collect();
+        Some(self.substitute(&ty, &fresh))
+    }
+
+    /// 呼び出す式が関数を定義した `fn` 式の範囲
+    fn definition(&self, func: &Expr) -> Option<Span> {
+        match &func.kind {
+            ExprKind::Fn { .. } => Some(func.span),
+            ExprKind::Ident(name) => {
+                self.scopes
+                    .iter()
+                    .rev()
+                    .find_map(|scope| scope.get(name))?
+                    .definition
+            }
+            _ => None,
+        }
+    }
+
+    fn substitute(&self, ty: &Type, fresh: &HashMap<u32, Type>) -> Type {
+        match self.shallow(ty) {
+            Type::Var(v) => fresh.get(&v).cloned().unwrap_or(Type::Var(v)),
+            Type::Fn { params, ret } => Type::Fn {
+                params: params.iter().map(|p| self.substitute(p, fresh)).collect(),
+                ret: Box::new(self.substitute(&ret, fresh)),
+            },
+            ty => ty,
+        }
+    }
+
+    fn define(&mut self, name: Symbol, scheme: Scheme) {
+        self.scopes
+            .last_mut()
+            .expect("the global scope is never popped")
+            .insert(name, scheme);
+    }
+
+    /// スコープの変数が参照していない型変数を量化する
+    fn generalize(&self, ty: Type) -> Scheme {
+        let mut vars = HashSet::new();
+        self.free_vars(&ty, &mut vars);
+        for scheme in self.scopes.iter().flat_map(HashMap::values) {
+            let mut used = HashSet::new();
+            self.free_vars(&scheme.ty, &mut used);
+            for v in used.difference(&scheme.vars.iter().copied().collect()) {
+                vars.remove(v);
+            }
+        }
+        let mut vars: Vec<_> = vars.into_iter().collect();
+        vars.sort_unstable();
+        Scheme {
+            vars,
+            ty,
+            definition: None,
+        }
+    }
+
+    /// 変数を定義し、その値の型を返す
+    ///
+    /// 関数を値とする変数は、本体の中から自身を再帰的に呼び出せるようにしてから検査し、
+    /// 検査が済んだら型を量化する。
+    fn bind(&mut self, name: Symbol, value: &Expr) -> Type {
+        if !matches!(value.kind, ExprKind::Fn { .. }) {
+            let ty = self.infer(value);
+            self.define(name, Scheme::mono(ty.clone()));
+            return ty;
+        }
+        let var = self.fresh();
+        self.define(name, Scheme::mono(var.clone()));
+        let ty = self.infer(value);
+        self.unify(&var, &ty);
+        self.scopes
+            .last_mut()
+            .expect("the global scope is never popped")
+            .remove(&name);
+        let scheme = Scheme {
+            definition: Some(value.span),
+            ..self.generalize(self.resolve(&ty))
+        };
+        self.define(name, scheme);
+        ty
+    }
+
+    /// 数値の二項演算の結果の型
+    fn arithmetic(&self, lhs: &Type, rhs: &Type) -> Type {
+        match (self.resolve(lhs), self.resolve(rhs)) {
+            (Type::Int, Type::Int) => Type::Int,
+            (Type::Float, Type::Int | Type::Float) | (Type::Int, Type::Float) => Type::Float,
+            _ => Type::Any,
+        }
+    }
+
+    fn infer(&mut self, expr: &Expr) -> Type {
+        match &expr.kind {
+            ExprKind::Int(_) => Type::Int,
+            ExprKind::Float(_) => Type::Float,
+            ExprKind::Str(_) => Type::Str,
+            ExprKind::Bool(_) => Type::Bool,
+            ExprKind::Nil => Type::Nil,
+            // 定義の見つからない変数は実行時のエラーになるので、ここでは型を決めない
+            ExprKind::Ident(name) => self.lookup(*name).unwrap_or(Type::Any),
+            ExprKind::BinaryOp { op, lhs, rhs } => {
+                let (l, r) = (self.infer(lhs), self.infer(rhs));
+                match op {
+                    BinOp::And | BinOp::Or | BinOp::Eq | BinOp::Ne => Type::Bool,
+                    _ => {
+                        self.expect_number(&l, lhs.span);
+                        self.expect_number(&r, rhs.span);
+                        if op.is_comparison() {
+                            Type::Bool
+                        } else {
+                            self.arithmetic(&l, &r)
+                        }
+                    }
+                }
+            }
+            ExprKind::UnaryOp {
+                op: UnOp::Neg,
+                operand,
+            } => {
+                let ty = self.infer(operand);
+                self.expect_number(&ty, operand.span);
+                match self.resolve(&ty) {
+                    ty @ (Type::Int | Type::Float) => ty,
+                    _ => Type::Any,
+                }
+            }
+            ExprKind::UnaryOp {
+                op: UnOp::Not,
+                operand,
+            } => {
+                self.infer(operand);
+                Type::Bool
+            }
+            ExprKind::Group(inner) => self.infer(inner),
+            ExprKind::Call { func, args } => self.call(func, args, expr.span),
+            // 配列とマップの要素の型は追わないので、配列とマップとその要素は動的な型として扱う
+            ExprKind::Array(items) => {
+                for item in items {
+                    self.infer(item);
+                }
+                Type::Any
+            }
+            ExprKind::Import(_) => Type::Any,
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.infer(key);
+                    self.infer(value);
+                }
+                Type::Any
+            }
+            ExprKind::Index { target, index } => {
+                self.infer(target);
+                self.index(index);
+                Type::Any
+            }
+            ExprKind::Define { name, value } => self.bind(*name, value),
+            ExprKind::Let { bindings, body } => {
+                self.scopes.push(HashMap::new());
+                for (name, value) in bindings {
+                    self.bind(*name, value);
+                }
+                let mut last = Type::Nil;
+                for expr in body {
+                    last = self.infer(expr);
+                }
+                self.scopes.pop();
+                last
+            }
+            ExprKind::Fn {
+                params,
+                body,
+                signature,
+            } => {
+                let annotation = |i: usize| {
+                    signature
+                        .as_ref()
+                        .and_then(|s| s.params.get(i).copied().flatten())
+                };
+                let param_types: Vec<_> = (0..params.len())
+                    .map(|i| annotation(i).map_or_else(|| self.fresh(), Type::from))
+                    .collect();
+                self.scopes.push(HashMap::new());
+                for (param, ty) in params.iter().zip(&param_types) {
+                    self.define(*param, Scheme::mono(ty.clone()));
+                }
+                let mut last = Type::Nil;
+                for expr in body.iter() {
+                    last = self.infer(expr);
+                }
+                self.scopes.pop();
+                let ret = match signature.as_ref().and_then(|s| s.ret) {
+                    Some(ret) => {
+                        let ret = Type::from(ret);
+                        let span = body.last().map_or(expr.span, |e| e.span);
+                        self.expect(&ret, &last, span);
+                        ret
+                    }
+                    None => last,
+                };
+                Type::Fn {
+                    params: param_types,
+                    ret: Box::new(ret),
+                }
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.infer(cond);
+                let then_type = self.infer(then_branch);
+                let else_type = match else_branch {
+                    Some(else_branch) => self.infer(else_branch),
+                    None => Type::Nil,
+                };
+                self.join(&then_type, &else_type)
+            }
+            ExprKind::Block(statements) => {
+                self.scopes.push(HashMap::new());
+                let ty = self.statements(statements);
+                self.scopes.pop();
+                ty
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                let ty = self.infer(scrutinee);
+                let mut result: Option<Type> = None;
+                for arm in arms {
+                    self.scopes.push(HashMap::new());
+                    if let Pattern::Binding(name) = arm.pattern {
+                        self.define(name, Scheme::mono(ty.clone()));
+                    }
+                    if let Some(guard) = &arm.guard {
+                        self.infer(guard);
+                    }
+                    let body = self.infer(&arm.body);
+                    self.scopes.pop();
+                    result = Some(match result {
+                        Some(prev) => self.join(&prev, &body),
+                        None => body,
+                    });
+                }
+                if !is_exhaustive(&self.resolve(&ty), arms) {
+                    self.warnings.push(
+                        Diagnostic::warning(expr.span, "non-exhaustive `match`")
+                            .with_code("W0001")
+                            .with_note("add a `_ => ...` arm for the values no arm matches"),
+                    );
+                }
+                result.unwrap_or(Type::Any)
+            }
+            ExprKind::While { cond, body } => {
+                self.infer(cond);
+                self.infer(body);
+                Type::Nil
+            }
+            ExprKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                let ty = self.infer(start);
+                self.expect(&Type::Int, &ty, start.span);
+                let ty = self.infer(end);
+                self.expect(&Type::Int, &ty, end.span);
+                self.scopes.push(HashMap::new());
+                self.define(*var, Scheme::mono(Type::Int));
+                self.infer(body);
+                self.scopes.pop();
+                Type::Nil
+            }
+            // 値を持たずに制御を移すので、どの型の場所にも置ける
+            ExprKind::Break | ExprKind::Continue => Type::Any,
+            ExprKind::Return(value) => {
+                if let Some(value) = value {
+                    self.infer(value);
+                }
+                Type::Any
+            }
+            // リストと識別子のデータは型で区別しない
+            ExprKind::Quote(_) => Type::Any,
+            ExprKind::Quasiquote(template) => {
+                self.template(template);
+                Type::Any
+            }
+        }
+    }
+
+    /// 関数呼び出しの型を推論する
+    fn call(&mut self, func: &Expr, args: &[Expr], span: Span) -> Type {
+        // 変数で隠されていない演算子は組み込みの演算として扱う
+        if let ExprKind::Ident(name) = &func.kind {
+            if let Some(op) = BinOp::from_symbol(name.as_str()) {
+                if self.lookup(*name).is_none() {
+                    let mut ty = match op {
+                        BinOp::Add | BinOp::Mul if args.is_empty() => Type::Int,
+                        _ => Type::Any,
+                    };
+                    for (i, arg) in args.iter().enumerate() {
+                        let arg_type = self.infer(arg);
+                        self.expect_number(&arg_type, arg.span);
+                        ty = if i == 0 {
+                            self.resolve(&arg_type)
+                        } else {
+                            self.arithmetic(&ty, &arg_type)
+                        };
+                    }
+                    return if op.is_arithmetic() { ty } else { Type::Any };
+                }
+            }
+        }
+        let func_type = self.infer(func);
+        let arg_types: Vec<_> = args.iter().map(|arg| self.infer(arg)).collect();
+        match self.shallow(&func_type) {
+            Type::Fn { params, ret } => {
+                if params.len() != args.len() {
+                    self.errors.push(TypeError::Arity {
+                        expected: params.len(),
+                        found: args.len(),
+                        span,
+                        definition: self.definition(func),
+                    });
+                    return Type::Any;
+                }
+                for ((param, arg_type), arg) in params.iter().zip(&arg_types).zip(args) {
+                    self.expect(param, arg_type, arg.span);
+                }
+                *ret
+            }
+            Type::Var(_) => {
+                let ret = self.fresh();
+                let ty = Type::Fn {
+                    params: arg_types,
+                    ret: Box::new(ret.clone()),
+                };
+                self.expect(&ty, &func_type, func.span);
+                ret
+            }
+            Type::Any => Type::Any,
+            found => {
+                self.errors.push(TypeError::NotAFunction {
+                    found,
+                    span: func.span,
+                });
+                Type::Any
+            }
+        }
+    }
+
+    /// 準引用の雛形に埋め込む式を検査する
+    fn template(&mut self, template: &Template) {
+        match template {
+            Template::Datum(_) => {}
+            Template::List(items, _) => items.iter().for_each(|item| self.template(item)),
+            Template::Unquote(expr) => {
+                self.infer(expr);
+            }
+        }
+    }
+
+    /// 配列の要素の位置が整数であることを確かめる
+    fn index(&mut self, index: &Expr) {
+        let ty = self.infer(index);
+        self.expect(&Type::Int, &ty, index.span);
+    }
+
+    /// 文の並びを検査し、最後の文の型を返す
+    fn statements(&mut self, statements: &[Statement]) -> Type {
+        let mut last = Type::Nil;
+        for statement in statements {
+            last = match statement {
+                Statement::VarDef { name, value, .. } => self.bind(*name, value),
+                Statement::Assignment { name, value, .. } => {
+                    let ty = self.infer(value);
+                    let var = self
+                        .scopes
+                        .iter()
+                        .rev()
+                        .find_map(|scope| scope.get(name))
+                        .map(|scheme| scheme.ty.clone());
+                    if let Some(var) = var {
+                        self.expect(&var, &ty, value.span);
+                    }
+                    ty
+                }
+                Statement::IndexAssignment {
+                    target,
+                    index,
+                    value,
+                    ..
+                } => {
+                    self.infer(target);
+                    self.index(index);
+                    self.infer(value)
+                }
+                Statement::Expr(expr) => self.infer(expr),
+            };
+        }
+        last
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::lower;
+    use crate::infix::statements;
+    use crate::parser::source;
+    use crate::TokenTree;
+
+    fn check_str(input: &str) -> Result<Type, Vec<TypeError>> {
+        let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+            panic!("failed to parse {input:?}");
+        };
+        let exprs: Vec<_> = forms.iter().map(|form| lower(form).unwrap()).collect();
+        check(&exprs)
+    }
+
+    fn check_infix(input: &str) -> Result<Type, Vec<TypeError>> {
+        check_statements(&statements(input).unwrap())
+    }
+
+    #[test]
+    fn test_infer() {
+        assert_eq!(check_str("(+ 1 (* 2 3))"), Ok(Type::Int));
+        assert_eq!(check_str("(+ 1 0.5)"), Ok(Type::Float));
+        assert_eq!(check_str("(< 1 2)"), Ok(Type::Bool));
+        assert_eq!(check_str("(if true 1 nil)"), Ok(Type::Any));
+        assert_eq!(
+            check_str("(define id (fn (x) x)) (id true) (id 1)"),
+            Ok(Type::Int)
+        );
+        assert_eq!(
+            check_str("(define apply (fn (f x) (f x))) apply")
+                .unwrap()
+                .to_string(),
+            "fn(fn(_) -> _, _) -> _"
+        );
+        assert_eq!(
+            check_infix("fn add(a: f64, b: f64) -> f64 { a + b }; add").map(|t| t.to_string()),
+            Ok("fn(f64, f64) -> f64".to_string())
+        );
+    }
+
+    #[test]
+    fn test_type_errors() {
+        assert_eq!(
+            check_str("(+ 1 \"a\")"),
+            Err(vec![TypeError::NotANumber {
+                found: Type::Str,
+                span: Span::new(5, 8)
+            }])
+        );
+        assert_eq!(
+            check_infix("fn add(a: f64, b: f64) -> f64 { a + b }; add(1.0, true)"),
+            Err(vec![TypeError::Mismatch {
+                expected: Type::Float,
+                found: Type::Bool,
+                span: Span::new(50, 54)
+            }])
+        );
+        assert_eq!(
+            check_infix("fn f(a: i64) -> bool { a + 1 }")
+                .unwrap_err()
+                .iter()
+                .map(TypeError::to_string)
+                .collect::<Vec<_>>(),
+            ["mismatched types: expected bool, found i64 at byte 21"]
+        );
+        assert_eq!(
+            check_str("(define f (fn (x) x)) (f 1 2) (1 2)"),
+            Err(vec![
+                TypeError::Arity {
+                    expected: 1,
+                    found: 2,
+                    span: Span::new(22, 29),
+                    definition: Some(Span::new(10, 20)),
+                },
+                TypeError::NotAFunction {
+                    found: Type::Int,
+                    span: Span::new(31, 32)
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_dynamic_programs_are_accepted() {
+        // 実行時に型が決まる値や、未定義の変数は拒否しない
+        assert!(check_str("(define x (if c 1 2.0)) (* x 2)").is_ok());
+        // 分岐の型を合わせようとして仮引数の型を決めてしまわない
+        assert!(check_str("(define f (fn (n) (if (< n 0) nil n))) (f 3)").is_ok());
+        assert!(check_str("(define f (fn (n) (if (< n 0) \"z\" n))) (f 3)").is_ok());
+        assert!(check_str("(define f (fn (n) (if (< n 0) 0.5 n))) (f 3)").is_ok());
+        assert!(check_str("(define f (fn (n) (if (< n 0) nil n))) (f 3) (f 0.5)").is_ok());
+        assert!(check_infix("var n = 0; for i in 0..10 { n = n + i }; n").is_ok());
+        assert!(
+            check_infix("fn fact(n) { if n < 2 { 1 } else { n * fact(n - 1) } }; fact(5)").is_ok()
+        );
+        assert_eq!(
+            check_infix("var n = 0; n = true"),
+            Err(vec![TypeError::Mismatch {
+                expected: Type::Int,
+                found: Type::Bool,
+                span: Span::new(15, 19)
+            }])
+        );
+        // 数値の演算に使った仮引数には数値しか渡せない
+        assert_eq!(
+            check_str("(define f (fn (n) (if (< n 0) nil n))) (f \"s\")"),
+            Err(vec![TypeError::NotANumber {
+                found: Type::Str,
+                span: Span::new(42, 45)
+            }])
+        );
+    }
+
+    #[test]
+    fn test_match() {
+        assert_eq!(check_infix("match 1 { 1 => 2, n => n * 3 }"), Ok(Type::Int));
+        assert_eq!(check_infix("match 1 { 1 => 2, _ => \"s\" }"), Ok(Type::Any));
+        let warned = |input: &str| warnings(&statements(input).unwrap());
+        let found = warned("var x = 2\nmatch x { 1 => 2, n if n > 1 => n }");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].span, Span::new(10, 45));
+        assert!(found[0].message.contains("non-exhaustive"));
+        assert!(warned("match 1 < 2 { true => 1, false => 0 }").is_empty());
+        assert!(warned("match nil { nil => 1 }").is_empty());
+        assert!(warned("var x = 2\nmatch x { 1 => 2, _ => 3 }").is_empty());
+    }
+}
@@ -0,0 +1,166 @@
+//! `TokenTree` をたどって解析するための訪問者
+//!
+//! [`Visitor`] のメソッドのうち必要なものだけを実装して [`walk`] に渡すと、
+//! 木を深さ優先で先頭から順にたどりながらメソッドが呼ばれる。
+//!
+//! ```
+//! use ruscal_b::visit::{walk, Visitor};
+//! use ruscal_b::{source, Span, Token};
+//!
+//! /// 識別子の数を数える
+//! #[derive(Default)]
+//! struct CountIdents(usize);
+//!
+//! impl Visitor<'_> for CountIdents {
+//!     fn visit_token(&mut self, token: &Token, _span: Span) {
+//!         if let Token::Ident(_) = token {
+//!             self.0 += 1;
+//!         }
+//!     }
+//! }
+//!
+//! let mut count = CountIdents::default();
+//! walk(&source("(define x (+ x 1))").unwrap(), &mut count);
+//! assert_eq!(count.0, 4);
+//! ```
+
+use crate::ast::{Span, Token, TokenTree};
+
+/// 木をたどりながら呼ばれるメソッドの集まり
+///
+/// どのメソッドにも何もしない既定の実装があるので、必要なものだけを実装すればよい。
+pub trait Visitor<'src> {
+    /// 葉となるトークンを訪れる
+    ///
+    /// # 引数
+    /// * `token` - トークン
+    /// * `span` - トークンの範囲
+    fn visit_token(&mut self, token: &Token<'src>, span: Span) {
+        let _ = (token, span);
+    }
+
+    /// 括弧で囲まれた部分木を訪れる
+    ///
+    /// 既定の実装は [`walk_tree`] で要素をたどる。
+    /// 実装し直して [`walk_tree`] を呼ばなければ、部分木の内側を読み飛ばせる。
+    ///
+    /// # 引数
+    /// * `children` - 部分木の要素
+    /// * `span` - 括弧を含む部分木の範囲
+    fn visit_tree(&mut self, children: &[TokenTree<'src>], span: Span) {
+        walk_tree(self, children, span);
+    }
+
+    /// 部分木の要素をたどる前に呼ばれる
+    fn enter_tree(&mut self, children: &[TokenTree<'src>], span: Span) {
+        let _ = (children, span);
+    }
+
+    /// 部分木の要素をすべてたどった後に呼ばれる
+    fn exit_tree(&mut self, children: &[TokenTree<'src>], span: Span) {
+        let _ = (children, span);
+    }
+}
+
+/// 木を深さ優先でたどり、訪問者のメソッドを呼ぶ関数
+///
+/// # 引数
+/// * `tree` - たどる木
+/// * `visitor` - 訪問者
+pub fn walk<'src, V: Visitor<'src> + ?Sized>(tree: &TokenTree<'src>, visitor: &mut V) {
+    match tree {
+        TokenTree::Token(token, span) => visitor.visit_token(token, *span),
+        TokenTree::Tree(children, span) => visitor.visit_tree(children, *span),
+    }
+}
+
+/// 部分木に入り、要素を先頭から順にたどってから出る関数
+///
+/// [`Visitor::visit_tree`] の既定の実装で、実装し直したメソッドから内側をたどるときにも使う。
+///
+/// # 引数
+/// * `visitor` - 訪問者
+/// * `children` - 部分木の要素
+/// * `span` - 括弧を含む部分木の範囲
+pub fn walk_tree<'src, V: Visitor<'src> + ?Sized>(
+    visitor: &mut V,
+    children: &[TokenTree<'src>],
+    span: Span,
+) {
+    visitor.enter_tree(children, span);
+    for child in children {
+        walk(child, visitor);
+    }
+    visitor.exit_tree(children, span);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::OwnedToken;
+    use crate::parser::source;
+
+    /// 入れ子の最も深いところの深さと、訪れた順のトークン
+    #[derive(Default)]
+    struct Trace {
+        depth: usize,
+        max_depth: usize,
+        tokens: Vec<OwnedToken>,
+    }
+
+    impl<'src> Visitor<'src> for Trace {
+        fn visit_token(&mut self, token: &Token<'src>, _span: Span) {
+            self.tokens.push(token.to_owned());
+        }
+
+        fn enter_tree(&mut self, _children: &[TokenTree<'src>], _span: Span) {
+            self.depth += 1;
+            self.max_depth = self.max_depth.max(self.depth);
+        }
+
+        fn exit_tree(&mut self, _children: &[TokenTree<'src>], _span: Span) {
+            self.depth -= 1;
+        }
+    }
+
+    #[test]
+    fn test_walk() {
+        let tree = source("(a (b (c)) d) e").unwrap();
+        let mut trace = Trace::default();
+        walk(&tree, &mut trace);
+        // 入力全体を表す最上位の木も1段に数える
+        assert_eq!(trace.max_depth, 4);
+        assert_eq!(trace.depth, 0);
+        assert_eq!(
+            trace.tokens,
+            ["a", "b", "c", "d", "e"].map(|name| Token::Ident(name).to_owned())
+        );
+    }
+
+    /// `quote` の中を読み飛ばして、最初に現れた数値の範囲を探す
+    #[derive(Default)]
+    struct FindNumber(Option<Span>);
+
+    impl<'src> Visitor<'src> for FindNumber {
+        fn visit_token(&mut self, token: &Token<'src>, span: Span) {
+            if let (None, Token::Int(_) | Token::Float(_)) = (self.0, token) {
+                self.0 = Some(span);
+            }
+        }
+
+        fn visit_tree(&mut self, children: &[TokenTree<'src>], span: Span) {
+            if let Some(TokenTree::Token(Token::Ident("quote"), _)) = children.first() {
+                return;
+            }
+            walk_tree(self, children, span);
+        }
+    }
+
+    #[test]
+    fn test_skip_subtree() {
+        let tree = source("(f '(1 2) 3.5 4)").unwrap();
+        let mut find = FindNumber::default();
+        walk(&tree, &mut find);
+        assert_eq!(find.0, Some(Span::new(10, 13)));
+    }
+}
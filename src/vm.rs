@@ -0,0 +1,616 @@
+//! コンパイル済みの命令列を実行するスタックマシン
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::{BinOp, Span};
+use crate::bytecode::{Bytecode, Instruction};
+use crate::env::Environment;
+use crate::eval::{
+    arithmetic, binary, bind_arguments, call, expect_integer, expect_key, expect_number, get_index,
+    set_index, values_equal, CallGuard, EvalError, Function, FunctionBody, Value,
+};
+use crate::limits;
+use crate::module;
+use crate::profile;
+
+/// [`Bytecode`] を実行するスタックマシン
+///
+/// 値のスタックは関数の呼び出しをまたいで共有し、呼び出しごとに確保し直さない。
+#[derive(Default)]
+pub struct Vm {
+    stack: Vec<Value>,
+    /// 命令を実行する前に呼ぶフック
+    hook: Option<Box<dyn Hook>>,
+    /// 実行中の呼び出し。フックがあるときだけ記録する
+    frames: Vec<Frame>,
+}
+
+impl fmt::Debug for Vm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vm")
+            .field("stack", &self.stack)
+            .field("frames", &self.frames.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// スタックマシンが命令を実行する前に呼ぶフック
+///
+/// フックを設定すると、コンパイル済みの関数も JIT を使わずに命令を1つずつ実行する。
+pub trait Hook {
+    /// `frames` の最後の呼び出しで、その `pc` の命令を実行する直前に呼ばれる
+    ///
+    /// `false` を返すと、実行を [`EvalError::Interrupted`] で打ち切る。
+    fn before(&mut self, frames: &[Frame]) -> bool;
+}
+
+/// フックに渡す、実行中の1つの呼び出し
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// 呼び出した関数の名前。最上位の命令列は `<top level>`
+    pub name: String,
+    /// 実行している命令列
+    pub code: Rc<Bytecode>,
+    /// 次に実行する命令の位置
+    pub pc: usize,
+    /// 命令を実行している環境
+    pub env: Environment,
+}
+
+impl Vm {
+    /// 空のスタックを持つスタックマシンを作る
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 命令を実行する前に `hook` を呼ぶスタックマシンにする
+    pub fn with_hook(mut self, hook: impl Hook + 'static) -> Self {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+
+    /// 命令列を実行する
+    ///
+    /// # 引数
+    /// * `bytecode` - 実行する命令列
+    /// * `env` - 変数の束縛を探し、`define` で定義を追加する環境
+    ///
+    /// # 戻り値
+    /// * `Result<Value, EvalError>` - 命令列が返した値
+    pub fn run(&mut self, bytecode: &Bytecode, env: &mut Environment) -> Result<Value, EvalError> {
+        let (base, saved) = (self.stack.len(), env.clone());
+        let res = if self.hook.is_some() {
+            let code = Rc::new(bytecode.clone());
+            self.enter("<top level>", &code, env, |vm, env| vm.execute(&code, env))
+        } else {
+            self.execute(bytecode, env)
+        };
+        // エラーで中断したときは、実行中に積んだ値を捨て、開始したスコープを終了する
+        if res.is_err() {
+            self.stack.truncate(base);
+            *env = saved;
+        }
+        res
+    }
+
+    /// 呼び出しを記録して `f` を実行し、終わったら記録を取り除く
+    fn enter(
+        &mut self,
+        name: &str,
+        code: &Rc<Bytecode>,
+        env: &mut Environment,
+        f: impl FnOnce(&mut Self, &mut Environment) -> Result<Value, EvalError>,
+    ) -> Result<Value, EvalError> {
+        self.frames.push(Frame {
+            name: name.to_string(),
+            code: code.clone(),
+            pc: 0,
+            env: env.clone(),
+        });
+        let res = f(self, env);
+        self.frames.pop();
+        res
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack
+            .pop()
+            .expect("the compiler keeps the stack balanced")
+    }
+
+    fn peek(&self, depth: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - depth]
+    }
+
+    fn execute(&mut self, bytecode: &Bytecode, env: &mut Environment) -> Result<Value, EvalError> {
+        let (base, mut pc) = (self.stack.len(), 0);
+        loop {
+            let instruction = bytecode.code[pc];
+            let span = bytecode.spans[pc];
+            pc += 1;
+            if !limits::step() {
+                return Err(limits::exhausted(span));
+            }
+            if let Some(hook) = &mut self.hook {
+                let frame = self.frames.last_mut().expect("a frame for each execution");
+                frame.pc = pc - 1;
+                frame.env = env.clone();
+                if !hook.before(&self.frames) {
+                    return Err(EvalError::Interrupted { span });
+                }
+            }
+            match instruction {
+                Instruction::Constant(index) => {
+                    self.stack.push(bytecode.constants[index as usize].clone())
+                }
+                Instruction::Pop => {
+                    self.pop();
+                }
+                Instruction::Dup => self.stack.push(self.peek(0).clone()),
+                Instruction::Load(name) => {
+                    let value = env.get(name).ok_or_else(|| EvalError::UnknownIdentifier {
+                        name: name.to_string(),
+                        span,
+                    })?;
+                    self.stack.push(value);
+                }
+                Instruction::Define(name) => env.define(name, self.peek(0).clone()),
+                Instruction::Assign(name) => {
+                    if !env.assign(name, self.peek(0).clone()) {
+                        return Err(EvalError::UnknownIdentifier {
+                            name: name.to_string(),
+                            span,
+                        });
+                    }
+                }
+                Instruction::ExpectNumber => {
+                    expect_number(self.peek(0).clone(), span)?;
+                }
+                Instruction::ExpectInteger => {
+                    expect_integer(self.peek(0).clone(), span)?;
+                }
+                Instruction::ExpectOperand(name) => {
+                    if env.get(name).is_none() {
+                        expect_number(self.peek(0).clone(), span)?;
+                    }
+                }
+                Instruction::Eq | Instruction::Ne => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    let equal = values_equal(&lhs, &rhs);
+                    self.stack
+                        .push(Value::Bool(equal == (instruction == Instruction::Eq)));
+                }
+                Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::Rem
+                | Instruction::Lt
+                | Instruction::Le
+                | Instruction::Gt
+                | Instruction::Ge => {
+                    let op = instruction.binary_op().expect("matched a binary operator");
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    self.stack.push(binary(op, lhs, rhs, span)?);
+                }
+                Instruction::AddConstant(index) => {
+                    let lhs = self.pop();
+                    let rhs = bytecode.constants[index as usize].clone();
+                    self.stack.push(binary(BinOp::Add, lhs, rhs, span)?);
+                }
+                Instruction::Neg => {
+                    let value = match self.pop() {
+                        Value::I64(n) => n
+                            .checked_neg()
+                            .map(Value::I64)
+                            .ok_or(EvalError::IntegerOverflow { span })?,
+                        Value::F64(n) => Value::F64(-n),
+                        _ => unreachable!("ExpectNumber precedes Neg"),
+                    };
+                    self.stack.push(value);
+                }
+                Instruction::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Bool(!value.is_truthy()));
+                }
+                Instruction::Truthy => {
+                    let value = self.pop();
+                    self.stack.push(Value::Bool(value.is_truthy()));
+                }
+                Instruction::Jump(target) => pc = target as usize,
+                Instruction::JumpIfFalse(target) => {
+                    if !self.pop().is_truthy() {
+                        pc = target as usize;
+                    }
+                }
+                Instruction::Operator { name, argc } => {
+                    let args = self.stack.split_off(self.stack.len() - argc as usize);
+                    let value = match env.get(name) {
+                        // 同じ名前の変数があれば、組み込みの演算子の代わりに呼び出す
+                        Some(Value::Fn(function)) => {
+                            self.call(&function, name.as_str(), args, span)?
+                        }
+                        Some(Value::NativeFn(function)) => function.call(&args, span)?,
+                        Some(_) => return Err(EvalError::NotAFunction { span }),
+                        None => arithmetic(name.as_str(), span, span, &args)?,
+                    };
+                    self.stack.push(value);
+                }
+                Instruction::ExpectFunction(argc) => {
+                    if !matches!(self.peek(argc as usize), Value::Fn(_) | Value::NativeFn(_)) {
+                        return Err(EvalError::NotAFunction { span });
+                    }
+                }
+                Instruction::Call { name, argc } => {
+                    let args = self.stack.split_off(self.stack.len() - argc as usize);
+                    let name = name.map_or("<fn>", |name| name.as_str());
+                    let value = match self.pop() {
+                        Value::Fn(function) => self.call(&function, name, args, span)?,
+                        Value::NativeFn(function) => function.call(&args, span)?,
+                        _ => unreachable!("ExpectFunction precedes Call"),
+                    };
+                    self.stack.push(value);
+                }
+                Instruction::Closure(index) => {
+                    let prototype = &bytecode.functions[index as usize];
+                    self.stack.push(Value::function(Function {
+                        params: prototype.params.clone(),
+                        body: FunctionBody::Compiled(prototype.code.clone()),
+                        env: env.clone(),
+                        span: prototype.span,
+                    }));
+                }
+                Instruction::PushScope => env.push_scope(),
+                Instruction::PopScope => env.pop_scope(),
+                Instruction::ForNext(target) => {
+                    let (Value::I64(i), Value::I64(end)) = (self.peek(1), self.peek(0)) else {
+                        unreachable!("ExpectInteger precedes ForNext");
+                    };
+                    let (i, end) = (*i, *end);
+                    if i < end {
+                        let len = self.stack.len();
+                        self.stack[len - 2] = Value::I64(i + 1);
+                        self.stack.push(Value::I64(i));
+                    } else {
+                        pc = target as usize;
+                    }
+                }
+                Instruction::OutsideLoop(keyword) => {
+                    return Err(EvalError::OutsideLoop { keyword, span })
+                }
+                Instruction::List(len) => {
+                    let items = self.stack.split_off(self.stack.len() - len as usize);
+                    let list = Value::List(items.into());
+                    limits::allocate_value(&list, span)?;
+                    self.stack.push(list);
+                }
+                Instruction::Array(len) => {
+                    let items = self.stack.split_off(self.stack.len() - len as usize);
+                    let array = Value::array(items);
+                    limits::allocate_value(&array, span)?;
+                    self.stack.push(array);
+                }
+                Instruction::Map(len) => {
+                    let items = self.stack.split_off(self.stack.len() - 2 * len as usize);
+                    let mut entries = HashMap::with_capacity(len as usize);
+                    let mut items = items.into_iter();
+                    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                        entries.insert(key, value);
+                    }
+                    let map = Value::map(entries);
+                    limits::allocate_value(&map, span)?;
+                    self.stack.push(map);
+                }
+                Instruction::ExpectKey => {
+                    let key = self.pop();
+                    self.stack.push(expect_key(key, span)?);
+                }
+                Instruction::Import(index) => {
+                    let Value::Str(path) = &bytecode.constants[index as usize] else {
+                        unreachable!("Import refers to a string constant");
+                    };
+                    self.stack.push(module::import(path, span)?);
+                }
+                Instruction::Index => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    self.stack.push(get_index(&target, &index, span)?);
+                }
+                Instruction::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let target = self.pop();
+                    set_index(&target, &index, value.clone(), span)?;
+                    self.stack.push(value);
+                }
+                Instruction::OutsideFunction => return Err(EvalError::OutsideFunction { span }),
+                Instruction::NoMatch => {
+                    let value = self.pop().to_string();
+                    return Err(EvalError::NoMatch { value, span });
+                }
+                Instruction::Return => {
+                    // 関数の途中の `return` は、ループが積んだ値を残したまま戻る
+                    let value = self.pop();
+                    self.stack.truncate(base);
+                    return Ok(value);
+                }
+            }
+        }
+    }
+
+    /// 関数を呼び出す
+    ///
+    /// コンパイル済みの関数はこのスタックマシンで、それ以外は木構造の評価器で実行する。
+    fn call(
+        &mut self,
+        function: &Function,
+        name: &str,
+        args: Vec<Value>,
+        span: Span,
+    ) -> Result<Value, EvalError> {
+        match &function.body {
+            FunctionBody::Compiled(code) => {
+                let _guard = CallGuard::enter(span)?;
+                let _profile = profile::enter(name, function.span);
+                #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+                if _profile.is_none() && self.hook.is_none() {
+                    if let Some(value) = crate::jit::call(function, code, &args) {
+                        return Ok(value);
+                    }
+                }
+                let mut env = bind_arguments(function, name, args, span)?;
+                if self.hook.is_some() {
+                    return self.enter(name, code, &mut env, |vm, env| vm.execute(code, env));
+                }
+                self.execute(code, &mut env)
+            }
+            FunctionBody::Tree(_) => call(function, name, args, span),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{Expr, ExprKind};
+    use crate::bytecode::compile;
+    use crate::eval::{eval_forms, eval_statements, lower};
+    use crate::infix::statements;
+    use crate::parser::source;
+    use crate::TokenTree;
+
+    /// 各式をコンパイルして同じ環境で順に実行する
+    fn run_str(input: &str) -> Result<Value, EvalError> {
+        let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+            panic!("failed to parse {input:?}");
+        };
+        let mut vm = Vm::new();
+        let mut env = Environment::new();
+        let mut last = Value::Nil;
+        for form in &forms {
+            last = vm.run(&compile(&lower(form)?), &mut env)?;
+        }
+        assert!(vm.stack.is_empty());
+        Ok(last)
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run_str("(+ 1 (* 2 3))"), Ok(Value::I64(7)));
+        assert_eq!(run_str("(- 10 2 3) (/ 4.0)"), Ok(Value::F64(0.25)));
+        assert_eq!(run_str("(+)"), Ok(Value::I64(0)));
+        assert_eq!(run_str("(&& 1 nil)"), Ok(Value::Bool(false)));
+        assert_eq!(run_str("(|| nil 2)"), Ok(Value::Bool(true)));
+        assert_eq!(
+            run_str("(define fact (fn (n) (if (<= n 1) 1 (* n (fact (- n 1)))))) (fact 10)"),
+            Ok(Value::I64(3628800))
+        );
+        assert_eq!(
+            run_str("(define add (fn (a) (fn (b) (+ a b)))) ((add 1) 2)"),
+            Ok(Value::I64(3))
+        );
+        // 二項演算にならない演算子の呼び出しは、同じ名前の変数で隠せる
+        assert_eq!(
+            run_str("(define + (fn (a) (* a a))) (+ 3)"),
+            Ok(Value::I64(9))
+        );
+    }
+
+    /// 中置記法の文の並びを1つのブロックとしてコンパイルして実行する
+    fn run_statements(input: &str) -> Result<Value, EvalError> {
+        let block = Expr::new(
+            ExprKind::Block(statements(input).unwrap()),
+            Span::new(0, input.len()),
+        );
+        Vm::new().run(&compile(&block), &mut Environment::new())
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_eq!(
+            run_statements(
+                "var s = 0; for i in 0..10 { if i == 5 { continue }; if i == 8 { break }; s = s + i }; s"
+            ),
+            Ok(Value::I64(23))
+        );
+        assert_eq!(
+            run_statements("var i = 0; while true { i = i + 1; if i == 3 { break } }; i"),
+            Ok(Value::I64(3))
+        );
+        // 式の途中の `continue` は、それまでに積んだ値を捨ててから次の繰り返しに進む
+        assert_eq!(
+            run_statements(
+                "var s = 0; for i in 0..5 { s = s + if i == 2 { continue } else { i } }; s"
+            ),
+            Ok(Value::I64(8))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            run_str("(+ 1 x)"),
+            Err(EvalError::UnknownIdentifier {
+                name: "x".to_string(),
+                span: Span::new(5, 6)
+            })
+        );
+        assert_eq!(
+            run_str("(1 2)"),
+            Err(EvalError::NotAFunction {
+                span: Span::new(1, 2)
+            })
+        );
+        assert_eq!(
+            run_str("(break)"),
+            Err(EvalError::OutsideLoop {
+                keyword: "break",
+                span: Span::new(0, 7)
+            })
+        );
+    }
+
+    #[test]
+    fn test_matches_tree_walker() {
+        for input in [
+            "(/ 7 2) (/ -7 2) (/ 7 2.0)",
+            "(% -7 2) (% 7.5 2) (% 1 0) (% -9223372036854775808 -1)",
+            "(define f (fn (x y) (- x y))) (f 10 3)",
+            "(let ((a 1) (b (+ a 1))) (* a b))",
+            "(define xs 0) (for (i 0 4) (define xs (+ xs i))) xs",
+            "(define g (fn (x) x)) (g 1 2)",
+            "(* 9223372036854775807 2)",
+            "(< 1 2.5) (== 1 1.0) (! 0)",
+            "(define x 1) `(a (b ,x) ,(+ x 1) 'c)",
+        ] {
+            let Ok(TokenTree::Tree(forms, _)) = source(input) else {
+                panic!("failed to parse {input:?}");
+            };
+            let expected = eval_forms(&forms, &mut Environment::new()).map(Option::unwrap);
+            assert_eq!(run_str(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_return() {
+        // ループの途中の `return` は、ループが積んだ値とスコープを残したまま関数から戻る
+        assert_eq!(
+            run_statements(
+                "fn find(n) { for i in 0..10 { { var j = i * i; if j > n { return i } } }; -1 }\n\
+                 find(20) * 100 + find(200)"
+            ),
+            Ok(Value::I64(499))
+        );
+        assert_eq!(run_statements("fn f() { return; 1 }\nf()"), Ok(Value::Nil));
+        assert_eq!(
+            run_statements("var x = 1; if x { return x }"),
+            Err(EvalError::OutsideFunction {
+                span: Span::new(18, 26)
+            })
+        );
+    }
+
+    #[test]
+    fn test_match() {
+        for input in [
+            "fn f(x) { match x { 1 => \"one\", \"s\" => 2, nil => 3, n if n > 9 => n, _ => 0 } }\n\
+             f(1) + f(1.0) + f(\"s\") * 10 + f(nil) * 100 + f(10) * 1000 + f(5)",
+            "fn g(x) { match x { 1 => \"one\", _ => x } }\ng(1)",
+            "var n = 1\nvar m = match 5 { n => n * 2 }\nm + n",
+            "var s = 0\nfor i in 0..10 { match i { 3 => continue, j if j > 6 => break, j => { s = s + j } } }\ns",
+            "fn count(n, acc) { match n { 0 => acc, _ => count(n - 1, acc + 1) } }\ncount(50, 0)",
+            "match 3 { 1 => 1, n if n < 0 => 2 }",
+        ] {
+            let expected = eval_statements(&statements(input).unwrap(), &mut Environment::new())
+                .map(|value| value.unwrap_or(Value::Nil));
+            assert_eq!(run_statements(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_array() {
+        for input in [
+            "var a = [1, [2, 3]]\na[1][0] = a[0] + 10\na",
+            "fn set(xs, i) { xs[i] = i * 2 }\nvar a = [0, 0, 0]\nfor i in 0..3 { set(a, i) }\na",
+            "var a = [1, 2]\na[2]",
+            "var a = [1]\na[-1] = 0",
+            "[1][true]",
+            "var k = 2\n{ k: [k], \"a\": { nil: 1 } } == { 2: [2.0], \"a\": { nil: 1 } }",
+            "{ \"a\": 1, 0.5: 2 }",
+        ] {
+            let expected = eval_statements(&statements(input).unwrap(), &mut Environment::new())
+                .map(|value| value.unwrap_or(Value::Nil));
+            assert_eq!(run_statements(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = std::env::temp_dir().join(format!("ruscal-vm-import-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("m.rscl"), "(define twice (fn (x) (* 2 x)))").unwrap();
+        crate::module::set_search_paths(vec![dir]);
+        for input in [
+            "var m = import \"m.rscl\"\nm[\"twice\"](21)",
+            "import \"m.rscl\" == import \"m.rscl\"",
+            "import \"missing.rscl\"",
+        ] {
+            let expected = eval_statements(&statements(input).unwrap(), &mut Environment::new())
+                .map(|value| value.unwrap_or(Value::Nil));
+            assert_eq!(run_statements(input), expected, "{input}");
+        }
+    }
+
+    /// 呼ばれたときの呼び出しの名前を記録し、`limit` 回目で打ち切るフック
+    struct Recorder {
+        calls: Rc<std::cell::RefCell<Vec<String>>>,
+        limit: usize,
+    }
+
+    impl Hook for Recorder {
+        fn before(&mut self, frames: &[Frame]) -> bool {
+            let names: Vec<_> = frames.iter().map(|frame| frame.name.as_str()).collect();
+            let mut calls = self.calls.borrow_mut();
+            calls.push(names.join(" > "));
+            calls.len() < self.limit
+        }
+    }
+
+    #[test]
+    fn test_hook() {
+        let input = "fn f(n) { n + 1 }\nf(1) + f(2)";
+        let bytecode = compile(&Expr::new(
+            ExprKind::Block(statements(input).unwrap()),
+            Span::new(0, input.len()),
+        ));
+        let calls = Rc::default();
+        let hook = Recorder {
+            calls: Rc::clone(&calls),
+            limit: usize::MAX,
+        };
+        let mut env = Environment::new();
+        let res = Vm::new().with_hook(hook).run(&bytecode, &mut env);
+        assert_eq!(res, Ok(Value::I64(5)));
+        let calls = calls.take();
+        assert_eq!(
+            calls.len(),
+            bytecode.code.len() + 2 * bytecode.functions[0].code.code.len()
+        );
+        assert_eq!(calls[0], "<top level>");
+        assert!(calls.contains(&"<top level> > f".to_string()));
+
+        // フックが `false` を返すと、その命令を実行せずに打ち切る
+        let calls = Rc::default();
+        let hook = Recorder {
+            calls: Rc::clone(&calls),
+            limit: 3,
+        };
+        let res = Vm::new().with_hook(hook).run(&bytecode, &mut env);
+        assert!(matches!(res, Err(EvalError::Interrupted { .. })));
+        assert_eq!(calls.borrow().len(), 3);
+    }
+}
@@ -0,0 +1,203 @@
+//! WebAssembly から JavaScript に公開する解析と評価の API
+//!
+//! `wasm` フィーチャーを有効にすると使える。外部クレートに依存しないよう wasm-bindgen は使わず、
+//! 文字列は線形メモリ上の UTF-8 のバイト列としてやり取りする。
+//!
+//! ```text
+//! cargo rustc --release --target wasm32-unknown-unknown --features wasm --crate-type cdylib
+//! ```
+//!
+//! JavaScript からは次のように呼び出す。結果はどちらの関数も JSON の文字列で、
+//! 成功すれば `{"Ok": ...}`、失敗すれば `{"Err": [報告, ...]}` の形になる。
+//!
+//! ```js
+//! const { exports } = (await WebAssembly.instantiateStreaming(fetch("ruscal_b.wasm"))).instance;
+//! function call(f, input) {
+//!   const bytes = new TextEncoder().encode(input);
+//!   const ptr = exports.ruscal_alloc(bytes.length);
+//!   new Uint8Array(exports.memory.buffer, ptr, bytes.length).set(bytes);
+//!   const len = exports[f](ptr, bytes.length);
+//!   exports.ruscal_dealloc(ptr, bytes.length);
+//!   const out = new Uint8Array(exports.memory.buffer, exports.ruscal_output_ptr(), len);
+//!   return JSON.parse(new TextDecoder().decode(out));
+//! }
+//! call("ruscal_eval_str", "(+ 1 2)"); // { Ok: "3" }
+//! ```
+
+use std::cell::RefCell;
+
+use crate::ast::{Span, TokenTree};
+use crate::diagnostics::Diagnostic;
+use crate::env::Environment;
+use crate::eval::eval_forms;
+use crate::json::{Json, ToJson};
+use crate::parser::source_recovering;
+use crate::stdlib;
+
+/// 報告の一覧を `{"Err": [...]}` にする
+fn errors(diagnostics: &[Diagnostic]) -> Json {
+    Json::tagged(
+        "Err",
+        Json::Array(diagnostics.iter().map(ToJson::to_json).collect()),
+    )
+}
+
+/// ソースコードを解析して、木を JSON の文字列にする関数
+///
+/// # 引数
+/// * `input` - 解析対象の文字列
+///
+/// # 戻り値
+/// * `String` - `{"Ok": 木}`、または解析エラーの報告を並べた `{"Err": [...]}`
+pub fn parse_to_json(input: &str) -> String {
+    let (tree, diagnostics) = source_recovering(input);
+    if diagnostics.is_empty() {
+        Json::tagged("Ok", tree.to_json()).to_string()
+    } else {
+        errors(&diagnostics).to_string()
+    }
+}
+
+/// ソースコードを標準ライブラリ入りの新しい環境で評価し、結果を JSON の文字列にする関数
+///
+/// `print` の出力は標準出力に書かれるので、wasm32-unknown-unknown では捨てられる。
+///
+/// # 引数
+/// * `input` - 評価するソースコード
+///
+/// # 戻り値
+/// * `String` - 最後の式の値を表示した文字列を持つ `{"Ok": "..."}`。式が無ければ `{"Ok": null}`
+///   - 解析や評価に失敗すれば、その報告を並べた `{"Err": [...]}`
+pub fn eval_str(input: &str) -> String {
+    let (tree, diagnostics) = source_recovering(input);
+    if !diagnostics.is_empty() {
+        return errors(&diagnostics).to_string();
+    }
+    let TokenTree::Tree(forms, _) = &tree else {
+        unreachable!("source_recovering() always returns a tree");
+    };
+    let mut env = Environment::new();
+    stdlib::register(&mut env);
+    match eval_forms(forms, &mut env) {
+        Ok(value) => {
+            let value = value.map_or(Json::Null, |value| Json::String(value.to_string()));
+            Json::tagged("Ok", value).to_string()
+        }
+        Err(e) => errors(&[Diagnostic::from(&e)]).to_string(),
+    }
+}
+
+thread_local! {
+    /// 最後に呼び出した関数の結果。次の呼び出しまで線形メモリ上に残す
+    static OUTPUT: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// 入力の文字列を置く領域を確保する
+///
+/// 確保した領域は [`ruscal_dealloc`] に同じ長さを渡して解放する。
+#[no_mangle]
+pub extern "C" fn ruscal_alloc(len: usize) -> *mut u8 {
+    let mut buf = Vec::<u8>::with_capacity(len);
+    let ptr = buf.as_mut_ptr();
+    std::mem::forget(buf);
+    ptr
+}
+
+/// [`ruscal_alloc`] で確保した領域を解放する
+///
+/// # Safety
+/// `ptr` と `len` は [`ruscal_alloc`] の引数と戻り値の組で、まだ解放していないものでなければならない。
+#[no_mangle]
+pub unsafe extern "C" fn ruscal_dealloc(ptr: *mut u8, len: usize) {
+    // SAFETY: 呼び出し側が ruscal_alloc(len) の戻り値を渡すことを保証する
+    drop(unsafe { Vec::from_raw_parts(ptr, 0, len) });
+}
+
+/// 最後の結果の先頭の位置
+#[no_mangle]
+pub extern "C" fn ruscal_output_ptr() -> *const u8 {
+    OUTPUT.with_borrow(|output| output.as_ptr())
+}
+
+/// 線形メモリ上の入力を読み、関数の結果を [`OUTPUT`] に置いてその長さを返す
+///
+/// # Safety
+/// `ptr` から `len` バイトが読み出せなければならない。
+unsafe fn call(ptr: *const u8, len: usize, f: fn(&str) -> String) -> usize {
+    // SAFETY: 呼び出し側が読み出せる範囲を渡すことを保証する
+    let bytes = unsafe { std::slice::from_raw_parts(ptr, len) };
+    let output = match std::str::from_utf8(bytes) {
+        Ok(input) => f(input),
+        Err(e) => errors(&[Diagnostic::new(
+            Span::new(e.valid_up_to(), e.valid_up_to()),
+            "input is not valid UTF-8",
+        )])
+        .to_string(),
+    };
+    OUTPUT.with_borrow_mut(|out| *out = output);
+    OUTPUT.with_borrow(String::len)
+}
+
+/// [`parse_to_json`] を呼び、結果のバイト数を返す
+///
+/// 結果は [`ruscal_output_ptr`] の位置から読む。
+///
+/// # Safety
+/// `ptr` から `len` バイトが読み出せなければならない。
+#[no_mangle]
+pub unsafe extern "C" fn ruscal_parse_to_json(ptr: *const u8, len: usize) -> usize {
+    // SAFETY: 引数の条件は呼び出し側が保証する
+    unsafe { call(ptr, len, parse_to_json) }
+}
+
+/// [`eval_str`] を呼び、結果のバイト数を返す
+///
+/// 結果は [`ruscal_output_ptr`] の位置から読む。
+///
+/// # Safety
+/// `ptr` から `len` バイトが読み出せなければならない。
+#[no_mangle]
+pub unsafe extern "C" fn ruscal_eval_str(ptr: *const u8, len: usize) -> usize {
+    // SAFETY: 引数の条件は呼び出し側が保証する
+    unsafe { call(ptr, len, eval_str) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_to_json() {
+        assert_eq!(
+            parse_to_json("1"),
+            r#"{"Ok":{"Tree":[[{"Token":[{"Int":1},{"start":0,"end":1}]}],{"start":0,"end":1}]}}"#
+        );
+        let out = Json::parse(&parse_to_json("(a")).unwrap();
+        let Some(Json::Array(errors)) = out.get("Err") else {
+            panic!("expected errors, got {out}");
+        };
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_eval_str() {
+        assert_eq!(eval_str("(define x 2) (* x 1.5)"), r#"{"Ok":"3.0"}"#);
+        assert_eq!(eval_str(""), r#"{"Ok":null}"#);
+        let out = Json::parse(&eval_str("(/ 1 0)")).unwrap();
+        assert!(out.get("Err").is_some(), "{out}");
+    }
+
+    #[test]
+    fn test_exports() {
+        let input = "(+ 1 2)";
+        let ptr = ruscal_alloc(input.len());
+        let len = unsafe {
+            std::ptr::copy_nonoverlapping(input.as_ptr(), ptr, input.len());
+            let len = ruscal_eval_str(ptr, input.len());
+            ruscal_dealloc(ptr, input.len());
+            len
+        };
+        let out = unsafe { std::slice::from_raw_parts(ruscal_output_ptr(), len) };
+        assert_eq!(out, br#"{"Ok":"3"}"#);
+    }
+}
@@ -0,0 +1,1221 @@
+//! 型の決まるプログラムを WebAssembly のモジュールにコンパイルするバックエンド
+//!
+//! 関数の型は [`transpile`](crate::transpile) と同じく、型注釈と最初に呼び出した場所の引数の型から
+//! 決める。整数、浮動小数点数、真偽値をそれぞれ `i64`、`f64`、`i32` の値で表し、`nil` は値を
+//! 持たない。変数は関数の局所変数になる。最上位で定義した関数は元の名前で、それ以外の最上位の
+//! 文は最後の文の値を返す `main` 関数として公開する。一度も呼び出さず型注釈も無い関数は
+//! 型が決まらないので、[`TranspileError::Unexported`] で呼び出すか型注釈を付けるよう求める。
+//!
+//! 整数の桁あふれと0での除算はトラップになる。`print` は1つの値を取り、`ruscal` モジュールから
+//! 取り込む `print_i64`、`print_f64`、`print_bool`、`print_nil` のどれかを呼び出す。文字列、関数値、
+//! 配列、マップなど、数値の値で表せない構文は [`TranspileError`] で拒否する。
+//!
+//! 外部クレートに依存しないよう wasm-encoder は使わず、モジュールのバイト列を直接組み立てる。
+//!
+//! ```
+//! use ruscal_b::statements;
+//! use ruscal_b::wasm_codegen::compile;
+//!
+//! let program = statements("fn square(x: i64) -> i64 { x * x }; square(7)").unwrap();
+//! let module = compile(&program).unwrap();
+//! assert!(module.starts_with(b"\0asm\x01\0\0\0"));
+//! ```
+//!
+//! S式のプログラムは、最上位の式を [`Statement::Expr`] で包めばコンパイルできる。
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::ast::{BinOp, Expr, ExprKind, Span, Statement, TypeName, UnOp};
+use crate::intern::Symbol;
+use crate::transpile::{
+    expr_item, item, join, let_items, number, Backend, Function, Item, Program, TranspileError, Ty,
+};
+
+/// `i32` の値の型。真偽値を表す
+const I32: u8 = 0x7f;
+/// `i64` の値の型。整数を表す
+const I64: u8 = 0x7e;
+/// `f64` の値の型。浮動小数点数を表す
+const F64: u8 = 0x7c;
+/// 値を残さないブロックの型
+const EMPTY: u8 = 0x40;
+
+/// 使う命令の番号
+mod op {
+    pub const UNREACHABLE: u8 = 0x00;
+    pub const BLOCK: u8 = 0x02;
+    pub const LOOP: u8 = 0x03;
+    pub const IF: u8 = 0x04;
+    pub const ELSE: u8 = 0x05;
+    pub const END: u8 = 0x0b;
+    pub const BR: u8 = 0x0c;
+    pub const BR_IF: u8 = 0x0d;
+    pub const RETURN: u8 = 0x0f;
+    pub const CALL: u8 = 0x10;
+    pub const DROP: u8 = 0x1a;
+    pub const SELECT: u8 = 0x1b;
+    pub const LOCAL_GET: u8 = 0x20;
+    pub const LOCAL_SET: u8 = 0x21;
+    pub const LOCAL_TEE: u8 = 0x22;
+    pub const I32_CONST: u8 = 0x41;
+    pub const I64_CONST: u8 = 0x42;
+    pub const F64_CONST: u8 = 0x44;
+    pub const I32_EQZ: u8 = 0x45;
+    pub const I32_EQ: u8 = 0x46;
+    pub const I32_NE: u8 = 0x47;
+    pub const I64_EQZ: u8 = 0x50;
+    pub const I64_EQ: u8 = 0x51;
+    pub const I64_NE: u8 = 0x52;
+    pub const I64_LT_S: u8 = 0x53;
+    pub const I64_GT_S: u8 = 0x55;
+    pub const I64_LE_S: u8 = 0x57;
+    pub const I64_GE_S: u8 = 0x59;
+    pub const F64_EQ: u8 = 0x61;
+    pub const F64_NE: u8 = 0x62;
+    pub const F64_LT: u8 = 0x63;
+    pub const F64_GT: u8 = 0x64;
+    pub const F64_LE: u8 = 0x65;
+    pub const F64_GE: u8 = 0x66;
+    pub const I32_AND: u8 = 0x71;
+    pub const I64_ADD: u8 = 0x7c;
+    pub const I64_SUB: u8 = 0x7d;
+    pub const I64_MUL: u8 = 0x7e;
+    pub const I64_DIV_S: u8 = 0x7f;
+    pub const I64_REM_S: u8 = 0x81;
+    pub const I64_AND: u8 = 0x83;
+    pub const I64_XOR: u8 = 0x85;
+    pub const F64_ABS: u8 = 0x99;
+    pub const F64_NEG: u8 = 0x9a;
+    pub const F64_SQRT: u8 = 0x9f;
+    pub const F64_ADD: u8 = 0xa0;
+    pub const F64_SUB: u8 = 0xa1;
+    pub const F64_MUL: u8 = 0xa2;
+    pub const F64_DIV: u8 = 0xa3;
+    pub const F64_CONVERT_I64_S: u8 = 0xb9;
+}
+
+/// プログラムを WebAssembly のモジュールにコンパイルする関数
+///
+/// 一度も呼び出さず型注釈も無い関数は、公開する型が決まらないので拒否する。
+/// `main` という名前の関数は、最上位の文をまとめた関数と重なるので拒否する。
+pub fn compile(statements: &[Statement]) -> Result<Vec<u8>, TranspileError> {
+    let program = Program::new(statements)?;
+    for function in &program.functions {
+        let annotated = function.param_types.iter().flatten().chain(&function.ret);
+        if annotated.copied().any(|ty| ty == TypeName::Str) {
+            return Err(TranspileError::Unsupported {
+                construct: "a string",
+                span: function.span,
+            });
+        }
+        if function.name.as_str() == "main" {
+            return Err(TranspileError::Unsupported {
+                construct: "a function named `main`",
+                span: function.span,
+            });
+        }
+    }
+    let mut compiler = Compiler {
+        functions: program.functions,
+        index: program.index,
+    };
+    let mut cx = Context::new(&[], false, false);
+    let ty = compiler.items(&mut cx, &program.main, false)?;
+    compiler.instantiate_annotated()?;
+    if let Some(function) = compiler.functions.iter().find(|f| f.code.is_none()) {
+        return Err(TranspileError::Unexported {
+            name: function.name.as_str().to_string(),
+            span: function.span,
+        });
+    }
+    let main = Body {
+        locals: cx.locals,
+        code: cx.code,
+    };
+    Ok(compiler.module(main, value_type(ty)))
+}
+
+/// 呼び出す関数。関数の番号はモジュールを組み立てるときに決める
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Callee {
+    /// 取り込む表示の関数
+    Host(Host),
+    /// 最上位で定義した関数
+    Function(usize),
+    /// モジュールに加える補助関数
+    Helper(Helper),
+}
+
+/// `ruscal` モジュールから取り込む関数。値を1つ表示する
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Host {
+    I64,
+    F64,
+    Bool,
+    Nil,
+}
+
+impl Host {
+    fn name(self) -> &'static str {
+        match self {
+            Self::I64 => "print_i64",
+            Self::F64 => "print_f64",
+            Self::Bool => "print_bool",
+            Self::Nil => "print_nil",
+        }
+    }
+
+    fn params(self) -> &'static [u8] {
+        match self {
+            Self::I64 => &[I64],
+            Self::F64 => &[F64],
+            Self::Bool => &[I32],
+            Self::Nil => &[],
+        }
+    }
+}
+
+/// 整数の桁あふれを確かめる演算と、浮動小数点数の真偽値への変換の補助関数
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Helper {
+    Add,
+    Sub,
+    Mul,
+    Rem,
+    Neg,
+    Abs,
+    Truthy,
+}
+
+impl Helper {
+    fn params(self) -> &'static [u8] {
+        match self {
+            Self::Add | Self::Sub | Self::Mul | Self::Rem => &[I64, I64],
+            Self::Neg | Self::Abs => &[I64],
+            Self::Truthy => &[F64],
+        }
+    }
+
+    fn result(self) -> u8 {
+        match self {
+            Self::Truthy => I32,
+            _ => I64,
+        }
+    }
+
+    /// 補助関数の本体。結果を入れる局所変数の型と命令の並び
+    fn body(self) -> Body {
+        use op::*;
+        let mut code = Code::default();
+        let mut locals = vec![];
+        match self {
+            Self::Add | Self::Sub => {
+                // 結果の符号が、加算なら両方の項と、減算なら左の項と違い右の項と同じなら桁あふれ
+                let (arith, other) = if self == Self::Add {
+                    (I64_ADD, 2)
+                } else {
+                    (I64_SUB, 0)
+                };
+                locals.push(I64);
+                code.ops(&[LOCAL_GET, 0, LOCAL_GET, 1, arith, LOCAL_SET, 2]);
+                code.ops(&[LOCAL_GET, 0, LOCAL_GET, 2, I64_XOR]);
+                code.ops(&[LOCAL_GET, 1, LOCAL_GET, other, I64_XOR, I64_AND]);
+                code.ops(&[I64_CONST, 0, I64_LT_S, IF, EMPTY, UNREACHABLE, END]);
+                code.ops(&[LOCAL_GET, 2]);
+            }
+            Self::Mul => {
+                // 左の項が0でなく、結果を左の項で割って右の項に戻らなければ桁あふれ
+                locals.push(I64);
+                code.ops(&[LOCAL_GET, 0, LOCAL_GET, 1, I64_MUL, LOCAL_SET, 2]);
+                code.ops(&[LOCAL_GET, 0, I64_EQZ, I32_EQZ, IF, EMPTY]);
+                code.ops(&[LOCAL_GET, 2, LOCAL_GET, 0, I64_DIV_S, LOCAL_GET, 1, I64_NE]);
+                code.ops(&[IF, EMPTY, UNREACHABLE, END, END, LOCAL_GET, 2]);
+            }
+            Self::Rem => {
+                // `i64::MIN % -1` は WebAssembly では0になるが、ほかの実行方法に合わせて桁あふれにする
+                code.ops(&[LOCAL_GET, 1]);
+                code.i64_const(-1);
+                code.ops(&[I64_EQ, IF, EMPTY, LOCAL_GET, 0]);
+                code.i64_const(i64::MIN);
+                code.ops(&[I64_EQ, IF, EMPTY, UNREACHABLE, END, END]);
+                code.ops(&[LOCAL_GET, 0, LOCAL_GET, 1, I64_REM_S]);
+            }
+            Self::Neg | Self::Abs => {
+                code.ops(&[LOCAL_GET, 0]);
+                code.i64_const(i64::MIN);
+                code.ops(&[I64_EQ, IF, EMPTY, UNREACHABLE, END]);
+                code.ops(&[I64_CONST, 0, LOCAL_GET, 0, I64_SUB]);
+                if self == Self::Abs {
+                    code.ops(&[LOCAL_GET, 0, LOCAL_GET, 0, I64_CONST, 0, I64_LT_S, SELECT]);
+                }
+            }
+            Self::Truthy => {
+                // 0 と NaN が偽になる
+                code.ops(&[LOCAL_GET, 0]);
+                code.f64_const(0.0);
+                code.ops(&[F64_NE, LOCAL_GET, 0, LOCAL_GET, 0, F64_EQ, I32_AND]);
+            }
+        }
+        Body { locals, code }
+    }
+}
+
+/// 関数の命令の並び
+#[derive(Default)]
+struct Code {
+    bytes: Vec<u8>,
+    /// `call` の命令の後の、関数の番号を書く位置と呼び出す関数
+    calls: Vec<(usize, Callee)>,
+}
+
+impl Code {
+    fn ops(&mut self, ops: &[u8]) {
+        self.bytes.extend_from_slice(ops);
+    }
+
+    fn index(&mut self, index: u32) {
+        uleb(&mut self.bytes, index.into());
+    }
+
+    fn i64_const(&mut self, n: i64) {
+        self.bytes.push(op::I64_CONST);
+        sleb(&mut self.bytes, n);
+    }
+
+    fn f64_const(&mut self, n: f64) {
+        self.bytes.push(op::F64_CONST);
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    /// 関数を呼び出す。番号は後で書き込むので、5バイトの場所を空けておく
+    fn call(&mut self, callee: Callee) {
+        self.bytes.push(op::CALL);
+        self.calls.push((self.bytes.len(), callee));
+        self.bytes.extend_from_slice(&[0; 5]);
+    }
+}
+
+/// コンパイルした関数の本体
+struct Body {
+    /// 仮引数の後に置く局所変数の型
+    locals: Vec<u8>,
+    code: Code,
+}
+
+/// WebAssembly のモジュールにコンパイルするバックエンド
+struct Compiler<'a> {
+    functions: Vec<Function<'a, Body>>,
+    index: HashMap<Symbol, usize>,
+}
+
+/// 局所変数
+struct Local {
+    /// 局所変数の番号。`nil` の変数は値を持たないので `None`
+    index: Option<u32>,
+    ty: Ty,
+}
+
+/// 1つの関数の本体か、`main` の本体をコンパイルしている間の状態
+struct Context {
+    scopes: Vec<HashMap<Symbol, Local>>,
+    /// 関数の本体なら `true`。`return` を使える
+    function: bool,
+    /// 戻り値の型を推論するための仮のコンパイルなら `true`
+    tentative: bool,
+    /// `return` で返す値の型
+    returns: Vec<(Ty, Span)>,
+    /// 値を持つ仮引数の数
+    params: u32,
+    /// 仮引数の後に置く局所変数の型
+    locals: Vec<u8>,
+    /// 囲んでいる `block`、`loop`、`if` の数
+    depth: u32,
+    /// 囲んでいるループの、`break` と `continue` で飛ぶ先のラベルの深さ
+    loops: Vec<(u32, u32)>,
+    code: Code,
+}
+
+impl Context {
+    fn new(params: &[(Symbol, TypeName)], function: bool, tentative: bool) -> Self {
+        let mut count = 0;
+        let scope = params
+            .iter()
+            .map(|&(name, ty)| {
+                let index = value_type(Some(ty)).map(|_| {
+                    count += 1;
+                    count - 1
+                });
+                let ty = Some(ty);
+                (name, Local { index, ty })
+            })
+            .collect();
+        Self {
+            scopes: vec![scope],
+            function,
+            tentative,
+            returns: vec![],
+            params: count,
+            locals: vec![],
+            depth: 0,
+            loops: vec![],
+            code: Code::default(),
+        }
+    }
+
+    fn lookup(&mut self, name: Symbol) -> Option<&mut Local> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(&name))
+    }
+
+    /// 型が決まっていなければ、仮のコンパイルでない限り誤りにする
+    fn require(&self, ty: Ty, span: Span) -> Result<Ty, TranspileError> {
+        match ty {
+            None if !self.tentative => Err(TranspileError::CannotInfer { span }),
+            ty => Ok(ty),
+        }
+    }
+
+    /// 新しい局所変数を作り、その番号を返す
+    fn local(&mut self, ty: u8) -> u32 {
+        self.locals.push(ty);
+        self.params + self.locals.len() as u32 - 1
+    }
+
+    /// `block`、`loop`、`if` を始め、そのラベルの深さを返す
+    fn enter(&mut self, op: u8, block_type: u8) -> u32 {
+        self.code.ops(&[op, block_type]);
+        self.depth += 1;
+        self.depth - 1
+    }
+
+    fn end(&mut self) {
+        self.code.ops(&[op::END]);
+        self.depth -= 1;
+    }
+
+    /// 深さ `label` のラベルへ飛ぶ `br` か `br_if`
+    fn branch(&mut self, op: u8, label: u32) {
+        self.code.ops(&[op]);
+        self.code.index(self.depth - 1 - label);
+    }
+
+    /// スタックの値を局所変数に入れる。`keep` なら値をスタックにも残す
+    fn store(&mut self, index: Option<u32>, keep: bool) {
+        if let Some(index) = index {
+            self.code
+                .ops(&[if keep { op::LOCAL_TEE } else { op::LOCAL_SET }]);
+            self.code.index(index);
+        }
+    }
+}
+
+impl<'a> Backend<'a> for Compiler<'a> {
+    type Code = Body;
+
+    fn functions(&mut self) -> &mut [Function<'a, Body>] {
+        &mut self.functions
+    }
+
+    fn function_body(&mut self, id: usize, tentative: bool) -> Result<(Body, Ty), TranspileError> {
+        let function = &self.functions[id];
+        let (params, body, span, ret) =
+            (function.params, function.body, function.span, function.ret);
+        let types = function
+            .param_types
+            .clone()
+            .expect("parameter types are known before compiling the body");
+        let typed: Vec<_> = params.iter().copied().zip(types).collect();
+        let mut cx = Context::new(&typed, true, tentative);
+        // 中置記法の関数の本体は1つのブロックなので、中の文を直接並べる
+        let ty = match body {
+            [body] => self.body(&mut cx, body, false)?,
+            _ => {
+                let items: Vec<_> = body.iter().map(expr_item).collect();
+                self.items(&mut cx, &items, false)?
+            }
+        };
+        let mut found = ty;
+        for &(ty, span) in &cx.returns {
+            found = join(found, ty, span)?;
+        }
+        let ty = join(ret, found, span)?;
+        let body = Body {
+            locals: cx.locals,
+            code: cx.code,
+        };
+        Ok((body, ty))
+    }
+}
+
+impl Compiler<'_> {
+    /// 並びを新しいスコープでコンパイルし、最後の要素の型を返す
+    ///
+    /// `discard` なら並びの値を使わないので、最後の要素の値もスタックに残さない。
+    fn items(
+        &mut self,
+        cx: &mut Context,
+        items: &[Item],
+        discard: bool,
+    ) -> Result<Ty, TranspileError> {
+        cx.scopes.push(HashMap::new());
+        let res = self.items_in_scope(cx, items, discard);
+        cx.scopes.pop();
+        res
+    }
+
+    fn items_in_scope(
+        &mut self,
+        cx: &mut Context,
+        items: &[Item],
+        discard: bool,
+    ) -> Result<Ty, TranspileError> {
+        let mut last = Some(TypeName::Nil);
+        for (i, item) in items.iter().enumerate() {
+            let keep = i + 1 == items.len() && !discard;
+            match *item {
+                Item::Define(name, value) => {
+                    let ty = self.expr(cx, value)?;
+                    let ty = cx.require(ty, value.span)?;
+                    let index = value_type(ty).map(|ty| cx.local(ty));
+                    cx.store(index, keep);
+                    cx.scopes
+                        .last_mut()
+                        .expect("a scope is pushed before compiling items")
+                        .insert(name, Local { index, ty });
+                    last = ty;
+                }
+                Item::Assign(name, value, span) => {
+                    let ty = self.expr(cx, value)?;
+                    let Some(local) = cx.lookup(name) else {
+                        return Err(TranspileError::UnknownIdentifier {
+                            name: name.to_string(),
+                            span,
+                        });
+                    };
+                    local.ty = join(local.ty, ty, value.span)?;
+                    let index = local.index;
+                    cx.store(index, keep);
+                    last = ty;
+                }
+                Item::Expr(expr) if keep => last = self.expr(cx, expr)?,
+                Item::Expr(expr) => self.discarded(cx, expr)?,
+            }
+        }
+        Ok(if discard { Some(TypeName::Nil) } else { last })
+    }
+
+    /// 式の本体をコンパイルする。`{ ... }` のブロックは中の文を直接並べる
+    fn body(&mut self, cx: &mut Context, expr: &Expr, discard: bool) -> Result<Ty, TranspileError> {
+        match &expr.kind {
+            ExprKind::Block(statements) => {
+                let items = statements.iter().map(item).collect::<Result<Vec<_>, _>>()?;
+                self.items(cx, &items, discard)
+            }
+            _ if discard => {
+                self.discarded(cx, expr)?;
+                Ok(Some(TypeName::Nil))
+            }
+            _ => self.expr(cx, expr),
+        }
+    }
+
+    /// 値を使わない位置の式をコンパイルする。値はスタックに残さない
+    ///
+    /// `if` とブロックの値も捨てるので、`if` の腕の型は揃っていなくてよい。
+    fn discarded(&mut self, cx: &mut Context, expr: &Expr) -> Result<(), TranspileError> {
+        match &expr.kind {
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let else_branch = else_branch.as_deref();
+                self.if_expr(cx, cond, then_branch, else_branch, true)?;
+            }
+            ExprKind::Block(_) => {
+                self.body(cx, expr, true)?;
+            }
+            ExprKind::Let { bindings, body } => {
+                self.items(cx, &let_items(bindings, body), true)?;
+            }
+            _ => {
+                if value_type(self.expr(cx, expr)?).is_some() {
+                    cx.code.ops(&[op::DROP]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 式をコンパイルし、その型を返す。値を持つ型なら値を1つスタックに積む
+    fn expr(&mut self, cx: &mut Context, expr: &Expr) -> Result<Ty, TranspileError> {
+        let span = expr.span;
+        let unsupported = |construct| Err(TranspileError::Unsupported { construct, span });
+        Ok(match &expr.kind {
+            ExprKind::Int(n) => {
+                cx.code.i64_const(*n);
+                Some(TypeName::Int)
+            }
+            ExprKind::Float(n) => {
+                cx.code.f64_const(*n);
+                Some(TypeName::Float)
+            }
+            ExprKind::Str(_) => return unsupported("a string"),
+            ExprKind::Bool(b) => {
+                cx.code.ops(&[op::I32_CONST, u8::from(*b)]);
+                Some(TypeName::Bool)
+            }
+            ExprKind::Nil => Some(TypeName::Nil),
+            ExprKind::Ident(name) => match cx.lookup(*name) {
+                Some(&mut Local { index, ty }) => {
+                    if let Some(index) = index {
+                        cx.code.ops(&[op::LOCAL_GET]);
+                        cx.code.index(index);
+                    }
+                    ty
+                }
+                None if self.index.contains_key(name) => return unsupported("a function value"),
+                None => {
+                    return Err(TranspileError::UnknownIdentifier {
+                        name: name.to_string(),
+                        span,
+                    })
+                }
+            },
+            ExprKind::BinaryOp { op, lhs, rhs } => self.binary(cx, *op, lhs, rhs)?,
+            ExprKind::UnaryOp { op, operand } => {
+                let ty = self.expr(cx, operand)?;
+                match (op, ty) {
+                    (UnOp::Not, ty) => {
+                        truthy(cx, ty);
+                        cx.code.ops(&[op::I32_EQZ]);
+                        Some(TypeName::Bool)
+                    }
+                    (UnOp::Neg, Some(TypeName::Int)) => {
+                        cx.code.call(Callee::Helper(Helper::Neg));
+                        ty
+                    }
+                    (UnOp::Neg, Some(TypeName::Float) | None) => {
+                        cx.code.ops(&[op::F64_NEG]);
+                        ty
+                    }
+                    (UnOp::Neg, Some(found)) => {
+                        return Err(TranspileError::NotANumber {
+                            found,
+                            span: operand.span,
+                        })
+                    }
+                }
+            }
+            ExprKind::Group(inner) => self.expr(cx, inner)?,
+            ExprKind::Call { func, args } => self.call(cx, func, args, span)?,
+            ExprKind::Define { .. } => return unsupported("a definition inside an expression"),
+            ExprKind::Fn { .. } => return unsupported("a closure"),
+            ExprKind::Let { bindings, body } => {
+                self.items(cx, &let_items(bindings, body), false)?
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let else_branch = else_branch.as_deref();
+                self.if_expr(cx, cond, then_branch, else_branch, false)?
+            }
+            ExprKind::Block(_) => self.body(cx, expr, false)?,
+            ExprKind::While { cond, body } => {
+                let exit = cx.enter(op::BLOCK, EMPTY);
+                let top = cx.enter(op::LOOP, EMPTY);
+                let ty = self.expr(cx, cond)?;
+                truthy(cx, ty);
+                cx.code.ops(&[op::I32_EQZ]);
+                cx.branch(op::BR_IF, exit);
+                cx.loops.push((exit, top));
+                let res = self.body(cx, body, true);
+                cx.loops.pop();
+                res?;
+                cx.branch(op::BR, top);
+                cx.end();
+                cx.end();
+                Some(TypeName::Nil)
+            }
+            ExprKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                // 範囲は始めに1度だけ求め、本体で変数に代入しても次の値は変わらない
+                let mut bound = |expr: &Expr| -> Result<u32, TranspileError> {
+                    let ty = self.expr(cx, expr)?;
+                    join(Some(TypeName::Int), ty, expr.span)?;
+                    let index = cx.local(I64);
+                    cx.store(Some(index), false);
+                    Ok(index)
+                };
+                let (counter, limit) = (bound(start)?, bound(end)?);
+                let index = cx.local(I64);
+                let exit = cx.enter(op::BLOCK, EMPTY);
+                let top = cx.enter(op::LOOP, EMPTY);
+                cx.code.ops(&[op::LOCAL_GET]);
+                cx.code.index(counter);
+                cx.code.ops(&[op::LOCAL_GET]);
+                cx.code.index(limit);
+                cx.code.ops(&[op::I64_GE_S]);
+                cx.branch(op::BR_IF, exit);
+                cx.code.ops(&[op::LOCAL_GET]);
+                cx.code.index(counter);
+                cx.store(Some(index), false);
+                let next = cx.enter(op::BLOCK, EMPTY);
+                let local = Local {
+                    index: Some(index),
+                    ty: Some(TypeName::Int),
+                };
+                cx.scopes.push(HashMap::from([(*var, local)]));
+                cx.loops.push((exit, next));
+                let res = self.body(cx, body, true);
+                cx.loops.pop();
+                cx.scopes.pop();
+                res?;
+                cx.end();
+                cx.code.ops(&[op::LOCAL_GET]);
+                cx.code.index(counter);
+                cx.code.i64_const(1);
+                cx.code.ops(&[op::I64_ADD]);
+                cx.store(Some(counter), false);
+                cx.branch(op::BR, top);
+                cx.end();
+                cx.end();
+                Some(TypeName::Nil)
+            }
+            ExprKind::Break | ExprKind::Continue if cx.loops.is_empty() => {
+                return unsupported("a jump outside of a loop")
+            }
+            ExprKind::Break | ExprKind::Continue => {
+                let (exit, next) = *cx.loops.last().expect("checked above");
+                let label = if matches!(expr.kind, ExprKind::Break) {
+                    exit
+                } else {
+                    next
+                };
+                cx.branch(op::BR, label);
+                None
+            }
+            ExprKind::Return(_) if !cx.function => {
+                return unsupported("a return outside of a function")
+            }
+            ExprKind::Return(value) => {
+                let ty = match value {
+                    Some(value) => self.expr(cx, value)?,
+                    None => Some(TypeName::Nil),
+                };
+                cx.code.ops(&[op::RETURN]);
+                cx.returns.push((ty, span));
+                None
+            }
+            ExprKind::Match { .. } => return unsupported("a match expression"),
+            ExprKind::Array(_) => return unsupported("an array"),
+            ExprKind::Map(_) => return unsupported("a map"),
+            ExprKind::Import(_) => return unsupported("an import"),
+            ExprKind::Index { .. } => return unsupported("an index expression"),
+            ExprKind::Quote(_) | ExprKind::Quasiquote(_) => return unsupported("quoted data"),
+        })
+    }
+
+    /// `if` 式をコンパイルする
+    ///
+    /// ブロックの型は両方の腕をコンパイルして型が決まってから書き込む。`discard` なら値を捨てる
+    /// 位置の `if` なので、腕の型を揃えない。
+    fn if_expr(
+        &mut self,
+        cx: &mut Context,
+        cond: &Expr,
+        then_branch: &Expr,
+        else_branch: Option<&Expr>,
+        discard: bool,
+    ) -> Result<Ty, TranspileError> {
+        let ty = self.expr(cx, cond)?;
+        truthy(cx, ty);
+        cx.enter(op::IF, EMPTY);
+        let block_type = cx.code.bytes.len() - 1;
+        let then_ty = self.body(cx, then_branch, discard)?;
+        let Some(else_branch) = else_branch else {
+            // 条件が偽なら `nil` になるので、値を使うなら本体も `nil` でなければならない
+            join(Some(TypeName::Nil), then_ty, then_branch.span)?;
+            cx.end();
+            return Ok(Some(TypeName::Nil));
+        };
+        cx.code.ops(&[op::ELSE]);
+        let else_ty = self.body(cx, else_branch, discard)?;
+        let ty = join(then_ty, else_ty, else_branch.span)?;
+        cx.end();
+        match value_type(ty) {
+            Some(value) => cx.code.bytes[block_type] = value,
+            // どちらの腕も値にならなければ、後に続く命令には届かない
+            None if ty.is_none() => cx.code.ops(&[op::UNREACHABLE]),
+            None => {}
+        }
+        Ok(ty)
+    }
+
+    /// 二項演算をコンパイルする
+    fn binary(
+        &mut self,
+        cx: &mut Context,
+        op: BinOp,
+        lhs: &Expr,
+        rhs: &Expr,
+    ) -> Result<Ty, TranspileError> {
+        use op::*;
+        let lhs_ty = self.expr(cx, lhs)?;
+        if let BinOp::And | BinOp::Or = op {
+            truthy(cx, lhs_ty);
+            cx.enter(IF, I32);
+            if op == BinOp::Or {
+                cx.code.ops(&[I32_CONST, 1, ELSE]);
+            }
+            let rhs_ty = self.expr(cx, rhs)?;
+            truthy(cx, rhs_ty);
+            if op == BinOp::And {
+                cx.code.ops(&[ELSE, I32_CONST, 0]);
+            }
+            cx.end();
+            return Ok(Some(TypeName::Bool));
+        }
+        let rhs_ty = self.expr(cx, rhs)?;
+        let numeric = |ty: Ty| matches!(ty, Some(TypeName::Int | TypeName::Float) | None);
+        if matches!(op, BinOp::Eq | BinOp::Ne) && !(numeric(lhs_ty) && numeric(rhs_ty)) {
+            let eq = op == BinOp::Eq;
+            match join(lhs_ty, rhs_ty, rhs.span)? {
+                Some(TypeName::Bool) => cx.code.ops(&[if eq { I32_EQ } else { I32_NE }]),
+                // `nil` は値を持たないので、比べるまでもなく等しい
+                _ => cx.code.ops(&[I32_CONST, u8::from(eq)]),
+            }
+            return Ok(Some(TypeName::Bool));
+        }
+        let lhs_ty = number(lhs_ty, lhs.span)?;
+        let rhs_ty = number(rhs_ty, rhs.span)?;
+        let float = lhs_ty == Some(TypeName::Float) || rhs_ty == Some(TypeName::Float);
+        // 整数と浮動小数点数の演算は、整数を浮動小数点数にしてから計算する
+        if float && lhs_ty == Some(TypeName::Int) {
+            let rhs = cx.local(F64);
+            cx.store(Some(rhs), false);
+            cx.code.ops(&[F64_CONVERT_I64_S, LOCAL_GET]);
+            cx.code.index(rhs);
+        }
+        if float && rhs_ty == Some(TypeName::Int) {
+            cx.code.ops(&[F64_CONVERT_I64_S]);
+        }
+        let ty = if float {
+            Some(TypeName::Float)
+        } else if lhs_ty.is_some() || rhs_ty.is_some() {
+            Some(TypeName::Int)
+        } else {
+            None
+        };
+        let instr = match (op, float) {
+            (BinOp::Add, false) => return Ok(call_helper(cx, Helper::Add, ty)),
+            (BinOp::Sub, false) => return Ok(call_helper(cx, Helper::Sub, ty)),
+            (BinOp::Mul, false) => return Ok(call_helper(cx, Helper::Mul, ty)),
+            (BinOp::Div, false) => I64_DIV_S,
+            (BinOp::Rem, false) => return Ok(call_helper(cx, Helper::Rem, ty)),
+            (BinOp::Rem, true) => {
+                return Err(TranspileError::Unsupported {
+                    construct: "a remainder of floats",
+                    span: lhs.span.merge(rhs.span),
+                })
+            }
+            (BinOp::Add, true) => F64_ADD,
+            (BinOp::Sub, true) => F64_SUB,
+            (BinOp::Mul, true) => F64_MUL,
+            (BinOp::Div, true) => F64_DIV,
+            (BinOp::Lt, false) => I64_LT_S,
+            (BinOp::Le, false) => I64_LE_S,
+            (BinOp::Gt, false) => I64_GT_S,
+            (BinOp::Ge, false) => I64_GE_S,
+            (BinOp::Eq, false) => I64_EQ,
+            (BinOp::Ne, false) => I64_NE,
+            (BinOp::Lt, true) => F64_LT,
+            (BinOp::Le, true) => F64_LE,
+            (BinOp::Gt, true) => F64_GT,
+            (BinOp::Ge, true) => F64_GE,
+            (BinOp::Eq, true) => F64_EQ,
+            (BinOp::Ne, true) => F64_NE,
+            (BinOp::And | BinOp::Or, _) => unreachable!("logical operators are handled above"),
+        };
+        cx.code.ops(&[instr]);
+        Ok(if op.is_comparison() {
+            Some(TypeName::Bool)
+        } else {
+            ty
+        })
+    }
+
+    /// 最上位の関数か、一部の標準関数の呼び出しをコンパイルする
+    fn call(
+        &mut self,
+        cx: &mut Context,
+        func: &Expr,
+        args: &[Expr],
+        span: Span,
+    ) -> Result<Ty, TranspileError> {
+        let ExprKind::Ident(name) = func.kind else {
+            return Err(TranspileError::Unsupported {
+                construct: "a call of a computed function",
+                span,
+            });
+        };
+        if cx.lookup(name).is_some() {
+            return Err(TranspileError::Unsupported {
+                construct: "a call of a local variable",
+                span,
+            });
+        }
+        let types = args
+            .iter()
+            .map(|arg| self.expr(cx, arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(&id) = self.index.get(&name) {
+            let ty = self.instantiate(cx.tentative, id, &types, span)?;
+            cx.code.call(Callee::Function(id));
+            return Ok(ty);
+        }
+        if types.len() != 1 && matches!(name.as_str(), "print" | "sqrt" | "abs") {
+            return Err(TranspileError::Arity {
+                expected: 1,
+                found: types.len(),
+                span,
+            });
+        }
+        match name.as_str() {
+            "print" => {
+                let host = match cx.require(types[0], span)? {
+                    Some(TypeName::Int) => Host::I64,
+                    Some(TypeName::Float) => Host::F64,
+                    Some(TypeName::Bool) => Host::Bool,
+                    Some(TypeName::Nil) => Host::Nil,
+                    Some(TypeName::Str) => unreachable!("strings are rejected before use"),
+                    None => return Ok(Some(TypeName::Nil)),
+                };
+                cx.code.call(Callee::Host(host));
+                Ok(Some(TypeName::Nil))
+            }
+            "sqrt" => {
+                if number(types[0], span)? == Some(TypeName::Int) {
+                    cx.code.ops(&[op::F64_CONVERT_I64_S]);
+                }
+                cx.code.ops(&[op::F64_SQRT]);
+                Ok(Some(TypeName::Float))
+            }
+            "abs" => match number(types[0], span)? {
+                Some(TypeName::Int) => Ok(call_helper(cx, Helper::Abs, types[0])),
+                ty => {
+                    cx.code.ops(&[op::F64_ABS]);
+                    Ok(ty)
+                }
+            },
+            "to_string" => Err(TranspileError::Unsupported {
+                construct: "a string",
+                span,
+            }),
+            _ => Err(TranspileError::UnknownIdentifier {
+                name: name.to_string(),
+                span: func.span,
+            }),
+        }
+    }
+
+    /// 関数とその型を並べ、モジュールのバイト列にする
+    ///
+    /// 関数の番号は、取り込む関数、最上位の関数、`main`、補助関数の順に振る。取り込む関数と補助関数は
+    /// 呼び出しているものだけを加える。
+    fn module(&self, main: Body, result: Option<u8>) -> Vec<u8> {
+        let mut functions = vec![];
+        for function in &self.functions {
+            if let Some(body) = &function.code {
+                let params = function
+                    .param_types
+                    .iter()
+                    .flatten()
+                    .filter_map(|&ty| value_type(Some(ty)))
+                    .collect();
+                let results = value_type(function.ret).into_iter().collect();
+                functions.push((function.name.as_str(), params, results, body));
+            }
+        }
+        functions.push(("main", vec![], result.into_iter().collect(), &main));
+        let callees: BTreeSet<Callee> = functions
+            .iter()
+            .flat_map(|(.., body)| body.code.calls.iter().map(|&(_, callee)| callee))
+            .collect();
+        let hosts: Vec<Host> = callees
+            .iter()
+            .filter_map(|callee| match callee {
+                Callee::Host(host) => Some(*host),
+                _ => None,
+            })
+            .collect();
+        let helpers: Vec<(Helper, Body)> = callees
+            .iter()
+            .filter_map(|callee| match callee {
+                Callee::Helper(helper) => Some((*helper, helper.body())),
+                _ => None,
+            })
+            .collect();
+        for (helper, body) in &helpers {
+            let params = helper.params().to_vec();
+            functions.push(("", params, vec![helper.result()], body));
+        }
+
+        // 最上位の関数の番号。書き出さない関数には振らない
+        let mut numbers = HashMap::new();
+        for (id, function) in self.functions.iter().enumerate() {
+            if function.code.is_some() {
+                numbers.insert(id, (hosts.len() + numbers.len()) as u32);
+            }
+        }
+        let number = |callee: Callee| match callee {
+            Callee::Host(host) => hosts.iter().position(|&h| h == host).unwrap() as u32,
+            Callee::Function(id) => numbers[&id],
+            Callee::Helper(helper) => {
+                let position = helpers.iter().position(|&(h, _)| h == helper).unwrap();
+                (hosts.len() + numbers.len() + 1 + position) as u32
+            }
+        };
+
+        let mut types: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+        let mut type_index = |params: &[u8], results: &[u8]| {
+            let position = types.iter().position(|(p, r)| p == params && r == results);
+            position.unwrap_or_else(|| {
+                types.push((params.to_vec(), results.to_vec()));
+                types.len() - 1
+            }) as u32
+        };
+        let mut imports = vec![];
+        uleb(&mut imports, hosts.len() as u64);
+        for host in &hosts {
+            name(&mut imports, "ruscal");
+            name(&mut imports, host.name());
+            imports.push(0x00);
+            uleb(&mut imports, type_index(host.params(), &[]).into());
+        }
+        let mut declarations = vec![];
+        uleb(&mut declarations, functions.len() as u64);
+        for (_, params, results, _) in &functions {
+            uleb(&mut declarations, type_index(params, results).into());
+        }
+        let mut signatures = vec![];
+        uleb(&mut signatures, types.len() as u64);
+        for (params, results) in &types {
+            signatures.push(0x60);
+            uleb(&mut signatures, params.len() as u64);
+            signatures.extend_from_slice(params);
+            uleb(&mut signatures, results.len() as u64);
+            signatures.extend_from_slice(results);
+        }
+        let exported = functions.len() - helpers.len();
+        let mut exports = vec![];
+        uleb(&mut exports, exported as u64);
+        for (i, (export, ..)) in functions.iter().take(exported).enumerate() {
+            name(&mut exports, export);
+            exports.push(0x00);
+            uleb(&mut exports, (hosts.len() + i) as u64);
+        }
+        let mut code = vec![];
+        uleb(&mut code, functions.len() as u64);
+        for (.., body) in &functions {
+            let mut bytes = vec![];
+            uleb(&mut bytes, body.locals.len() as u64);
+            for &local in &body.locals {
+                bytes.extend_from_slice(&[1, local]);
+            }
+            let start = bytes.len();
+            bytes.extend_from_slice(&body.code.bytes);
+            for &(at, callee) in &body.code.calls {
+                padded_uleb(&mut bytes[start + at..start + at + 5], number(callee));
+            }
+            bytes.push(op::END);
+            uleb(&mut code, bytes.len() as u64);
+            code.extend_from_slice(&bytes);
+        }
+
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(&mut module, 1, &signatures);
+        if !hosts.is_empty() {
+            section(&mut module, 2, &imports);
+        }
+        section(&mut module, 3, &declarations);
+        section(&mut module, 7, &exports);
+        section(&mut module, 10, &code);
+        module
+    }
+}
+
+/// 補助関数を呼び出し、結果の型として `ty` を返す
+fn call_helper(cx: &mut Context, helper: Helper, ty: Ty) -> Ty {
+    cx.code.call(Callee::Helper(helper));
+    ty
+}
+
+/// スタックの値を条件に使う `i32` の真偽値にする。`false` と `nil`、0、NaN が偽になる
+fn truthy(cx: &mut Context, ty: Ty) {
+    match ty {
+        Some(TypeName::Bool) | None => {}
+        Some(TypeName::Int) => cx.code.ops(&[op::I64_EQZ, op::I32_EQZ]),
+        Some(TypeName::Float) => cx.code.call(Callee::Helper(Helper::Truthy)),
+        Some(TypeName::Nil) => cx.code.ops(&[op::I32_CONST, 0]),
+        Some(TypeName::Str) => unreachable!("strings are rejected before use"),
+    }
+}
+
+/// 型の値を表す WebAssembly の型。`nil` と型の決まらない式は値を持たない
+fn value_type(ty: Ty) -> Option<u8> {
+    match ty? {
+        TypeName::Int => Some(I64),
+        TypeName::Float => Some(F64),
+        TypeName::Bool => Some(I32),
+        TypeName::Nil => None,
+        TypeName::Str => unreachable!("strings are rejected before use"),
+    }
+}
+
+/// 符号なしの LEB128 で整数を書く
+fn uleb(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// 符号付きの LEB128 で整数を書く
+fn sleb(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// 5バイトに揃えた符号なしの LEB128 で32ビットの整数を書く
+fn padded_uleb(out: &mut [u8], n: u32) {
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = (n >> (7 * i)) as u8 & 0x7f;
+        if i < 4 {
+            *byte |= 0x80;
+        }
+    }
+}
+
+/// 長さを前に付けた名前を書く
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+/// 番号と長さを前に付けたセクションを書く
+fn section(out: &mut Vec<u8>, id: u8, content: &[u8]) {
+    out.push(id);
+    uleb(out, content.len() as u64);
+    out.extend_from_slice(content);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::infix::statements;
+
+    fn compile_infix(input: &str) -> Result<Vec<u8>, TranspileError> {
+        compile(&statements(input).unwrap())
+    }
+
+    fn contains(module: &[u8], bytes: &[u8]) -> bool {
+        module.windows(bytes.len()).any(|window| window == bytes)
+    }
+
+    #[test]
+    fn test_module_layout() {
+        let mut expected = b"\0asm\x01\0\0\0".to_vec();
+        expected.extend_from_slice(&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, F64]);
+        expected.extend_from_slice(&[0x03, 0x02, 0x01, 0x00]);
+        expected.extend_from_slice(&[0x07, 0x08, 0x01, 0x04, b'm', b'a', b'i', b'n', 0x00, 0x00]);
+        expected.extend_from_slice(&[0x0a, 0x0d, 0x01, 0x0b, 0x00, op::F64_CONST]);
+        expected.extend_from_slice(&2.5_f64.to_le_bytes());
+        expected.push(op::END);
+        assert_eq!(compile_infix("2.5"), Ok(expected));
+
+        let mut bytes = vec![];
+        sleb(&mut bytes, -1);
+        sleb(&mut bytes, 64);
+        uleb(&mut bytes, 300);
+        assert_eq!(bytes, [0x7f, 0xc0, 0x00, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_functions_and_imports() {
+        let module = compile_infix(
+            "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }; \
+             fn half(x: f64) -> f64 { x / 2 }; print(fib(10)); fib(20)",
+        )
+        .unwrap();
+        assert!(contains(&module, b"\x06ruscal\x09print_i64"));
+        assert!(!contains(&module, b"print_f64"));
+        // 呼び出さなくても型注釈で型の決まる関数は書き出す
+        assert!(contains(&module, b"\x03fib\x00\x01"));
+        assert!(contains(&module, b"\x04half\x00\x02"));
+        assert!(contains(&module, b"\x04main\x00\x03"));
+        // 呼び出さず型も決まらない関数は、黙って書き出さずにおくのではなく拒否する
+        let unused = compile_infix("fn half(x) { x / 2 }; 1").unwrap_err();
+        assert_eq!(
+            unused,
+            TranspileError::Unexported {
+                name: "half".to_string(),
+                span: Span::new(0, 20)
+            }
+        );
+        assert_eq!(
+            unused.message(),
+            "cannot export `half` because it is never called; call it or add type annotations to its parameters"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            compile_infix("print(\"hi\")"),
+            Err(TranspileError::Unsupported {
+                construct: "a string",
+                span: Span::new(6, 10)
+            })
+        );
+        assert!(matches!(
+            compile_infix("fn main() { 1 }; main()"),
+            Err(TranspileError::Unsupported {
+                construct: "a function named `main`",
+                ..
+            })
+        ));
+        assert_eq!(
+            compile_infix("print(1, 2)"),
+            Err(TranspileError::Arity {
+                expected: 1,
+                found: 2,
+                span: Span::new(0, 11)
+            })
+        );
+        assert!(matches!(
+            compile_infix("fn f(x) { x }; if true { 1 } else { 1.5 }"),
+            Err(TranspileError::Mismatch { .. })
+        ));
+    }
+}